@@ -0,0 +1,42 @@
+/* C interface to symon. Link against libsymon (built as a cdylib). */
+#ifndef SYMON_H
+#define SYMON_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct SymonHandle SymonHandle;
+
+/* Called with each sample as a JSON object, valid only during the call. */
+typedef void (*symon_callback)(const char *json, void *user_data);
+
+/* Initialize NVML. Returns NULL on failure. */
+SymonHandle *symon_init(void);
+
+/* Take a sample and return it as a JSON object; free it with symon_free.
+ * pid selects a process whose GPU usage is reported separately, or 0.
+ * Returns NULL on failure. */
+char *symon_sample_json(SymonHandle *handle, int pid);
+
+/* Free a string returned by symon. NULL is ignored. */
+void symon_free(char *s);
+
+/* Sample every interval_ms on a background thread and pass each sample to
+ * callback. Replaces any previous callback; NULL stops sampling.
+ * Returns 0 on success, -1 on failure. */
+int symon_set_callback(SymonHandle *handle, unsigned int interval_ms, int pid,
+                       symon_callback callback, void *user_data);
+
+/* Stop sampling, shut down NVML and free the handle. */
+void symon_shutdown(SymonHandle *handle);
+
+/* Description of the last error on this thread, or NULL. Valid until the
+ * next symon call on this thread. */
+const char *symon_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SYMON_H */
@@ -0,0 +1,97 @@
+use crate::metrics::Metrics;
+#[cfg(all(unix, not(feature = "processes")))]
+use std::time::{Duration, Instant};
+#[cfg(feature = "processes")]
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+/// Collects resource usage of the symon process itself.
+///
+/// Reported as `_agent.*` metrics so the overhead of monitoring can itself be
+/// monitored and alerted on. Without the `processes` feature, it's read from
+/// `getrusage` and `/proc/self/statm` instead, and not at all on Windows.
+pub struct AgentMonitor {
+    #[cfg(feature = "processes")]
+    system: System,
+    #[cfg(feature = "processes")]
+    pid: Option<Pid>,
+    /// CPU time used up to the last sample, and when it was taken.
+    #[cfg(all(unix, not(feature = "processes")))]
+    last: Option<(Duration, Instant)>,
+}
+
+impl AgentMonitor {
+    pub fn new() -> Self {
+        AgentMonitor {
+            #[cfg(feature = "processes")]
+            system: System::new(),
+            #[cfg(feature = "processes")]
+            pid: sysinfo::get_current_pid().ok(),
+            #[cfg(all(unix, not(feature = "processes")))]
+            last: None,
+        }
+    }
+
+    /// Add `_agent.cpuPercent` (percent of one core) and `_agent.rssBytes`.
+    #[cfg(feature = "processes")]
+    pub fn sample(&mut self, metrics: &mut Metrics) {
+        let Some(pid) = self.pid else {
+            return;
+        };
+        self.system.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&[pid]),
+            ProcessRefreshKind::new().with_cpu().with_memory(),
+        );
+        if let Some(process) = self.system.process(pid) {
+            metrics.add_metric("_agent.cpuPercent", process.cpu_usage() as f64);
+            metrics.add_metric("_agent.rssBytes", process.memory());
+        }
+    }
+
+    /// Add `_agent.cpuPercent` (percent of one core, from the second sample
+    /// on) and, on Linux, `_agent.rssBytes`.
+    #[cfg(all(unix, not(feature = "processes")))]
+    pub fn sample(&mut self, metrics: &mut Metrics) {
+        use nix::sys::resource::{getrusage, UsageWho};
+        use nix::sys::time::TimeVal;
+
+        let Ok(usage) = getrusage(UsageWho::RUSAGE_SELF) else {
+            return;
+        };
+        let duration =
+            |time: TimeVal| Duration::new(time.tv_sec() as u64, time.tv_usec() as u32 * 1000);
+        let cpu = duration(usage.user_time()) + duration(usage.system_time());
+        let now = Instant::now();
+        if let Some((last_cpu, last_time)) = self.last.replace((cpu, now)) {
+            let elapsed = now.duration_since(last_time).as_secs_f64();
+            if elapsed > 0.0 {
+                let used = cpu.saturating_sub(last_cpu).as_secs_f64();
+                metrics.add_metric("_agent.cpuPercent", used / elapsed * 100.0);
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(bytes) = resident_bytes() {
+            metrics.add_metric("_agent.rssBytes", bytes);
+        }
+    }
+
+    /// Resource usage of the agent isn't read on Windows without the
+    /// `processes` feature.
+    #[cfg(all(not(unix), not(feature = "processes")))]
+    pub fn sample(&mut self, _metrics: &mut Metrics) {}
+}
+
+impl Default for AgentMonitor {
+    fn default() -> Self {
+        AgentMonitor::new()
+    }
+}
+
+/// The resident set of this process, from the pages `/proc/self/statm` counts.
+#[cfg(all(target_os = "linux", not(feature = "processes")))]
+fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf only reads a configuration value
+    let page_size = unsafe { nix::libc::sysconf(nix::libc::_SC_PAGESIZE) };
+    u64::try_from(page_size).ok().map(|size| pages * size)
+}
@@ -0,0 +1,300 @@
+use crate::histogram::Histogram;
+use crate::metrics::{Metrics, SampleTime};
+use crate::report::gpu_field;
+use crate::timefmt::UtcDateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Per-GPU metrics a baseline covers, averaged over the GPUs of each sample.
+pub const FIELDS: &[&str] = &["gpu", "memory", "memoryAllocated", "powerWatts"];
+
+/// Percentiles of the per-sample readings that bound a baseline's envelope,
+/// so a few outliers in the recorded run don't widen it.
+const LOW_PERCENTILE: f64 = 5.0;
+const HIGH_PERCENTILE: f64 = 95.0;
+
+/// Where baselines are stored when no directory is given: the user's data
+/// directory, e.g. `~/.local/share/symon/baselines`.
+pub fn default_baseline_dir() -> PathBuf {
+    let data_dir = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .unwrap_or_else(env::temp_dir);
+    data_dir.join("symon").join("baselines")
+}
+
+/// The range a metric stayed in during the recorded run.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub low: f64,
+    pub high: f64,
+    pub mean: f64,
+}
+
+/// The expected utilization and power envelope of a workload, recorded from a
+/// good run and stored under a name, so later runs of the same job can be
+/// checked against it, see `BaselineMonitor`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Baseline {
+    pub name: String,
+    /// When the baseline was recorded, in RFC 3339.
+    pub created: String,
+    /// Samples the baseline was built from.
+    pub samples: u64,
+    /// Envelope of each of `FIELDS`, averaged over GPUs.
+    pub envelopes: BTreeMap<String, Envelope>,
+}
+
+/// The mean over GPUs of each of `FIELDS` in a sample.
+fn gpu_means(metrics: &Metrics) -> BTreeMap<&'static str, f64> {
+    let mut sums: BTreeMap<&'static str, (f64, u32)> = BTreeMap::new();
+    metrics.for_each(|key, value| {
+        let Some((_, field)) = gpu_field(key) else {
+            return;
+        };
+        let (Some(field), Some(value)) = (FIELDS.iter().find(|&&f| f == field), value.as_f64())
+        else {
+            return;
+        };
+        let (sum, count) = sums.entry(field).or_default();
+        *sum += value;
+        *count += 1;
+    });
+    sums.into_iter()
+        .map(|(field, (sum, count))| (field, sum / f64::from(count)))
+        .collect()
+}
+
+fn is_sample(metrics: &Metrics) -> bool {
+    metrics.get("_record").is_none()
+}
+
+impl Baseline {
+    /// Build a baseline from the samples of a recorded run.
+    pub fn from_samples<I>(name: &str, samples: I) -> Self
+    where
+        I: IntoIterator<Item = Metrics>,
+    {
+        let mut histograms: BTreeMap<&'static str, (Histogram, f64)> = BTreeMap::new();
+        let mut count = 0;
+        for metrics in samples.into_iter().filter(is_sample) {
+            count += 1;
+            for (field, value) in gpu_means(&metrics) {
+                let (histogram, sum) = histograms.entry(field).or_default();
+                histogram.add(value);
+                *sum += value;
+            }
+        }
+        let envelopes = histograms
+            .into_iter()
+            .filter_map(|(field, (histogram, sum))| {
+                let envelope = Envelope {
+                    low: histogram.percentile(LOW_PERCENTILE)?,
+                    high: histogram.percentile(HIGH_PERCENTILE)?,
+                    mean: sum / histogram.count() as f64,
+                };
+                Some((field.to_string(), envelope))
+            })
+            .collect();
+        Baseline {
+            name: name.to_string(),
+            created: UtcDateTime::from_system_time(SystemTime::now()).rfc3339(),
+            samples: count,
+            envelopes,
+        }
+    }
+
+    /// The file of baseline `name` in `dir`. Names that would lead out of
+    /// `dir`, e.g. `../x` or `/etc/x`, are rejected.
+    fn path(dir: &Path, name: &str) -> io::Result<PathBuf> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid baseline name {:?}", name),
+            ));
+        }
+        Ok(dir.join(format!("{}.json", name)))
+    }
+
+    pub fn load(dir: &Path, name: &str) -> io::Result<Self> {
+        let path = Baseline::path(dir, name)?;
+        let data = fs::read(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("error reading baseline {}: {}", path.display(), e),
+            )
+        })?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Store the baseline in `dir`, replacing one of the same name, and
+    /// return its path.
+    pub fn save(&self, dir: &Path) -> io::Result<PathBuf> {
+        let path = Baseline::path(dir, &self.name)?;
+        fs::create_dir_all(dir)?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(path)
+    }
+
+    /// Names of the baselines stored in `dir`, sorted.
+    pub fn list(dir: &Path) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                Some(name.strip_suffix(".json")?.to_string())
+            })
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// A metric outside its envelope.
+struct Deviation {
+    since: f64,
+}
+
+/// Compares a live run against a baseline and raises a `baseline_deviation`
+/// event when a metric, averaged over GPUs and over `window`, leaves the
+/// baseline's envelope widened by `tolerance` percent, and a
+/// `baseline_recovered` event when it's back inside.
+///
+/// Nothing is checked until the run has lasted `window`, so a job's start-up
+/// doesn't count as a deviation.
+pub struct BaselineMonitor {
+    baseline: Baseline,
+    window: Duration,
+    tolerance: f64,
+    first_timestamp: Option<f64>,
+    /// Per-sample means within the window, oldest first.
+    readings: BTreeMap<&'static str, VecDeque<(f64, f64)>>,
+    deviations: BTreeMap<&'static str, Deviation>,
+}
+
+impl BaselineMonitor {
+    pub fn new(baseline: Baseline, window: Duration, tolerance: f64) -> Self {
+        BaselineMonitor {
+            baseline,
+            window,
+            tolerance,
+            first_timestamp: None,
+            readings: BTreeMap::new(),
+            deviations: BTreeMap::new(),
+        }
+    }
+
+    /// Check a sample, returning an event record for each metric that just
+    /// left or re-entered its envelope.
+    pub fn check(&mut self, metrics: &Metrics) -> Vec<Metrics> {
+        let (Some(timestamp), Some(time)) = (metrics.timestamp(), metrics.time()) else {
+            return Vec::new();
+        };
+        if !is_sample(metrics) {
+            return Vec::new();
+        }
+        let window = self.window.as_secs_f64();
+        for (field, value) in gpu_means(metrics) {
+            let readings = self.readings.entry(field).or_default();
+            readings.push_back((timestamp, value));
+            while readings
+                .front()
+                .is_some_and(|&(at, _)| at <= timestamp - window)
+            {
+                readings.pop_front();
+            }
+        }
+        let first = *self.first_timestamp.get_or_insert(timestamp);
+        if timestamp - first < window {
+            return Vec::new();
+        }
+
+        let mut events = Vec::new();
+        for (&field, readings) in &self.readings {
+            let Some(envelope) = self.baseline.envelopes.get(field) else {
+                continue;
+            };
+            if readings.is_empty() {
+                continue;
+            }
+            let mean = readings.iter().map(|&(_, v)| v).sum::<f64>() / readings.len() as f64;
+            let low = envelope.low * (1.0 - self.tolerance / 100.0);
+            let high = envelope.high * (1.0 + self.tolerance / 100.0);
+            let direction = if mean < low {
+                Some("below")
+            } else if mean > high {
+                Some("above")
+            } else {
+                None
+            };
+            match (direction, self.deviations.get(field)) {
+                (Some(direction), None) => {
+                    self.deviations
+                        .insert(field, Deviation { since: timestamp });
+                    let mut event = self.event("baseline_deviation", field, mean, envelope, time);
+                    event.add_metric("direction", direction);
+                    events.push(event);
+                }
+                (None, Some(deviation)) => {
+                    let seconds = timestamp - deviation.since;
+                    self.deviations.remove(field);
+                    let mut event = self.event("baseline_recovered", field, mean, envelope, time);
+                    event.add_metric("durationSeconds", seconds);
+                    events.push(event);
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+
+    fn event(
+        &self,
+        name: &str,
+        field: &str,
+        mean: f64,
+        envelope: &Envelope,
+        time: SampleTime,
+    ) -> Metrics {
+        let mut record = Metrics::new();
+        record.add_metric("_record", "event");
+        record.add_metric("_event", name.to_string());
+        record.add_metric("baseline", self.baseline.name.as_str());
+        record.add_metric("metric", field.to_string());
+        record.add_metric("value", mean);
+        record.add_metric("low", envelope.low);
+        record.add_metric("high", envelope.high);
+        record.set_time(time);
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_names_leaving_the_directory() {
+        let dir = Path::new("/var/lib/symon/baselines");
+        assert_eq!(
+            Baseline::path(dir, "a100-node").unwrap(),
+            dir.join("a100-node.json")
+        );
+        for name in ["", ".", "..", "../x", "/etc/x", "a/b", "a\\b"] {
+            assert!(Baseline::path(dir, name).is_err(), "{:?}", name);
+        }
+    }
+}
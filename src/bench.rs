@@ -0,0 +1,224 @@
+//! Active benchmarks for `symon bench`, which measure what the hardware
+//! actually delivers rather than what it reports.
+
+use crate::cuda::{self, check, sym, CuContext, CuDevice, CuDevicePtr, CuResult, CudaError};
+use crate::metrics::Metrics;
+use libloading::{Library, Symbol};
+use std::ffi::{c_uint, c_void};
+use std::fmt;
+use std::time::Instant;
+
+const GB: f64 = 1e9;
+
+/// Usable bandwidth of one PCIe lane in each direction (in bytes per second)
+/// by link generation, after line encoding. Gen1 and Gen2 use 8b/10b, Gen3 to
+/// Gen5 128b/130b, and Gen6 FLIT mode.
+const LANE_BYTES_PER_SECOND: [f64; 6] = [0.25e9, 0.5e9, 0.985e9, 1.969e9, 3.938e9, 7.563e9];
+
+/// The theoretical bandwidth in each direction of a link of generation `gen`
+/// and `width` lanes, in bytes per second.
+pub fn pcie_link_bandwidth(gen: u32, width: u32) -> Option<f64> {
+    let lane = LANE_BYTES_PER_SECOND.get((gen as usize).checked_sub(1)?)?;
+    Some(lane * f64::from(width))
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PcieOptions {
+    /// Bytes per transfer.
+    pub size: usize,
+    /// Timed transfers in each direction.
+    pub iterations: u32,
+    /// Share of the theoretical bandwidth of the link (in percentage) below
+    /// which it's degraded. Well-behaved links reach 70-90%.
+    pub min_efficiency: f64,
+}
+
+/// The measured transfer bandwidth of one GPU and its link, and whether it's
+/// degraded.
+#[derive(Clone, Debug)]
+pub struct PcieResult {
+    pub gpu: u32,
+    /// Generation and width the link is running at, and the most it supports
+    /// in this system.
+    pub link: Option<(u32, u32)>,
+    pub max_link: Option<(u32, u32)>,
+    /// Bytes per second from pinned host memory to the GPU, and back.
+    pub host_to_device: f64,
+    pub device_to_host: f64,
+    /// Why the link is degraded; empty if it isn't.
+    pub problems: Vec<String>,
+}
+
+impl PcieResult {
+    fn new(
+        gpu: u32,
+        metrics: &Metrics,
+        (host_to_device, device_to_host): (f64, f64),
+        min_efficiency: f64,
+    ) -> Self {
+        let number = |field: &str| {
+            metrics
+                .get(&format!("_gpu.{}.{}", gpu, field))
+                .and_then(|v| v.as_u64())
+                .map(|v| v as u32)
+        };
+        let link = number("pcieLinkGen").zip(number("pcieLinkWidth"));
+        let max_link = number("maxPcieLinkGen").zip(number("maxPcieLinkWidth"));
+        let mut problems = Vec::new();
+        // Idle links drop to a lower generation to save power, but keep their
+        // width, so only a narrow link is a fault in itself
+        if let (Some((_, width)), Some((_, max_width))) = (link, max_link) {
+            if width < max_width {
+                problems.push(format!("link is x{}, below x{}", width, max_width));
+            }
+        }
+        if let Some(expected) = max_link.and_then(|(gen, width)| pcie_link_bandwidth(gen, width)) {
+            for (direction, measured) in [
+                ("host to device", host_to_device),
+                ("device to host", device_to_host),
+            ] {
+                let efficiency = 100.0 * measured / expected;
+                if efficiency < min_efficiency {
+                    problems.push(format!(
+                        "{} reached {:.0}% of {:.1} GB/s, below {:.0}%",
+                        direction,
+                        efficiency,
+                        expected / GB,
+                        min_efficiency
+                    ));
+                }
+            }
+        }
+        PcieResult {
+            gpu,
+            link,
+            max_link,
+            host_to_device,
+            device_to_host,
+            problems,
+        }
+    }
+
+    pub fn degraded(&self) -> bool {
+        !self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for PcieResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verdict = if self.degraded() { "DEGRADED" } else { "OK" };
+        writeln!(f, "GPU {}: {}", self.gpu, verdict)?;
+        let link = |link: Option<(u32, u32)>| {
+            link.map_or_else(
+                || "N/A".to_string(),
+                |(gen, width)| format!("Gen{} x{}", gen, width),
+            )
+        };
+        write!(f, "  link {} (max {}", link(self.link), link(self.max_link))?;
+        if let Some(expected) = self
+            .max_link
+            .and_then(|(gen, width)| pcie_link_bandwidth(gen, width))
+        {
+            write!(f, ", {:.1} GB/s", expected / GB)?;
+        }
+        writeln!(f, ")")?;
+        writeln!(
+            f,
+            "  host to device {:.1} GB/s, device to host {:.1} GB/s",
+            self.host_to_device / GB,
+            self.device_to_host / GB
+        )?;
+        for problem in &self.problems {
+            writeln!(f, "  degraded: {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Measure the host to device and device to host bandwidth of `gpus` one at a
+/// time, so GPUs behind a shared switch don't compete, and judge their links
+/// against what `metrics` reports.
+pub fn pcie(
+    metrics: &Metrics,
+    gpus: &[(u32, String)],
+    options: &PcieOptions,
+) -> Result<Vec<PcieResult>, CudaError> {
+    let lib = cuda::load()?;
+    gpus.iter()
+        .map(|(gpu, bus_id)| {
+            let gpu = *gpu;
+            let bandwidth = measure_pcie(&lib, bus_id, options)?;
+            Ok(PcieResult::new(
+                gpu,
+                metrics,
+                bandwidth,
+                options.min_efficiency,
+            ))
+        })
+        .collect()
+}
+
+/// Time copies between pinned host memory and the GPU at `bus_id`, returning
+/// the host to device and device to host bandwidth in bytes per second.
+fn measure_pcie(
+    lib: &Library,
+    bus_id: &str,
+    options: &PcieOptions,
+) -> Result<(f64, f64), CudaError> {
+    // SAFETY: the signatures match cuda.h, every out pointer outlives its call,
+    // both buffers are `options.size` bytes, and the context is current on
+    // this thread for all calls using it
+    unsafe {
+        let ctx_create: Symbol<unsafe extern "C" fn(*mut CuContext, c_uint, CuDevice) -> CuResult> =
+            sym(lib, b"cuCtxCreate_v2\0")?;
+        let ctx_destroy: Symbol<unsafe extern "C" fn(CuContext) -> CuResult> =
+            sym(lib, b"cuCtxDestroy_v2\0")?;
+        let mem_alloc: Symbol<unsafe extern "C" fn(*mut CuDevicePtr, usize) -> CuResult> =
+            sym(lib, b"cuMemAlloc_v2\0")?;
+        let mem_alloc_host: Symbol<unsafe extern "C" fn(*mut *mut c_void, usize) -> CuResult> =
+            sym(lib, b"cuMemAllocHost_v2\0")?;
+        let copy_to_device: Symbol<
+            unsafe extern "C" fn(CuDevicePtr, *const c_void, usize) -> CuResult,
+        > = sym(lib, b"cuMemcpyHtoD_v2\0")?;
+        let copy_to_host: Symbol<
+            unsafe extern "C" fn(*mut c_void, CuDevicePtr, usize) -> CuResult,
+        > = sym(lib, b"cuMemcpyDtoH_v2\0")?;
+        let synchronize: Symbol<unsafe extern "C" fn() -> CuResult> =
+            sym(lib, b"cuCtxSynchronize\0")?;
+
+        let device = cuda::device(lib, bus_id)?;
+        let mut context: CuContext = std::ptr::null_mut();
+        check("cuCtxCreate", ctx_create(&mut context, 0, device))?;
+
+        // Destroying the context frees both buffers, whatever failed
+        let result = (|| {
+            let size = options.size;
+            let mut device_buffer: CuDevicePtr = 0;
+            check("cuMemAlloc", mem_alloc(&mut device_buffer, size))?;
+            let mut host_buffer: *mut c_void = std::ptr::null_mut();
+            check("cuMemAllocHost", mem_alloc_host(&mut host_buffer, size))?;
+
+            let time = |name: &str, copy: &dyn Fn() -> CuResult| {
+                // The first copy pays for waking the link up
+                check(name, copy())?;
+                check("cuCtxSynchronize", synchronize())?;
+                let start = Instant::now();
+                for _ in 0..options.iterations {
+                    check(name, copy())?;
+                }
+                check("cuCtxSynchronize", synchronize())?;
+                let bytes = size as f64 * f64::from(options.iterations);
+                Ok::<_, CudaError>(bytes / start.elapsed().as_secs_f64())
+            };
+            let host_to_device = time("cuMemcpyHtoD", &|| {
+                copy_to_device(device_buffer, host_buffer, size)
+            })?;
+            let device_to_host = time("cuMemcpyDtoH", &|| {
+                copy_to_host(host_buffer, device_buffer, size)
+            })?;
+            Ok((host_to_device, device_to_host))
+        })();
+        ctx_destroy(context);
+        result
+    }
+}
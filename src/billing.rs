@@ -0,0 +1,268 @@
+use crate::metrics::Metrics;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Intervals longer than this many sampling intervals are treated as a gap
+/// (e.g. the agent was paused or down) and not billed.
+const MAX_GAP_INTERVALS: f64 = 3.0;
+
+/// Who GPU usage is billed to.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Account {
+    /// "process" (the monitored pid), "user", "pod" or "label".
+    pub kind: String,
+    pub name: String,
+}
+
+/// Usage accumulated by an account.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    /// Seconds the account had processes on a GPU, summed over GPUs.
+    pub gpu_seconds: f64,
+    /// GPU memory held by the account integrated over time (in GB-hours).
+    pub memory_gb_hours: f64,
+    /// The account's share of the GPU energy (in Joules).
+    pub energy_joules: f64,
+}
+
+impl Usage {
+    fn accrue(&mut self, seconds: f64, memory_bytes: f64, energy_joules: f64) {
+        self.gpu_seconds += seconds;
+        self.memory_gb_hours += memory_bytes / 1e9 * seconds / 3600.0;
+        self.energy_joules += energy_joules;
+    }
+}
+
+/// One line of a billing record.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(flatten)]
+    pub account: Account,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+/// The open billing period, persisted so restarts don't lose it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BillingState {
+    /// Unix timestamp the period started at.
+    pub period_start: Option<f64>,
+    pub entries: Vec<Entry>,
+}
+
+/// Per-GPU readings of the previous sample.
+struct Previous {
+    timestamp: f64,
+    energy: Vec<Option<f64>>,
+}
+
+/// Accumulates GPU-seconds, memory GB-hours and energy per account and emits
+/// a billing record at the end of each period.
+///
+/// Users and pods are billed from `_gpu.N.users` and `_gpu.N.pods`, with a
+/// GPU's energy split among them by SM utilization. The monitored process is
+/// billed for every GPU it uses, and each label for every GPU with processes,
+/// each at the full allocated memory and energy of the GPU. Periods are
+/// aligned to multiples of their length since the epoch, e.g. on the hour.
+pub struct Billing {
+    period: f64,
+    interval: f64,
+    pid: i32,
+    labels: Vec<String>,
+    period_start: Option<f64>,
+    accounts: BTreeMap<Account, Usage>,
+    previous: Option<Previous>,
+}
+
+impl Billing {
+    pub fn new(period: Duration, interval: Duration, labels: Vec<String>) -> Self {
+        Billing {
+            period: period.as_secs_f64().max(1.0),
+            interval: interval.as_secs_f64(),
+            pid: 0,
+            labels,
+            period_start: None,
+            accounts: BTreeMap::new(),
+            previous: None,
+        }
+    }
+
+    /// Bill GPU usage of `pid` to a "process" account; 0 for none.
+    pub fn set_pid(&mut self, pid: i32) {
+        self.pid = pid;
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval.as_secs_f64();
+    }
+
+    /// Continue the period saved by `state`.
+    pub fn restore(&mut self, state: BillingState) {
+        self.period_start = state.period_start;
+        self.accounts = state
+            .entries
+            .into_iter()
+            .map(|entry| (entry.account, entry.usage))
+            .collect();
+    }
+
+    pub fn state(&self) -> BillingState {
+        BillingState {
+            period_start: self.period_start,
+            entries: self.entries(),
+        }
+    }
+
+    fn entries(&self) -> Vec<Entry> {
+        self.accounts
+            .iter()
+            .map(|(account, usage)| Entry {
+                account: account.clone(),
+                usage: *usage,
+            })
+            .collect()
+    }
+
+    /// Bill the interval since the previous sample. Returns a billing record
+    /// if `metrics` is the first sample past the end of the period.
+    pub fn add(&mut self, metrics: &Metrics) -> Option<Metrics> {
+        let timestamp = metrics.timestamp()?;
+        if metrics.get("_record").is_some() {
+            return None;
+        }
+        let gpu_count = metrics
+            .get("_gpu.count")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as usize;
+        let energy: Vec<Option<f64>> = (0..gpu_count)
+            .map(|i| number(metrics, &format!("_gpu.{}.energyJoules", i)))
+            .collect();
+
+        if let Some(previous) = self.previous.take() {
+            let seconds = timestamp - previous.timestamp;
+            if seconds > 0.0 && seconds <= MAX_GAP_INTERVALS * self.interval.max(1.0) {
+                for (i, energy_now) in energy.iter().enumerate() {
+                    let counted = energy_now
+                        .zip(previous.energy.get(i).copied().flatten())
+                        .map(|(now, before)| now - before)
+                        .filter(|delta| *delta >= 0.0);
+                    let power = number(metrics, &format!("gpu.{}.powerWatts", i));
+                    let joules = counted.or(power.map(|watts| watts * seconds));
+                    self.bill_gpu(metrics, i, seconds, joules.unwrap_or(0.0));
+                }
+            }
+        }
+        self.previous = Some(Previous { timestamp, energy });
+
+        let start = (timestamp / self.period).floor() * self.period;
+        let period_start = *self.period_start.get_or_insert(start);
+        if timestamp < period_start + self.period {
+            return None;
+        }
+        let mut record = Metrics::new();
+        record.add_metric("_record", "billing");
+        record.add_metric("_billing_period_start", period_start);
+        record.add_metric("_billing_period_end", period_start + self.period);
+        record.add_metric(
+            "_billing",
+            serde_json::to_value(self.entries()).unwrap_or_default(),
+        );
+        if let Some(time) = metrics.time() {
+            record.set_time(time);
+        }
+        self.accounts.clear();
+        self.period_start = Some(start);
+        Some(record)
+    }
+
+    fn bill_gpu(&mut self, metrics: &Metrics, i: usize, seconds: f64, joules: f64) {
+        let mut in_use = false;
+        for (kind, key, name_of) in [
+            (
+                "user",
+                "users",
+                user_name as fn(&serde_json::Value) -> Option<String>,
+            ),
+            ("pod", "pods", pod_name),
+        ] {
+            let Some(groups) = metrics
+                .get(&format!("_gpu.{}.{}", i, key))
+                .and_then(|v| v.as_array())
+            else {
+                continue;
+            };
+            in_use |= !groups.is_empty();
+            // A pod can have several containers on the same GPU
+            let mut usage: BTreeMap<String, (f64, f64)> = BTreeMap::new();
+            for group in groups {
+                if let Some(name) = name_of(group) {
+                    let (sm, memory) = usage.entry(name).or_default();
+                    *sm += field(group, "smUtilization");
+                    *memory += field(group, "memoryBytes");
+                }
+            }
+            let total_sm: f64 = usage.values().map(|(sm, _)| sm).sum();
+            let count = usage.len() as f64;
+            for (name, (sm, memory)) in usage {
+                let share = if total_sm > 0.0 {
+                    sm / total_sm
+                } else {
+                    1.0 / count
+                };
+                let account = Account {
+                    kind: kind.to_string(),
+                    name,
+                };
+                self.accounts
+                    .entry(account)
+                    .or_default()
+                    .accrue(seconds, memory, joules * share);
+            }
+        }
+
+        let memory = number(metrics, &format!("gpu.{}.memoryAllocatedBytes", i)).unwrap_or(0.0);
+        if self.pid != 0 && metrics.get(&format!("gpu.process.{}.gpu", i)).is_some() {
+            let account = Account {
+                kind: "process".to_string(),
+                name: self.pid.to_string(),
+            };
+            self.accounts
+                .entry(account)
+                .or_default()
+                .accrue(seconds, memory, joules);
+        }
+        if in_use {
+            for label in &self.labels {
+                let account = Account {
+                    kind: "label".to_string(),
+                    name: label.clone(),
+                };
+                self.accounts
+                    .entry(account)
+                    .or_default()
+                    .accrue(seconds, memory, joules);
+            }
+        }
+    }
+}
+
+fn number(metrics: &Metrics, key: &str) -> Option<f64> {
+    metrics.get(key).and_then(|v| v.as_f64())
+}
+
+fn field(value: &serde_json::Value, name: &str) -> f64 {
+    value.get(name).and_then(|v| v.as_f64()).unwrap_or(0.0)
+}
+
+fn user_name(user: &serde_json::Value) -> Option<String> {
+    Some(user.get("user")?.as_str()?.to_string())
+}
+
+fn pod_name(pod: &serde_json::Value) -> Option<String> {
+    let namespace = pod.get("namespace")?.as_str()?;
+    let name = pod.get("pod")?.as_str()?;
+    Some(format!("{}/{}", namespace, name))
+}
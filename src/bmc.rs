@@ -0,0 +1,377 @@
+use crate::log;
+use crate::metrics::Metrics;
+use crate::tls::{self, Connector};
+use serde_json::Value;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long an `ipmitool` call may take before it's killed, e.g. when the
+/// BMC stops answering.
+const IPMITOOL_TIMEOUT: Duration = Duration::from_secs(30);
+/// Readings older than this many intervals are left out of samples, e.g.
+/// while a read hangs.
+const STALE_INTERVALS: u32 = 3;
+
+/// Where to read node power and inlet temperature from.
+pub enum BmcSource {
+    /// `ipmitool` against the local BMC.
+    Ipmi,
+    /// The Redfish API of a BMC at `authority` (`host[:port]`), over HTTPS
+    /// with `tls`.
+    Redfish {
+        authority: String,
+        tls: Option<Connector>,
+        /// `user:password` for HTTP basic authentication.
+        credentials: Option<String>,
+    },
+}
+
+impl BmcSource {
+    /// Parse `ipmi`, `https://bmc-host` or `http://bmc-host`. `tls` is used
+    /// for the latter two if HTTPS.
+    pub fn parse(
+        spec: &str,
+        tls: impl FnOnce() -> io::Result<Connector>,
+        credentials: Option<String>,
+    ) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid BMC source {:?}, expected ipmi or https://host",
+                    spec
+                ),
+            )
+        };
+        if spec == "ipmi" {
+            return Ok(BmcSource::Ipmi);
+        }
+        let (scheme, authority) = spec.split_once("://").ok_or_else(invalid)?;
+        let authority = authority.trim_end_matches('/');
+        if authority.is_empty() || authority.contains('/') {
+            return Err(invalid());
+        }
+        let tls = match scheme {
+            "https" => Some(tls()?),
+            "http" => None,
+            _ => return Err(invalid()),
+        };
+        Ok(BmcSource::Redfish {
+            authority: authority.to_string(),
+            tls,
+            credentials,
+        })
+    }
+}
+
+/// Node power and inlet temperature as last read from the BMC.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BmcReading {
+    pub power_watts: Option<f64>,
+    pub inlet_temp: Option<f64>,
+}
+
+/// Reads node wall power and inlet temperature from the BMC on a background
+/// thread, as BMCs take up to seconds to answer.
+///
+/// Samples get the latest reading as `node.powerWatts` and `node.inletTemp`,
+/// and the GPUs' share of the node's draw as `node.gpu.powerPercent`.
+pub struct BmcCollector {
+    /// The latest reading and when it was taken.
+    latest: Arc<Mutex<Option<(BmcReading, Instant)>>>,
+    max_age: Duration,
+}
+
+impl BmcCollector {
+    /// Start reading `source` every `interval`.
+    pub fn spawn(source: BmcSource, interval: Duration) -> io::Result<Self> {
+        let latest = Arc::new(Mutex::new(None));
+        let shared = latest.clone();
+        thread::Builder::new()
+            .name("bmc".to_string())
+            .spawn(move || {
+                let mut chassis = None;
+                loop {
+                    let reading = match &source {
+                        BmcSource::Ipmi => read_ipmi(),
+                        BmcSource::Redfish { .. } => read_redfish(&source, &mut chassis),
+                    };
+                    let reading = match reading {
+                        Ok(reading) => Some((reading, Instant::now())),
+                        Err(e) => {
+                            log::warning!("Error reading BMC: {}", e);
+                            None
+                        }
+                    };
+                    if let Ok(mut latest) = shared.lock() {
+                        *latest = reading;
+                    }
+                    thread::sleep(interval);
+                }
+            })?;
+        Ok(BmcCollector {
+            latest,
+            max_age: interval * STALE_INTERVALS,
+        })
+    }
+
+    /// Add the latest reading to a sample, unless it's stale.
+    pub fn add(&self, metrics: &mut Metrics) {
+        let Some((reading, taken)) = self.latest.lock().ok().and_then(|latest| *latest) else {
+            return;
+        };
+        if taken.elapsed() > self.max_age {
+            return;
+        }
+        if let Some(watts) = reading.power_watts {
+            metrics.add_metric("node.powerWatts", watts);
+            let gpu_watts = metrics
+                .get("node.gpu.totalPowerWatts")
+                .and_then(|v| v.as_f64());
+            if let Some(gpu_watts) = gpu_watts.filter(|_| watts > 0.0) {
+                metrics.add_metric("node.gpu.powerPercent", gpu_watts / watts * 100.0);
+            }
+        }
+        if let Some(celsius) = reading.inlet_temp {
+            metrics.add_metric("node.inletTemp", celsius);
+        }
+    }
+}
+
+fn read_ipmi() -> io::Result<BmcReading> {
+    let power = ipmitool(&["dcmi", "power", "reading"])?;
+    // Some BMCs lack an inlet sensor, which isn't worth failing the power over
+    let temperatures = ipmitool(&["sdr", "type", "Temperature"]).unwrap_or_default();
+    Ok(BmcReading {
+        power_watts: parse_dcmi_power(&power),
+        inlet_temp: parse_sdr_inlet(&temperatures),
+    })
+}
+
+/// Run `ipmitool`, killing it if it takes longer than `IPMITOOL_TIMEOUT`.
+fn ipmitool(args: &[&str]) -> io::Result<String> {
+    let mut child = Command::new("ipmitool")
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Drain the pipes while waiting, so a long output can't block ipmitool
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+    let deadline = Instant::now() + IPMITOOL_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("ipmitool {} timed out", args.join(" ")),
+            ));
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "ipmitool {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// Read a child's pipe to the end on a thread of its own.
+fn drain(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut out = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut out);
+        }
+        out
+    })
+}
+
+/// The power in `Instantaneous power reading:   350 Watts`.
+fn parse_dcmi_power(output: &str) -> Option<f64> {
+    output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Instantaneous power reading:"))
+        .and_then(|value| value.split_whitespace().next())
+        .and_then(|watts| watts.parse().ok())
+}
+
+/// The temperature of the inlet sensor in lines like
+/// `Inlet Temp | 04h | ok | 7.1 | 23 degrees C`.
+fn parse_sdr_inlet(output: &str) -> Option<f64> {
+    output.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split('|').map(str::trim).collect();
+        let name = fields.first()?.to_ascii_lowercase();
+        if !(name.contains("inlet") || name.contains("intake") || name.contains("ambient")) {
+            return None;
+        }
+        fields
+            .last()?
+            .strip_suffix("degrees C")?
+            .trim()
+            .parse()
+            .ok()
+    })
+}
+
+/// Read the first chassis' power and thermal resources, finding the chassis
+/// on first use.
+fn read_redfish(source: &BmcSource, chassis: &mut Option<String>) -> io::Result<BmcReading> {
+    let path = match chassis {
+        Some(path) => path.clone(),
+        None => {
+            let collection = redfish_get(source, "/redfish/v1/Chassis")?;
+            let path = collection["Members"][0]["@odata.id"]
+                .as_str()
+                .ok_or_else(|| io::Error::other("no chassis found"))?
+                .to_string();
+            chassis.insert(path).clone()
+        }
+    };
+    let power = redfish_get(source, &format!("{}/Power", path))?;
+    let thermal = redfish_get(source, &format!("{}/Thermal", path)).unwrap_or_default();
+    let inlet = thermal["Temperatures"].as_array().and_then(|sensors| {
+        sensors.iter().find(|sensor| {
+            sensor["PhysicalContext"] == "Intake"
+                || sensor["Name"]
+                    .as_str()
+                    .is_some_and(|name| name.to_ascii_lowercase().contains("inlet"))
+        })
+    });
+    Ok(BmcReading {
+        power_watts: power["PowerControl"][0]["PowerConsumedWatts"].as_f64(),
+        inlet_temp: inlet.and_then(|sensor| sensor["ReadingCelsius"].as_f64()),
+    })
+}
+
+/// GET a Redfish resource. Requests are HTTP/1.0 so the body is never chunked
+/// and ends with the connection.
+fn redfish_get(source: &BmcSource, path: &str) -> io::Result<Value> {
+    let BmcSource::Redfish {
+        authority,
+        tls,
+        credentials,
+    } = source
+    else {
+        return Err(io::Error::from(io::ErrorKind::Unsupported));
+    };
+    let mut stream = tls::connect(authority, tls.as_ref(), CONNECT_TIMEOUT, IO_TIMEOUT)?;
+    let mut head = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n",
+        path, authority
+    );
+    if let Some(credentials) = credentials {
+        head.push_str(&format!(
+            "Authorization: Basic {}\r\n",
+            base64(credentials.as_bytes())
+        ));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.flush()?;
+
+    let mut response = Vec::new();
+    match stream.read_to_end(&mut response) {
+        Ok(_) => {}
+        // Many BMCs close TLS connections without a close_notify
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !response.is_empty() => {}
+        Err(e) => return Err(e),
+    }
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| io::Error::other("truncated response"))?;
+    let status_line = String::from_utf8_lossy(&response[..split]);
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_string();
+    if !status.starts_with('2') {
+        return Err(io::Error::other(format!("GET {}: HTTP {}", path, status)));
+    }
+    serde_json::from_slice(&response[split + 4..]).map_err(io::Error::other)
+}
+
+/// Standard base64 with padding, for basic authentication.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ipmitool_output() {
+        let power = "    Instantaneous power reading:                   350 Watts\n\
+                     \x20   Minimum during sampling period:                 80 Watts\n";
+        assert_eq!(parse_dcmi_power(power), Some(350.0));
+        let sdr = "Exhaust Temp     | 01h | ok  |  7.1 | 35 degrees C\n\
+                   Inlet Temp       | 04h | ok  |  7.1 | 23 degrees C\n";
+        assert_eq!(parse_sdr_inlet(sdr), Some(23.0));
+        assert_eq!(
+            parse_sdr_inlet("Inlet Temp | 04h | ns | 7.1 | No Reading\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!(base64(b"admin:password"), "YWRtaW46cGFzc3dvcmQ=");
+        assert_eq!(base64(b"ab"), "YWI=");
+    }
+
+    #[test]
+    fn leaves_out_stale_readings() {
+        let reading = BmcReading {
+            power_watts: Some(500.0),
+            inlet_temp: None,
+        };
+        let collector = BmcCollector {
+            latest: Arc::new(Mutex::new(Some((reading, Instant::now())))),
+            max_age: Duration::from_secs(30),
+        };
+        let mut metrics = Metrics::new();
+        collector.add(&mut metrics);
+        assert!(metrics.get("node.powerWatts").is_some());
+
+        let Some(taken) = Instant::now().checked_sub(Duration::from_secs(60)) else {
+            return;
+        };
+        *collector.latest.lock().unwrap() = Some((reading, taken));
+        let mut metrics = Metrics::new();
+        collector.add(&mut metrics);
+        assert!(metrics.get("node.powerWatts").is_none());
+    }
+}
@@ -0,0 +1,215 @@
+use crate::log;
+use crate::metrics::Metrics;
+use std::time::Duration;
+
+/// Samples to wait after an adjustment before judging its effect.
+const SETTLE_SAMPLES: u32 = 10;
+/// Weight of the latest reading in the average CPU usage.
+const SMOOTHING: f64 = 0.2;
+/// Factor by which the interval is lengthened or shortened again.
+const INTERVAL_STEP: f64 = 1.5;
+/// The interval is lengthened to at most this multiple of the configured one.
+const MAX_STRETCH: u32 = 16;
+/// Undo adjustments once usage falls below this share of the budget, so they
+/// don't flip back and forth around it.
+const RELAX_BELOW: f64 = 0.5;
+
+/// Optional collectors the budget can turn off, most expensive first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Collector {
+    Profiling,
+    ProcessList,
+    Pods,
+    Users,
+}
+
+impl Collector {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Collector::Profiling => "profiling metrics",
+            Collector::ProcessList => "process lists",
+            Collector::Pods => "per-pod attribution",
+            Collector::Users => "per-user attribution",
+        }
+    }
+}
+
+/// A change to stay within (or return to) the configured sampling.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Adjustment {
+    Interval(Duration),
+    Disable(Collector),
+    Enable(Collector),
+}
+
+/// Keeps the agent's own CPU usage (`_agent.cpuPercent`, in percent of one
+/// core) under a budget.
+///
+/// While the average usage is over budget, the enabled optional collectors
+/// are turned off one by one, then the interval is lengthened step by step.
+/// Once usage has dropped well below the budget, the same steps are undone in
+/// reverse. Every adjustment is logged and followed by a few samples to let
+/// the usage settle.
+pub struct OverheadBudget {
+    max_percent: f64,
+    configured: Duration,
+    interval: Duration,
+    collectors: Vec<Collector>,
+    /// How many of `collectors` are turned off, from the front.
+    disabled: usize,
+    average: Option<f64>,
+    samples_since_change: u32,
+}
+
+impl OverheadBudget {
+    /// `collectors` are the enabled collectors that may be turned off, most
+    /// expensive first.
+    pub fn new(max_percent: f64, interval: Duration, collectors: Vec<Collector>) -> Self {
+        OverheadBudget {
+            max_percent,
+            configured: interval,
+            interval,
+            collectors,
+            disabled: 0,
+            average: None,
+            samples_since_change: 0,
+        }
+    }
+
+    /// The configured interval changed, e.g. on reload. Lengthening starts
+    /// over from it.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.configured = interval;
+        self.interval = interval;
+        self.average = None;
+        self.samples_since_change = 0;
+    }
+
+    /// The collectors currently turned off to stay within the budget.
+    pub fn disabled(&self) -> &[Collector] {
+        &self.collectors[..self.disabled]
+    }
+
+    /// Account for the usage in a sample, returning the adjustment to make.
+    pub fn check(&mut self, metrics: &Metrics) -> Option<Adjustment> {
+        let usage = metrics.get("_agent.cpuPercent")?.as_f64()?;
+        let average = match self.average {
+            Some(average) => average + SMOOTHING * (usage - average),
+            None => usage,
+        };
+        self.average = Some(average);
+        self.samples_since_change += 1;
+        if self.samples_since_change < SETTLE_SAMPLES {
+            return None;
+        }
+
+        let longest = self.configured * MAX_STRETCH;
+        let adjustment = if average > self.max_percent {
+            if let Some(&collector) = self.collectors.get(self.disabled) {
+                self.disabled += 1;
+                Adjustment::Disable(collector)
+            } else if self.interval < longest {
+                self.interval = self.interval.mul_f64(INTERVAL_STEP).min(longest);
+                Adjustment::Interval(self.interval)
+            } else {
+                return None;
+            }
+        } else if average < self.max_percent * RELAX_BELOW {
+            if self.interval > self.configured {
+                self.interval = self.interval.div_f64(INTERVAL_STEP).max(self.configured);
+                Adjustment::Interval(self.interval)
+            } else if self.disabled > 0 {
+                self.disabled -= 1;
+                Adjustment::Enable(self.collectors[self.disabled])
+            } else {
+                return None;
+            }
+        } else {
+            return None;
+        };
+
+        let change = match adjustment {
+            Adjustment::Interval(interval) => {
+                format!("sampling every {:.3}s", interval.as_secs_f64())
+            }
+            Adjustment::Disable(collector) => format!("disabling {}", collector.as_str()),
+            Adjustment::Enable(collector) => format!("enabling {}", collector.as_str()),
+        };
+        log::info!(
+            "Agent CPU usage {:.2}% against a budget of {:.2}%, {}",
+            average,
+            self.max_percent,
+            change
+        );
+        self.average = None;
+        self.samples_since_change = 0;
+        Some(adjustment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(percent: f64) -> Metrics {
+        let mut metrics = Metrics::new();
+        metrics.add_metric("_agent.cpuPercent", percent);
+        metrics
+    }
+
+    /// The first adjustment within a few settling periods of `percent`.
+    fn settle(budget: &mut OverheadBudget, percent: f64) -> Option<Adjustment> {
+        (0..SETTLE_SAMPLES * 4).find_map(|_| budget.check(&usage(percent)))
+    }
+
+    #[test]
+    fn disables_collectors_before_stretching_the_interval() {
+        let interval = Duration::from_secs(1);
+        let collectors = vec![Collector::ProcessList, Collector::Users];
+        let mut budget = OverheadBudget::new(1.0, interval, collectors);
+        assert_eq!(
+            settle(&mut budget, 5.0),
+            Some(Adjustment::Disable(Collector::ProcessList))
+        );
+        assert_eq!(
+            settle(&mut budget, 5.0),
+            Some(Adjustment::Disable(Collector::Users))
+        );
+        assert_eq!(
+            budget.disabled(),
+            [Collector::ProcessList, Collector::Users]
+        );
+        assert_eq!(
+            settle(&mut budget, 5.0),
+            Some(Adjustment::Interval(Duration::from_millis(1500)))
+        );
+        // Within budget, but not well below it
+        assert_eq!(settle(&mut budget, 0.8), None);
+        // Undone in reverse
+        assert_eq!(
+            settle(&mut budget, 0.1),
+            Some(Adjustment::Interval(interval))
+        );
+        assert_eq!(
+            settle(&mut budget, 0.1),
+            Some(Adjustment::Enable(Collector::Users))
+        );
+        assert_eq!(
+            settle(&mut budget, 0.1),
+            Some(Adjustment::Enable(Collector::ProcessList))
+        );
+        assert_eq!(settle(&mut budget, 0.1), None);
+    }
+
+    #[test]
+    fn stretches_the_interval_at_most_sixteenfold() {
+        let interval = Duration::from_secs(1);
+        let mut budget = OverheadBudget::new(1.0, interval, Vec::new());
+        let mut longest = interval;
+        while let Some(Adjustment::Interval(stretched)) = settle(&mut budget, 5.0) {
+            assert!(stretched > longest);
+            longest = stretched;
+        }
+        assert_eq!(longest, interval * MAX_STRETCH);
+    }
+}
@@ -0,0 +1,73 @@
+use serde::Serialize;
+use std::fs;
+
+/// The container a process runs in, as recorded in its cgroup path.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerRef {
+    /// Full 64-character container ID.
+    pub id: String,
+    /// Container name, looked up separately as cgroup paths only carry the ID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// UID of the Kubernetes pod the container belongs to, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pod_uid: Option<String>,
+}
+
+/// Look up the container of a process from `/proc/<pid>/cgroup`.
+///
+/// Returns `None` for processes outside containers and on other platforms.
+pub fn container_of(pid: u32) -> Option<ContainerRef> {
+    let contents = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    contents.lines().find_map(|line| {
+        // hierarchy-ID:controller-list:cgroup-path
+        let path = line.splitn(3, ':').nth(2)?;
+        parse_path(path)
+    })
+}
+
+/// Extract the container and pod from a cgroup path.
+///
+/// Handles both the cgroupfs layout
+/// (`/kubepods/burstable/pod<uid>/<id>`, `/docker/<id>`) and the systemd one
+/// (`/kubepods.slice/…/kubepods-burstable-pod<uid>.slice/cri-containerd-<id>.scope`,
+/// `/system.slice/docker-<id>.scope`).
+pub fn parse_path(path: &str) -> Option<ContainerRef> {
+    let mut pod_uid = None;
+    let mut id = None;
+    for segment in path.split('/') {
+        let segment = segment
+            .strip_suffix(".scope")
+            .or_else(|| segment.strip_suffix(".slice"))
+            .unwrap_or(segment);
+        if let Some(uid) = segment.rsplit_once("pod").map(|(_, uid)| uid) {
+            // systemd escapes the dashes of the UID as underscores
+            let uid = uid.replace('_', "-");
+            if is_pod_uid(&uid) {
+                pod_uid = Some(uid);
+                continue;
+            }
+        }
+        let candidate = segment.rsplit(['-', ':']).next().unwrap_or(segment);
+        if is_container_id(candidate) {
+            id = Some(candidate.to_string());
+        }
+    }
+    Some(ContainerRef {
+        id: id?,
+        name: None,
+        pod_uid,
+    })
+}
+
+fn is_container_id(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn is_pod_uid(s: &str) -> bool {
+    s.len() == 36
+        && s.bytes().enumerate().all(|(i, b)| {
+            matches!(i, 8 | 13 | 18 | 23) == (b == b'-') && (b == b'-' || b.is_ascii_hexdigit())
+        })
+}
@@ -0,0 +1,171 @@
+use crate::log;
+use crate::metrics::Metrics;
+use std::io;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How often `ClockMonitor` reads the clock state.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How well the host's clock is synchronized, so samples of different nodes
+/// can be aligned, e.g. the steps of a distributed training job.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClockSync {
+    /// Where the state was read from, `kernel` or `chrony`.
+    pub source: &'static str,
+    pub synchronized: bool,
+    /// Offset of the system clock from true time, positive if it is ahead.
+    pub offset_ms: f64,
+    /// Upper bound of the clock's error.
+    pub max_error_ms: f64,
+}
+
+impl ClockSync {
+    /// The clock state as chrony reports it, or as the kernel keeps it where
+    /// chrony isn't running. chrony steers the clock by frequency, so the
+    /// kernel's offset is only meaningful with ntpd or systemd-timesyncd.
+    pub fn query() -> io::Result<Self> {
+        ClockSync::chrony().or_else(|_| ClockSync::kernel())
+    }
+
+    /// The kernel's NTP state, as set by whichever daemon disciplines the clock.
+    /// Cheap enough to read with every sample.
+    #[cfg(target_os = "linux")]
+    pub fn kernel() -> io::Result<Self> {
+        use nix::libc;
+        // SAFETY: adjtimex with modes 0 only reads into the struct passed in
+        let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+        let state = unsafe { libc::adjtimex(&mut timex) };
+        if state < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let offset_unit_ms = if timex.status & libc::STA_NANO != 0 {
+            1e-6
+        } else {
+            1e-3
+        };
+        Ok(ClockSync {
+            source: "kernel",
+            synchronized: state != libc::TIME_ERROR && timex.status & libc::STA_UNSYNC == 0,
+            offset_ms: timex.offset as f64 * offset_unit_ms,
+            max_error_ms: timex.maxerror as f64 / 1000.0,
+        })
+    }
+
+    /// The kernel's NTP state is only available on Linux.
+    #[cfg(not(target_os = "linux"))]
+    pub fn kernel() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the kernel clock state is only available on Linux",
+        ))
+    }
+
+    /// The state reported by `chronyc tracking`.
+    pub fn chrony() -> io::Result<Self> {
+        let output = Command::new("chronyc").args(["-c", "tracking"]).output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "chronyc tracking failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        parse_chrony_tracking(&String::from_utf8_lossy(&output.stdout)).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected output of chronyc tracking",
+            )
+        })
+    }
+
+    /// Add the state to a record as `_clock.*`.
+    pub fn add_to(&self, record: &mut Metrics) {
+        record.add_metric("_clock.source", self.source);
+        record.add_metric("_clock.synchronized", self.synchronized);
+        record.add_metric("_clock.offsetMs", self.offset_ms);
+        record.add_metric("_clock.maxErrorMs", self.max_error_ms);
+    }
+}
+
+/// Reads the clock state every `REFRESH_INTERVAL` on a background thread, so
+/// samples can carry it without running `chronyc` on the sampling thread.
+pub struct ClockMonitor {
+    latest: Arc<Mutex<Option<ClockSync>>>,
+}
+
+impl ClockMonitor {
+    /// Read the clock state and keep reading it, failing if it can't be read
+    /// at all.
+    pub fn spawn() -> io::Result<Self> {
+        let latest = Arc::new(Mutex::new(Some(ClockSync::query()?)));
+        let shared = latest.clone();
+        thread::Builder::new()
+            .name("clock".to_string())
+            .spawn(move || loop {
+                thread::sleep(REFRESH_INTERVAL);
+                let clock = match ClockSync::query() {
+                    Ok(clock) => Some(clock),
+                    Err(e) => {
+                        log::warning!("Error reading clock synchronization state: {}", e);
+                        None
+                    }
+                };
+                if let Ok(mut latest) = shared.lock() {
+                    *latest = clock;
+                }
+            })?;
+        Ok(ClockMonitor { latest })
+    }
+
+    /// Add the latest state to a sample as `_clock.*`.
+    pub fn add(&self, metrics: &mut Metrics) {
+        if let Some(clock) = self.latest.lock().ok().and_then(|latest| latest.clone()) {
+            clock.add_to(metrics);
+        }
+    }
+}
+
+/// Parse the CSV of `chronyc -c tracking`: reference ID, name, stratum,
+/// reference time, system time offset, last offset, RMS offset, frequency,
+/// residual frequency, skew, root delay, root dispersion, update interval and
+/// leap status, with times in seconds.
+fn parse_chrony_tracking(output: &str) -> Option<ClockSync> {
+    let fields: Vec<&str> = output.trim().split(',').collect();
+    if fields.len() < 14 {
+        return None;
+    }
+    let seconds = |i: usize| fields[i].trim().parse::<f64>().ok();
+    // chrony reports how far the clock is behind true time
+    let behind = seconds(4)?;
+    let root_delay = seconds(10)?;
+    let root_dispersion = seconds(11)?;
+    Some(ClockSync {
+        source: "chrony",
+        synchronized: fields[13].trim() != "Not synchronised",
+        offset_ms: -behind * 1000.0,
+        max_error_ms: (root_delay / 2.0 + root_dispersion + behind.abs()) * 1000.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_chrony_tracking() {
+        let output = "A9FEA97B,169.254.169.123,4,1700000000.123,0.000250000,0.000001,\
+                      0.000020,-5.123,0.001,0.010,0.002000,0.000500,64.5,Normal\n";
+        let clock = parse_chrony_tracking(output).expect("valid tracking output");
+        assert_eq!(clock.source, "chrony");
+        assert!(clock.synchronized);
+        // 250 µs behind true time
+        assert!((clock.offset_ms + 0.25).abs() < 1e-9);
+        assert!((clock.max_error_ms - (1.0 + 0.5 + 0.25)).abs() < 1e-9);
+
+        let unsynced = output.replace("Normal", "Not synchronised");
+        assert!(!parse_chrony_tracking(&unsynced).unwrap().synchronized);
+        assert_eq!(parse_chrony_tracking("506 Cannot talk to daemon"), None);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::metrics::Metrics;
+use crate::query::glob_match;
+use serde_json::Value;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl Comparison {
+    /// Operators in the order they're looked for, so `<=` isn't taken for `<`.
+    const OPERATORS: [(&'static str, Comparison); 6] = [
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+        }
+    }
+}
+
+/// A check of the metrics in a sample against a value, e.g.
+/// `gpu.0.memoryAllocated < 10`, for pre-flight checks in job scripts.
+///
+/// The metric may contain `*` wildcards, e.g. `gpu.*.temp < 80`, in which
+/// case every matching metric must pass. Like `symon get`, the leading `_` of
+/// metadata names may be left out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub metric: String,
+    pub comparison: Comparison,
+    /// A number, or for `==` and `!=` also a string or boolean.
+    pub value: Value,
+}
+
+impl Condition {
+    /// Parse `METRIC OP VALUE` with OP one of `<`, `<=`, `>`, `>=`, `==` or
+    /// `!=`. Spaces around the operator are optional.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("invalid condition {:?}: {}", s, reason);
+        let (at, op, comparison) = Comparison::OPERATORS
+            .iter()
+            .filter_map(|&(op, comparison)| s.find(op).map(|at| (at, op, comparison)))
+            .min_by_key(|&(at, _, _)| at)
+            .ok_or_else(|| invalid("expected METRIC OP VALUE, e.g. gpu.0.temp < 80"))?;
+        let metric = s[..at].trim();
+        let value = s[at + op.len()..].trim();
+        if metric.is_empty() || value.is_empty() {
+            return Err(invalid("expected METRIC OP VALUE, e.g. gpu.0.temp < 80"));
+        }
+        let value = if let Ok(number) = value.parse::<i64>() {
+            Value::from(number)
+        } else if let Ok(number) = value.parse::<f64>() {
+            Value::from(number)
+        } else if let Ok(flag) = value.parse::<bool>() {
+            Value::Bool(flag)
+        } else {
+            Value::from(value.trim_matches(|c| c == '"' || c == '\''))
+        };
+        if !value.is_number() && !matches!(comparison, Comparison::Eq | Comparison::Ne) {
+            return Err(invalid("only == and != compare strings and booleans"));
+        }
+        Ok(Condition {
+            metric: metric.to_string(),
+            comparison,
+            value,
+        })
+    }
+
+    /// Check the condition against a sample, returning a description of each
+    /// metric that fails it. A metric that isn't reported fails.
+    pub fn check(&self, metrics: &Metrics) -> Vec<String> {
+        let mut failures = Vec::new();
+        let mut matched = false;
+        metrics.for_each(|key, value| {
+            let matches = glob_match(&self.metric, key)
+                || key
+                    .strip_prefix('_')
+                    .is_some_and(|key| glob_match(&self.metric, key));
+            if !matches {
+                return;
+            }
+            matched = true;
+            if !self.holds(value) {
+                failures.push(format!(
+                    "{} is {}, expected {} {}",
+                    key,
+                    value,
+                    self.comparison.as_str(),
+                    self.value
+                ));
+            }
+        });
+        if !matched {
+            failures.push(format!("{} is not reported", self.metric));
+        }
+        failures
+    }
+
+    fn holds(&self, actual: &Value) -> bool {
+        if let (Some(actual), Some(expected)) = (actual.as_f64(), self.value.as_f64()) {
+            return match self.comparison {
+                Comparison::Lt => actual < expected,
+                Comparison::Le => actual <= expected,
+                Comparison::Gt => actual > expected,
+                Comparison::Ge => actual >= expected,
+                Comparison::Eq => actual == expected,
+                Comparison::Ne => actual != expected,
+            };
+        }
+        match self.comparison {
+            Comparison::Eq => *actual == self.value,
+            Comparison::Ne => *actual != self.value,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.metric,
+            self.comparison.as_str(),
+            self.value
+        )
+    }
+}
@@ -0,0 +1,52 @@
+use crate::tenant::Tenant;
+use crate::units;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Settings read from `--config` at startup and again on SIGHUP.
+///
+/// Values present in the file override the corresponding command-line flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Sampling interval in seconds.
+    pub interval: Option<f64>,
+    /// Process ID to attribute GPU usage to.
+    pub pid: Option<i32>,
+    /// Sink specs, replacing `--sink` and `--out`.
+    pub sinks: Option<Vec<String>>,
+    /// Teams receiving their own process-attributed metrics on separate sinks.
+    pub tenants: Option<Vec<Tenant>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read {}: {1}", .0.display())]
+    Io(PathBuf, #[source] io::Error),
+    #[error("invalid config {}: {1}", .0.display())]
+    Parse(PathBuf, #[source] serde_json::Error),
+    #[error("invalid config {}: {1}", .0.display())]
+    Invalid(PathBuf, String),
+}
+
+impl Config {
+    /// Read a JSON config file, e.g. `{"interval": 5, "sinks": ["tcp://collector:9000"]}`.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let config: Config = serde_json::from_slice(&contents)
+            .map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+        if let Some(interval) = config.interval {
+            units::parse_seconds(&interval.to_string()).map_err(|e| {
+                ConfigError::Invalid(path.to_path_buf(), format!("interval: {}", e))
+            })?;
+        }
+        for tenant in config.tenants.iter().flatten() {
+            tenant
+                .validate()
+                .map_err(|e| ConfigError::Invalid(path.to_path_buf(), e))?;
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,128 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use signal_hook::consts::TERM_SIGNALS;
+#[cfg(unix)]
+use signal_hook::consts::{SIGHUP, SIGUSR1, SIGUSR2};
+
+/// Runtime requests delivered to the sampling loop, from signals, the HTTP
+/// control API or a marker file.
+#[cfg_attr(windows, allow(dead_code))]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Control {
+    /// SIGTERM, SIGINT or SIGQUIT: stop sampling and exit.
+    Terminate,
+    /// SIGUSR1: pause sampling, or resume it if paused.
+    TogglePause,
+    /// SIGUSR2: take a sample right away, even while paused.
+    SampleNow,
+    /// SIGHUP: re-read the config file.
+    Reload,
+    /// Pause sampling until resumed.
+    Pause,
+    /// Resume paused sampling.
+    Resume,
+    /// Sample at a new interval until the next reload.
+    SetInterval(Duration),
+    /// Change the power limit of a GPU.
+    SetPowerLimit { gpu: u32, watts: f64 },
+    /// Monitor a new process tree, e.g. the command of `symon run`.
+    SetPid(i32),
+    /// Write a marker event record, e.g. `epoch 3 start`.
+    Marker(String),
+}
+
+/// Control API operations that can be allowed individually.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Operation {
+    Pause,
+    Resume,
+    Sample,
+    Interval,
+    PowerLimit,
+    Marker,
+}
+
+/// Who may send control requests over HTTP, and what they may ask for.
+pub struct ControlAccess {
+    /// Bearer token required for every control request.
+    pub token: String,
+    pub allowed: Vec<Operation>,
+    pub sender: Sender<Control>,
+}
+
+/// Receives control requests and doubles as the sampling loop's sleep.
+pub struct Controls {
+    sender: Sender<Control>,
+    receiver: Receiver<Control>,
+}
+
+impl Controls {
+    /// Install signal handlers. Termination also clears `running`.
+    #[cfg(unix)]
+    pub fn listen(running: Arc<AtomicBool>) -> io::Result<Self> {
+        let mut signal_list = TERM_SIGNALS.to_vec();
+        signal_list.extend([SIGUSR1, SIGUSR2, SIGHUP]);
+        let mut signals = signal_hook::iterator::Signals::new(signal_list)?;
+
+        let (sender, receiver) = mpsc::channel();
+        let signal_sender = sender.clone();
+        thread::Builder::new()
+            .name("signals".to_string())
+            .spawn(move || {
+                for signal in signals.forever() {
+                    let control = match signal {
+                        SIGUSR1 => Control::TogglePause,
+                        SIGUSR2 => Control::SampleNow,
+                        SIGHUP => Control::Reload,
+                        _ => {
+                            running.store(false, Ordering::Relaxed);
+                            Control::Terminate
+                        }
+                    };
+                    if signal_sender.send(control).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Controls { sender, receiver })
+    }
+
+    /// Install termination handlers. Windows has no user signals, so only
+    /// `running` is ever cleared and requests only come from the control API.
+    #[cfg(windows)]
+    pub fn listen(running: Arc<AtomicBool>) -> io::Result<Self> {
+        for &signal in TERM_SIGNALS {
+            let running = running.clone();
+            // SAFETY: storing to an atomic is async-signal-safe
+            unsafe {
+                signal_hook::low_level::register(signal, move || {
+                    running.store(false, Ordering::Relaxed)
+                })?;
+            }
+        }
+        let (sender, receiver) = mpsc::channel();
+        Ok(Controls { sender, receiver })
+    }
+
+    /// A handle for delivering requests from other threads.
+    pub fn sender(&self) -> Sender<Control> {
+        self.sender.clone()
+    }
+
+    /// Wait up to `timeout` for a control request.
+    pub fn wait(&self, timeout: Duration) -> Option<Control> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(control) => Some(control),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                thread::sleep(timeout);
+                None
+            }
+        }
+    }
+}
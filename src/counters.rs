@@ -0,0 +1,144 @@
+use crate::metrics::{MetricKey, MetricKind, Metrics};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// Per-counter state: the derived metric names and the previous reading.
+struct Counter {
+    rate_key: &'static str,
+    type_key: &'static str,
+    last: Option<(f64, Duration)>,
+}
+
+/// Derives per-second rates for counter metrics and optionally tags them.
+///
+/// For every counter `<name>`, adds `<name>PerSecond` computed from the
+/// previous sample. No rate is emitted for the first sample or after a
+/// counter reset. With `tag_types`, each counter is also marked with a
+/// `_type.<name>: "counter"` field; all other metrics are gauges.
+pub struct CounterRates {
+    tag_types: bool,
+    counters: HashMap<MetricKey, Counter>,
+    scratch: Vec<(&'static str, &'static str, Option<f64>)>,
+}
+
+impl CounterRates {
+    pub fn new(tag_types: bool) -> Self {
+        CounterRates {
+            tag_types,
+            counters: HashMap::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    pub fn apply(&mut self, metrics: &mut Metrics) {
+        let Some(time) = metrics.time() else {
+            return;
+        };
+
+        self.scratch.clear();
+        metrics.for_each(|key, value| {
+            if MetricKind::of(key) != MetricKind::Counter {
+                return;
+            }
+            let Some(value) = value.as_f64() else {
+                return;
+            };
+            let counter = self.counters.entry(key.clone()).or_insert_with(|| Counter {
+                // Counter names are a small fixed set, so derived names are leaked
+                // like the per-device metric names
+                rate_key: Box::leak(format!("{}PerSecond", key).into_boxed_str()),
+                type_key: Box::leak(format!("_type.{}", key).into_boxed_str()),
+                last: None,
+            });
+            let rate = counter.last.and_then(|(last_value, last_uptime)| {
+                let elapsed = time.uptime.checked_sub(last_uptime)?.as_secs_f64();
+                (value >= last_value && elapsed > 0.0).then(|| (value - last_value) / elapsed)
+            });
+            counter.last = Some((value, time.uptime));
+            self.scratch
+                .push((counter.rate_key, counter.type_key, rate));
+        });
+
+        for &(rate_key, type_key, rate) in &self.scratch {
+            if let Some(rate) = rate {
+                metrics.add_metric(rate_key, rate);
+            }
+            if self.tag_types {
+                metrics.add_metric(type_key, MetricKind::Counter.as_str());
+            }
+        }
+    }
+}
+
+/// Reset-adjusted state of a GPU counter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CounterTotal {
+    /// Sum of the readings seen right before each reset.
+    pub offset: f64,
+    /// Previous raw reading.
+    pub last: f64,
+}
+
+/// Makes GPU counters monotonic across driver reloads and agent restarts.
+///
+/// A reading below the previous one means the counter was reset, so the
+/// previous reading is added to all later ones. With the state persisted, this
+/// also covers agent restarts, including XID errors which are only counted
+/// while symon runs. A reset while the agent is down that the counter has
+/// already grown past can't be detected. Agent counters are left alone.
+#[derive(Default)]
+pub struct CounterTotals {
+    counters: BTreeMap<String, CounterTotal>,
+    scratch: Vec<(MetricKey, serde_json::Value)>,
+}
+
+impl CounterTotals {
+    pub fn new() -> Self {
+        CounterTotals::default()
+    }
+
+    /// Continue from counters saved by `state`.
+    pub fn restore(&mut self, state: BTreeMap<String, CounterTotal>) {
+        self.counters = state;
+    }
+
+    pub fn state(&self) -> BTreeMap<String, CounterTotal> {
+        self.counters.clone()
+    }
+
+    /// Replace the GPU counters in `metrics` with their reset-adjusted totals.
+    pub fn apply(&mut self, metrics: &mut Metrics) {
+        self.scratch.clear();
+        metrics.for_each(|key, value| {
+            if !(key.starts_with("_gpu.") || key.starts_with("gpu."))
+                || MetricKind::of(key) != MetricKind::Counter
+            {
+                return;
+            }
+            let Some(reading) = value.as_f64() else {
+                return;
+            };
+            let counter = match self.counters.get_mut(key.as_ref()) {
+                Some(counter) => counter,
+                None => self.counters.entry(key.to_string()).or_default(),
+            };
+            if reading < counter.last {
+                counter.offset += counter.last;
+            }
+            counter.last = reading;
+            if counter.offset > 0.0 {
+                let total = reading + counter.offset;
+                let total = if value.is_u64() {
+                    serde_json::Value::from(total.round() as u64)
+                } else {
+                    serde_json::Value::from(total)
+                };
+                self.scratch.push((key.clone(), total));
+            }
+        });
+        for (key, total) in self.scratch.drain(..) {
+            metrics.add_metric(key, total);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Minimal access to the CUDA driver API, loaded at runtime so symon neither
+//! needs the CUDA toolkit to build nor libcuda to run, for the subcommands
+//! that put work on the GPUs (`symon stress`, `symon bench`).
+
+use libloading::{Library, Symbol};
+use std::ffi::{c_char, c_int, c_uint, c_void, CString};
+
+pub type CuResult = c_int;
+pub type CuDevice = c_int;
+pub type CuContext = *mut c_void;
+pub type CuModule = *mut c_void;
+pub type CuFunction = *mut c_void;
+pub type CuDevicePtr = u64;
+
+pub const CUDA_SUCCESS: CuResult = 0;
+
+/// The CUDA driver couldn't be loaded, or a call failed.
+#[derive(Debug, thiserror::Error)]
+#[error("CUDA error: {0}")]
+pub struct CudaError(pub(crate) String);
+
+pub(crate) fn check(call: &str, result: CuResult) -> Result<(), CudaError> {
+    match result {
+        CUDA_SUCCESS => Ok(()),
+        _ => Err(CudaError(format!(
+            "{} failed with CUDA error {}",
+            call, result
+        ))),
+    }
+}
+
+/// Look up a driver API entry point.
+///
+/// # Safety
+/// `T` must match the C signature of `name`.
+pub(crate) unsafe fn sym<'a, T>(lib: &'a Library, name: &[u8]) -> Result<Symbol<'a, T>, CudaError> {
+    lib.get(name).map_err(|e| {
+        CudaError(format!(
+            "{}: {}",
+            String::from_utf8_lossy(&name[..name.len() - 1]),
+            e
+        ))
+    })
+}
+
+/// Load and initialize the driver.
+pub(crate) fn load() -> Result<Library, CudaError> {
+    #[cfg(unix)]
+    let path = "libcuda.so.1";
+    #[cfg(windows)]
+    let path = "nvcuda.dll";
+    // SAFETY: loading libcuda runs no initialization code beyond the loader's
+    let lib = unsafe { Library::new(path) }
+        .map_err(|e| CudaError(format!("failed to load {}: {}", path, e)))?;
+    // SAFETY: the signature matches cuda.h
+    unsafe {
+        let init: Symbol<unsafe extern "C" fn(c_uint) -> CuResult> = sym(&lib, b"cuInit\0")?;
+        check("cuInit", init(0))?;
+    }
+    Ok(lib)
+}
+
+/// The device at PCI bus ID `bus_id`, e.g. `00000000:3B:00.0` as NVML reports
+/// it. CUDA numbers devices fastest first unless `CUDA_DEVICE_ORDER` says
+/// otherwise, so ordinals can't be taken for NVML indices.
+pub(crate) fn device(lib: &Library, bus_id: &str) -> Result<CuDevice, CudaError> {
+    let bus_id = CString::new(bus_id).map_err(|e| CudaError(e.to_string()))?;
+    let mut device: CuDevice = 0;
+    // SAFETY: the signature matches cuda.h, and both pointers outlive the call
+    unsafe {
+        let by_bus_id: Symbol<unsafe extern "C" fn(*mut CuDevice, *const c_char) -> CuResult> =
+            sym(lib, b"cuDeviceGetByPCIBusId\0")?;
+        check(
+            "cuDeviceGetByPCIBusId",
+            by_bus_id(&mut device, bus_id.as_ptr()),
+        )?;
+    }
+    Ok(device)
+}
@@ -0,0 +1,154 @@
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::{close, dup2, fork, setsid, ForkResult, Pid};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long `symon stop` waits for the daemon to exit.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Detach from the controlling terminal and continue in the background.
+///
+/// Uses the classic double fork: the first child starts a new session, and the
+/// grandchild (which can never reacquire a terminal) carries on. Standard
+/// streams are redirected to /dev/null and inherited descriptors are closed.
+/// The working directory is kept so relative paths in arguments keep working.
+///
+/// Must be called before any threads are spawned.
+pub fn daemonize() -> io::Result<()> {
+    // SAFETY: the process is still single-threaded at this point
+    if let ForkResult::Parent { .. } = unsafe { fork() }? {
+        std::process::exit(0);
+    }
+    setsid()?;
+    // SAFETY: as above, the first child is single-threaded too
+    if let ForkResult::Parent { .. } = unsafe { fork() }? {
+        std::process::exit(0);
+    }
+
+    umask(Mode::from_bits_truncate(0o022));
+
+    let dev_null = open("/dev/null", OFlag::O_RDWR, Mode::empty())?;
+    for fd in 0..=2 {
+        dup2(dev_null, fd)?;
+    }
+    if dev_null > 2 {
+        close(dev_null)?;
+    }
+
+    // Close any other descriptors inherited from the parent
+    let inherited: Vec<i32> = fs::read_dir("/proc/self/fd")?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|&fd| fd > 2)
+        .collect();
+    for fd in inherited {
+        // The descriptor used to list /proc/self/fd is already gone
+        let _ = close(fd);
+    }
+
+    Ok(())
+}
+
+/// A pidfile that is removed when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the current pid to `path`, refusing if another live instance owns it.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        if let Some(pid) = read_pid(path) {
+            if is_alive(pid) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("already running with pid {} ({})", pid, path.display()),
+                ));
+            }
+        }
+        fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn read_pid(path: &Path) -> Option<Pid> {
+    fs::read_to_string(path)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Pid::from_raw)
+}
+
+fn is_alive(pid: Pid) -> bool {
+    // EPERM means the process exists but belongs to someone else
+    matches!(kill(pid, None), Ok(()) | Err(Errno::EPERM))
+}
+
+/// Stop the daemon recorded in `pidfile` and wait for it to exit.
+pub fn stop(pidfile: &Path) -> io::Result<()> {
+    let pid = read_pid(pidfile).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no valid pid in {}", pidfile.display()),
+        )
+    })?;
+    if !is_alive(pid) {
+        let _ = fs::remove_file(pidfile);
+        println!("symon is not running (removed stale {})", pidfile.display());
+        return Ok(());
+    }
+
+    kill(pid, Signal::SIGTERM)?;
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while is_alive(pid) {
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("pid {} did not exit within {:?}", pid, STOP_TIMEOUT),
+            ));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    // The daemon removes its pidfile on a clean exit; clean up after it otherwise
+    let _ = fs::remove_file(pidfile);
+    println!("symon stopped (pid {})", pid);
+    Ok(())
+}
+
+/// Print whether the daemon recorded in `pidfile` is running.
+///
+/// Returns an LSB init-script style exit code: 0 if running, 1 if the pidfile
+/// is stale and 3 if not running.
+pub fn status(pidfile: &Path) -> i32 {
+    match read_pid(pidfile) {
+        Some(pid) if is_alive(pid) => {
+            println!("symon is running (pid {})", pid);
+            0
+        }
+        Some(pid) => {
+            println!(
+                "symon is not running, but {} refers to pid {}",
+                pidfile.display(),
+                pid
+            );
+            1
+        }
+        None => {
+            println!("symon is not running");
+            3
+        }
+    }
+}
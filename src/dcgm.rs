@@ -0,0 +1,463 @@
+//! Profiling metrics from NVIDIA DCGM, which NVML doesn't expose: SM
+//! activity and occupancy, tensor core and DRAM activity, and PCIe and
+//! NVLink bandwidth. SM, tensor core and DRAM activity are also read from NVML
+//! on Hopper and newer, see `gpm`.
+//!
+//! libdcgm is loaded at runtime so symon runs on nodes without DCGM, and only
+//! the handful of entry points used here are declared.
+
+use crate::log;
+use crate::manifest::MetricInfo;
+use crate::metrics::Metrics;
+use libloading::{Library, Symbol};
+use std::ffi::{c_char, c_int, c_longlong, c_uint, c_ushort, CString};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type DcgmReturn = c_int;
+type Handle = usize;
+type GroupId = usize;
+type FieldGroupId = usize;
+
+const DCGM_ST_OK: DcgmReturn = 0;
+const DCGM_OPERATION_MODE_AUTO: c_uint = 1;
+const DCGM_GROUP_ALL_GPUS: GroupId = 0x7fff_ffff;
+const DCGM_MAX_NUM_DEVICES: usize = 32;
+const DCGM_FT_DOUBLE: c_ushort = b'd' as c_ushort;
+const DCGM_FT_INT64: c_ushort = b'i' as c_ushort;
+const DCGM_FT_STRING: c_ushort = b's' as c_ushort;
+/// `DCGM_FI_DEV_UUID`, watched to match DCGM's GPUs with NVML's.
+const DCGM_FI_DEV_UUID: c_ushort = 54;
+/// Values at or above these are DCGM's markers for "no value".
+const DCGM_FP64_BLANK: f64 = 140737488355328.0;
+const DCGM_INT64_BLANK: i64 = 0x7fff_ffff_ffff_fff0;
+/// How often DCGM updates the watched fields (in microseconds).
+const UPDATE_FREQ_US: c_longlong = 1_000_000;
+
+/// `dcgmFieldValue_v1`.
+#[repr(C)]
+struct FieldValue {
+    version: c_uint,
+    field_id: c_ushort,
+    field_type: c_ushort,
+    status: c_int,
+    ts: i64,
+    value: FieldValueUnion,
+}
+
+#[repr(C)]
+union FieldValueUnion {
+    i64: i64,
+    dbl: f64,
+    str: [c_char; 256],
+    blob: [c_char; 4096],
+}
+
+const FIELD_VALUE_VERSION: c_uint = std::mem::size_of::<FieldValue>() as c_uint | (1 << 24);
+
+/// A profiling field: its DCGM field ID, metric name, and the factor from
+/// DCGM's unit to the metric's, e.g. ratios to percentages.
+struct Field {
+    id: c_ushort,
+    name: &'static str,
+    scale: f64,
+}
+
+macro_rules! dcgm_fields {
+    ($($id:literal => $name:literal [$unit:literal, $scale:literal $(, $notes:literal)?] $description:literal),* $(,)?) => {
+        const FIELDS: &[Field] = &[$(Field { id: $id, name: $name, scale: $scale },)*];
+
+        /// Descriptions of the DCGM metrics, for `symon metrics`.
+        pub const DCGM_METRICS: &[MetricInfo] = &[
+            $(MetricInfo {
+                name: $name,
+                unit: $unit,
+                source: "DCGM",
+                description: $description,
+                notes: dcgm_fields!(@notes $($notes)?),
+            },)*
+        ];
+    };
+    (@notes) => { "With --backend dcgm" };
+    (@notes $notes:literal) => { $notes };
+}
+
+dcgm_fields! {
+    1001 => "gpu.{}.graphicsActive" ["%", 100.0, "With --backend dcgm, or with --profiling from nvmlGpmMetricsGet on Hopper and newer"]
+        "Share of time the graphics or compute engine was active",
+    1002 => "gpu.{}.smActive" ["%", 100.0, "With --backend dcgm, or with --profiling from nvmlGpmMetricsGet on Hopper and newer"]
+        "Share of time at least one warp was active on an SM, averaged over SMs",
+    1003 => "gpu.{}.smOccupancy" ["%", 100.0, "With --backend dcgm, or with --profiling from nvmlGpmMetricsGet on Hopper and newer"]
+        "Resident warps as a share of the maximum, averaged over SMs",
+    1004 => "gpu.{}.tensorActive" ["%", 100.0, "With --backend dcgm, or with --profiling from nvmlGpmMetricsGet on Hopper and newer"]
+        "Share of cycles the tensor cores were active",
+    1005 => "gpu.{}.dramActive" ["%", 100.0, "With --backend dcgm, or with --profiling from nvmlGpmMetricsGet on Hopper and newer"]
+        "Share of cycles device memory was sending or receiving data",
+    1009 => "gpu.{}.pcieTxBytesPerSecond" ["B/s", 1.0]
+        "PCIe bandwidth to the host",
+    1010 => "gpu.{}.pcieRxBytesPerSecond" ["B/s", 1.0]
+        "PCIe bandwidth from the host",
+    1011 => "gpu.{}.nvlinkTxBytesPerSecond" ["B/s", 1.0]
+        "NVLink bandwidth sent, over all links",
+    1012 => "gpu.{}.nvlinkRxBytesPerSecond" ["B/s", 1.0]
+        "NVLink bandwidth received, over all links",
+}
+
+/// Which library reads the GPUs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// NVML only.
+    #[default]
+    Nvml,
+    /// NVML plus DCGM's profiling metrics.
+    Dcgm,
+}
+
+/// DCGM couldn't be loaded, or a call failed.
+#[derive(Debug, thiserror::Error)]
+#[error("DCGM error: {0}")]
+pub struct DcgmError(String);
+
+impl DcgmError {
+    pub(crate) fn new(message: impl Into<String>) -> Self {
+        DcgmError(message.into())
+    }
+}
+
+fn check(call: &str, status: DcgmReturn) -> Result<(), DcgmError> {
+    match status {
+        DCGM_ST_OK => Ok(()),
+        _ => Err(DcgmError(format!(
+            "{} failed with DCGM error {}",
+            call, status
+        ))),
+    }
+}
+
+/// Names libdcgm is loaded by, newest first: the soname changes with each
+/// major DCGM version, and the unversioned name is only there with the
+/// development package.
+#[cfg(unix)]
+const LIBRARY_NAMES: &[&str] = &["libdcgm.so.4", "libdcgm.so.3", "libdcgm.so"];
+#[cfg(windows)]
+const LIBRARY_NAMES: &[&str] = &["dcgm.dll"];
+
+/// A connection to DCGM, embedded in this process or to a host engine,
+/// watching the profiling fields of all GPUs.
+pub struct Dcgm {
+    lib: Library,
+    handle: Handle,
+    embedded: bool,
+    field_group: FieldGroupId,
+    /// DCGM GPU IDs, and the metric names of each field per GPU.
+    gpus: Vec<(c_uint, Vec<&'static str>)>,
+}
+
+impl Dcgm {
+    /// Load libdcgm and start an embedded host engine, or connect to the
+    /// `nv-hostengine` at `host` (e.g. `localhost:5555`).
+    pub fn connect(host: Option<&str>) -> Result<Self, DcgmError> {
+        let lib = load()?;
+
+        let mut handle: Handle = 0;
+        // SAFETY: the signatures match dcgm_agent.h, and every out pointer
+        // outlives its call
+        unsafe {
+            let init: Symbol<unsafe extern "C" fn() -> DcgmReturn> = sym(&lib, b"dcgmInit\0")?;
+            check("dcgmInit", init())?;
+            match host {
+                Some(host) => {
+                    let connect: Symbol<
+                        unsafe extern "C" fn(*const c_char, *mut Handle) -> DcgmReturn,
+                    > = sym(&lib, b"dcgmConnect\0")?;
+                    let host = CString::new(host).map_err(|e| DcgmError(e.to_string()))?;
+                    check("dcgmConnect", connect(host.as_ptr(), &mut handle))?;
+                }
+                None => {
+                    let start: Symbol<unsafe extern "C" fn(c_uint, *mut Handle) -> DcgmReturn> =
+                        sym(&lib, b"dcgmStartEmbedded\0")?;
+                    check(
+                        "dcgmStartEmbedded",
+                        start(DCGM_OPERATION_MODE_AUTO, &mut handle),
+                    )?;
+                }
+            }
+        }
+        let mut dcgm = Dcgm {
+            lib,
+            handle,
+            embedded: host.is_none(),
+            field_group: 0,
+            gpus: Vec::new(),
+        };
+        dcgm.watch()?;
+        Ok(dcgm)
+    }
+
+    /// Watch the profiling fields on all GPUs.
+    fn watch(&mut self) -> Result<(), DcgmError> {
+        let mut ids: Vec<c_ushort> = FIELDS.iter().map(|field| field.id).collect();
+        ids.push(DCGM_FI_DEV_UUID);
+        // Field group names are unique per host engine, which other symon
+        // instances, or one that didn't get to clean up, may share
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let name =
+            CString::new(format!("symon-{}-{}", std::process::id(), nanos)).unwrap_or_default();
+        let mut gpu_ids = [0 as c_uint; DCGM_MAX_NUM_DEVICES];
+        let mut count: c_int = 0;
+        // SAFETY: as in `connect`; `ids` and `gpu_ids` are as long as stated
+        unsafe {
+            let devices: Symbol<
+                unsafe extern "C" fn(Handle, *mut c_uint, *mut c_int) -> DcgmReturn,
+            > = sym(&self.lib, b"dcgmGetAllSupportedDevices\0")?;
+            check(
+                "dcgmGetAllSupportedDevices",
+                devices(self.handle, gpu_ids.as_mut_ptr(), &mut count),
+            )?;
+            let create: Symbol<
+                unsafe extern "C" fn(
+                    Handle,
+                    c_int,
+                    *mut c_ushort,
+                    *const c_char,
+                    *mut FieldGroupId,
+                ) -> DcgmReturn,
+            > = sym(&self.lib, b"dcgmFieldGroupCreate\0")?;
+            check(
+                "dcgmFieldGroupCreate",
+                create(
+                    self.handle,
+                    ids.len() as c_int,
+                    ids.as_mut_ptr(),
+                    name.as_ptr(),
+                    &mut self.field_group,
+                ),
+            )?;
+            let watch: Symbol<
+                unsafe extern "C" fn(
+                    Handle,
+                    GroupId,
+                    FieldGroupId,
+                    c_longlong,
+                    f64,
+                    c_int,
+                ) -> DcgmReturn,
+            > = sym(&self.lib, b"dcgmWatchFields\0")?;
+            check(
+                "dcgmWatchFields",
+                watch(
+                    self.handle,
+                    DCGM_GROUP_ALL_GPUS,
+                    self.field_group,
+                    UPDATE_FREQ_US,
+                    60.0,
+                    0,
+                ),
+            )?;
+        }
+        let count = (count.max(0) as usize).min(DCGM_MAX_NUM_DEVICES);
+        // Until matched with NVML's GPUs, metrics are named by DCGM GPU ID
+        self.gpus = gpu_ids[..count]
+            .iter()
+            .map(|&gpu| (gpu, keys(gpu)))
+            .collect();
+        Ok(())
+    }
+
+    /// Name each GPU's metrics by its NVML index, given the UUIDs of NVML's
+    /// GPUs by index. DCGM GPU IDs differ from NVML indices when GPUs are
+    /// hidden from one of them, e.g. by `CUDA_VISIBLE_DEVICES` or cgroups.
+    /// GPUs NVML doesn't see are left out.
+    pub fn match_nvml(&mut self, nvml_uuids: &[Option<String>]) -> Result<(), DcgmError> {
+        // Static fields are only read on the next update after being watched
+        // SAFETY: as in `connect`
+        unsafe {
+            let update: Symbol<unsafe extern "C" fn(Handle, c_int) -> DcgmReturn> =
+                sym(&self.lib, b"dcgmUpdateAllFields\0")?;
+            check("dcgmUpdateAllFields", update(self.handle, 1))?;
+        }
+        let mut gpus = Vec::with_capacity(self.gpus.len());
+        for &(gpu, ref keys) in &self.gpus {
+            let values = self.latest(gpu, &mut [DCGM_FI_DEV_UUID])?;
+            let Some(uuid) = values.first().and_then(string_value) else {
+                log::warning!(
+                    "DCGM has no UUID for GPU {}, assuming it is NVML's GPU {}",
+                    gpu,
+                    gpu
+                );
+                gpus.push((gpu, keys.clone()));
+                continue;
+            };
+            match nvml_uuids
+                .iter()
+                .position(|nvml| nvml.as_deref() == Some(uuid.as_str()))
+            {
+                Some(index) => gpus.push((gpu, self::keys(index as c_uint))),
+                None => log::warning!(
+                    "DCGM GPU {} ({}) isn't visible to NVML, leaving it out",
+                    gpu,
+                    uuid
+                ),
+            }
+        }
+        self.gpus = gpus;
+        Ok(())
+    }
+
+    /// The latest values of fields `ids` of DCGM GPU `gpu`.
+    fn latest(&self, gpu: c_uint, ids: &mut [c_ushort]) -> Result<Vec<FieldValue>, DcgmError> {
+        // SAFETY: FieldValue is plain data, for which all zeros is valid
+        let mut values: Vec<FieldValue> = (0..ids.len())
+            .map(|_| unsafe { std::mem::zeroed() })
+            .collect();
+        for value in &mut values {
+            value.version = FIELD_VALUE_VERSION;
+        }
+        // SAFETY: as in `connect`; `ids` and `values` both hold `ids.len()`
+        // entries
+        unsafe {
+            let latest: Symbol<
+                unsafe extern "C" fn(
+                    Handle,
+                    c_int,
+                    *mut c_ushort,
+                    c_uint,
+                    *mut FieldValue,
+                ) -> DcgmReturn,
+            > = sym(&self.lib, b"dcgmGetLatestValuesForFields\0")?;
+            check(
+                "dcgmGetLatestValuesForFields",
+                latest(
+                    self.handle,
+                    gpu as c_int,
+                    ids.as_mut_ptr(),
+                    ids.len() as c_uint,
+                    values.as_mut_ptr(),
+                ),
+            )?;
+        }
+        Ok(values)
+    }
+
+    /// Add the latest profiling values of each GPU, see `match_nvml`.
+    pub fn sample(&self, metrics: &mut Metrics) -> Result<(), DcgmError> {
+        let mut ids: Vec<c_ushort> = FIELDS.iter().map(|field| field.id).collect();
+        for (gpu, keys) in &self.gpus {
+            let values = self.latest(*gpu, &mut ids)?;
+            for ((field, key), value) in FIELDS.iter().zip(keys).zip(&values) {
+                if value.status != DCGM_ST_OK {
+                    continue;
+                }
+                // SAFETY: the field type says which union member is set
+                let reading = match value.field_type {
+                    DCGM_FT_DOUBLE => {
+                        Some(unsafe { value.value.dbl }).filter(|&v| v < DCGM_FP64_BLANK)
+                    }
+                    DCGM_FT_INT64 => Some(unsafe { value.value.i64 })
+                        .filter(|&v| v < DCGM_INT64_BLANK)
+                        .map(|v| v as f64),
+                    _ => None,
+                };
+                if let Some(reading) = reading {
+                    metrics.add_metric(*key, reading * field.scale);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Dcgm {
+    fn drop(&mut self) {
+        // SAFETY: as in `connect`; the handle isn't used afterwards
+        unsafe {
+            if let Ok(destroy) = sym::<unsafe extern "C" fn(Handle, FieldGroupId) -> DcgmReturn>(
+                &self.lib,
+                b"dcgmFieldGroupDestroy\0",
+            ) {
+                destroy(self.handle, self.field_group);
+            }
+            let stop: &[u8] = if self.embedded {
+                b"dcgmStopEmbedded\0"
+            } else {
+                b"dcgmDisconnect\0"
+            };
+            if let Ok(stop) = sym::<unsafe extern "C" fn(Handle) -> DcgmReturn>(&self.lib, stop) {
+                stop(self.handle);
+            }
+            if let Ok(shutdown) =
+                sym::<unsafe extern "C" fn() -> DcgmReturn>(&self.lib, b"dcgmShutdown\0")
+            {
+                shutdown();
+            }
+        }
+    }
+}
+
+/// Load the first of `LIBRARY_NAMES` found.
+fn load() -> Result<Library, DcgmError> {
+    let mut errors = Vec::new();
+    for name in LIBRARY_NAMES {
+        // SAFETY: loading DCGM runs no initialization code beyond the loader's
+        match unsafe { Library::new(name) } {
+            Ok(lib) => return Ok(lib),
+            Err(e) => errors.push(format!("{}: {}", name, e)),
+        }
+    }
+    Err(DcgmError(format!(
+        "failed to load libdcgm ({})",
+        errors.join("; ")
+    )))
+}
+
+/// Metric names of the fields of the GPU with NVML index `index`. GPUs are
+/// few and fixed, so their metric names are leaked like the per-device metric
+/// names.
+fn keys(index: c_uint) -> Vec<&'static str> {
+    FIELDS
+        .iter()
+        .map(|field| {
+            &*Box::leak(
+                field
+                    .name
+                    .replace("{}", &index.to_string())
+                    .into_boxed_str(),
+            )
+        })
+        .collect()
+}
+
+/// A string field's value, unless blank.
+fn string_value(value: &FieldValue) -> Option<String> {
+    if value.status != DCGM_ST_OK || value.field_type != DCGM_FT_STRING {
+        return None;
+    }
+    // SAFETY: the field type says `str` is set; it's NUL-terminated within
+    // its 256 bytes
+    let bytes = unsafe { &value.value.str };
+    let bytes: Vec<u8> = bytes
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as u8)
+        .collect();
+    let string = String::from_utf8_lossy(&bytes).into_owned();
+    // Blank strings are DCGM markers such as `<<<NULL>>>`
+    Some(string).filter(|s| !s.is_empty() && !s.starts_with("<<<"))
+}
+
+/// Look up a symbol of libdcgm.
+///
+/// # Safety
+///
+/// `T` must match the symbol's C signature.
+unsafe fn sym<'lib, T>(lib: &'lib Library, name: &[u8]) -> Result<Symbol<'lib, T>, DcgmError> {
+    lib.get(name).map_err(|e| {
+        DcgmError(format!(
+            "{}: {}",
+            String::from_utf8_lossy(name.strip_suffix(b"\0").unwrap_or(name)),
+            e
+        ))
+    })
+}
@@ -0,0 +1,250 @@
+use nvml_wrapper::enums::device::GpuLockedClocksSetting;
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::{Device, Nvml};
+
+/// A clock range to lock to, or a request to restore default clocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockLock {
+    Lock { min_mhz: u32, max_mhz: u32 },
+    Reset,
+}
+
+impl ClockLock {
+    /// Parse `MIN:MAX` in MHz, e.g. `1200:1800`, or `reset`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if s.trim() == "reset" {
+            return Ok(ClockLock::Reset);
+        }
+        let invalid = || format!("expected MIN:MAX in MHz or reset: {:?}", s);
+        let (min, max) = s.split_once(':').ok_or_else(invalid)?;
+        let min_mhz: u32 = min.trim().parse().map_err(|_| invalid())?;
+        let max_mhz: u32 = max.trim().parse().map_err(|_| invalid())?;
+        if min_mhz > max_mhz {
+            return Err(invalid());
+        }
+        Ok(ClockLock::Lock { min_mhz, max_mhz })
+    }
+
+    fn describe(self, clock: &str) -> String {
+        match self {
+            ClockLock::Lock { min_mhz, max_mhz } => {
+                format!("lock {} clocks to {}-{} MHz", clock, min_mhz, max_mhz)
+            }
+            ClockLock::Reset => format!("reset {} clocks", clock),
+        }
+    }
+}
+
+/// Failure to inspect or change a device setting.
+#[derive(Debug, thiserror::Error)]
+pub enum SettingError {
+    #[error("GPU {gpu}: failed to {action}: {source}")]
+    Nvml {
+        gpu: u32,
+        action: String,
+        #[source]
+        source: NvmlError,
+    },
+    #[error("GPU {gpu}: {message}")]
+    Invalid { gpu: u32, message: String },
+}
+
+/// Changes to apply to devices. Unset fields are left alone.
+///
+/// Changing settings requires root (or Administrator) and persists until the
+/// driver is reloaded, unlike everything else symon does.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceSettings {
+    pub power_limit_watts: Option<f64>,
+    pub gpu_clocks: Option<ClockLock>,
+    pub memory_clocks: Option<ClockLock>,
+    pub persistence: Option<bool>,
+}
+
+impl DeviceSettings {
+    pub fn is_empty(&self) -> bool {
+        self.power_limit_watts.is_none()
+            && self.gpu_clocks.is_none()
+            && self.memory_clocks.is_none()
+            && self.persistence.is_none()
+    }
+
+    /// Describe the changes for each of `gpus` (all if `None`) without applying
+    /// them, checking the power limit against what each device allows.
+    pub fn plan(&self, nvml: &Nvml, gpus: Option<&[u32]>) -> Result<Vec<String>, SettingError> {
+        let mut plan = Vec::new();
+        for gpu in selected(nvml, gpus)? {
+            let device = device(nvml, gpu)?;
+            let name = device.name().unwrap_or_else(|_| "unknown".to_string());
+            let mut changes = Vec::new();
+
+            if let Some(watts) = self.power_limit_watts {
+                let milliwatts = self.check_power_limit(&device, gpu, watts)?;
+                match device.power_management_limit() {
+                    Ok(current) => changes.push(format!(
+                        "power limit {:.0} W -> {:.0} W",
+                        current as f64 / 1000.0,
+                        milliwatts as f64 / 1000.0
+                    )),
+                    Err(_) => changes.push(format!("power limit -> {:.0} W", watts)),
+                }
+            }
+            if let Some(lock) = self.gpu_clocks {
+                changes.push(lock.describe("GPU"));
+            }
+            if let Some(lock) = self.memory_clocks {
+                changes.push(lock.describe("memory"));
+            }
+            if let Some(enabled) = self.persistence {
+                let state = |on: bool| if on { "on" } else { "off" };
+                match is_persistent(&device) {
+                    Some(current) => changes.push(format!(
+                        "persistence mode {} -> {}",
+                        state(current),
+                        state(enabled)
+                    )),
+                    None => changes.push(format!("persistence mode -> {}", state(enabled))),
+                }
+            }
+            plan.push(format!("GPU {} ({}): {}", gpu, name, changes.join(", ")));
+        }
+        Ok(plan)
+    }
+
+    /// Apply the changes to each of `gpus` (all if `None`).
+    ///
+    /// A failure on one device doesn't stop the others; all failures are returned.
+    pub fn apply(
+        &self,
+        nvml: &Nvml,
+        gpus: Option<&[u32]>,
+    ) -> Result<Vec<SettingError>, SettingError> {
+        let mut failures = Vec::new();
+        for gpu in selected(nvml, gpus)? {
+            if let Err(e) = self.apply_to(nvml, gpu) {
+                failures.push(e);
+            }
+        }
+        Ok(failures)
+    }
+
+    fn apply_to(&self, nvml: &Nvml, gpu: u32) -> Result<(), SettingError> {
+        let mut device = device(nvml, gpu)?;
+        let failed = |action: &str| {
+            let action = action.to_string();
+            move |source| SettingError::Nvml {
+                gpu,
+                action,
+                source,
+            }
+        };
+
+        if let Some(watts) = self.power_limit_watts {
+            let milliwatts = self.check_power_limit(&device, gpu, watts)?;
+            device
+                .set_power_management_limit(milliwatts)
+                .map_err(failed("set the power limit"))?;
+        }
+        match self.gpu_clocks {
+            Some(ClockLock::Lock { min_mhz, max_mhz }) => device
+                .set_gpu_locked_clocks(GpuLockedClocksSetting::Numeric {
+                    min_clock_mhz: min_mhz,
+                    max_clock_mhz: max_mhz,
+                })
+                .map_err(failed("lock GPU clocks"))?,
+            Some(ClockLock::Reset) => device
+                .reset_gpu_locked_clocks()
+                .map_err(failed("reset GPU clocks"))?,
+            None => {}
+        }
+        match self.memory_clocks {
+            Some(ClockLock::Lock { min_mhz, max_mhz }) => device
+                .set_mem_locked_clocks(min_mhz, max_mhz)
+                .map_err(failed("lock memory clocks"))?,
+            Some(ClockLock::Reset) => device
+                .reset_mem_locked_clocks()
+                .map_err(failed("reset memory clocks"))?,
+            None => {}
+        }
+        if let Some(enabled) = self.persistence {
+            set_persistent(&mut device, gpu, enabled)?;
+        }
+        Ok(())
+    }
+
+    /// Convert `watts` to milliwatts, rejecting limits the device doesn't allow.
+    fn check_power_limit(
+        &self,
+        device: &Device,
+        gpu: u32,
+        watts: f64,
+    ) -> Result<u32, SettingError> {
+        let milliwatts = (watts * 1000.0).round() as u32;
+        if let Ok(constraints) = device.power_management_limit_constraints() {
+            if !(constraints.min_limit..=constraints.max_limit).contains(&milliwatts) {
+                return Err(SettingError::Invalid {
+                    gpu,
+                    message: format!(
+                        "power limit {:.0} W is outside the allowed range {:.0}-{:.0} W",
+                        watts,
+                        constraints.min_limit as f64 / 1000.0,
+                        constraints.max_limit as f64 / 1000.0
+                    ),
+                });
+            }
+        }
+        Ok(milliwatts)
+    }
+}
+
+fn selected(nvml: &Nvml, gpus: Option<&[u32]>) -> Result<Vec<u32>, SettingError> {
+    match gpus {
+        Some(gpus) => Ok(gpus.to_vec()),
+        None => {
+            let count = nvml.device_count().map_err(|source| SettingError::Nvml {
+                gpu: 0,
+                action: "count devices".to_string(),
+                source,
+            })?;
+            Ok((0..count).collect())
+        }
+    }
+}
+
+fn device(nvml: &Nvml, gpu: u32) -> Result<Device<'_>, SettingError> {
+    nvml.device_by_index(gpu)
+        .map_err(|source| SettingError::Nvml {
+            gpu,
+            action: "open the device".to_string(),
+            source,
+        })
+}
+
+#[cfg(target_os = "linux")]
+fn is_persistent(device: &Device) -> Option<bool> {
+    device.is_in_persistent_mode().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_persistent(_device: &Device) -> Option<bool> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn set_persistent(device: &mut Device, gpu: u32, enabled: bool) -> Result<(), SettingError> {
+    device
+        .set_persistent(enabled)
+        .map_err(|source| SettingError::Nvml {
+            gpu,
+            action: "set persistence mode".to_string(),
+            source,
+        })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_persistent(_device: &mut Device, gpu: u32, _enabled: bool) -> Result<(), SettingError> {
+    Err(SettingError::Invalid {
+        gpu,
+        message: "persistence mode can only be set on Linux".to_string(),
+    })
+}
@@ -0,0 +1,70 @@
+use crate::metrics::Metrics;
+use serde::Serialize;
+
+/// Facts about the GPUs and the driver that don't change while it's loaded,
+/// for segmenting a fleet, e.g. by GSP firmware or kernel module flavor.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Devices {
+    /// Driver version, e.g. `535.104.05`.
+    pub driver_version: String,
+    /// `open` for the open GPU kernel module, `proprietary` otherwise, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernel_module: Option<&'static str>,
+    pub gpus: Vec<DeviceInfo>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    pub index: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    /// Architecture, e.g. `Ampere`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub architecture: Option<String>,
+    /// Product class: consumer, workstation, datacenter or unknown.
+    pub class: &'static str,
+    /// Whether the driver offloads to the GPU System Processor: `enabled` or
+    /// `disabled`. Unset on GPUs without one and drivers before 510.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gsp_firmware_mode: Option<&'static str>,
+    /// Version of the GSP firmware, if enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gsp_firmware_version: Option<String>,
+}
+
+impl Devices {
+    /// A one-off record carrying the devices under `_devices`, and the driver
+    /// as `_driver_version` and `_kernel_module`.
+    pub fn to_metrics(&self) -> Result<Metrics, serde_json::Error> {
+        let mut metrics = Metrics::new();
+        metrics.add_metric("_record", "devices");
+        metrics.add_metric("_driver_version", &*self.driver_version);
+        if let Some(module) = self.kernel_module {
+            metrics.add_metric("_kernel_module", module);
+        }
+        metrics.add_metric("_devices", serde_json::to_value(&self.gpus)?);
+        Ok(metrics)
+    }
+}
+
+/// Which kernel module the driver runs on, from the banner in
+/// `/proc/driver/nvidia/version`.
+#[cfg(target_os = "linux")]
+pub fn kernel_module() -> Option<&'static str> {
+    let version = std::fs::read_to_string("/proc/driver/nvidia/version").ok()?;
+    let banner = version.lines().next()?;
+    Some(if banner.contains("Open Kernel Module") {
+        "open"
+    } else {
+        "proprietary"
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn kernel_module() -> Option<&'static str> {
+    None
+}
@@ -0,0 +1,61 @@
+use crate::report::{Better, Report};
+use std::fmt::Write;
+
+/// Compare the headline numbers of two runs.
+///
+/// Returns the formatted comparison and the number of regressions, i.e.
+/// aggregates that moved in the wrong direction by more than `threshold`
+/// percent. Aggregates present in only one run are listed but never count as
+/// regressions.
+pub fn compare(a: &Report, b: &Report, threshold: f64) -> (String, usize) {
+    let a = a.aggregates();
+    let mut b = b.aggregates();
+    let mut out = String::new();
+    let mut regressions = 0;
+
+    let _ = writeln!(out, "{:<32}{:>12}{:>12}{:>10}", "", "A", "B", "change");
+    for aggregate in &a {
+        let Some(i) = b.iter().position(|other| other.name == aggregate.name) else {
+            let _ = writeln!(
+                out,
+                "{:<32}{:>12.2}{:>12}",
+                aggregate.name, aggregate.value, "-"
+            );
+            continue;
+        };
+        let other = b.remove(i);
+        let change = if aggregate.value == 0.0 {
+            if other.value == 0.0 {
+                0.0
+            } else {
+                f64::INFINITY.copysign(other.value)
+            }
+        } else {
+            (other.value - aggregate.value) / aggregate.value.abs() * 100.0
+        };
+        let worse = match aggregate.better {
+            Better::Higher => change < -threshold,
+            Better::Lower => change > threshold,
+        };
+        if worse {
+            regressions += 1;
+        }
+        let _ = writeln!(
+            out,
+            "{:<32}{:>12.2}{:>12.2}{:>+9.1}%{}",
+            aggregate.name,
+            aggregate.value,
+            other.value,
+            change,
+            if worse { "  <- regression" } else { "" }
+        );
+    }
+    for aggregate in &b {
+        let _ = writeln!(
+            out,
+            "{:<32}{:>12}{:>12.2}",
+            aggregate.name, "-", aggregate.value
+        );
+    }
+    (out, regressions)
+}
@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use std::fs;
+
+/// Where Docker keeps per-container state with the default `data-root`.
+pub(crate) const CONTAINERS: &str = "/var/lib/docker/containers";
+/// Forget cached names beyond this many containers, e.g. on busy CI hosts.
+const MAX_CACHED: usize = 1024;
+
+/// Looks up Docker container names by ID.
+///
+/// Names are read from `config.v2.json` in the container's state directory,
+/// which needs read access to the Docker data root but not to the API socket.
+#[derive(Default)]
+pub struct ContainerNames {
+    names: HashMap<String, Option<String>>,
+}
+
+impl ContainerNames {
+    pub fn new() -> Self {
+        ContainerNames::default()
+    }
+
+    /// The name of a container without the leading slash, or `None` if it
+    /// isn't a Docker container.
+    pub fn name(&mut self, id: &str) -> Option<String> {
+        if let Some(name) = self.names.get(id) {
+            return name.clone();
+        }
+        if self.names.len() >= MAX_CACHED {
+            self.names.clear();
+        }
+        let name = read_name(id);
+        self.names.insert(id.to_string(), name.clone());
+        name
+    }
+}
+
+fn read_name(id: &str) -> Option<String> {
+    let config = fs::read(format!("{}/{}/config.v2.json", CONTAINERS, id)).ok()?;
+    let config: serde_json::Value = serde_json::from_slice(&config).ok()?;
+    let name = config.get("Name")?.as_str()?;
+    Some(name.trim_start_matches('/').to_string())
+}
@@ -0,0 +1,109 @@
+use crate::error::Result;
+use crate::metrics::{Metrics, SampleTime};
+use crate::sampler::Sampler;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often to check whether processes have exited.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Steps of taking a GPU out of service, e.g. after an XID 79.
+pub struct DrainOptions {
+    pub gpu: u32,
+    /// `key=value` label file to mark the GPU unhealthy in, e.g. for
+    /// node-feature-discovery's `features.d`.
+    pub label_file: Option<PathBuf>,
+    /// How long to wait for processes to exit.
+    pub timeout: Duration,
+    /// Reset the GPU once it's idle.
+    pub reset: bool,
+}
+
+/// Drain a GPU, writing progress to `out` as `_record: "event"` JSON lines.
+///
+/// New processes are kept off the GPU with NVML's drain state, which lasts
+/// until the driver reloads. Returns whether the GPU became idle (and was
+/// reset, if requested).
+pub fn drain(sampler: &mut Sampler, options: &DrainOptions, out: &mut impl Write) -> Result<bool> {
+    let gpu = options.gpu;
+    sampler.with_gpu(move |nvidia_gpu| nvidia_gpu.set_drain(gpu, true))??;
+    if let Some(path) = &options.label_file {
+        set_label(path, &format!("symon-gpu-{}-unhealthy", gpu), "true")?;
+    }
+    event(out, "gpu_draining", gpu, &[])?;
+
+    let deadline = Instant::now() + options.timeout;
+    loop {
+        let pids = sampler.with_gpu(move |nvidia_gpu| nvidia_gpu.gpu_processes(gpu))??;
+        if pids.is_empty() {
+            break;
+        }
+        if Instant::now() >= deadline {
+            let pids: Vec<String> = pids.iter().map(u32::to_string).collect();
+            event(
+                out,
+                "gpu_drain_timed_out",
+                gpu,
+                &[("pids", &pids.join(","))],
+            )?;
+            return Ok(false);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    event(out, "gpu_drained", gpu, &[])?;
+
+    if options.reset {
+        sampler.with_gpu(move |nvidia_gpu| nvidia_gpu.reset(gpu))??;
+        event(out, "gpu_reset", gpu, &[])?;
+    }
+    Ok(true)
+}
+
+fn event(out: &mut impl Write, name: &str, gpu: u32, details: &[(&str, &str)]) -> io::Result<()> {
+    let mut record = Metrics::new();
+    record.add_metric("_record", "event");
+    record.add_metric("_event", name);
+    record.add_metric("gpu", gpu);
+    for &(key, value) in details {
+        record.add_metric(key.to_string(), value);
+    }
+    record.set_time(SampleTime {
+        wall: SystemTime::now(),
+        uptime: Duration::ZERO,
+    });
+    let mut line = Vec::new();
+    record.to_json_line(&mut line)?;
+    out.write_all(&line)?;
+    out.flush()
+}
+
+/// Set `key=value` in a label file, keeping other labels.
+fn set_label(path: &Path, key: &str, value: &str) -> io::Result<()> {
+    let mut labels = BTreeMap::new();
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            for line in contents.lines() {
+                if let Some((k, v)) = line.split_once('=') {
+                    labels.insert(k.to_string(), v.to_string());
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    labels.insert(key.to_string(), value.to_string());
+
+    let contents: String = labels
+        .iter()
+        .map(|(k, v)| format!("{}={}\n", k, v))
+        .collect();
+    // Write a sibling and rename so readers never see a partial file
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,237 @@
+use crate::metrics::{Metrics, SampleTime};
+use crate::report::gpu_field;
+use crate::subscribers::Severity;
+use std::collections::BTreeMap;
+
+/// Corrected ECC errors since symon started (or the GPU was last reset) above
+/// which a GPU is worth watching: a few are normal, a steady stream means
+/// memory is wearing out.
+pub const CORRECTED_ERRORS_TO_MONITOR: u64 = 100;
+
+/// Retired pages at which NVIDIA recommends replacing a GPU that predates row
+/// remapping; the driver can retire at most 64.
+pub const RETIRED_PAGES_TO_REPLACE: u64 = 60;
+
+/// What to do about a GPU's memory, from least to most urgent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    /// Nothing yet, but watch the error rate.
+    Monitor,
+    /// Let running jobs finish, then reset the GPU to apply a pending row
+    /// remapping or page retirement.
+    Reset,
+    /// Stop scheduling work on the GPU and reset it; jobs on it may have
+    /// computed on corrupted memory.
+    Drain,
+    /// The GPU is out of spare memory and should be replaced.
+    Replace,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Monitor => "monitor",
+            Action::Reset => "reset",
+            Action::Drain => "drain",
+            Action::Replace => "replace",
+        }
+    }
+}
+
+/// A recommendation for one GPU.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Advice {
+    pub severity: Severity,
+    pub action: Action,
+    pub message: String,
+}
+
+/// The memory error readings of one GPU in a sample.
+#[derive(Default)]
+struct Reading {
+    corrected: Option<u64>,
+    uncorrected: Option<u64>,
+    pending: bool,
+    failed: bool,
+    retired_pages: Option<u64>,
+}
+
+fn readings(metrics: &Metrics) -> BTreeMap<u32, Reading> {
+    let mut readings: BTreeMap<u32, Reading> = BTreeMap::new();
+    metrics.for_each(|key, value| {
+        let Some((index, field)) = gpu_field(key) else {
+            return;
+        };
+        let flag = value.as_bool() == Some(true);
+        match field {
+            "correctedVolatileMemoryErrors" => {
+                readings.entry(index).or_default().corrected = value.as_u64()
+            }
+            "uncorrectedVolatileMemoryErrors" => {
+                readings.entry(index).or_default().uncorrected = value.as_u64()
+            }
+            "remappedRowsPending" | "retiredPagesPending" => {
+                readings.entry(index).or_default().pending |= flag
+            }
+            "remappedRowsFailed" => readings.entry(index).or_default().failed |= flag,
+            "retiredPages" => readings.entry(index).or_default().retired_pages = value.as_u64(),
+            _ => {}
+        }
+    });
+    readings
+}
+
+/// Error counts a GPU's advice is judged from, reset once the GPU is reset.
+#[derive(Default)]
+struct Baseline {
+    corrected: Option<u64>,
+    uncorrected: Option<u64>,
+    /// Whether a remapping or retirement was pending in the previous sample.
+    pending: bool,
+}
+
+/// Turns ECC and row remapping readings into recommendations, e.g. "GPU 3:
+/// pending row remapping, schedule a reset", written as `ecc_advice` event
+/// records whose `severity` and `action` alerting sinks can route on.
+///
+/// A GPU's advice is written when it changes, including back to `ok` once the
+/// GPU no longer needs attention. Errors count from when symon started, or
+/// from when a pending remapping or retirement was last applied by a reset.
+#[derive(Default)]
+pub struct EccAdvisor {
+    baselines: BTreeMap<u32, Baseline>,
+    advice: BTreeMap<u32, Advice>,
+}
+
+impl EccAdvisor {
+    pub fn new() -> Self {
+        EccAdvisor::default()
+    }
+
+    /// Check a sample, returning an event record for each GPU whose advice
+    /// changed.
+    pub fn check(&mut self, metrics: &Metrics) -> Vec<Metrics> {
+        let Some(time) = metrics.time() else {
+            return Vec::new();
+        };
+        if metrics.get("_record").is_some() {
+            return Vec::new();
+        }
+
+        let mut events = Vec::new();
+        for (index, reading) in readings(metrics) {
+            let baseline = self.baselines.entry(index).or_default();
+            // The reset applying a pending remapping or retirement also clears
+            // the errors that caused it, as do counters going backwards on a
+            // driver reload
+            let reset = baseline.pending && !reading.pending;
+            let reloaded = |base: Option<u64>, now: Option<u64>| {
+                base.zip(now).is_some_and(|(base, now)| now < base)
+            };
+            if reset
+                || baseline.corrected.is_none()
+                || reloaded(baseline.corrected, reading.corrected)
+            {
+                baseline.corrected = reading.corrected;
+            }
+            if reset
+                || baseline.uncorrected.is_none()
+                || reloaded(baseline.uncorrected, reading.uncorrected)
+            {
+                baseline.uncorrected = reading.uncorrected;
+            }
+            baseline.pending = reading.pending;
+
+            let advice = advise(index, &reading, baseline);
+            if advice.as_ref() == self.advice.get(&index) {
+                continue;
+            }
+            events.push(advice_record(index, advice.as_ref(), time));
+            match advice {
+                Some(advice) => self.advice.insert(index, advice),
+                None => self.advice.remove(&index),
+            };
+        }
+        events
+    }
+}
+
+/// The most urgent recommendation for a GPU, or `None` if its memory is fine.
+fn advise(index: u32, reading: &Reading, baseline: &Baseline) -> Option<Advice> {
+    let since = |now: Option<u64>, base: Option<u64>| now?.checked_sub(base?);
+    let advice = |severity, action, message: String| {
+        Some(Advice {
+            severity,
+            action,
+            message: format!("GPU {}: {}", index, message),
+        })
+    };
+    if reading.failed {
+        return advice(
+            Severity::Critical,
+            Action::Replace,
+            "row remapping failed, replace the GPU".to_string(),
+        );
+    }
+    if let Some(pages) = reading
+        .retired_pages
+        .filter(|&pages| pages >= RETIRED_PAGES_TO_REPLACE)
+    {
+        return advice(
+            Severity::Critical,
+            Action::Replace,
+            format!("{} memory pages retired, replace the GPU", pages),
+        );
+    }
+    if let Some(errors) = since(reading.uncorrected, baseline.uncorrected).filter(|&e| e > 0) {
+        return advice(
+            Severity::Critical,
+            Action::Drain,
+            format!(
+                "{} uncorrected ECC errors, drain the GPU and reset it",
+                errors
+            ),
+        );
+    }
+    if reading.pending {
+        return advice(
+            Severity::Warning,
+            Action::Reset,
+            "pending row remapping or page retirement, schedule a reset".to_string(),
+        );
+    }
+    if let Some(errors) = since(reading.corrected, baseline.corrected)
+        .filter(|&errors| errors >= CORRECTED_ERRORS_TO_MONITOR)
+    {
+        return advice(
+            Severity::Info,
+            Action::Monitor,
+            format!("{} corrected ECC errors, watch for more", errors),
+        );
+    }
+    None
+}
+
+fn advice_record(gpu: u32, advice: Option<&Advice>, time: SampleTime) -> Metrics {
+    let mut record = Metrics::new();
+    record.add_metric("_record", "event");
+    record.add_metric("_event", "ecc_advice");
+    record.add_metric("gpu", gpu);
+    match advice {
+        Some(advice) => {
+            record.add_metric("severity", advice.severity.as_str());
+            record.add_metric("action", advice.action.as_str());
+            record.add_metric("message", advice.message.as_str());
+        }
+        None => {
+            record.add_metric("severity", "ok");
+            record.add_metric("action", "none");
+            record.add_metric(
+                "message",
+                format!("GPU {}: memory no longer needs attention", gpu),
+            );
+        }
+    }
+    record.set_time(time);
+    record
+}
@@ -0,0 +1,113 @@
+use crate::metrics::{MetricKey, Metrics};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Which metrics each emitted sample contains.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum EmitMode {
+    /// Every metric in every sample
+    Full,
+    /// Only metrics that changed since they were last emitted, plus identity fields
+    Changed,
+}
+
+/// Drops metrics that have not changed since they were last emitted.
+///
+/// Numeric values count as changed once they drift more than `tolerance` from
+/// the last emitted value, so slow drifts are still reported eventually.
+pub struct ChangeFilter {
+    tolerance: f64,
+    last: HashMap<MetricKey, Value>,
+}
+
+impl ChangeFilter {
+    pub fn new(tolerance: f64) -> Self {
+        ChangeFilter {
+            tolerance,
+            last: HashMap::new(),
+        }
+    }
+
+    pub fn apply(&mut self, metrics: &mut Metrics) {
+        // Forget metrics that disappeared so they are emitted again when they come back
+        self.last.retain(|key, _| metrics.get(key).is_some());
+
+        metrics.retain(|key, value| {
+            if is_identity(key) {
+                return true;
+            }
+            match self.last.get_mut(key) {
+                Some(last) if !changed(last, value, self.tolerance) => false,
+                Some(last) => {
+                    *last = value.clone();
+                    true
+                }
+                None => {
+                    self.last.insert(key.clone(), value.clone());
+                    true
+                }
+            }
+        });
+    }
+}
+
+fn changed(last: &Value, value: &Value, tolerance: f64) -> bool {
+    match (last.as_f64(), value.as_f64()) {
+        (Some(last), Some(value)) => (value - last).abs() > tolerance,
+        _ => last != value,
+    }
+}
+
+/// Fields that identify the sample and its source, included in every sample.
+fn is_identity(key: &str) -> bool {
+    match key {
+        "_timestamp" | "_schema_version" | "_sampling_timeout" | "_gpu.count" | "cuda_version" => {
+            true
+        }
+        _ => key.starts_with("_gpu.") && (key.ends_with(".name") || key.ends_with(".brand")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(temp: f64, name: &str) -> Metrics {
+        let mut metrics = Metrics::new();
+        metrics.add_metric("_gpu.count", 1u32);
+        metrics.add_metric("_gpu.0.name", name.to_string());
+        metrics.add_metric("gpu.0.temp", temp);
+        metrics
+    }
+
+    #[test]
+    fn emits_changes_beyond_the_tolerance_and_identity_fields() {
+        let mut filter = ChangeFilter::new(1.0);
+        let mut first = sample(60.0, "A100");
+        filter.apply(&mut first);
+        assert!(first.get("gpu.0.temp").is_some());
+
+        let mut unchanged = sample(60.8, "A100");
+        filter.apply(&mut unchanged);
+        assert!(unchanged.get("gpu.0.temp").is_none());
+        assert!(unchanged.get("_gpu.count").is_some());
+        assert!(unchanged.get("_gpu.0.name").is_some());
+
+        // Drift is measured from the last emitted value
+        let mut drifted = sample(61.5, "A100");
+        filter.apply(&mut drifted);
+        assert!(drifted.get("gpu.0.temp").is_some());
+    }
+
+    #[test]
+    fn emits_metrics_again_after_they_disappear() {
+        let mut filter = ChangeFilter::new(0.0);
+        filter.apply(&mut sample(60.0, "A100"));
+        let mut missing = Metrics::new();
+        missing.add_metric("_gpu.count", 1u32);
+        filter.apply(&mut missing);
+        let mut back = sample(60.0, "A100");
+        filter.apply(&mut back);
+        assert!(back.get("gpu.0.temp").is_some());
+    }
+}
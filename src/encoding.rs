@@ -0,0 +1,590 @@
+use crate::metrics::Metrics;
+use crate::otel;
+#[cfg(feature = "protobuf")]
+use crate::proto;
+#[cfg(feature = "protobuf")]
+use prost::Message;
+use serde_json::{Map, Number, Value};
+use std::io::{self, Read};
+
+/// Wire format of samples.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    /// One JSON object per line
+    #[default]
+    Json,
+    /// One MessagePack map per sample
+    Msgpack,
+    /// One CBOR map per sample (RFC 8949)
+    Cbor,
+    /// One `symon.v1.Sample` message per sample, see `proto/symon.proto`
+    Protobuf,
+}
+
+impl Encoding {
+    /// Media type of a sequence of encoded samples.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/x-ndjson",
+            Encoding::Msgpack => "application/vnd.msgpack",
+            Encoding::Cbor => "application/cbor-seq",
+            Encoding::Protobuf => "application/x-protobuf; delimited=true",
+        }
+    }
+
+    /// Encode a sample into `buf`, replacing its contents. MessagePack and CBOR
+    /// samples are self-delimiting and protobuf messages are prefixed with
+    /// their length, so, like JSON lines, samples can be concatenated into a
+    /// stream or file.
+    pub fn encode(self, metrics: &Metrics, buf: &mut Vec<u8>) -> io::Result<()> {
+        let mut span = otel::Span::start("encode");
+        span.set_attribute("symon.metrics", metrics.len());
+        match self {
+            Encoding::Json => Ok(metrics.to_json_line(buf)?),
+            Encoding::Msgpack => {
+                buf.clear();
+                msgpack_map_header(buf, metrics.len());
+                metrics.for_each(|key, value| {
+                    msgpack_str(buf, key);
+                    msgpack_value(buf, value);
+                });
+                Ok(())
+            }
+            Encoding::Cbor => {
+                buf.clear();
+                cbor_head(buf, CBOR_MAP, metrics.len() as u64);
+                metrics.for_each(|key, value| {
+                    cbor_head(buf, CBOR_TEXT, key.len() as u64);
+                    buf.extend_from_slice(key.as_bytes());
+                    cbor_value(buf, value);
+                });
+                Ok(())
+            }
+            Encoding::Protobuf => encode_protobuf(metrics, buf, true),
+        }
+    }
+
+    /// Encode a sample as a standalone message, without the line break of JSON
+    /// or the length prefix of protobuf.
+    pub fn encode_message(self, metrics: &Metrics, buf: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Encoding::Json => {
+                metrics.to_json_line(buf)?;
+                buf.pop();
+                Ok(())
+            }
+            Encoding::Protobuf => encode_protobuf(metrics, buf, false),
+            Encoding::Msgpack | Encoding::Cbor => self.encode(metrics, buf),
+        }
+    }
+}
+
+/// Read the next MessagePack sample from `reader`, or None at its end.
+pub fn read_msgpack(reader: &mut impl Read) -> io::Result<Option<Metrics>> {
+    let Some(first) = read_first(reader)? else {
+        return Ok(None);
+    };
+    to_metrics(msgpack_read_value(reader, first, 0)?).map(Some)
+}
+
+/// Read the next CBOR sample from `reader`, or None at its end.
+pub fn read_cbor(reader: &mut impl Read) -> io::Result<Option<Metrics>> {
+    let Some(first) = read_first(reader)? else {
+        return Ok(None);
+    };
+    to_metrics(cbor_read_value(reader, first, 0)?).map(Some)
+}
+
+/// Read the next length-delimited protobuf sample from `reader`, or None at
+/// its end.
+pub fn read_protobuf(reader: &mut impl Read) -> io::Result<Option<Metrics>> {
+    let Some(first) = read_first(reader)? else {
+        return Ok(None);
+    };
+    let mut len = 0u64;
+    let mut byte = first;
+    for shift in (0..64).step_by(7) {
+        len |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        byte = read_u8(reader)?;
+    }
+    if len > MAX_MESSAGE {
+        return Err(invalid("protobuf message too large"));
+    }
+    let mut message = vec![0; len as usize];
+    reader.read_exact(&mut message)?;
+    decode_protobuf(&message).map(Some)
+}
+
+/// Encode a sample as a `symon.v1.Sample` into `buf`, replacing its contents,
+/// prefixed with its length if `delimited`.
+#[cfg(feature = "protobuf")]
+fn encode_protobuf(metrics: &Metrics, buf: &mut Vec<u8>, delimited: bool) -> io::Result<()> {
+    buf.clear();
+    let sample = proto::sample(metrics);
+    if delimited {
+        sample.encode_length_delimited(buf)?;
+    } else {
+        sample.encode(buf)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "protobuf")]
+fn decode_protobuf(message: &[u8]) -> io::Result<Metrics> {
+    let sample = proto::v1::Sample::decode(message)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(proto::metrics(sample))
+}
+
+#[cfg(not(feature = "protobuf"))]
+fn encode_protobuf(_metrics: &Metrics, _buf: &mut Vec<u8>, _delimited: bool) -> io::Result<()> {
+    Err(no_protobuf())
+}
+
+#[cfg(not(feature = "protobuf"))]
+fn decode_protobuf(_message: &[u8]) -> io::Result<Metrics> {
+    Err(no_protobuf())
+}
+
+/// What encoding and decoding protobuf samples fail with without the
+/// `protobuf` feature; sinks refuse to start with it then, see
+/// `sink::from_spec`.
+#[cfg(not(feature = "protobuf"))]
+fn no_protobuf() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "protobuf encoding requires the `protobuf` feature",
+    )
+}
+
+/// Largest protobuf sample accepted when decoding.
+const MAX_MESSAGE: u64 = 64 << 20;
+
+/// Nesting accepted when decoding, far more than samples use.
+const MAX_DEPTH: usize = 32;
+
+fn read_first(reader: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn to_metrics(value: Value) -> io::Result<Metrics> {
+    let Value::Object(map) = value else {
+        return Err(invalid("sample is not a map"));
+    };
+    let mut metrics = Metrics::new();
+    for (key, value) in map {
+        metrics.add_metric(key, value);
+    }
+    Ok(metrics)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_be<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Read `len` bytes without trusting `len` for the allocation, as a corrupt
+/// length could be anything.
+fn read_string(reader: &mut impl Read, len: u64) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes).map_err(|_| invalid("string is not UTF-8"))
+}
+
+fn float(f: f64) -> Value {
+    Number::from_f64(f).map_or(Value::Null, Value::Number)
+}
+
+fn msgpack_read_value(reader: &mut impl Read, first: u8, depth: usize) -> io::Result<Value> {
+    if depth > MAX_DEPTH {
+        return Err(invalid("nested too deeply"));
+    }
+    Ok(match first {
+        0x00..=0x7f => Value::from(first),
+        0x80..=0x8f => msgpack_read_map(reader, u64::from(first & 0x0f), depth)?,
+        0x90..=0x9f => msgpack_read_array(reader, u64::from(first & 0x0f), depth)?,
+        0xa0..=0xbf => Value::String(read_string(reader, u64::from(first & 0x1f))?),
+        0xc0 => Value::Null,
+        0xc2 => Value::Bool(false),
+        0xc3 => Value::Bool(true),
+        0xca => float(f64::from(f32::from_be_bytes(read_be(reader)?))),
+        0xcb => float(f64::from_be_bytes(read_be(reader)?)),
+        0xcc => Value::from(read_u8(reader)?),
+        0xcd => Value::from(u16::from_be_bytes(read_be(reader)?)),
+        0xce => Value::from(u32::from_be_bytes(read_be(reader)?)),
+        0xcf => Value::from(u64::from_be_bytes(read_be(reader)?)),
+        0xd0 => Value::from(read_u8(reader)? as i8),
+        0xd1 => Value::from(i16::from_be_bytes(read_be(reader)?)),
+        0xd2 => Value::from(i32::from_be_bytes(read_be(reader)?)),
+        0xd3 => Value::from(i64::from_be_bytes(read_be(reader)?)),
+        0xd9 => {
+            let len = read_u8(reader)?;
+            Value::String(read_string(reader, u64::from(len))?)
+        }
+        0xda => {
+            let len = u16::from_be_bytes(read_be(reader)?);
+            Value::String(read_string(reader, u64::from(len))?)
+        }
+        0xdb => {
+            let len = u32::from_be_bytes(read_be(reader)?);
+            Value::String(read_string(reader, u64::from(len))?)
+        }
+        0xdc => {
+            let len = u16::from_be_bytes(read_be(reader)?);
+            msgpack_read_array(reader, u64::from(len), depth)?
+        }
+        0xdd => {
+            let len = u32::from_be_bytes(read_be(reader)?);
+            msgpack_read_array(reader, u64::from(len), depth)?
+        }
+        0xde => {
+            let len = u16::from_be_bytes(read_be(reader)?);
+            msgpack_read_map(reader, u64::from(len), depth)?
+        }
+        0xdf => {
+            let len = u32::from_be_bytes(read_be(reader)?);
+            msgpack_read_map(reader, u64::from(len), depth)?
+        }
+        0xe0..=0xff => Value::from(first as i8),
+        _ => return Err(invalid("unsupported MessagePack type")),
+    })
+}
+
+fn msgpack_read_array(reader: &mut impl Read, len: u64, depth: usize) -> io::Result<Value> {
+    let mut items = Vec::new();
+    for _ in 0..len {
+        let first = read_u8(reader)?;
+        items.push(msgpack_read_value(reader, first, depth + 1)?);
+    }
+    Ok(Value::Array(items))
+}
+
+fn msgpack_read_map(reader: &mut impl Read, len: u64, depth: usize) -> io::Result<Value> {
+    let mut map = Map::new();
+    for _ in 0..len {
+        let first = read_u8(reader)?;
+        let Value::String(key) = msgpack_read_value(reader, first, depth + 1)? else {
+            return Err(invalid("map key is not a string"));
+        };
+        let first = read_u8(reader)?;
+        map.insert(key, msgpack_read_value(reader, first, depth + 1)?);
+    }
+    Ok(Value::Object(map))
+}
+
+fn cbor_read_value(reader: &mut impl Read, first: u8, depth: usize) -> io::Result<Value> {
+    if depth > MAX_DEPTH {
+        return Err(invalid("nested too deeply"));
+    }
+    let major = first >> 5;
+    let info = first & 0x1f;
+    if major == 7 {
+        return Ok(match info {
+            20 => Value::Bool(false),
+            21 => Value::Bool(true),
+            22 | 23 => Value::Null,
+            26 => float(f64::from(f32::from_be_bytes(read_be(reader)?))),
+            27 => float(f64::from_be_bytes(read_be(reader)?)),
+            _ => return Err(invalid("unsupported CBOR simple value")),
+        });
+    }
+    let n = match info {
+        0..=23 => u64::from(info),
+        24 => u64::from(read_u8(reader)?),
+        25 => u64::from(u16::from_be_bytes(read_be(reader)?)),
+        26 => u64::from(u32::from_be_bytes(read_be(reader)?)),
+        27 => u64::from_be_bytes(read_be(reader)?),
+        _ => return Err(invalid("indefinite CBOR lengths are not supported")),
+    };
+    Ok(match major {
+        CBOR_UINT => Value::from(n),
+        CBOR_NEGINT => match i64::try_from(n) {
+            Ok(n) => Value::from(-1 - n),
+            Err(_) => float(-1.0 - n as f64),
+        },
+        CBOR_TEXT => Value::String(read_string(reader, n)?),
+        CBOR_ARRAY => {
+            let mut items = Vec::new();
+            for _ in 0..n {
+                let first = read_u8(reader)?;
+                items.push(cbor_read_value(reader, first, depth + 1)?);
+            }
+            Value::Array(items)
+        }
+        CBOR_MAP => {
+            let mut map = Map::new();
+            for _ in 0..n {
+                let first = read_u8(reader)?;
+                let Value::String(key) = cbor_read_value(reader, first, depth + 1)? else {
+                    return Err(invalid("map key is not a string"));
+                };
+                let first = read_u8(reader)?;
+                map.insert(key, cbor_read_value(reader, first, depth + 1)?);
+            }
+            Value::Object(map)
+        }
+        _ => return Err(invalid("unsupported CBOR type")),
+    })
+}
+
+fn msgpack_value(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => buf.push(0xc0),
+        Value::Bool(b) => buf.push(if *b { 0xc3 } else { 0xc2 }),
+        Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                msgpack_uint(buf, u);
+            } else if let Some(i) = n.as_i64() {
+                msgpack_int(buf, i);
+            } else {
+                buf.push(0xcb);
+                buf.extend_from_slice(&n.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+            }
+        }
+        Value::String(s) => msgpack_str(buf, s),
+        Value::Array(items) => {
+            match items.len() {
+                len @ 0..=15 => buf.push(0x90 | len as u8),
+                len @ 16..=0xffff => {
+                    buf.push(0xdc);
+                    buf.extend_from_slice(&(len as u16).to_be_bytes());
+                }
+                len => {
+                    buf.push(0xdd);
+                    buf.extend_from_slice(&(len as u32).to_be_bytes());
+                }
+            }
+            for item in items {
+                msgpack_value(buf, item);
+            }
+        }
+        Value::Object(map) => {
+            msgpack_map_header(buf, map.len());
+            for (key, value) in map {
+                msgpack_str(buf, key);
+                msgpack_value(buf, value);
+            }
+        }
+    }
+}
+
+fn msgpack_map_header(buf: &mut Vec<u8>, len: usize) {
+    match len {
+        0..=15 => buf.push(0x80 | len as u8),
+        16..=0xffff => {
+            buf.push(0xde);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xdf);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+fn msgpack_str(buf: &mut Vec<u8>, s: &str) {
+    match s.len() {
+        len @ 0..=31 => buf.push(0xa0 | len as u8),
+        len @ 32..=0xff => buf.extend_from_slice(&[0xd9, len as u8]),
+        len @ 0x100..=0xffff => {
+            buf.push(0xda);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            buf.push(0xdb);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn msgpack_uint(buf: &mut Vec<u8>, u: u64) {
+    match u {
+        0..=0x7f => buf.push(u as u8),
+        0x80..=0xff => buf.extend_from_slice(&[0xcc, u as u8]),
+        0x100..=0xffff => {
+            buf.push(0xcd);
+            buf.extend_from_slice(&(u as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buf.push(0xce);
+            buf.extend_from_slice(&(u as u32).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xcf);
+            buf.extend_from_slice(&u.to_be_bytes());
+        }
+    }
+}
+
+/// Only called for negative integers; others are unsigned.
+fn msgpack_int(buf: &mut Vec<u8>, i: i64) {
+    match i {
+        -32..=-1 => buf.push(i as u8),
+        -0x80..=-33 => buf.extend_from_slice(&[0xd0, i as u8]),
+        -0x8000..=-0x81 => {
+            buf.push(0xd1);
+            buf.extend_from_slice(&(i as i16).to_be_bytes());
+        }
+        -0x8000_0000..=-0x8001 => {
+            buf.push(0xd2);
+            buf.extend_from_slice(&(i as i32).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xd3);
+            buf.extend_from_slice(&i.to_be_bytes());
+        }
+    }
+}
+
+const CBOR_UINT: u8 = 0;
+const CBOR_NEGINT: u8 = 1;
+const CBOR_TEXT: u8 = 3;
+const CBOR_ARRAY: u8 = 4;
+const CBOR_MAP: u8 = 5;
+
+fn cbor_value(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => buf.push(0xf6),
+        Value::Bool(b) => buf.push(if *b { 0xf5 } else { 0xf4 }),
+        Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                cbor_head(buf, CBOR_UINT, u);
+            } else if let Some(i) = n.as_i64() {
+                cbor_head(buf, CBOR_NEGINT, !i as u64);
+            } else {
+                buf.push(0xfb);
+                buf.extend_from_slice(&n.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            cbor_head(buf, CBOR_TEXT, s.len() as u64);
+            buf.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(items) => {
+            cbor_head(buf, CBOR_ARRAY, items.len() as u64);
+            for item in items {
+                cbor_value(buf, item);
+            }
+        }
+        Value::Object(map) => {
+            cbor_head(buf, CBOR_MAP, map.len() as u64);
+            for (key, value) in map {
+                cbor_head(buf, CBOR_TEXT, key.len() as u64);
+                buf.extend_from_slice(key.as_bytes());
+                cbor_value(buf, value);
+            }
+        }
+    }
+}
+
+/// The initial byte of a data item and its argument, in the shortest form.
+fn cbor_head(buf: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    match n {
+        0..=23 => buf.push(major | n as u8),
+        24..=0xff => buf.extend_from_slice(&[major | 24, n as u8]),
+        0x100..=0xffff => {
+            buf.push(major | 25);
+            buf.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buf.push(major | 26);
+            buf.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            buf.push(major | 27);
+            buf.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sample() -> Metrics {
+        let mut metrics = Metrics::new();
+        metrics.add_metric("_timestamp", json!(1700000000.25));
+        metrics.add_metric("gpu.0.powerWatts", json!(120.5));
+        metrics.add_metric("gpu.0.temp", json!(60));
+        metrics.add_metric("gpu.0.clockOffsetMHz", json!(-200));
+        metrics.add_metric(
+            "gpu.0.name",
+            json!("NVIDIA A100-SXM4-80GB with a long name"),
+        );
+        metrics.add_metric("gpu.0.throttled", json!(false));
+        metrics.add_metric(
+            "gpu.0.processes",
+            json!([{"pid": 70000, "usedMemory": 1u64 << 33}]),
+        );
+        metrics
+    }
+
+    fn assert_round_trips(encoding: Encoding, read: fn(&mut &[u8]) -> io::Result<Option<Metrics>>) {
+        let mut stream = Vec::new();
+        let mut buf = Vec::new();
+        for _ in 0..2 {
+            encoding.encode(&sample(), &mut buf).unwrap();
+            stream.extend_from_slice(&buf);
+        }
+        let mut reader = &stream[..];
+        for _ in 0..2 {
+            let decoded = read(&mut reader).unwrap().unwrap();
+            let mut expected = Vec::new();
+            sample().to_json_line(&mut expected).unwrap();
+            let mut actual = Vec::new();
+            decoded.to_json_line(&mut actual).unwrap();
+            assert_eq!(
+                String::from_utf8(actual).unwrap(),
+                String::from_utf8(expected).unwrap()
+            );
+        }
+        assert!(read(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_back_msgpack() {
+        assert_round_trips(Encoding::Msgpack, |reader| read_msgpack(reader));
+    }
+
+    #[test]
+    fn reads_back_cbor() {
+        assert_round_trips(Encoding::Cbor, |reader| read_cbor(reader));
+    }
+
+    #[test]
+    fn rejects_truncated_samples() {
+        let mut buf = Vec::new();
+        Encoding::Cbor.encode(&sample(), &mut buf).unwrap();
+        buf.truncate(buf.len() - 3);
+        let result = read_cbor(&mut &buf[..]);
+        assert!(result.is_err_and(|e| e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+}
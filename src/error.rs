@@ -0,0 +1,63 @@
+use crate::config::ConfigError;
+use crate::device_settings::SettingError;
+use crate::watchdog::WatchdogError;
+use nvml_wrapper::error::NvmlError;
+use std::io;
+
+/// Errors returned by the symon library.
+#[derive(Debug, thiserror::Error)]
+pub enum SymonError {
+    #[error("NVML error: {0}")]
+    Nvml(#[from] NvmlError),
+    #[error("NVML is not available")]
+    NvmlUnavailable,
+    #[error(transparent)]
+    Sampling(#[from] WatchdogError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Setting(#[from] SettingError),
+    #[error("invalid sink: {0}")]
+    Sink(String),
+    #[error("failed to serialize sample: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl SymonError {
+    /// Whether NVML timed out, as opposed to failing outright.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, SymonError::Sampling(e) if e.is_timeout())
+    }
+
+    /// Whether NVML couldn't be loaded at all, e.g. on a host without the
+    /// NVIDIA driver or with the library but not the kernel module, as opposed
+    /// to failing once loaded.
+    pub fn is_nvml_missing(&self) -> bool {
+        let missing = |e: &NvmlError| {
+            matches!(
+                e,
+                NvmlError::LibloadingError(_)
+                    | NvmlError::LibraryNotFound
+                    | NvmlError::DriverNotLoaded
+            )
+        };
+        match self {
+            SymonError::NvmlUnavailable => true,
+            SymonError::Nvml(e) | SymonError::Sampling(WatchdogError::Nvml(e)) => missing(e),
+            _ => false,
+        }
+    }
+}
+
+pub type Result<T, E = SymonError> = std::result::Result<T, E>;
+
+/// Report an error to Sentry, if symon was built with the `sentry` feature
+/// and error reporting is enabled.
+pub fn report<E: std::error::Error + ?Sized>(error: &E) {
+    #[cfg(feature = "sentry")]
+    sentry::capture_error(error);
+    #[cfg(not(feature = "sentry"))]
+    let _ = error;
+}
@@ -0,0 +1,223 @@
+use crate::config::ConfigError;
+use crate::metrics::Metrics;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Changes smaller than this aren't worth an NVML call.
+const MIN_CHANGE_PERCENT: u32 = 2;
+
+/// Fan speed as a function of GPU temperature, read from `--fan-curve`.
+///
+/// Speeds are interpolated linearly between points and clamped to
+/// `[min_percent, max_percent]` as well as to what the device accepts. At or
+/// above `critical_temp`, and whenever the temperature can't be read, fans
+/// are handed back to the driver.
+///
+/// ```json
+/// {"points": [[40, 30], [60, 50], [75, 80], [83, 100]], "min_percent": 30}
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FanCurve {
+    /// `[temperature in Celsius, fan speed in percent]`, by increasing temperature.
+    pub points: Vec<[f64; 2]>,
+    #[serde(default = "default_min_percent")]
+    pub min_percent: u32,
+    #[serde(default = "default_max_percent")]
+    pub max_percent: u32,
+    #[serde(default = "default_critical_temp")]
+    pub critical_temp: f64,
+    /// GPU indices to control; all if unset.
+    #[serde(default)]
+    pub gpus: Option<Vec<u32>>,
+}
+
+fn default_min_percent() -> u32 {
+    30
+}
+
+fn default_max_percent() -> u32 {
+    100
+}
+
+fn default_critical_temp() -> f64 {
+    85.0
+}
+
+impl FanCurve {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let curve: FanCurve = serde_json::from_slice(&contents)
+            .map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+        let invalid = |message: &str| ConfigError::Invalid(path.to_path_buf(), message.to_string());
+        if curve.points.is_empty() {
+            return Err(invalid("points must not be empty"));
+        }
+        if curve.points.windows(2).any(|pair| pair[0][0] >= pair[1][0]) {
+            return Err(invalid("points must be sorted by increasing temperature"));
+        }
+        if curve
+            .points
+            .iter()
+            .any(|&[_, speed]| !(0.0..=100.0).contains(&speed))
+        {
+            return Err(invalid("fan speeds must be between 0 and 100"));
+        }
+        if curve.min_percent > curve.max_percent || curve.max_percent > 100 {
+            return Err(invalid("expected min_percent <= max_percent <= 100"));
+        }
+        Ok(curve)
+    }
+
+    pub fn applies_to(&self, gpu: u32) -> bool {
+        self.gpus.as_ref().is_none_or(|gpus| gpus.contains(&gpu))
+    }
+
+    /// The fan speed in percent for `temp`, or `None` to leave the fans to the
+    /// driver.
+    pub fn speed_for(&self, temp: Option<f64>) -> Option<u32> {
+        let temp = temp.filter(|&temp| temp < self.critical_temp)?;
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        let speed = if temp <= first[0] {
+            first[1]
+        } else if temp >= last[0] {
+            last[1]
+        } else {
+            let i = self.points.iter().position(|point| point[0] > temp)?;
+            let ([t0, s0], [t1, s1]) = (self.points[i - 1], self.points[i]);
+            s0 + (s1 - s0) * (temp - t0) / (t1 - t0)
+        };
+        Some((speed.round() as u32).clamp(self.min_percent, self.max_percent))
+    }
+}
+
+/// A fan speed to apply; `None` hands the fans back to the driver.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FanChange {
+    pub gpu: u32,
+    pub percent: Option<u32>,
+}
+
+/// Evaluates a `FanCurve` against samples, remembering which GPUs' fans are
+/// under manual control so they can be handed back.
+pub struct FanController {
+    curve: FanCurve,
+    manual: HashMap<u32, u32>,
+}
+
+impl FanController {
+    pub fn new(curve: FanCurve) -> Self {
+        FanController {
+            curve,
+            manual: HashMap::new(),
+        }
+    }
+
+    /// Replace the curve. Returns changes for GPUs the new curve no longer covers.
+    pub fn set_curve(&mut self, curve: FanCurve) -> Vec<FanChange> {
+        self.curve = curve;
+        let released: Vec<u32> = self
+            .manual
+            .keys()
+            .copied()
+            .filter(|&gpu| !self.curve.applies_to(gpu))
+            .collect();
+        released
+            .into_iter()
+            .map(|gpu| {
+                self.manual.remove(&gpu);
+                FanChange { gpu, percent: None }
+            })
+            .collect()
+    }
+
+    /// Work out the fan speeds `metrics` calls for, returning those that
+    /// differ from what was last applied. The changes are assumed to be applied.
+    /// A sample without GPUs hands all fans back.
+    pub fn evaluate(&mut self, metrics: &Metrics) -> Vec<FanChange> {
+        let Some(gpu_count) = metrics.get("_gpu.count").and_then(|v| v.as_u64()) else {
+            return self.release_all();
+        };
+        let mut changes = Vec::new();
+        for gpu in (0..gpu_count as u32).filter(|&gpu| self.curve.applies_to(gpu)) {
+            let temp = metrics
+                .get(&format!("gpu.{}.temp", gpu))
+                .and_then(|v| v.as_f64());
+            let target = self.curve.speed_for(temp);
+            let changed = match (self.manual.get(&gpu), target) {
+                (Some(&current), Some(target)) => current.abs_diff(target) >= MIN_CHANGE_PERCENT,
+                (current, target) => current.is_some() || target.is_some(),
+            };
+            if !changed {
+                continue;
+            }
+            match target {
+                Some(percent) => self.manual.insert(gpu, percent),
+                None => self.manual.remove(&gpu),
+            };
+            changes.push(FanChange {
+                gpu,
+                percent: target,
+            });
+        }
+        changes
+    }
+
+    /// Hand all fans under manual control back to the driver.
+    pub fn release_all(&mut self) -> Vec<FanChange> {
+        self.manual
+            .drain()
+            .map(|(gpu, _)| FanChange { gpu, percent: None })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve() -> FanCurve {
+        FanCurve {
+            points: vec![[40.0, 30.0], [80.0, 70.0]],
+            min_percent: 30,
+            max_percent: 100,
+            critical_temp: 85.0,
+            gpus: None,
+        }
+    }
+
+    #[test]
+    fn interpolates_and_hands_back_when_critical() {
+        let curve = curve();
+        assert_eq!(curve.speed_for(Some(20.0)), Some(30));
+        assert_eq!(curve.speed_for(Some(60.0)), Some(50));
+        assert_eq!(curve.speed_for(Some(85.0)), None);
+        assert_eq!(curve.speed_for(None), None);
+    }
+
+    #[test]
+    fn hands_fans_back_without_readings() {
+        let mut fans = FanController::new(curve());
+        let mut metrics = Metrics::new();
+        metrics.add_metric("_gpu.count", 1);
+        metrics.add_metric("gpu.0.temp", 60);
+        assert_eq!(
+            fans.evaluate(&metrics),
+            [FanChange {
+                gpu: 0,
+                percent: Some(50)
+            }]
+        );
+        assert_eq!(fans.evaluate(&metrics), []);
+        assert_eq!(
+            fans.evaluate(&Metrics::new()),
+            [FanChange {
+                gpu: 0,
+                percent: None
+            }]
+        );
+    }
+}
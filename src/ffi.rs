@@ -0,0 +1,212 @@
+//! C ABI for embedding symon in C, C++ and Go programs. See `include/symon.h`.
+//!
+//! All functions are safe to call from any thread. Errors are reported by a
+//! null or negative return value, with a description available from
+//! `symon_last_error` on the calling thread.
+
+use crate::sampler::Sampler;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Called with each sample as a NUL-terminated JSON object. The string is
+/// only valid for the duration of the call.
+pub type SymonCallback = extern "C" fn(json: *const c_char, user_data: *mut c_void);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: impl ToString) {
+    let message = CString::new(message.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+struct Subscription {
+    running: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+/// Opaque handle returned by `symon_init`.
+pub struct SymonHandle {
+    sampler: Arc<Mutex<Sampler>>,
+    subscription: Mutex<Option<Subscription>>,
+}
+
+/// Raw pointers are only passed through to the caller's callback.
+struct UserData(*mut c_void);
+
+// SAFETY: the caller of `symon_set_callback` guarantees that `user_data` may
+// be used from the sampling thread
+unsafe impl Send for UserData {}
+
+fn sample_json(sampler: &mut Sampler, pid: c_int) -> Result<CString, String> {
+    sampler.set_pid(pid);
+    let sample = sampler.sample().map_err(|e| e.to_string())?;
+    let json = serde_json::to_vec(&sample).map_err(|e| e.to_string())?;
+    CString::new(json).map_err(|e| e.to_string())
+}
+
+/// Run `f`, converting panics into an error so they don't unwind into C.
+fn guard<T>(on_error: T, f: impl FnOnce() -> Result<T, String>) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(e)) => {
+            set_last_error(e);
+            on_error
+        }
+        Err(_) => {
+            set_last_error("internal error: symon panicked");
+            on_error
+        }
+    }
+}
+
+/// Initialize NVML. Returns null on failure.
+#[no_mangle]
+pub extern "C" fn symon_init() -> *mut SymonHandle {
+    guard(ptr::null_mut(), || {
+        let sampler = Sampler::new().map_err(|e| e.to_string())?;
+        let handle = SymonHandle {
+            sampler: Arc::new(Mutex::new(sampler)),
+            subscription: Mutex::new(None),
+        };
+        Ok(Box::into_raw(Box::new(handle)))
+    })
+}
+
+/// Take a sample and return it as a JSON object. `pid` selects the process
+/// whose GPU usage is reported separately; pass 0 for none.
+///
+/// The result must be released with `symon_free`. Returns null on failure.
+///
+/// # Safety
+///
+/// `handle` must have been returned by `symon_init` and not yet passed to
+/// `symon_shutdown`.
+#[no_mangle]
+pub unsafe extern "C" fn symon_sample_json(handle: *mut SymonHandle, pid: c_int) -> *mut c_char {
+    guard(ptr::null_mut(), || {
+        // SAFETY: guaranteed by the caller
+        let handle = unsafe { handle.as_ref() }.ok_or("null handle")?;
+        let mut sampler = handle.sampler.lock().map_err(|e| e.to_string())?;
+        Ok(sample_json(&mut sampler, pid)?.into_raw())
+    })
+}
+
+/// Release a string returned by symon. Null is ignored.
+///
+/// # Safety
+///
+/// `s` must have been returned by symon and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn symon_free(s: *mut c_char) {
+    if !s.is_null() {
+        // SAFETY: guaranteed by the caller
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
+
+/// Sample every `interval_ms` milliseconds on a background thread and pass
+/// each sample to `callback`. Replaces a previously set callback; a null
+/// callback stops sampling. Returns 0 on success, -1 on failure.
+///
+/// # Safety
+///
+/// `handle` must be valid as for `symon_sample_json`, and `user_data` must be
+/// safe to use from another thread until the callback is replaced or the
+/// handle is shut down.
+#[no_mangle]
+pub unsafe extern "C" fn symon_set_callback(
+    handle: *mut SymonHandle,
+    interval_ms: u32,
+    pid: c_int,
+    callback: Option<SymonCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    guard(-1, || {
+        // SAFETY: guaranteed by the caller
+        let handle = unsafe { handle.as_ref() }.ok_or("null handle")?;
+        let mut subscription = handle.subscription.lock().map_err(|e| e.to_string())?;
+        if let Some(previous) = subscription.take() {
+            previous.running.store(false, Ordering::Relaxed);
+            let _ = previous.thread.join();
+        }
+        let Some(callback) = callback else {
+            return Ok(0);
+        };
+        if interval_ms == 0 {
+            return Err("interval_ms must be positive".to_string());
+        }
+
+        let running = Arc::new(AtomicBool::new(true));
+        let sampler = handle.sampler.clone();
+        let user_data = UserData(user_data);
+        let interval = Duration::from_millis(u64::from(interval_ms));
+        let thread = thread::Builder::new()
+            .name("symon-callback".to_string())
+            .spawn({
+                let running = running.clone();
+                move || {
+                    let user_data = user_data;
+                    while running.load(Ordering::Relaxed) {
+                        let start = Instant::now();
+                        let sample = match sampler.lock() {
+                            Ok(mut sampler) => sample_json(&mut sampler, pid),
+                            Err(e) => Err(e.to_string()),
+                        };
+                        if let Ok(json) = sample {
+                            callback(json.as_ptr(), user_data.0);
+                        }
+                        // Sleep in short steps so replacing the callback is prompt
+                        while running.load(Ordering::Relaxed) && start.elapsed() < interval {
+                            thread::sleep(
+                                (interval - start.elapsed()).min(Duration::from_millis(50)),
+                            );
+                        }
+                    }
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        *subscription = Some(Subscription { running, thread });
+        Ok(0)
+    })
+}
+
+/// Stop any callback, shut down NVML and free the handle.
+///
+/// # Safety
+///
+/// `handle` must have been returned by `symon_init` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn symon_shutdown(handle: *mut SymonHandle) {
+    if handle.is_null() {
+        return;
+    }
+    guard((), || {
+        // SAFETY: guaranteed by the caller
+        let handle = unsafe { Box::from_raw(handle) };
+        let subscription = handle.subscription.into_inner().ok().flatten();
+        if let Some(subscription) = subscription {
+            subscription.running.store(false, Ordering::Relaxed);
+            let _ = subscription.thread.join();
+        }
+        let sampler = Arc::try_unwrap(handle.sampler)
+            .map_err(|_| "sampler still in use")?
+            .into_inner()
+            .map_err(|e| e.to_string())?;
+        sampler.shutdown().map_err(|e| e.to_string())
+    })
+}
+
+/// Description of the last error on the calling thread, or null. The string
+/// is valid until the next symon call on this thread.
+#[no_mangle]
+pub extern "C" fn symon_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_deref().map_or(ptr::null(), CStr::as_ptr))
+}
@@ -0,0 +1,287 @@
+use crate::metrics::{Metrics, SampleTime};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Why samples are missing between two records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GapReason {
+    /// Samples were taken but dropped because the writer fell behind.
+    Dropped,
+    /// The loop overran its interval, e.g. NVML took longer than an interval,
+    /// and skipped at least one sample.
+    Overrun,
+    /// Sampling was paused.
+    Paused,
+    /// A sink accepted samples but lost them afterwards, e.g. the oldest
+    /// output of a stalled stdout reader or a batch a collector rejected.
+    Lost,
+}
+
+impl GapReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GapReason::Dropped => "dropped",
+            GapReason::Overrun => "overrun",
+            GapReason::Paused => "paused",
+            GapReason::Lost => "lost",
+        }
+    }
+}
+
+fn epoch_seconds(time: SampleTime) -> f64 {
+    time.wall
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// A `gap` record marking `missed` samples of `reason` between `start` and
+/// `end`.
+fn gap_record(reason: GapReason, start: Option<f64>, end: Option<f64>, missed: u64) -> Metrics {
+    let mut record = Metrics::new();
+    record.add_metric("_record", "gap");
+    record.add_metric("_gap_reason", reason.as_str());
+    if let Some(start) = start {
+        record.add_metric("_gap_start", start);
+    }
+    if let Some(end) = end {
+        record.add_metric("_gap_end", end);
+    }
+    record.add_metric("_gap_missed_samples", missed);
+    record
+}
+
+/// Tracks the samples handed to the writer and yields a `gap` record when
+/// samples are missing, so downstream analytics can tell a monitoring gap
+/// from an idle GPU. A gap record carries the time of the last record before
+/// the gap (`_gap_start`) and of the sample after it (`_gap_end`, also its
+/// `_timestamp`), the reason, and how many samples are missing.
+///
+/// A stretch of `run_end` to `run_start`, e.g. outside `--active-window`, is
+/// marked by those records and isn't a gap, see `reset`.
+///
+/// Samples a sink loses after accepting them are marked in that sink's output
+/// alone, see `SampleSpan`.
+pub struct GapTracker {
+    interval: Duration,
+    /// Time of the last sample (or gap record) handed to the writer, and the
+    /// interval it was taken with.
+    last: Option<(SampleTime, Duration)>,
+    reason: Option<GapReason>,
+    dropped: u64,
+}
+
+impl GapTracker {
+    pub fn new(interval: Duration) -> Self {
+        GapTracker {
+            interval,
+            last: None,
+            reason: None,
+            dropped: 0,
+        }
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// The gap record for missing samples before the sample taken at `time`,
+    /// to be submitted before it. Nothing changes until `delivered`.
+    pub fn check(&self, time: SampleTime) -> Option<Metrics> {
+        let (last, last_interval) = self.last?;
+        let elapsed = time.uptime.saturating_sub(last.uptime);
+        // After the interval changed, the sample may be due after either one
+        let interval = self.interval.max(last_interval);
+        let intervals = match interval.as_secs_f64() {
+            interval if interval > 0.0 => (elapsed.as_secs_f64() / interval).floor() as u64,
+            _ => 0,
+        };
+        let (reason, missed) = match self.reason {
+            Some(GapReason::Dropped) => (GapReason::Dropped, self.dropped),
+            Some(reason) => (reason, intervals.saturating_sub(1)),
+            // At least one whole interval without a sample
+            None if intervals >= 2 => (GapReason::Overrun, intervals - 1),
+            None => return None,
+        };
+        let mut record = gap_record(
+            reason,
+            Some(epoch_seconds(last)),
+            Some(epoch_seconds(time)),
+            missed,
+        );
+        record.set_time(time);
+        Some(record)
+    }
+
+    /// A sample or gap record taken at `time` was handed to the writer.
+    pub fn delivered(&mut self, time: SampleTime) {
+        self.last = Some((time, self.interval));
+        self.reason = None;
+        self.dropped = 0;
+    }
+
+    /// A sample was dropped because the writer's queue was full.
+    pub fn dropped(&mut self) {
+        self.reason = Some(GapReason::Dropped);
+        self.dropped += 1;
+    }
+
+    /// Sampling was paused; the gap is reported once it resumes.
+    pub fn paused(&mut self) {
+        if self.reason.is_none() {
+            self.reason = Some(GapReason::Paused);
+        }
+    }
+
+    /// Forget the last sample, e.g. when recording stops at the end of an
+    /// active window, so the time until it starts again isn't a gap.
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.reason = None;
+        self.dropped = 0;
+    }
+}
+
+/// The samples in some output of a sink, to mark them with a gap record in
+/// that sink's output should it lose them after accepting them, see
+/// `Sink::take_lost`. Such a record has the reason `lost` and carries the
+/// times of the first and last lost sample as `_gap_start` and `_gap_end`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SampleSpan {
+    /// `_timestamp` of the oldest and newest sample.
+    first: Option<f64>,
+    last: Option<f64>,
+    count: u64,
+}
+
+impl SampleSpan {
+    /// Add a sample.
+    pub fn push(&mut self, metrics: &Metrics) {
+        let timestamp = metrics.timestamp();
+        self.extend(&SampleSpan {
+            first: timestamp,
+            last: timestamp,
+            count: 1,
+        });
+    }
+
+    /// Add the samples of `other`.
+    pub fn extend(&mut self, other: &SampleSpan) {
+        self.first = match (self.first, other.first) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.last = match (self.last, other.last) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        self.count += other.count;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// The gap record for these samples, lost by a sink, to be written to it
+    /// before `next`.
+    pub fn lost_record(&self, next: &Metrics) -> Metrics {
+        let mut record = gap_record(GapReason::Lost, self.first, self.last, self.count);
+        match (next.time(), next.timestamp()) {
+            (Some(time), _) => record.set_time(time),
+            (None, Some(timestamp)) => record.add_metric("_timestamp", timestamp),
+            (None, None) => {}
+        }
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: f64) -> SampleTime {
+        SampleTime {
+            wall: UNIX_EPOCH + Duration::from_secs_f64(1000.0 + secs),
+            uptime: Duration::from_secs_f64(secs),
+        }
+    }
+
+    fn field(record: &Metrics, key: &str) -> Option<f64> {
+        record.get(key).and_then(|v| v.as_f64())
+    }
+
+    #[test]
+    fn marks_overruns_but_not_late_samples() {
+        let mut gaps = GapTracker::new(Duration::from_secs(1));
+        assert!(gaps.check(at(0.0)).is_none());
+        gaps.delivered(at(0.0));
+        assert!(gaps.check(at(1.9)).is_none());
+        let gap = gaps.check(at(3.5)).expect("two intervals were skipped");
+        assert_eq!(
+            gap.get("_gap_reason").and_then(|v| v.as_str()),
+            Some("overrun")
+        );
+        assert_eq!(field(&gap, "_gap_missed_samples"), Some(2.0));
+        assert_eq!(field(&gap, "_gap_start"), Some(1000.0));
+        assert_eq!(field(&gap, "_gap_end"), Some(1003.5));
+    }
+
+    #[test]
+    fn a_changed_interval_is_not_an_overrun() {
+        let mut gaps = GapTracker::new(Duration::from_secs(10));
+        gaps.delivered(at(0.0));
+        gaps.set_interval(Duration::from_secs(1));
+        assert!(gaps.check(at(10.0)).is_none());
+        gaps.delivered(at(10.0));
+        assert!(gaps.check(at(11.0)).is_none());
+        gaps.delivered(at(11.0));
+        gaps.set_interval(Duration::from_secs(10));
+        assert!(gaps.check(at(21.0)).is_none());
+    }
+
+    #[test]
+    fn counts_dropped_samples_until_delivered() {
+        let mut gaps = GapTracker::new(Duration::from_secs(1));
+        gaps.delivered(at(0.0));
+        gaps.dropped();
+        gaps.dropped();
+        let gap = gaps.check(at(3.0)).expect("samples were dropped");
+        assert_eq!(
+            gap.get("_gap_reason").and_then(|v| v.as_str()),
+            Some("dropped")
+        );
+        assert_eq!(field(&gap, "_gap_missed_samples"), Some(2.0));
+        gaps.delivered(at(3.0));
+        assert!(gaps.check(at(4.0)).is_none());
+        gaps.reset();
+        assert!(gaps.check(at(100.0)).is_none());
+    }
+
+    #[test]
+    fn marks_lost_samples_with_their_times() {
+        let mut lost = SampleSpan::default();
+        for secs in [2.0, 1.0] {
+            let mut metrics = Metrics::new();
+            metrics.set_time(at(secs));
+            lost.push(&metrics);
+        }
+        let mut more = SampleSpan::default();
+        more.extend(&lost);
+        assert_eq!(more.count(), 2);
+
+        let mut next = Metrics::new();
+        next.set_time(at(5.0));
+        let record = more.lost_record(&next);
+        assert_eq!(
+            record.get("_gap_reason").and_then(|v| v.as_str()),
+            Some("lost")
+        );
+        assert_eq!(field(&record, "_gap_start"), Some(1001.0));
+        assert_eq!(field(&record, "_gap_end"), Some(1002.0));
+        assert_eq!(field(&record, "_gap_missed_samples"), Some(2.0));
+        assert_eq!(record.timestamp(), Some(1005.0));
+    }
+}
@@ -0,0 +1,195 @@
+//! Profiling metrics from NVML's GPU Performance Monitoring (GPM), the same
+//! SM, tensor core and DRAM activity DCGM reports, on Hopper and newer GPUs
+//! without DCGM, and a breakdown of activity by pipe (FP64, FP32, FP16,
+//! integer and tensor core precisions) which DCGM's default fields lack.
+
+use crate::manifest::MetricInfo;
+use crate::metrics::Metrics;
+use crate::nvml_ext::{GpmSample, NvmlExt};
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::Device;
+use nvml_wrapper_sys::bindings::{
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_ANY_TENSOR_UTIL,
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_DFMA_TENSOR_UTIL,
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_DRAM_BW_UTIL, nvmlGpmMetricId_t_NVML_GPM_METRIC_FP16_UTIL,
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_FP32_UTIL, nvmlGpmMetricId_t_NVML_GPM_METRIC_FP64_UTIL,
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_GRAPHICS_UTIL,
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_HMMA_TENSOR_UTIL,
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_IMMA_TENSOR_UTIL,
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_INTEGER_UTIL, nvmlGpmMetricId_t_NVML_GPM_METRIC_SM_OCCUPANCY,
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_SM_UTIL,
+};
+
+/// GPM metrics (already in percent) and their names. The first five are
+/// reported under the names of DCGM's equivalents, see `dcgm::DCGM_METRICS`;
+/// the pipe breakdown is described in `GPM_METRICS`.
+const METRICS: &[(u32, &str)] = &[
+    (
+        nvmlGpmMetricId_t_NVML_GPM_METRIC_GRAPHICS_UTIL,
+        "gpu.{}.graphicsActive",
+    ),
+    (nvmlGpmMetricId_t_NVML_GPM_METRIC_SM_UTIL, "gpu.{}.smActive"),
+    (
+        nvmlGpmMetricId_t_NVML_GPM_METRIC_SM_OCCUPANCY,
+        "gpu.{}.smOccupancy",
+    ),
+    (
+        nvmlGpmMetricId_t_NVML_GPM_METRIC_ANY_TENSOR_UTIL,
+        "gpu.{}.tensorActive",
+    ),
+    (
+        nvmlGpmMetricId_t_NVML_GPM_METRIC_DRAM_BW_UTIL,
+        "gpu.{}.dramActive",
+    ),
+    (
+        nvmlGpmMetricId_t_NVML_GPM_METRIC_FP64_UTIL,
+        "gpu.{}.fp64Active",
+    ),
+    (
+        nvmlGpmMetricId_t_NVML_GPM_METRIC_FP32_UTIL,
+        "gpu.{}.fp32Active",
+    ),
+    (
+        nvmlGpmMetricId_t_NVML_GPM_METRIC_FP16_UTIL,
+        "gpu.{}.fp16Active",
+    ),
+    (
+        nvmlGpmMetricId_t_NVML_GPM_METRIC_INTEGER_UTIL,
+        "gpu.{}.integerActive",
+    ),
+    (
+        nvmlGpmMetricId_t_NVML_GPM_METRIC_DFMA_TENSOR_UTIL,
+        "gpu.{}.tensorDfmaActive",
+    ),
+    (
+        nvmlGpmMetricId_t_NVML_GPM_METRIC_HMMA_TENSOR_UTIL,
+        "gpu.{}.tensorHmmaActive",
+    ),
+    (
+        nvmlGpmMetricId_t_NVML_GPM_METRIC_IMMA_TENSOR_UTIL,
+        "gpu.{}.tensorImmaActive",
+    ),
+];
+
+const GPM_NOTES: &str = "With --profiling, on Hopper and newer";
+
+/// Descriptions of the metrics only GPM reports, for `symon metrics`.
+pub const GPM_METRICS: &[MetricInfo] = &[
+    MetricInfo {
+        name: "gpu.{}.fp64Active",
+        unit: "%",
+        source: "nvmlGpmMetricsGet",
+        description: "Share of cycles the FP64 (double precision) pipe was active",
+        notes: GPM_NOTES,
+    },
+    MetricInfo {
+        name: "gpu.{}.fp32Active",
+        unit: "%",
+        source: "nvmlGpmMetricsGet",
+        description: "Share of cycles the FP32 (single precision) pipe was active",
+        notes: GPM_NOTES,
+    },
+    MetricInfo {
+        name: "gpu.{}.fp16Active",
+        unit: "%",
+        source: "nvmlGpmMetricsGet",
+        description: "Share of cycles the FP16 (half precision) pipe was active",
+        notes: GPM_NOTES,
+    },
+    MetricInfo {
+        name: "gpu.{}.integerActive",
+        unit: "%",
+        source: "nvmlGpmMetricsGet",
+        description: "Share of cycles the integer pipe was active",
+        notes: GPM_NOTES,
+    },
+    MetricInfo {
+        name: "gpu.{}.tensorDfmaActive",
+        unit: "%",
+        source: "nvmlGpmMetricsGet",
+        description: "Share of cycles the tensor cores were running FP64 math",
+        notes: GPM_NOTES,
+    },
+    MetricInfo {
+        name: "gpu.{}.tensorHmmaActive",
+        unit: "%",
+        source: "nvmlGpmMetricsGet",
+        description: "Share of cycles the tensor cores were running half precision math",
+        notes: GPM_NOTES,
+    },
+    MetricInfo {
+        name: "gpu.{}.tensorImmaActive",
+        unit: "%",
+        source: "nvmlGpmMetricsGet",
+        description: "Share of cycles the tensor cores were running integer math",
+        notes: GPM_NOTES,
+    },
+];
+
+/// Performance counters of one GPU. GPM metrics cover the time between two
+/// samples of the counters, so each sample is reported against the previous
+/// one and the first yields nothing.
+pub struct GpmCounters {
+    previous: GpmSample,
+    current: GpmSample,
+    primed: bool,
+    keys: Vec<&'static str>,
+}
+
+impl GpmCounters {
+    /// Allocate sample buffers for GPU `index`, or `None` if it doesn't
+    /// support GPM.
+    pub fn new(ext: &NvmlExt, device: &Device, index: u32) -> Option<Self> {
+        if !ext.gpm_supported(device).unwrap_or(false) {
+            return None;
+        }
+        let previous = ext.gpm_alloc().ok()?;
+        let current = match ext.gpm_alloc() {
+            Ok(current) => current,
+            Err(_) => {
+                ext.gpm_free(previous);
+                return None;
+            }
+        };
+        // GPUs are few and fixed, so their metric names are leaked like the
+        // per-device metric names
+        let keys = METRICS
+            .iter()
+            .map(|(_, name)| &*Box::leak(name.replace("{}", &index.to_string()).into_boxed_str()))
+            .collect();
+        Some(GpmCounters {
+            previous,
+            current,
+            primed: false,
+            keys,
+        })
+    }
+
+    /// Snapshot the counters and add the metrics since the previous call.
+    pub fn sample(
+        &mut self,
+        ext: &NvmlExt,
+        device: &Device,
+        metrics: &mut Metrics,
+    ) -> Result<(), NvmlError> {
+        ext.gpm_sample(device, &mut self.current)?;
+        if self.primed {
+            let ids: Vec<u32> = METRICS.iter().map(|(id, _)| *id).collect();
+            let values = ext.gpm_metrics(&self.previous, &self.current, &ids)?;
+            for (key, value) in self.keys.iter().zip(values) {
+                if let Some(value) = value {
+                    metrics.add_metric(*key, value);
+                }
+            }
+        }
+        std::mem::swap(&mut self.previous, &mut self.current);
+        self.primed = true;
+        Ok(())
+    }
+
+    /// Free the sample buffers. Must be called before NVML shuts down.
+    pub fn free(self, ext: &NvmlExt) {
+        ext.gpm_free(self.previous);
+        ext.gpm_free(self.current);
+    }
+}
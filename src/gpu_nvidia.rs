@@ -1,13 +1,925 @@
+use crate::device_settings::{DeviceSettings, SettingError};
+use crate::devices::{self, DeviceInfo, Devices};
+use crate::docker::ContainerNames;
+use crate::gpm::GpmCounters;
+use crate::kube::PodResolver;
+use crate::log;
+use crate::manifest::MetricInfo;
 use crate::metrics::Metrics;
-use nvml_wrapper::enum_wrappers::device::{Clock, TemperatureSensor};
+use crate::nvml_ext::NvmlExt;
+use crate::otel;
+use crate::processes;
+use crate::rollup;
+use crate::topology::Topology;
+use nvml_wrapper::bitmasks::device::ThrottleReasons;
+use nvml_wrapper::enum_wrappers::device::{
+    Brand, Clock, ComputeMode, EccCounter, MemoryError, MemoryLocation, PerformanceState,
+    RetirementCause, Sampling, TemperatureSensor,
+};
+use nvml_wrapper::enums::device::SampleValue;
 use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::struct_wrappers::device::ProcessInfo;
+use nvml_wrapper::structs::device::FieldId;
+use nvml_wrapper::sys_exports::field_id::{
+    NVML_FI_DEV_ECC_DBE_AGG_DEV, NVML_FI_DEV_ECC_DBE_VOL_TOTAL, NVML_FI_DEV_ECC_SBE_AGG_DEV,
+    NVML_FI_DEV_ECC_SBE_VOL_TOTAL, NVML_FI_DEV_MEMORY_TEMP, NVML_FI_DEV_PCIE_REPLAY_COUNTER,
+    NVML_FI_DEV_REMAPPED_COR, NVML_FI_DEV_REMAPPED_FAILURE, NVML_FI_DEV_REMAPPED_PENDING,
+    NVML_FI_DEV_REMAPPED_UNC, NVML_FI_DEV_RETIRED_DBE, NVML_FI_DEV_RETIRED_PENDING,
+    NVML_FI_DEV_RETIRED_SBE, NVML_FI_DEV_TOTAL_ENERGY_CONSUMPTION,
+};
 use nvml_wrapper::{Device, Nvml};
+use serde_json::{json, Value};
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(feature = "processes")]
 use sysinfo::{Pid, System};
 
+macro_rules! device_keys {
+    ($($field:ident => $fmt:literal [$unit:literal, $source:literal $(, $notes:literal)?] $description:literal),* $(,)?) => {
+        /// Metric names for a single device.
+        ///
+        /// Formatting `gpu.{i}.*` keys on every sample is a measurable source of
+        /// allocation churn at high sampling rates, so they are formatted once per
+        /// device index and shared for the lifetime of the program.
+        // Some metrics, e.g. persistence mode, are only sampled on Linux
+        #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+        struct DeviceKeys {
+            $($field: &'static str,)*
+        }
+
+        impl DeviceKeys {
+            fn new(di: u32) -> Self {
+                DeviceKeys {
+                    $($field: Box::leak(format!($fmt, di).into_boxed_str()),)*
+                }
+            }
+        }
+
+        /// Descriptions of the per-device metrics, for `symon metrics`.
+        pub const DEVICE_METRICS: &[MetricInfo] = &[
+            $(MetricInfo {
+                name: $fmt,
+                unit: $unit,
+                source: $source,
+                description: $description,
+                notes: device_keys!(@notes $($notes)?),
+            },)*
+        ];
+    };
+    (@notes) => { "" };
+    (@notes $notes:literal) => { $notes };
+}
+
+device_keys! {
+    gpu => "gpu.{}.gpu" ["%", "nvmlDeviceGetUtilizationRates"]
+        "Share of time one or more kernels were running during the driver's latest sample period (_gpu.{i}.utilizationPeriodMs), not the whole interval; see _gpu.{i}.gpuMean",
+    memory => "gpu.{}.memory" ["%", "nvmlDeviceGetUtilizationRates"]
+        "Share of time device memory was being read or written during the driver's latest sample period",
+    utilization_period_ms => "_gpu.{}.utilizationPeriodMs" ["ms", "nvmlDeviceGetSamples"]
+        "How often the driver samples utilization, 1/6 to 1 s depending on the GPU; intervals shorter than this repeat readings",
+    memory_total => "_gpu.{}.memoryTotal" ["bytes", "nvmlDeviceGetMemoryInfo"]
+        "Total device memory",
+    memory_allocated => "gpu.{}.memoryAllocated" ["%", "nvmlDeviceGetMemoryInfo"]
+        "Share of device memory allocated",
+    memory_allocated_bytes => "gpu.{}.memoryAllocatedBytes" ["bytes", "nvmlDeviceGetMemoryInfo"]
+        "Device memory allocated",
+    compute_process_count => "gpu.{}.computeProcessCount" ["", "nvmlDeviceGetComputeRunningProcesses"]
+        "Number of processes with a compute context on the GPU",
+    graphics_process_count => "gpu.{}.graphicsProcessCount" ["", "nvmlDeviceGetGraphicsRunningProcesses"]
+        "Number of processes with a graphics context on the GPU",
+    temp => "gpu.{}.temp" ["Celsius", "nvmlDeviceGetTemperature"]
+        "GPU core temperature",
+    memory_temp => "gpu.{}.memoryTemp" ["Celsius", "nvmlDeviceGetFieldValues", "Driver 450 and newer, on GPUs with HBM"]
+        "Device memory temperature",
+    power_watts => "gpu.{}.powerWatts" ["W", "nvmlDeviceGetPowerUsage"]
+        "Power draw of the board",
+    enforced_power_limit_watts => "gpu.{}.enforcedPowerLimitWatts" ["W", "nvmlDeviceGetEnforcedPowerLimit"]
+        "Power limit the driver enforces",
+    power_percent => "gpu.{}.powerPercent" ["%", "nvmlDeviceGetPowerUsage"]
+        "Power draw as a share of the enforced power limit",
+    gpu_max => "_gpu.{}.gpuMax" ["%", "nvmlDeviceGetSamples"]
+        "Highest utilization among the driver's samples since the previous sample",
+    gpu_mean => "_gpu.{}.gpuMean" ["%", "nvmlDeviceGetSamples"]
+        "Mean utilization of the driver's samples since the previous sample, covering the whole interval",
+    power_watts_max => "_gpu.{}.powerWattsMax" ["W", "nvmlDeviceGetSamples"]
+        "Highest power draw among the driver's samples since the previous sample",
+    power_watts_mean => "_gpu.{}.powerWattsMean" ["W", "nvmlDeviceGetSamples"]
+        "Mean power draw of the driver's samples since the previous sample",
+    name => "_gpu.{}.name" ["", "nvmlDeviceGetName"]
+        "Product name, e.g. Tesla T4",
+    sm_clock => "_gpu.{}.smClock" ["MHz", "nvmlDeviceGetClockInfo"]
+        "Current SM clock",
+    memory_clock => "_gpu.{}.memoryClock" ["MHz", "nvmlDeviceGetClockInfo"]
+        "Current memory clock",
+    memory_bandwidth_estimate => "_gpu.{}.memoryBandwidthEstimate" ["%", "nvmlDeviceGetUtilizationRates", "Estimate; prefer gpu.{i}.dramActive from --backend dcgm where available"]
+        "Estimated share of peak memory bandwidth in use: memory utilization scaled by the current over the maximum memory clock",
+    memory_bandwidth_estimate_bytes => "_gpu.{}.memoryBandwidthEstimateBytesPerSecond" ["bytes/s", "nvmlDeviceGetMemoryBusWidth", "Estimate, assuming double data rate memory; prefer gpu.{i}.dramActive from --backend dcgm where available"]
+        "Estimated memory bandwidth in use, from memory utilization, the current memory clock and the memory bus width",
+    graphics_clock => "_gpu.{}.graphicsClock" ["MHz", "nvmlDeviceGetClockInfo"]
+        "Current graphics clock",
+    pstate => "_gpu.{}.pstate" ["", "nvmlDeviceGetPerformanceState"]
+        "Performance state, 0 (fastest) to 15",
+    throttle_reasons => "_gpu.{}.throttleReasons" ["", "nvmlDeviceGetCurrentClocksThrottleReasons"]
+        "Why clocks are currently reduced, e.g. swPowerCap; empty if they aren't",
+    corrected_memory_errors => "_gpu.{}.correctedMemoryErrors" ["", "nvmlDeviceGetFieldValues", "Only on GPUs with ECC memory, e.g. data center GPUs"]
+        "Corrected ECC errors over the GPU's lifetime",
+    uncorrected_memory_errors => "_gpu.{}.uncorrectedMemoryErrors" ["", "nvmlDeviceGetFieldValues", "Only on GPUs with ECC memory, e.g. data center GPUs"]
+        "Uncorrected ECC errors over the GPU's lifetime",
+    corrected_volatile_memory_errors => "_gpu.{}.correctedVolatileMemoryErrors" ["", "nvmlDeviceGetFieldValues", "Only on GPUs with ECC memory, e.g. data center GPUs"]
+        "Corrected ECC errors since the last driver reload or GPU reset",
+    uncorrected_volatile_memory_errors => "_gpu.{}.uncorrectedVolatileMemoryErrors" ["", "nvmlDeviceGetFieldValues", "Only on GPUs with ECC memory, e.g. data center GPUs"]
+        "Uncorrected ECC errors since the last driver reload or GPU reset",
+    remapped_rows_correctable => "_gpu.{}.remappedRowsCorrectable" ["", "nvmlDeviceGetFieldValues", "Driver 460 and newer, on Ampere and newer GPUs with ECC memory"]
+        "Memory rows remapped after correctable errors",
+    remapped_rows_uncorrectable => "_gpu.{}.remappedRowsUncorrectable" ["", "nvmlDeviceGetFieldValues", "Driver 460 and newer, on Ampere and newer GPUs with ECC memory"]
+        "Memory rows remapped after uncorrectable errors",
+    remapped_rows_pending => "_gpu.{}.remappedRowsPending" ["", "nvmlDeviceGetFieldValues", "Driver 460 and newer, on Ampere and newer GPUs with ECC memory"]
+        "Whether a remapping waits for the GPU to be reset",
+    remapped_rows_failed => "_gpu.{}.remappedRowsFailed" ["", "nvmlDeviceGetFieldValues", "Driver 460 and newer, on Ampere and newer GPUs with ECC memory"]
+        "Whether a remapping failed; the GPU should be replaced",
+    retired_pages => "_gpu.{}.retiredPages" ["", "nvmlDeviceGetFieldValues", "Before Ampere, on GPUs with ECC memory"]
+        "Memory pages retired after double-bit or repeated single-bit ECC errors",
+    retired_pages_pending => "_gpu.{}.retiredPagesPending" ["", "nvmlDeviceGetFieldValues", "Before Ampere, on GPUs with ECC memory"]
+        "Whether a page retirement waits for the driver to reload",
+    energy => "_gpu.{}.energyJoules" ["J", "nvmlDeviceGetFieldValues", "Volta and newer"]
+        "Energy consumed since the last driver reload",
+    pcie_replays => "_gpu.{}.pcieReplays" ["", "nvmlDeviceGetFieldValues"]
+        "PCIe replays since the last driver reload",
+    brand => "_gpu.{}.brand" ["", "nvmlDeviceGetBrand"]
+        "Brand, e.g. GeForce or Nvidia",
+    class => "_gpu.{}.class" ["", "nvmlDeviceGetBrand"]
+        "Product class from the brand: consumer, workstation, datacenter or unknown",
+    persistence_mode => "_gpu.{}.persistenceMode" ["", "nvmlDeviceGetPersistenceMode", "Linux only"]
+        "Whether the driver stays loaded without clients",
+    display_mode => "_gpu.{}.displayMode" ["", "nvmlDeviceGetDisplayMode"]
+        "Whether a display is connected to the GPU",
+    display_active => "_gpu.{}.displayActive" ["", "nvmlDeviceGetDisplayActive"]
+        "Whether the GPU is driving a display, e.g. a desktop session",
+    compute_mode => "_gpu.{}.computeMode" ["", "nvmlDeviceGetComputeMode"]
+        "Which contexts the GPU accepts: default, exclusiveThread, prohibited or exclusiveProcess",
+    fan_speed => "_gpu.{}.fanSpeed" ["%", "nvmlDeviceGetFanSpeed", "Not on passively cooled GPUs"]
+        "Speed of the first fan as a share of its maximum",
+    fan_target_speed => "_gpu.{}.fanTargetSpeed" ["%", "nvmlDeviceGetTargetFanSpeed", "Driver 460 and newer"]
+        "Fan speed the driver is aiming for",
+    fan_count => "_gpu.{}.fanCount" ["", "nvmlDeviceGetNumFans", "Not on passively cooled GPUs"]
+        "Number of fans",
+    xid_errors => "_gpu.{}.xidErrors" ["", "nvmlEventSetWait"]
+        "Critical XID errors since symon started",
+    last_xid => "_gpu.{}.lastXid" ["", "nvmlEventSetWait"]
+        "Most recent critical XID error code, e.g. 79 (fallen off the bus)",
+    pods => "_gpu.{}.pods" ["", "nvmlDeviceGetComputeRunningProcesses", "With --k8s, on Kubernetes nodes"]
+        "Memory (bytes) and SM utilization (%) per Kubernetes pod and container",
+    processes => "_gpu.{}.processes" ["", "nvmlDeviceGetProcessUtilization", "With --processes"]
+        "PID, type, user, command line, memory (bytes), SM and memory utilization (%) and container of each process",
+    users => "_gpu.{}.users" ["", "nvmlDeviceGetProcessUtilization", "With --users"]
+        "Process count, memory (bytes) and SM and memory utilization (%) per user",
+    virtualization_mode => "_gpu.{}.virtualizationMode" ["", "nvmlDeviceGetVirtualizationMode"]
+        "How the GPU is virtualized: none, passthrough, vgpu, hostVgpu or hostVsga",
+    licensed => "_gpu.{}.licensed" ["", "nvmlDeviceGetGridLicensableFeatures", "vGPU guests only"]
+        "Whether the vGPU software license is active",
+    license_product => "_gpu.{}.licenseProduct" ["", "nvmlDeviceGetGridLicensableFeatures", "vGPU guests only"]
+        "Licensed vGPU product",
+    license_expiry => "_gpu.{}.licenseExpiry" ["", "nvmlDeviceGetGridLicensableFeatures", "vGPU guests only"]
+        "When the vGPU software license expires",
+    fbc_sessions => "_gpu.{}.fbcSessions" ["", "nvmlDeviceGetFBCStats", "vGPU guests only"]
+        "Active frame buffer capture sessions",
+    fbc_fps => "_gpu.{}.fbcFps" ["fps", "nvmlDeviceGetFBCStats", "vGPU guests only"]
+        "Average frame rate of frame buffer capture",
+    fbc_latency_us => "_gpu.{}.fbcLatencyUs" ["us", "nvmlDeviceGetFBCStats", "vGPU guests only"]
+        "Average latency of frame buffer capture",
+    vgpus => "_gpu.{}.vgpus" ["", "nvmlDeviceGetActiveVgpus", "vGPU hosts only"]
+        "VM ID, type, frame buffer usage (bytes), utilization (%), frame rate limit and license state of each vGPU",
+    encoder_utilization => "_gpu.{}.encoderUtilization" ["%", "nvmlDeviceGetEncoderUtilization"]
+        "Video encoder utilization",
+    pcie_link_gen => "_gpu.{}.pcieLinkGen" ["", "nvmlDeviceGetCurrPcieLinkGeneration"]
+        "Current PCIe link generation",
+    pcie_link_speed => "_gpu.{}.pcieLinkSpeed" ["bit/s", "nvmlDeviceGetPcieSpeed"]
+        "Current PCIe link speed",
+    pcie_link_width => "_gpu.{}.pcieLinkWidth" ["", "nvmlDeviceGetCurrPcieLinkWidth"]
+        "Current PCIe link width (lanes)",
+    max_pcie_link_gen => "_gpu.{}.maxPcieLinkGen" ["", "nvmlDeviceGetMaxPcieLinkGeneration"]
+        "Highest PCIe link generation supported",
+    max_pcie_link_width => "_gpu.{}.maxPcieLinkWidth" ["", "nvmlDeviceGetMaxPcieLinkWidth"]
+        "Widest PCIe link supported (lanes)",
+    board_id => "_gpu.{}.boardId" ["", "nvmlDeviceGetBoardId", "Multi-GPU boards only"]
+        "ID of the board the GPU shares with others, see _board.{id}.*",
+    cuda_cores => "_gpu.{}.cudaCores" ["", "nvmlDeviceGetNumGpuCores", "Driver 520 and newer"]
+        "Number of CUDA cores",
+    architecture => "_gpu.{}.architecture" ["", "nvmlDeviceGetArchitecture"]
+        "Architecture, e.g. Ampere",
+    process_gpu => "gpu.process.{}.gpu" ["%", "nvmlDeviceGetUtilizationRates", "With --pid, while the process uses the GPU"]
+        "GPU utilization while the monitored process uses the GPU",
+    process_memory => "gpu.process.{}.memory" ["%", "nvmlDeviceGetUtilizationRates", "With --pid, while the process uses the GPU"]
+        "Memory utilization while the monitored process uses the GPU",
+    process_memory_allocated => "gpu.process.{}.memoryAllocated" ["%", "nvmlDeviceGetMemoryInfo", "With --pid, while the process uses the GPU"]
+        "Share of device memory allocated while the monitored process uses the GPU",
+    process_memory_allocated_bytes => "gpu.process.{}.memoryAllocatedBytes" ["bytes", "nvmlDeviceGetMemoryInfo", "With --pid, while the process uses the GPU"]
+        "Device memory allocated while the monitored process uses the GPU",
+    process_temp => "gpu.process.{}.temp" ["Celsius", "nvmlDeviceGetTemperature", "With --pid, while the process uses the GPU"]
+        "GPU temperature while the monitored process uses the GPU",
+    process_power_watts => "gpu.process.{}.powerWatts" ["W", "nvmlDeviceGetPowerUsage", "With --pid, while the process uses the GPU"]
+        "Power draw while the monitored process uses the GPU",
+    process_enforced_power_limit_watts => "gpu.process.{}.enforcedPowerLimitWatts" ["W", "nvmlDeviceGetEnforcedPowerLimit", "With --pid, while the process uses the GPU"]
+        "Enforced power limit while the monitored process uses the GPU",
+    process_power_percent => "gpu.process.{}.powerPercent" ["%", "nvmlDeviceGetPowerUsage", "With --pid, while the process uses the GPU"]
+        "Power draw as a share of the limit while the monitored process uses the GPU",
+    process_accounting_max_memory_bytes => "gpu.process.{}.accountingMaxMemoryBytes" ["bytes", "nvmlDeviceGetAccountingStats", "With --pid and accounting mode enabled"]
+        "Lifetime peak memory of the monitored processes",
+    process_accounting_gpu => "gpu.process.{}.accountingGpu" ["%", "nvmlDeviceGetAccountingStats", "With --pid and accounting mode enabled"]
+        "Lifetime GPU utilization of the monitored processes",
+    process_accounting_memory => "gpu.process.{}.accountingMemory" ["%", "nvmlDeviceGetAccountingStats", "With --pid and accounting mode enabled"]
+        "Lifetime memory utilization of the monitored processes",
+    process_accounting_time_ms => "gpu.process.{}.accountingTimeMs" ["ms", "nvmlDeviceGetAccountingStats", "With --pid and accounting mode enabled"]
+        "Time the monitored processes had a context on the GPU",
+}
+
+/// Get the metric names for devices `0..device_count`.
+///
+/// Names are cached process-wide, so re-initializing NVML doesn't format them again.
+fn device_keys(device_count: u32) -> Vec<&'static DeviceKeys> {
+    static KEYS: Mutex<Vec<&'static DeviceKeys>> = Mutex::new(Vec::new());
+
+    let mut keys = KEYS.lock().unwrap_or_else(|e| e.into_inner());
+    while keys.len() < device_count as usize {
+        let di = keys.len() as u32;
+        keys.push(Box::leak(Box::new(DeviceKeys::new(di))));
+    }
+    keys[..device_count as usize].to_vec()
+}
+
+/// Product class of a GPU, from its brand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceClass {
+    Consumer,
+    Workstation,
+    Datacenter,
+    Unknown,
+}
+
+impl DeviceClass {
+    pub fn of(brand: &Brand) -> Self {
+        match brand {
+            Brand::GeForce | Brand::GeForceRTX | Brand::Titan | Brand::TitanRTX => {
+                DeviceClass::Consumer
+            }
+            Brand::Quadro | Brand::QuadroRTX | Brand::NvidiaRTX | Brand::NVS => {
+                DeviceClass::Workstation
+            }
+            Brand::Tesla
+            | Brand::Nvidia
+            | Brand::GRID
+            | Brand::VApps
+            | Brand::VPC
+            | Brand::VCS
+            | Brand::VWS
+            | Brand::CloudGaming
+            | Brand::VGaming => DeviceClass::Datacenter,
+            Brand::Unknown => DeviceClass::Unknown,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeviceClass::Consumer => "consumer",
+            DeviceClass::Workstation => "workstation",
+            DeviceClass::Datacenter => "datacenter",
+            DeviceClass::Unknown => "unknown",
+        }
+    }
+
+    /// The optional metric groups GPUs of this class support. Querying the
+    /// others would fail on every sample.
+    fn groups(self) -> MetricGroups {
+        match self {
+            // No ECC memory, and accounting mode needs a fully supported product
+            DeviceClass::Consumer => MetricGroups {
+                ecc: false,
+                accounting: false,
+                ..MetricGroups::ALL
+            },
+            // Fans are probed per device, see `MetricGroups::probe`
+            DeviceClass::Datacenter | DeviceClass::Workstation | DeviceClass::Unknown => {
+                MetricGroups::ALL
+            }
+        }
+    }
+}
+
+/// Optional groups of device metrics, skipped on GPUs known not to support them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct MetricGroups {
+    /// Corrected and uncorrected memory errors.
+    ecc: bool,
+    /// Accounting statistics of the monitored processes.
+    accounting: bool,
+    /// Fan speeds.
+    fans: bool,
+}
+
+impl MetricGroups {
+    const ALL: MetricGroups = MetricGroups {
+        ecc: true,
+        accounting: true,
+        fans: true,
+    };
+
+    /// The groups of `class` that `device` supports. GPUs with data center
+    /// brands are mostly passively cooled, but the brand alone doesn't tell,
+    /// so fans are asked for rather than assumed.
+    fn probe(class: DeviceClass, device: &Device) -> Self {
+        let fans = match device.num_fans() {
+            Ok(fans) => fans > 0,
+            // Drivers before 460 can't count fans
+            Err(_) => device.fan_speed(0).is_ok(),
+        };
+        MetricGroups {
+            fans,
+            ..class.groups()
+        }
+    }
+
+    /// Names of the groups left out, for logging.
+    fn skipped(self) -> Vec<&'static str> {
+        [
+            (self.ecc, "ECC"),
+            (self.accounting, "accounting"),
+            (self.fans, "fan"),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| !enabled)
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+/// GPUs sharing a board, e.g. the two dies of a dual-GPU card, which share
+/// its power delivery and cooling.
+struct Board {
+    gpus: Vec<u32>,
+    id: u32,
+    power_watts: &'static str,
+    enforced_power_limit_watts: &'static str,
+    temp: &'static str,
+}
+
+impl Board {
+    /// Group the GPUs on multi-GPU boards by board ID.
+    fn group(devices: &[Option<Device>]) -> Vec<Board> {
+        let mut boards: Vec<Board> = Vec::new();
+        for (di, device) in devices.iter().enumerate() {
+            let Some(device) = device else {
+                continue;
+            };
+            if !device.is_multi_gpu_board().unwrap_or(false) {
+                continue;
+            }
+            let Ok(id) = device.board_id() else {
+                continue;
+            };
+            match boards.iter_mut().find(|board| board.id == id) {
+                Some(board) => board.gpus.push(di as u32),
+                None => boards.push(Board::new(id, di as u32)),
+            }
+        }
+        boards
+    }
+
+    fn new(id: u32, gpu: u32) -> Self {
+        // Boards are few and fixed, so their metric names are leaked like the
+        // per-device metric names
+        let key = |name: &str| -> &'static str {
+            Box::leak(format!("_board.{}.{}", id, name).into_boxed_str())
+        };
+        Board {
+            gpus: vec![gpu],
+            id,
+            power_watts: key("powerWatts"),
+            enforced_power_limit_watts: key("enforcedPowerLimitWatts"),
+            temp: key("temp"),
+        }
+    }
+
+    /// Add the board's total power and power limit and its hottest GPU's
+    /// temperature, from its GPUs' metrics in `metrics`. Totals are left out
+    /// unless every GPU on the board has a reading, as a partial sum would
+    /// understate the board.
+    fn sample(&self, keys: &[&'static DeviceKeys], metrics: &mut Metrics) {
+        let keys: Vec<&DeviceKeys> = self
+            .gpus
+            .iter()
+            .filter_map(|&di| keys.get(di as usize).copied())
+            .collect();
+        for keys in &keys {
+            metrics.add_metric(keys.board_id, self.id);
+        }
+        let complete = keys.len() == self.gpus.len();
+        let values = |key: fn(&DeviceKeys) -> &'static str| -> Option<Vec<f64>> {
+            let values: Option<Vec<f64>> = keys
+                .iter()
+                .map(|keys| metrics.get(key(keys)).and_then(|v| v.as_f64()))
+                .collect();
+            values.filter(|_| complete)
+        };
+        let power = values(|keys| keys.power_watts).map(|watts| watts.iter().sum::<f64>());
+        let limit =
+            values(|keys| keys.enforced_power_limit_watts).map(|watts| watts.iter().sum::<f64>());
+        let temp = keys
+            .iter()
+            .filter_map(|keys| metrics.get(keys.temp).and_then(|v| v.as_f64()))
+            .reduce(f64::max);
+        if let Some(power) = power {
+            metrics.add_metric(self.power_watts, power);
+        }
+        if let Some(limit) = limit {
+            metrics.add_metric(self.enforced_power_limit_watts, limit);
+        }
+        if let Some(temp) = temp {
+            metrics.add_metric(self.temp, temp);
+        }
+    }
+}
+
+/// Driver and NVML versions, and the newer NVML calls the driver supports.
+///
+/// Calls newer than the driver fail on every sample, or worse, aren't
+/// exported by the library at all, so they are gated on the driver version
+/// found at startup instead.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Features {
+    /// Driver version, e.g. `535.104.05`.
+    pub driver_version: String,
+    /// NVML library version, e.g. `12.535.104.05`.
+    pub nvml_version: String,
+    /// Remapped memory rows (`nvmlDeviceGetRemappedRows`), driver 460 and newer.
+    pub row_remapping: bool,
+    /// Memory temperature field value, driver 450 and newer.
+    pub memory_temperature: bool,
+    /// GSP firmware mode and version, driver 510 and newer.
+    pub gsp_firmware: bool,
+}
+
+impl Features {
+    const ROW_REMAPPING: u32 = 460;
+    const MEMORY_TEMPERATURE: u32 = 450;
+    const GSP_FIRMWARE: u32 = 510;
+
+    /// Features of a driver, by its version. Nothing newer is assumed of
+    /// versions that don't parse.
+    pub fn new(driver_version: String, nvml_version: String) -> Self {
+        let major = driver_major(&driver_version).unwrap_or(0);
+        Features {
+            row_remapping: major >= Features::ROW_REMAPPING,
+            memory_temperature: major >= Features::MEMORY_TEMPERATURE,
+            gsp_firmware: major >= Features::GSP_FIRMWARE,
+            driver_version,
+            nvml_version,
+        }
+    }
+
+    /// Names of the features the driver lacks, for logging.
+    pub fn missing(&self) -> Vec<&'static str> {
+        [
+            (self.row_remapping, "row remapping"),
+            (self.memory_temperature, "memory temperature"),
+            (self.gsp_firmware, "GSP firmware"),
+        ]
+        .into_iter()
+        .filter(|(supported, _)| !supported)
+        .map(|(_, name)| name)
+        .collect()
+    }
+
+    /// Add the versions and feature matrix to a record, as `_driver_version`,
+    /// `_nvml_version` and `_features`.
+    pub fn add_to(&self, record: &mut Metrics) {
+        record.add_metric("_driver_version", &*self.driver_version);
+        record.add_metric("_nvml_version", &*self.nvml_version);
+        record.add_metric("_features", self.to_json());
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "rowRemapping": self.row_remapping,
+            "memoryTemperature": self.memory_temperature,
+            "gspFirmware": self.gsp_firmware,
+        })
+    }
+}
+
+/// Major version of a driver version such as `535.104.05`.
+fn driver_major(version: &str) -> Option<u32> {
+    version.split('.').next()?.trim().parse().ok()
+}
+
+/// Cumulative counters fetched together with `nvmlDeviceGetFieldValues`,
+/// one driver round-trip per device instead of one per counter. Energy and
+/// PCIe replays come first so GPUs without ECC can skip the rest. GPUs with
+/// row remapping (Ampere and newer) use these; older ones retire pages
+/// instead, see `PAGE_RETIREMENT_COUNTER_FIELDS`.
+const COUNTER_FIELDS: [FieldId; 10] = [
+    FieldId(NVML_FI_DEV_TOTAL_ENERGY_CONSUMPTION),
+    FieldId(NVML_FI_DEV_PCIE_REPLAY_COUNTER),
+    FieldId(NVML_FI_DEV_ECC_SBE_AGG_DEV),
+    FieldId(NVML_FI_DEV_ECC_DBE_AGG_DEV),
+    FieldId(NVML_FI_DEV_ECC_SBE_VOL_TOTAL),
+    FieldId(NVML_FI_DEV_ECC_DBE_VOL_TOTAL),
+    FieldId(NVML_FI_DEV_REMAPPED_COR),
+    FieldId(NVML_FI_DEV_REMAPPED_UNC),
+    FieldId(NVML_FI_DEV_REMAPPED_PENDING),
+    FieldId(NVML_FI_DEV_REMAPPED_FAILURE),
+];
+
+/// `COUNTER_FIELDS` of GPUs that retire pages instead of remapping rows.
+const PAGE_RETIREMENT_COUNTER_FIELDS: [FieldId; 9] = [
+    FieldId(NVML_FI_DEV_TOTAL_ENERGY_CONSUMPTION),
+    FieldId(NVML_FI_DEV_PCIE_REPLAY_COUNTER),
+    FieldId(NVML_FI_DEV_ECC_SBE_AGG_DEV),
+    FieldId(NVML_FI_DEV_ECC_DBE_AGG_DEV),
+    FieldId(NVML_FI_DEV_ECC_SBE_VOL_TOTAL),
+    FieldId(NVML_FI_DEV_ECC_DBE_VOL_TOTAL),
+    FieldId(NVML_FI_DEV_RETIRED_SBE),
+    FieldId(NVML_FI_DEV_RETIRED_DBE),
+    FieldId(NVML_FI_DEV_RETIRED_PENDING),
+];
+
+/// Names of the reasons clocks are reduced, in camelCase like metric names.
+fn throttle_reason_names(reasons: ThrottleReasons) -> Vec<&'static str> {
+    const NAMES: &[(ThrottleReasons, &str)] = &[
+        (ThrottleReasons::GPU_IDLE, "gpuIdle"),
+        (
+            ThrottleReasons::APPLICATIONS_CLOCKS_SETTING,
+            "applicationsClocksSetting",
+        ),
+        (ThrottleReasons::SW_POWER_CAP, "swPowerCap"),
+        (ThrottleReasons::HW_SLOWDOWN, "hwSlowdown"),
+        (ThrottleReasons::SYNC_BOOST, "syncBoost"),
+        (ThrottleReasons::SW_THERMAL_SLOWDOWN, "swThermalSlowdown"),
+        (ThrottleReasons::HW_THERMAL_SLOWDOWN, "hwThermalSlowdown"),
+        (
+            ThrottleReasons::HW_POWER_BRAKE_SLOWDOWN,
+            "hwPowerBrakeSlowdown",
+        ),
+        (
+            ThrottleReasons::DISPLAY_CLOCK_SETTING,
+            "displayClockSetting",
+        ),
+    ];
+    NAMES
+        .iter()
+        .filter(|(reason, _)| reasons.contains(*reason))
+        .map(|&(_, name)| name)
+        .collect()
+}
+
+/// Name of a compute mode, in camelCase like metric names.
+fn compute_mode_name(mode: ComputeMode) -> &'static str {
+    match mode {
+        ComputeMode::Default => "default",
+        ComputeMode::ExclusiveThread => "exclusiveThread",
+        ComputeMode::Prohibited => "prohibited",
+        ComputeMode::ExclusiveProcess => "exclusiveProcess",
+    }
+}
+
+fn sample_value_f64(value: &SampleValue) -> f64 {
+    match *value {
+        SampleValue::F64(v) => v,
+        SampleValue::U32(v) => v as f64,
+        SampleValue::U64(v) => v as f64,
+        SampleValue::I64(v) => v as f64,
+    }
+}
+
+/// Sample the energy, PCIe replay, ECC, row remapping and page retirement
+/// counters of a device.
+///
+/// Drivers without field value support fail the whole batch, in which case
+/// each counter is queried separately.
+fn sample_counters(
+    device: &Device,
+    ecc: bool,
+    row_remapping: bool,
+    ext: Option<&NvmlExt>,
+    keys: &DeviceKeys,
+    metrics: &mut Metrics,
+) {
+    let fields = match (ecc, row_remapping) {
+        (false, _) => &COUNTER_FIELDS[..2],
+        (true, true) => &COUNTER_FIELDS[..],
+        (true, false) => &PAGE_RETIREMENT_COUNTER_FIELDS[..],
+    };
+    let Ok(values) = device.field_values_for(fields) else {
+        sample_counters_individually(device, ecc, row_remapping, ext, keys, metrics);
+        return;
+    };
+    // Pages retired for single- and double-bit errors are reported as one total
+    let mut retired_pages = None;
+    for (field, value) in fields.iter().zip(values) {
+        let Ok(value) = value
+            .and_then(|sample| sample.value)
+            .map(|value| sample_value_f64(&value))
+        else {
+            continue;
+        };
+        match field.0 {
+            NVML_FI_DEV_TOTAL_ENERGY_CONSUMPTION => metrics.add_metric(keys.energy, value / 1000.0),
+            NVML_FI_DEV_PCIE_REPLAY_COUNTER => metrics.add_metric(keys.pcie_replays, value as u64),
+            NVML_FI_DEV_ECC_SBE_AGG_DEV => {
+                metrics.add_metric(keys.corrected_memory_errors, value as u64)
+            }
+            NVML_FI_DEV_ECC_DBE_AGG_DEV => {
+                metrics.add_metric(keys.uncorrected_memory_errors, value as u64)
+            }
+            NVML_FI_DEV_ECC_SBE_VOL_TOTAL => {
+                metrics.add_metric(keys.corrected_volatile_memory_errors, value as u64)
+            }
+            NVML_FI_DEV_ECC_DBE_VOL_TOTAL => {
+                metrics.add_metric(keys.uncorrected_volatile_memory_errors, value as u64)
+            }
+            NVML_FI_DEV_REMAPPED_COR => {
+                metrics.add_metric(keys.remapped_rows_correctable, value as u64)
+            }
+            NVML_FI_DEV_REMAPPED_UNC => {
+                metrics.add_metric(keys.remapped_rows_uncorrectable, value as u64)
+            }
+            NVML_FI_DEV_REMAPPED_PENDING => {
+                metrics.add_metric(keys.remapped_rows_pending, value != 0.0)
+            }
+            NVML_FI_DEV_REMAPPED_FAILURE => {
+                metrics.add_metric(keys.remapped_rows_failed, value != 0.0)
+            }
+            NVML_FI_DEV_RETIRED_SBE | NVML_FI_DEV_RETIRED_DBE => {
+                *retired_pages.get_or_insert(0) += value as u64
+            }
+            NVML_FI_DEV_RETIRED_PENDING => {
+                metrics.add_metric(keys.retired_pages_pending, value != 0.0)
+            }
+            _ => {}
+        }
+    }
+    if let Some(retired_pages) = retired_pages {
+        metrics.add_metric(keys.retired_pages, retired_pages);
+    }
+}
+
+/// Sample the memory temperature, reported by GPUs with HBM only.
+fn sample_memory_temperature(device: &Device, keys: &DeviceKeys, metrics: &mut Metrics) {
+    let Ok(values) = device.field_values_for(&[FieldId(NVML_FI_DEV_MEMORY_TEMP)]) else {
+        return;
+    };
+    let temperature = values
+        .into_iter()
+        .next()
+        .and_then(|value| value.and_then(|sample| sample.value).ok())
+        .map(|value| sample_value_f64(&value));
+    // GPUs without a memory sensor report zero rather than failing
+    if let Some(temperature) = temperature.filter(|&t| t > 0.0) {
+        metrics.add_metric(keys.memory_temp, temperature as u64);
+    }
+}
+
+/// Estimate memory bandwidth use where no profiling counters are available.
+///
+/// `memory` is the share of time memory was being read or written, so at
+/// best the bus was busy that often at the current clock: the estimate
+/// overstates bandwidth-bound phases with sparse accesses, but does tell them
+/// apart from compute-bound ones.
+fn sample_memory_bandwidth(
+    device: &Device,
+    memory: u32,
+    clock: u32,
+    keys: &DeviceKeys,
+    metrics: &mut Metrics,
+) {
+    let busy = f64::from(memory) / 100.0;
+    if let Ok(max_clock) = device.max_clock_info(Clock::Memory) {
+        if max_clock > 0 {
+            let percent = busy * f64::from(clock) / f64::from(max_clock) * 100.0;
+            metrics.add_metric(keys.memory_bandwidth_estimate, percent);
+        }
+    }
+    if let Ok(bus_width) = device.memory_bus_width() {
+        // Two transfers per clock, each as wide as the bus
+        let bytes_per_second = f64::from(clock) * 1e6 * 2.0 * f64::from(bus_width) / 8.0;
+        metrics.add_metric(
+            keys.memory_bandwidth_estimate_bytes,
+            busy * bytes_per_second,
+        );
+    }
+}
+
+fn sample_counters_individually(
+    device: &Device,
+    ecc: bool,
+    row_remapping: bool,
+    ext: Option<&NvmlExt>,
+    keys: &DeviceKeys,
+    metrics: &mut Metrics,
+) {
+    if let Ok(energy) = device.total_energy_consumption() {
+        metrics.add_metric(keys.energy, energy as f64 / 1000.0);
+    }
+
+    if let Ok(replays) = device.pcie_replay_counter() {
+        metrics.add_metric(keys.pcie_replays, replays);
+    }
+
+    if !ecc {
+        return;
+    }
+
+    // nvmlDeviceGetMemoryErrorCounter
+    let memory_errors = [
+        (
+            MemoryError::Corrected,
+            EccCounter::Aggregate,
+            keys.corrected_memory_errors,
+        ),
+        (
+            MemoryError::Uncorrected,
+            EccCounter::Aggregate,
+            keys.uncorrected_memory_errors,
+        ),
+        (
+            MemoryError::Corrected,
+            EccCounter::Volatile,
+            keys.corrected_volatile_memory_errors,
+        ),
+        (
+            MemoryError::Uncorrected,
+            EccCounter::Volatile,
+            keys.uncorrected_volatile_memory_errors,
+        ),
+    ];
+    for (error, counter, key) in memory_errors {
+        if let Ok(errors) = device.memory_error_counter(error, counter, MemoryLocation::Device) {
+            metrics.add_metric(key, errors);
+        }
+    }
+
+    if row_remapping {
+        if let Some(Ok(rows)) = ext.map(|ext| ext.remapped_rows(device)) {
+            metrics.add_metric(keys.remapped_rows_correctable, rows.correctable);
+            metrics.add_metric(keys.remapped_rows_uncorrectable, rows.uncorrectable);
+            metrics.add_metric(keys.remapped_rows_pending, rows.pending);
+            metrics.add_metric(keys.remapped_rows_failed, rows.failed);
+        }
+        return;
+    }
+
+    let retired = [
+        RetirementCause::MultipleSingleBitEccErrors,
+        RetirementCause::DoubleBitEccError,
+    ]
+    .into_iter()
+    .map(|cause| device.retired_pages(cause).map(|pages| pages.len()))
+    .sum::<Result<usize, _>>();
+    if let Ok(retired) = retired {
+        metrics.add_metric(keys.retired_pages, retired);
+    }
+    if let Ok(pending) = device.are_pages_pending_retired() {
+        metrics.add_metric(keys.retired_pages_pending, pending);
+    }
+}
+
+/// Max and mean of the driver's buffered samples of one kind, and the
+/// driver's sampling period (in microseconds) if there are enough samples to
+/// tell.
+///
+/// NVML keeps a short ring buffer of utilization and power readings taken
+/// at a much higher rate than we sample, so reading everything since the
+/// previous tick catches bursts an instantaneous reading would miss.
+fn buffered_samples(
+    device: &Device,
+    sampling: Sampling,
+    last_seen: &mut Option<u64>,
+) -> Option<(f64, f64, Option<u64>)> {
+    let samples = device.samples(sampling, *last_seen).ok()?;
+    let newest = samples.iter().map(|sample| sample.timestamp).max()?;
+    *last_seen = Some(newest);
+
+    let mut max = f64::MIN;
+    let mut sum = 0.0;
+    for sample in &samples {
+        let value = sample_value_f64(&sample.value);
+        max = max.max(value);
+        sum += value;
+    }
+    let period = sample_period(samples.iter().map(|sample| sample.timestamp));
+    Some((max, sum / samples.len() as f64, period))
+}
+
+/// Median spacing of sample timestamps, or `None` for fewer than three
+/// samples.
+fn sample_period(timestamps: impl Iterator<Item = u64>) -> Option<u64> {
+    let mut timestamps: Vec<u64> = timestamps.collect();
+    if timestamps.len() < 3 {
+        return None;
+    }
+    timestamps.sort_unstable();
+    let mut gaps: Vec<u64> = timestamps.windows(2).map(|w| w[1] - w[0]).collect();
+    gaps.sort_unstable();
+    Some(gaps[gaps.len() / 2]).filter(|&gap| gap > 0)
+}
+
+/// Add the driver's accounting statistics of the tracked processes.
+///
+/// Only available when accounting mode is enabled (`nvidia-smi -am 1`). Unlike
+/// the per-sample readings these cover each process's whole lifetime, including
+/// processes that have already exited. Across processes, peak memory and active
+/// time are summed and utilization is the maximum.
+fn sample_accounting(device: &Device, our_pids: &[i32], keys: &DeviceKeys, metrics: &mut Metrics) {
+    if !device.is_accounting_enabled().unwrap_or(false) {
+        return;
+    }
+    let Ok(accounted) = device.accounting_pids() else {
+        return;
+    };
+
+    let mut found = false;
+    let mut max_memory = 0;
+    let mut gpu = 0;
+    let mut memory = 0;
+    let mut time_ms = 0;
+    for &pid in our_pids {
+        if !accounted.contains(&(pid as u32)) {
+            continue;
+        }
+        let Ok(stats) = device.accounting_stats_for(pid as u32) else {
+            continue;
+        };
+        found = true;
+        max_memory += stats.max_memory_usage.unwrap_or(0);
+        gpu = gpu.max(stats.gpu_utilization.unwrap_or(0));
+        memory = memory.max(stats.memory_utilization.unwrap_or(0));
+        time_ms += stats.time;
+    }
+
+    if found {
+        metrics.add_metric(keys.process_accounting_max_memory_bytes, max_memory);
+        metrics.add_metric(keys.process_accounting_gpu, gpu);
+        metrics.add_metric(keys.process_accounting_memory, memory);
+        metrics.add_metric(keys.process_accounting_time_ms, time_ms);
+    }
+}
+
+/// Timestamps of the newest utilization, power and per-process samples read
+/// per device.
+#[derive(Clone, Copy, Default)]
+struct LastSeen {
+    utilization: Option<u64>,
+    /// Spacing of the driver's utilization samples (in microseconds), i.e.
+    /// the window each utilization reading averages over.
+    utilization_period: Option<u64>,
+    power: Option<u64>,
+    processes: Option<u64>,
+    vgpus: Option<u64>,
+}
+
+/// What to sample besides the per-device metrics.
+#[derive(Clone, Debug, Default)]
+pub struct SampleOptions {
+    /// Report GPU usage of this process and its children as `gpu.process.*`.
+    pub pid: i32,
+    /// Report GPU usage per Kubernetes pod as `_gpu.{i}.pods`.
+    pub pods: bool,
+    /// List the processes on each device as `_gpu.{i}.processes`.
+    pub processes: bool,
+    /// Report GPU usage per user as `_gpu.{i}.users`.
+    pub users: bool,
+    /// Report SM, tensor core and DRAM activity from GPM, see `GpmCounters`.
+    pub profiling: bool,
+}
+
 pub struct NvidiaGpu {
     nvml: Nvml,
     cuda_version: String,
     device_count: u32,
+    keys: Vec<&'static DeviceKeys>,
+    last_seen: Mutex<Vec<LastSeen>>,
+    xids: Mutex<Vec<XidState>>,
+    pods: Mutex<PodResolver>,
+    container_names: Mutex<ContainerNames>,
+    /// Virtualization mode per device, queried once as it can't change while
+    /// the driver is loaded.
+    virtualization: Vec<Option<&'static str>>,
+    /// Product class per device, and the optional metrics it supports.
+    classes: Vec<DeviceClass>,
+    groups: Vec<MetricGroups>,
+    features: Features,
+    /// GPUs sharing a board with others.
+    boards: Vec<Board>,
+    /// Performance counters per device, set up on the first sample with
+    /// profiling; `None` for devices without GPM.
+    gpm: Mutex<Option<Vec<Option<GpmCounters>>>>,
+    ext: Option<NvmlExt>,
+}
+
+/// Critical XID errors seen on a device since NVML was initialized.
+#[derive(Clone, Copy, Default)]
+struct XidState {
+    count: u64,
+    last: Option<u64>,
 }
 
 impl NvidiaGpu {
@@ -16,16 +928,98 @@ impl NvidiaGpu {
         // to libnvidia-ml.so.1 and not available in certain environments.
         // We follow go-nvml example and attempt to load libnvidia-ml.so.1 directly, see:
         // https://github.com/NVIDIA/go-nvml/blob/0e815c71ca6e8184387d8b502b2ef2d2722165b9/pkg/nvml/lib.go#L30
+        // The library is loaded at runtime rather than linked, so the binary runs on
+        // hosts without the driver; a static musl build can't load it at all, and
+        // both end up in `Sampler::without_nvml`. `cargo build-musl-nvml` builds a
+        // musl binary that links libc dynamically and so can load it.
+        #[cfg(unix)]
         let nvml = Nvml::builder()
             .lib_path("libnvidia-ml.so.1".as_ref())
             .init()?;
+        // On Windows the default nvml.dll lookup is the right one
+        #[cfg(windows)]
+        let nvml = Nvml::init()?;
         let cuda_version = nvml.sys_cuda_driver_version()?;
-        format!(
-            "{}.{}",
-            nvml_wrapper::cuda_driver_version_major(cuda_version),
-            nvml_wrapper::cuda_driver_version_minor(cuda_version)
-        );
         let device_count = nvml.device_count()?;
+        let features = Features::new(
+            nvml.sys_driver_version().unwrap_or_default(),
+            nvml.sys_nvml_version().unwrap_or_default(),
+        );
+        let missing = features.missing();
+        if !missing.is_empty() {
+            log::info!(
+                "Driver {} predates {}, skipping those metrics",
+                features.driver_version,
+                missing.join(", ")
+            );
+        }
+
+        let mut ext = NvmlExt::open();
+        // One slot per index, so a GPU that can't be opened doesn't shift
+        // the ones after it
+        let devices: Vec<Option<Device>> = (0..device_count)
+            .map(|di| match nvml.device_by_index(di) {
+                Ok(device) => Some(device),
+                Err(e) => {
+                    log::warning!("Error opening GPU {}: {}", di, e);
+                    None
+                }
+            })
+            .collect();
+        if let Some(ext) = ext.as_mut() {
+            // Without events XID errors simply aren't reported
+            let _ = ext.watch_xids(devices.iter().flatten());
+        }
+        let virtualization = devices
+            .iter()
+            .map(|device| ext.as_ref()?.virtualization_mode(device.as_ref()?).ok())
+            .collect();
+        let classes: Vec<DeviceClass> = devices
+            .iter()
+            .map(|device| {
+                device
+                    .as_ref()
+                    .and_then(|device| device.brand().ok())
+                    .map_or(DeviceClass::Unknown, |b| DeviceClass::of(&b))
+            })
+            .collect();
+        let groups: Vec<MetricGroups> = devices
+            .iter()
+            .zip(&classes)
+            .map(|(device, class)| match device {
+                Some(device) => MetricGroups::probe(*class, device),
+                None => class.groups(),
+            })
+            .collect();
+        // The whole buffer tells the utilization period before the first sample
+        let last_seen: Vec<LastSeen> = devices
+            .iter()
+            .map(|device| LastSeen {
+                utilization_period: device
+                    .as_ref()
+                    .and_then(|device| device.samples(Sampling::GpuUtilization, None).ok())
+                    .and_then(|samples| {
+                        sample_period(samples.iter().map(|sample| sample.timestamp))
+                    }),
+                ..LastSeen::default()
+            })
+            .collect();
+        for (di, (class, groups)) in classes.iter().zip(&groups).enumerate() {
+            let skipped = groups.skipped();
+            if !skipped.is_empty() {
+                log::info!(
+                    "GPU {} is a {} GPU, skipping {} metrics",
+                    di,
+                    class.as_str(),
+                    skipped.join(", ")
+                );
+            }
+        }
+        let boards = Board::group(&devices);
+        for board in &boards {
+            log::info!("GPUs {:?} share board {}", board.gpus, board.id);
+        }
+        drop(devices);
 
         Ok(NvidiaGpu {
             nvml,
@@ -35,28 +1029,42 @@ impl NvidiaGpu {
                 nvml_wrapper::cuda_driver_version_minor(cuda_version)
             ),
             device_count,
+            keys: device_keys(device_count),
+            last_seen: Mutex::new(last_seen),
+            xids: Mutex::new(vec![XidState::default(); device_count as usize]),
+            pods: Mutex::new(PodResolver::new()),
+            container_names: Mutex::new(ContainerNames::new()),
+            virtualization,
+            classes,
+            groups,
+            features,
+            boards,
+            gpm: Mutex::new(None),
+            ext,
         })
     }
 
-    /// Check if a GPU is being used by a specific process or its children.
-    fn gpu_in_use_by_process(&self, device: &Device, pid: i32) -> bool {
-        let our_pids: Vec<i32> = std::iter::once(pid)
-            .chain(self.get_child_pids(pid))
-            .collect();
-
-        let compute_processes = device.running_compute_processes().unwrap_or_default();
-        let graphics_processes = device.running_graphics_processes().unwrap_or_default();
+    /// Driver and NVML versions and the features gated on them.
+    pub fn features(&self) -> &Features {
+        &self.features
+    }
 
-        let device_pids: Vec<i32> = compute_processes
+    /// Check if a GPU is being used by a specific process or its children,
+    /// given the compute and graphics processes on it.
+    fn gpu_in_use_by_process(
+        &self,
+        compute_processes: &[ProcessInfo],
+        graphics_processes: &[ProcessInfo],
+        our_pids: &[i32],
+    ) -> bool {
+        compute_processes
             .iter()
             .chain(graphics_processes.iter())
-            .map(|p| p.pid as i32)
-            .collect();
-
-        our_pids.iter().any(|&p| device_pids.contains(&p))
+            .any(|p| our_pids.contains(&(p.pid as i32)))
     }
 
     /// Get child process IDs for a given parent PID.
+    #[cfg(feature = "processes")]
     fn get_child_pids(&self, pid: i32) -> Vec<i32> {
         let mut sys = System::new_all();
         sys.refresh_all();
@@ -68,6 +1076,30 @@ impl NvidiaGpu {
             .collect()
     }
 
+    /// Get child process IDs for a given parent PID, from the parent each
+    /// process in `/proc` names. Only Linux is supported without the
+    /// `processes` feature.
+    #[cfg(not(feature = "processes"))]
+    fn get_child_pids(&self, pid: i32) -> Vec<i32> {
+        let Ok(entries) = std::fs::read_dir("/proc") else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok())
+            .filter(|&child| {
+                // The parent follows the command name, which is in parentheses
+                // and may contain anything
+                std::fs::read_to_string(format!("/proc/{}/stat", child))
+                    .ok()
+                    .and_then(|stat| {
+                        let fields = &stat[stat.rfind(')')? + 1..];
+                        fields.split_whitespace().nth(1)?.parse::<i32>().ok()
+                    })
+                    == Some(pid)
+            })
+            .collect()
+    }
+
     /// Samples GPU metrics using NVML.
     ///
     /// This function collects various metrics from all available GPUs, including
@@ -79,8 +1111,25 @@ impl NvidiaGpu {
     /// cuda_version: The version of CUDA installed on the system.
     /// gpu.count: The total number of GPUs detected in the system.
     /// gpu.{i}.name: The name of the GPU at index i (e.g., Tesla T4).
+    /// gpu.{i}.correctedMemoryErrors: Corrected ECC errors over the GPU's lifetime (counter).
+    /// gpu.{i}.uncorrectedMemoryErrors: Uncorrected ECC errors over the GPU's lifetime (counter).
+    /// gpu.{i}.correctedVolatileMemoryErrors, gpu.{i}.uncorrectedVolatileMemoryErrors: The same,
+    ///     since the last driver reload or GPU reset (counters).
+    /// gpu.{i}.energyJoules: Energy consumed since the last driver reload (counter, in Joules).
+    /// gpu.{i}.pcieReplays: PCIe replays since the last driver reload (counter).
+    /// gpu.{i}.remappedRows*: Memory rows remapped after correctable and uncorrectable
+    ///     errors, and whether a remapping is pending or failed (driver 460 and newer).
+    /// gpu.{i}.retiredPages, gpu.{i}.retiredPagesPending: Memory pages retired after ECC
+    ///     errors, and whether a retirement waits for the driver to reload (before Ampere).
     /// gpu.{i}.brand: The brand of the GPU at index i (e.g., GeForce, Nvidia).
+    /// gpu.{i}.persistenceMode, gpu.{i}.displayMode, gpu.{i}.displayActive: Whether the
+    ///     driver stays loaded, and whether a display is connected to or driven by the GPU.
+    /// gpu.{i}.computeMode: Which contexts the GPU at index i accepts, e.g. exclusiveProcess.
     /// gpu.{i}.fanSpeed: The current fan speed of the GPU at index i (in percentage).
+    /// gpu.{i}.fanTargetSpeed: The fan speed the driver is aiming for (in percentage).
+    /// gpu.{i}.fanCount: The number of fans of the GPU at index i.
+    /// gpu.{i}.xidErrors: Critical XID errors since symon started (counter).
+    /// gpu.{i}.lastXid: The most recent critical XID error code, e.g. 79 (fallen off the bus).
     /// gpu.{i}.encoderUtilization: The utilization of the GPU's encoder at index i (in percentage).
     /// gpu.{i}.gpu: The overall GPU utilization at index i (in percentage).
     /// gpu.{i}.memory: The GPU memory utilization at index i (in percentage).
@@ -88,11 +1137,29 @@ impl NvidiaGpu {
     /// gpu.{i}.memoryAllocated: The percentage of GPU memory allocated at index i.
     /// gpu.{i}.memoryAllocatedBytes: The amount of GPU memory allocated at index i (in bytes).
     /// gpu.{i}.temp: The temperature of the GPU at index i (in Celsius).
+    /// gpu.{i}.memoryTemp: The memory temperature of the GPU at index i, if it has HBM
+    ///     (in Celsius, driver 450 and newer).
+    /// gpu.{i}.computeProcessCount, gpu.{i}.graphicsProcessCount: The number of processes
+    ///     with a compute or graphics context on the GPU at index i.
     /// gpu.{i}.powerWatts: The power consumption of the GPU at index i (in Watts).
     /// gpu.{i}.enforcedPowerLimitWatts: The enforced power limit of the GPU at index i (in Watts).
     /// gpu.{i}.powerPercent: The percentage of power limit being used by the GPU at index i.
+    /// gpu.{i}.gpuMax, gpu.{i}.gpuMean: Max and mean GPU utilization over the driver's
+    ///     samples since the previous call (in percentage).
+    /// gpu.{i}.powerWattsMax, gpu.{i}.powerWattsMean: Max and mean power over the driver's
+    ///     samples since the previous call (in Watts).
     /// gpu.{i}.graphicsClock: The current graphics clock speed of the GPU at index i (in MHz).
     /// gpu.{i}.memoryClock: The current memory clock speed of the GPU at index i (in MHz).
+    /// gpu.{i}.smActive, gpu.{i}.smOccupancy, gpu.{i}.tensorActive, gpu.{i}.dramActive: SM,
+    ///     tensor core and DRAM activity from GPM with profiling, on Hopper and newer (in
+    ///     percentage), see `gpm`. gpu.{i}.fp64Active, gpu.{i}.tensorHmmaActive etc. break
+    ///     activity down by pipe.
+    /// gpu.{i}.memoryBandwidthEstimate, gpu.{i}.memoryBandwidthEstimateBytesPerSecond: A rough
+    ///     estimate of the memory bandwidth in use (in percentage of peak and bytes per second),
+    ///     from memory utilization, memory clock and bus width.
+    /// gpu.{i}.pstate: The current performance state of the GPU at index i, 0 (fastest) to 15.
+    /// gpu.{i}.throttleReasons: Why clocks of the GPU at index i are currently reduced, e.g.
+    ///     `swPowerCap` or `hwThermalSlowdown`; empty if they aren't.
     /// gpu.{i}.pcieLinkGen: The current PCIe link generation of the GPU at index i.
     /// gpu.{i}.pcieLinkSpeed: The current PCIe link speed of the GPU at index i (in bits per second).
     /// gpu.{i}.pcieLinkWidth: The current PCIe link width of the GPU at index i.
@@ -100,9 +1167,32 @@ impl NvidiaGpu {
     /// gpu.{i}.maxPcieLinkWidth: The maximum PCIe link width supported by the GPU at index i.
     /// gpu.{i}.cudaCores: The number of CUDA cores in the GPU at index i.
     /// gpu.{i}.architecture: The architecture of the GPU at index i (e.g., Ampere, Turing).
+    /// node.gpu.*: Total power and memory used, mean utilization and whether any GPU is
+    ///     throttled, see `rollup::add_node_rollups`.
+    /// gpu.{i}.boardId, board.{id}.*: The board shared by GPUs of a multi-GPU board, and its
+    ///     total power and power limit and hottest GPU's temperature.
     /// gpu.process.{i}.*: Various metrics specific to the monitored process
     ///    (if the GPU is in use by the process). These include GPU utilization, memory utilization,
     ///     temperature, and power consumption.
+    /// gpu.process.{i}.accounting*: Lifetime peak memory (bytes), GPU and memory utilization
+    ///     (in percentage) and active time (in ms) of the monitored processes, if accounting
+    ///     mode is enabled.
+    /// gpu.{i}.pods: Memory (bytes) and SM utilization (in percentage) per Kubernetes pod and
+    ///     container using the GPU at index i, if requested with `SampleOptions::pods`.
+    /// gpu.{i}.processes: PID, type (compute or graphics), user, command line, memory (bytes),
+    ///     SM and memory utilization (in percentage) and container ID and name of each process
+    ///     on the GPU at index i, if requested with `SampleOptions::processes`.
+    /// gpu.{i}.virtualizationMode: How the GPU at index i is virtualized: none, passthrough,
+    ///     vgpu (inside a vGPU guest), hostVgpu or hostVsga.
+    /// gpu.{i}.licensed, gpu.{i}.licenseProduct, gpu.{i}.licenseExpiry: vGPU software license
+    ///     state of a vGPU guest.
+    /// gpu.{i}.fbcSessions, gpu.{i}.fbcFps, gpu.{i}.fbcLatencyUs: Frame buffer capture sessions
+    ///     and their average frame rate and latency (in μs) in a vGPU guest.
+    /// gpu.{i}.vgpus: VM ID, vGPU type, frame buffer usage (bytes), SM, memory, encoder and
+    ///     decoder utilization (in percentage), frame rate limit and license state of each
+    ///     vGPU instance on the GPU at index i, on a vGPU host.
+    /// gpu.{i}.users: Process count, memory (bytes) and SM and memory utilization (in percentage)
+    ///     per user of the GPU at index i, if requested with `SampleOptions::users`.
     /// _timestamp: The Unix timestamp when the metrics were collected.
     ///
     /// Note that {i} represents the index of each GPU in the system, starting from 0.
@@ -125,91 +1215,139 @@ impl NvidiaGpu {
     ///
     /// # Examples
     ///
-    /// ```
-    /// use crate::gpu_nvidia::NvidiaGpu;
-    /// use crate::metrics::Metrics;
+    /// ```no_run
+    /// use symon::gpu_nvidia::NvidiaGpu;
+    /// use symon::metrics::Metrics;
     /// let nvidia_gpu = NvidiaGpu::new().unwrap();
     /// let mut metrics = Metrics::new();
     /// nvidia_gpu.sample_metrics(&mut metrics, 1234).unwrap();
     /// ```
     pub fn sample_metrics(&self, metrics: &mut Metrics, pid: i32) -> Result<(), NvmlError> {
+        self.sample_with(
+            metrics,
+            &SampleOptions {
+                pid,
+                ..SampleOptions::default()
+            },
+        )
+    }
+
+    /// Like `sample_metrics`, with everything besides the per-device metrics
+    /// set by `options`.
+    pub fn sample_with(
+        &self,
+        metrics: &mut Metrics,
+        options: &SampleOptions,
+    ) -> Result<(), NvmlError> {
+        let pid = options.pid;
         metrics.add_metric("cuda_version", &*self.cuda_version);
         metrics.add_metric("_gpu.count", self.device_count);
 
+        let our_pids: Vec<i32> = std::iter::once(pid)
+            .chain(self.get_child_pids(pid))
+            .collect();
+
+        let xids = self.ext.as_ref().map(NvmlExt::poll_xids);
+
         for di in 0..self.device_count {
+            let mut span = otel::Span::start("nvml.device");
+            span.set_attribute("gpu.index", di);
             let device = match self.nvml.device_by_index(di) {
                 Ok(device) => device,
-                Err(_e) => {
+                Err(e) => {
+                    span.set_error(e);
                     continue;
                 }
             };
 
-            let gpu_in_use = self.gpu_in_use_by_process(&device, pid);
+            let keys = self.keys[di as usize];
+            if let Some(xids) = &xids {
+                self.sample_xids(&device, di, xids, keys, metrics);
+            }
+            // A failed query says nothing about the processes, so the count is
+            // left out rather than reported as 0, which would read as idle
+            let compute_processes = device.running_compute_processes();
+            let graphics_processes = device.running_graphics_processes();
+            if let Ok(processes) = &compute_processes {
+                metrics.add_metric(keys.compute_process_count, processes.len());
+            }
+            if let Ok(processes) = &graphics_processes {
+                metrics.add_metric(keys.graphics_process_count, processes.len());
+            }
+            let compute_processes = compute_processes.unwrap_or_default();
+            let graphics_processes = graphics_processes.unwrap_or_default();
+            let gpu_in_use =
+                self.gpu_in_use_by_process(&compute_processes, &graphics_processes, &our_pids);
+            let groups = self
+                .groups
+                .get(di as usize)
+                .copied()
+                .unwrap_or(MetricGroups::ALL);
+            if groups.accounting {
+                sample_accounting(&device, &our_pids, keys, metrics);
+            }
+            if options.pods || options.processes || options.users {
+                self.sample_processes(&device, di, options, keys, metrics);
+            }
 
-            if let Ok(utilization) = device.utilization_rates() {
-                metrics.add_metric(&format!("gpu.{}.gpu", di), utilization.gpu);
-                metrics.add_metric(&format!("gpu.{}.memory", di), utilization.memory);
+            let utilization = device.utilization_rates().ok();
+            if let Some(utilization) = &utilization {
+                metrics.add_metric(keys.gpu, utilization.gpu);
+                metrics.add_metric(keys.memory, utilization.memory);
 
                 if gpu_in_use {
-                    metrics.add_metric(&format!("gpu.process.{}.gpu", di), utilization.gpu);
-                    metrics.add_metric(&format!("gpu.process.{}.memory", di), utilization.memory);
+                    metrics.add_metric(keys.process_gpu, utilization.gpu);
+                    metrics.add_metric(keys.process_memory, utilization.memory);
                 }
             }
 
             if let Ok(memory_info) = device.memory_info() {
-                metrics.add_metric(&format!("_gpu.{}.memoryTotal", di), memory_info.total);
+                metrics.add_metric(keys.memory_total, memory_info.total);
                 let memory_allocated = (memory_info.used as f64 / memory_info.total as f64) * 100.0;
-                metrics.add_metric(&format!("gpu.{}.memoryAllocated", di), memory_allocated);
-                metrics.add_metric(
-                    &format!("gpu.{}.memoryAllocatedBytes", di),
-                    memory_info.used,
-                );
+                metrics.add_metric(keys.memory_allocated, memory_allocated);
+                metrics.add_metric(keys.memory_allocated_bytes, memory_info.used);
 
                 if gpu_in_use {
-                    metrics.add_metric(
-                        &format!("gpu.process.{}.memoryAllocated", di),
-                        memory_allocated,
-                    );
-                    metrics.add_metric(
-                        &format!("gpu.process.{}.memoryAllocatedBytes", di),
-                        memory_info.used,
-                    );
+                    metrics.add_metric(keys.process_memory_allocated, memory_allocated);
+                    metrics.add_metric(keys.process_memory_allocated_bytes, memory_info.used);
                 }
             }
 
             if let Ok(temperature) = device.temperature(TemperatureSensor::Gpu) {
-                metrics.add_metric(&format!("gpu.{}.temp", di), temperature);
+                metrics.add_metric(keys.temp, temperature);
                 if gpu_in_use {
-                    metrics.add_metric(&format!("gpu.process.{}.temp", di), temperature);
+                    metrics.add_metric(keys.process_temp, temperature);
                 }
             }
 
+            if self.features.memory_temperature {
+                sample_memory_temperature(&device, keys, metrics);
+            }
+
             if let Ok(power_usage) = device.power_usage() {
                 let power_usage = power_usage as f64 / 1000.0;
-                metrics.add_metric(&format!("gpu.{}.powerWatts", di), power_usage);
+                metrics.add_metric(keys.power_watts, power_usage);
                 if gpu_in_use {
-                    metrics.add_metric(&format!("gpu.process.{}.powerWatts", di), power_usage);
+                    metrics.add_metric(keys.process_power_watts, power_usage);
                 }
 
                 if let Ok(power_limit) = device.enforced_power_limit() {
                     let power_limit = power_limit as f64 / 1000.0;
-                    metrics.add_metric(&format!("gpu.{}.enforcedPowerLimitWatts", di), power_limit);
+                    metrics.add_metric(keys.enforced_power_limit_watts, power_limit);
                     let power_percent = (power_usage / power_limit) * 100.0;
-                    metrics.add_metric(&format!("gpu.{}.powerPercent", di), power_percent);
+                    metrics.add_metric(keys.power_percent, power_percent);
 
                     if gpu_in_use {
-                        metrics.add_metric(
-                            &format!("gpu.process.{}.enforcedPowerLimitWatts", di),
-                            power_limit,
-                        );
-                        metrics
-                            .add_metric(&format!("gpu.process.{}.powerPercent", di), power_percent);
+                        metrics.add_metric(keys.process_enforced_power_limit_watts, power_limit);
+                        metrics.add_metric(keys.process_power_percent, power_percent);
                     }
                 }
             }
 
+            self.sample_buffered(&device, di, keys, metrics);
+
             if let Ok(name) = device.name() {
-                metrics.add_metric(&format!("_gpu.{}.name", di), name);
+                metrics.add_metric(keys.name, name);
             }
 
             // Additional metrics. These may not be available on all devices.
@@ -217,91 +1355,523 @@ impl NvidiaGpu {
             // and may be added in the future.
 
             if let Ok(sm_clock) = device.clock_info(Clock::SM) {
-                metrics.add_metric(&format!("_gpu.{}.smClock", di), sm_clock);
+                metrics.add_metric(keys.sm_clock, sm_clock);
             }
 
             if let Ok(mem_clock) = device.clock_info(Clock::Memory) {
-                metrics.add_metric(&format!("_gpu.{}.memoryClock", di), mem_clock);
+                metrics.add_metric(keys.memory_clock, mem_clock);
+                if let Some(utilization) = &utilization {
+                    sample_memory_bandwidth(&device, utilization.memory, mem_clock, keys, metrics);
+                }
             }
 
             if let Ok(graphics_clock) = device.clock_info(Clock::Graphics) {
-                metrics.add_metric(&format!("_gpu.{}.graphicsClock", di), graphics_clock);
+                metrics.add_metric(keys.graphics_clock, graphics_clock);
             }
 
-            // nvmlDeviceGetMemoryErrorCounter
-            if let Ok(corrected_memory_errors) = device.memory_error_counter(
-                nvml_wrapper::enum_wrappers::device::MemoryError::Corrected,
-                nvml_wrapper::enum_wrappers::device::EccCounter::Aggregate,
-                nvml_wrapper::enum_wrappers::device::MemoryLocation::Device,
-            ) {
-                metrics.add_metric(
-                    &format!("_gpu.{}.correctedMemoryErrors", di),
-                    corrected_memory_errors,
-                );
+            if let Ok(pstate) = device.performance_state() {
+                if pstate != PerformanceState::Unknown {
+                    metrics.add_metric(keys.pstate, pstate.as_c());
+                }
             }
 
-            if let Ok(uncorrected_memory_errors) = device.memory_error_counter(
-                nvml_wrapper::enum_wrappers::device::MemoryError::Uncorrected,
-                nvml_wrapper::enum_wrappers::device::EccCounter::Aggregate,
-                nvml_wrapper::enum_wrappers::device::MemoryLocation::Device,
-            ) {
-                metrics.add_metric(
-                    &format!("_gpu.{}.uncorrectedMemoryErrors", di),
-                    uncorrected_memory_errors,
-                );
+            if let Ok(reasons) = device.current_throttle_reasons() {
+                metrics.add_metric(keys.throttle_reasons, throttle_reason_names(reasons));
             }
 
+            // Row remapping replaced page retirement with Ampere
+            sample_counters(
+                &device,
+                groups.ecc,
+                self.features.row_remapping,
+                self.ext.as_ref(),
+                keys,
+                metrics,
+            );
+
             if let Ok(brand) = device.brand() {
-                metrics.add_metric(&format!("_gpu.{}.brand", di), format!("{:?}", brand));
+                metrics.add_metric(keys.brand, format!("{:?}", brand));
+            }
+            if let Some(class) = self.classes.get(di as usize) {
+                metrics.add_metric(keys.class, class.as_str());
+            }
+
+            #[cfg(target_os = "linux")]
+            if let Ok(persistent) = device.is_in_persistent_mode() {
+                metrics.add_metric(keys.persistence_mode, persistent);
+            }
+            if let Ok(connected) = device.is_display_connected() {
+                metrics.add_metric(keys.display_mode, connected);
+            }
+            if let Ok(active) = device.is_display_active() {
+                metrics.add_metric(keys.display_active, active);
+            }
+            if let Ok(mode) = device.compute_mode() {
+                metrics.add_metric(keys.compute_mode, compute_mode_name(mode));
+            }
+
+            let fan_speed = groups.fans.then(|| device.fan_speed(0).ok()).flatten();
+            if let Some(fan_speed) = fan_speed {
+                metrics.add_metric(keys.fan_speed, fan_speed);
+                if let Ok(fan_count) = device.num_fans() {
+                    metrics.add_metric(keys.fan_count, fan_count);
+                }
+                if let Some(Ok(target)) = self
+                    .ext
+                    .as_ref()
+                    .map(|ext| ext.target_fan_speed(&device, 0))
+                {
+                    metrics.add_metric(keys.fan_target_speed, target);
+                }
             }
 
-            if let Ok(fan_speed) = device.fan_speed(0) {
-                metrics.add_metric(&format!("_gpu.{}.fanSpeed", di), fan_speed);
+            if let Some(mode) = self.virtualization.get(di as usize).copied().flatten() {
+                metrics.add_metric(keys.virtualization_mode, mode);
+                match mode {
+                    "vgpu" => self.sample_vgpu_guest(&device, keys, metrics),
+                    "hostVgpu" => self.sample_vgpu_host(&device, di, keys, metrics),
+                    _ => {}
+                }
             }
 
             if let Ok(encoder_util) = device.encoder_utilization() {
-                metrics.add_metric(
-                    &format!("_gpu.{}.encoderUtilization", di),
-                    encoder_util.utilization,
-                );
+                metrics.add_metric(keys.encoder_utilization, encoder_util.utilization);
             }
 
             if let Ok(link_gen) = device.current_pcie_link_gen() {
-                metrics.add_metric(&format!("_gpu.{}.pcieLinkGen", di), link_gen);
+                metrics.add_metric(keys.pcie_link_gen, link_gen);
             }
 
             if let Ok(link_speed) = device.pcie_link_speed().map(u64::from).map(|x| x * 1000000) {
-                metrics.add_metric(&format!("_gpu.{}.pcieLinkSpeed", di), link_speed);
+                metrics.add_metric(keys.pcie_link_speed, link_speed);
             }
 
             if let Ok(link_width) = device.current_pcie_link_width() {
-                metrics.add_metric(&format!("_gpu.{}.pcieLinkWidth", di), link_width);
+                metrics.add_metric(keys.pcie_link_width, link_width);
             }
 
             if let Ok(max_link_gen) = device.max_pcie_link_gen() {
-                metrics.add_metric(&format!("_gpu.{}.maxPcieLinkGen", di), max_link_gen);
+                metrics.add_metric(keys.max_pcie_link_gen, max_link_gen);
             }
 
             if let Ok(max_link_width) = device.max_pcie_link_width() {
-                metrics.add_metric(&format!("_gpu.{}.maxPcieLinkWidth", di), max_link_width);
+                metrics.add_metric(keys.max_pcie_link_width, max_link_width);
             }
 
             if let Ok(cuda_cores) = device.num_cores() {
-                metrics.add_metric(&format!("_gpu.{}.cudaCores", di), cuda_cores);
+                metrics.add_metric(keys.cuda_cores, cuda_cores);
             }
 
             if let Ok(architecture) = device.architecture() {
-                metrics.add_metric(
-                    &format!("_gpu.{}.architecture", di),
-                    format!("{:?}", architecture),
-                );
+                metrics.add_metric(keys.architecture, format!("{:?}", architecture));
+            }
+        }
+
+        if options.profiling {
+            self.sample_gpm(metrics);
+        }
+        for board in &self.boards {
+            board.sample(&self.keys, metrics);
+        }
+        rollup::add_node_rollups(metrics);
+
+        Ok(())
+    }
+
+    /// Count the critical XID errors of a device among `xids`.
+    fn sample_xids(
+        &self,
+        device: &Device,
+        di: u32,
+        xids: &[(usize, u64)],
+        keys: &DeviceKeys,
+        metrics: &mut Metrics,
+    ) {
+        // SAFETY: the handle is only compared, never dereferenced
+        let handle = unsafe { device.handle() } as usize;
+        let mut states = self.xids.lock().unwrap_or_else(|e| e.into_inner());
+        let state = &mut states[di as usize];
+        for &(_, xid) in xids.iter().filter(|(device, _)| *device == handle) {
+            state.count += 1;
+            state.last = Some(xid);
+        }
+        metrics.add_metric(keys.xid_errors, state.count);
+        if let Some(last) = state.last {
+            metrics.add_metric(keys.last_xid, last);
+        }
+    }
+
+    /// Add the processes running on a device and/or their GPU usage per
+    /// Kubernetes pod or user, as selected by `options`.
+    fn sample_processes(
+        &self,
+        device: &Device,
+        di: u32,
+        options: &SampleOptions,
+        keys: &DeviceKeys,
+        metrics: &mut Metrics,
+    ) {
+        let mut processes = {
+            let mut last_seen = self.last_seen.lock().unwrap_or_else(|e| e.into_inner());
+            processes::collect(device, &mut last_seen[di as usize].processes)
+        };
+
+        if options.pods {
+            let usage = self
+                .pods
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .usage(&processes, device.uuid().ok().as_deref());
+            if let Ok(usage) = serde_json::to_value(usage) {
+                metrics.add_metric(keys.pods, usage);
+            }
+        }
+
+        if options.processes || options.users {
+            processes::describe(&mut processes);
+        }
+
+        if options.users {
+            if let Ok(usage) = serde_json::to_value(processes::usage_by_user(&processes)) {
+                metrics.add_metric(keys.users, usage);
             }
         }
 
+        if options.processes {
+            let mut names = self
+                .container_names
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            for container in processes.iter_mut().filter_map(|p| p.container.as_mut()) {
+                container.name = names.name(&container.id);
+            }
+            if let Ok(processes) = serde_json::to_value(processes) {
+                metrics.add_metric(keys.processes, processes);
+            }
+        }
+    }
+
+    /// Add the license and remote display state of a vGPU guest. Most other
+    /// device queries, e.g. temperature, power and fans, aren't supported in
+    /// a guest and are left out of its samples. The frame rate limit is a
+    /// property of the vGPU type that only the host can query.
+    fn sample_vgpu_guest(&self, device: &Device, keys: &DeviceKeys, metrics: &mut Metrics) {
+        if let Some(Ok(Some(license))) = self.ext.as_ref().map(|ext| ext.license(device)) {
+            metrics.add_metric(keys.licensed, license.licensed);
+            metrics.add_metric(keys.license_product, license.product);
+            if let Some(expiry) = license.expiry {
+                metrics.add_metric(keys.license_expiry, expiry);
+            }
+        }
+        if let Ok(fbc) = device.fbc_stats() {
+            metrics.add_metric(keys.fbc_sessions, fbc.sessions_count);
+            metrics.add_metric(keys.fbc_fps, fbc.average_fps);
+            metrics.add_metric(keys.fbc_latency_us, fbc.average_latency);
+        }
+    }
+
+    /// Add the vGPU instances, i.e. the per-VM usage, of a GPU on a vGPU host.
+    fn sample_vgpu_host(&self, device: &Device, di: u32, keys: &DeviceKeys, metrics: &mut Metrics) {
+        let Some(ext) = self.ext.as_ref() else {
+            return;
+        };
+        let mut last_seen = self.last_seen.lock().unwrap_or_else(|e| e.into_inner());
+        let Ok(instances) = ext.vgpu_instances(device, &mut last_seen[di as usize].vgpus) else {
+            return;
+        };
+        if let Ok(instances) = serde_json::to_value(instances) {
+            metrics.add_metric(keys.vgpus, instances);
+        }
+    }
+
+    /// Add the GPM profiling metrics of each device that supports GPM.
+    fn sample_gpm(&self, metrics: &mut Metrics) {
+        let Some(ext) = self.ext.as_ref() else {
+            return;
+        };
+        let mut gpm = self.gpm.lock().unwrap_or_else(|e| e.into_inner());
+        let counters = gpm.get_or_insert_with(|| {
+            (0..self.device_count)
+                .map(|di| {
+                    let device = self.nvml.device_by_index(di).ok()?;
+                    let counters = GpmCounters::new(ext, &device, di);
+                    if counters.is_none() {
+                        log::info!(
+                            "GPU {} doesn't support GPM (Hopper and newer), skipping profiling metrics",
+                            di
+                        );
+                    }
+                    counters
+                })
+                .collect()
+        });
+        for (di, counters) in counters.iter_mut().enumerate() {
+            let Some(counters) = counters else {
+                continue;
+            };
+            let Ok(device) = self.nvml.device_by_index(di as u32) else {
+                continue;
+            };
+            if let Err(e) = counters.sample(ext, &device, metrics) {
+                log::warning!("Error sampling GPU {} GPM metrics: {}", di, e);
+            }
+        }
+    }
+
+    /// Add the max and mean of the utilization and power samples the driver
+    /// buffered since the previous call.
+    fn sample_buffered(&self, device: &Device, di: u32, keys: &DeviceKeys, metrics: &mut Metrics) {
+        let mut last_seen = self.last_seen.lock().unwrap_or_else(|e| e.into_inner());
+        let last_seen = &mut last_seen[di as usize];
+
+        if let Some((max, mean, period)) =
+            buffered_samples(device, Sampling::GpuUtilization, &mut last_seen.utilization)
+        {
+            metrics.add_metric(keys.gpu_max, max);
+            metrics.add_metric(keys.gpu_mean, mean);
+            last_seen.utilization_period = period.or(last_seen.utilization_period);
+        }
+        if let Some(period) = last_seen.utilization_period {
+            metrics.add_metric(keys.utilization_period_ms, period as f64 / 1000.0);
+        }
+        // Power samples are in milliwatts
+        if let Some((max, mean, _)) =
+            buffered_samples(device, Sampling::Power, &mut last_seen.power)
+        {
+            metrics.add_metric(keys.power_watts_max, max / 1000.0);
+            metrics.add_metric(keys.power_watts_mean, mean / 1000.0);
+        }
+    }
+
+    /// How often the driver samples each GPU's utilization, i.e. the window
+    /// `gpu.{i}.gpu` averages over, if known.
+    pub fn utilization_periods(&self) -> Vec<Option<Duration>> {
+        let last_seen = self.last_seen.lock().unwrap_or_else(|e| e.into_inner());
+        last_seen
+            .iter()
+            .map(|seen| seen.utilization_period.map(Duration::from_micros))
+            .collect()
+    }
+
+    /// Describe the devices and the driver, see `Devices`.
+    pub fn devices(&self) -> Devices {
+        let gpus = (0..self.device_count)
+            .map(|di| {
+                let device = self.nvml.device_by_index(di).ok();
+                let gsp = device
+                    .as_ref()
+                    .filter(|_| self.features.gsp_firmware)
+                    .and_then(|device| {
+                        let ext = self.ext.as_ref()?;
+                        let (enabled, _) = ext.gsp_firmware_mode(device).ok()?;
+                        let version = enabled
+                            .then(|| ext.gsp_firmware_version(device).ok())
+                            .flatten();
+                        Some((if enabled { "enabled" } else { "disabled" }, version))
+                    });
+                DeviceInfo {
+                    index: di,
+                    name: device.as_ref().and_then(|d| d.name().ok()),
+                    uuid: device.as_ref().and_then(|d| d.uuid().ok()),
+                    architecture: device
+                        .as_ref()
+                        .and_then(|d| d.architecture().ok())
+                        .map(|arch| format!("{:?}", arch)),
+                    class: self
+                        .classes
+                        .get(di as usize)
+                        .map_or(DeviceClass::Unknown, |class| *class)
+                        .as_str(),
+                    gsp_firmware_mode: gsp.as_ref().map(|(mode, _)| *mode),
+                    gsp_firmware_version: gsp.and_then(|(_, version)| version),
+                }
+            })
+            .collect();
+        Devices {
+            driver_version: self.features.driver_version.clone(),
+            kernel_module: devices::kernel_module(),
+            gpus,
+        }
+    }
+
+    /// Describe how the GPUs are connected to each other and to the host.
+    pub fn topology(&self) -> Result<Topology, NvmlError> {
+        Topology::discover(&self.nvml)
+    }
+
+    /// Describe what `settings` would change on `gpus` (all if `None`).
+    pub fn plan_settings(
+        &self,
+        settings: &DeviceSettings,
+        gpus: Option<&[u32]>,
+    ) -> Result<Vec<String>, SettingError> {
+        settings.plan(&self.nvml, gpus)
+    }
+
+    /// Apply `settings` to `gpus` (all if `None`), returning per-device failures.
+    pub fn apply_settings(
+        &self,
+        settings: &DeviceSettings,
+        gpus: Option<&[u32]>,
+    ) -> Result<Vec<SettingError>, SettingError> {
+        settings.apply(&self.nvml, gpus)
+    }
+
+    /// The power limits each GPU accepts, in watts, if known.
+    pub fn power_limit_ranges(&self) -> Vec<Option<(f64, f64)>> {
+        (0..self.device_count)
+            .map(|di| {
+                let constraints = self
+                    .nvml
+                    .device_by_index(di)
+                    .and_then(|device| device.power_management_limit_constraints())
+                    .ok()?;
+                Some((
+                    constraints.min_limit as f64 / 1000.0,
+                    constraints.max_limit as f64 / 1000.0,
+                ))
+            })
+            .collect()
+    }
+
+    /// Set all fans of a GPU to `percent`, clamped to what the device accepts,
+    /// or hand them back to the driver if `None`.
+    pub fn set_fan_speed(&self, gpu: u32, percent: Option<u32>) -> Result<(), NvmlError> {
+        let ext = self.ext.as_ref().ok_or(NvmlError::FunctionNotFound)?;
+        let device = self.nvml.device_by_index(gpu)?;
+        let percent = match percent {
+            Some(percent) => {
+                let (min, max) = ext.min_max_fan_speed(&device).unwrap_or((0, 100));
+                Some(percent.clamp(min, max))
+            }
+            None => None,
+        };
+        for fan in 0..device.num_fans()? {
+            match percent {
+                Some(percent) => ext.set_fan_speed(&device, fan, percent)?,
+                None => ext.set_default_fan_speed(&device, fan)?,
+            }
+        }
         Ok(())
     }
 
+    /// Whether accounting statistics can be read, or `None` if no GPU has
+    /// accounting mode enabled. The driver may restrict them to root, see
+    /// `nvidia-smi --accounted-apps-permission`.
+    pub fn accounting_access(&self) -> Option<bool> {
+        let mut access = None;
+        for index in 0..self.device_count {
+            let Ok(device) = self.nvml.device_by_index(index) else {
+                continue;
+            };
+            if !device.is_accounting_enabled().unwrap_or(false) {
+                continue;
+            }
+            match device.accounting_pids() {
+                Err(NvmlError::NoPermission) => return Some(false),
+                _ => access = Some(true),
+            }
+        }
+        access
+    }
+
+    /// Whether ECC error counters can be read, or `None` if no GPU has ECC
+    /// enabled. Some drivers only return them to root.
+    pub fn ecc_access(&self) -> Option<bool> {
+        let mut access = None;
+        for index in 0..self.device_count {
+            let Ok(device) = self.nvml.device_by_index(index) else {
+                continue;
+            };
+            if !device
+                .is_ecc_enabled()
+                .is_ok_and(|mode| mode.currently_enabled)
+            {
+                continue;
+            }
+            match device.memory_error_counter(
+                MemoryError::Corrected,
+                EccCounter::Volatile,
+                MemoryLocation::Device,
+            ) {
+                Err(NvmlError::NoPermission) => return Some(false),
+                _ => access = Some(true),
+            }
+        }
+        access
+    }
+
+    /// PIDs of compute and graphics processes running on a GPU.
+    pub fn gpu_processes(&self, gpu: u32) -> Result<Vec<u32>, NvmlError> {
+        let device = self.nvml.device_by_index(gpu)?;
+        let mut pids: Vec<u32> = device
+            .running_compute_processes()?
+            .into_iter()
+            .chain(device.running_graphics_processes().unwrap_or_default())
+            .map(|process| process.pid)
+            .collect();
+        pids.sort_unstable();
+        pids.dedup();
+        Ok(pids)
+    }
+
+    /// Stop new processes from attaching to a GPU, or allow them again.
+    #[cfg(target_os = "linux")]
+    pub fn set_drain(&self, gpu: u32, enabled: bool) -> Result<(), NvmlError> {
+        self.nvml.device_by_index(gpu)?.set_drain(enabled, None)
+    }
+
+    /// Detach a drained GPU from the driver and probe its PCI slot again,
+    /// which resets it. The GPU must have no processes attached and
+    /// persistence mode off. Device indices may change afterwards.
+    #[cfg(target_os = "linux")]
+    pub fn reset(&self, gpu: u32) -> Result<(), NvmlError> {
+        use nvml_wrapper::enum_wrappers::device::{DetachGpuState, PcieLinkState};
+
+        let device = self.nvml.device_by_index(gpu)?;
+        let pci_info = device.pci_info()?;
+        let (result, _) = device.remove(None, DetachGpuState::Remove, PcieLinkState::Keep);
+        result.map_err(|e| e.error)?;
+        self.nvml.discover_gpus(pci_info)
+    }
+
     pub fn shutdown(self) -> Result<(), NvmlError> {
+        // Free the GPM samples and the event set while NVML is still initialized
+        let gpm = self.gpm.into_inner().unwrap_or_else(|e| e.into_inner());
+        if let Some(ext) = self.ext.as_ref() {
+            for counters in gpm.into_iter().flatten().flatten() {
+                counters.free(ext);
+            }
+        }
+        drop(self.ext);
         self.nvml.shutdown()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_out_partial_board_totals() {
+        let keys: Vec<&'static DeviceKeys> = (0..2)
+            .map(|di| &*Box::leak(Box::new(DeviceKeys::new(di))))
+            .collect();
+        let mut board = Board::new(7, 0);
+        board.gpus.push(1);
+
+        let mut metrics = Metrics::new();
+        metrics.add_metric(keys[0].power_watts, 100.0);
+        metrics.add_metric(keys[1].power_watts, 50.0);
+        metrics.add_metric(keys[0].temp, 60);
+        board.sample(&keys, &mut metrics);
+        assert_eq!(metrics.get("_board.7.powerWatts"), Some(&150.0.into()));
+        assert_eq!(metrics.get("_board.7.enforcedPowerLimitWatts"), None);
+        assert_eq!(metrics.get("_board.7.temp"), Some(&60.0.into()));
+
+        let mut metrics = Metrics::new();
+        metrics.add_metric(keys[0].power_watts, 100.0);
+        board.sample(&keys, &mut metrics);
+        assert_eq!(metrics.get("_board.7.powerWatts"), None);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::manifest::{self, MetricInfo};
+use crate::metrics::MetricKind;
+use crate::series::Series;
+use serde_json::{json, Value};
+
+/// Where a dashboard reads symon's metrics from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Datasource {
+    /// Prometheus, with nodes told apart by the `instance` label of the scrape target
+    Prom,
+    /// InfluxDB 1.x or InfluxQL, with nodes told apart by the `host` tag
+    Influx,
+}
+
+impl Datasource {
+    fn plugin(self) -> &'static str {
+        match self {
+            Datasource::Prom => "prometheus",
+            Datasource::Influx => "influxdb",
+        }
+    }
+
+    /// Label or tag telling nodes apart.
+    fn host_label(self) -> &'static str {
+        match self {
+            Datasource::Prom => "instance",
+            Datasource::Influx => "host",
+        }
+    }
+}
+
+const PANEL_WIDTH: u64 = 12;
+const PANEL_HEIGHT: u64 = 8;
+
+/// A Grafana dashboard with a time series panel for every numeric metric in
+/// the manifest, ready to import. Counters are plotted as rates. The data
+/// source and the nodes shown are dashboard variables.
+pub fn dashboard(datasource: Datasource) -> Value {
+    let panels: Vec<Value> = manifest::metrics()
+        .filter(|metric| is_plotted(metric))
+        .filter_map(|metric| {
+            let series = Series::of(&metric.name.replace("{}", "0"))?;
+            Some((metric, series))
+        })
+        .enumerate()
+        .map(|(i, (metric, series))| panel(datasource, i as u64, metric, &series))
+        .collect();
+    let host = datasource.host_label();
+    let hosts = match datasource {
+        Datasource::Prom => json!(format!("label_values(symon_gpu_count, {})", host)),
+        Datasource::Influx => json!(format!(
+            "SHOW TAG VALUES FROM \"symon_gpu\" WITH KEY = \"{}\"",
+            host
+        )),
+    };
+    json!({
+        "title": "symon",
+        "uid": format!("symon-{}", datasource.plugin()),
+        "tags": ["symon"],
+        "timezone": "browser",
+        "schemaVersion": 39,
+        "refresh": "30s",
+        "time": {"from": "now-1h", "to": "now"},
+        "templating": {
+            "list": [
+                {
+                    "name": "datasource",
+                    "label": "Data source",
+                    "type": "datasource",
+                    "query": datasource.plugin(),
+                },
+                {
+                    "name": host,
+                    "label": "Node",
+                    "type": "query",
+                    "datasource": {"type": datasource.plugin(), "uid": "${datasource}"},
+                    "query": hosts,
+                    "refresh": 2,
+                    "multi": true,
+                    "includeAll": true,
+                    "current": {"text": "All", "value": "$__all"},
+                },
+            ],
+        },
+        "panels": panels,
+    })
+}
+
+/// Whether a metric has numeric values worth plotting. Names, lists and
+/// families documented by pattern, e.g. `gpu.{i}.<gpu|powerWatts|temp>P<n>`,
+/// are left out.
+fn is_plotted(metric: &MetricInfo) -> bool {
+    !metric.name.contains('<')
+        && (!metric.unit.is_empty()
+            || metric.kind() == MetricKind::Counter
+            || metric.name.ends_with("Count")
+            || metric.name.ends_with(".count"))
+}
+
+fn panel(datasource: Datasource, i: u64, metric: &MetricInfo, series: &Series) -> Value {
+    let counter = metric.kind() == MetricKind::Counter;
+    let labels: Vec<&str> = series.labels.iter().map(|(l, _)| l.as_str()).collect();
+    let host = datasource.host_label();
+    let target = match datasource {
+        Datasource::Prom => {
+            let selector = format!("{}{{{}=~\"${}\"}}", series.name(), host, host);
+            let legend: String = std::iter::once(host)
+                .chain(labels.iter().copied())
+                .map(|label| format!("{{{{{}}}}}", label))
+                .collect::<Vec<_>>()
+                .join(" ");
+            json!({
+                "refId": "A",
+                "expr": if counter {
+                    format!("rate({}[$__rate_interval])", selector)
+                } else {
+                    selector
+                },
+                "legendFormat": legend,
+            })
+        }
+        Datasource::Influx => {
+            let value = if counter {
+                format!("non_negative_derivative(mean(\"{}\"), 1s)", series.field)
+            } else {
+                format!("mean(\"{}\")", series.field)
+            };
+            let group_by: String = std::iter::once(host)
+                .chain(labels.iter().copied())
+                .map(|label| format!(", \"{}\"", label))
+                .collect();
+            json!({
+                "refId": "A",
+                "rawQuery": true,
+                "resultFormat": "time_series",
+                "query": format!(
+                    "SELECT {} FROM \"{}\" WHERE \"{}\" =~ /^${}$/ AND $timeFilter GROUP BY time($__interval){}",
+                    value, series.measurement, host, host, group_by
+                ),
+            })
+        }
+    };
+    let title = if counter {
+        format!("{} per second", metric.display_name())
+    } else {
+        metric.display_name()
+    };
+    json!({
+        "id": i + 1,
+        "type": "timeseries",
+        "title": title,
+        "description": metric.description,
+        "datasource": {"type": datasource.plugin(), "uid": "${datasource}"},
+        "gridPos": {
+            "x": (i % 2) * PANEL_WIDTH,
+            "y": (i / 2) * PANEL_HEIGHT,
+            "w": PANEL_WIDTH,
+            "h": PANEL_HEIGHT,
+        },
+        "fieldConfig": {"defaults": {"unit": grafana_unit(metric.unit, counter)}, "overrides": []},
+        "options": {"legend": {"displayMode": "list", "placement": "bottom"}},
+        "targets": [target],
+    })
+}
+
+/// Grafana's unit for a manifest unit, per second for counters.
+fn grafana_unit(unit: &str, counter: bool) -> &'static str {
+    match (unit, counter) {
+        ("J", true) => "watt",
+        // Seconds in a state per second
+        ("s", true) => "percentunit",
+        (_, true) => "short",
+        ("%", _) => "percent",
+        ("W", _) => "watt",
+        ("Celsius", _) => "celsius",
+        ("bytes", _) => "bytes",
+        ("bytes/s", _) => "Bps",
+        ("ms", _) => "ms",
+        ("s", _) => "s",
+        ("J", _) => "joule",
+        ("MHz", _) => "rotmhz",
+        _ => "short",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn panel_titled<'a>(dashboard: &'a Value, title: &str) -> &'a Value {
+        dashboard["panels"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|panel| panel["title"] == title)
+            .unwrap()
+    }
+
+    #[test]
+    fn queries_prometheus_series() {
+        let dashboard = dashboard(Datasource::Prom);
+        let power = panel_titled(&dashboard, "gpu.{i}.powerWatts");
+        assert_eq!(
+            power["targets"][0]["expr"],
+            "symon_gpu_power_watts{instance=~\"$instance\"}"
+        );
+        assert_eq!(power["targets"][0]["legendFormat"], "{{instance}} {{gpu}}");
+        assert_eq!(power["fieldConfig"]["defaults"]["unit"], "watt");
+        let energy = panel_titled(&dashboard, "_gpu.{i}.energyJoules per second");
+        assert_eq!(
+            energy["targets"][0]["expr"],
+            "rate(symon_gpu_energy_joules{instance=~\"$instance\"}[$__rate_interval])"
+        );
+    }
+
+    #[test]
+    fn queries_influx_measurements() {
+        let dashboard = dashboard(Datasource::Influx);
+        let temp = panel_titled(&dashboard, "gpu.{i}.temp");
+        assert_eq!(
+            temp["targets"][0]["query"],
+            "SELECT mean(\"temp\") FROM \"symon_gpu\" WHERE \"host\" =~ /^$host$/ \
+             AND $timeFilter GROUP BY time($__interval), \"host\", \"gpu\""
+        );
+    }
+
+    #[test]
+    fn leaves_out_names() {
+        let dashboard = dashboard(Datasource::Prom);
+        let titles: Vec<&str> = dashboard["panels"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|panel| panel["title"].as_str().unwrap())
+            .collect();
+        assert!(!titles.contains(&"_gpu.{i}.name"));
+        assert!(titles.contains(&"_gpu.count"));
+    }
+}
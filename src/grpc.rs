@@ -0,0 +1,267 @@
+//! A minimal gRPC client for unary calls over cleartext HTTP/2, enough to
+//! talk to local services such as the kubelet's pod resources API without an
+//! async runtime.
+//!
+//! Request headers are sent as literals, so no HPACK state is kept, and
+//! response headers are skipped: a call succeeds if the server sends a
+//! response message and fails if the stream ends without one.
+
+use prost::Message;
+use std::io::{self, Read, Write};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+/// Flow control window we grant, the largest allowed, so responses never
+/// wait for window updates.
+const MAX_WINDOW: u32 = 0x7fff_ffff;
+/// Window of a new connection before any updates.
+const DEFAULT_WINDOW: u32 = 65_535;
+/// Largest response message accepted.
+const MAX_MESSAGE: usize = 16 << 20;
+
+const STREAM: u32 = 1;
+
+/// Call `path`, e.g. `/v1.PodResourcesLister/List`, on a fresh connection.
+/// Timeouts are up to the caller, e.g. read and write timeouts on `stream`.
+pub fn unary<Req: Message, Resp: Message + Default>(
+    mut stream: impl Read + Write,
+    path: &str,
+    request: &Req,
+) -> io::Result<Resp> {
+    let mut out = PREFACE.to_vec();
+    let mut settings = Vec::new();
+    for (id, value) in [
+        (SETTINGS_ENABLE_PUSH, 0),
+        (SETTINGS_INITIAL_WINDOW_SIZE, MAX_WINDOW),
+    ] {
+        settings.extend_from_slice(&id.to_be_bytes());
+        settings.extend_from_slice(&value.to_be_bytes());
+    }
+    frame(&mut out, SETTINGS, 0, 0, &settings);
+    // The connection window isn't covered by SETTINGS_INITIAL_WINDOW_SIZE
+    frame(
+        &mut out,
+        WINDOW_UPDATE,
+        0,
+        0,
+        &(MAX_WINDOW - DEFAULT_WINDOW).to_be_bytes(),
+    );
+
+    let mut headers = Vec::new();
+    for (name, value) in [
+        (":method", "POST"),
+        (":scheme", "http"),
+        (":path", path),
+        (":authority", "localhost"),
+        ("content-type", "application/grpc"),
+        ("te", "trailers"),
+    ] {
+        literal_header(&mut headers, name, value);
+    }
+    frame(&mut out, HEADERS, END_HEADERS, STREAM, &headers);
+
+    let message = request.encode_to_vec();
+    let mut data = Vec::with_capacity(5 + message.len());
+    data.push(0);
+    data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    data.extend_from_slice(&message);
+    frame(&mut out, DATA, END_STREAM, STREAM, &data);
+    stream.write_all(&out)?;
+    stream.flush()?;
+
+    let mut body = Vec::new();
+    loop {
+        let (kind, flags, stream_id, mut payload) = read_frame(&mut stream)?;
+        match kind {
+            SETTINGS if flags & ACK == 0 => {
+                let mut ack = Vec::new();
+                frame(&mut ack, SETTINGS, ACK, 0, &[]);
+                stream.write_all(&ack)?;
+            }
+            PING if flags & ACK == 0 => {
+                let mut pong = Vec::new();
+                frame(&mut pong, PING, ACK, 0, &payload);
+                stream.write_all(&pong)?;
+            }
+            DATA if stream_id == STREAM => {
+                if flags & PADDED != 0 {
+                    let padding = *payload.first().unwrap_or(&0) as usize;
+                    let end = payload.len().saturating_sub(padding);
+                    payload.truncate(end);
+                    payload.drain(..1.min(payload.len()));
+                }
+                if body.len() + payload.len() > MAX_MESSAGE + 5 {
+                    return Err(io::Error::other("gRPC response too large"));
+                }
+                body.extend_from_slice(&payload);
+                if flags & END_STREAM != 0 {
+                    break;
+                }
+            }
+            HEADERS if stream_id == STREAM && flags & END_STREAM != 0 => break,
+            RST_STREAM if stream_id == STREAM => {
+                return Err(io::Error::other("gRPC call was reset"));
+            }
+            GOAWAY => return Err(io::Error::other("server closed the connection")),
+            _ => {}
+        }
+    }
+
+    // Length-prefixed message: compressed flag, then length
+    if body.len() < 5 {
+        return Err(io::Error::other("gRPC call failed without a response"));
+    }
+    if body[0] != 0 {
+        return Err(io::Error::other(
+            "compressed gRPC responses are not supported",
+        ));
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    let message = body
+        .get(5..5 + len)
+        .ok_or_else(|| io::Error::other("truncated gRPC response"))?;
+    Resp::decode(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.push(flags);
+    out.extend_from_slice(&stream.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+fn read_frame(stream: &mut impl Read) -> io::Result<(u8, u8, u32, Vec<u8>)> {
+    let mut head = [0; 9];
+    stream.read_exact(&mut head)?;
+    let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+    let stream_id = u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok((head[3], head[4], stream_id, payload))
+}
+
+/// HPACK literal header field without indexing, with a new name (RFC 7541 6.2.2).
+fn literal_header(out: &mut Vec<u8>, name: &str, value: &str) {
+    out.push(0);
+    for s in [name, value] {
+        hpack_int(out, s.len(), 7);
+        out.extend_from_slice(s.as_bytes());
+    }
+}
+
+/// HPACK integer with an N-bit prefix (RFC 7541 5.1), Huffman flag unset.
+fn hpack_int(out: &mut Vec<u8>, mut n: usize, prefix: u32) {
+    let max = (1 << prefix) - 1;
+    if n < max {
+        out.push(n as u8);
+        return;
+    }
+    out.push(max as u8);
+    n -= max;
+    while n >= 128 {
+        out.push((n % 128 + 128) as u8);
+        n /= 128;
+    }
+    out.push(n as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Echo {
+        #[prost(string, tag = "1")]
+        text: String,
+    }
+
+    /// A server's side of a connection: `input` is what it sends.
+    struct Conversation {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Conversation {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Conversation {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn encodes_long_lengths() {
+        let mut out = Vec::new();
+        hpack_int(&mut out, 1337, 5);
+        assert_eq!(out, [31, 154, 10]);
+    }
+
+    #[test]
+    fn reads_a_response_split_across_frames() {
+        let message = Echo {
+            text: "hello".to_string(),
+        }
+        .encode_to_vec();
+        let mut body = vec![0];
+        body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        body.extend_from_slice(&message);
+
+        let mut input = Vec::new();
+        frame(&mut input, SETTINGS, 0, 0, &[]);
+        frame(&mut input, HEADERS, END_HEADERS, STREAM, &[0x88]);
+        frame(&mut input, DATA, 0, STREAM, &body[..3]);
+        frame(&mut input, PING, 0, 0, &[7; 8]);
+        frame(&mut input, DATA, 0, STREAM, &body[3..]);
+        frame(&mut input, HEADERS, END_HEADERS | END_STREAM, STREAM, &[]);
+        let mut conversation = Conversation {
+            input: io::Cursor::new(input),
+            output: Vec::new(),
+        };
+        let response: Echo = unary(&mut conversation, "/test.Echo/Echo", &Echo::default()).unwrap();
+        assert_eq!(response.text, "hello");
+
+        let mut pong = Vec::new();
+        frame(&mut pong, PING, ACK, 0, &[7; 8]);
+        assert!(conversation.output.starts_with(PREFACE));
+        assert!(conversation
+            .output
+            .windows(pong.len())
+            .any(|window| window == pong));
+    }
+
+    #[test]
+    fn fails_without_a_response() {
+        let mut input = Vec::new();
+        frame(&mut input, HEADERS, END_HEADERS | END_STREAM, STREAM, &[]);
+        let conversation = Conversation {
+            input: io::Cursor::new(input),
+            output: Vec::new(),
+        };
+        let result: io::Result<Echo> = unary(conversation, "/test.Echo/Echo", &Echo::default());
+        assert!(result.is_err());
+    }
+}
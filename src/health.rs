@@ -0,0 +1,155 @@
+use crate::metrics::{MetricKey, Metrics};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A health check result.
+#[derive(Clone, Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Overall result of a set of checks.
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    pub ok: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Status {
+    fn new(checks: Vec<Check>) -> Self {
+        Status {
+            ok: checks.iter().all(|check| check.ok),
+            checks,
+        }
+    }
+
+    /// Write as JSON, replacing `path` atomically.
+    pub fn write_file(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Node health derived from the sample stream, for Kubernetes probes.
+///
+/// Liveness only asks whether the sampling loop is making progress, since
+/// restarting the agent can't fix a sick GPU. Readiness additionally requires
+/// NVML to respond, no uncorrected ECC or critical XID errors since the agent
+/// started, and sinks to accept writes, so a node with a sick GPU can be
+/// taken out of rotation.
+pub struct Health {
+    /// Samples older than this mean the sampling loop is stuck.
+    stale_after: Duration,
+    last_sample: Option<Instant>,
+    nvml_error: Option<String>,
+    /// First value of each fault counter, to detect increases.
+    baselines: HashMap<MetricKey, f64>,
+    faults: Vec<String>,
+    /// Last error count of each sink.
+    sink_errors: HashMap<MetricKey, f64>,
+    failing_sinks: Vec<String>,
+}
+
+pub type SharedHealth = Arc<Mutex<Health>>;
+
+impl Health {
+    pub fn new(interval: Duration) -> Self {
+        Health {
+            stale_after: (interval * 3).max(Duration::from_secs(10)),
+            last_sample: None,
+            nvml_error: None,
+            baselines: HashMap::new(),
+            faults: Vec::new(),
+            sink_errors: HashMap::new(),
+            failing_sinks: Vec::new(),
+        }
+    }
+
+    pub fn shared(interval: Duration) -> SharedHealth {
+        Arc::new(Mutex::new(Health::new(interval)))
+    }
+
+    /// Adjust staleness to a new sampling interval.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.stale_after = (interval * 3).max(Duration::from_secs(10));
+    }
+
+    pub fn update(&mut self, metrics: &Metrics) {
+        self.last_sample = Some(Instant::now());
+        self.nvml_error = if metrics.get("_sampling_timeout").is_some() {
+            Some("NVML timed out".to_string())
+        } else if metrics.get("_gpu.count").is_none() {
+            Some("no GPU metrics".to_string())
+        } else {
+            None
+        };
+
+        self.failing_sinks.clear();
+        metrics.for_each(|key, value| {
+            let Some(value) = value.as_f64() else {
+                return;
+            };
+            if key.ends_with(".uncorrectedMemoryErrors") || key.ends_with(".xidErrors") {
+                let baseline = *self.baselines.entry(key.clone()).or_insert(value);
+                if value > baseline && !self.faults.iter().any(|fault| fault == key.as_ref()) {
+                    self.faults.push(key.to_string());
+                }
+            } else if key.starts_with("_agent.sink.") && key.ends_with(".errors") {
+                let last = self.sink_errors.insert(key.clone(), value);
+                if last.is_some_and(|last| value > last) {
+                    self.failing_sinks.push(key.to_string());
+                }
+            }
+        });
+    }
+
+    fn sampling(&self) -> Check {
+        let age = self.last_sample.map(|last| last.elapsed());
+        Check {
+            name: "sampling",
+            ok: age.is_some_and(|age| age <= self.stale_after),
+            detail: match age {
+                Some(age) => Some(format!("last sample {:.1}s ago", age.as_secs_f64())),
+                None => Some("no samples yet".to_string()),
+            },
+        }
+    }
+
+    /// Liveness: the sampling loop is making progress.
+    pub fn live(&self) -> Status {
+        Status::new(vec![self.sampling()])
+    }
+
+    /// Readiness: sampling, NVML, GPU faults and sinks are all healthy.
+    pub fn ready(&self) -> Status {
+        let failing = |items: &[String]| (!items.is_empty()).then(|| items.join(", "));
+        Status::new(vec![
+            self.sampling(),
+            Check {
+                name: "nvml",
+                ok: self.nvml_error.is_none(),
+                detail: self.nvml_error.clone(),
+            },
+            Check {
+                name: "gpu_faults",
+                ok: self.faults.is_empty(),
+                detail: failing(&self.faults),
+            },
+            Check {
+                name: "sinks",
+                ok: self.failing_sinks.is_empty(),
+                detail: failing(&self.failing_sinks),
+            },
+        ])
+    }
+}
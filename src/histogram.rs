@@ -0,0 +1,103 @@
+use crate::report::gpu_field;
+use std::collections::BTreeMap;
+
+/// Relative error of percentiles.
+const PRECISION: f64 = 0.01;
+/// Ratio of the bounds of a bucket.
+const GAMMA: f64 = (1.0 + PRECISION) / (1.0 - PRECISION);
+
+/// Percentiles reported when none are configured.
+pub const DEFAULT_PERCENTILES: &[f64] = &[50.0, 90.0, 99.0];
+
+/// A streaming histogram of readings with logarithmic buckets, so
+/// percentiles are within 1% of the exact value however long it runs.
+///
+/// A day of 1 Hz power readings between 50 and 700 W fits in under 150
+/// buckets, where keeping the readings would take 86400.
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    /// Number of readings by bucket; bucket `i` holds `(GAMMA^(i-1), GAMMA^i]`.
+    buckets: BTreeMap<i32, u64>,
+    /// Number of readings of zero or less, e.g. an idle GPU's utilization.
+    zeros: u64,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram::default()
+    }
+
+    pub fn add(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+        if self.count == 0 || value < self.min {
+            self.min = value;
+        }
+        if self.count == 0 || value > self.max {
+            self.max = value;
+        }
+        self.count += 1;
+        if value <= 0.0 {
+            self.zeros += 1;
+        } else {
+            let bucket = (value.ln() / GAMMA.ln()).ceil() as i32;
+            *self.buckets.entry(bucket).or_default() += 1;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn clear(&mut self) {
+        self.buckets.clear();
+        self.zeros = 0;
+        self.count = 0;
+    }
+
+    /// Nearest-rank percentile, 0-100.
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0) * self.count as f64).ceil() as u64;
+        let rank = rank.clamp(1, self.count);
+        if rank <= self.zeros {
+            return Some(self.min.min(0.0));
+        }
+        let mut seen = self.zeros;
+        for (&bucket, &count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                // The value with the least relative error to any in the bucket
+                let value = 2.0 * GAMMA.powi(bucket) / (GAMMA + 1.0);
+                return Some(value.clamp(self.min, self.max));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+/// Whether percentiles are kept for a metric: power, utilization and
+/// temperature of each GPU.
+pub fn is_tracked(key: &str) -> bool {
+    key.starts_with("gpu.") && matches!(gpu_field(key), Some((_, "powerWatts" | "gpu" | "temp")))
+}
+
+/// Name of a percentile of a metric, e.g. `gpu.0.powerWattsP99`.
+pub fn percentile_key(key: &str, p: f64) -> String {
+    // Keep the last key segment free of dots, e.g. `P99_9`
+    format!("{}P{}", key, p.to_string().replace('.', "_"))
+}
+
+/// Parse a percentile, 0-100, e.g. `99` or `p99`.
+pub fn parse_percentile(s: &str) -> Result<f64, String> {
+    match s.trim().trim_start_matches(['p', 'P']).parse::<f64>() {
+        Ok(p) if (0.0..=100.0).contains(&p) => Ok(p),
+        _ => Err(format!("invalid percentile: {:?}", s)),
+    }
+}
@@ -0,0 +1,83 @@
+use crate::metrics::Metrics;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The most recent samples, covering a fixed window of time.
+///
+/// Lets clients that connect late backfill recent context. Evicted samples
+/// are reused for new ones, so a full buffer doesn't allocate per sample.
+pub struct History {
+    window: f64,
+    samples: VecDeque<Metrics>,
+    spare: Option<Metrics>,
+}
+
+/// A history shared between the sampling loop and its readers.
+pub type SharedHistory = Arc<Mutex<History>>;
+
+impl History {
+    pub fn new(window: Duration) -> Self {
+        History {
+            window: window.as_secs_f64(),
+            samples: VecDeque::new(),
+            spare: None,
+        }
+    }
+
+    pub fn shared(window: Duration) -> SharedHistory {
+        Arc::new(Mutex::new(History::new(window)))
+    }
+
+    /// Record a copy of `metrics`. Samples without a `_timestamp` are ignored.
+    pub fn push(&mut self, metrics: &Metrics) {
+        let Some(timestamp) = metrics.timestamp() else {
+            return;
+        };
+        while let Some(oldest) = self.samples.front() {
+            if oldest
+                .timestamp()
+                .is_some_and(|t| t >= timestamp - self.window)
+            {
+                break;
+            }
+            self.spare = self.samples.pop_front();
+        }
+        let mut sample = self.spare.take().unwrap_or_default();
+        sample.copy_from(metrics);
+        self.samples.push_back(sample);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Samples taken after `since` (Unix epoch seconds), oldest first.
+    pub fn since(&self, since: f64) -> impl Iterator<Item = &Metrics> {
+        // Samples are in time order, so skip straight to the first match
+        let start = self
+            .samples
+            .partition_point(|m| m.timestamp().is_some_and(|t| t <= since));
+        self.samples.range(start..)
+    }
+
+    /// All retained samples, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &Metrics> {
+        self.samples.iter()
+    }
+
+    /// Write samples taken after `since` as JSON lines.
+    pub fn write_json_lines(&self, since: f64, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut line = Vec::new();
+        for metrics in self.since(since) {
+            metrics.to_json_line(&mut line)?;
+            out.extend_from_slice(&line);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,364 @@
+use crate::control::{Control, ControlAccess, Operation};
+use crate::health::{Health, SharedHealth, Status};
+use crate::history::SharedHistory;
+use crate::log;
+use crate::manifest;
+use crate::marker;
+use crate::tls::{self, Acceptor, Stream};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time a client has to send its whole request, including the TLS handshake.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest request line and headers accepted; no endpoint reads a body.
+const MAX_REQUEST_BYTES: u64 = 16 * 1024;
+/// Connections served at once; further ones are closed right away.
+const MAX_CONNECTIONS: usize = 32;
+
+/// Data served over HTTP.
+pub struct HttpState {
+    pub history: SharedHistory,
+    pub health: SharedHealth,
+    /// Serve HTTPS instead of plain HTTP.
+    pub tls: Option<Acceptor>,
+    /// Require `Authorization: Bearer <token>` for `/history`.
+    pub token: Option<String>,
+    /// Serve the control endpoints; they are absent otherwise.
+    pub control: Option<ControlAccess>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status: &'static str, body: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.as_bytes().to_vec(),
+        }
+    }
+}
+
+/// Serve the read-only HTTP API on `addr` from a background thread, with a
+/// thread of its own for each connection.
+///
+/// Endpoints:
+/// * `GET /history[?since=<epoch seconds>]`: retained samples as JSON lines.
+/// * `GET /healthz`, `GET /readyz`: liveness and readiness checks as JSON,
+///   with status 503 if any check fails.
+/// * `GET /manifest`: name, type, unit, source and description of every
+///   metric as JSON, as printed by `symon metrics --json`.
+///
+/// * `POST /control/<operation>`: with `control`, send a request to the
+///   sampling loop: `pause`, `resume`, `sample`, `interval?seconds=5`,
+///   `power-limit?gpu=0&watts=250` or `marker?name=epoch%203%20start`.
+///   Answers 202 once the request is queued.
+///
+/// With a token, `/history` answers 401 to requests without it. The health
+/// checks stay open so probes need no credentials; they reveal no samples.
+/// Control requests always need the separate control token (401) and an
+/// operation on the allow-list (403), so a leaked read token can't
+/// reconfigure GPUs.
+pub fn spawn(addr: &str, state: HttpState) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    thread::Builder::new()
+        .name("http".to_string())
+        .spawn(move || {
+            let state = Arc::new(state);
+            let connections = Arc::new(AtomicUsize::new(0));
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::warning!("Error accepting HTTP connection: {}", e);
+                        continue;
+                    }
+                };
+                // Each connection gets its own thread, so a slow client can't
+                // hold up health checks or control requests
+                let Some(slot) = ConnectionSlot::take(&connections) else {
+                    continue;
+                };
+                let state = Arc::clone(&state);
+                let spawned = thread::Builder::new()
+                    .name("http-connection".to_string())
+                    .spawn(move || {
+                        let _slot = slot;
+                        if let Err(e) = handle(stream, &state) {
+                            log::warning!("Error handling HTTP request: {}", e);
+                        }
+                    });
+                if let Err(e) = spawned {
+                    log::warning!("Error spawning HTTP connection thread: {}", e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// One of the `MAX_CONNECTIONS` connections served at once, given back when
+/// dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(connections: &Arc<AtomicUsize>) -> Option<Self> {
+        connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_CONNECTIONS).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot(Arc::clone(connections)))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Reads a request, failing once its deadline has passed however slowly
+/// the client sends.
+struct DeadlineReader<'a> {
+    stream: &'a mut Stream,
+    /// The connection's socket, to bound each read by the time left.
+    socket: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "request not received in time",
+            ));
+        }
+        self.socket.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+fn handle(stream: TcpStream, state: &HttpState) -> io::Result<()> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let peer = stream.peer_addr()?;
+    let socket = stream.try_clone()?;
+    let mut stream = match &state.tls {
+        Some(tls) => tls.accept(stream)?,
+        None => Stream::Plain(stream),
+    };
+    let request = DeadlineReader {
+        stream: &mut stream,
+        socket,
+        deadline,
+    };
+    let mut reader = BufReader::new(request.take(MAX_REQUEST_BYTES));
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // No endpoint reads a request body
+    let mut authorization = None;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+        header.clear();
+    }
+    let too_large = reader.get_ref().limit() == 0;
+    drop(reader);
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let authorized = state
+        .token
+        .as_ref()
+        .is_none_or(|token| tls::is_authorized(authorization.as_deref(), token));
+    let response = match (method, path) {
+        _ if too_large => Response::text(
+            "431 Request Header Fields Too Large",
+            "request headers too large\n",
+        ),
+        ("GET", "/history") if !authorized => {
+            Response::text("401 Unauthorized", "missing or invalid token\n")
+        }
+        ("GET", "/history") => history(state, query),
+        ("GET", "/healthz") => health(state, |health| health.live()),
+        ("GET", "/readyz") => health(state, |health| health.ready()),
+        ("GET", "/manifest") => manifest(),
+        (_, path) if path.starts_with("/control/") && state.control.is_some() => {
+            control(state, method, path, query, authorization.as_deref(), peer)
+        }
+        (_, "/history" | "/healthz" | "/readyz" | "/manifest") => {
+            Response::text("405 Method Not Allowed", "method not allowed\n")
+        }
+        _ => Response::text("404 Not Found", "not found\n"),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.close()
+}
+
+fn history(state: &HttpState, query: &str) -> Response {
+    let mut since = f64::NEG_INFINITY;
+    for (key, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+        if key == "since" {
+            match value.parse() {
+                Ok(value) => since = value,
+                Err(_) => return Response::text("400 Bad Request", "invalid since\n"),
+            }
+        }
+    }
+
+    let mut body = Vec::new();
+    let result = match state.history.lock() {
+        Ok(history) => history.write_json_lines(since, &mut body),
+        Err(_) => Err(io::Error::other("history lock poisoned")),
+    };
+    match result {
+        Ok(()) => Response {
+            status: "200 OK",
+            content_type: "application/x-ndjson",
+            body,
+        },
+        Err(e) => Response::text("500 Internal Server Error", &format!("{}\n", e)),
+    }
+}
+
+fn control(
+    state: &HttpState,
+    method: &str,
+    path: &str,
+    query: &str,
+    authorization: Option<&str>,
+    peer: SocketAddr,
+) -> Response {
+    let Some(access) = &state.control else {
+        return Response::text("404 Not Found", "not found\n");
+    };
+    if method != "POST" {
+        return Response::text("405 Method Not Allowed", "method not allowed\n");
+    }
+    if !tls::is_authorized(authorization, &access.token) {
+        log::warning!(
+            "Rejected unauthenticated control request {} from {}",
+            path,
+            peer
+        );
+        return Response::text("401 Unauthorized", "missing or invalid token\n");
+    }
+    let name = path.trim_start_matches("/control/");
+    let Ok(operation) = <Operation as clap::ValueEnum>::from_str(name, false) else {
+        return Response::text("404 Not Found", "unknown operation\n");
+    };
+    if !access.allowed.contains(&operation) {
+        log::warning!(
+            "Rejected control request {} from {}: not allowed",
+            path,
+            peer
+        );
+        return Response::text("403 Forbidden", "operation not allowed\n");
+    }
+
+    let param = |name: &str| {
+        query
+            .split('&')
+            .filter_map(|p| p.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    };
+    let request = match operation {
+        Operation::Pause => Some(Control::Pause),
+        Operation::Resume => Some(Control::Resume),
+        Operation::Sample => Some(Control::SampleNow),
+        Operation::Interval => param("seconds")
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|seconds| seconds.is_finite() && *seconds > 0.0)
+            .map(|seconds| Control::SetInterval(Duration::from_secs_f64(seconds))),
+        Operation::PowerLimit => param("gpu")
+            .and_then(|v| v.parse().ok())
+            .zip(param("watts").and_then(|v| v.parse::<f64>().ok()))
+            .filter(|(_, watts)| watts.is_finite() && *watts > 0.0)
+            .map(|(gpu, watts)| Control::SetPowerLimit { gpu, watts }),
+        Operation::Marker => param("name")
+            .and_then(percent_decode)
+            .and_then(|name| marker::sanitize(&name))
+            .map(Control::Marker),
+    };
+    let Some(request) = request else {
+        return Response::text("400 Bad Request", "missing or invalid parameters\n");
+    };
+    log::info!("Control request {:?} from {}", request, peer);
+    match access.sender.send(request) {
+        Ok(()) => Response::text("202 Accepted", "accepted\n"),
+        Err(_) => Response::text("503 Service Unavailable", "shutting down\n"),
+    }
+}
+
+/// Decode a query string value, where `+` stands for a space.
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn manifest() -> Response {
+    let mut body = serde_json::to_vec(&manifest::to_json()).unwrap_or_default();
+    body.push(b'\n');
+    Response {
+        status: "200 OK",
+        content_type: "application/json",
+        body,
+    }
+}
+
+fn health(state: &HttpState, check: impl FnOnce(&Health) -> Status) -> Response {
+    let status = match state.health.lock() {
+        Ok(health) => check(&health),
+        Err(_) => return Response::text("500 Internal Server Error", "health lock poisoned\n"),
+    };
+    let mut body = serde_json::to_vec(&status).unwrap_or_default();
+    body.push(b'\n');
+    Response {
+        status: if status.ok {
+            "200 OK"
+        } else {
+            "503 Service Unavailable"
+        },
+        content_type: "application/json",
+        body,
+    }
+}
@@ -0,0 +1,164 @@
+use crate::metrics::{Metrics, SampleTime};
+use crate::report::gpu_field;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Utilization (in percentage) at or below which a GPU without processes
+/// counts as idle, when none is configured.
+pub const DEFAULT_IDLE_UTILIZATION: f64 = 1.0;
+
+/// What one sample says about a GPU. Process counts are missing when NVML
+/// can't list processes, and a GPU whose processes are unknown isn't idle.
+#[derive(Default)]
+struct Reading {
+    compute: Option<u64>,
+    graphics: Option<u64>,
+    utilization: Option<f64>,
+}
+
+impl Reading {
+    fn is_idle(&self, utilization: f64) -> bool {
+        self.compute == Some(0)
+            && self.graphics == Some(0)
+            && self.utilization.is_some_and(|u| u <= utilization)
+    }
+}
+
+fn readings(metrics: &Metrics) -> BTreeMap<u32, Reading> {
+    let mut readings: BTreeMap<u32, Reading> = BTreeMap::new();
+    metrics.for_each(|key, value| {
+        if !key.starts_with("gpu.") {
+            return;
+        }
+        let Some((index, field)) = gpu_field(key) else {
+            return;
+        };
+        match field {
+            "computeProcessCount" => readings.entry(index).or_default().compute = value.as_u64(),
+            "graphicsProcessCount" => readings.entry(index).or_default().graphics = value.as_u64(),
+            "gpu" => readings.entry(index).or_default().utilization = value.as_f64(),
+            _ => {}
+        }
+    });
+    readings
+}
+
+/// GPUs in a sample without processes and with utilization at or below
+/// `utilization`, in index order.
+pub fn idle_gpus(metrics: &Metrics, utilization: f64) -> Vec<u32> {
+    readings(metrics)
+        .into_iter()
+        .filter(|(_, reading)| reading.is_idle(utilization))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// GPUs in a sample whose process counts are missing, e.g. because NVML
+/// can't list their processes, and which so never count as idle.
+pub fn gpus_without_process_counts(metrics: &Metrics) -> Vec<u32> {
+    readings(metrics)
+        .into_iter()
+        .filter(|(_, reading)| reading.compute.is_none() || reading.graphics.is_none())
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Raises an `idle_gpu` event when a GPU has had no processes and near-zero
+/// utilization for a while, e.g. one allocated to a job that never uses it.
+///
+/// Each idle stretch is reported once, when it reaches `after`; a process or
+/// utilization above the threshold ends it.
+pub struct IdleDetector {
+    after: Duration,
+    utilization: f64,
+    /// Timestamp of the first idle sample of each GPU, and whether its stretch
+    /// has been reported.
+    idle_since: BTreeMap<u32, (f64, bool)>,
+}
+
+impl IdleDetector {
+    pub fn new(after: Duration, utilization: f64) -> Self {
+        IdleDetector {
+            after,
+            utilization,
+            idle_since: BTreeMap::new(),
+        }
+    }
+
+    /// Check a sample, returning an event record for each GPU that has just
+    /// been idle for long enough.
+    pub fn check(&mut self, metrics: &Metrics) -> Vec<Metrics> {
+        let (Some(timestamp), Some(time)) = (metrics.timestamp(), metrics.time()) else {
+            return Vec::new();
+        };
+        if metrics.get("_record").is_some() {
+            return Vec::new();
+        }
+
+        let mut events = Vec::new();
+        for (index, reading) in readings(metrics) {
+            if !reading.is_idle(self.utilization) {
+                self.idle_since.remove(&index);
+                continue;
+            }
+            let (since, reported) = self.idle_since.entry(index).or_insert((timestamp, false));
+            let idle_seconds = timestamp - *since;
+            if !*reported && idle_seconds >= self.after.as_secs_f64() {
+                *reported = true;
+                events.push(idle_record(index, idle_seconds, time));
+            }
+        }
+        events
+    }
+}
+
+fn idle_record(gpu: u32, idle_seconds: f64, time: SampleTime) -> Metrics {
+    let mut record = Metrics::new();
+    record.add_metric("_record", "event");
+    record.add_metric("_event", "idle_gpu");
+    record.add_metric("gpu", gpu);
+    record.add_metric("idleSeconds", idle_seconds);
+    record.set_time(time);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn sample(timestamp: f64, processes: Option<u64>, utilization: f64) -> Metrics {
+        let mut metrics = Metrics::new();
+        metrics.set_time(SampleTime {
+            wall: UNIX_EPOCH + Duration::from_secs_f64(timestamp),
+            uptime: Duration::from_secs_f64(timestamp),
+        });
+        metrics.add_metric("gpu.0.gpu", utilization);
+        if let Some(processes) = processes {
+            metrics.add_metric("gpu.0.computeProcessCount", processes);
+            metrics.add_metric("gpu.0.graphicsProcessCount", 0);
+        }
+        metrics
+    }
+
+    #[test]
+    fn gpus_with_unknown_processes_are_not_idle() {
+        assert_eq!(idle_gpus(&sample(0.0, Some(0), 0.0), 1.0), [0]);
+        assert!(idle_gpus(&sample(0.0, Some(1), 0.0), 1.0).is_empty());
+        assert!(idle_gpus(&sample(0.0, None, 0.0), 1.0).is_empty());
+        assert_eq!(gpus_without_process_counts(&sample(0.0, None, 0.0)), [0]);
+        assert!(gpus_without_process_counts(&sample(0.0, Some(0), 0.0)).is_empty());
+    }
+
+    #[test]
+    fn reports_each_idle_stretch_once() {
+        let mut detector = IdleDetector::new(Duration::from_secs(60), 1.0);
+        assert!(detector.check(&sample(0.0, Some(0), 0.0)).is_empty());
+        assert_eq!(detector.check(&sample(60.0, Some(0), 0.0)).len(), 1);
+        assert!(detector.check(&sample(120.0, Some(0), 0.0)).is_empty());
+        // Unknown processes end the stretch
+        assert!(detector.check(&sample(130.0, None, 0.0)).is_empty());
+        assert!(detector.check(&sample(140.0, Some(0), 0.0)).is_empty());
+        assert_eq!(detector.check(&sample(200.0, Some(0), 0.0)).len(), 1);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::cgroup::ContainerRef;
+#[cfg(all(unix, feature = "protobuf"))]
+use crate::grpc;
+use crate::log;
+use crate::processes::GpuProcess;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+#[cfg(all(unix, feature = "protobuf"))]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The kubelet's pod resources API, gRPC over a unix socket.
+const POD_RESOURCES_SOCKET: &str = "/var/lib/kubelet/pod-resources/kubelet.sock";
+#[cfg(feature = "protobuf")]
+const LIST_PODS: &str = "/v1.PodResourcesLister/List";
+/// How long a pod resources query may take.
+#[cfg(feature = "protobuf")]
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Where the kubelet keeps pod log directories, named `<namespace>_<pod>_<uid>`.
+const POD_LOGS: &str = "/var/log/pods";
+/// Don't query the kubelet more often than this on lookup misses.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Messages of the kubelet's `v1.PodResourcesLister` service, as in
+/// `k8s.io/kubelet/pkg/apis/podresources/v1/api.proto`, limited to the fields
+/// symon reads.
+#[cfg(feature = "protobuf")]
+mod podresources {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListPodResourcesRequest {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListPodResourcesResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub pod_resources: Vec<PodResources>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PodResources {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub namespace: String,
+        #[prost(message, repeated, tag = "3")]
+        pub containers: Vec<ContainerResources>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ContainerResources {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(message, repeated, tag = "2")]
+        pub devices: Vec<ContainerDevices>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ContainerDevices {
+        #[prost(string, tag = "1")]
+        pub resource_name: String,
+        #[prost(string, repeated, tag = "2")]
+        pub device_ids: Vec<String>,
+    }
+}
+
+/// The Kubernetes pod and container a process belongs to.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct PodRef {
+    pub namespace: String,
+    pub pod: String,
+    /// Container name; unknown if the GPU wasn't allocated to the container
+    /// through the kubelet, or to several containers of the pod.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+}
+
+/// GPU usage of one pod's container on a device.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodUsage {
+    #[serde(flatten)]
+    pub pod: PodRef,
+    pub pids: Vec<u32>,
+    /// Sum of the GPU memory used by the container's processes (in bytes).
+    pub memory_bytes: u64,
+    /// Sum of the SM utilization of the container's processes (in percentage).
+    pub sm_utilization: u32,
+}
+
+/// Maps processes on a GPU to the pod and container it was allocated to.
+///
+/// The kubelet's pod resources API lists the devices, e.g. GPU UUIDs, each
+/// container was allocated by a device plugin. A process is attributed to the
+/// container allocated the GPU it runs on; when the GPU is shared, the pod UID
+/// in the process's cgroup path picks among them, by the kubelet's pod log
+/// directories. Processes in pods that got the GPU some other way, e.g.
+/// privileged ones, are attributed to their pod without a container.
+#[derive(Default)]
+pub struct PodResolver {
+    allocations: Vec<Allocation>,
+    pods: HashMap<String, (String, String)>,
+    scanned_at: Option<Instant>,
+}
+
+/// Devices allocated to a container.
+struct Allocation {
+    pod: PodRef,
+    device_ids: Vec<String>,
+}
+
+impl Allocation {
+    /// Whether the container got the GPU with `uuid`. Time-sliced replicas
+    /// of a GPU are named `<uuid>::<replica>`.
+    fn uses(&self, uuid: &str) -> bool {
+        self.device_ids.iter().any(|id| {
+            id.strip_prefix(uuid)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        })
+    }
+}
+
+impl PodResolver {
+    pub fn new() -> Self {
+        PodResolver::default()
+    }
+
+    /// Find the pod of a container running on the GPU with `uuid`, querying
+    /// the kubelet again if it isn't known yet.
+    pub fn resolve(&mut self, container: &ContainerRef, uuid: Option<&str>) -> Option<PodRef> {
+        let pod_uid = container.pod_uid.as_ref()?;
+        if let Some(pod) = self.lookup(pod_uid, uuid) {
+            return Some(pod);
+        }
+        if self
+            .scanned_at
+            .is_some_and(|at| at.elapsed() < RESCAN_INTERVAL)
+        {
+            return None;
+        }
+        self.scan();
+        self.lookup(pod_uid, uuid)
+    }
+
+    /// Group the processes of the device with `uuid` by pod and container.
+    /// Processes outside pods are left out.
+    pub fn usage(&mut self, processes: &[GpuProcess], uuid: Option<&str>) -> Vec<PodUsage> {
+        let mut usage: BTreeMap<PodRef, PodUsage> = BTreeMap::new();
+        for process in processes {
+            let Some(pod) = process
+                .container
+                .as_ref()
+                .and_then(|c| self.resolve(c, uuid))
+            else {
+                continue;
+            };
+            let entry = usage.entry(pod.clone()).or_insert_with(|| PodUsage {
+                pod,
+                pids: Vec::new(),
+                memory_bytes: 0,
+                sm_utilization: 0,
+            });
+            entry.pids.push(process.pid);
+            entry.memory_bytes += process.memory_bytes.unwrap_or(0);
+            entry.sm_utilization += process.sm_utilization.unwrap_or(0);
+        }
+        usage.into_values().collect()
+    }
+
+    fn lookup(&self, pod_uid: &str, uuid: Option<&str>) -> Option<PodRef> {
+        let named = self.pods.get(pod_uid);
+        let mut candidates = self.allocations.iter().filter(|allocation| {
+            uuid.is_some_and(|uuid| allocation.uses(uuid))
+                && named.is_none_or(|(namespace, pod)| {
+                    allocation.pod.namespace == *namespace && allocation.pod.pod == *pod
+                })
+        });
+        match (candidates.next(), candidates.next()) {
+            (Some(allocation), None) => Some(allocation.pod.clone()),
+            // Several containers of the pod share the GPU
+            (Some(allocation), Some(_)) if named.is_some() => Some(PodRef {
+                container: None,
+                ..allocation.pod.clone()
+            }),
+            _ => named.map(|(namespace, pod)| PodRef {
+                namespace: namespace.clone(),
+                pod: pod.clone(),
+                container: None,
+            }),
+        }
+    }
+
+    fn scan(&mut self) {
+        self.scanned_at = Some(Instant::now());
+        match pod_allocations() {
+            Ok(allocations) => self.allocations = allocations,
+            Err(e) => log::warning!(
+                "Error listing pod resources from {}: {}",
+                POD_RESOURCES_SOCKET,
+                e
+            ),
+        }
+        self.pods = file_names(Path::new(POD_LOGS))
+            .filter_map(|name| {
+                let mut parts = name.splitn(3, '_');
+                let namespace = parts.next()?.to_string();
+                let pod = parts.next()?.to_string();
+                Some((parts.next()?.to_string(), (namespace, pod)))
+            })
+            .collect();
+    }
+}
+
+/// The containers the kubelet allocated devices to.
+#[cfg(all(unix, feature = "protobuf"))]
+fn pod_allocations() -> io::Result<Vec<Allocation>> {
+    let stream = UnixStream::connect(POD_RESOURCES_SOCKET)?;
+    stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    stream.set_write_timeout(Some(QUERY_TIMEOUT))?;
+    let response = grpc::unary(stream, LIST_PODS, &podresources::ListPodResourcesRequest {})?;
+    Ok(allocations(response))
+}
+
+#[cfg(not(unix))]
+fn pod_allocations() -> io::Result<Vec<Allocation>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// The kubelet isn't asked without the `protobuf` feature, so pods are known
+/// from their log directories only, without containers.
+#[cfg(all(unix, not(feature = "protobuf")))]
+fn pod_allocations() -> io::Result<Vec<Allocation>> {
+    Ok(Vec::new())
+}
+
+/// The containers that were allocated devices.
+#[cfg(feature = "protobuf")]
+fn allocations(response: podresources::ListPodResourcesResponse) -> Vec<Allocation> {
+    let mut allocations = Vec::new();
+    for pod in response.pod_resources {
+        for container in pod.containers {
+            let device_ids: Vec<String> = container
+                .devices
+                .into_iter()
+                .flat_map(|devices| devices.device_ids)
+                .collect();
+            if device_ids.is_empty() {
+                continue;
+            }
+            allocations.push(Allocation {
+                pod: PodRef {
+                    namespace: pod.namespace.clone(),
+                    pod: pod.name.clone(),
+                    container: Some(container.name),
+                },
+                device_ids,
+            });
+        }
+    }
+    allocations
+}
+
+fn file_names(dir: &Path) -> impl Iterator<Item = String> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolver() -> PodResolver {
+        let pod = |pod: &str, container: &str| PodRef {
+            namespace: "ml".to_string(),
+            pod: pod.to_string(),
+            container: Some(container.to_string()),
+        };
+        PodResolver {
+            allocations: vec![
+                Allocation {
+                    pod: pod("train", "worker"),
+                    device_ids: vec!["GPU-a".to_string()],
+                },
+                Allocation {
+                    pod: pod("infer", "server"),
+                    device_ids: vec!["GPU-b::0".to_string()],
+                },
+                Allocation {
+                    pod: pod("notebook", "lab"),
+                    device_ids: vec!["GPU-b::1".to_string()],
+                },
+            ],
+            pods: HashMap::from([(
+                "uid-2".to_string(),
+                ("ml".to_string(), "notebook".to_string()),
+            )]),
+            scanned_at: Some(Instant::now()),
+        }
+    }
+
+    #[test]
+    fn attributes_processes_to_the_container_allocated_the_gpu() {
+        let resolver = resolver();
+        let worker = resolver.lookup("uid-1", Some("GPU-a")).unwrap();
+        assert_eq!(worker.container.as_deref(), Some("worker"));
+        // GPU-b is time-sliced between two pods; the pod UID picks one
+        let lab = resolver.lookup("uid-2", Some("GPU-b")).unwrap();
+        assert_eq!(lab.container.as_deref(), Some("lab"));
+        assert_eq!(resolver.lookup("uid-3", Some("GPU-b")), None);
+        // Not allocated through the kubelet: the pod is known, the container isn't
+        let privileged = resolver.lookup("uid-2", Some("GPU-c")).unwrap();
+        assert_eq!(
+            (privileged.pod.as_str(), privileged.container),
+            ("notebook", None)
+        );
+    }
+}
@@ -0,0 +1,113 @@
+//! symon samples NVIDIA GPU metrics via NVML and writes them to pluggable
+//! sinks as flat JSON records.
+//!
+//! The `symon` binary is a thin command-line wrapper around these modules.
+
+pub mod agent;
+pub mod baseline;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod billing;
+#[cfg(feature = "net")]
+pub mod bmc;
+pub mod budget;
+pub mod cgroup;
+pub mod clock;
+pub mod condition;
+pub mod config;
+pub mod control;
+pub mod counters;
+#[cfg(any(feature = "bench", feature = "stress"))]
+pub mod cuda;
+#[cfg(unix)]
+pub mod daemon;
+pub mod dcgm;
+pub mod device_settings;
+pub mod devices;
+pub mod diff;
+pub mod docker;
+#[cfg(target_os = "linux")]
+pub mod drain;
+pub mod ecc_advisor;
+pub mod emit;
+pub mod encoding;
+pub mod error;
+pub mod fan_curve;
+pub mod ffi;
+pub mod gap;
+pub mod gpm;
+pub mod gpu_nvidia;
+pub mod grafana;
+#[cfg(all(unix, feature = "protobuf"))]
+mod grpc;
+pub mod health;
+pub mod histogram;
+pub mod history;
+#[cfg(feature = "net")]
+pub mod http;
+pub mod idle;
+pub mod kube;
+pub mod limits;
+pub mod log;
+pub mod manifest;
+pub mod marker;
+pub mod metrics;
+pub mod nvml_ext;
+pub mod otel;
+pub mod pick;
+pub mod pipe;
+mod placement;
+pub mod power_policy;
+pub mod privileges;
+pub mod processes;
+#[cfg(feature = "protobuf")]
+pub mod proto;
+pub mod query;
+pub mod report;
+pub mod reserve;
+pub mod residency;
+pub mod rollup;
+pub mod run;
+pub mod sampler;
+#[cfg(target_os = "linux")]
+pub mod sandbox;
+pub mod schedule;
+pub mod schema;
+pub mod series;
+pub mod sink;
+pub mod sink_file;
+#[cfg(feature = "net")]
+pub mod sink_http;
+#[cfg(feature = "net")]
+pub mod sink_nats;
+#[cfg(feature = "net")]
+pub mod sink_redis;
+pub mod sink_smi;
+pub mod sink_status;
+#[cfg(feature = "net")]
+pub mod sink_tcp;
+#[cfg(feature = "net")]
+pub mod sink_udp;
+pub mod sink_window;
+#[cfg(feature = "net")]
+pub mod sink_zmq;
+pub mod smoothing;
+pub mod snapshot;
+pub mod spool;
+pub mod state;
+#[cfg(feature = "stress")]
+pub mod stress;
+pub mod subscribers;
+pub mod systemd;
+pub mod tenant;
+pub mod throttle;
+pub mod timefmt;
+#[cfg(feature = "net")]
+pub mod tls;
+pub mod topology;
+pub mod trace;
+pub mod units;
+pub mod watchdog;
+pub mod writer;
+
+pub use error::{Result, SymonError};
@@ -0,0 +1,171 @@
+#[cfg(target_os = "linux")]
+use nix::sched::{sched_setaffinity, CpuSet};
+#[cfg(target_os = "linux")]
+use nix::sys::resource::{setrlimit, Resource};
+#[cfg(target_os = "linux")]
+use nix::unistd::Pid;
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::path::Path;
+use std::path::PathBuf;
+
+/// cgroup v2 CPU bandwidth period used with `--cpu-quota`.
+#[cfg(target_os = "linux")]
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// Resource limits the agent imposes on itself so it never competes with
+/// workloads for CPU or memory.
+pub struct SelfLimits {
+    pub nice: Option<i32>,
+    pub cpu_affinity: Option<Vec<usize>>,
+    pub cgroup: Option<PathBuf>,
+    pub cpu_quota_percent: Option<f64>,
+    pub memory_limit_bytes: Option<u64>,
+}
+
+impl SelfLimits {
+    /// Apply the configured limits to the current process.
+    ///
+    /// Niceness and affinity are per-thread on Linux and only inherited by
+    /// threads spawned afterwards, so this must run before any threads are started.
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self) -> io::Result<()> {
+        if let Some(nice) = self.nice {
+            // SAFETY: setpriority has no memory-safety preconditions
+            let ret = unsafe { nix::libc::setpriority(nix::libc::PRIO_PROCESS, 0, nice) };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        if let Some(cpus) = &self.cpu_affinity {
+            let mut cpu_set = CpuSet::new();
+            for &cpu in cpus {
+                cpu_set.set(cpu).map_err(io::Error::from)?;
+            }
+            sched_setaffinity(Pid::from_raw(0), &cpu_set).map_err(io::Error::from)?;
+        }
+
+        match &self.cgroup {
+            Some(cgroup) => self.join_cgroup(cgroup)?,
+            None => {
+                if self.cpu_quota_percent.is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "--cpu-quota requires --cgroup",
+                    ));
+                }
+                // Without a cgroup, fall back to limiting the data segment size
+                if let Some(limit) = self.memory_limit_bytes {
+                    setrlimit(Resource::RLIMIT_DATA, limit, limit).map_err(io::Error::from)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Self-limits rely on Linux scheduling and cgroup APIs.
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self) -> io::Result<()> {
+        let any_set = self.nice.is_some()
+            || self.cpu_affinity.is_some()
+            || self.cgroup.is_some()
+            || self.cpu_quota_percent.is_some()
+            || self.memory_limit_bytes.is_some();
+        if any_set {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "resource self-limits are only supported on Linux",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Create (if needed) a cgroup v2 group, set its limits and move this process into it.
+    #[cfg(target_os = "linux")]
+    fn join_cgroup(&self, cgroup: &Path) -> io::Result<()> {
+        fs::create_dir_all(cgroup)?;
+        if let Some(percent) = self.cpu_quota_percent {
+            let quota_us = ((percent / 100.0) * CPU_PERIOD_US as f64).max(1000.0) as u64;
+            fs::write(
+                cgroup.join("cpu.max"),
+                format!("{} {}", quota_us, CPU_PERIOD_US),
+            )?;
+        }
+        if let Some(limit) = self.memory_limit_bytes {
+            fs::write(cgroup.join("memory.max"), limit.to_string())?;
+        }
+        fs::write(cgroup.join("cgroup.procs"), std::process::id().to_string())
+    }
+}
+
+/// Parse a CPU list such as `0-3,8,10-11`.
+pub fn parse_cpu_list(s: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = Vec::new();
+    for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let invalid = || format!("invalid CPU list: {:?}", s);
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start.trim().parse().map_err(|_| invalid())?;
+                let end: usize = end.trim().parse().map_err(|_| invalid())?;
+                if start > end {
+                    return Err(invalid());
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(part.parse().map_err(|_| invalid())?),
+        }
+    }
+    if cpus.is_empty() {
+        return Err(format!("empty CPU list: {:?}", s));
+    }
+    Ok(cpus)
+}
+
+/// Format sorted CPU numbers as a CPU list such as `0-3,8,10-11`.
+pub fn format_cpu_list(cpus: &[usize]) -> String {
+    let mut list = String::new();
+    let mut i = 0;
+    while i < cpus.len() {
+        let start = cpus[i];
+        while i + 1 < cpus.len() && cpus[i + 1] == cpus[i] + 1 {
+            i += 1;
+        }
+        if !list.is_empty() {
+            list.push(',');
+        }
+        if cpus[i] == start {
+            list.push_str(&start.to_string());
+        } else {
+            list.push_str(&format!("{}-{}", start, cpus[i]));
+        }
+        i += 1;
+    }
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cpu_lists() {
+        assert_eq!(
+            parse_cpu_list("0-3, 8,10-11"),
+            Ok(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpu_list("5"), Ok(vec![5]));
+        for invalid in ["", ",", "3-1", "a", "1-", "-2"] {
+            assert!(parse_cpu_list(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn formats_cpu_lists() {
+        assert_eq!(format_cpu_list(&[0, 1, 2, 3, 8, 10, 11]), "0-3,8,10-11");
+        assert_eq!(format_cpu_list(&[]), "");
+    }
+}
@@ -0,0 +1,151 @@
+#[cfg(target_os = "linux")]
+use std::env;
+use std::io::{self, Write};
+#[cfg(target_os = "linux")]
+use std::os::unix::net::UnixDatagram;
+#[cfg(target_os = "linux")]
+use std::sync::OnceLock;
+
+#[cfg(target_os = "linux")]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// syslog(3) priorities, as understood by journald.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+}
+
+impl Level {
+    fn label(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Info => "info",
+        }
+    }
+}
+
+/// Where diagnostics are written. Metrics never go through the logger.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogTarget {
+    /// Use journald when running under systemd, stderr otherwise
+    Auto,
+    Stderr,
+    /// Native journald protocol (Linux only; stderr elsewhere)
+    Journald,
+}
+
+#[cfg(target_os = "linux")]
+static JOURNAL: OnceLock<Option<UnixDatagram>> = OnceLock::new();
+
+/// Select the log target. Without a call to `init`, logs go to stderr.
+#[cfg(not(target_os = "linux"))]
+pub fn init(_target: LogTarget) {}
+
+/// Select the log target. Without a call to `init`, logs go to stderr.
+#[cfg(target_os = "linux")]
+pub fn init(target: LogTarget) {
+    let use_journald = match target {
+        LogTarget::Stderr => false,
+        LogTarget::Journald => true,
+        // systemd sets JOURNAL_STREAM when stderr is connected to the journal
+        LogTarget::Auto => env::var_os("JOURNAL_STREAM").is_some(),
+    };
+    JOURNAL.get_or_init(|| {
+        use_journald
+            .then(|| {
+                let socket = UnixDatagram::unbound().ok()?;
+                socket.connect(JOURNALD_SOCKET).ok()?;
+                Some(socket)
+            })
+            .flatten()
+    });
+}
+
+/// Log a message with optional structured fields.
+///
+/// With journald, fields are attached as native journal fields (names must be
+/// upper-case, e.g. `SYMON_SINK`); on stderr they are appended as `key=value`.
+pub fn emit(level: Level, message: &str, fields: &[(&str, &str)], code: (&str, u32)) {
+    if send_to_journal(level, message, fields, code) {
+        return;
+    }
+
+    let mut stderr = io::stderr().lock();
+    let _ = write!(stderr, "[{}] {}", level.label(), message);
+    for (key, value) in fields {
+        let _ = write!(stderr, " {}={}", key.to_lowercase(), value);
+    }
+    let _ = writeln!(stderr);
+}
+
+/// Send an entry to journald if it was selected in `init`. Returns whether it was sent.
+#[cfg(target_os = "linux")]
+fn send_to_journal(
+    level: Level,
+    message: &str,
+    fields: &[(&str, &str)],
+    code: (&str, u32),
+) -> bool {
+    let Some(Some(journal)) = JOURNAL.get() else {
+        return false;
+    };
+    let mut entry = Vec::with_capacity(256);
+    append_field(&mut entry, "PRIORITY", &(level as u8).to_string());
+    append_field(&mut entry, "SYSLOG_IDENTIFIER", "symon");
+    append_field(&mut entry, "MESSAGE", message);
+    append_field(&mut entry, "CODE_FILE", code.0);
+    append_field(&mut entry, "CODE_LINE", &code.1.to_string());
+    for (key, value) in fields {
+        append_field(&mut entry, key, value);
+    }
+    journal.send(&entry).is_ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_to_journal(_: Level, _: &str, _: &[(&str, &str)], _: (&str, u32)) -> bool {
+    false
+}
+
+/// Append a field using the journal's native protocol, switching to the
+/// length-prefixed form for values containing newlines.
+#[cfg(target_os = "linux")]
+fn append_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    entry.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_error {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Error, &format!($($arg)*), &[], (file!(), line!()))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_warning {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Warning, &format!($($arg)*), &[], (file!(), line!()))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_info {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Info, &format!($($arg)*), &[], (file!(), line!()))
+    };
+}
+
+pub use {__log_error as error, __log_info as info, __log_warning as warning};
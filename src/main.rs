@@ -1,36 +1,881 @@
-use clap::Parser;
-use nix::unistd::getppid;
+use clap::{Parser, Subcommand};
+#[cfg(feature = "sentry")]
 use sentry::types::Dsn;
-use signal_hook::{consts::TERM_SIGNALS, iterator::Signals};
+#[cfg(feature = "sentry")]
 use std::env;
+use std::ffi::OsString;
+#[cfg(feature = "tools")]
+use std::io::{self, IsTerminal};
+#[cfg(feature = "tools")]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(feature = "tools")]
+use std::process;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread;
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime};
 
-mod gpu_nvidia;
-mod metrics;
+#[cfg(windows)]
+mod win_service;
 
-use crate::gpu_nvidia::NvidiaGpu;
-use crate::metrics::Metrics;
+use symon::agent::AgentMonitor;
+use symon::baseline::{self, Baseline, BaselineMonitor};
+#[cfg(feature = "bench")]
+use symon::bench::{self, PcieOptions, PcieResult};
+use symon::billing::Billing;
+#[cfg(feature = "net")]
+use symon::bmc::{BmcCollector, BmcSource};
+use symon::budget::{Adjustment, Collector, OverheadBudget};
+use symon::clock::{ClockMonitor, ClockSync};
+#[cfg(feature = "tools")]
+use symon::condition::Condition;
+use symon::config::Config;
+use symon::control::{Control, Controls};
+#[cfg(feature = "net")]
+use symon::control::{ControlAccess, Operation};
+use symon::counters::{CounterRates, CounterTotals};
+#[cfg(unix)]
+use symon::daemon::{self, PidFile};
+use symon::dcgm::{Backend, Dcgm};
+#[cfg(feature = "tools")]
+use symon::device_settings::ClockLock;
+use symon::device_settings::DeviceSettings;
+#[cfg(feature = "tools")]
+use symon::diff;
+#[cfg(all(target_os = "linux", feature = "tools"))]
+use symon::drain::{self, DrainOptions};
+use symon::ecc_advisor::EccAdvisor;
+use symon::emit::{ChangeFilter, EmitMode};
+use symon::encoding::Encoding;
+use symon::error;
+use symon::fan_curve::FanCurve;
+use symon::gap::GapTracker;
+#[cfg(feature = "tools")]
+use symon::grafana::{self, Datasource};
+use symon::health::Health;
+#[cfg(feature = "net")]
+use symon::history::History;
+#[cfg(feature = "net")]
+use symon::http::{self, HttpState};
+use symon::idle::{self, IdleDetector};
+use symon::limits::{self, SelfLimits};
+use symon::log::{self, LogTarget};
+#[cfg(feature = "tools")]
+use symon::manifest;
+use symon::marker;
+use symon::metrics::Metrics;
+use symon::otel;
+#[cfg(feature = "tools")]
+use symon::pick::{self, PickOptions};
+use symon::pipe::Backpressure;
+use symon::power_policy::PowerPolicy;
+#[cfg(unix)]
+use symon::privileges;
+use symon::privileges::CapabilityReport;
+#[cfg(feature = "tools")]
+use symon::query::{self, Aggregation, Query, QueryFormat};
+use symon::report::Report;
+#[cfg(feature = "tools")]
+use symon::reserve::{self, Reservation};
+use symon::residency::{self, Residency};
+#[cfg(feature = "tools")]
+use symon::run;
+use symon::run::Runner;
+use symon::sampler::Sampler;
+#[cfg(target_os = "linux")]
+use symon::sandbox::{self, SandboxOptions};
+use symon::schedule::{self, ActiveWindow};
+#[cfg(feature = "tools")]
+use symon::schema;
+use symon::sink::{self, OutputFormat, Sink, SinkOptions};
+use symon::sink_file::{Compression, RotationOptions};
+use symon::sink_status::StatusThresholds;
+use symon::smoothing::Smoother;
+#[cfg(feature = "tools")]
+use symon::snapshot;
+use symon::state::{State, StateFile};
+#[cfg(feature = "stress")]
+use symon::stress::{Burner, StressLimits, StressReport};
+use symon::systemd::Notifier;
+use symon::tenant::Tenant;
+use symon::throttle::ThrottleClassifier;
+#[cfg(feature = "net")]
+use symon::tls::{self, Acceptor};
+#[cfg(feature = "tools")]
+use symon::trace::TraceReader;
+use symon::units::{self, UnitSystem};
+use symon::writer::SampleWriter;
 
 // Define command-line arguments
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Monitor this process ID and its children for GPU usage
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Monitor this process ID and its children for GPU usage; a `placement` event warns
+    /// when it's pinned to CPUs far from a GPU it uses
     #[arg(short, long, default_value_t = 0)]
     pid: i32,
 
     /// Parent process ID. The program will exit if the parent process is no longer alive.
-    #[arg(short, long, default_value_t = 0)]
+    /// If 0, the parent process is not watched.
+    #[arg(long, default_value_t = 0)]
     ppid: i32,
 
-    /// Sampling interval in seconds
-    #[arg(short, long, default_value_t = 1.0)]
+    /// Stop after recording for this long, e.g. `2h`
+    #[arg(long, value_parser = units::parse_duration)]
+    duration: Option<Duration>,
+
+    /// Only sample during this time of day (local time), e.g. `08:00-20:00`.
+    /// Run start and end records mark where recording starts and stops
+    #[arg(long, value_parser = ActiveWindow::parse)]
+    active_window: Option<ActiveWindow>,
+
+    /// Sampling interval in seconds. Raised, with a warning, to the driver's utilization
+    /// sample period (1/6 to 1 s depending on the GPU) unless `--no-min-interval`
+    #[arg(short, long, default_value_t = 1.0, value_parser = units::parse_seconds)]
     interval: f64,
+
+    /// Allow intervals shorter than the driver's utilization sample period, e.g. for
+    /// fast power readings; utilization readings then repeat between driver samples
+    #[arg(long)]
+    no_min_interval: bool,
+
+    /// Keep the agent's own CPU usage under this share of one core, e.g. `0.5%`, by
+    /// turning off profiling, process lists and per-pod and per-user attribution, then
+    /// lengthening the interval up to 16 times. Every adjustment is logged
+    #[arg(long, value_name = "PERCENT", value_parser = units::parse_percent)]
+    max_overhead: Option<f64>,
+
+    /// Report SM activity and occupancy, tensor core and DRAM activity, e.g.
+    /// `gpu.0.smOccupancy` and `gpu.0.tensorActive`. Read from NVML's GPM on Hopper
+    /// and newer GPUs, which also breaks activity down by pipe, e.g. `gpu.0.fp64Active`,
+    /// or from DCGM with `--backend dcgm`. Adds a little overhead to
+    /// workloads and may conflict with profilers like Nsight Compute
+    #[arg(long)]
+    profiling: bool,
+
+    /// Library to read the GPUs with. `dcgm` adds DCGM's profiling metrics, also on
+    /// GPUs before Hopper, and PCIe and NVLink bandwidth, to NVML's; implies
+    /// `--profiling`
+    #[arg(long, value_enum, default_value_t = Backend::Nvml)]
+    backend: Backend,
+
+    /// Connect to the DCGM host engine at this address (e.g. `localhost:5555`)
+    /// instead of starting one embedded in symon
+    #[arg(long)]
+    dcgm_host: Option<String>,
+
+    /// Maximum time in seconds to wait for NVML before emitting a degraded sample
+    #[arg(long, default_value_t = 10.0, value_parser = units::parse_seconds)]
+    sampling_timeout: f64,
+
+    /// Re-initialize NVML after this many consecutive sampling timeouts (0 to never)
+    #[arg(long, default_value_t = 3)]
+    max_sampling_timeouts: u32,
+
+    /// Maximum number of samples waiting to be written before new ones are dropped
+    #[arg(long, default_value_t = 64)]
+    queue_size: usize,
+
+    /// Where to write samples: `stdout`, `file://path`, `tcp://host:port`,
+    /// `http://host:port/path` (batched POSTs; `?batch=500&linger=10s&compress=zstd`)
+    /// `redis://host:port` (`?mode=stream|pubsub|both&prefix=symon&maxlen=N`),
+    /// `nats://host:port` (subjects `symon.<host>.<gpu>`; `?jetstream=true`),
+    /// `udp://host:port` (`?maxsize=1400`)
+    /// or `zmq://127.0.0.1:port` (ZeroMQ PUB on loopback, topics `symon.gpu.<i>` and `symon.node`).
+    /// May be repeated. Defaults to stdout unless `--out` is given.
+    /// Append `?time=rfc3339,uptime` to also emit `_time` and `_uptime_ms`,
+    /// `?every=10s` to write at most every 10s, `&agg=mean` to aggregate over that interval,
+    /// `?format=status` to pick the stdout format or `?encoding=cbor` to override `--encoding`
+    #[arg(long = "sink")]
+    sinks: Vec<String>,
+
+    /// Write samples to this file; shorthand for `--sink file://PATH`
+    #[arg(long)]
+    out: Option<PathBuf>,
+
+    /// Rotate output files once they reach this size, e.g. `100MiB`
+    #[arg(long, value_parser = units::parse_size)]
+    rotate_size: Option<u64>,
+
+    /// Rotate output files after this long, e.g. `1h` or `1d`
+    #[arg(long, value_parser = units::parse_duration)]
+    rotate_every: Option<Duration>,
+
+    /// Compress rotated output files with `gzip` or `zstd`, or write files
+    /// (and stdout, when piped) as zstd streams that can be followed while
+    /// being written with `zstd-stream`
+    #[arg(long, value_enum, default_value_t = Compression::None)]
+    compress: Compression,
+
+    /// Number of rotated output files to keep
+    #[arg(long)]
+    retain: Option<usize>,
+
+    /// Emit every metric in every sample, or only metrics that changed
+    #[arg(long, value_enum, default_value_t = EmitMode::Full)]
+    emit: EmitMode,
+
+    /// How far a numeric metric must move to count as changed with `--emit changed`
+    #[arg(long, default_value_t = 0.0)]
+    emit_tolerance: f64,
+
+    /// Mark counter metrics with `_type.<name>: "counter"`; all others are gauges
+    #[arg(long)]
+    tag_types: bool,
+
+    /// Add exponentially smoothed utilization and power with this half-life, e.g.
+    /// `gpu.0.gpuSmoothed` next to `gpu.0.gpu`
+    #[arg(long, value_parser = units::parse_duration)]
+    smooth_half_life: Option<Duration>,
+
+    /// Format of samples written to stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Json)]
+    format: OutputFormat,
+
+    /// Encoding of samples written to files and network sinks
+    #[arg(long, value_enum, default_value_t = Encoding::Json)]
+    encoding: Encoding,
+
+    /// Units of memory and clock metrics in written samples. Keys stay the same and
+    /// samples carry `_units`; `symon query`, `report` and `diff` convert them back
+    #[arg(long, value_enum, default_value_t = UnitSystem::Raw)]
+    units: UnitSystem,
+
+    /// Color thresholds for `--format status`, e.g. `temp=80:90,memory=90:98,power=90:100`
+    #[arg(long, value_parser = StatusThresholds::parse)]
+    status_thresholds: Option<StatusThresholds>,
+
+    /// Redraw `--format smi` tables in place instead of printing one after another
+    #[arg(long)]
+    watch: bool,
+
+    /// Spool samples for unreachable network sinks to this directory
+    #[arg(long)]
+    spool_dir: Option<PathBuf>,
+
+    /// What to do when the reader of stdout stops reading, e.g. a stuck consumer at the
+    /// other end of a pipe; `spill` spools samples in `--spool-dir` until it catches up
+    #[arg(long, value_enum, default_value_t = Backpressure::Block)]
+    stdout_backpressure: Backpressure,
+
+    /// Maximum size of each network sink's spool, e.g. `256MiB`
+    #[arg(long, default_value = "256MiB", value_parser = units::parse_size)]
+    spool_max_size: u64,
+
+    /// PEM bundle of CAs trusted for `tcps://` and `https://` sinks instead of the
+    /// public web roots
+    #[cfg(feature = "net")]
+    #[arg(long)]
+    sink_ca: Option<PathBuf>,
+
+    /// PEM client certificate chain presented by `tcps://` and `https://` sinks
+    #[cfg(feature = "net")]
+    #[arg(long, requires = "sink_key")]
+    sink_cert: Option<PathBuf>,
+
+    /// PEM private key of `--sink-cert`
+    #[cfg(feature = "net")]
+    #[arg(long, requires = "sink_cert")]
+    sink_key: Option<PathBuf>,
+
+    /// File holding a bearer token sent by `http://`, `https://` and `nats://` sinks,
+    /// also used as the password of `redis://` sinks
+    #[cfg(feature = "net")]
+    #[arg(long)]
+    sink_token_file: Option<PathBuf>,
+
+    /// Export tracing spans of sampling (NVML per GPU, DCGM), encoding and sink writes to
+    /// this OpenTelemetry collector over OTLP/HTTP, e.g. `http://collector:4318`, to find
+    /// what slows down a sample. `https://` collectors are trusted as `--sink-ca` says
+    #[cfg(feature = "net")]
+    #[arg(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// Read node wall power and inlet temperature from the BMC: `ipmi` (via ipmitool)
+    /// or a Redfish endpoint such as `https://bmc-host`. Adds `node.powerWatts`,
+    /// `node.inletTemp` and the GPUs' share of node power as `node.gpu.powerPercent`
+    #[cfg(feature = "net")]
+    #[arg(long)]
+    bmc: Option<String>,
+
+    /// How often to read the BMC, which can take seconds to answer
+    #[cfg(feature = "net")]
+    #[arg(long, default_value = "10s", value_parser = units::parse_duration)]
+    bmc_interval: Duration,
+
+    /// PEM bundle of CAs trusted for a Redfish BMC, e.g. its self-signed certificate
+    #[cfg(feature = "net")]
+    #[arg(long)]
+    bmc_ca: Option<PathBuf>,
+
+    /// File holding `user:password` for a Redfish BMC
+    #[cfg(feature = "net")]
+    #[arg(long)]
+    bmc_credentials_file: Option<PathBuf>,
+
+    /// Scheduling niceness of the agent (-20 to 19)
+    #[arg(long, allow_hyphen_values = true)]
+    nice: Option<i32>,
+
+    /// Restrict the agent to these CPUs, e.g. `0-1` or `0,4`
+    #[arg(long)]
+    cpu_affinity: Option<String>,
+
+    /// Run the agent in this cgroup v2 directory, creating it if needed
+    #[arg(long)]
+    cgroup: Option<PathBuf>,
+
+    /// CPU limit for the agent's cgroup, in percent of one CPU
+    #[arg(long, requires = "cgroup")]
+    cpu_quota: Option<f64>,
+
+    /// Memory limit for the agent, e.g. `64MiB`. Uses the cgroup if set, rlimit otherwise
+    #[arg(long, value_parser = units::parse_size)]
+    memory_limit: Option<u64>,
+
+    /// Detach from the terminal and run in the background
+    #[cfg(unix)]
+    #[arg(long)]
+    daemonize: bool,
+
+    /// Write the agent's process ID to this file while it is running
+    #[cfg(unix)]
+    #[arg(long)]
+    pidfile: Option<PathBuf>,
+
+    /// When started as root, switch to this user once NVML, sinks and servers are set up.
+    /// Files written later (rotated output, `--state-file`, the pidfile's removal) need
+    /// permissions for this user, and GPU settings stop working, so it can't be combined
+    /// with --power-policy or --fan-curve, which couldn't hand fans back on exit
+    #[cfg(unix)]
+    #[arg(long, conflicts_with_all = ["power_policy", "fan_curve"])]
+    user: Option<String>,
+
+    /// Restrict the agent with Landlock and seccomp once started: it may only read the
+    /// driver's libraries, /proc, /sys and the files it's given, write to GPU devices and
+    /// its output files, spool and state, make only the system calls it needs, and can't
+    /// run programs unless `--bmc ipmi`, `--clock-sync` or `symon run` need to, which
+    /// leaves everything readable. Sinks added by reloading `--config` can't write files
+    /// outside the directories known at startup
+    #[cfg(target_os = "linux")]
+    #[arg(long)]
+    sandbox: bool,
+
+    /// Where to write diagnostic messages
+    #[arg(long, value_enum, default_value_t = LogTarget::Auto)]
+    log_target: LogTarget,
+
+    /// Serve the HTTP API (`/history`, `/healthz`, `/readyz`) on this address,
+    /// e.g. `127.0.0.1:9400`
+    #[cfg(feature = "net")]
+    #[arg(long)]
+    http_listen: Option<String>,
+
+    /// Serve the HTTP API over TLS with this PEM certificate chain
+    #[cfg(feature = "net")]
+    #[arg(long, requires = "http_key")]
+    http_cert: Option<PathBuf>,
+
+    /// PEM private key of `--http-cert`
+    #[cfg(feature = "net")]
+    #[arg(long, requires = "http_cert")]
+    http_key: Option<PathBuf>,
+
+    /// Require HTTPS clients to present a certificate signed by a CA in this PEM bundle
+    #[cfg(feature = "net")]
+    #[arg(long, requires = "http_cert")]
+    http_client_ca: Option<PathBuf>,
+
+    /// File holding a bearer token required for `/history`
+    #[cfg(feature = "net")]
+    #[arg(long)]
+    http_token_file: Option<PathBuf>,
+
+    /// Serve `POST /control/<operation>` on the HTTP API, requiring the bearer token
+    /// in this file
+    #[cfg(feature = "net")]
+    #[arg(long, requires = "http_listen")]
+    control_token_file: Option<PathBuf>,
+
+    /// Control operations the HTTP API accepts; `power-limit` must be listed explicitly
+    #[cfg(feature = "net")]
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "pause,resume,sample,interval,marker"
+    )]
+    control_allow: Vec<Operation>,
+
+    /// Follow this file for markers, one per line, e.g.
+    /// `echo "epoch 3 start" >> /tmp/symon.markers`, and record each as an event
+    #[arg(long)]
+    marker_file: Option<PathBuf>,
+
+    /// Write the readiness status as JSON to this file after every sample, e.g. for
+    /// exec probes
+    #[arg(long)]
+    health_file: Option<PathBuf>,
+
+    /// How much sample history to keep for `/history`, e.g. `10m`
+    #[cfg(feature = "net")]
+    #[arg(long, default_value = "10m", value_parser = units::parse_duration)]
+    history_window: Duration,
+
+    /// Print a summary of the run to stderr on exit. Markers named `<phase> start`
+    /// and `<phase> end` split it into phases, with the GPU time of the `--pid`
+    /// processes per phase from accounting mode or their utilization
+    #[arg(long)]
+    report_on_exit: bool,
+
+    /// Utilization thresholds (in percentage) for the time spent above each in the
+    /// summary and residency counters
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "50,90",
+        value_parser = residency::parse_threshold
+    )]
+    utilization_thresholds: Vec<f64>,
+
+    /// Add running totals of the seconds spent in each P-state, throttled for each
+    /// reason and above each utilization threshold, as `_gpu.{i}.residency.*`
+    #[arg(long)]
+    residency_counters: bool,
+
+    /// Write an `idle_gpu` event when a GPU has had no processes and utilization at
+    /// or below `--idle-utilization` for this long, e.g. `30m`
+    #[arg(long, value_parser = units::parse_duration)]
+    idle_after: Option<Duration>,
+
+    /// Utilization (in percentage) at or below which a GPU without processes is idle
+    #[arg(long, default_value_t = idle::DEFAULT_IDLE_UTILIZATION)]
+    idle_utilization: f64,
+
+    /// Also send `idle_gpu` events to this sink, e.g.
+    /// `https://hooks.example.com/idle?batch=1` to post each event as it happens
+    #[arg(long, requires = "idle_after")]
+    idle_webhook: Option<String>,
+
+    /// Write an `ecc_advice` event when a GPU's ECC errors, row remapping or page retirement
+    /// call for action, e.g. a pending remapping that needs a reset, with a `severity` (info,
+    /// warning or critical) and `action` (monitor, reset, drain or replace) to route on
+    #[arg(long)]
+    ecc_advisor: bool,
+
+    /// Also send `ecc_advice` events to this sink, e.g. `https://hooks.example.com/ecc?batch=1`
+    #[arg(long, requires = "ecc_advisor")]
+    ecc_advice_webhook: Option<String>,
+
+    /// Write a `throttle` event when a GPU's clocks were held down for one cause (power-capped,
+    /// thermally limited, power brake, hardware slowdown or sync boost) for at least this long,
+    /// e.g. `10s`, with its duration and the temperature, power and clocks meanwhile
+    #[arg(long, value_parser = units::parse_duration)]
+    throttle_events: Option<Duration>,
+
+    /// Compare the run against this stored baseline (see `symon baseline save`) and write
+    /// `baseline_deviation` and `baseline_recovered` events when utilization, memory or power
+    /// leave or re-enter its envelope, e.g. to catch regressions of a recurring training job
+    #[arg(long, value_name = "NAME")]
+    baseline: Option<String>,
+
+    /// Directory of stored baselines; defaults to `symon/baselines` in the user's data directory
+    #[arg(long)]
+    baseline_dir: Option<PathBuf>,
+
+    /// Average readings over this long before comparing them with the baseline; nothing is
+    /// compared until the run has lasted this long
+    #[arg(long, default_value = "60s", value_parser = units::parse_duration)]
+    baseline_window: Duration,
+
+    /// Widen the baseline's envelope by this much (in percentage) before flagging a deviation
+    #[arg(long, default_value_t = 10.0)]
+    baseline_tolerance: f64,
+
+    /// Adjust GPU power limits according to the rules in this JSON file, writing a
+    /// `power_limit` event for each change; re-read on SIGHUP
+    #[arg(long)]
+    power_policy: Option<PathBuf>,
+
+    /// Drive fan speeds from the temperature curve in this JSON file; re-read on SIGHUP.
+    /// Fans are handed back to the driver on exit
+    #[arg(long)]
+    fan_curve: Option<PathBuf>,
+
+    /// Report GPU memory and utilization per Kubernetes pod and container as `_gpu.N.pods`,
+    /// as allocated by the kubelet's pod resources API
+    #[arg(long)]
+    k8s: bool,
+
+    /// Report GPU memory and utilization per user as `_gpu.N.users`
+    #[arg(long)]
+    users: bool,
+
+    /// Accumulate GPU-seconds, memory GB-hours and energy per user, pod, monitored process
+    /// and label, and emit them as a `_billing` record at the end of every period.
+    /// Implies --users
+    #[arg(long)]
+    billing: bool,
+
+    /// Length of a billing period, e.g. `1h`; periods start on multiples of it
+    #[arg(long, default_value = "1h", value_parser = units::parse_duration)]
+    billing_period: Duration,
+
+    /// Bill all GPUs in use to this label, e.g. `job=1234`; can be repeated
+    #[arg(long, value_name = "KEY=VALUE")]
+    billing_label: Vec<String>,
+
+    /// Keep billing accumulators and GPU counters in this file so restarts don't reset them.
+    /// Counters such as energy, ECC and XID errors then also keep growing across driver reloads
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// List the processes on each GPU (pid, user, command, memory, container) as
+    /// `_gpu.N.processes`, in every sample or, with a value, every N-th sample
+    #[arg(
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "1",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    processes: Option<u32>,
+
+    /// Emit a one-off `_topology` record describing how the GPUs are connected at startup
+    #[arg(long)]
+    topology: bool,
+
+    /// Add the host's clock synchronization state (`_clock.*`) to every sample, as chrony
+    /// reports it, or the kernel where chrony isn't running, read once a minute. `run_start`
+    /// records always carry it
+    #[arg(long)]
+    clock_sync: bool,
+
+    /// JSON file overriding `interval`, `pid` and `sinks`, and routing the process-attributed
+    /// metrics of `tenants` to their own sinks; re-read on SIGHUP
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Summarize a recorded trace, e.g. one written with `--out`
+    #[cfg(feature = "tools")]
+    Report {
+        /// Trace file; may be gzip or zstd compressed
+        trace: PathBuf,
+    },
+    /// Select metrics from a recorded trace, optionally aggregated
+    #[cfg(feature = "tools")]
+    Query {
+        /// Trace file; may be gzip or zstd compressed
+        trace: PathBuf,
+        /// Metric name, may contain `*` wildcards, e.g. `gpu.*.powerWatts`. May be repeated
+        #[arg(long = "metric", required = true)]
+        metrics: Vec<String>,
+        /// Only include samples at or after this time (epoch seconds or RFC 3339)
+        #[arg(long, value_parser = query::parse_time)]
+        from: Option<f64>,
+        /// Only include samples at or before this time (epoch seconds or RFC 3339)
+        #[arg(long, value_parser = query::parse_time)]
+        to: Option<f64>,
+        /// Reduce each metric to one value: count, min, max, mean, sum, first, last or pNN
+        #[arg(long, value_parser = Aggregation::parse)]
+        agg: Option<Aggregation>,
+        #[arg(long, value_enum, default_value_t = QueryFormat::Csv)]
+        format: QueryFormat,
+    },
+    /// Compare two recorded traces and highlight regressions from A to B
+    #[cfg(feature = "tools")]
+    Diff {
+        a: PathBuf,
+        b: PathBuf,
+        /// Percentage change in the wrong direction that counts as a regression
+        #[arg(long, default_value_t = 5.0)]
+        threshold: f64,
+        /// Exit with status 1 if any regression is found
+        #[arg(long)]
+        fail_on_regression: bool,
+    },
+    /// Store, list or show baselines, the expected envelope of a workload that `--baseline`
+    /// compares runs against
+    #[cfg(feature = "tools")]
+    Baseline {
+        #[command(subcommand)]
+        action: BaselineAction,
+        /// Directory of stored baselines; defaults to `symon/baselines` in the user's data
+        /// directory
+        #[arg(long, global = true)]
+        baseline_dir: Option<PathBuf>,
+    },
+    /// Change power limits, clock locks or persistence mode; requires root
+    #[cfg(feature = "tools")]
+    Set {
+        /// GPU index to change. May be repeated or comma-separated; all GPUs if omitted
+        #[arg(long, value_delimiter = ',')]
+        gpu: Vec<u32>,
+        /// Enforced power limit, e.g. `300W`
+        #[arg(long, value_parser = units::parse_watts)]
+        power_limit: Option<f64>,
+        /// Lock GPU clocks to `MIN:MAX` MHz, or `reset` to unlock
+        #[arg(long, value_parser = ClockLock::parse)]
+        gpu_clocks: Option<ClockLock>,
+        /// Lock memory clocks to `MIN:MAX` MHz, or `reset` to unlock
+        #[arg(long, value_parser = ClockLock::parse)]
+        memory_clocks: Option<ClockLock>,
+        /// Turn persistence mode on or off
+        #[arg(long, value_parser = clap::builder::BoolishValueParser::new())]
+        persistence: Option<bool>,
+        /// Show what would change without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Take a GPU out of service: keep new processes off it, wait for running ones
+    /// to exit and optionally reset it. Requires root
+    #[cfg(all(target_os = "linux", feature = "tools"))]
+    Drain {
+        /// GPU index
+        gpu: u32,
+        /// Mark the GPU unhealthy in this `key=value` label file, e.g. under
+        /// node-feature-discovery's features.d
+        #[arg(long)]
+        label_file: Option<PathBuf>,
+        /// How long to wait for processes to exit
+        #[arg(long, default_value = "5m", value_parser = units::parse_duration)]
+        timeout: Duration,
+        /// Reset the GPU once no processes are left
+        #[arg(long)]
+        reset: bool,
+    },
+    /// Run a command and monitor its processes, e.g. `symon --out run.jsonl run -- python train.py`.
+    /// Exits with the command's exit code
+    Run {
+        /// Sample for this long before starting the command, e.g. `30s`
+        #[arg(long, default_value = "0s", value_parser = units::parse_duration)]
+        warm_up: Duration,
+        /// Keep sampling for this long after the command exits
+        #[arg(long, default_value = "10s", value_parser = units::parse_duration)]
+        cool_down: Duration,
+        /// The command and its arguments
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        command: Vec<OsString>,
+    },
+    /// Print how the GPUs are connected to each other and to the host as JSON
+    #[cfg(feature = "tools")]
+    Topology,
+    /// Print a human-readable overview of the driver and every GPU, e.g. for bug reports
+    #[cfg(feature = "tools")]
+    Snapshot,
+    /// Take one sample and print a single metric's value, e.g. `symon get gpu.0.memoryAllocatedBytes`.
+    /// Strings are printed without quotes. Exits with status 1 if the metric isn't reported
+    #[cfg(feature = "tools")]
+    Get {
+        /// Metric name; the leading `_` of metadata names such as `_gpu.0.name` may be left out
+        metric: String,
+    },
+    /// Block until a GPU is idle (no processes, utilization at or below a threshold) and print
+    /// its index, e.g. `symon wait --idle gpu.0 --timeout 10m`. Exits with status 1 on timeout
+    #[cfg(feature = "tools")]
+    Wait {
+        /// GPU to wait for, as `gpu.N` or `N`, or `any` for the first GPU to go idle
+        #[arg(long, value_name = "GPU", value_parser = parse_gpu_target)]
+        idle: GpuTarget,
+        /// Utilization (in percentage) at or below which a GPU without processes is idle
+        #[arg(long, default_value_t = idle::DEFAULT_IDLE_UTILIZATION)]
+        utilization: f64,
+        /// Give up after this long, e.g. `10m`; waits indefinitely if omitted
+        #[arg(long, value_parser = units::parse_duration)]
+        timeout: Option<Duration>,
+        /// How often to check
+        #[arg(long, default_value = "1s", value_parser = units::parse_duration)]
+        interval: Duration,
+    },
+    /// Reserve the least used GPUs for a command, e.g. `symon reserve 2 -- python train.py`.
+    /// The command sees only those GPUs through CUDA_VISIBLE_DEVICES, and others running
+    /// `symon reserve` won't pick them until it exits. Exits with the command's exit code
+    #[cfg(feature = "tools")]
+    Reserve {
+        /// Number of GPUs to reserve
+        #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+        count: u32,
+        /// Only reserve GPUs with at least this much memory free, e.g. `20GiB`
+        #[arg(long, value_parser = units::parse_size)]
+        min_free_mem: Option<u64>,
+        /// Directory of the reservation lock files, shared by everyone on the machine;
+        /// defaults to `symon-reservations` in the temporary directory
+        #[arg(long)]
+        lock_dir: Option<PathBuf>,
+        /// The command and its arguments
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        command: Vec<OsString>,
+    },
+    /// Print the indices of the least used GPUs, comma-separated, e.g.
+    /// `symon pick --count 2 --min-free-mem 20GiB`. Indices are NVML's, which CUDA shares
+    /// with CUDA_DEVICE_ORDER=PCI_BUS_ID. GPUs without processes come first, then
+    /// those with the lowest utilization plus memory allocated, then the coolest. Exits with
+    /// status 1 if fewer GPUs qualify
+    #[cfg(feature = "tools")]
+    Pick {
+        /// Number of GPUs to pick
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        count: u32,
+        /// Only pick GPUs with at least this much memory free, e.g. `20GiB`
+        #[arg(long, value_parser = units::parse_size)]
+        min_free_mem: Option<u64>,
+    },
+    /// Run an active benchmark of the GPUs, e.g. `symon bench pcie`
+    #[cfg(feature = "bench")]
+    Bench {
+        #[command(subcommand)]
+        bench: BenchCommand,
+    },
+    /// Load GPUs with a CUDA workload while sampling them often, then print a pass/fail report
+    /// of their temperature, power, throttling and errors, e.g. `symon stress --duration 10m`
+    /// as an acceptance test for new nodes. Exits with status 1 if any GPU fails
+    #[cfg(feature = "stress")]
+    Stress {
+        /// How long to load the GPUs
+        #[arg(long, default_value = "5m", value_parser = units::parse_duration)]
+        duration: Duration,
+        /// How often to sample while loaded
+        #[arg(long, default_value = "100ms", value_parser = units::parse_duration)]
+        interval: Duration,
+        /// GPU to load, by NVML index; may be repeated. Loads every GPU if omitted
+        #[arg(long)]
+        gpu: Vec<u32>,
+        /// Fail GPUs hotter than this (in Celsius)
+        #[arg(long, default_value_t = 85.0)]
+        max_temp: f64,
+        /// Fail GPUs whose mean power draw under load is below this share of their power
+        /// limit (in percentage), as they aren't reaching full performance
+        #[arg(long, default_value_t = 50.0)]
+        min_power_percent: f64,
+    },
+    /// Take one sample and exit with status 0 if all conditions hold, or 1 after printing
+    /// those that don't, e.g. `symon assert 'gpu.*.memoryAllocated < 10'` before a job starts
+    #[cfg(feature = "tools")]
+    Assert {
+        /// `METRIC OP VALUE` with OP one of <, <=, >, >=, == or !=. The metric may contain
+        /// `*` wildcards, which every matching metric must pass. May be repeated
+        #[arg(required = true, value_parser = Condition::parse)]
+        conditions: Vec<Condition>,
+    },
+    /// Print the JSON Schema of the records symon writes
+    #[cfg(feature = "tools")]
+    Schema {
+        /// Schema version, as in the `_schema_version` of records; defaults to the current one
+        #[arg(long, default_value_t = schema::VERSION)]
+        version: u32,
+    },
+    /// List every metric symon can write, with its type, unit, source and description
+    #[cfg(feature = "tools")]
+    Metrics {
+        /// Print the list as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print a Grafana dashboard of symon's metrics as JSON, ready to import
+    #[cfg(feature = "tools")]
+    GrafanaDashboard {
+        /// Data source the dashboard queries
+        #[arg(long, value_enum, default_value_t = Datasource::Prom)]
+        datasource: Datasource,
+    },
+    /// Stop a running agent using its pidfile
+    #[cfg(unix)]
+    Stop {
+        #[arg(long, default_value = "/run/symon.pid")]
+        pidfile: PathBuf,
+    },
+    /// Report whether an agent is running using its pidfile
+    #[cfg(unix)]
+    Status {
+        #[arg(long, default_value = "/run/symon.pid")]
+        pidfile: PathBuf,
+    },
+    /// Manage the symon Windows service
+    #[cfg(windows)]
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+}
+
+#[cfg(windows)]
+#[derive(Subcommand, Debug)]
+enum ServiceAction {
+    /// Register symon as an automatically started Windows service
+    Install {
+        /// Monitoring options the service is started with, e.g. `-- --sink tcp://collector:9000`
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<OsString>,
+    },
+    /// Stop and remove the symon Windows service
+    Uninstall,
+    /// Run as the service. Invoked by the service control manager
+    Run,
 }
 
+#[cfg(feature = "bench")]
+#[derive(Subcommand, Debug)]
+enum BenchCommand {
+    /// Measure host to GPU and GPU to host transfer bandwidth, one GPU at a time, and compare
+    /// it with what the PCIe link should carry. Exits with status 1 if any link is degraded,
+    /// i.e. narrower than it supports or well short of its bandwidth
+    Pcie {
+        /// GPU to measure, by NVML index; may be repeated. Measures every GPU if omitted
+        #[arg(long)]
+        gpu: Vec<u32>,
+        /// Bytes per transfer, e.g. `256MiB`
+        #[arg(long, default_value = "256MiB", value_parser = units::parse_size)]
+        size: u64,
+        /// Timed transfers in each direction
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+        iterations: u32,
+        /// Share of the link's theoretical bandwidth (in percentage) below which it's degraded
+        #[arg(long, default_value_t = 60.0)]
+        min_efficiency: f64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum BaselineAction {
+    /// Record the envelope of a good run from its trace under a name, replacing any baseline of
+    /// that name, e.g. `symon baseline save nightly-train run.jsonl`
+    Save {
+        name: String,
+        /// Trace file; may be gzip or zstd compressed
+        trace: PathBuf,
+    },
+    /// List the stored baselines
+    List,
+    /// Print a stored baseline as JSON
+    Show { name: String },
+}
+
+#[cfg(feature = "tools")]
+/// A GPU for `symon wait`, or any GPU if `None`.
+type GpuTarget = Option<u32>;
+
+#[cfg(feature = "tools")]
+fn parse_gpu_target(s: &str) -> Result<GpuTarget, String> {
+    if s == "any" {
+        return Ok(None);
+    }
+    s.strip_prefix("gpu.")
+        .unwrap_or(s)
+        .parse()
+        .map(Some)
+        .map_err(|_| format!("invalid GPU {:?}: expected gpu.N, N or any", s))
+}
+
+#[cfg(feature = "sentry")]
 fn parse_bool(s: &str) -> bool {
     match s.to_lowercase().as_str() {
         "true" | "1" => true,
@@ -43,85 +888,1656 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse command-line arguments
     let args = Args::parse();
 
-    let error_reporting_enabled = env::var("WANDB_ERROR_REPORTING")
-        .map(|v| parse_bool(&v))
-        .unwrap_or(true);
+    match &args.command {
+        #[cfg(feature = "tools")]
+        Some(Command::Report { trace }) => report(trace),
+        #[cfg(feature = "tools")]
+        Some(Command::Query {
+            trace,
+            metrics,
+            from,
+            to,
+            agg,
+            format,
+        }) => {
+            let query = Query {
+                patterns: metrics.clone(),
+                from: *from,
+                to: *to,
+                aggregation: *agg,
+                format: *format,
+            };
+            let mut reader = TraceReader::open(trace)?;
+            query.run(&mut reader, &mut io::stdout().lock())?;
+            Ok(())
+        }
+        #[cfg(feature = "tools")]
+        Some(Command::Set {
+            gpu,
+            power_limit,
+            gpu_clocks,
+            memory_clocks,
+            persistence,
+            dry_run,
+            yes,
+        }) => {
+            let settings = DeviceSettings {
+                power_limit_watts: *power_limit,
+                gpu_clocks: *gpu_clocks,
+                memory_clocks: *memory_clocks,
+                persistence: *persistence,
+            };
+            let gpus = (!gpu.is_empty()).then(|| gpu.clone());
+            set(settings, gpus, *dry_run, *yes)
+        }
+        #[cfg(all(target_os = "linux", feature = "tools"))]
+        Some(Command::Drain {
+            gpu,
+            label_file,
+            timeout,
+            reset,
+        }) => {
+            let options = DrainOptions {
+                gpu: *gpu,
+                label_file: label_file.clone(),
+                timeout: *timeout,
+                reset: *reset,
+            };
+            let mut sampler = Sampler::new()?;
+            let drained = drain::drain(&mut sampler, &options, &mut io::stdout().lock());
+            sampler.shutdown()?;
+            if !drained? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        #[cfg(feature = "tools")]
+        Some(Command::Topology) => {
+            let mut sampler = Sampler::new()?;
+            let topology = sampler.topology();
+            sampler.shutdown()?;
+            serde_json::to_writer_pretty(io::stdout().lock(), &topology?)?;
+            println!();
+            Ok(())
+        }
+        #[cfg(feature = "tools")]
+        Some(Command::Snapshot) => {
+            let mut sampler = Sampler::new()?;
+            sampler.set_process_list(Some(1));
+            let sample = sampler.sample();
+            let devices = sampler.devices();
+            let features = sampler.features();
+            sampler.shutdown()?;
+            print!(
+                "{}",
+                snapshot::render(&sample?, &devices?, &features?.nvml_version)
+            );
+            Ok(())
+        }
+        #[cfg(feature = "tools")]
+        Some(Command::Get { metric }) => {
+            let mut sampler = Sampler::new()?;
+            if metric.ends_with(".processes") {
+                sampler.set_process_list(Some(1));
+            }
+            let sample = sampler.sample();
+            sampler.shutdown()?;
+            let sample = sample?;
+            let value = sample
+                .get(metric)
+                .or_else(|| sample.get(&format!("_{}", metric)));
+            match value {
+                Some(serde_json::Value::String(text)) => println!("{}", text),
+                Some(value) => println!("{}", value),
+                None => {
+                    eprintln!("Error: {} is not reported on this node", metric);
+                    std::process::exit(1);
+                }
+            }
+            Ok(())
+        }
+        #[cfg(feature = "tools")]
+        Some(Command::Wait {
+            idle,
+            utilization,
+            timeout,
+            interval,
+        }) => {
+            let mut sampler = Sampler::new()?;
+            let idle_gpu = wait_for_idle(&mut sampler, *idle, *utilization, *timeout, *interval);
+            sampler.shutdown()?;
+            match idle_gpu? {
+                Some(gpu) => println!("{}", gpu),
+                None => {
+                    eprintln!("Timed out waiting for an idle GPU");
+                    std::process::exit(1);
+                }
+            }
+            Ok(())
+        }
+        #[cfg(feature = "tools")]
+        Some(Command::Pick {
+            count,
+            min_free_mem,
+        }) => {
+            let options = PickOptions {
+                min_free_memory_bytes: *min_free_mem,
+            };
+            let mut sampler = Sampler::new()?;
+            let sample = sampler.sample();
+            let devices = sampler.devices();
+            sampler.shutdown()?;
+            let picked = pick::pick(&sample?, &devices?, &options);
+            if picked.len() < *count as usize {
+                eprintln!("Only {} of {} GPUs qualify", picked.len(), count);
+                std::process::exit(1);
+            }
+            let indices: Vec<String> = picked
+                .iter()
+                .take(*count as usize)
+                .map(|gpu| gpu.index.to_string())
+                .collect();
+            println!("{}", indices.join(","));
+            Ok(())
+        }
+        #[cfg(feature = "bench")]
+        Some(Command::Bench {
+            bench:
+                BenchCommand::Pcie {
+                    gpu,
+                    size,
+                    iterations,
+                    min_efficiency,
+                },
+        }) => {
+            let options = PcieOptions {
+                size: *size as usize,
+                iterations: *iterations,
+                min_efficiency: *min_efficiency,
+            };
+            let mut sampler = Sampler::new()?;
+            let selected = (|| -> Result<_, Box<dyn std::error::Error>> {
+                let sample = sampler.sample()?;
+                let gpus = gpu_selection(&sample, gpu)?;
+                Ok((with_bus_ids(&mut sampler, &gpus)?, sample))
+            })();
+            // NVML isn't needed while measuring
+            sampler.shutdown()?;
+            let (gpus, sample) = selected?;
+            let results = bench::pcie(&sample, &gpus, &options)?;
+            for result in &results {
+                print!("{}", result);
+            }
+            if results.iter().any(PcieResult::degraded) {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        #[cfg(feature = "stress")]
+        Some(Command::Stress {
+            duration,
+            interval,
+            gpu,
+            max_temp,
+            min_power_percent,
+        }) => {
+            let limits = StressLimits {
+                max_temp: *max_temp,
+                min_power_percent: *min_power_percent,
+            };
+            let mut sampler = Sampler::new()?;
+            let report = stress(&mut sampler, gpu, limits, *duration, *interval);
+            sampler.shutdown()?;
+            let report = report?;
+            print!("{}", report);
+            if !report.passed() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        #[cfg(feature = "tools")]
+        Some(Command::Reserve {
+            count,
+            min_free_mem,
+            lock_dir,
+            command,
+        }) => {
+            let options = PickOptions {
+                min_free_memory_bytes: *min_free_mem,
+            };
+            let exit_code = reserve(*count, &options, lock_dir.clone(), command)?;
+            std::process::exit(exit_code)
+        }
+        #[cfg(feature = "tools")]
+        Some(Command::Assert { conditions }) => {
+            let mut sampler = Sampler::new()?;
+            let sample = sampler.sample();
+            sampler.shutdown()?;
+            let sample = sample?;
+            let failures: Vec<String> = conditions
+                .iter()
+                .flat_map(|condition| condition.check(&sample))
+                .collect();
+            if !failures.is_empty() {
+                for failure in failures {
+                    eprintln!("{}", failure);
+                }
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        #[cfg(feature = "tools")]
+        Some(Command::Schema { version }) => {
+            let schema = schema::json_schema(*version).ok_or_else(|| {
+                format!(
+                    "unsupported schema version {}; supported versions: {:?}",
+                    version,
+                    schema::SUPPORTED_VERSIONS
+                )
+            })?;
+            serde_json::to_writer_pretty(io::stdout().lock(), &schema)?;
+            println!();
+            Ok(())
+        }
+        #[cfg(feature = "tools")]
+        Some(Command::Metrics { json }) => {
+            if *json {
+                serde_json::to_writer_pretty(io::stdout().lock(), &manifest::to_json())?;
+                println!();
+                return Ok(());
+            }
+            for metric in manifest::metrics() {
+                let unit = match metric.unit {
+                    "" => String::new(),
+                    unit => format!(" ({})", unit),
+                };
+                println!(
+                    "{} [{}{}]\n    {}; from {}",
+                    metric.display_name(),
+                    metric.kind().as_str(),
+                    unit,
+                    metric.description,
+                    metric.source
+                );
+                if !metric.notes.is_empty() {
+                    println!("    {}", metric.notes);
+                }
+            }
+            Ok(())
+        }
+        #[cfg(feature = "tools")]
+        Some(Command::Baseline {
+            action,
+            baseline_dir,
+        }) => {
+            let dir = baseline_dir
+                .clone()
+                .unwrap_or_else(baseline::default_baseline_dir);
+            match action {
+                BaselineAction::Save { name, trace } => {
+                    let mut reader = TraceReader::open(trace)?;
+                    let mut error = None;
+                    let samples = (&mut reader)
+                        .map_while(|metrics| metrics.map_err(|e| error = Some(e)).ok());
+                    let baseline = Baseline::from_samples(name, samples);
+                    if let Some(e) = error {
+                        return Err(e.into());
+                    }
+                    if baseline.envelopes.is_empty() {
+                        return Err(format!("{} has no GPU samples", trace.display()).into());
+                    }
+                    let path = baseline.save(&dir)?;
+                    println!(
+                        "Saved baseline {} from {} samples to {}",
+                        name,
+                        baseline.samples,
+                        path.display()
+                    );
+                }
+                BaselineAction::List => {
+                    for name in Baseline::list(&dir)? {
+                        println!("{}", name);
+                    }
+                }
+                BaselineAction::Show { name } => {
+                    let baseline = Baseline::load(&dir, name)?;
+                    serde_json::to_writer_pretty(io::stdout().lock(), &baseline)?;
+                    println!();
+                }
+            }
+            Ok(())
+        }
+        #[cfg(feature = "tools")]
+        Some(Command::Diff {
+            a,
+            b,
+            threshold,
+            fail_on_regression,
+        }) => {
+            let (comparison, regressions) =
+                diff::compare(&load_report(a)?, &load_report(b)?, *threshold);
+            print!("{}", comparison);
+            if regressions > 0 {
+                println!("\n{} regressions", regressions);
+                if *fail_on_regression {
+                    std::process::exit(1);
+                }
+            }
+            Ok(())
+        }
+        #[cfg(feature = "tools")]
+        Some(Command::GrafanaDashboard { datasource }) => {
+            serde_json::to_writer_pretty(io::stdout().lock(), &grafana::dashboard(*datasource))?;
+            println!();
+            Ok(())
+        }
+        #[cfg(unix)]
+        Some(Command::Stop { pidfile }) => Ok(daemon::stop(pidfile)?),
+        #[cfg(unix)]
+        Some(Command::Status { pidfile }) => std::process::exit(daemon::status(pidfile)),
+        #[cfg(windows)]
+        Some(Command::Service { action }) => match action {
+            ServiceAction::Install { args } => Ok(win_service::install(args)?),
+            ServiceAction::Uninstall => Ok(win_service::uninstall()?),
+            ServiceAction::Run => Ok(win_service::run()?),
+        },
+        Some(Command::Run { .. }) => {
+            let exit_code = monitor(&args, Arc::new(AtomicBool::new(true)))?;
+            std::process::exit(exit_code)
+        }
+        None => monitor(&args, Arc::new(AtomicBool::new(true))).map(drop),
+    }
+}
+
+/// Sample metrics until `running` is cleared, a termination signal is received,
+/// or the parent process exits. Returns the exit code of the command of
+/// `symon run`, or 0.
+fn monitor(args: &Args, running: Arc<AtomicBool>) -> Result<i32, Box<dyn std::error::Error>> {
+    if args.watch && args.format != OutputFormat::Smi {
+        return Err("--watch requires --format smi".into());
+    }
 
-    let dsn: Option<Dsn> = if error_reporting_enabled {
-        "https://9e9d0694aa7ccd41aeb5bc34aadd716a@o151352.ingest.us.sentry.io/4506068829470720"
-            .parse()
-            .ok()
-    } else {
-        None
+    // Detaching forks the process, so it has to happen before any threads are spawned
+    #[cfg(unix)]
+    if args.daemonize {
+        daemon::daemonize()?;
+    }
+    log::init(args.log_target);
+    #[cfg(unix)]
+    let _pidfile = args.pidfile.as_deref().map(PidFile::create).transpose()?;
+    let mut notifier = Notifier::from_env();
+
+    // Apply self-imposed resource limits before any threads are spawned
+    let self_limits = SelfLimits {
+        nice: args.nice,
+        cpu_affinity: args
+            .cpu_affinity
+            .as_deref()
+            .map(limits::parse_cpu_list)
+            .transpose()?,
+        cgroup: args.cgroup.clone(),
+        cpu_quota_percent: args.cpu_quota,
+        memory_limit_bytes: args.memory_limit,
     };
+    self_limits.apply()?;
 
-    let _guard = sentry::init(sentry::ClientOptions {
-        dsn,
-        release: sentry::release_name!(),
-        ..Default::default()
-    });
+    // Landlock only restricts threads started afterwards, so sandbox before any are
+    #[cfg(target_os = "linux")]
+    if args.sandbox {
+        sandbox::apply(&sandbox_options(args)?)?;
+        log::info!("Sandbox applied");
+    }
 
-    // Initialize NVIDIA GPU
-    let nvidia_gpu = NvidiaGpu::new().map_err(|e| {
-        // this typically means that the NVIDIA driver is not installed /
-        // libnvidia-ml.so is not found / no NVIDIA GPU is present
-        e
-    })?;
-
-    // Set up a flag to control the main sampling loop
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-
-    // Set up signal handler for graceful shutdown
-    let mut signals = Signals::new(TERM_SIGNALS)?;
-    thread::spawn(move || {
-        for _sig in signals.forever() {
-            r.store(false, Ordering::Relaxed);
-            break;
+    #[cfg(feature = "sentry")]
+    let _guard = {
+        let error_reporting_enabled = env::var("WANDB_ERROR_REPORTING")
+            .map(|v| parse_bool(&v))
+            .unwrap_or(true);
+
+        let dsn: Option<Dsn> = if error_reporting_enabled {
+            "https://9e9d0694aa7ccd41aeb5bc34aadd716a@o151352.ingest.us.sentry.io/4506068829470720"
+                .parse()
+                .ok()
+        } else {
+            None
+        };
+
+        sentry::init(sentry::ClientOptions {
+            dsn,
+            release: sentry::release_name!(),
+            ..Default::default()
+        })
+    };
+
+    // Initialize NVIDIA GPU on a guarded sampling thread. An error here
+    // typically means that the NVIDIA driver is not installed /
+    // libnvidia-ml.so is not found / no NVIDIA GPU is present. Without
+    // libnvidia-ml at all, keep sampling what doesn't need it
+    let mut sampler = match Sampler::with_timeout(
+        Duration::from_secs_f64(args.sampling_timeout),
+        args.max_sampling_timeouts,
+    ) {
+        Ok(sampler) => sampler,
+        Err(e) if e.is_nvml_missing() => {
+            log::warning!("Sampling without GPU metrics: {}", e);
+            if cfg!(all(target_env = "musl", target_feature = "crt-static")) {
+                log::warning!(
+                    "This is a static musl build, which can't load libnvidia-ml; \
+                     use one built with `cargo build-musl-nvml` for GPU metrics"
+                );
+            }
+            Sampler::without_nvml()
         }
+        Err(e) => return Err(e.into()),
+    };
+    if args.profiling || args.backend == Backend::Dcgm {
+        log::warning!(
+            "Profiling metrics are enabled; they add overhead to workloads and may \
+             conflict with profilers like Nsight Compute"
+        );
+    }
+    match args.backend {
+        Backend::Dcgm => sampler.set_dcgm(Some(Dcgm::connect(args.dcgm_host.as_deref())?)),
+        Backend::Nvml => sampler.set_profiling(args.profiling),
+    }
+
+    // Set up signal handlers for shutdown, pause/resume, immediate samples and reloads
+    let controls = Controls::listen(running.clone())?;
+
+    // Samples are written on a separate thread so slow consumers can't delay sampling
+    let sink_options = SinkOptions {
+        spool_dir: args.spool_dir.clone(),
+        spool_max_bytes: args.spool_max_size,
+        rotation: RotationOptions {
+            max_bytes: args.rotate_size,
+            max_age: args.rotate_every,
+            compression: args.compress,
+            retain: args.retain,
+        },
+        format: args.format,
+        status_thresholds: args.status_thresholds.unwrap_or_default(),
+        watch: args.watch,
+        #[cfg(feature = "net")]
+        tls: tls::ClientOptions {
+            ca: args.sink_ca.clone(),
+            cert: args.sink_cert.clone(),
+            key: args.sink_key.clone(),
+        },
+        #[cfg(feature = "net")]
+        token: args
+            .sink_token_file
+            .as_deref()
+            .map(tls::read_token)
+            .transpose()?,
+        #[cfg(not(feature = "net"))]
+        token: None,
+        encoding: args.encoding,
+        units: args.units,
+        stdout_backpressure: args.stdout_backpressure,
+    };
+    #[cfg(feature = "net")]
+    if let Some(url) = &args.otlp_endpoint {
+        let (tls, target) = match url.split_once("://") {
+            Some(("http", target)) => (None, target),
+            Some(("https", target)) => {
+                let tls = tls::Connector::new(&sink_options.tls)
+                    .map_err(|e| format!("{}: invalid TLS settings: {}", url, e))?;
+                (Some(tls), target)
+            }
+            _ => return Err(format!("unsupported OTLP endpoint: {:?}", url).into()),
+        };
+        otel::init(target, tls)?;
+    }
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let periods = sampler.utilization_periods().unwrap_or_default();
+    let min_interval = periods
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+        .filter(|_| !args.no_min_interval);
+    let mut interval = effective_interval(
+        Duration::from_secs_f64(config.interval.unwrap_or(args.interval)),
+        min_interval,
+    );
+    if let Some(longest) = periods.iter().flatten().max() {
+        if interval > *longest * 2 {
+            log::info!(
+                "gpu.{{i}}.gpu covers the driver's last {:.0}ms of each {:.3}s interval; \
+                 _gpu.{{i}}.gpuMean covers all of it",
+                longest.as_secs_f64() * 1000.0,
+                interval.as_secs_f64()
+            );
+        }
+    }
+    // The command of `symon run` takes precedence over configured pids
+    let mut run_pid = None;
+    sampler.set_pid(config.pid.unwrap_or(args.pid));
+    sampler.set_pod_attribution(args.k8s);
+    sampler.set_user_attribution(args.users || args.billing);
+    sampler.set_process_list(args.processes);
+    #[cfg(not(feature = "processes"))]
+    if args.users || args.billing || args.processes.is_some() {
+        log::warning!("Process users and command lines require the `processes` feature");
+    }
+    if let Some(path) = &args.power_policy {
+        sampler.set_power_policy(Some(PowerPolicy::load(path)?));
+    }
+    if let Some(path) = &args.fan_curve {
+        sampler.set_fan_curve(Some(FanCurve::load(path)?));
+    }
+    let mut specs = config.sinks.unwrap_or_else(|| sink_specs(args));
+    let mut writer = SampleWriter::spawn(build_sinks(&specs, &sink_options)?, args.queue_size)?;
+    let mut tenants = config.tenants.unwrap_or_default();
+    check_tenants(args, sampler.pid(), &tenants);
+    let mut tenant_writers = spawn_tenant_writers(&tenants, &sink_options, args.queue_size)?;
+    match sampler.devices_record() {
+        Ok(record) => {
+            writer.submit(record);
+        }
+        Err(e) if e.is_nvml_missing() => {}
+        Err(e) => log::warning!("Error describing GPUs: {}", e),
+    }
+    if args.topology {
+        match sampler.topology_record() {
+            Ok(record) => {
+                writer.submit(record);
+            }
+            Err(e) => log::warning!("Error querying GPU topology: {}", e),
+        }
+    }
+    let mut state_file = args.state_file.as_deref().map(StateFile::new);
+    let mut saved_state = state_file.as_ref().map(StateFile::load).unwrap_or_default();
+    let mut counter_totals = state_file.as_ref().map(|_| {
+        let mut totals = CounterTotals::new();
+        totals.restore(std::mem::take(&mut saved_state.counters));
+        totals
     });
+    let mut billing = args.billing.then(|| {
+        let mut billing = Billing::new(args.billing_period, interval, args.billing_label.clone());
+        billing.set_pid(config.pid.unwrap_or(args.pid));
+        if let Some(state) = saved_state.billing.take() {
+            billing.restore(state);
+        }
+        billing
+    });
+    let mut agent_monitor = AgentMonitor::new();
+    let mut budget = overhead_budget(args, interval, &tenants);
+    #[cfg(feature = "net")]
+    let bmc = match &args.bmc {
+        Some(spec) => {
+            let credentials = args
+                .bmc_credentials_file
+                .as_deref()
+                .map(tls::read_token)
+                .transpose()?;
+            let tls = || {
+                tls::Connector::new(&tls::ClientOptions {
+                    ca: args.bmc_ca.clone(),
+                    ..tls::ClientOptions::default()
+                })
+            };
+            let source = BmcSource::parse(spec, tls, credentials)?;
+            Some(BmcCollector::spawn(source, args.bmc_interval)?)
+        }
+        None => None,
+    };
+    let mut counter_rates = CounterRates::new(args.tag_types);
+    let mut smoother = args.smooth_half_life.map(Smoother::new);
+    let mut run_report = args
+        .report_on_exit
+        .then(|| Report::with_thresholds(args.utilization_thresholds.clone()));
+    let mut residency = args
+        .residency_counters
+        .then(|| Residency::new(args.utilization_thresholds.clone()));
+    let mut idle = args
+        .idle_after
+        .map(|after| IdleDetector::new(after, args.idle_utilization));
+    // Webhooks post on their own threads, so a slow endpoint can't stall sampling
+    let idle_webhook = args
+        .idle_webhook
+        .as_ref()
+        .map(|spec| {
+            build_sinks(std::slice::from_ref(spec), &sink_options)
+                .and_then(|sinks| Ok(SampleWriter::spawn(sinks, args.queue_size)?))
+        })
+        .transpose()?;
+    let mut ecc_advisor = args.ecc_advisor.then(EccAdvisor::new);
+    let mut throttle = args.throttle_events.map(ThrottleClassifier::new);
+    let mut baseline = match &args.baseline {
+        Some(name) => {
+            let dir = args
+                .baseline_dir
+                .clone()
+                .unwrap_or_else(baseline::default_baseline_dir);
+            let baseline = Baseline::load(&dir, name)?;
+            Some(BaselineMonitor::new(
+                baseline,
+                args.baseline_window,
+                args.baseline_tolerance,
+            ))
+        }
+        None => None,
+    };
+    let ecc_advice_webhook = args
+        .ecc_advice_webhook
+        .as_ref()
+        .map(|spec| {
+            build_sinks(std::slice::from_ref(spec), &sink_options)
+                .and_then(|sinks| Ok(SampleWriter::spawn(sinks, args.queue_size)?))
+        })
+        .transpose()?;
+    // Recent samples and health are only tracked if something can read them
+    #[cfg(feature = "net")]
+    let health = match (&args.http_listen, &args.health_file) {
+        (None, None) => None,
+        _ => Some(Health::shared(interval)),
+    };
+    #[cfg(not(feature = "net"))]
+    let health = args.health_file.as_ref().map(|_| Health::shared(interval));
+    #[cfg(feature = "net")]
+    let history = match (&args.http_listen, &health) {
+        (Some(addr), Some(health)) => {
+            let history = History::shared(args.history_window);
+            http::spawn(
+                addr,
+                HttpState {
+                    history: history.clone(),
+                    health: health.clone(),
+                    tls: match (&args.http_cert, &args.http_key) {
+                        (Some(cert), Some(key)) => Some(Acceptor::new(&tls::ServerOptions {
+                            cert: cert.clone(),
+                            key: key.clone(),
+                            client_ca: args.http_client_ca.clone(),
+                        })?),
+                        _ => None,
+                    },
+                    token: args
+                        .http_token_file
+                        .as_deref()
+                        .map(tls::read_token)
+                        .transpose()?,
+                    control: match &args.control_token_file {
+                        Some(path) => Some(ControlAccess {
+                            token: tls::read_token(path)?,
+                            allowed: args.control_allow.clone(),
+                            sender: controls.sender(),
+                        }),
+                        None => None,
+                    },
+                },
+            )?;
+            Some(history)
+        }
+        _ => None,
+    };
+    let mut change_filter = match args.emit {
+        EmitMode::Full => None,
+        EmitMode::Changed => Some(ChangeFilter::new(args.emit_tolerance)),
+    };
+
+    // Everything that may need root is set up; report what remains possible
+    // after giving it up
+    #[cfg(unix)]
+    match &args.user {
+        Some(user) if privileges::is_root() => {
+            privileges::drop_to(user)
+                .map_err(|e| format!("failed to switch to user {:?}: {}", user, e))?;
+            log::info!("Dropped root privileges, running as {}", user);
+        }
+        None if privileges::is_root() => {
+            log::info!("Running as root; --user drops privileges after startup")
+        }
+        _ => {}
+    }
+    let capabilities = CapabilityReport::probe(&mut sampler);
+    for capability in capabilities.unavailable() {
+        log::info!("Missing privileges for {}", capability.used_by);
+    }
+    writer.submit(capabilities.to_record(sampler.now()));
+
+    // Startup is complete; let systemd know when running as a Type=notify unit
+    if let Err(e) = notifier.ready() {
+        log::warning!("Error notifying systemd: {}", e);
+    }
+
+    if let Some(path) = &args.marker_file {
+        marker::watch(path.clone(), controls.sender())?;
+    }
+    let runner = match &args.command {
+        Some(Command::Run {
+            warm_up,
+            cool_down,
+            command,
+        }) => Some(Runner::spawn(
+            command.clone(),
+            *warm_up,
+            *cool_down,
+            controls.sender(),
+            running.clone(),
+        )?),
+        _ => None,
+    };
+
+    // Every recording marks where it starts, with what the driver supports.
+    // Bounded recordings also mark where they stop
+    let deadline = args.duration.map(|duration| Instant::now() + duration);
+    let bounded = deadline.is_some() || args.active_window.is_some();
+    let in_window = || {
+        args.active_window
+            .is_none_or(|window| window.contains(SystemTime::now()))
+    };
+    let mut active = in_window();
+    if active {
+        let mut record = schedule::run_record("run_start", "start", sampler.now());
+        match sampler.features() {
+            Ok(features) => features.add_to(&mut record),
+            Err(e) if e.is_nvml_missing() => {}
+            Err(e) => log::error!("Error reading driver features: {}", e),
+        }
+        match ClockSync::query() {
+            Ok(clock) => clock.add_to(&mut record),
+            Err(e) => log::warning!("Error reading clock synchronization state: {}", e),
+        }
+        writer.submit(record);
+    }
+    let mut end_reason = "shutdown";
+    let mut gaps = GapTracker::new(interval);
+    let clock_sync = match args.clock_sync.then(ClockMonitor::spawn) {
+        Some(Ok(monitor)) => Some(monitor),
+        Some(Err(e)) => {
+            log::warning!(
+                "Not adding the clock synchronization state to samples: {}",
+                e
+            );
+            None
+        }
+        None => None,
+    };
 
     // Main sampling loop. Will run until the parent process is no longer alive or a signal is received.
+    let mut paused = false;
+    let mut next_sample = Instant::now();
     while running.load(Ordering::Relaxed) {
-        let sampling_start = Instant::now();
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs_f64();
-
-        // Sample GPU metrics
-        let mut metrics = Metrics::new();
-        if let Err(e) = nvidia_gpu.sample_metrics(&mut metrics, args.pid) {
-            sentry::capture_error(&e);
+        let wake = deadline.map_or(next_sample, |deadline| deadline.min(next_sample));
+        let control = controls.wait(wake.saturating_duration_since(Instant::now()));
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            end_reason = "duration";
+            break;
+        }
+        if active != in_window() {
+            active = !active;
+            let event = if active { "run_start" } else { "run_end" };
+            writer.submit(schedule::run_record(event, "window", sampler.now()));
+            gaps.reset();
+            log::info!("Recording {}", if active { "started" } else { "stopped" });
         }
+        let take_sample = match control {
+            Some(Control::Terminate) => break,
+            Some(Control::TogglePause) => {
+                paused = !paused;
+                if paused {
+                    gaps.paused();
+                }
+                log::info!("Sampling {}", if paused { "paused" } else { "resumed" });
+                false
+            }
+            Some(Control::SampleNow) => true,
+            Some(Control::Pause | Control::Resume) => {
+                paused = matches!(control, Some(Control::Pause));
+                if paused {
+                    gaps.paused();
+                }
+                log::info!("Sampling {}", if paused { "paused" } else { "resumed" });
+                false
+            }
+            Some(Control::SetInterval(new_interval)) => {
+                interval = effective_interval(new_interval, min_interval);
+                gaps.set_interval(interval);
+                if let Some(budget) = budget.as_mut() {
+                    budget.set_interval(interval);
+                }
+                if let Some(billing) = billing.as_mut() {
+                    billing.set_interval(interval);
+                }
+                if let Some(Ok(mut health)) = health.as_ref().map(|h| h.lock()) {
+                    health.set_interval(interval);
+                }
+                next_sample = next_sample.min(Instant::now() + interval);
+                log::info!("Sampling every {:.3}s", interval.as_secs_f64());
+                false
+            }
+            Some(Control::SetPid(pid)) => {
+                run_pid = Some(pid);
+                sampler.set_pid(pid);
+                if let Some(billing) = billing.as_mut() {
+                    billing.set_pid(pid);
+                }
+                false
+            }
+            Some(Control::Marker(name)) => {
+                let record = marker::record(&name, sampler.now());
+                if let Some(report) = run_report.as_mut() {
+                    report.add(&record);
+                }
+                writer.submit(record);
+                false
+            }
+            Some(Control::SetPowerLimit { gpu, watts }) => {
+                let settings = DeviceSettings {
+                    power_limit_watts: Some(watts),
+                    ..DeviceSettings::default()
+                };
+                match sampler.apply_settings(settings, Some(vec![gpu])) {
+                    Ok(failures) if failures.is_empty() => {
+                        log::info!("Set GPU {} power limit to {:.0} W", gpu, watts)
+                    }
+                    Ok(failures) => {
+                        for failure in failures {
+                            log::error!("Error setting power limit: {}", failure);
+                        }
+                    }
+                    Err(e) => log::error!("Error setting power limit: {}", e),
+                }
+                false
+            }
+            Some(Control::Reload) => {
+                if let Some(path) = &args.power_policy {
+                    match PowerPolicy::load(path) {
+                        Ok(policy) => {
+                            sampler.set_power_policy(Some(policy));
+                            log::info!("Reloaded {}", path.display());
+                        }
+                        Err(e) => log::error!("Error reloading power policy: {}", e),
+                    }
+                }
+                if let Some(path) = &args.fan_curve {
+                    match FanCurve::load(path) {
+                        Ok(curve) => {
+                            sampler.set_fan_curve(Some(curve));
+                            log::info!("Reloaded {}", path.display());
+                        }
+                        Err(e) => log::error!("Error reloading fan curve: {}", e),
+                    }
+                }
+                let loaded = args.config.as_ref().map(|path| (path, Config::load(path)));
+                if loaded.is_none() && args.power_policy.is_none() && args.fan_curve.is_none() {
+                    log::warning!("Received SIGHUP but no --config file was given");
+                }
+                match loaded {
+                    Some((path, Ok(config))) => {
+                        interval = effective_interval(
+                            Duration::from_secs_f64(config.interval.unwrap_or(args.interval)),
+                            min_interval,
+                        );
+                        gaps.set_interval(interval);
+                        if let Some(budget) = budget.as_mut() {
+                            budget.set_interval(interval);
+                        }
+                        let pid = run_pid.or(config.pid).unwrap_or(args.pid);
+                        sampler.set_pid(pid);
+                        if let Some(billing) = billing.as_mut() {
+                            billing.set_pid(pid);
+                            billing.set_interval(interval);
+                        }
+                        let new_specs = config.sinks.unwrap_or_else(|| sink_specs(args));
+                        if new_specs != specs {
+                            match build_sinks(&new_specs, &sink_options)
+                                .and_then(|sinks| Ok(SampleWriter::spawn(sinks, args.queue_size)?))
+                            {
+                                Ok(new_writer) => {
+                                    // Spools are only opened on the first write, so the
+                                    // new sinks take them over once the old ones are closed
+                                    std::mem::replace(&mut writer, new_writer).close();
+                                    specs = new_specs;
+                                }
+                                Err(e) => log::error!("Error reconfiguring sinks: {}", e),
+                            }
+                        }
+                        let new_tenants = config.tenants.unwrap_or_default();
+                        if new_tenants != tenants {
+                            check_tenants(args, sampler.pid(), &new_tenants);
+                            match spawn_tenant_writers(&new_tenants, &sink_options, args.queue_size)
+                            {
+                                Ok(new_writers) => {
+                                    for old in std::mem::replace(&mut tenant_writers, new_writers) {
+                                        old.close();
+                                    }
+                                    tenants = new_tenants;
+                                    if let Some(old) = budget.take() {
+                                        for &collector in old.disabled() {
+                                            set_collector(&mut sampler, collector, Some(args));
+                                        }
+                                        budget = overhead_budget(args, interval, &tenants);
+                                    }
+                                }
+                                Err(e) => log::error!("Error reconfiguring tenant sinks: {}", e),
+                            }
+                        }
+                        next_sample = next_sample.min(Instant::now() + interval);
+                        if let Some(Ok(mut health)) = health.as_ref().map(|h| h.lock()) {
+                            health.set_interval(interval);
+                        }
+                        log::info!("Reloaded {}", path.display());
+                    }
+                    Some((_, Err(e))) => log::error!("Error reloading config: {}", e),
+                    None => {}
+                }
+                false
+            }
+            None => {
+                next_sample = (next_sample + interval).max(Instant::now());
+                !paused && active
+            }
+        };
 
-        // Add timestamp to metrics
-        metrics.add_timestamp(timestamp);
+        if take_sample {
+            // Sample GPU metrics. If NVML hangs, emit a degraded record instead
+            let mut metrics = writer.recycled();
+            if let Err(e) = sampler.sample_into(&mut metrics) {
+                error::report(&e);
+            }
 
-        // Convert metrics to JSON and print to stdout for collection
-        if let Err(e) = metrics.print_json() {
-            eprintln!("Error printing metrics: {}", e);
-            sentry::capture_error(&e);
+            // Add self-telemetry and hand the sample over for output
+            agent_monitor.sample(&mut metrics);
+            match budget.as_mut().and_then(|budget| budget.check(&metrics)) {
+                Some(Adjustment::Interval(stretched)) => {
+                    interval = stretched;
+                    gaps.set_interval(interval);
+                    if let Some(billing) = billing.as_mut() {
+                        billing.set_interval(interval);
+                    }
+                    if let Some(Ok(mut health)) = health.as_ref().map(|h| h.lock()) {
+                        health.set_interval(interval);
+                    }
+                    next_sample = next_sample.min(Instant::now() + interval);
+                }
+                Some(Adjustment::Disable(collector)) => {
+                    set_collector(&mut sampler, collector, None)
+                }
+                Some(Adjustment::Enable(collector)) => {
+                    set_collector(&mut sampler, collector, Some(args))
+                }
+                None => {}
+            }
+            if let Some(clock_sync) = &clock_sync {
+                clock_sync.add(&mut metrics);
+            }
+            #[cfg(feature = "net")]
+            if let Some(bmc) = &bmc {
+                bmc.add(&mut metrics);
+            }
+            writer.stats().add_metrics(&mut metrics);
+            if let Some(residency) = residency.as_mut() {
+                residency.add(&metrics);
+                residency.add_counters(&mut metrics);
+            }
+            if let Some(totals) = counter_totals.as_mut() {
+                totals.apply(&mut metrics);
+            }
+            counter_rates.apply(&mut metrics);
+            if let Some(smoother) = smoother.as_mut() {
+                smoother.apply(&mut metrics);
+            }
+            let billing_record = billing.as_mut().and_then(|billing| billing.add(&metrics));
+            if let Some(state_file) = state_file.as_mut() {
+                if billing_record.is_some() || state_file.save_due() {
+                    save_state(state_file, counter_totals.as_ref(), billing.as_ref());
+                }
+            }
+            if let Some(record) = billing_record {
+                writer.submit(record);
+            }
+            if let Some(report) = run_report.as_mut() {
+                report.add(&metrics);
+            }
+            for event in sampler.take_events() {
+                writer.submit(event);
+            }
+            for event in idle
+                .as_mut()
+                .map(|idle| idle.check(&metrics))
+                .unwrap_or_default()
+            {
+                if let Some(gpu) = event.get("gpu") {
+                    log::info!("GPU {} is idle", gpu);
+                }
+                if let Some(webhook) = &idle_webhook {
+                    let mut copy = Metrics::new();
+                    copy.copy_from(&event);
+                    if !webhook.submit(copy) {
+                        log::warning!("Dropped an idle event: the idle webhook is falling behind");
+                    }
+                }
+                writer.submit(event);
+            }
+            for event in ecc_advisor
+                .as_mut()
+                .map(|advisor| advisor.check(&metrics))
+                .unwrap_or_default()
+            {
+                if let Some(message) = event.get("message").and_then(|v| v.as_str()) {
+                    match event.get("severity").and_then(|v| v.as_str()) {
+                        Some("critical") => log::error!("{}", message),
+                        Some("warning") => log::warning!("{}", message),
+                        _ => log::info!("{}", message),
+                    }
+                }
+                if let Some(webhook) = &ecc_advice_webhook {
+                    let mut copy = Metrics::new();
+                    copy.copy_from(&event);
+                    if !webhook.submit(copy) {
+                        log::warning!(
+                            "Dropped ECC advice: the ECC advice webhook is falling behind"
+                        );
+                    }
+                }
+                writer.submit(event);
+            }
+            for event in throttle
+                .as_mut()
+                .map(|throttle| throttle.check(&metrics))
+                .unwrap_or_default()
+            {
+                writer.submit(event);
+            }
+            for event in baseline
+                .as_mut()
+                .map(|baseline| baseline.check(&metrics))
+                .unwrap_or_default()
+            {
+                if let (Some(name), Some(metric)) = (event.get("_event"), event.get("metric")) {
+                    log::warning!("{} of {}", name, metric);
+                }
+                writer.submit(event);
+            }
+            #[cfg(feature = "net")]
+            if let Some(history) = &history {
+                if let Ok(mut history) = history.lock() {
+                    history.push(&metrics);
+                }
+            }
+            if let Some(health) = &health {
+                if let Ok(mut health) = health.lock() {
+                    health.update(&metrics);
+                    if let Some(path) = &args.health_file {
+                        if let Err(e) = health.ready().write_file(path) {
+                            log::warning!("Error writing {}: {}", path.display(), e);
+                        }
+                    }
+                }
+            }
+            // Route each tenant's share before unchanged metrics are filtered out
+            for (tenant, tenant_writer) in tenants.iter().zip(&tenant_writers) {
+                if let Some(record) = tenant.record(&metrics, sampler.pid()) {
+                    tenant_writer.submit(record);
+                }
+            }
+            if let Some(filter) = change_filter.as_mut() {
+                filter.apply(&mut metrics);
+            }
+            // Mark samples missing since the last one, dropped or never taken
+            let time = metrics.time().unwrap_or_else(|| sampler.now());
+            if let Some(gap) = gaps.check(time) {
+                if writer.submit(gap) {
+                    gaps.delivered(time);
+                }
+            }
+            if writer.submit(metrics) {
+                gaps.delivered(time);
+            } else {
+                gaps.dropped();
+            }
+        }
+
+        // Keep the systemd watchdog fed as long as the loop makes progress, even while paused
+        if let Err(e) = notifier.watchdog() {
+            log::warning!("Error pinging systemd watchdog: {}", e);
         }
 
         // Check if parent process is still alive and break loop if not
-        if getppid() != nix::unistd::Pid::from_raw(args.ppid) {
+        if args.ppid != 0 && parent_exited(args.ppid) {
             break;
         }
+    }
 
-        // Sleep to maintain requested sampling interval
-        let loop_duration = sampling_start.elapsed();
-        let sleep_duration = Duration::from_secs_f64(args.interval);
-        if loop_duration < sleep_duration {
-            thread::sleep(sleep_duration - loop_duration);
-        }
+    let _ = notifier.stopping();
+    let exit_code = runner.map_or(0, Runner::finish);
+
+    if bounded && active {
+        writer.submit(schedule::run_record("run_end", end_reason, sampler.now()));
+    }
+
+    if let Some(state_file) = state_file.as_mut() {
+        save_state(state_file, counter_totals.as_ref(), billing.as_ref());
+    }
+
+    for event in throttle
+        .as_mut()
+        .map(|throttle| throttle.finish(sampler.now()))
+        .unwrap_or_default()
+    {
+        writer.submit(event);
+    }
+
+    if let Some(report) = run_report {
+        eprint!("{}", report);
+    }
+
+    // Write out pending samples
+    writer.close();
+    for tenant_writer in tenant_writers {
+        tenant_writer.close();
+    }
+    otel::shutdown();
+    if let Some(webhook) = idle_webhook {
+        webhook.close();
+    }
+    if let Some(webhook) = ecc_advice_webhook {
+        webhook.close();
     }
 
     // Graceful shutdown of NVML
-    if let Err(e) = nvidia_gpu.shutdown() {
-        sentry::capture_error(&e);
-        eprintln!("Error shutting down NVML: {}", e);
+    if let Err(e) = sampler.shutdown() {
+        error::report(&e);
+        log::error!("Error shutting down NVML: {}", e);
     }
 
+    Ok(exit_code)
+}
+
+/// The overhead budget of `--max-overhead`, if given. Tenants' records are made
+/// of process lists and per-pod and per-user attribution, so those stay on
+/// while any tenants are configured.
+fn overhead_budget(args: &Args, interval: Duration, tenants: &[Tenant]) -> Option<OverheadBudget> {
+    let max_percent = args.max_overhead?;
+    let mut collectors = Vec::new();
+    if args.profiling && args.backend == Backend::Nvml {
+        collectors.push(Collector::Profiling);
+    }
+    if tenants.is_empty() {
+        if args.processes.is_some() {
+            collectors.push(Collector::ProcessList);
+        }
+        if args.k8s {
+            collectors.push(Collector::Pods);
+        }
+        // Billing needs per-user attribution
+        if args.users && !args.billing {
+            collectors.push(Collector::Users);
+        }
+    }
+    Some(OverheadBudget::new(max_percent, interval, collectors))
+}
+
+/// Turn a collector the overhead budget manages off, or back on as `args` configure it.
+fn set_collector(sampler: &mut Sampler, collector: Collector, args: Option<&Args>) {
+    match collector {
+        Collector::Profiling => sampler.set_profiling(args.is_some()),
+        Collector::ProcessList => sampler.set_process_list(args.and_then(|args| args.processes)),
+        Collector::Pods => sampler.set_pod_attribution(args.is_some()),
+        Collector::Users => sampler.set_user_attribution(args.is_some()),
+    }
+}
+
+/// `interval`, raised to `min_interval` if shorter: sampling faster than the
+/// driver updates utilization only repeats its readings. Every raise is logged,
+/// whether the interval comes from `--interval`, the config file or a control
+/// request.
+fn effective_interval(interval: Duration, min_interval: Option<Duration>) -> Duration {
+    match min_interval {
+        Some(min) if interval < min => {
+            log::warning!(
+                "Interval {:.3}s is shorter than the driver's utilization sample period, \
+                 sampling every {:.3}s instead (see --no-min-interval)",
+                interval.as_secs_f64(),
+                min.as_secs_f64()
+            );
+            min
+        }
+        _ => interval,
+    }
+}
+
+fn save_state(
+    state_file: &mut StateFile,
+    counter_totals: Option<&CounterTotals>,
+    billing: Option<&Billing>,
+) {
+    let state = State {
+        billing: billing.map(Billing::state),
+        counters: counter_totals.map(CounterTotals::state).unwrap_or_default(),
+    };
+    if let Err(e) = state_file.save(&state) {
+        log::warning!("Error saving state: {}", e);
+    }
+}
+
+#[cfg(feature = "tools")]
+/// Show the changes `settings` would make, confirm them and apply them.
+fn set(
+    settings: DeviceSettings,
+    gpus: Option<Vec<u32>>,
+    dry_run: bool,
+    yes: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if settings.is_empty() {
+        return Err("nothing to set; see `symon set --help`".into());
+    }
+    let mut sampler = Sampler::new()?;
+    let result = (|| -> Result<Vec<_>, Box<dyn std::error::Error>> {
+        for line in sampler.plan_settings(settings.clone(), gpus.clone())? {
+            println!("{}", line);
+        }
+        if dry_run || !(yes || confirm("Apply these changes?")?) {
+            return Ok(Vec::new());
+        }
+        Ok(sampler.apply_settings(settings, gpus)?)
+    })();
+    sampler.shutdown()?;
+
+    let failures = result?;
+    for failure in &failures {
+        eprintln!("Error: {}", failure);
+    }
+    if !failures.is_empty() {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+#[cfg(feature = "tools")]
+/// Reserve `count` GPUs and run `command` on them, returning its exit code.
+fn reserve(
+    count: u32,
+    options: &PickOptions,
+    lock_dir: Option<PathBuf>,
+    command: &[OsString],
+) -> Result<i32, Box<dyn std::error::Error>> {
+    let mut sampler = Sampler::new()?;
+    let sample = sampler.sample();
+    let devices = sampler.devices();
+    // NVML isn't needed while the command runs
+    sampler.shutdown()?;
+    let candidates = pick::pick(&sample?, &devices?, options);
+
+    let dir = lock_dir.unwrap_or_else(reserve::default_lock_dir);
+    let description = command
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let reservation = Reservation::acquire(&dir, &candidates, count as usize, &description)?;
+    log::info!("Reserved GPUs {:?}", reservation.indices());
+
+    let Some((program, args)) = command.split_first() else {
+        return Ok(run::NOT_STARTED);
+    };
+    #[cfg(unix)]
+    reservation.inherit()?;
+    let status = process::Command::new(program)
+        .args(args)
+        .env("CUDA_VISIBLE_DEVICES", reservation.cuda_visible_devices())
+        .status();
+    drop(reservation);
+    match status {
+        Ok(status) => Ok(run::exit_code(status)),
+        Err(e) => {
+            log::error!("Error starting {}: {}", program.to_string_lossy(), e);
+            Ok(run::NOT_STARTED)
+        }
+    }
+}
+
+/// The GPUs a subcommand that puts work on them should use: `gpus`, or every
+/// GPU if empty. Fails if any doesn't exist.
+#[cfg(any(feature = "bench", feature = "stress"))]
+fn gpu_selection(sample: &Metrics, gpus: &[u32]) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+    let count = sample
+        .get("_gpu.count")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0) as u32;
+    if count == 0 {
+        return Err("no GPUs found".into());
+    }
+    if let Some(&gpu) = gpus.iter().find(|&&gpu| gpu >= count) {
+        return Err(format!("no GPU {}; the node has {}", gpu, count).into());
+    }
+    if gpus.is_empty() {
+        Ok((0..count).collect())
+    } else {
+        Ok(gpus.to_vec())
+    }
+}
+
+/// `gpus` with their PCI bus IDs, by which CUDA finds the same devices.
+#[cfg(any(feature = "bench", feature = "stress"))]
+fn with_bus_ids(
+    sampler: &mut Sampler,
+    gpus: &[u32],
+) -> Result<Vec<(u32, String)>, Box<dyn std::error::Error>> {
+    let topology = sampler.topology()?;
+    gpus.iter()
+        .map(|&gpu| {
+            let bus_id = topology
+                .gpus
+                .iter()
+                .find(|topology| topology.index == gpu)
+                .and_then(|topology| topology.bus_id.clone())
+                .ok_or_else(|| format!("no PCI bus ID for GPU {}", gpu))?;
+            Ok((gpu, bus_id))
+        })
+        .collect()
+}
+
+/// Load `gpus`, or every GPU if empty, for `duration` while sampling every
+/// `interval`, and judge them against `limits`.
+#[cfg(feature = "stress")]
+fn stress(
+    sampler: &mut Sampler,
+    gpus: &[u32],
+    limits: StressLimits,
+    duration: Duration,
+    interval: Duration,
+) -> Result<StressReport, Box<dyn std::error::Error>> {
+    let baseline = sampler.sample()?;
+    let gpus = gpu_selection(&baseline, gpus)?;
+
+    let mut report = StressReport::new(&gpus, limits);
+    report.observe_errors(&baseline);
+    log::info!("Loading GPUs {:?} for {:?}", gpus, duration);
+    let burner = Burner::start(&with_bus_ids(sampler, &gpus)?)?;
+    let deadline = Instant::now() + duration;
+    let mut next = Instant::now();
+    while next < deadline {
+        match sampler.sample() {
+            Ok(sample) => report.observe(&sample),
+            Err(e) => log::warning!("Error sampling GPU metrics: {}", e),
+        }
+        next += interval;
+        if let Some(pause) = next.checked_duration_since(Instant::now()) {
+            std::thread::sleep(pause);
+        }
+    }
+    report.set_load_failures(burner.stop());
+    // Errors the load caused may only be reported after it ends
+    if let Ok(sample) = sampler.sample() {
+        report.observe_errors(&sample);
+    }
+    Ok(report)
+}
+
+#[cfg(feature = "tools")]
+/// Sample every `interval` until `target` is idle, returning the idle GPU, or
+/// `None` once `timeout` passes.
+fn wait_for_idle(
+    sampler: &mut Sampler,
+    target: GpuTarget,
+    utilization: f64,
+    timeout: Option<Duration>,
+    interval: Duration,
+) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut warned = false;
+    loop {
+        match sampler.sample() {
+            Ok(sample) => {
+                let count = sample.get("_gpu.count").and_then(|v| v.as_u64());
+                if let (Some(gpu), Some(count)) = (target, count) {
+                    if u64::from(gpu) >= count {
+                        return Err(format!("no GPU {}; the node has {}", gpu, count).into());
+                    }
+                }
+                // GPUs whose processes can't be listed never count as idle
+                if !warned {
+                    for gpu in idle::gpus_without_process_counts(&sample)
+                        .into_iter()
+                        .filter(|&gpu| target.is_none_or(|target| target == gpu))
+                    {
+                        warned = true;
+                        log::warning!(
+                            "Can't list the processes on GPU {}, so it won't count as idle",
+                            gpu
+                        );
+                    }
+                }
+                let idle_gpu = idle::idle_gpus(&sample, utilization)
+                    .into_iter()
+                    .find(|&gpu| target.is_none_or(|target| target == gpu));
+                if idle_gpu.is_some() {
+                    return Ok(idle_gpu);
+                }
+            }
+            Err(e) => log::warning!("Error sampling GPU metrics: {}", e),
+        }
+        let pause = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) => interval.min(remaining),
+                None => return Ok(None),
+            },
+            None => interval,
+        };
+        std::thread::sleep(pause);
+    }
+}
+
+#[cfg(feature = "tools")]
+/// Ask a yes/no question on the terminal; anything but yes declines.
+fn confirm(question: &str) -> io::Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Err(io::Error::other(
+            "refusing to apply changes without confirmation; pass --yes",
+        ));
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(feature = "tools")]
+/// Print a summary of a recorded trace.
+fn report(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    print!("{}", load_report(path)?);
+    Ok(())
+}
+
+#[cfg(feature = "tools")]
+/// Summarize a recorded trace.
+fn load_report(path: &Path) -> Result<Report, Box<dyn std::error::Error>> {
+    let mut reader = TraceReader::open(path)?;
+    let mut report = Report::new();
+    for metrics in &mut reader {
+        report.add(&metrics?);
+    }
+    if reader.skipped() > 0 {
+        eprintln!(
+            "Skipped {} malformed lines in {}",
+            reader.skipped(),
+            path.display()
+        );
+    }
+    Ok(report)
+}
+
+/// The files the agent reads and the directories it writes to, for `--sandbox`.
+/// The spool directory is created if needed, as it's the agent's own.
+#[cfg(target_os = "linux")]
+fn sandbox_options(args: &Args) -> Result<SandboxOptions, Box<dyn std::error::Error>> {
+    let mut specs = sink_specs(args);
+    if let Some(path) = &args.config {
+        let config = Config::load(path)?;
+        specs.extend(config.sinks.unwrap_or_default());
+        specs.extend(config.tenants.into_iter().flatten().flat_map(|t| t.sinks));
+    }
+    specs.extend(args.idle_webhook.iter().cloned());
+    specs.extend(args.ecc_advice_webhook.iter().cloned());
+    let mut writable: Vec<PathBuf> = specs
+        .iter()
+        .filter_map(|spec| sink::file_path(spec))
+        .chain(args.state_file.as_deref())
+        .chain(args.health_file.as_deref())
+        .chain(args.pidfile.as_deref())
+        .filter_map(|path| path.parent())
+        .map(|dir| {
+            if dir.as_os_str().is_empty() {
+                PathBuf::from(".")
+            } else {
+                dir.to_path_buf()
+            }
+        })
+        .collect();
+    writable.sort();
+    writable.dedup();
+    if let Some(dir) = &args.spool_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let baseline_dir = args.baseline.as_ref().map(|_| {
+        args.baseline_dir
+            .clone()
+            .unwrap_or_else(baseline::default_baseline_dir)
+    });
+    let mut readable: Vec<PathBuf> = [
+        &args.config,
+        &args.power_policy,
+        &args.fan_curve,
+        &args.marker_file,
+        &baseline_dir,
+    ]
+    .into_iter()
+    .flatten()
+    .cloned()
+    .collect();
+    #[cfg(feature = "net")]
+    readable.extend(
+        [
+            &args.sink_ca,
+            &args.sink_cert,
+            &args.sink_key,
+            &args.sink_token_file,
+            &args.http_cert,
+            &args.http_key,
+            &args.http_client_ca,
+            &args.http_token_file,
+            &args.control_token_file,
+            &args.bmc_ca,
+            &args.bmc_credentials_file,
+        ]
+        .into_iter()
+        .flatten()
+        .cloned(),
+    );
+    readable.sort();
+    readable.dedup();
+
+    #[cfg(feature = "net")]
+    let ipmi = args.bmc.as_deref() == Some("ipmi");
+    #[cfg(not(feature = "net"))]
+    let ipmi = false;
+    Ok(SandboxOptions {
+        readable,
+        writable,
+        spool: args.spool_dir.clone(),
+        exec: ipmi || args.clock_sync || matches!(args.command, Some(Command::Run { .. })),
+    })
+}
+
+/// Sink specs from `--sink` and `--out`, defaulting to stdout.
+fn sink_specs(args: &Args) -> Vec<String> {
+    let mut specs = args.sinks.clone();
+    if let Some(out) = &args.out {
+        specs.push(format!("file://{}", out.display()));
+    }
+    if specs.is_empty() {
+        specs.push("stdout".to_string());
+    }
+    specs
+}
+
+/// A writer for the sinks of each tenant, in the same order. Each tenant spools
+/// to its own subdirectory, as its sinks may share specs with the regular ones.
+fn spawn_tenant_writers(
+    tenants: &[Tenant],
+    options: &SinkOptions,
+    queue_size: usize,
+) -> Result<Vec<SampleWriter>, Box<dyn std::error::Error>> {
+    tenants
+        .iter()
+        .map(|tenant| {
+            let options = SinkOptions {
+                spool_dir: options
+                    .spool_dir
+                    .as_ref()
+                    .map(|dir| dir.join(format!("tenant-{}", tenant.name))),
+                ..options.clone()
+            };
+            Ok(SampleWriter::spawn(
+                build_sinks(&tenant.sinks, &options)?,
+                queue_size,
+            )?)
+        })
+        .collect()
+}
+
+/// Warn about tenants selecting processes by attribution that isn't enabled,
+/// as they would never get a share. `pid` is the monitored process.
+fn check_tenants(args: &Args, pid: i32, tenants: &[Tenant]) {
+    for tenant in tenants {
+        if !tenant.namespaces.is_empty() && !args.k8s {
+            log::warning!(
+                "Tenant {} selects namespaces, which needs --k8s",
+                tenant.name
+            );
+        }
+        if !tenant.users.is_empty() && !args.users && !args.billing && args.processes.is_none() {
+            log::warning!(
+                "Tenant {} selects users, which needs --users or --processes",
+                tenant.name
+            );
+        }
+        // Without process lists, only the monitored process can be matched
+        if args.processes.is_none() && tenant.pids.iter().any(|&p| p as i32 != pid) {
+            log::warning!(
+                "Tenant {} selects pids, which needs --processes; without it only \
+                 gpu.process.* of the monitored --pid is shared",
+                tenant.name
+            );
+        }
+    }
+}
+
+fn build_sinks(
+    specs: &[String],
+    options: &SinkOptions,
+) -> Result<Vec<Box<dyn Sink>>, Box<dyn std::error::Error>> {
+    Ok(specs
+        .iter()
+        .map(|spec| sink::from_spec(spec, options))
+        .collect::<Result<_, _>>()?)
+}
+
+/// Whether the process with pid `ppid`, which started us, is gone.
+#[cfg(unix)]
+fn parent_exited(ppid: i32) -> bool {
+    // Orphaned processes are re-parented, so a changed parent pid means it exited
+    nix::unistd::getppid() != nix::unistd::Pid::from_raw(ppid)
+}
+
+/// Whether the process with pid `ppid`, which started us, is gone.
+#[cfg(all(windows, feature = "processes"))]
+fn parent_exited(ppid: i32) -> bool {
+    let pid = sysinfo::Pid::from(ppid as usize);
+    let mut system = sysinfo::System::new();
+    system.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[pid]));
+    system.process(pid).is_none()
+}
+
+/// The parent isn't watched on Windows without the `processes` feature.
+#[cfg(all(windows, not(feature = "processes")))]
+fn parent_exited(_ppid: i32) -> bool {
+    false
+}
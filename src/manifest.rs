@@ -0,0 +1,293 @@
+use crate::dcgm::DCGM_METRICS;
+use crate::gpm::GPM_METRICS;
+use crate::gpu_nvidia::DEVICE_METRICS;
+use crate::metrics::MetricKind;
+use serde_json::{json, Value};
+
+/// Description of a metric symon writes.
+pub struct MetricInfo {
+    /// Name, with `{}` in place of the GPU, board or sink index.
+    pub name: &'static str,
+    /// Unit, or empty for counts, names and structured values.
+    pub unit: &'static str,
+    /// The NVML call or other source of the value.
+    pub source: &'static str,
+    pub description: &'static str,
+    /// When the metric is available, e.g. which GPUs or options; empty if always.
+    pub notes: &'static str,
+}
+
+/// Metrics of the node and the agent itself, and metrics derived from others.
+const OTHER_METRICS: &[MetricInfo] = &[
+    MetricInfo {
+        name: "_gpu.count",
+        unit: "",
+        source: "nvmlDeviceGetCount",
+        description: "Number of GPUs on the node",
+        notes: "",
+    },
+    MetricInfo {
+        name: "cuda_version",
+        unit: "",
+        source: "nvmlSystemGetCudaDriverVersion",
+        description: "Highest CUDA version the driver supports, e.g. 12.4",
+        notes: "",
+    },
+    MetricInfo {
+        name: "node.gpu.totalPowerWatts",
+        unit: "W",
+        source: "derived",
+        description: "Total power draw of the node's GPUs",
+        notes: "",
+    },
+    MetricInfo {
+        name: "node.gpu.meanUtil",
+        unit: "%",
+        source: "derived",
+        description: "Utilization averaged over the node's GPUs",
+        notes: "",
+    },
+    MetricInfo {
+        name: "node.gpu.totalMemoryUsedBytes",
+        unit: "bytes",
+        source: "derived",
+        description: "Device memory allocated across the node's GPUs",
+        notes: "",
+    },
+    MetricInfo {
+        name: "node.gpu.anyThrottling",
+        unit: "",
+        source: "derived",
+        description: "Whether any GPU's clocks are reduced for a reason other than being idle",
+        notes: "",
+    },
+    MetricInfo {
+        name: "node.powerWatts",
+        unit: "W",
+        source: "ipmitool dcmi power reading, Redfish Power",
+        description: "Wall power of the node, as read from its BMC",
+        notes: "With --bmc",
+    },
+    MetricInfo {
+        name: "node.inletTemp",
+        unit: "Celsius",
+        source: "ipmitool sdr, Redfish Thermal",
+        description: "Inlet air temperature of the node, as read from its BMC",
+        notes: "With --bmc, if the BMC has an inlet sensor",
+    },
+    MetricInfo {
+        name: "node.gpu.powerPercent",
+        unit: "%",
+        source: "derived",
+        description: "Power of the node's GPUs as a share of the node's wall power",
+        notes: "With --bmc",
+    },
+    MetricInfo {
+        name: "_board.{}.powerWatts",
+        unit: "W",
+        source: "nvmlDeviceGetPowerUsage",
+        description: "Total power draw of the GPUs sharing a board, by board ID",
+        notes: "Multi-GPU boards only; left out unless every GPU on the board has a reading",
+    },
+    MetricInfo {
+        name: "_board.{}.enforcedPowerLimitWatts",
+        unit: "W",
+        source: "nvmlDeviceGetEnforcedPowerLimit",
+        description: "Total enforced power limit of the GPUs sharing a board",
+        notes: "Multi-GPU boards only; left out unless every GPU on the board has a reading",
+    },
+    MetricInfo {
+        name: "_board.{}.temp",
+        unit: "Celsius",
+        source: "nvmlDeviceGetTemperature",
+        description: "Temperature of the hottest GPU on a board",
+        notes: "Multi-GPU boards only",
+    },
+    MetricInfo {
+        name: "_agent.cpuPercent",
+        unit: "%",
+        source: "agent",
+        description: "CPU time used by symon, as a share of one core",
+        notes: "",
+    },
+    MetricInfo {
+        name: "_agent.rssBytes",
+        unit: "bytes",
+        source: "agent",
+        description: "Resident memory of symon",
+        notes: "",
+    },
+    MetricInfo {
+        name: "_agent.queueDepth",
+        unit: "",
+        source: "agent",
+        description: "Samples waiting to be written",
+        notes: "",
+    },
+    MetricInfo {
+        name: "_agent.droppedSamples",
+        unit: "",
+        source: "agent",
+        description: "Samples dropped because the queue was full",
+        notes: "",
+    },
+    MetricInfo {
+        name: "_agent.sink.{}.latencyMs",
+        unit: "ms",
+        source: "agent",
+        description: "Time the sink took to write the previous sample",
+        notes: "",
+    },
+    MetricInfo {
+        name: "_agent.sink.{}.errors",
+        unit: "",
+        source: "agent",
+        description: "Failed writes to the sink",
+        notes: "",
+    },
+    MetricInfo {
+        name: "_agent.sink.{}.batchLatencyMs",
+        unit: "ms",
+        source: "agent",
+        description: "Time the previous batch took to send",
+        notes: "HTTP sinks only",
+    },
+    MetricInfo {
+        name: "_agent.sink.{}.pendingBatches",
+        unit: "",
+        source: "agent",
+        description: "Batches waiting to be sent",
+        notes: "HTTP sinks only",
+    },
+    MetricInfo {
+        name: "_agent.sink.{}.retries",
+        unit: "",
+        source: "agent",
+        description: "Batches sent again after a failure",
+        notes: "HTTP sinks only",
+    },
+    MetricInfo {
+        name: "_agent.sink.{}.droppedBatches",
+        unit: "",
+        source: "agent",
+        description: "Batches given up on",
+        notes: "HTTP sinks only",
+    },
+    MetricInfo {
+        name: "_agent.sink.{}.droppedSamples",
+        unit: "",
+        source: "agent",
+        description: "Samples given up on",
+        notes: "HTTP, UDP and stdout sinks only",
+    },
+    MetricInfo {
+        name: "_agent.sink.{}.pendingBytes",
+        unit: "bytes",
+        source: "agent",
+        description: "Output waiting for the reader of stdout",
+        notes: "stdout only",
+    },
+    MetricInfo {
+        name: "_agent.sink.{}.stalls",
+        unit: "",
+        source: "agent",
+        description: "Times the reader of stdout stopped keeping up",
+        notes: "stdout only",
+    },
+    MetricInfo {
+        name: "<counter>PerSecond",
+        unit: "1/s",
+        source: "derived",
+        description: "Rate of increase of each counter since the previous sample",
+        notes: "",
+    },
+    MetricInfo {
+        name: "gpu.{}.gpuSmoothed",
+        unit: "%",
+        source: "derived",
+        description: "Exponentially smoothed utilization",
+        notes: "With --smooth-half-life",
+    },
+    MetricInfo {
+        name: "gpu.{}.memorySmoothed",
+        unit: "%",
+        source: "derived",
+        description: "Exponentially smoothed memory utilization",
+        notes: "With --smooth-half-life",
+    },
+    MetricInfo {
+        name: "gpu.{}.powerWattsSmoothed",
+        unit: "W",
+        source: "derived",
+        description: "Exponentially smoothed power draw",
+        notes: "With --smooth-half-life",
+    },
+    MetricInfo {
+        name: "gpu.{}.<gpu|powerWatts|temp>P<n>",
+        unit: "",
+        source: "derived",
+        description: "Percentile of utilization, power or temperature over the sink's interval",
+        notes: "With a sink's percentiles= option",
+    },
+    MetricInfo {
+        name: "_gpu.{}.residency.pstate<n>",
+        unit: "s",
+        source: "derived",
+        description: "Time spent in each P-state",
+        notes: "With --residency-counters",
+    },
+    MetricInfo {
+        name: "_gpu.{}.residency.<reason>",
+        unit: "s",
+        source: "derived",
+        description: "Time clocks were reduced for each throttle reason",
+        notes: "With --residency-counters",
+    },
+    MetricInfo {
+        name: "_gpu.{}.residency.utilizationAbove<t>",
+        unit: "s",
+        source: "derived",
+        description: "Time utilization was above each of --utilization-thresholds",
+        notes: "With --residency-counters",
+    },
+];
+
+/// Every metric symon can write, device metrics first.
+pub fn metrics() -> impl Iterator<Item = &'static MetricInfo> {
+    DEVICE_METRICS
+        .iter()
+        .chain(DCGM_METRICS)
+        .chain(GPM_METRICS)
+        .chain(OTHER_METRICS)
+}
+
+impl MetricInfo {
+    /// The name with `{i}` for the index, e.g. `gpu.{i}.gpu`.
+    pub fn display_name(&self) -> String {
+        self.name.replace("{}", "{i}")
+    }
+
+    pub fn kind(&self) -> MetricKind {
+        MetricKind::of(self.name)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut info = json!({
+            "name": self.display_name(),
+            "type": self.kind().as_str(),
+            "unit": self.unit,
+            "source": self.source,
+            "description": self.description,
+        });
+        if !self.notes.is_empty() {
+            info["notes"] = self.notes.into();
+        }
+        info
+    }
+}
+
+/// The manifest as a JSON array, for `symon metrics --json` and
+/// `GET /manifest`.
+pub fn to_json() -> Value {
+    Value::Array(metrics().map(MetricInfo::to_json).collect())
+}
@@ -0,0 +1,118 @@
+use crate::control::Control;
+use crate::log;
+use crate::metrics::{Metrics, SampleTime};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Longer marker names are truncated.
+const MAX_NAME: usize = 256;
+
+/// Clean up a marker name from an untrusted source: control characters are
+/// dropped and long names truncated. Returns `None` if nothing is left.
+pub fn sanitize(name: &str) -> Option<String> {
+    let name: String = name
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME)
+        .collect();
+    (!name.is_empty()).then_some(name)
+}
+
+/// An event record for a marker, e.g. `epoch 3 start`, so samples can be
+/// aligned with what the application was doing.
+pub fn record(name: &str, time: SampleTime) -> Metrics {
+    let mut record = Metrics::new();
+    record.add_metric("_record", "event");
+    record.add_metric("_event", "marker");
+    record.add_metric("_marker", name.to_string());
+    record.set_time(time);
+    record
+}
+
+/// Follow a file that applications append markers to, one per line, and
+/// deliver each new line as `Control::Marker`.
+///
+/// Lines already in the file when symon starts are skipped. The file may be
+/// created later, and truncating it starts over from the beginning.
+pub fn watch(path: PathBuf, sender: Sender<Control>) -> io::Result<()> {
+    thread::Builder::new()
+        .name("marker-file".to_string())
+        .spawn(move || {
+            let mut offset = File::open(&path)
+                .and_then(|file| file.metadata())
+                .map_or(0, |metadata| metadata.len());
+            loop {
+                thread::sleep(POLL_INTERVAL);
+                match read_new_lines(&path, &mut offset) {
+                    Ok(lines) => {
+                        for name in lines.iter().filter_map(|line| sanitize(line)) {
+                            if sender.send(Control::Marker(name)).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => offset = 0,
+                    Err(e) => log::warning!("Error reading {}: {}", path.display(), e),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Read the complete lines after `offset`, advancing it past them. A partly
+/// written last line is left for the next call, and invalid UTF-8 is
+/// replaced rather than stalling the file on that line forever.
+fn read_new_lines(path: &Path, offset: &mut u64) -> io::Result<Vec<String>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < *offset {
+        *offset = 0;
+    }
+    if len == *offset {
+        return Ok(Vec::new());
+    }
+    file.seek(SeekFrom::Start(*offset))?;
+    let mut reader = BufReader::new(file);
+    let mut lines = Vec::new();
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        if !line.ends_with(b"\n") {
+            break;
+        }
+        *offset += line.len() as u64;
+        lines.push(String::from_utf8_lossy(&line).trim_end().to_string());
+        line.clear();
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_complete_lines_past_invalid_utf8() {
+        let path = std::env::temp_dir().join(format!("symon-markers-{}", std::process::id()));
+        std::fs::write(&path, b"epoch 1\n\xffbad\nepoch 2\npartial").unwrap();
+        let mut offset = 0;
+        let lines = read_new_lines(&path, &mut offset).unwrap();
+        assert_eq!(lines, ["epoch 1", "\u{fffd}bad", "epoch 2"]);
+        assert_eq!(offset, 21);
+        assert!(read_new_lines(&path, &mut offset).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(sanitize("  epoch\t3 \n").as_deref(), Some("epoch3"));
+        assert_eq!(sanitize("\x1b[0m").as_deref(), Some("[0m"));
+        assert_eq!(sanitize(" \r\n"), None);
+        assert_eq!(sanitize(&"x".repeat(1000)).map(|n| n.len()), Some(MAX_NAME));
+    }
+}
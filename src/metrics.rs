@@ -1,36 +1,181 @@
-use serde::Serialize;
-use std::collections::BTreeMap;
+use crate::schema;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::borrow::Cow;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Metric name. Names known ahead of time are borrowed for the lifetime of
+/// the program so that adding them to a sample does not allocate.
+pub type MetricKey = Cow<'static, str>;
+
+/// How a metric's values relate over time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricKind {
+    /// A point-in-time reading, e.g. temperature.
+    Gauge,
+    /// A monotonically increasing total, e.g. energy consumed. May reset to
+    /// zero when the driver reloads or the agent restarts.
+    Counter,
+}
+
+impl MetricKind {
+    /// Classify a metric by name. Metrics are gauges unless listed as counters.
+    pub fn of(key: &str) -> Self {
+        const COUNTER_SUFFIXES: &[&str] = &[
+            ".correctedMemoryErrors",
+            ".uncorrectedMemoryErrors",
+            ".correctedVolatileMemoryErrors",
+            ".uncorrectedVolatileMemoryErrors",
+            ".energyJoules",
+            ".pcieReplays",
+            ".xidErrors",
+            ".droppedSamples",
+            ".errors",
+            ".stalls",
+        ];
+        // Residency totals are named after the state, e.g. `.residency.pstate0`
+        if COUNTER_SUFFIXES.iter().any(|suffix| key.ends_with(suffix))
+            || key.contains(".residency.")
+        {
+            MetricKind::Counter
+        } else {
+            MetricKind::Gauge
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Gauge => "gauge",
+            MetricKind::Counter => "counter",
+        }
+    }
+}
+
+/// When a sample was taken, by wall clock and by the agent's monotonic clock.
+#[derive(Clone, Copy, Debug)]
+pub struct SampleTime {
+    pub wall: SystemTime,
+    /// Time since the agent started.
+    pub uptime: Duration,
+}
 
 /// System metrics storage.
 ///
-/// Metrics are stored in a BTreeMap to ensure consistent ordering of keys
+/// Metrics are kept in a Vec sorted by key to ensure consistent ordering of keys
 /// in the output JSON. The output map is flat to make it easier to parse
-/// in downstream applications.
-#[derive(Serialize)]
+/// in downstream applications. The storage is meant to be cleared and reused
+/// between samples, so steady-state sampling doesn't reallocate it.
+#[derive(Default)]
 pub struct Metrics {
-    #[serde(flatten)]
-    metrics: BTreeMap<String, serde_json::Value>,
+    metrics: Vec<(MetricKey, serde_json::Value)>,
+    time: Option<SampleTime>,
 }
 
 impl Metrics {
     pub fn new() -> Self {
         Metrics {
-            metrics: BTreeMap::new(),
+            metrics: Vec::new(),
+            time: None,
         }
     }
 
-    pub fn add_metric<T: Into<serde_json::Value>>(&mut self, key: &str, value: T) {
-        self.metrics.insert(key.to_string(), value.into());
+    /// Remove all metrics, keeping the allocated storage.
+    pub fn clear(&mut self) {
+        self.metrics.clear();
+        self.time = None;
+    }
+
+    pub fn len(&self) -> usize {
+        self.metrics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metrics.is_empty()
+    }
+
+    /// Replace the contents with a copy of `other`, reusing the allocated storage.
+    pub fn copy_from(&mut self, other: &Metrics) {
+        self.metrics.clone_from(&other.metrics);
+        self.time = other.time;
     }
 
-    pub fn add_timestamp(&mut self, timestamp: f64) {
+    pub fn add_metric<K: Into<MetricKey>, T: Into<serde_json::Value>>(&mut self, key: K, value: T) {
+        let key = key.into();
+        match self
+            .metrics
+            .binary_search_by(|(k, _)| k.as_ref().cmp(key.as_ref()))
+        {
+            Ok(i) => self.metrics[i].1 = value.into(),
+            Err(i) => self.metrics.insert(i, (key, value.into())),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&serde_json::Value> {
+        self.metrics
+            .binary_search_by(|(k, _)| k.as_ref().cmp(key))
+            .ok()
+            .map(|i| &self.metrics[i].1)
+    }
+
+    pub fn for_each(&self, mut f: impl FnMut(&MetricKey, &serde_json::Value)) {
+        for (key, value) in &self.metrics {
+            f(key, value);
+        }
+    }
+
+    /// Keep only the metrics for which `keep` returns true.
+    pub fn retain(&mut self, mut keep: impl FnMut(&MetricKey, &serde_json::Value) -> bool) {
+        self.metrics.retain(|(key, value)| keep(key, value));
+    }
+
+    pub fn timestamp(&self) -> Option<f64> {
+        self.get("_timestamp").and_then(|v| v.as_f64())
+    }
+
+    /// Record when the sample was taken and add it as the `_timestamp` epoch,
+    /// along with the `_schema_version` every record carries.
+    ///
+    /// Other representations of the sample time are added per sink, see
+    /// `sink::TimeFieldsSink`.
+    pub fn set_time(&mut self, time: SampleTime) {
+        let timestamp = time
+            .wall
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
         self.add_metric("_timestamp", timestamp);
+        self.add_metric("_schema_version", schema::VERSION);
+        self.time = Some(time);
     }
 
-    /// Print the metrics as a JSON string to stdout.
-    pub fn print_json(&self) -> Result<(), serde_json::Error> {
-        let json_output = serde_json::to_string(&self.metrics)?;
-        println!("{}", json_output);
+    pub fn time(&self) -> Option<SampleTime> {
+        self.time
+    }
+
+    /// Parse a flat JSON object, e.g. a line previously produced by `to_json_line`.
+    pub fn from_json(line: &[u8]) -> Result<Self, serde_json::Error> {
+        let map: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(line)?;
+        let mut metrics = Metrics::new();
+        for (key, value) in map {
+            metrics.add_metric(key, value);
+        }
+        Ok(metrics)
+    }
+
+    /// Serialize the metrics as a single JSON line into `buf`, reusing its allocation.
+    pub fn to_json_line(&self, buf: &mut Vec<u8>) -> Result<(), serde_json::Error> {
+        buf.clear();
+        serde_json::to_writer(&mut *buf, self)?;
+        buf.push(b'\n');
         Ok(())
     }
 }
+
+impl Serialize for Metrics {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.metrics.len()))?;
+        for (key, value) in &self.metrics {
+            map.serialize_entry(key.as_ref(), value)?;
+        }
+        map.end()
+    }
+}
@@ -0,0 +1,506 @@
+//! NVML functions that nvml-wrapper doesn't wrap yet, called through the raw
+//! bindings on devices opened by nvml-wrapper.
+
+use nvml_wrapper::error::{nvml_sym, nvml_try, NvmlError};
+use nvml_wrapper::Device;
+use nvml_wrapper_sys::bindings::{
+    nvmlEventData_t, nvmlEventSet_t, nvmlEventTypeXidCriticalError, nvmlGpmMetricsGet_t,
+    nvmlGpmSample_t, nvmlGpmSupport_t,
+    nvmlGpuVirtualizationMode_NVML_GPU_VIRTUALIZATION_MODE_HOST_VGPU,
+    nvmlGpuVirtualizationMode_NVML_GPU_VIRTUALIZATION_MODE_HOST_VSGA,
+    nvmlGpuVirtualizationMode_NVML_GPU_VIRTUALIZATION_MODE_NONE,
+    nvmlGpuVirtualizationMode_NVML_GPU_VIRTUALIZATION_MODE_PASSTHROUGH,
+    nvmlGpuVirtualizationMode_NVML_GPU_VIRTUALIZATION_MODE_VGPU, nvmlGridLicensableFeatures_t,
+    nvmlReturn_enum_NVML_SUCCESS, nvmlValueType_enum_NVML_VALUE_TYPE_DOUBLE,
+    nvmlValueType_enum_NVML_VALUE_TYPE_SIGNED_INT,
+    nvmlValueType_enum_NVML_VALUE_TYPE_SIGNED_LONG_LONG,
+    nvmlValueType_enum_NVML_VALUE_TYPE_UNSIGNED_INT,
+    nvmlValueType_enum_NVML_VALUE_TYPE_UNSIGNED_LONG, nvmlValueType_t, nvmlValue_t,
+    nvmlVgpuInstanceUtilizationSample_t, nvmlVgpuInstance_t, NvmlLib, NVML_DEVICE_UUID_BUFFER_SIZE,
+    NVML_GPM_METRICS_GET_VERSION, NVML_GPM_SUPPORT_VERSION, NVML_GRID_LICENSE_EXPIRY_PERMANENT,
+    NVML_GRID_LICENSE_EXPIRY_VALID, NVML_GSP_FIRMWARE_VERSION_BUF_SIZE, NVML_VGPU_NAME_BUFFER_SIZE,
+};
+use serde::Serialize;
+use std::ffi::{c_char, CStr};
+use std::ptr;
+
+/// State of the vGPU software license of a guest.
+#[derive(Clone, Debug)]
+pub struct License {
+    /// Licensed product, e.g. "NVIDIA Virtual Compute Server".
+    pub product: String,
+    pub licensed: bool,
+    /// When the license expires as `YYYY-MM-DDTHH:MM:SSZ`, or "permanent".
+    pub expiry: Option<String>,
+}
+
+/// Memory rows remapped to spare rows after ECC errors (Ampere and newer).
+#[derive(Clone, Copy, Debug)]
+pub struct RemappedRows {
+    pub correctable: u32,
+    pub uncorrectable: u32,
+    /// A remapping takes effect at the next GPU reset.
+    pub pending: bool,
+    /// A remapping failed, i.e. the spare rows ran out.
+    pub failed: bool,
+}
+
+/// A buffer of GPU Performance Monitoring counters, see `NvmlExt::gpm_alloc`.
+pub struct GpmSample(nvmlGpmSample_t);
+
+/// A vGPU instance running on a host GPU, i.e. one VM's share of it.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VgpuInstance {
+    pub id: u32,
+    /// ID of the VM the instance is assigned to, e.g. its domain UUID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vm_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    /// vGPU type, e.g. "GRID A100-4C".
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_name: Option<String>,
+    /// Frame buffer used by the VM (in bytes).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sm_utilization: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_utilization: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoder_utilization: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decoder_utilization: Option<u32>,
+    /// Frame rate limit of the vGPU type, in frames per second.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_rate_limit: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub licensed: Option<bool>,
+}
+
+pub struct NvmlExt {
+    lib: NvmlLib,
+    /// Event set for critical XID errors, or null if not watching.
+    xid_events: nvmlEventSet_t,
+}
+
+impl NvmlExt {
+    /// Load the NVML library a second time. The loader hands back the library
+    /// nvml-wrapper already initialized, so this must only be used while an
+    /// `Nvml` instance is alive.
+    pub fn open() -> Option<Self> {
+        #[cfg(unix)]
+        let path = "libnvidia-ml.so.1";
+        #[cfg(windows)]
+        let path = "nvml.dll";
+        // SAFETY: loading NVML runs no initialization code beyond the loader's
+        let lib = unsafe { NvmlLib::new(path) }.ok()?;
+        Some(NvmlExt {
+            lib,
+            xid_events: ptr::null_mut(),
+        })
+    }
+
+    /// Start collecting critical XID errors of `devices`, for `poll_xids`.
+    ///
+    /// Devices that don't support events are skipped.
+    pub fn watch_xids<'a>(
+        &mut self,
+        devices: impl IntoIterator<Item = &'a Device<'a>>,
+    ) -> Result<(), NvmlError> {
+        let create = nvml_sym(self.lib.nvmlEventSetCreate.as_ref())?;
+        let register = nvml_sym(self.lib.nvmlDeviceRegisterEvents.as_ref())?;
+        let mut set = ptr::null_mut();
+        // SAFETY: `set` outlives the call and is only used with this library
+        unsafe { nvml_try(create(&mut set))? };
+        self.xid_events = set;
+        for device in devices {
+            // SAFETY: device handles are valid while `devices` is
+            let _ = unsafe {
+                nvml_try(register(
+                    device.handle(),
+                    nvmlEventTypeXidCriticalError as u64,
+                    set,
+                ))
+            };
+        }
+        Ok(())
+    }
+
+    /// XID errors raised since the previous call, as (device handle address,
+    /// XID) pairs. Never blocks.
+    pub fn poll_xids(&self) -> Vec<(usize, u64)> {
+        let mut xids = Vec::new();
+        if self.xid_events.is_null() {
+            return xids;
+        }
+        let Ok(wait) = nvml_sym(self.lib.nvmlEventSetWait_v2.as_ref()) else {
+            return xids;
+        };
+        loop {
+            // SAFETY: the set is valid until drop and `data` outlives the call
+            let mut data: nvmlEventData_t = unsafe { std::mem::zeroed() };
+            if unsafe { nvml_try(wait(self.xid_events, &mut data, 0)) }.is_err() {
+                // Timeout once no events are left
+                return xids;
+            }
+            xids.push((data.device as usize, data.eventData));
+        }
+    }
+
+    /// Fan speed the driver is aiming for, in percent.
+    pub fn target_fan_speed(&self, device: &Device, fan: u32) -> Result<u32, NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlDeviceGetTargetFanSpeed.as_ref())?;
+        let mut speed = 0;
+        // SAFETY: the handle is valid while `device` is, and `speed` outlives the call
+        unsafe { nvml_try(sym(device.handle(), fan, &mut speed))? };
+        Ok(speed)
+    }
+
+    /// Range of fan speeds the device accepts, in percent.
+    pub fn min_max_fan_speed(&self, device: &Device) -> Result<(u32, u32), NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlDeviceGetMinMaxFanSpeed.as_ref())?;
+        let (mut min, mut max) = (0, 0);
+        // SAFETY: as above
+        unsafe { nvml_try(sym(device.handle(), &mut min, &mut max))? };
+        Ok((min, max))
+    }
+
+    /// Take manual control of a fan and set its speed in percent. Requires root.
+    pub fn set_fan_speed(&self, device: &Device, fan: u32, speed: u32) -> Result<(), NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlDeviceSetFanSpeed_v2.as_ref())?;
+        // SAFETY: as above
+        unsafe { nvml_try(sym(device.handle(), fan, speed)) }
+    }
+
+    /// Hand a fan back to the driver's automatic control. Requires root.
+    pub fn set_default_fan_speed(&self, device: &Device, fan: u32) -> Result<(), NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlDeviceSetDefaultFanSpeed_v2.as_ref())?;
+        // SAFETY: as above
+        unsafe { nvml_try(sym(device.handle(), fan)) }
+    }
+
+    /// Rows of device memory remapped after ECC errors.
+    pub fn remapped_rows(&self, device: &Device) -> Result<RemappedRows, NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlDeviceGetRemappedRows.as_ref())?;
+        let (mut correctable, mut uncorrectable, mut pending, mut failed) = (0, 0, 0, 0);
+        // SAFETY: as above
+        unsafe {
+            nvml_try(sym(
+                device.handle(),
+                &mut correctable,
+                &mut uncorrectable,
+                &mut pending,
+                &mut failed,
+            ))?
+        };
+        Ok(RemappedRows {
+            correctable,
+            uncorrectable,
+            pending: pending != 0,
+            failed: failed != 0,
+        })
+    }
+
+    /// Whether GSP firmware is enabled, and whether that is the default for
+    /// the GPU.
+    pub fn gsp_firmware_mode(&self, device: &Device) -> Result<(bool, bool), NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlDeviceGetGspFirmwareMode.as_ref())?;
+        let (mut enabled, mut default) = (0, 0);
+        // SAFETY: as above
+        unsafe { nvml_try(sym(device.handle(), &mut enabled, &mut default))? };
+        Ok((enabled != 0, default != 0))
+    }
+
+    /// Version of the GSP firmware the driver loaded.
+    pub fn gsp_firmware_version(&self, device: &Device) -> Result<String, NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlDeviceGetGspFirmwareVersion.as_ref())?;
+        let mut buf = [0 as c_char; NVML_GSP_FIRMWARE_VERSION_BUF_SIZE as usize];
+        // SAFETY: `buf` is the size NVML requires and outlives the call
+        unsafe { nvml_try(sym(device.handle(), buf.as_mut_ptr()))? };
+        Ok(string_from(&buf))
+    }
+
+    /// Whether the device supports GPU Performance Monitoring, i.e. is Hopper
+    /// or newer with driver 520 or newer.
+    pub fn gpm_supported(&self, device: &Device) -> Result<bool, NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlGpmQueryDeviceSupport.as_ref())?;
+        let mut support = nvmlGpmSupport_t {
+            version: NVML_GPM_SUPPORT_VERSION,
+            isSupportedDevice: 0,
+        };
+        // SAFETY: as above
+        unsafe { nvml_try(sym(device.handle(), &mut support))? };
+        Ok(support.isSupportedDevice != 0)
+    }
+
+    /// Allocate a buffer for `gpm_sample`, freed by `gpm_free`.
+    pub fn gpm_alloc(&self) -> Result<GpmSample, NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlGpmSampleAlloc.as_ref())?;
+        let mut sample = ptr::null_mut();
+        // SAFETY: `sample` outlives the call
+        unsafe { nvml_try(sym(&mut sample))? };
+        Ok(GpmSample(sample))
+    }
+
+    pub fn gpm_free(&self, sample: GpmSample) {
+        if let Ok(free) = nvml_sym(self.lib.nvmlGpmSampleFree.as_ref()) {
+            // SAFETY: the sample was allocated by this library and is consumed
+            unsafe { free(sample.0) };
+        }
+    }
+
+    /// Snapshot the device's performance counters into `sample`.
+    pub fn gpm_sample(&self, device: &Device, sample: &mut GpmSample) -> Result<(), NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlGpmSampleGet.as_ref())?;
+        // SAFETY: the handle is valid while `device` is, and the sample until freed
+        unsafe { nvml_try(sym(device.handle(), sample.0)) }
+    }
+
+    /// Compute GPM metrics (`nvmlGpmMetricId_t`) over the time between two
+    /// samples of the same device. Metrics the device can't compute are `None`.
+    pub fn gpm_metrics(
+        &self,
+        earlier: &GpmSample,
+        later: &GpmSample,
+        ids: &[u32],
+    ) -> Result<Vec<Option<f64>>, NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlGpmMetricsGet.as_ref())?;
+        // SAFETY: plain C struct, all-zero is a valid value
+        let mut get: nvmlGpmMetricsGet_t = unsafe { std::mem::zeroed() };
+        let ids = &ids[..ids.len().min(get.metrics.len())];
+        get.version = NVML_GPM_METRICS_GET_VERSION;
+        get.numMetrics = ids.len() as u32;
+        get.sample1 = earlier.0;
+        get.sample2 = later.0;
+        for (metric, &id) in get.metrics.iter_mut().zip(ids) {
+            metric.metricId = id;
+        }
+        // SAFETY: `get` outlives the call and the samples are valid until freed
+        unsafe { nvml_try(sym(&mut get))? };
+        Ok(get.metrics[..ids.len()]
+            .iter()
+            .map(|metric| {
+                (metric.nvmlReturn == nvmlReturn_enum_NVML_SUCCESS).then_some(metric.value)
+            })
+            .collect())
+    }
+
+    /// How a GPU is virtualized: "none", "passthrough", "vgpu" (inside a vGPU
+    /// guest), "hostVgpu" or "hostVsga".
+    pub fn virtualization_mode(&self, device: &Device) -> Result<&'static str, NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlDeviceGetVirtualizationMode.as_ref())?;
+        let mut mode = 0;
+        // SAFETY: as above
+        unsafe { nvml_try(sym(device.handle(), &mut mode))? };
+        #[allow(non_upper_case_globals)]
+        Ok(match mode {
+            nvmlGpuVirtualizationMode_NVML_GPU_VIRTUALIZATION_MODE_NONE => "none",
+            nvmlGpuVirtualizationMode_NVML_GPU_VIRTUALIZATION_MODE_PASSTHROUGH => "passthrough",
+            nvmlGpuVirtualizationMode_NVML_GPU_VIRTUALIZATION_MODE_VGPU => "vgpu",
+            nvmlGpuVirtualizationMode_NVML_GPU_VIRTUALIZATION_MODE_HOST_VGPU => "hostVgpu",
+            nvmlGpuVirtualizationMode_NVML_GPU_VIRTUALIZATION_MODE_HOST_VSGA => "hostVsga",
+            _ => "unknown",
+        })
+    }
+
+    /// The enabled licensable feature of a vGPU guest, or `None` if the
+    /// device doesn't support licensing.
+    pub fn license(&self, device: &Device) -> Result<Option<License>, NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlDeviceGetGridLicensableFeatures_v4.as_ref())?;
+        // SAFETY: plain C struct, all-zero is a valid value
+        let mut features: nvmlGridLicensableFeatures_t = unsafe { std::mem::zeroed() };
+        // SAFETY: as above
+        unsafe { nvml_try(sym(device.handle(), &mut features))? };
+        if features.isGridLicenseSupported == 0 {
+            return Ok(None);
+        }
+        let count =
+            (features.licensableFeaturesCount as usize).min(features.gridLicensableFeatures.len());
+        let available = &features.gridLicensableFeatures[..count];
+        let Some(feature) = available
+            .iter()
+            .find(|feature| feature.featureEnabled != 0)
+            .or(available.first())
+        else {
+            return Ok(None);
+        };
+
+        // SAFETY: NVML null-terminates the name within the buffer
+        let product = unsafe { CStr::from_ptr(feature.productName.as_ptr()) };
+        let expiry = &feature.licenseExpiry;
+        let expiry = match expiry.status as u32 {
+            NVML_GRID_LICENSE_EXPIRY_VALID => Some(format!(
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+                expiry.year, expiry.month, expiry.day, expiry.hour, expiry.min, expiry.sec
+            )),
+            NVML_GRID_LICENSE_EXPIRY_PERMANENT => Some("permanent".to_string()),
+            _ => None,
+        };
+        Ok(Some(License {
+            product: product.to_string_lossy().into_owned(),
+            licensed: feature.featureState != 0,
+            expiry,
+        }))
+    }
+
+    /// The vGPU instances running on a host GPU, with their utilization from
+    /// the driver's samples since `last_seen`, which is advanced to the newest.
+    pub fn vgpu_instances(
+        &self,
+        device: &Device,
+        last_seen: &mut Option<u64>,
+    ) -> Result<Vec<VgpuInstance>, NvmlError> {
+        let active = nvml_sym(self.lib.nvmlDeviceGetActiveVgpus.as_ref())?;
+        let mut count = 0;
+        // SAFETY: as above; a null buffer only queries the count
+        match unsafe { nvml_try(active(device.handle(), &mut count, ptr::null_mut())) } {
+            Ok(()) => return Ok(Vec::new()),
+            Err(NvmlError::InsufficientSize(_)) => {}
+            Err(e) => return Err(e),
+        }
+        let mut ids: Vec<nvmlVgpuInstance_t> = vec![0; count as usize];
+        // SAFETY: `ids` holds `count` entries
+        unsafe { nvml_try(active(device.handle(), &mut count, ids.as_mut_ptr()))? };
+        ids.truncate(count as usize);
+
+        let mut instances: Vec<VgpuInstance> =
+            ids.iter().map(|&id| self.vgpu_instance(id)).collect();
+        self.vgpu_utilization(device, &mut instances, last_seen);
+        Ok(instances)
+    }
+
+    fn vgpu_instance(&self, id: nvmlVgpuInstance_t) -> VgpuInstance {
+        let mut instance = VgpuInstance {
+            id,
+            ..VgpuInstance::default()
+        };
+        // SAFETY: for all calls below, `id` is an instance reported by the
+        // driver and every out-parameter outlives its call
+        unsafe {
+            if let Ok(sym) = nvml_sym(self.lib.nvmlVgpuInstanceGetVmID.as_ref()) {
+                let mut buf = [0 as c_char; NVML_DEVICE_UUID_BUFFER_SIZE as usize];
+                let mut id_type = 0;
+                if nvml_try(sym(id, buf.as_mut_ptr(), buf.len() as u32, &mut id_type)).is_ok() {
+                    instance.vm_id = Some(string_from(&buf));
+                }
+            }
+            if let Ok(sym) = nvml_sym(self.lib.nvmlVgpuInstanceGetUUID.as_ref()) {
+                let mut buf = [0 as c_char; NVML_DEVICE_UUID_BUFFER_SIZE as usize];
+                if nvml_try(sym(id, buf.as_mut_ptr(), buf.len() as u32)).is_ok() {
+                    instance.uuid = Some(string_from(&buf));
+                }
+            }
+            if let (Ok(get_type), Ok(get_name)) = (
+                nvml_sym(self.lib.nvmlVgpuInstanceGetType.as_ref()),
+                nvml_sym(self.lib.nvmlVgpuTypeGetName.as_ref()),
+            ) {
+                let mut type_id = 0;
+                let mut buf = [0 as c_char; NVML_VGPU_NAME_BUFFER_SIZE as usize];
+                let mut size = buf.len() as u32;
+                if nvml_try(get_type(id, &mut type_id)).is_ok()
+                    && nvml_try(get_name(type_id, buf.as_mut_ptr(), &mut size)).is_ok()
+                {
+                    instance.type_name = Some(string_from(&buf));
+                }
+            }
+            if let Ok(sym) = nvml_sym(self.lib.nvmlVgpuInstanceGetFbUsage.as_ref()) {
+                let mut usage = 0;
+                if nvml_try(sym(id, &mut usage)).is_ok() {
+                    instance.memory_bytes = Some(usage);
+                }
+            }
+            if let Ok(sym) = nvml_sym(self.lib.nvmlVgpuInstanceGetFrameRateLimit.as_ref()) {
+                let mut limit = 0;
+                if nvml_try(sym(id, &mut limit)).is_ok() {
+                    instance.frame_rate_limit = Some(limit);
+                }
+            }
+            if let Ok(sym) = nvml_sym(self.lib.nvmlVgpuInstanceGetLicenseStatus.as_ref()) {
+                let mut licensed = 0;
+                if nvml_try(sym(id, &mut licensed)).is_ok() {
+                    instance.licensed = Some(licensed != 0);
+                }
+            }
+        }
+        instance
+    }
+
+    fn vgpu_utilization(
+        &self,
+        device: &Device,
+        instances: &mut [VgpuInstance],
+        last_seen: &mut Option<u64>,
+    ) {
+        let Ok(sym) = nvml_sym(self.lib.nvmlDeviceGetVgpuUtilization.as_ref()) else {
+            return;
+        };
+        // The driver returns at most one sample per instance
+        // SAFETY: plain C structs, all-zero is a valid value
+        let mut samples: Vec<nvmlVgpuInstanceUtilizationSample_t> =
+            vec![unsafe { std::mem::zeroed() }; instances.len()];
+        let mut count = samples.len() as u32;
+        let mut value_type: nvmlValueType_t = 0;
+        // SAFETY: `samples` holds `count` entries and the handle is valid
+        let result = unsafe {
+            nvml_try(sym(
+                device.handle(),
+                last_seen.unwrap_or(0),
+                &mut value_type,
+                &mut count,
+                samples.as_mut_ptr(),
+            ))
+        };
+        if result.is_err() {
+            return;
+        }
+        samples.truncate(count as usize);
+        for sample in &samples {
+            *last_seen = Some(last_seen.unwrap_or(0).max(sample.timeStamp));
+            let Some(instance) = instances.iter_mut().find(|i| i.id == sample.vgpuInstance) else {
+                continue;
+            };
+            instance.sm_utilization = Some(value_u32(sample.smUtil, value_type));
+            instance.memory_utilization = Some(value_u32(sample.memUtil, value_type));
+            instance.encoder_utilization = Some(value_u32(sample.encUtil, value_type));
+            instance.decoder_utilization = Some(value_u32(sample.decUtil, value_type));
+        }
+    }
+}
+
+/// Read a union value of the type NVML reported.
+#[allow(non_upper_case_globals)]
+fn value_u32(value: nvmlValue_t, value_type: nvmlValueType_t) -> u32 {
+    // SAFETY: the field read matches the type NVML wrote
+    unsafe {
+        match value_type {
+            nvmlValueType_enum_NVML_VALUE_TYPE_DOUBLE => value.dVal as u32,
+            nvmlValueType_enum_NVML_VALUE_TYPE_UNSIGNED_INT => value.uiVal,
+            nvmlValueType_enum_NVML_VALUE_TYPE_UNSIGNED_LONG => value.ulVal as u32,
+            nvmlValueType_enum_NVML_VALUE_TYPE_SIGNED_LONG_LONG => value.sllVal as u32,
+            nvmlValueType_enum_NVML_VALUE_TYPE_SIGNED_INT => value.siVal as u32,
+            _ => value.ullVal as u32,
+        }
+    }
+}
+
+fn string_from(buf: &[c_char]) -> String {
+    // SAFETY: NVML null-terminates strings within the buffer, and the buffer
+    // was zeroed in case it didn't write one
+    unsafe { CStr::from_ptr(buf.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+impl Drop for NvmlExt {
+    fn drop(&mut self) {
+        if self.xid_events.is_null() {
+            return;
+        }
+        if let Ok(free) = nvml_sym(self.lib.nvmlEventSetFree.as_ref()) {
+            // SAFETY: the set was created by this library and isn't used afterwards
+            unsafe { free(self.xid_events) };
+        }
+    }
+}
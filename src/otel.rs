@@ -0,0 +1,558 @@
+//! Tracing of the sampling pipeline, exported as OpenTelemetry spans over
+//! OTLP/HTTP, to find where the time of a slow sample (`_sampling_duration_ms`)
+//! goes: NVML calls per GPU, DCGM, encoding, or a sink write.
+//!
+//! Each sample is one trace. Spans nest under the span current on their
+//! thread; work handed to another thread carries the context along (see
+//! `current` and `enter`). Without `init`, spans cost a thread-local lookup.
+
+// Without the `net` feature there's no exporter, only the span bookkeeping
+#![cfg_attr(not(feature = "net"), allow(dead_code, unused_imports))]
+
+use crate::log;
+use crate::metrics::SampleTime;
+#[cfg(feature = "net")]
+use crate::sink_http::{self, Endpoint};
+#[cfg(feature = "net")]
+use crate::tls::Connector;
+#[cfg(feature = "net")]
+use prost::Message;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Finished spans waiting to be exported before new ones are dropped.
+const MAX_QUEUED_SPANS: usize = 4096;
+/// Export spans at least this often.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// Export once this many spans are waiting.
+const MAX_BATCH_SPANS: usize = 512;
+/// How long `shutdown` waits for the last spans to be exported.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// OTLP trace messages, as in `opentelemetry/proto/collector/trace/v1` and
+/// `opentelemetry/proto/trace/v1`, limited to the fields symon sets.
+#[cfg(feature = "net")]
+mod otlp {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ExportTraceServiceRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub resource_spans: Vec<ResourceSpans>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ResourceSpans {
+        #[prost(message, optional, tag = "1")]
+        pub resource: Option<Resource>,
+        #[prost(message, repeated, tag = "2")]
+        pub scope_spans: Vec<ScopeSpans>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Resource {
+        #[prost(message, repeated, tag = "1")]
+        pub attributes: Vec<KeyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ScopeSpans {
+        #[prost(message, optional, tag = "1")]
+        pub scope: Option<InstrumentationScope>,
+        #[prost(message, repeated, tag = "2")]
+        pub spans: Vec<Span>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InstrumentationScope {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub version: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Span {
+        #[prost(bytes = "vec", tag = "1")]
+        pub trace_id: Vec<u8>,
+        #[prost(bytes = "vec", tag = "2")]
+        pub span_id: Vec<u8>,
+        #[prost(bytes = "vec", tag = "4")]
+        pub parent_span_id: Vec<u8>,
+        #[prost(string, tag = "5")]
+        pub name: String,
+        #[prost(int32, tag = "6")]
+        pub kind: i32,
+        #[prost(fixed64, tag = "7")]
+        pub start_time_unix_nano: u64,
+        #[prost(fixed64, tag = "8")]
+        pub end_time_unix_nano: u64,
+        #[prost(message, repeated, tag = "9")]
+        pub attributes: Vec<KeyValue>,
+        #[prost(message, optional, tag = "15")]
+        pub status: Option<Status>,
+    }
+
+    /// `SPAN_KIND_INTERNAL`
+    pub const SPAN_KIND_INTERNAL: i32 = 1;
+    /// `STATUS_CODE_ERROR`
+    pub const STATUS_CODE_ERROR: i32 = 2;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Status {
+        #[prost(string, tag = "2")]
+        pub message: String,
+        #[prost(int32, tag = "3")]
+        pub code: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KeyValue {
+        #[prost(string, tag = "1")]
+        pub key: String,
+        #[prost(message, optional, tag = "2")]
+        pub value: Option<AnyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AnyValue {
+        #[prost(oneof = "any_value::Value", tags = "1, 2, 3, 4")]
+        pub value: Option<any_value::Value>,
+    }
+
+    pub mod any_value {
+        // Named as in the OTLP schema
+        #[allow(clippy::enum_variant_names)]
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Value {
+            #[prost(string, tag = "1")]
+            StringValue(String),
+            #[prost(bool, tag = "2")]
+            BoolValue(bool),
+            #[prost(int64, tag = "3")]
+            IntValue(i64),
+            #[prost(double, tag = "4")]
+            DoubleValue(f64),
+        }
+    }
+
+    pub fn key_value(key: &str, value: impl Into<super::Attribute>) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(value.into().into()),
+            }),
+        }
+    }
+}
+
+/// The value of a span attribute.
+pub enum Attribute {
+    String(String),
+    Bool(bool),
+    Int(i64),
+    Double(f64),
+}
+
+impl From<&str> for Attribute {
+    fn from(value: &str) -> Self {
+        Attribute::String(value.to_string())
+    }
+}
+
+impl From<String> for Attribute {
+    fn from(value: String) -> Self {
+        Attribute::String(value)
+    }
+}
+
+impl From<bool> for Attribute {
+    fn from(value: bool) -> Self {
+        Attribute::Bool(value)
+    }
+}
+
+impl From<u32> for Attribute {
+    fn from(value: u32) -> Self {
+        Attribute::Int(i64::from(value))
+    }
+}
+
+impl From<u64> for Attribute {
+    fn from(value: u64) -> Self {
+        Attribute::Int(value as i64)
+    }
+}
+
+impl From<usize> for Attribute {
+    fn from(value: usize) -> Self {
+        Attribute::Int(value as i64)
+    }
+}
+
+impl From<f64> for Attribute {
+    fn from(value: f64) -> Self {
+        Attribute::Double(value)
+    }
+}
+
+#[cfg(feature = "net")]
+impl From<Attribute> for otlp::any_value::Value {
+    fn from(value: Attribute) -> Self {
+        match value {
+            Attribute::String(value) => Self::StringValue(value),
+            Attribute::Bool(value) => Self::BoolValue(value),
+            Attribute::Int(value) => Self::IntValue(value),
+            Attribute::Double(value) => Self::DoubleValue(value),
+        }
+    }
+}
+
+/// Identifies a span and its trace, e.g. to parent spans on another thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpanContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+}
+
+enum Export {
+    /// A span that ended, and how long it took.
+    Span(Box<Active>, Duration),
+    /// Export what's waiting, then acknowledge.
+    Flush(SyncSender<()>),
+}
+
+struct Exporter {
+    queue: SyncSender<Export>,
+    /// Distinguishes the traces of agent restarts.
+    run_id: u64,
+    next_id: AtomicU64,
+    dropped: AtomicU64,
+}
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+thread_local! {
+    static CURRENT: Cell<Option<SpanContext>> = const { Cell::new(None) };
+}
+
+/// Start exporting spans to an OTLP/HTTP collector. `target` is `host:port`
+/// or `host:port/path` as in `http://host:port/path`; the path defaults to
+/// `/v1/traces`. With `tls`, spans are posted over HTTPS.
+///
+/// Spans are batched on a thread of their own; when the collector can't keep
+/// up, spans are dropped rather than slowing down sampling.
+#[cfg(feature = "net")]
+pub fn init(target: &str, tls: Option<Connector>) -> std::io::Result<()> {
+    let target = if target.contains('/') {
+        target.to_string()
+    } else {
+        format!("{}/v1/traces", target)
+    };
+    let endpoint = Endpoint::new(&target, tls, "application/x-protobuf");
+    let (queue, spans) = mpsc::sync_channel(MAX_QUEUED_SPANS);
+    let exporter = Exporter {
+        queue,
+        run_id: sink_http::random(),
+        next_id: AtomicU64::new(1),
+        dropped: AtomicU64::new(0),
+    };
+    if EXPORTER.set(exporter).is_err() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "tracing is already initialized",
+        ));
+    }
+    thread::Builder::new()
+        .name("otlp".to_string())
+        .spawn(move || export_spans(&endpoint, spans))?;
+    Ok(())
+}
+
+/// Export the spans still waiting, giving up after a few seconds.
+pub fn shutdown() {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    let (done_tx, done_rx) = mpsc::sync_channel(1);
+    if exporter.queue.send(Export::Flush(done_tx)).is_ok() {
+        let _ = done_rx.recv_timeout(SHUTDOWN_TIMEOUT);
+    }
+    let dropped = exporter.dropped.load(Ordering::Relaxed);
+    if dropped > 0 {
+        log::warning!(
+            "{} trace spans dropped because the collector fell behind",
+            dropped
+        );
+    }
+}
+
+#[cfg(feature = "net")]
+fn export_spans(endpoint: &Endpoint, spans: Receiver<Export>) {
+    let mut batch = Vec::new();
+    let mut deadline = Instant::now() + EXPORT_INTERVAL;
+    let mut failing = false;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let flush = match spans.recv_timeout(timeout) {
+            Ok(Export::Span(active, duration)) => {
+                batch.push(otlp_span(*active, duration));
+                if batch.len() < MAX_BATCH_SPANS {
+                    continue;
+                }
+                None
+            }
+            Ok(Export::Flush(done)) => Some(done),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        deadline = Instant::now() + EXPORT_INTERVAL;
+        if !batch.is_empty() {
+            let body = export_request(std::mem::take(&mut batch)).encode_to_vec();
+            let id = format!("{:016x}", sink_http::random());
+            // Log when exporting starts failing and when it recovers, not
+            // every failed batch
+            match endpoint.post(&id, &body) {
+                Ok(()) if failing => {
+                    log::info!("Exporting trace spans to {} again", endpoint.authority);
+                    failing = false;
+                }
+                Err(e) if !failing => {
+                    log::warning!(
+                        "Error exporting trace spans to {}: {}",
+                        endpoint.authority,
+                        e
+                    );
+                    failing = true;
+                }
+                _ => {}
+            }
+        }
+        if let Some(done) = flush {
+            let _ = done.send(());
+        }
+    }
+}
+
+#[cfg(feature = "net")]
+fn export_request(spans: Vec<otlp::Span>) -> otlp::ExportTraceServiceRequest {
+    let mut attributes = vec![
+        otlp::key_value("service.name", "symon"),
+        otlp::key_value("service.version", env!("CARGO_PKG_VERSION")),
+    ];
+    if let Some(host) = hostname() {
+        attributes.push(otlp::key_value("host.name", host));
+    }
+    otlp::ExportTraceServiceRequest {
+        resource_spans: vec![otlp::ResourceSpans {
+            resource: Some(otlp::Resource { attributes }),
+            scope_spans: vec![otlp::ScopeSpans {
+                scope: Some(otlp::InstrumentationScope {
+                    name: "symon".to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                }),
+                spans,
+            }],
+        }],
+    }
+}
+
+#[cfg(feature = "net")]
+fn otlp_span(active: Active, duration: Duration) -> otlp::Span {
+    let start = active
+        .start
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    otlp::Span {
+        trace_id: active.context.trace_id.to_vec(),
+        span_id: active.context.span_id.to_vec(),
+        parent_span_id: active
+            .parent
+            .map(|parent| parent.span_id.to_vec())
+            .unwrap_or_default(),
+        name: active.name.to_string(),
+        kind: otlp::SPAN_KIND_INTERNAL,
+        start_time_unix_nano: start,
+        // Measured on the monotonic clock, so a clock step doesn't skew it
+        end_time_unix_nano: start + duration.as_nanos() as u64,
+        attributes: active
+            .attributes
+            .into_iter()
+            .map(|(key, value)| otlp::key_value(&key, value))
+            .collect(),
+        status: active.error.map(|message| otlp::Status {
+            message,
+            code: otlp::STATUS_CODE_ERROR,
+        }),
+    }
+}
+
+/// The node's host name, or None if it can't be determined.
+#[cfg(feature = "net")]
+pub(crate) fn hostname() -> Option<String> {
+    #[cfg(unix)]
+    let host = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .or_else(|| std::env::var("HOSTNAME").ok());
+    #[cfg(windows)]
+    let host = std::env::var("COMPUTERNAME").ok();
+    host.filter(|name| !name.is_empty())
+}
+
+/// splitmix64: spreads a counter over the 64 bits of an ID.
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (x ^ (x >> 31)) | 1
+}
+
+impl Exporter {
+    fn next_id(&self) -> u64 {
+        mix(self.run_id ^ self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// The span current on this thread, to carry to work done on another thread.
+pub fn current() -> Option<SpanContext> {
+    CURRENT.with(Cell::get)
+}
+
+/// Makes `context` the parent of spans started on this thread until dropped.
+pub struct Scope {
+    previous: Option<SpanContext>,
+}
+
+/// Nest the spans started on this thread under `context`, e.g. one taken with
+/// `current` on the thread that handed over the work.
+pub fn enter(context: Option<SpanContext>) -> Scope {
+    Scope {
+        previous: CURRENT.with(|current| current.replace(context)),
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.previous));
+    }
+}
+
+/// The context of the root span of the sample taken at `time`, derived from
+/// the time so threads handed the sample later (e.g. the writer) can nest
+/// their spans in its trace without it being passed along.
+pub fn sample_context(time: SampleTime) -> Option<SpanContext> {
+    let exporter = EXPORTER.get()?;
+    let uptime = time.uptime.as_nanos() as u64;
+    let mut trace_id = [0; 16];
+    trace_id[..8].copy_from_slice(&exporter.run_id.to_be_bytes());
+    trace_id[8..].copy_from_slice(&uptime.to_be_bytes());
+    Some(SpanContext {
+        trace_id,
+        span_id: mix(exporter.run_id ^ uptime).to_be_bytes(),
+    })
+}
+
+struct Active {
+    context: SpanContext,
+    parent: Option<SpanContext>,
+    /// The context current before the span started, restored when it ends.
+    previous: Option<SpanContext>,
+    name: &'static str,
+    start: SystemTime,
+    started: Instant,
+    attributes: Vec<(String, Attribute)>,
+    error: Option<String>,
+}
+
+/// A span of work, current on its thread from when it's started until it's
+/// dropped, when it's queued for export. Spans must end in the reverse order
+/// they were started on a thread, which dropping guards ensures.
+pub struct Span(Option<Box<Active>>);
+
+impl Span {
+    /// Start a span nested under the current span, or a new trace if there is
+    /// none.
+    pub fn start(name: &'static str) -> Span {
+        let Some(exporter) = EXPORTER.get() else {
+            return Span(None);
+        };
+        let parent = current();
+        let trace_id = match parent {
+            Some(parent) => parent.trace_id,
+            None => {
+                let mut trace_id = [0; 16];
+                trace_id[..8].copy_from_slice(&exporter.run_id.to_be_bytes());
+                trace_id[8..].copy_from_slice(&exporter.next_id().to_be_bytes());
+                trace_id
+            }
+        };
+        let context = SpanContext {
+            trace_id,
+            span_id: exporter.next_id().to_be_bytes(),
+        };
+        Span::activate(name, context, parent)
+    }
+
+    /// Start the root span of the sample taken at `time`, see `sample_context`.
+    pub fn sample(name: &'static str, time: SampleTime) -> Span {
+        match sample_context(time) {
+            Some(context) => Span::activate(name, context, None),
+            None => Span(None),
+        }
+    }
+
+    fn activate(name: &'static str, context: SpanContext, parent: Option<SpanContext>) -> Span {
+        let previous = CURRENT.with(|current| current.replace(Some(context)));
+        Span(Some(Box::new(Active {
+            context,
+            parent,
+            previous,
+            name,
+            start: SystemTime::now(),
+            started: Instant::now(),
+            attributes: Vec::new(),
+            error: None,
+        })))
+    }
+
+    /// Whether spans are exported at all, to skip preparing attributes.
+    pub fn is_recording(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn set_attribute(&mut self, key: &str, value: impl Into<Attribute>) {
+        if let Some(active) = &mut self.0 {
+            active.attributes.push((key.to_string(), value.into()));
+        }
+    }
+
+    /// Mark the span as failed.
+    pub fn set_error(&mut self, message: impl std::fmt::Display) {
+        if let Some(active) = &mut self.0 {
+            active.error = Some(message.to_string());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(active) = self.0.take() else {
+            return;
+        };
+        CURRENT.with(|current| current.set(active.previous));
+        let Some(exporter) = EXPORTER.get() else {
+            return;
+        };
+        let duration = active.started.elapsed();
+        if exporter
+            .queue
+            .try_send(Export::Span(active, duration))
+            .is_err()
+        {
+            exporter.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
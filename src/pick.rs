@@ -0,0 +1,133 @@
+use crate::devices::Devices;
+use crate::metrics::Metrics;
+use std::cmp::Ordering;
+
+/// A GPU that could be picked, and how busy it was when sampled.
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub index: u32,
+    pub uuid: Option<String>,
+    pub processes: u64,
+    /// Utilization (in percentage).
+    pub utilization: f64,
+    /// Share of memory allocated (in percentage).
+    pub memory_allocated: f64,
+    pub free_memory_bytes: Option<u64>,
+    pub temp: Option<f64>,
+}
+
+impl Candidate {
+    /// Utilization plus share of memory allocated, in percentage points.
+    fn load(&self) -> f64 {
+        self.utilization + self.memory_allocated
+    }
+
+    /// GPUs without processes first, then the least loaded, with loads within
+    /// a point of each other counting as equal and the cooler GPU going first.
+    fn cmp_usage(&self, other: &Candidate) -> Ordering {
+        self.processes
+            .cmp(&other.processes)
+            .then((self.load().round() as i64).cmp(&(other.load().round() as i64)))
+            .then(
+                self.temp
+                    .unwrap_or(f64::INFINITY)
+                    .total_cmp(&other.temp.unwrap_or(f64::INFINITY)),
+            )
+    }
+}
+
+/// Requirements a GPU must meet to be picked.
+#[derive(Clone, Debug, Default)]
+pub struct PickOptions {
+    /// Minimum memory not yet allocated (in bytes).
+    pub min_free_memory_bytes: Option<u64>,
+}
+
+/// The GPUs of a sample that meet `options`, least used first, for
+/// `symon pick` and `symon reserve`. GPUs missing a process count,
+/// utilization or memory reading are left out, as their load is unknown.
+pub fn pick(metrics: &Metrics, devices: &Devices, options: &PickOptions) -> Vec<Candidate> {
+    let number = |key: String| metrics.get(&key).and_then(|v| v.as_f64());
+    let count = number("_gpu.count".to_string()).unwrap_or(0.0) as u32;
+    let mut candidates: Vec<Candidate> = (0..count)
+        .filter_map(|i| {
+            let compute = number(format!("gpu.{}.computeProcessCount", i))?;
+            let graphics = number(format!("gpu.{}.graphicsProcessCount", i))?;
+            Some(Candidate {
+                index: i,
+                uuid: devices
+                    .gpus
+                    .iter()
+                    .find(|gpu| gpu.index == i)
+                    .and_then(|gpu| gpu.uuid.clone()),
+                processes: (compute + graphics) as u64,
+                utilization: number(format!("gpu.{}.gpu", i))?,
+                memory_allocated: number(format!("gpu.{}.memoryAllocated", i))?,
+                free_memory_bytes: number(format!("_gpu.{}.memoryTotal", i))
+                    .zip(number(format!("gpu.{}.memoryAllocatedBytes", i)))
+                    .map(|(total, used)| (total - used).max(0.0) as u64),
+                temp: number(format!("gpu.{}.temp", i)),
+            })
+        })
+        .filter(|candidate| {
+            options
+                .min_free_memory_bytes
+                .is_none_or(|min| candidate.free_memory_bytes.is_some_and(|free| free >= min))
+        })
+        .collect();
+    candidates.sort_by(Candidate::cmp_usage);
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_gpu(metrics: &mut Metrics, i: u32, processes: u64, utilization: f64, temp: f64) {
+        metrics.add_metric(format!("gpu.{}.computeProcessCount", i), processes);
+        metrics.add_metric(format!("gpu.{}.graphicsProcessCount", i), 0);
+        metrics.add_metric(format!("gpu.{}.gpu", i), utilization);
+        metrics.add_metric(format!("gpu.{}.memoryAllocated", i), 0.0);
+        metrics.add_metric(format!("gpu.{}.temp", i), temp);
+    }
+
+    fn devices() -> Devices {
+        Devices {
+            driver_version: String::new(),
+            kernel_module: None,
+            gpus: Vec::new(),
+        }
+    }
+
+    fn indices(candidates: &[Candidate]) -> Vec<u32> {
+        candidates.iter().map(|candidate| candidate.index).collect()
+    }
+
+    #[test]
+    fn picks_the_least_used_gpus_first() {
+        let mut metrics = Metrics::new();
+        metrics.add_metric("_gpu.count", 3);
+        add_gpu(&mut metrics, 0, 1, 0.0, 40.0);
+        add_gpu(&mut metrics, 1, 0, 50.0, 40.0);
+        add_gpu(&mut metrics, 2, 0, 50.3, 30.0);
+        assert_eq!(
+            indices(&pick(&metrics, &devices(), &PickOptions::default())),
+            [2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn leaves_out_gpus_without_readings() {
+        let mut metrics = Metrics::new();
+        metrics.add_metric("_gpu.count", 3);
+        add_gpu(&mut metrics, 0, 0, 10.0, 40.0);
+        add_gpu(&mut metrics, 1, 0, 0.0, 40.0);
+        metrics.retain(|key, _| key != "gpu.1.computeProcessCount");
+        metrics.add_metric("gpu.2.computeProcessCount", 0);
+        metrics.add_metric("gpu.2.graphicsProcessCount", 0);
+        assert_eq!(
+            indices(&pick(&metrics, &devices(), &PickOptions::default())),
+            [0]
+        );
+    }
+}
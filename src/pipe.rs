@@ -0,0 +1,256 @@
+use crate::gap::SampleSpan;
+use crate::log;
+use crate::metrics::Metrics;
+use crate::sink::SinkMetrics;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Output waiting for the reader before backpressure applies; well above a
+/// pipe's own buffer (64 KiB on Linux), so a reader that is merely slow for a
+/// moment doesn't count as stalled.
+const MAX_PENDING_BYTES: usize = 1 << 20;
+/// How long flushing waits for a stalled reader.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What to do with new output while the reader of stdout has stalled, e.g. a
+/// `symon | consumer` pipeline whose consumer stopped reading.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backpressure {
+    /// Discard the oldest output not yet read to make room for new samples
+    DropOldest,
+    /// Wait for the reader; samples queue up for the writer, which drops the
+    /// newest once `--queue-size` are waiting
+    #[default]
+    Block,
+    /// Spool samples to `--spool-dir` and replay them once the reader catches up
+    Spill,
+}
+
+impl Backpressure {
+    fn describe(self) -> &'static str {
+        match self {
+            Backpressure::DropOldest => "dropping the oldest samples",
+            Backpressure::Block => "waiting for it",
+            Backpressure::Spill => "spooling samples to disk",
+        }
+    }
+}
+
+/// Self-metrics of stdout, reported as `_agent.sink.{i}.*`.
+#[derive(Default)]
+pub(crate) struct PipeStats {
+    keys: OnceLock<[&'static str; 3]>,
+    pending_bytes: AtomicUsize,
+    stalls: AtomicU64,
+    dropped_samples: AtomicU64,
+}
+
+impl SinkMetrics for PipeStats {
+    fn add_metrics(&self, index: usize, metrics: &mut Metrics) {
+        let [pending, stalls, dropped] = *self.keys.get_or_init(|| {
+            ["pendingBytes", "stalls", "droppedSamples"]
+                .map(|name| &*Box::leak(format!("_agent.sink.{}.{}", index, name).into_boxed_str()))
+        });
+        metrics.add_metric(pending, self.pending_bytes.load(Ordering::Relaxed) as u64);
+        metrics.add_metric(stalls, self.stalls.load(Ordering::Relaxed));
+        metrics.add_metric(dropped, self.dropped_samples.load(Ordering::Relaxed));
+    }
+}
+
+/// Encoded output and the samples in it.
+struct Chunk {
+    data: Vec<u8>,
+    samples: SampleSpan,
+}
+
+#[derive(Default)]
+struct Queue {
+    chunks: VecDeque<Chunk>,
+    /// Bytes queued or being written.
+    bytes: usize,
+    stalled_since: Option<Instant>,
+    /// Samples dropped with `Backpressure::DropOldest` since `take_lost`.
+    lost: SampleSpan,
+    /// Why writing to stdout failed, e.g. the reader went away.
+    error: Option<(io::ErrorKind, String)>,
+    /// No more output is coming; the thread exits once the queue is written.
+    closed: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+    stats: Arc<PipeStats>,
+}
+
+/// Writes to stdout on a thread of its own, so a reader that stops reading
+/// can be noticed instead of silently holding up the writer thread in a
+/// blocked `write`: once `MAX_PENDING_BYTES` are waiting, the reader counts as
+/// stalled and `Backpressure` decides what happens to new output.
+pub(crate) struct PipeWriter {
+    shared: Arc<Shared>,
+    policy: Backpressure,
+}
+
+impl PipeWriter {
+    pub(crate) fn spawn(policy: Backpressure, stats: Arc<PipeStats>) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            changed: Condvar::new(),
+            stats,
+        });
+        let thread_shared = shared.clone();
+        thread::Builder::new()
+            .name("stdout".to_string())
+            .spawn(move || write_chunks(&thread_shared))?;
+        Ok(PipeWriter { shared, policy })
+    }
+
+    /// Queue `data`, holding `samples`, for stdout, taking it out of
+    /// the buffer. With `Backpressure::Spill`, fails with `WouldBlock` while
+    /// the reader is stalled and leaves `data` with the caller, for a spool to
+    /// take the sample or the caller to retry.
+    pub(crate) fn write(&mut self, data: &mut Vec<u8>, samples: SampleSpan) -> io::Result<()> {
+        let shared = &*self.shared;
+        let mut queue = shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+        let is_full = |queue: &Queue| {
+            queue.bytes > 0 && queue.bytes + data.len() > MAX_PENDING_BYTES && queue.error.is_none()
+        };
+        if is_full(&queue) {
+            if queue.stalled_since.is_none() {
+                queue.stalled_since = Some(Instant::now());
+                shared.stats.stalls.fetch_add(1, Ordering::Relaxed);
+                log::warning!(
+                    "Reader of stdout stalled with {} bytes unread, {}",
+                    queue.bytes,
+                    self.policy.describe()
+                );
+            }
+            match self.policy {
+                Backpressure::Block => {
+                    queue = shared
+                        .changed
+                        .wait_while(queue, |queue| is_full(queue))
+                        .unwrap_or_else(|e| e.into_inner());
+                }
+                Backpressure::DropOldest => {
+                    while is_full(&queue) {
+                        let Some(chunk) = queue.chunks.pop_front() else {
+                            // Only the chunk being written is left
+                            break;
+                        };
+                        queue.bytes -= chunk.data.len();
+                        queue.lost.extend(&chunk.samples);
+                        shared
+                            .stats
+                            .dropped_samples
+                            .fetch_add(chunk.samples.count(), Ordering::Relaxed);
+                    }
+                }
+                Backpressure::Spill => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        format!("reader stalled with {} bytes unread", queue.bytes),
+                    ));
+                }
+            }
+        }
+        if let Some((kind, message)) = &queue.error {
+            return Err(io::Error::new(*kind, message.clone()));
+        }
+        queue.bytes += data.len();
+        shared
+            .stats
+            .pending_bytes
+            .store(queue.bytes, Ordering::Relaxed);
+        queue.chunks.push_back(Chunk {
+            data: std::mem::take(data),
+            samples,
+        });
+        shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Samples dropped since the last call, see `Sink::take_lost`.
+    pub(crate) fn take_lost(&mut self) -> SampleSpan {
+        let mut queue = self.shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+        std::mem::take(&mut queue.lost)
+    }
+
+    /// Wait a while for queued output to be read.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        let shared = &*self.shared;
+        let queue = shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+        let (queue, _) = shared
+            .changed
+            .wait_timeout_while(queue, FLUSH_TIMEOUT, |queue| {
+                queue.bytes > 0 && queue.error.is_none()
+            })
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some((kind, message)) = &queue.error {
+            return Err(io::Error::new(*kind, message.clone()));
+        }
+        if queue.bytes > 0 {
+            log::warning!(
+                "Reader of stdout stalled, {} bytes not written",
+                queue.bytes
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let mut queue = self.shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+fn write_chunks(shared: &Shared) {
+    let mut stdout = io::stdout();
+    loop {
+        let chunk = {
+            let queue = shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+            let mut queue = shared
+                .changed
+                .wait_while(queue, |queue| queue.chunks.is_empty() && !queue.closed)
+                .unwrap_or_else(|e| e.into_inner());
+            match queue.chunks.pop_front() {
+                Some(chunk) => chunk,
+                None => return,
+            }
+        };
+        let result = stdout.write_all(&chunk.data).and_then(|()| stdout.flush());
+
+        let mut queue = shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.bytes -= chunk.data.len();
+        if let Err(e) = result {
+            queue.error = Some((e.kind(), e.to_string()));
+            queue.bytes = 0;
+            queue.chunks.clear();
+        }
+        if let Some(since) = queue.stalled_since {
+            if queue.bytes <= MAX_PENDING_BYTES / 2 {
+                log::info!(
+                    "Reader of stdout caught up after {:.1}s",
+                    since.elapsed().as_secs_f64()
+                );
+                queue.stalled_since = None;
+            }
+        }
+        shared
+            .stats
+            .pending_bytes
+            .store(queue.bytes, Ordering::Relaxed);
+        shared.changed.notify_all();
+        if queue.error.is_some() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::limits::{self, format_cpu_list};
+use crate::metrics::{Metrics, SampleTime};
+use crate::subscribers::{Alert, Severity};
+use crate::topology::{GpuTopology, Topology};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// How long to wait before querying the topology again after getting none.
+const TOPOLOGY_RETRY: Duration = Duration::from_secs(60);
+
+/// CPUs close to a GPU, from the topology.
+struct GpuPlacement {
+    cpus: Vec<usize>,
+    numa_node: Option<u32>,
+}
+
+/// Warns when the tracked process is pinned to CPUs far from a GPU it uses.
+///
+/// A process restricted to the other socket of a dual-socket node crosses the
+/// inter-socket link for every host transfer, which silently costs 10-20% of
+/// throughput. Each process/GPU pair is reported once.
+#[derive(Default)]
+pub(crate) struct PlacementChecker {
+    /// Ideal CPUs per GPU index, queried on first use. Queries that fail or
+    /// find no GPUs are retried after `TOPOLOGY_RETRY`.
+    gpus: Option<Vec<Option<GpuPlacement>>>,
+    retry_at: Option<Instant>,
+    warned: HashSet<(i32, u32)>,
+}
+
+/// A process pinned to CPUs far from a GPU it uses.
+pub(crate) struct Mismatch {
+    pid: i32,
+    gpu: u32,
+    process_cpus: Vec<usize>,
+    gpu_cpus: Vec<usize>,
+    numa_node: Option<u32>,
+}
+
+impl PlacementChecker {
+    pub(crate) fn check(
+        &mut self,
+        metrics: &Metrics,
+        pid: i32,
+        topology: impl FnOnce() -> Option<Topology>,
+    ) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        if pid == 0 {
+            return mismatches;
+        }
+        let mut in_use = Vec::new();
+        metrics.for_each(|key, _| {
+            let Some(index) = key
+                .strip_prefix("gpu.process.")
+                .and_then(|rest| rest.split_once('.'))
+                .and_then(|(index, _)| index.parse::<u32>().ok())
+            else {
+                return;
+            };
+            if !in_use.contains(&index) && !self.warned.contains(&(pid, index)) {
+                in_use.push(index);
+            }
+        });
+        if in_use.is_empty() {
+            return mismatches;
+        }
+
+        if self.gpus.is_none() && self.retry_at.is_none_or(|at| Instant::now() >= at) {
+            let gpus: Vec<_> = topology()
+                .map(|topology| topology.gpus.into_iter().map(placement).collect())
+                .unwrap_or_default();
+            if gpus.is_empty() {
+                self.retry_at = Some(Instant::now() + TOPOLOGY_RETRY);
+            } else {
+                self.gpus = Some(gpus);
+            }
+        }
+        let Some(gpus) = &self.gpus else {
+            return mismatches;
+        };
+        let Some(process_cpus) = process_cpus(pid) else {
+            return mismatches;
+        };
+        for index in in_use {
+            let Some(Some(gpu)) = gpus.get(index as usize) else {
+                continue;
+            };
+            if gpu.cpus.iter().any(|cpu| process_cpus.contains(cpu)) {
+                continue;
+            }
+            self.warned.insert((pid, index));
+            mismatches.push(Mismatch {
+                pid,
+                gpu: index,
+                process_cpus: process_cpus.clone(),
+                gpu_cpus: gpu.cpus.clone(),
+                numa_node: gpu.numa_node,
+            });
+        }
+        mismatches
+    }
+}
+
+impl Mismatch {
+    fn message(&self) -> String {
+        let node = match self.numa_node {
+            Some(node) => format!("NUMA node {} ", node),
+            None => String::new(),
+        };
+        format!(
+            "process {} is pinned to CPUs {} but GPU {} is attached to {}(CPUs {})",
+            self.pid,
+            format_cpu_list(&self.process_cpus),
+            self.gpu,
+            node,
+            format_cpu_list(&self.gpu_cpus)
+        )
+    }
+
+    pub(crate) fn alert(&self) -> Alert {
+        Alert {
+            severity: Severity::Warning,
+            metric: format!("gpu.process.{}", self.gpu),
+            message: self.message(),
+        }
+    }
+
+    /// A `placement` event record.
+    pub(crate) fn record(&self, time: SampleTime) -> Metrics {
+        let mut record = Metrics::new();
+        record.add_metric("_record", "event");
+        record.add_metric("_event", "placement");
+        record.add_metric("severity", Severity::Warning.as_str());
+        record.add_metric("gpu", self.gpu);
+        record.add_metric("pid", self.pid);
+        record.add_metric("processCpus", format_cpu_list(&self.process_cpus));
+        record.add_metric("gpuCpus", format_cpu_list(&self.gpu_cpus));
+        if let Some(node) = self.numa_node {
+            record.add_metric("numaNode", node);
+        }
+        record.add_metric("message", self.message());
+        record.set_time(time);
+        record
+    }
+}
+
+fn placement(gpu: GpuTopology) -> Option<GpuPlacement> {
+    let cpus = limits::parse_cpu_list(gpu.cpu_affinity.as_deref()?).ok()?;
+    Some(GpuPlacement {
+        cpus,
+        numa_node: gpu.numa_node,
+    })
+}
+
+/// CPUs the process may run on.
+#[cfg(target_os = "linux")]
+fn process_cpus(pid: i32) -> Option<Vec<usize>> {
+    use nix::sched::{sched_getaffinity, CpuSet};
+    use nix::unistd::Pid;
+
+    let cpu_set = sched_getaffinity(Pid::from_raw(pid)).ok()?;
+    Some(
+        (0..CpuSet::count())
+            .filter(|&cpu| cpu_set.is_set(cpu).unwrap_or(false))
+            .collect(),
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_cpus(_pid: i32) -> Option<Vec<usize>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_a_missing_topology_later() {
+        let mut metrics = Metrics::new();
+        metrics.add_metric("gpu.process.0.gpu", 50);
+        let mut checker = PlacementChecker::default();
+        let mut queries = 0;
+        let mut check = |checker: &mut PlacementChecker| {
+            checker.check(&metrics, 1, || {
+                queries += 1;
+                None
+            })
+        };
+        assert!(check(&mut checker).is_empty());
+        assert!(check(&mut checker).is_empty());
+        checker.retry_at = Some(Instant::now());
+        assert!(check(&mut checker).is_empty());
+        assert_eq!(queries, 2);
+    }
+}
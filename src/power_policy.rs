@@ -0,0 +1,329 @@
+use crate::config::ConfigError;
+use crate::metrics::Metrics;
+use crate::timefmt::UtcDateTime;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Changes smaller than this are not worth an NVML call.
+const MIN_CHANGE_WATTS: f64 = 1.0;
+
+/// Rules for adjusting GPU power limits, read from `--power-policy`.
+///
+/// Every GPU gets `default_watts` unless rules match; when several rules
+/// match, the lowest limit wins. Limits are kept within what each GPU
+/// accepts. If they add up to more than `node_budget_watts`, they are scaled
+/// down proportionally to fit, except that no GPU goes below its minimum;
+/// the others make up the difference.
+///
+/// ```json
+/// {
+///   "default_watts": 400,
+///   "node_budget_watts": 2400,
+///   "rules": [
+///     {"name": "off-peak", "hours": [22, 6], "limit_watts": 250},
+///     {"name": "hot", "temp_above": 85, "limit_watts": 300}
+///   ]
+/// }
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PowerPolicy {
+    pub default_watts: f64,
+    #[serde(default)]
+    pub node_budget_watts: Option<f64>,
+    #[serde(default)]
+    pub rules: Vec<PowerRule>,
+}
+
+/// A limit that applies while all of its conditions hold.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PowerRule {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Hours of the day in UTC, `[start, end)`. Wraps past midnight if start > end.
+    #[serde(default)]
+    pub hours: Option<[u32; 2]>,
+    /// Applies once the GPU temperature exceeds this, until it drops
+    /// `hysteresis` degrees below it.
+    #[serde(default)]
+    pub temp_above: Option<f64>,
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: f64,
+    /// GPU indices the rule applies to; all if unset.
+    #[serde(default)]
+    pub gpus: Option<Vec<u32>>,
+    pub limit_watts: f64,
+}
+
+fn default_hysteresis() -> f64 {
+    5.0
+}
+
+impl PowerPolicy {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let policy: PowerPolicy = serde_json::from_slice(&contents)
+            .map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+        let invalid = |message: String| ConfigError::Invalid(path.to_path_buf(), message);
+        let limits = std::iter::once(policy.default_watts)
+            .chain(policy.node_budget_watts)
+            .chain(policy.rules.iter().map(|rule| rule.limit_watts));
+        for watts in limits {
+            if !(watts > 0.0 && watts.is_finite()) {
+                return Err(invalid(format!("expected a positive power: {}", watts)));
+            }
+        }
+        for rule in &policy.rules {
+            if let Some([start, end]) = rule.hours {
+                if start > 23 || end > 24 {
+                    return Err(invalid(format!("invalid hours: [{}, {}]", start, end)));
+                }
+            }
+        }
+        Ok(policy)
+    }
+}
+
+impl PowerRule {
+    fn describe(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("rule {}", index),
+        }
+    }
+
+    fn in_hours(&self, hour: u32) -> bool {
+        match self.hours {
+            Some([start, end]) if start <= end => (start..end).contains(&hour),
+            Some([start, end]) => hour >= start || hour < end,
+            None => true,
+        }
+    }
+}
+
+/// A power limit the policy wants applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerLimitChange {
+    pub gpu: u32,
+    pub watts: f64,
+    /// Which rules produced the limit, for logging.
+    pub reason: String,
+}
+
+/// Evaluates a `PowerPolicy` against samples, remembering what was applied
+/// so limits only change when the policy's decision does.
+pub struct PowerPolicyEngine {
+    policy: PowerPolicy,
+    /// (rule, GPU) pairs whose temperature condition is currently active.
+    hot: HashSet<(usize, u32)>,
+    applied: HashMap<u32, f64>,
+    /// Lowest and highest limit each GPU accepts, by index.
+    limit_ranges: Option<Vec<Option<(f64, f64)>>>,
+}
+
+impl PowerPolicyEngine {
+    pub fn new(policy: PowerPolicy) -> Self {
+        PowerPolicyEngine {
+            policy,
+            hot: HashSet::new(),
+            applied: HashMap::new(),
+            limit_ranges: None,
+        }
+    }
+
+    /// Whether `set_limit_ranges` is yet to be called.
+    pub fn needs_limit_ranges(&self) -> bool {
+        self.limit_ranges.is_none()
+    }
+
+    /// Set the lowest and highest power limit each GPU accepts, by index, if known.
+    pub fn set_limit_ranges(&mut self, ranges: Vec<Option<(f64, f64)>>) {
+        self.limit_ranges = Some(ranges);
+    }
+
+    /// Record that `change` was applied, so it isn't returned again.
+    pub fn applied(&mut self, change: &PowerLimitChange) {
+        self.applied.insert(change.gpu, change.watts);
+    }
+
+    /// Replace the policy, e.g. after a reload. Limits are re-applied as needed.
+    pub fn set_policy(&mut self, policy: PowerPolicy) {
+        self.policy = policy;
+        self.hot.clear();
+    }
+
+    /// The limits a GPU accepts, or any positive limit if unknown.
+    fn limit_range(&self, gpu: u32) -> (f64, f64) {
+        self.limit_ranges
+            .as_ref()
+            .and_then(|ranges| *ranges.get(gpu as usize)?)
+            .unwrap_or((0.0, f64::INFINITY))
+    }
+
+    /// Work out the limits `metrics` calls for, returning those that differ
+    /// from what was last recorded with `applied`, so failed changes are
+    /// returned again.
+    pub fn evaluate(&mut self, metrics: &Metrics, now: SystemTime) -> Vec<PowerLimitChange> {
+        let Some(gpu_count) = metrics.get("_gpu.count").and_then(|v| v.as_u64()) else {
+            return Vec::new();
+        };
+        let hour = UtcDateTime::from_system_time(now).hour;
+
+        let mut targets = Vec::with_capacity(gpu_count as usize);
+        for gpu in 0..gpu_count as u32 {
+            let temp = metrics
+                .get(&format!("gpu.{}.temp", gpu))
+                .and_then(|v| v.as_f64());
+            let mut watts = self.policy.default_watts;
+            let mut reasons = Vec::new();
+            for (index, rule) in self.policy.rules.iter().enumerate() {
+                if rule.gpus.as_ref().is_some_and(|gpus| !gpus.contains(&gpu)) {
+                    continue;
+                }
+                if !rule.in_hours(hour) {
+                    continue;
+                }
+                if let Some(threshold) = rule.temp_above {
+                    let hot = match temp {
+                        Some(temp) if self.hot.contains(&(index, gpu)) => {
+                            temp > threshold - rule.hysteresis
+                        }
+                        Some(temp) => temp > threshold,
+                        None => self.hot.contains(&(index, gpu)),
+                    };
+                    if hot {
+                        self.hot.insert((index, gpu));
+                    } else {
+                        self.hot.remove(&(index, gpu));
+                        continue;
+                    }
+                }
+                if rule.limit_watts < watts {
+                    watts = rule.limit_watts;
+                }
+                reasons.push(rule.describe(index));
+            }
+            let (min, max) = self.limit_range(gpu);
+            targets.push((gpu, watts.clamp(min, max), reasons));
+        }
+
+        if let Some(budget) = self.policy.node_budget_watts {
+            let total: f64 = targets.iter().map(|(_, watts, _)| watts).sum();
+            if total > budget {
+                let mut wanted: Vec<f64> = targets.iter().map(|(_, watts, _)| *watts).collect();
+                let mins: Vec<f64> = targets
+                    .iter()
+                    .map(|(gpu, ..)| self.limit_range(*gpu).0)
+                    .collect();
+                fit_budget(&mut wanted, &mins, budget);
+                for ((_, watts, reasons), fitted) in targets.iter_mut().zip(wanted) {
+                    *watts = fitted;
+                    reasons.push(format!("node budget {:.0} W", budget));
+                }
+            }
+        }
+
+        let mut changes = Vec::new();
+        for (gpu, watts, reasons) in targets {
+            let changed = self
+                .applied
+                .get(&gpu)
+                .is_none_or(|applied| (applied - watts).abs() >= MIN_CHANGE_WATTS);
+            if changed {
+                changes.push(PowerLimitChange {
+                    gpu,
+                    watts,
+                    reason: if reasons.is_empty() {
+                        "default".to_string()
+                    } else {
+                        reasons.join(", ")
+                    },
+                });
+            }
+        }
+        changes
+    }
+}
+
+/// Scale `watts` down proportionally until they add up to `budget`, holding
+/// each at its minimum in `mins` once it gets there. If the minimums alone
+/// exceed the budget, every GPU gets its minimum.
+fn fit_budget(watts: &mut [f64], mins: &[f64], budget: f64) {
+    let mut pinned = vec![false; watts.len()];
+    loop {
+        let pinned_total: f64 = (0..watts.len())
+            .filter(|&i| pinned[i])
+            .map(|i| mins[i])
+            .sum();
+        let free_total: f64 = (0..watts.len())
+            .filter(|&i| !pinned[i])
+            .map(|i| watts[i])
+            .sum();
+        let scale = if free_total > 0.0 {
+            ((budget - pinned_total) / free_total).max(0.0)
+        } else {
+            0.0
+        };
+        let mut newly_pinned = false;
+        for i in 0..watts.len() {
+            if !pinned[i] && watts[i] * scale < mins[i] {
+                pinned[i] = true;
+                newly_pinned = true;
+            }
+        }
+        if !newly_pinned {
+            for i in 0..watts.len() {
+                watts[i] = if pinned[i] { mins[i] } else { watts[i] * scale };
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(budget: f64) -> PowerPolicy {
+        PowerPolicy {
+            default_watts: 300.0,
+            node_budget_watts: Some(budget),
+            rules: Vec::new(),
+        }
+    }
+
+    fn sample(gpus: u32) -> Metrics {
+        let mut metrics = Metrics::new();
+        metrics.add_metric("_gpu.count", gpus);
+        metrics
+    }
+
+    #[test]
+    fn fits_the_budget_above_minimum_limits() {
+        let mut watts = [300.0, 300.0, 100.0];
+        fit_budget(&mut watts, &[150.0, 100.0, 100.0], 400.0);
+        assert_eq!(watts, [150.0, 150.0, 100.0]);
+
+        let mut watts = [300.0, 300.0];
+        fit_budget(&mut watts, &[150.0, 150.0], 200.0);
+        assert_eq!(watts, [150.0, 150.0]);
+    }
+
+    #[test]
+    fn returns_changes_until_applied() {
+        let mut engine = PowerPolicyEngine::new(policy(400.0));
+        engine.set_limit_ranges(vec![Some((100.0, 250.0)), None]);
+        let now = SystemTime::now();
+        let changes = engine.evaluate(&sample(2), now);
+        assert_eq!(changes.len(), 2);
+        // GPU 0 is held to its 250 W maximum before both are scaled to fit
+        assert_eq!(changes[0].watts, 250.0 * 400.0 / 550.0);
+        assert_eq!(engine.evaluate(&sample(2), now), changes);
+        engine.applied(&changes[0]);
+        assert_eq!(engine.evaluate(&sample(2), now), changes[1..]);
+    }
+}
@@ -0,0 +1,167 @@
+use crate::docker;
+use crate::metrics::{Metrics, SampleTime};
+use crate::sampler::Sampler;
+use std::fs;
+use std::io;
+
+/// Device nodes of the local BMC's IPMI driver, as `ipmitool` looks for them.
+#[cfg(unix)]
+pub(crate) const IPMI_DEVICES: [&str; 3] = ["/dev/ipmi0", "/dev/ipmi/0", "/dev/ipmidev/0"];
+
+/// Something the agent can do only with more privileges than sampling needs.
+pub struct Capability {
+    /// Name in the capability report, e.g. `gpuSettings`.
+    pub name: &'static str,
+    /// What needs it, for log messages.
+    pub used_by: &'static str,
+    pub available: bool,
+}
+
+/// What the agent can do with the privileges it runs with. Sampling itself
+/// only needs access to `/dev/nvidia*`; everything listed here degrades to
+/// missing metrics or failed operations without it.
+pub struct CapabilityReport {
+    pub uid: Option<u32>,
+    pub capabilities: Vec<Capability>,
+}
+
+impl CapabilityReport {
+    /// Probe the capabilities of the current process.
+    pub fn probe(sampler: &mut Sampler) -> Self {
+        let root = is_root();
+        let mut capabilities = vec![
+            Capability {
+                name: "gpuSettings",
+                used_by: "power limits, power policies, clock locks and persistence mode",
+                available: root,
+            },
+            Capability {
+                name: "fanControl",
+                used_by: "fan curves",
+                available: root,
+            },
+            Capability {
+                name: "dockerContainers",
+                used_by: "container names of GPU processes",
+                available: fs::read_dir(docker::CONTAINERS).is_ok(),
+            },
+            Capability {
+                name: "ipmi",
+                used_by: "--bmc ipmi",
+                available: ipmi_accessible(),
+            },
+        ];
+        // Only reported where a GPU has accounting mode enabled
+        if let Ok(Some(readable)) = sampler.with_gpu(|nvidia_gpu| nvidia_gpu.accounting_access()) {
+            capabilities.push(Capability {
+                name: "accounting",
+                used_by: "gpu.process.*.accounting* metrics",
+                available: readable,
+            });
+        }
+        // Only reported where a GPU has ECC enabled
+        if let Ok(Some(readable)) = sampler.with_gpu(|nvidia_gpu| nvidia_gpu.ecc_access()) {
+            capabilities.push(Capability {
+                name: "ecc",
+                used_by: "_gpu.*.correctedMemoryErrors and uncorrectedMemoryErrors",
+                available: readable,
+            });
+        }
+        CapabilityReport {
+            uid: uid(),
+            capabilities,
+        }
+    }
+
+    pub fn unavailable(&self) -> impl Iterator<Item = &Capability> {
+        self.capabilities.iter().filter(|c| !c.available)
+    }
+
+    /// A `capabilities` record: `_capabilities.uid`, and whether each
+    /// capability is available, e.g. `_capabilities.gpuSettings`.
+    pub fn to_record(&self, time: SampleTime) -> Metrics {
+        let mut record = Metrics::new();
+        record.add_metric("_record", "capabilities");
+        if let Some(uid) = self.uid {
+            record.add_metric("_capabilities.uid", uid);
+        }
+        for capability in &self.capabilities {
+            let key: &'static str =
+                Box::leak(format!("_capabilities.{}", capability.name).into_boxed_str());
+            record.add_metric(key, capability.available);
+        }
+        record.set_time(time);
+        record
+    }
+}
+
+#[cfg(unix)]
+fn uid() -> Option<u32> {
+    Some(nix::unistd::geteuid().as_raw())
+}
+
+#[cfg(not(unix))]
+fn uid() -> Option<u32> {
+    None
+}
+
+/// Whether the process runs as root.
+#[cfg(unix)]
+pub fn is_root() -> bool {
+    nix::unistd::geteuid().is_root()
+}
+
+/// Privileges aren't dropped on Windows, so the service is assumed to run as
+/// an administrator.
+#[cfg(not(unix))]
+pub fn is_root() -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn ipmi_accessible() -> bool {
+    IPMI_DEVICES.iter().any(|path| {
+        fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .is_ok()
+    })
+}
+
+#[cfg(not(unix))]
+fn ipmi_accessible() -> bool {
+    false
+}
+
+/// Switch to `user` and their primary group for good, dropping supplementary
+/// groups. Files and sockets opened before keep working; anything opened
+/// later, such as rotated files, needs permissions for `user`.
+#[cfg(unix)]
+pub fn drop_to(user: &str) -> io::Result<()> {
+    use nix::unistd::{setgid, setgroups, setuid, Uid, User};
+
+    let user = User::from_name(user)
+        .map_err(io::Error::from)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no user {:?}", user)))?;
+    setgroups(&[user.gid]).map_err(io::Error::from)?;
+    setgid(user.gid).map_err(io::Error::from)?;
+    setuid(user.uid).map_err(io::Error::from)?;
+    // Make sure there's no way back
+    if !user.uid.is_root() && setuid(Uid::from_raw(0)).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "root privileges could be regained after dropping them",
+        ));
+    }
+    Ok(())
+}
+
+/// Privileges are only dropped on Unix.
+#[cfg(not(unix))]
+pub fn drop_to(_user: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "dropping privileges is only supported on Unix",
+    ))
+}
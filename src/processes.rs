@@ -0,0 +1,158 @@
+use crate::cgroup::{self, ContainerRef};
+use nvml_wrapper::enums::device::UsedGpuMemory;
+use nvml_wrapper::Device;
+use serde::Serialize;
+use std::collections::BTreeMap;
+#[cfg(feature = "processes")]
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind, Users};
+
+/// A process running on a GPU, as seen by the driver.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GpuProcess {
+    pub pid: u32,
+    /// "compute", "graphics" or "compute+graphics".
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// Name of the user owning the process, or its user ID if it has no name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Command line, or the executable name if the command line isn't readable.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// GPU memory used by the process, if the driver reports it (not under WDDM).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<u64>,
+    /// Share of SM time used by the process (in percentage).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sm_utilization: Option<u32>,
+    /// Share of memory bandwidth used by the process (in percentage).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_utilization: Option<u32>,
+    /// The container the process runs in, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container: Option<ContainerRef>,
+}
+
+/// GPU usage of all processes of one user on a device.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserUsage {
+    /// User name, or "unknown" if the owner couldn't be determined.
+    pub user: String,
+    pub processes: u32,
+    /// Sum of the GPU memory used by the user's processes (in bytes).
+    pub memory_bytes: u64,
+    /// Sum of the SM utilization of the user's processes (in percentage).
+    pub sm_utilization: u32,
+    /// Sum of the memory utilization of the user's processes (in percentage).
+    pub memory_utilization: u32,
+}
+
+/// List the compute and graphics processes of a device.
+///
+/// Utilization comes from the driver's per-process samples since `last_seen`,
+/// which is advanced to the newest one. A process that is both a compute and
+/// a graphics process is listed once. Containers are identified by ID only.
+pub fn collect(device: &Device, last_seen: &mut Option<u64>) -> Vec<GpuProcess> {
+    let mut processes: BTreeMap<u32, GpuProcess> = BTreeMap::new();
+    let compute = device.running_compute_processes().unwrap_or_default();
+    let graphics = device.running_graphics_processes().unwrap_or_default();
+    let running = compute
+        .into_iter()
+        .map(|info| (info, "compute"))
+        .chain(graphics.into_iter().map(|info| (info, "graphics")));
+    for (info, kind) in running {
+        let process = processes.entry(info.pid).or_insert_with(|| GpuProcess {
+            pid: info.pid,
+            kind,
+            container: cgroup::container_of(info.pid),
+            ..GpuProcess::default()
+        });
+        if process.kind != kind {
+            process.kind = "compute+graphics";
+        }
+        if let UsedGpuMemory::Used(bytes) = info.used_gpu_memory {
+            process.memory_bytes = Some(process.memory_bytes.unwrap_or(0).max(bytes));
+        }
+    }
+    if processes.is_empty() {
+        return Vec::new();
+    }
+
+    // Fails with NotFound when no process ran since `last_seen`
+    let mut samples = device
+        .process_utilization_stats(*last_seen)
+        .unwrap_or_default();
+    samples.sort_by_key(|sample| sample.timestamp);
+    for sample in &samples {
+        if let Some(process) = processes.get_mut(&sample.pid) {
+            process.sm_utilization = Some(sample.sm_util);
+            process.memory_utilization = Some(sample.mem_util);
+        }
+    }
+    if let Some(newest) = samples.last() {
+        *last_seen = Some(newest.timestamp);
+    }
+
+    processes.into_values().collect()
+}
+
+/// Fill in the user and command line of each process.
+#[cfg(feature = "processes")]
+pub fn describe(processes: &mut [GpuProcess]) {
+    if processes.is_empty() {
+        return;
+    }
+    let pids: Vec<Pid> = processes.iter().map(|p| Pid::from_u32(p.pid)).collect();
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&pids),
+        ProcessRefreshKind::new()
+            .with_user(UpdateKind::Always)
+            .with_cmd(UpdateKind::Always),
+    );
+    let users = Users::new_with_refreshed_list();
+
+    for process in processes {
+        let Some(info) = system.process(Pid::from_u32(process.pid)) else {
+            continue;
+        };
+        process.user = info.user_id().map(|uid| match users.get_user_by_id(uid) {
+            Some(user) => user.name().to_string(),
+            None => (**uid).to_string(),
+        });
+        let command = info
+            .cmd()
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        process.command = Some(if command.is_empty() {
+            info.name().to_string_lossy().into_owned()
+        } else {
+            command
+        });
+    }
+}
+
+/// Users and command lines are only read with the `processes` feature.
+#[cfg(not(feature = "processes"))]
+pub fn describe(_processes: &mut [GpuProcess]) {}
+
+/// Group processes by user. Requires `describe` to have run first.
+pub fn usage_by_user(processes: &[GpuProcess]) -> Vec<UserUsage> {
+    let mut usage: BTreeMap<&str, UserUsage> = BTreeMap::new();
+    for process in processes {
+        let user = process.user.as_deref().unwrap_or("unknown");
+        let entry = usage.entry(user).or_insert_with(|| UserUsage {
+            user: user.to_string(),
+            ..UserUsage::default()
+        });
+        entry.processes += 1;
+        entry.memory_bytes += process.memory_bytes.unwrap_or(0);
+        entry.sm_utilization += process.sm_utilization.unwrap_or(0);
+        entry.memory_utilization += process.memory_utilization.unwrap_or(0);
+    }
+    usage.into_values().collect()
+}
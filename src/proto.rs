@@ -0,0 +1,240 @@
+//! Protobuf messages for samples and events, defined in `proto/symon.proto`.
+//!
+//! `proto/symon.v1.rs` is generated from the schema with prost-build and
+//! checked in, so building symon doesn't need `protoc`. Regenerate it after
+//! changing the schema.
+
+use crate::metrics::Metrics;
+use crate::schema;
+use crate::subscribers::Event;
+use serde_json::Value;
+
+#[allow(clippy::all)]
+pub mod v1 {
+    include!("proto/symon.v1.rs");
+}
+
+/// Convert a sample; `_timestamp` and `_schema_version` become fields. Event
+/// records (`"_record": "event"`) are converted to an `Event` instead.
+pub fn sample(metrics: &Metrics) -> v1::Sample {
+    let timestamp = metrics.timestamp().unwrap_or_default();
+    if metrics.get("_record").and_then(Value::as_str) == Some("event") {
+        return v1::Sample {
+            schema_version: schema::VERSION,
+            timestamp,
+            metrics: Vec::new(),
+            event: Some(event_record(metrics, timestamp)),
+        };
+    }
+    v1::Sample {
+        schema_version: schema::VERSION,
+        timestamp,
+        metrics: metrics_of(metrics, &["_timestamp", "_schema_version"]),
+        event: None,
+    }
+}
+
+/// Convert an event record, using the typed form of events that have one.
+fn event_record(metrics: &Metrics, timestamp: f64) -> v1::Event {
+    let name = metrics
+        .get("_event")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    if name == "power_limit" {
+        let gpu = metrics.get("gpu").and_then(Value::as_u64);
+        let watts = metrics.get("limitWatts").and_then(Value::as_f64);
+        let reason = metrics.get("reason").and_then(Value::as_str);
+        if let (Some(gpu), Some(watts), Some(reason)) = (gpu, watts, reason) {
+            let changed = Event::PowerLimitChanged {
+                gpu: gpu as u32,
+                watts,
+                reason: reason.to_string(),
+            };
+            return event(&changed, timestamp);
+        }
+    }
+    v1::Event {
+        schema_version: schema::VERSION,
+        timestamp,
+        kind: Some(v1::event::Kind::Named(v1::NamedEvent {
+            name: name.to_string(),
+            fields: metrics_of(
+                metrics,
+                &["_timestamp", "_schema_version", "_record", "_event"],
+            ),
+        })),
+    }
+}
+
+fn metrics_of(metrics: &Metrics, skipped: &[&str]) -> Vec<v1::Metric> {
+    let mut converted = Vec::with_capacity(metrics.len());
+    metrics.for_each(|key, value| {
+        if !skipped.contains(&key.as_ref()) {
+            converted.push(v1::Metric {
+                key: key.to_string(),
+                value: Some(value_of(value)),
+            });
+        }
+    });
+    converted
+}
+
+/// Convert a decoded sample or event record back to the metrics it was
+/// written from, e.g. to read a trace.
+pub fn metrics(sample: v1::Sample) -> Metrics {
+    let mut metrics = Metrics::new();
+    metrics.add_metric("_timestamp", sample.timestamp);
+    metrics.add_metric("_schema_version", sample.schema_version);
+    let Some(event) = sample.event else {
+        add_metrics(&mut metrics, sample.metrics);
+        return metrics;
+    };
+    use v1::event::Kind;
+    metrics.add_metric("_record", "event");
+    match event.kind {
+        Some(Kind::Named(named)) => {
+            metrics.add_metric("_event", named.name);
+            add_metrics(&mut metrics, named.fields);
+        }
+        Some(Kind::PowerLimitChanged(changed)) => {
+            metrics.add_metric("_event", "power_limit");
+            metrics.add_metric("gpu", changed.gpu);
+            metrics.add_metric("limitWatts", changed.watts);
+            metrics.add_metric("reason", changed.reason);
+        }
+        Some(Kind::SamplingTimedOut(_)) => metrics.add_metric("_event", "sampling_timed_out"),
+        Some(Kind::SamplingFailed(failed)) => {
+            metrics.add_metric("_event", "sampling_failed");
+            metrics.add_metric("error", failed.error);
+        }
+        None => {}
+    }
+    metrics
+}
+
+fn add_metrics(metrics: &mut Metrics, converted: Vec<v1::Metric>) {
+    for metric in converted {
+        let value = metric.value.map_or(Value::Null, json_of);
+        metrics.add_metric(metric.key, value);
+    }
+}
+
+/// Convert an event that happened at `timestamp` (seconds since the epoch).
+pub fn event(event: &Event, timestamp: f64) -> v1::Event {
+    use v1::event::Kind;
+    let kind = match event {
+        Event::SamplingTimedOut => Kind::SamplingTimedOut(v1::SamplingTimedOut {}),
+        Event::SamplingFailed(error) => Kind::SamplingFailed(v1::SamplingFailed {
+            error: error.clone(),
+        }),
+        Event::PowerLimitChanged { gpu, watts, reason } => {
+            Kind::PowerLimitChanged(v1::PowerLimitChanged {
+                gpu: *gpu,
+                watts: *watts,
+                reason: reason.clone(),
+            })
+        }
+    };
+    v1::Event {
+        schema_version: schema::VERSION,
+        timestamp,
+        kind: Some(kind),
+    }
+}
+
+fn value_of(value: &Value) -> v1::Value {
+    use v1::value::Kind;
+    let kind = match value {
+        Value::Null => None,
+        Value::Bool(b) => Some(Kind::Flag(*b)),
+        Value::Number(n) => Some(if let Some(u) = n.as_u64() {
+            Kind::Unsigned(u)
+        } else if let Some(i) = n.as_i64() {
+            Kind::Signed(i)
+        } else {
+            Kind::Number(n.as_f64().unwrap_or(f64::NAN))
+        }),
+        Value::String(s) => Some(Kind::Text(s.clone())),
+        Value::Array(items) => Some(Kind::List(v1::ValueList {
+            values: items.iter().map(value_of).collect(),
+        })),
+        Value::Object(_) => Some(Kind::Json(value.to_string())),
+    };
+    v1::Value { kind }
+}
+
+fn json_of(value: v1::Value) -> Value {
+    use v1::value::Kind;
+    match value.kind {
+        None => Value::Null,
+        Some(Kind::Flag(b)) => Value::Bool(b),
+        Some(Kind::Unsigned(u)) => Value::from(u),
+        Some(Kind::Signed(i)) => Value::from(i),
+        Some(Kind::Number(n)) => serde_json::Number::from_f64(n).map_or(Value::Null, Value::Number),
+        Some(Kind::Text(s)) => Value::String(s),
+        Some(Kind::List(list)) => Value::Array(list.values.into_iter().map(json_of).collect()),
+        Some(Kind::Json(json)) => serde_json::from_str(&json).unwrap_or(Value::String(json)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn json_line(metrics: &Metrics) -> String {
+        let mut line = Vec::new();
+        metrics.to_json_line(&mut line).unwrap();
+        String::from_utf8(line).unwrap()
+    }
+
+    #[test]
+    fn converts_samples_back() {
+        let mut sample_metrics = Metrics::new();
+        sample_metrics.add_metric("_timestamp", 1700000000.5);
+        sample_metrics.add_metric("_schema_version", schema::VERSION);
+        sample_metrics.add_metric("gpu.0.temp", 60);
+        sample_metrics.add_metric("gpu.0.name", "Tesla T4");
+        sample_metrics.add_metric("gpu.0.processes", json!([{"pid": 7}]));
+        let converted = sample(&sample_metrics);
+        assert!(converted.event.is_none());
+        assert_eq!(json_line(&metrics(converted)), json_line(&sample_metrics));
+    }
+
+    #[test]
+    fn converts_event_records_to_events() {
+        let mut record = Metrics::new();
+        record.add_metric("_timestamp", 1700000000.5);
+        record.add_metric("_schema_version", schema::VERSION);
+        record.add_metric("_record", "event");
+        record.add_metric("_event", "power_limit");
+        record.add_metric("gpu", 1);
+        record.add_metric("limitWatts", 250.0);
+        record.add_metric("reason", "budget");
+        let converted = sample(&record);
+        assert!(converted.metrics.is_empty());
+        let kind = converted
+            .event
+            .as_ref()
+            .and_then(|event| event.kind.clone());
+        assert!(matches!(
+            kind,
+            Some(v1::event::Kind::PowerLimitChanged(v1::PowerLimitChanged {
+                gpu: 1,
+                ..
+            }))
+        ));
+        assert_eq!(json_line(&metrics(converted)), json_line(&record));
+
+        record.add_metric("_event", "marker");
+        let converted = sample(&record);
+        let kind = converted
+            .event
+            .as_ref()
+            .and_then(|event| event.kind.clone());
+        assert!(
+            matches!(kind, Some(v1::event::Kind::Named(v1::NamedEvent { ref name, .. })) if name == "marker")
+        );
+        assert_eq!(json_line(&metrics(converted)), json_line(&record));
+    }
+}
@@ -0,0 +1,109 @@
+// This file is @generated by prost-build.
+/// A metric value. Unset for JSON null.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Value {
+    #[prost(oneof = "value::Kind", tags = "1, 2, 3, 4, 5, 6, 7")]
+    pub kind: ::core::option::Option<value::Kind>,
+}
+/// Nested message and enum types in `Value`.
+pub mod value {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Kind {
+        #[prost(double, tag = "1")]
+        Number(f64),
+        #[prost(uint64, tag = "2")]
+        Unsigned(u64),
+        #[prost(sint64, tag = "3")]
+        Signed(i64),
+        #[prost(string, tag = "4")]
+        Text(::prost::alloc::string::String),
+        #[prost(bool, tag = "5")]
+        Flag(bool),
+        #[prost(message, tag = "6")]
+        List(super::ValueList),
+        /// Nested objects, as JSON.
+        #[prost(string, tag = "7")]
+        Json(::prost::alloc::string::String),
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueList {
+    #[prost(message, repeated, tag = "1")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Metric {
+    /// Flat key such as `gpu.0.temperature`.
+    #[prost(string, tag = "1")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub value: ::core::option::Option<Value>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Sample {
+    /// Version of the record format (`_schema_version` in JSON), see
+    /// `symon schema`.
+    #[prost(uint32, tag = "1")]
+    pub schema_version: u32,
+    /// Seconds since the Unix epoch.
+    #[prost(double, tag = "2")]
+    pub timestamp: f64,
+    /// Sorted by key.
+    #[prost(message, repeated, tag = "3")]
+    pub metrics: ::prost::alloc::vec::Vec<Metric>,
+    /// Set instead of metrics for event records (`"_record": "event"` in JSON).
+    #[prost(message, optional, tag = "4")]
+    pub event: ::core::option::Option<Event>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Event {
+    #[prost(uint32, tag = "1")]
+    pub schema_version: u32,
+    #[prost(double, tag = "2")]
+    pub timestamp: f64,
+    #[prost(oneof = "event::Kind", tags = "3, 4, 5, 6")]
+    pub kind: ::core::option::Option<event::Kind>,
+}
+/// Nested message and enum types in `Event`.
+pub mod event {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Kind {
+        /// NVML did not return within the sampling timeout.
+        #[prost(message, tag = "3")]
+        SamplingTimedOut(super::SamplingTimedOut),
+        /// NVML returned an error; the sample may be incomplete.
+        #[prost(message, tag = "4")]
+        SamplingFailed(super::SamplingFailed),
+        /// The power policy changed a GPU's power limit.
+        #[prost(message, tag = "5")]
+        PowerLimitChanged(super::PowerLimitChanged),
+        /// Any other event record, e.g. `placement` or `marker`.
+        #[prost(message, tag = "6")]
+        Named(super::NamedEvent),
+    }
+}
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct SamplingTimedOut {}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SamplingFailed {
+    #[prost(string, tag = "1")]
+    pub error: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PowerLimitChanged {
+    #[prost(uint32, tag = "1")]
+    pub gpu: u32,
+    #[prost(double, tag = "2")]
+    pub watts: f64,
+    #[prost(string, tag = "3")]
+    pub reason: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct NamedEvent {
+    /// `_event` in JSON.
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    /// Sorted by key.
+    #[prost(message, repeated, tag = "2")]
+    pub fields: ::prost::alloc::vec::Vec<Metric>,
+}
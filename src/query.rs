@@ -0,0 +1,235 @@
+use crate::metrics::Metrics;
+use crate::timefmt;
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+
+/// How to reduce the selected samples of each metric.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregation {
+    Count,
+    Min,
+    Max,
+    Mean,
+    Sum,
+    First,
+    Last,
+    /// Nearest-rank percentile, 0-100.
+    Percentile(f64),
+}
+
+impl Aggregation {
+    /// Parse `count`, `min`, `max`, `mean`, `sum`, `first`, `last` or `pNN`, e.g. `p95`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        Ok(match s {
+            "count" => Aggregation::Count,
+            "min" => Aggregation::Min,
+            "max" => Aggregation::Max,
+            "mean" | "avg" => Aggregation::Mean,
+            "sum" => Aggregation::Sum,
+            "first" => Aggregation::First,
+            "last" => Aggregation::Last,
+            _ => match s.strip_prefix('p').and_then(|p| p.parse::<f64>().ok()) {
+                Some(p) if (0.0..=100.0).contains(&p) => Aggregation::Percentile(p),
+                _ => return Err(format!("unknown aggregation: {:?}", s)),
+            },
+        })
+    }
+
+    /// Reduce `values`, which are in time order.
+    pub(crate) fn apply(self, values: &mut [f64]) -> Option<f64> {
+        if values.is_empty() {
+            return (self == Aggregation::Count).then_some(0.0);
+        }
+        Some(match self {
+            Aggregation::Count => values.len() as f64,
+            Aggregation::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            Aggregation::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            Aggregation::Mean => values.iter().sum::<f64>() / values.len() as f64,
+            Aggregation::Sum => values.iter().sum(),
+            Aggregation::First => values[0],
+            Aggregation::Last => values[values.len() - 1],
+            Aggregation::Percentile(p) => {
+                values.sort_by(f64::total_cmp);
+                let rank = ((p / 100.0) * values.len() as f64).ceil() as usize;
+                values[rank.clamp(1, values.len()) - 1]
+            }
+        })
+    }
+}
+
+/// Output format of `symon query`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum QueryFormat {
+    Csv,
+    Json,
+}
+
+/// Parse a time bound given as Unix epoch seconds or an RFC 3339 timestamp.
+pub fn parse_time(s: &str) -> Result<f64, String> {
+    s.parse::<f64>()
+        .ok()
+        .or_else(|| timefmt::parse_rfc3339(s))
+        .ok_or_else(|| format!("invalid time {:?}: expected epoch seconds or RFC 3339", s))
+}
+
+/// Selects metrics by name from a time range of recorded samples.
+///
+/// Metric patterns may contain `*` wildcards, e.g. `gpu.*.powerWatts`.
+pub struct Query {
+    pub patterns: Vec<String>,
+    pub from: Option<f64>,
+    pub to: Option<f64>,
+    pub aggregation: Option<Aggregation>,
+    pub format: QueryFormat,
+}
+
+impl Query {
+    /// Run the query over `samples`, writing results to `out`.
+    pub fn run(
+        &self,
+        samples: impl Iterator<Item = io::Result<Metrics>>,
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        // Matched metric names in order of first appearance, with their values
+        let mut series: Vec<(String, Vec<f64>)> = Vec::new();
+        let mut rows: Vec<(f64, Vec<(usize, Value)>)> = Vec::new();
+
+        for metrics in samples {
+            let metrics = metrics?;
+            let Some(timestamp) = metrics.timestamp() else {
+                continue;
+            };
+            if self.from.is_some_and(|from| timestamp < from)
+                || self.to.is_some_and(|to| timestamp > to)
+            {
+                continue;
+            }
+            let mut row = Vec::new();
+            metrics.for_each(|key, value| {
+                if !self.patterns.iter().any(|p| glob_match(p, key)) {
+                    return;
+                }
+                let column = match series.iter().position(|(name, _)| name == key.as_ref()) {
+                    Some(column) => column,
+                    None => {
+                        series.push((key.to_string(), Vec::new()));
+                        series.len() - 1
+                    }
+                };
+                if let Some(number) = value.as_f64() {
+                    series[column].1.push(number);
+                }
+                row.push((column, value.clone()));
+            });
+            if self.aggregation.is_none() && !row.is_empty() {
+                rows.push((timestamp, row));
+            }
+        }
+
+        match self.aggregation {
+            Some(aggregation) => self.write_aggregates(aggregation, &mut series, out),
+            None => self.write_rows(&series, &rows, out),
+        }
+    }
+
+    fn write_aggregates(
+        &self,
+        aggregation: Aggregation,
+        series: &mut [(String, Vec<f64>)],
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        match self.format {
+            QueryFormat::Csv => {
+                writeln!(out, "metric,value")?;
+                for (name, values) in series.iter_mut() {
+                    let value = aggregation.apply(values);
+                    writeln!(out, "{},{}", csv_field(name), format_number(value))?;
+                }
+            }
+            QueryFormat::Json => {
+                let mut result = Map::new();
+                for (name, values) in series.iter_mut() {
+                    let value = aggregation.apply(values);
+                    result.insert(name.clone(), value.into());
+                }
+                serde_json::to_writer(&mut *out, &result)?;
+                writeln!(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_rows(
+        &self,
+        series: &[(String, Vec<f64>)],
+        rows: &[(f64, Vec<(usize, Value)>)],
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        match self.format {
+            QueryFormat::Csv => {
+                write!(out, "_timestamp")?;
+                for (name, _) in series {
+                    write!(out, ",{}", csv_field(name))?;
+                }
+                writeln!(out)?;
+                let mut cells = vec![String::new(); series.len()];
+                for (timestamp, row) in rows {
+                    cells.iter_mut().for_each(String::clear);
+                    for (column, value) in row {
+                        cells[*column] = match value {
+                            Value::String(s) => csv_field(s),
+                            other => other.to_string(),
+                        };
+                    }
+                    writeln!(out, "{},{}", timestamp, cells.join(","))?;
+                }
+            }
+            QueryFormat::Json => {
+                for (timestamp, row) in rows {
+                    let mut object = Map::new();
+                    object.insert("_timestamp".to_string(), (*timestamp).into());
+                    for (column, value) in row {
+                        object.insert(series[*column].0.clone(), value.clone());
+                    }
+                    serde_json::to_writer(&mut *out, &object)?;
+                    writeln!(out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn format_number(value: Option<f64>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// Quote a CSV field if needed.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Match `name` against a pattern where `*` matches any run of characters.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard: exact match
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
@@ -0,0 +1,686 @@
+use crate::histogram::{Histogram, DEFAULT_PERCENTILES};
+use crate::metrics::Metrics;
+use crate::residency::{self, Residency, StateTime};
+use crate::timefmt::UtcDateTime;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, UNIX_EPOCH};
+
+const GIB: f64 = (1u64 << 30) as f64;
+
+/// Minimum, maximum and mean of a series of readings.
+#[derive(Clone, Copy, Default)]
+struct Stats {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Stats {
+    fn add(&mut self, value: f64) {
+        if self.count == 0 || value < self.min {
+            self.min = value;
+        }
+        if self.count == 0 || value > self.max {
+            self.max = value;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// First and last reading of a counter.
+#[derive(Clone, Copy, Default)]
+struct Span {
+    first: Option<f64>,
+    last: f64,
+}
+
+impl Span {
+    fn add(&mut self, value: f64) {
+        self.first.get_or_insert(value);
+        self.last = value;
+    }
+
+    /// Increase over the run, ignoring counter resets.
+    fn increase(&self) -> Option<f64> {
+        self.first.map(|first| (self.last - first).max(0.0))
+    }
+}
+
+#[derive(Default)]
+struct GpuSummary {
+    name: Option<String>,
+    utilization: Stats,
+    memory_allocated: Stats,
+    memory_allocated_bytes: Stats,
+    memory_total: Option<f64>,
+    power: Stats,
+    power_limit: Stats,
+    temperature: Stats,
+    /// Distributions for percentiles; mean power alone understates the
+    /// power a node needs to be provisioned for.
+    utilization_histogram: Histogram,
+    power_histogram: Histogram,
+    temperature_histogram: Histogram,
+    sm_clock: Stats,
+    energy: Span,
+    /// Energy from integrating power over time, for GPUs without an energy counter.
+    integrated_energy: f64,
+    last_power: Option<(f64, f64)>,
+    corrected_errors: Span,
+    uncorrected_errors: Span,
+    pcie_replays: Span,
+    /// Lifetime statistics of the monitored processes from accounting mode.
+    accounting: Option<Accounting>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Accounting {
+    max_memory_bytes: f64,
+    gpu: f64,
+    memory: f64,
+    time_ms: f64,
+}
+
+impl GpuSummary {
+    fn energy_joules(&self) -> f64 {
+        self.energy.increase().unwrap_or(self.integrated_energy)
+    }
+}
+
+/// Aggregates over the samples taken during one named phase, across GPUs.
+#[derive(Default)]
+struct PhaseSummary {
+    /// How often the phase started.
+    occurrences: u64,
+    seconds: f64,
+    utilization: Stats,
+    energy_joules: f64,
+    memory_allocated_bytes: Stats,
+    /// GPU time of the tracked processes: the increase of their accounting
+    /// time, and their utilization integrated over the phase as a fallback
+    /// where accounting mode is off.
+    process_accounting_ms: Option<f64>,
+    process_busy_seconds: Option<f64>,
+}
+
+impl PhaseSummary {
+    /// Seconds the tracked processes kept the GPUs busy, if known.
+    fn process_gpu_seconds(&self) -> Option<f64> {
+        self.process_accounting_ms
+            .map(|ms| ms / 1000.0)
+            .or(self.process_busy_seconds)
+    }
+}
+
+/// Which direction of change is an improvement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Better {
+    Higher,
+    Lower,
+}
+
+/// A single headline number of a run, used to compare runs.
+pub struct Aggregate {
+    pub name: String,
+    pub value: f64,
+    pub better: Better,
+}
+
+/// Summary of a monitoring run, built incrementally from samples.
+pub struct Report {
+    samples: u64,
+    first_timestamp: Option<f64>,
+    last_timestamp: f64,
+    intervals: Stats,
+    gaps: u64,
+    sampling_timeouts: u64,
+    dropped_samples: Span,
+    gpus: BTreeMap<u32, GpuSummary>,
+    /// The phase started by the last `<name> start` marker, until `<name> end`.
+    phase: Option<usize>,
+    /// Phases in order of first appearance.
+    phases: Vec<(String, PhaseSummary)>,
+    residency: Residency,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Report::with_thresholds(residency::DEFAULT_THRESHOLDS.to_vec())
+    }
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Report::default()
+    }
+
+    /// A report with time above each of the utilization `thresholds` (in percentage).
+    pub fn with_thresholds(thresholds: Vec<f64>) -> Self {
+        Report {
+            samples: 0,
+            first_timestamp: None,
+            last_timestamp: 0.0,
+            intervals: Stats::default(),
+            gaps: 0,
+            sampling_timeouts: 0,
+            dropped_samples: Span::default(),
+            gpus: BTreeMap::new(),
+            phase: None,
+            phases: Vec::new(),
+            residency: Residency::new(thresholds),
+        }
+    }
+
+    pub fn add(&mut self, metrics: &Metrics) {
+        let Some(timestamp) = metrics.timestamp() else {
+            return;
+        };
+        if let Some(marker) = metrics.get("_marker").and_then(|v| v.as_str()) {
+            self.marker(marker);
+            return;
+        }
+        // One-off metadata records aren't samples
+        if metrics.get("_record").is_some() {
+            return;
+        }
+        self.samples += 1;
+        self.residency.add(metrics);
+        let mut phase = self.phase.map(|i| &mut self.phases[i].1);
+        let previous = self.first_timestamp.map(|_| self.last_timestamp);
+        if let Some(previous) = previous {
+            let interval = timestamp - previous;
+            if let Some(phase) = phase.as_mut() {
+                phase.seconds += interval;
+            }
+            // Anything much longer than the typical interval means samples are missing
+            if self.intervals.count >= 10 && interval > 3.0 * self.intervals.mean() {
+                self.gaps += 1;
+            }
+            self.intervals.add(interval);
+        }
+        self.first_timestamp.get_or_insert(timestamp);
+        self.last_timestamp = timestamp;
+
+        if metrics.get("_sampling_timeout").is_some() {
+            self.sampling_timeouts += 1;
+        }
+        if let Some(dropped) = metrics
+            .get("_agent.droppedSamples")
+            .and_then(|v| v.as_f64())
+        {
+            self.dropped_samples.add(dropped);
+        }
+
+        metrics.for_each(|key, value| {
+            if let Some((index, field)) = process_field(key) {
+                let Some(value) = value.as_f64() else {
+                    return;
+                };
+                if field == "gpu" {
+                    if let (Some(phase), Some(previous)) = (phase.as_mut(), previous) {
+                        *phase.process_busy_seconds.get_or_insert(0.0) +=
+                            value / 100.0 * (timestamp - previous);
+                    }
+                    return;
+                }
+                if !field.starts_with("accounting") {
+                    return;
+                }
+                let gpu = self.gpus.entry(index).or_default();
+                let seen = gpu.accounting.is_some();
+                let accounting = gpu.accounting.get_or_insert_with(Accounting::default);
+                if field == "accountingTimeMs" && seen {
+                    if let Some(phase) = phase.as_mut() {
+                        *phase.process_accounting_ms.get_or_insert(0.0) +=
+                            (value - accounting.time_ms).max(0.0);
+                    }
+                }
+                // Accounting values only grow over a process's lifetime
+                match field {
+                    "accountingMaxMemoryBytes" => {
+                        accounting.max_memory_bytes = accounting.max_memory_bytes.max(value)
+                    }
+                    "accountingGpu" => accounting.gpu = value,
+                    "accountingMemory" => accounting.memory = value,
+                    "accountingTimeMs" => accounting.time_ms = accounting.time_ms.max(value),
+                    _ => {}
+                }
+                return;
+            }
+            let Some((index, field)) = gpu_field(key) else {
+                return;
+            };
+            let gpu = self.gpus.entry(index).or_default();
+            if field == "name" {
+                gpu.name = value.as_str().map(str::to_string);
+                return;
+            }
+            let Some(value) = value.as_f64() else {
+                return;
+            };
+            match field {
+                "gpu" => {
+                    gpu.utilization.add(value);
+                    gpu.utilization_histogram.add(value);
+                    if let Some(phase) = phase.as_mut() {
+                        phase.utilization.add(value);
+                    }
+                }
+                "memoryAllocated" => gpu.memory_allocated.add(value),
+                "memoryAllocatedBytes" => {
+                    gpu.memory_allocated_bytes.add(value);
+                    if let Some(phase) = phase.as_mut() {
+                        phase.memory_allocated_bytes.add(value);
+                    }
+                }
+                "memoryTotal" => gpu.memory_total = Some(value),
+                "powerWatts" => {
+                    gpu.power.add(value);
+                    gpu.power_histogram.add(value);
+                    if let Some((last_timestamp, last_power)) = gpu.last_power {
+                        let energy = (timestamp - last_timestamp) * (value + last_power) / 2.0;
+                        gpu.integrated_energy += energy;
+                        if let Some(phase) = phase.as_mut() {
+                            phase.energy_joules += energy;
+                        }
+                    }
+                    gpu.last_power = Some((timestamp, value));
+                }
+                "enforcedPowerLimitWatts" => gpu.power_limit.add(value),
+                "temp" => {
+                    gpu.temperature.add(value);
+                    gpu.temperature_histogram.add(value);
+                }
+                "smClock" => gpu.sm_clock.add(value),
+                "energyJoules" => gpu.energy.add(value),
+                "correctedMemoryErrors" => gpu.corrected_errors.add(value),
+                "uncorrectedMemoryErrors" => gpu.uncorrected_errors.add(value),
+                "pcieReplays" => gpu.pcie_replays.add(value),
+                _ => {}
+            }
+        });
+    }
+
+    /// Track phases delimited by markers such as `forward start` and
+    /// `forward end`. A phase also ends when the next one starts.
+    fn marker(&mut self, name: &str) {
+        if let Some(phase) = name.strip_suffix(" start") {
+            let index = match self.phases.iter().position(|(name, _)| name == phase) {
+                Some(index) => index,
+                None => {
+                    self.phases
+                        .push((phase.to_string(), PhaseSummary::default()));
+                    self.phases.len() - 1
+                }
+            };
+            self.phases[index].1.occurrences += 1;
+            self.phase = Some(index);
+        } else if let Some(phase) = name.strip_suffix(" end") {
+            if self.phase.is_some_and(|i| self.phases[i].0 == phase) {
+                self.phase = None;
+            }
+        }
+    }
+
+    /// Headline numbers of the run, in a stable order.
+    pub fn aggregates(&self) -> Vec<Aggregate> {
+        let mut aggregates = Vec::new();
+        let mut push = |name: String, value: f64, better| {
+            aggregates.push(Aggregate {
+                name,
+                value,
+                better,
+            })
+        };
+        if let Some(first) = self.first_timestamp {
+            push(
+                "Duration (s)".to_string(),
+                self.last_timestamp - first,
+                Better::Lower,
+            );
+        }
+        push(
+            "Sampling timeouts".to_string(),
+            self.sampling_timeouts as f64,
+            Better::Lower,
+        );
+        for (index, gpu) in &self.gpus {
+            if gpu.utilization.count > 0 {
+                push(
+                    format!("GPU {} mean utilization (%)", index),
+                    gpu.utilization.mean(),
+                    Better::Higher,
+                );
+            }
+            if gpu.memory_allocated_bytes.count > 0 {
+                push(
+                    format!("GPU {} peak memory (GiB)", index),
+                    gpu.memory_allocated_bytes.max / GIB,
+                    Better::Lower,
+                );
+            }
+            if gpu.power.count > 0 {
+                push(
+                    format!("GPU {} mean power (W)", index),
+                    gpu.power.mean(),
+                    Better::Lower,
+                );
+            }
+            if let Some(p99) = gpu.power_histogram.percentile(99.0) {
+                push(format!("GPU {} p99 power (W)", index), p99, Better::Lower);
+            }
+            if gpu.temperature.count > 0 {
+                push(
+                    format!("GPU {} peak temperature (C)", index),
+                    gpu.temperature.max,
+                    Better::Lower,
+                );
+            }
+            push(
+                format!("GPU {} energy (kJ)", index),
+                gpu.energy_joules() / 1000.0,
+                Better::Lower,
+            );
+            let throttled = self
+                .residency
+                .gpu(*index)
+                .into_iter()
+                .flat_map(|residency| &residency.throttled)
+                // An idle GPU lowering its clocks isn't a performance problem
+                .filter(|(reason, _)| reason.as_str() != "gpuIdle");
+            for (reason, time) in throttled {
+                push(
+                    format!("GPU {} throttled by {} (s)", index, reason),
+                    time.seconds,
+                    Better::Lower,
+                );
+            }
+        }
+        for (name, phase) in &self.phases {
+            push(
+                format!("Phase {} duration (s)", name),
+                phase.seconds,
+                Better::Lower,
+            );
+            if phase.utilization.count > 0 {
+                push(
+                    format!("Phase {} mean utilization (%)", name),
+                    phase.utilization.mean(),
+                    Better::Higher,
+                );
+            }
+            push(
+                format!("Phase {} energy (kJ)", name),
+                phase.energy_joules / 1000.0,
+                Better::Lower,
+            );
+            if let Some(seconds) = phase.process_gpu_seconds() {
+                push(
+                    format!("Phase {} process GPU time (s)", name),
+                    seconds,
+                    Better::Lower,
+                );
+            }
+        }
+        aggregates
+    }
+
+    fn events(&self) -> Vec<String> {
+        let mut events = Vec::new();
+        if self.sampling_timeouts > 0 {
+            events.push(format!("{} sampling timeouts", self.sampling_timeouts));
+        }
+        if self.gaps > 0 {
+            events.push(format!("{} gaps in the sample stream", self.gaps));
+        }
+        if let Some(dropped) = self.dropped_samples.increase().filter(|&d| d > 0.0) {
+            events.push(format!("{} samples dropped by the writer", dropped));
+        }
+        for (index, gpu) in &self.gpus {
+            let counters = [
+                (&gpu.uncorrected_errors, "uncorrected ECC errors"),
+                (&gpu.corrected_errors, "corrected ECC errors"),
+                (&gpu.pcie_replays, "PCIe replays"),
+            ];
+            for (span, what) in counters {
+                if let Some(increase) = span.increase().filter(|&i| i > 0.0) {
+                    events.push(format!("GPU {}: {} {}", index, increase, what));
+                }
+            }
+            if gpu.power_limit.count > 0 && gpu.power_limit.min < gpu.power_limit.max {
+                events.push(format!(
+                    "GPU {}: power limit changed ({:.0}-{:.0} W)",
+                    index, gpu.power_limit.min, gpu.power_limit.max
+                ));
+            }
+        }
+        events
+    }
+}
+
+/// Split `gpu.{i}.{field}` or `_gpu.{i}.{field}` into the GPU index and field.
+pub(crate) fn gpu_field(key: &str) -> Option<(u32, &str)> {
+    let rest = key
+        .strip_prefix("gpu.")
+        .or_else(|| key.strip_prefix("_gpu."))?;
+    let (index, field) = rest.split_once('.')?;
+    Some((index.parse().ok()?, field))
+}
+
+/// Split `gpu.process.{i}.{field}` into the GPU index and field.
+fn process_field(key: &str) -> Option<(u32, &str)> {
+    let (index, field) = key.strip_prefix("gpu.process.")?.split_once('.')?;
+    Some((index.parse().ok()?, field))
+}
+
+/// A line of time spent in each state, e.g. `P-states: P0 50m (83%), P8 10m (17%)`.
+fn residency_row<'a>(
+    f: &mut fmt::Formatter,
+    label: &str,
+    states: impl Iterator<Item = (String, &'a StateTime)>,
+    duration: f64,
+) -> fmt::Result {
+    let states: Vec<String> = states
+        .filter(|(_, time)| time.seconds > 0.0)
+        .map(|(name, time)| {
+            let share = if duration > 0.0 {
+                100.0 * time.seconds / duration
+            } else {
+                0.0
+            };
+            format!("{} {} ({:.0}%)", name, format_duration(time.seconds), share)
+        })
+        .collect();
+    if states.is_empty() {
+        return Ok(());
+    }
+    writeln!(f, "  {}: {}", label, states.join(", "))
+}
+
+fn format_time(timestamp: f64) -> String {
+    let time = UNIX_EPOCH + Duration::try_from_secs_f64(timestamp).unwrap_or_default();
+    let time = UtcDateTime::from_system_time(time);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        time.year, time.month, time.day, time.hour, time.minute, time.second
+    )
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds / 60 % 60),
+    }
+}
+
+fn stats_row(
+    f: &mut fmt::Formatter,
+    label: &str,
+    stats: &Stats,
+    histogram: Option<&Histogram>,
+    scale: f64,
+) -> fmt::Result {
+    if stats.count == 0 {
+        return Ok(());
+    }
+    write!(
+        f,
+        "  {:<20}{:>10.1}{:>10.1}{:>10.1}",
+        label,
+        stats.min / scale,
+        stats.mean() / scale,
+        stats.max / scale
+    )?;
+    if let Some(histogram) = histogram {
+        for &p in DEFAULT_PERCENTILES {
+            if let Some(value) = histogram.percentile(p) {
+                write!(f, "{:>10.1}", value / scale)?;
+            }
+        }
+    }
+    writeln!(f)
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(first) = self.first_timestamp else {
+            return writeln!(f, "No samples recorded");
+        };
+        let duration = self.last_timestamp - first;
+        writeln!(
+            f,
+            "{} samples over {} ({} to {})",
+            self.samples,
+            format_duration(duration),
+            format_time(first),
+            format_time(self.last_timestamp)
+        )?;
+        if self.intervals.count > 0 {
+            writeln!(f, "Mean sampling interval: {:.2}s", self.intervals.mean())?;
+        }
+
+        for (index, gpu) in &self.gpus {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "GPU {}: {}",
+                index,
+                gpu.name.as_deref().unwrap_or("unknown")
+            )?;
+            write!(f, "  {:<20}{:>10}{:>10}{:>10}", "", "min", "mean", "max")?;
+            for p in DEFAULT_PERCENTILES {
+                write!(f, "{:>10}", format!("p{}", p))?;
+            }
+            writeln!(f)?;
+            let utilization = Some(&gpu.utilization_histogram);
+            stats_row(f, "Utilization (%)", &gpu.utilization, utilization, 1.0)?;
+            stats_row(f, "Memory allocated (%)", &gpu.memory_allocated, None, 1.0)?;
+            stats_row(
+                f,
+                "Memory used (GiB)",
+                &gpu.memory_allocated_bytes,
+                None,
+                GIB,
+            )?;
+            stats_row(f, "Power (W)", &gpu.power, Some(&gpu.power_histogram), 1.0)?;
+            let temperature = Some(&gpu.temperature_histogram);
+            stats_row(f, "Temperature (C)", &gpu.temperature, temperature, 1.0)?;
+            stats_row(f, "SM clock (MHz)", &gpu.sm_clock, None, 1.0)?;
+            if let Some(total) = gpu.memory_total {
+                writeln!(f, "  Memory total: {:.1} GiB", total / GIB)?;
+            }
+            let energy = gpu.energy_joules();
+            if energy > 0.0 {
+                writeln!(
+                    f,
+                    "  Energy: {:.1} kJ ({:.3} kWh)",
+                    energy / 1000.0,
+                    energy / 3.6e6
+                )?;
+            }
+            if let Some(accounting) = gpu.accounting {
+                writeln!(
+                    f,
+                    "  Process (accounting): peak memory {:.1} GiB, {:.0}% GPU, {:.0}% memory, active {}",
+                    accounting.max_memory_bytes / GIB,
+                    accounting.gpu,
+                    accounting.memory,
+                    format_duration(accounting.time_ms / 1000.0)
+                )?;
+            }
+            if let Some(residency) = self.residency.gpu(*index) {
+                let pstates = residency
+                    .pstates
+                    .iter()
+                    .map(|(pstate, time)| (format!("P{}", pstate), time));
+                residency_row(f, "P-states", pstates, duration)?;
+                let throttled = residency
+                    .throttled
+                    .iter()
+                    .map(|(reason, time)| (reason.clone(), time));
+                residency_row(f, "Throttled", throttled, duration)?;
+                let above = self
+                    .residency
+                    .thresholds()
+                    .iter()
+                    .zip(&residency.above)
+                    .map(|(threshold, time)| (format!(">{}%", threshold), time));
+                residency_row(f, "Utilization", above, duration)?;
+            }
+        }
+
+        if !self.phases.is_empty() {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "Phases:\n  {:<20}{:>8}{:>10}{:>10}{:>12}{:>12}{:>13}",
+                "", "count", "time", "util (%)", "energy (kJ)", "peak (GiB)", "process GPU"
+            )?;
+            for (name, phase) in &self.phases {
+                let mean = |stats: &Stats| match stats.count {
+                    0 => "-".to_string(),
+                    _ => format!("{:.1}", stats.mean()),
+                };
+                let peak = match phase.memory_allocated_bytes.count {
+                    0 => "-".to_string(),
+                    _ => format!("{:.1}", phase.memory_allocated_bytes.max / GIB),
+                };
+                let process = phase
+                    .process_gpu_seconds()
+                    .map_or_else(|| "-".to_string(), format_duration);
+                writeln!(
+                    f,
+                    "  {:<20}{:>8}{:>10}{:>10}{:>12.1}{:>12}{:>13}",
+                    name,
+                    phase.occurrences,
+                    format_duration(phase.seconds),
+                    mean(&phase.utilization),
+                    phase.energy_joules / 1000.0,
+                    peak,
+                    process
+                )?;
+            }
+        }
+
+        let events = self.events();
+        writeln!(f)?;
+        if events.is_empty() {
+            writeln!(f, "No notable events")?;
+        } else {
+            writeln!(f, "Events:")?;
+            for event in events {
+                writeln!(f, "  - {}", event)?;
+            }
+        }
+        Ok(())
+    }
+}
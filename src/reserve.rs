@@ -0,0 +1,245 @@
+use crate::pick::Candidate;
+use crate::timefmt::UtcDateTime;
+use serde_json::{json, Value};
+use std::env;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Where reservations are recorded when no directory is given. Shared by all
+/// users of the machine, so it's created world-writable and sticky like `/tmp`.
+pub fn default_lock_dir() -> PathBuf {
+    env::temp_dir().join("symon-reservations")
+}
+
+fn lock_path(dir: &Path, gpu: &Candidate) -> PathBuf {
+    match &gpu.uuid {
+        Some(uuid) => dir.join(format!("{}.lock", uuid)),
+        None => dir.join(format!("gpu{}.lock", gpu.index)),
+    }
+}
+
+/// Cooperative reservations of GPUs, for `symon reserve`.
+///
+/// Each reserved GPU holds an advisory lock on a file named after its UUID in
+/// a directory shared by everyone on the machine. The operating system drops
+/// the locks once the process, and the command it runs if they're inherited,
+/// exit, however they exit, so reservations can't go stale. Only programs
+/// that take the locks respect them.
+pub struct Reservation {
+    gpus: Vec<Candidate>,
+    /// Open lock files; closing them releases the locks.
+    _files: Vec<File>,
+}
+
+impl Reservation {
+    /// Reserve the first `count` of `candidates` not reserved by someone else,
+    /// e.g. as ranked by `pick::pick`,
+    /// recording `command` as the holder. Fails, listing the holders, if
+    /// fewer than `count` are free.
+    pub fn acquire(
+        dir: &Path,
+        candidates: &[Candidate],
+        count: usize,
+        command: &str,
+    ) -> io::Result<Self> {
+        create_shared_dir(dir)?;
+        let holder = json!({
+            "pid": std::process::id(),
+            "user": env::var("USER").or_else(|_| env::var("USERNAME")).unwrap_or_default(),
+            "since": UtcDateTime::from_system_time(SystemTime::now()).rfc3339(),
+            "command": command,
+        });
+        let mut gpus = Vec::new();
+        let mut files = Vec::new();
+        let mut taken = Vec::new();
+        for candidate in candidates {
+            if gpus.len() == count {
+                break;
+            }
+            let path = lock_path(dir, candidate);
+            let mut file = open_lock_file(&path)?;
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    let holder = fs::read_to_string(&path).unwrap_or_default();
+                    taken.push(describe_holder(candidate.index, &holder));
+                    continue;
+                }
+                Err(TryLockError::Error(e)) => return Err(e),
+            }
+            // The lock counts even if the holder can't be recorded, e.g. in
+            // another user's file
+            if is_own_file(&file) {
+                let _ = file.set_len(0).and_then(|()| writeln!(file, "{}", holder));
+            }
+            gpus.push(candidate.clone());
+            files.push(file);
+        }
+        if gpus.len() < count {
+            let mut message = format!("only {} of {} GPUs are free", gpus.len(), count);
+            for holder in taken {
+                message.push_str("\n  ");
+                message.push_str(&holder);
+            }
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, message));
+        }
+        Ok(Reservation {
+            gpus,
+            _files: files,
+        })
+    }
+
+    /// Let processes started from now on inherit the locks, so the GPUs stay
+    /// reserved until the command exits, even if symon is killed first.
+    #[cfg(unix)]
+    pub fn inherit(&self) -> io::Result<()> {
+        use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+        use std::os::fd::AsRawFd;
+        for file in &self._files {
+            fcntl(file.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::empty()))?;
+        }
+        Ok(())
+    }
+
+    /// NVML indices of the reserved GPUs.
+    pub fn indices(&self) -> Vec<u32> {
+        self.gpus.iter().map(|gpu| gpu.index).collect()
+    }
+
+    /// The value of `CUDA_VISIBLE_DEVICES` exposing only the reserved GPUs.
+    /// UUIDs are used where known, as CUDA numbers GPUs differently from NVML
+    /// unless `CUDA_DEVICE_ORDER=PCI_BUS_ID`.
+    pub fn cuda_visible_devices(&self) -> String {
+        self.gpus
+            .iter()
+            .map(|gpu| gpu.uuid.clone().unwrap_or_else(|| gpu.index.to_string()))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+fn create_shared_dir(dir: &Path) -> io::Result<()> {
+    if !dir.is_dir() {
+        fs::create_dir_all(dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o1777))?;
+        }
+    }
+    check_shared_dir(dir)
+}
+
+/// Refuse a directory others could swap lock files in: one owned by another
+/// user (other than root), or writable by others without the sticky bit that
+/// stops them from replacing our files.
+#[cfg(unix)]
+fn check_shared_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = fs::symlink_metadata(dir)?;
+    let owner = metadata.uid();
+    let unsafe_dir = !metadata.is_dir()
+        || (owner != 0 && owner != nix::unistd::geteuid().as_raw())
+        || (metadata.mode() & 0o022 != 0 && metadata.mode() & 0o1000 == 0);
+    if unsafe_dir {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} isn't a safe lock directory: it must be owned by root or you, \
+                 and sticky if others can write to it",
+                dir.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_shared_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Open a lock file for writing the holder, or only for locking if it's
+/// another user's. Symbolic links aren't followed, so nobody can point a lock
+/// file at a file of the user reserving.
+fn open_lock_file(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(nix::fcntl::OFlag::O_NOFOLLOW.bits());
+    }
+    options
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .or_else(|_| options.write(false).create(false).open(path))
+}
+
+/// Whether `file` is a lock file of our own, as opposed to another user's
+/// or a hard link to some other file, which mustn't be overwritten.
+#[cfg(unix)]
+fn is_own_file(file: &File) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    file.metadata().is_ok_and(|metadata| {
+        metadata.is_file()
+            && metadata.nlink() == 1
+            && metadata.uid() == nix::unistd::geteuid().as_raw()
+    })
+}
+
+#[cfg(not(unix))]
+fn is_own_file(_file: &File) -> bool {
+    true
+}
+
+fn describe_holder(index: u32, holder: &str) -> String {
+    let Ok(holder) = serde_json::from_str::<Value>(holder.trim()) else {
+        return format!("GPU {} is reserved", index);
+    };
+    format!(
+        "GPU {} is reserved by {} (pid {}) since {}: {}",
+        index,
+        holder["user"]
+            .as_str()
+            .filter(|user| !user.is_empty())
+            .unwrap_or("?"),
+        holder["pid"],
+        holder["since"].as_str().unwrap_or("?"),
+        holder["command"].as_str().unwrap_or("?")
+    )
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    #[test]
+    fn refuses_unsafe_lock_files_and_dirs() {
+        let dir = env::temp_dir().join(format!("symon-reserve-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        create_shared_dir(&dir).unwrap();
+
+        let victim = dir.join("victim");
+        fs::write(&victim, "keep").unwrap();
+        let link = dir.join("gpu0.lock");
+        symlink(&victim, &link).unwrap();
+        assert!(open_lock_file(&link).is_err());
+
+        let hard_link = dir.join("gpu1.lock");
+        fs::hard_link(&victim, &hard_link).unwrap();
+        assert!(!is_own_file(&open_lock_file(&hard_link).unwrap()));
+        assert!(is_own_file(
+            &open_lock_file(&dir.join("gpu2.lock")).unwrap()
+        ));
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(check_shared_dir(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
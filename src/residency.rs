@@ -0,0 +1,153 @@
+use crate::metrics::{MetricKind, Metrics};
+use crate::report::gpu_field;
+use std::collections::BTreeMap;
+
+/// Utilization thresholds (in percentage) when none are configured.
+pub const DEFAULT_THRESHOLDS: &[f64] = &[50.0, 90.0];
+
+/// Time a GPU spent in one state, and the name of its running counter.
+pub struct StateTime {
+    pub key: &'static str,
+    pub seconds: f64,
+}
+
+/// Residency of one GPU.
+#[derive(Default)]
+pub struct GpuResidency {
+    /// Seconds in each P-state, by P-state number.
+    pub pstates: BTreeMap<u32, StateTime>,
+    /// Seconds with clocks reduced for each reason, e.g. `swPowerCap`.
+    pub throttled: BTreeMap<String, StateTime>,
+    /// Seconds above each utilization threshold, in the order of the thresholds.
+    pub above: Vec<StateTime>,
+}
+
+/// Accumulates how long each GPU spent in each P-state, throttled for each
+/// reason and above utilization thresholds over a run.
+///
+/// Each sample's readings are credited with the time since the previous
+/// sample. Totals are available as counters `_gpu.{i}.residency.pstate{n}`,
+/// `_gpu.{i}.residency.<reason>` and `_gpu.{i}.residency.utilizationAbove{t}`
+/// (in seconds).
+pub struct Residency {
+    thresholds: Vec<f64>,
+    last_timestamp: Option<f64>,
+    gpus: BTreeMap<u32, GpuResidency>,
+}
+
+impl Residency {
+    pub fn new(thresholds: Vec<f64>) -> Self {
+        Residency {
+            thresholds,
+            last_timestamp: None,
+            gpus: BTreeMap::new(),
+        }
+    }
+
+    pub fn thresholds(&self) -> &[f64] {
+        &self.thresholds
+    }
+
+    pub fn gpu(&self, index: u32) -> Option<&GpuResidency> {
+        self.gpus.get(&index)
+    }
+
+    pub fn add(&mut self, metrics: &Metrics) {
+        let Some(timestamp) = metrics.timestamp() else {
+            return;
+        };
+        if metrics.get("_record").is_some() {
+            return;
+        }
+        let interval = self
+            .last_timestamp
+            .map_or(0.0, |last| (timestamp - last).max(0.0));
+        self.last_timestamp = Some(timestamp);
+
+        metrics.for_each(|key, value| {
+            let Some((index, field)) = gpu_field(key) else {
+                return;
+            };
+            match field {
+                "pstate" => {
+                    let Some(pstate) = value.as_u64() else {
+                        return;
+                    };
+                    let gpu = self.gpus.entry(index).or_default();
+                    gpu.pstates
+                        .entry(pstate as u32)
+                        .or_insert_with(|| state(index, &format!("pstate{}", pstate)))
+                        .seconds += interval;
+                }
+                "throttleReasons" => {
+                    let Some(reasons) = value.as_array() else {
+                        return;
+                    };
+                    let gpu = self.gpus.entry(index).or_default();
+                    for reason in reasons.iter().filter_map(|r| r.as_str()) {
+                        if !gpu.throttled.contains_key(reason) {
+                            gpu.throttled
+                                .insert(reason.to_string(), state(index, reason));
+                        }
+                        if let Some(time) = gpu.throttled.get_mut(reason) {
+                            time.seconds += interval;
+                        }
+                    }
+                }
+                "gpu" => {
+                    let Some(utilization) = value.as_f64() else {
+                        return;
+                    };
+                    let gpu = self.gpus.entry(index).or_default();
+                    if gpu.above.is_empty() {
+                        gpu.above = self
+                            .thresholds
+                            .iter()
+                            // Keep key segments free of dots, e.g. `utilizationAbove12_5`
+                            .map(|t| {
+                                state(index, &format!("utilizationAbove{}", t).replace('.', "_"))
+                            })
+                            .collect();
+                    }
+                    for (threshold, time) in self.thresholds.iter().zip(&mut gpu.above) {
+                        if utilization > *threshold {
+                            time.seconds += interval;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        });
+    }
+
+    /// Add the running totals to a sample.
+    pub fn add_counters(&self, metrics: &mut Metrics) {
+        for gpu in self.gpus.values() {
+            let times = gpu
+                .pstates
+                .values()
+                .chain(gpu.throttled.values())
+                .chain(&gpu.above);
+            for time in times {
+                metrics.add_metric(time.key, time.seconds);
+            }
+        }
+    }
+}
+
+fn state(index: u32, name: &str) -> StateTime {
+    // States are a small fixed set, so counter names are leaked like the
+    // per-device metric names
+    let key: &'static str =
+        Box::leak(format!("_gpu.{}.residency.{}", index, name).into_boxed_str());
+    debug_assert_eq!(MetricKind::of(key), MetricKind::Counter);
+    StateTime { key, seconds: 0.0 }
+}
+
+/// Parse a utilization threshold in percent, e.g. `90` or `90%`.
+pub fn parse_threshold(s: &str) -> Result<f64, String> {
+    match s.trim().trim_end_matches('%').parse::<f64>() {
+        Ok(value) if (0.0..100.0).contains(&value) => Ok(value),
+        _ => Err(format!("invalid utilization threshold: {:?}", s)),
+    }
+}
@@ -0,0 +1,58 @@
+use crate::metrics::Metrics;
+use crate::report::gpu_field;
+
+/// Add node-wide aggregates of the per-GPU metrics in a sample:
+/// `node.gpu.totalPowerWatts`, `node.gpu.meanUtil` (utilization averaged
+/// over GPUs), `node.gpu.totalMemoryUsedBytes` and `node.gpu.anyThrottling`
+/// (whether any GPU's clocks are reduced for a reason other than being idle).
+///
+/// Each is left out if no GPU reported the metric it's built from.
+pub fn add_node_rollups(metrics: &mut Metrics) {
+    let mut power = None;
+    let mut utilization = (0.0, 0);
+    let mut memory = None;
+    let mut throttling = None;
+    metrics.for_each(|key, value| {
+        let Some((_, field)) = gpu_field(key) else {
+            return;
+        };
+        match field {
+            "powerWatts" if key.starts_with("gpu.") => {
+                if let Some(watts) = value.as_f64() {
+                    *power.get_or_insert(0.0) += watts;
+                }
+            }
+            "gpu" if key.starts_with("gpu.") => {
+                if let Some(percent) = value.as_f64() {
+                    utilization.0 += percent;
+                    utilization.1 += 1;
+                }
+            }
+            "memoryAllocatedBytes" if key.starts_with("gpu.") => {
+                if let Some(bytes) = value.as_u64() {
+                    *memory.get_or_insert(0) += bytes;
+                }
+            }
+            "throttleReasons" => {
+                if let Some(reasons) = value.as_array() {
+                    let throttled = reasons.iter().any(|reason| reason != "gpuIdle");
+                    *throttling.get_or_insert(false) |= throttled;
+                }
+            }
+            _ => {}
+        }
+    });
+
+    if let Some(power) = power {
+        metrics.add_metric("node.gpu.totalPowerWatts", power);
+    }
+    if let (sum, count @ 1..) = utilization {
+        metrics.add_metric("node.gpu.meanUtil", sum / count as f64);
+    }
+    if let Some(memory) = memory {
+        metrics.add_metric("node.gpu.totalMemoryUsedBytes", memory);
+    }
+    if let Some(throttling) = throttling {
+        metrics.add_metric("node.gpu.anyThrottling", throttling);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::control::Control;
+use crate::log;
+use std::ffi::OsString;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Exit code when the command can't be started, as in shells.
+pub const NOT_STARTED: i32 = 127;
+
+/// Launches a command for `symon run` and ends monitoring after it exits.
+///
+/// The command starts once `warm_up` has passed, so the samples show the
+/// GPUs at rest beforehand. Its process tree is then monitored like `--pid`,
+/// and sampling continues for `cool_down` after it exits, capturing clocks and
+/// temperatures settling, before the sampling loop is told to stop.
+pub struct Runner {
+    child: Arc<Mutex<Option<Child>>>,
+    status: Arc<Mutex<Option<i32>>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Runner {
+    pub fn spawn(
+        command: Vec<OsString>,
+        warm_up: Duration,
+        cool_down: Duration,
+        controls: Sender<Control>,
+        running: Arc<AtomicBool>,
+    ) -> std::io::Result<Self> {
+        let child = Arc::new(Mutex::new(None));
+        let status = Arc::new(Mutex::new(None));
+        let (thread_child, thread_status, thread_running) =
+            (child.clone(), status.clone(), running.clone());
+        let thread = thread::Builder::new()
+            .name("run".to_string())
+            .spawn(move || {
+                if sleep_while(&thread_running, warm_up) {
+                    let code = run(&command, &thread_child, &controls);
+                    if let Ok(mut status) = thread_status.lock() {
+                        *status = Some(code);
+                    }
+                    sleep_while(&thread_running, cool_down);
+                }
+                thread_running.store(false, Ordering::Relaxed);
+                let _ = controls.send(Control::Terminate);
+            })?;
+        Ok(Runner {
+            child,
+            status,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    /// The command's exit code, once it exited. If monitoring was stopped
+    /// first, the command is killed.
+    pub fn finish(mut self) -> i32 {
+        self.running.store(false, Ordering::Relaxed);
+        if let Ok(mut child) = self.child.lock() {
+            if let Some(child) = child.as_mut() {
+                log::warning!("Stopping command {}", child.id());
+                let _ = child.kill();
+            }
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        self.status
+            .lock()
+            .ok()
+            .and_then(|status| *status)
+            .unwrap_or(NOT_STARTED)
+    }
+}
+
+/// Start the command and wait for it, returning its exit code.
+fn run(command: &[OsString], child: &Mutex<Option<Child>>, controls: &Sender<Control>) -> i32 {
+    let Some((program, args)) = command.split_first() else {
+        return NOT_STARTED;
+    };
+    let started = match Command::new(program).args(args).spawn() {
+        Ok(started) => started,
+        Err(e) => {
+            log::error!("Error starting {}: {}", program.to_string_lossy(), e);
+            return NOT_STARTED;
+        }
+    };
+    let pid = started.id();
+    log::info!("Started {} as {}", program.to_string_lossy(), pid);
+    let _ = controls.send(Control::SetPid(pid as i32));
+    if let Ok(mut child) = child.lock() {
+        *child = Some(started);
+    }
+
+    // Poll rather than block, so `Runner::finish` can kill the command
+    loop {
+        let result = match child.lock() {
+            Ok(mut child) => match child.as_mut().map(Child::try_wait) {
+                Some(Ok(Some(status))) => {
+                    *child = None;
+                    Ok(Some(status))
+                }
+                Some(Ok(None)) => Ok(None),
+                Some(Err(e)) => Err(e),
+                None => return NOT_STARTED,
+            },
+            Err(_) => return NOT_STARTED,
+        };
+        match result {
+            Ok(Some(status)) => {
+                log::info!("Command {} exited with {}", pid, status);
+                return exit_code(status);
+            }
+            Ok(None) => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                log::error!("Error waiting for command {}: {}", pid, e);
+                return NOT_STARTED;
+            }
+        }
+    }
+}
+
+/// Sleep for `duration` unless `running` is cleared first. Returns whether it
+/// is still set.
+fn sleep_while(running: &AtomicBool, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while running.load(Ordering::Relaxed) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return true;
+        }
+        thread::sleep(left.min(POLL_INTERVAL));
+    }
+    false
+}
+
+/// The status a shell would report: the exit code, or 128 plus the signal
+/// that killed the command.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
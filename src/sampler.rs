@@ -0,0 +1,552 @@
+use crate::dcgm::{Dcgm, DcgmError};
+use crate::device_settings::{DeviceSettings, SettingError};
+use crate::devices::Devices;
+use crate::error::{Result, SymonError};
+use crate::fan_curve::{FanChange, FanController, FanCurve};
+use crate::gpu_nvidia::{Features, NvidiaGpu, SampleOptions};
+use crate::log;
+use crate::metrics::{Metrics, SampleTime};
+use crate::otel;
+use crate::placement::PlacementChecker;
+use crate::power_policy::{PowerPolicy, PowerPolicyEngine};
+use crate::subscribers::{AlertDetector, Event, Subscribers};
+use crate::topology::Topology;
+use crate::watchdog::SamplingWatchdog;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A single sample of all metrics.
+pub type Sample = Metrics;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_TIMEOUTS: u32 = 3;
+
+/// Takes timestamped GPU samples, guarding against hung NVML calls.
+///
+/// This is the entry point for embedding symon as a library.
+pub struct Sampler {
+    /// `None` without NVML, see `without_nvml`.
+    watchdog: Option<SamplingWatchdog>,
+    started: Instant,
+    options: SampleOptions,
+    process_list_every: Option<u32>,
+    samples_taken: u64,
+    subscribers: Arc<Subscribers>,
+    alerts: AlertDetector,
+    placement: PlacementChecker,
+    power_policy: Option<PowerPolicyEngine>,
+    fans: Option<FanController>,
+    /// Shared with the NVML thread, which samples it, see `set_dcgm`.
+    dcgm: Option<Arc<Mutex<Dcgm>>>,
+    /// Event records for the stream, see `take_events`.
+    events: Vec<Metrics>,
+}
+
+impl Sampler {
+    /// Initialize NVML with the default sampling timeout of 10 seconds.
+    pub fn new() -> Result<Self> {
+        Sampler::with_timeout(DEFAULT_TIMEOUT, DEFAULT_MAX_TIMEOUTS)
+    }
+
+    /// Initialize NVML. See `SamplingWatchdog` for the meaning of the timeouts.
+    pub fn with_timeout(timeout: Duration, max_consecutive_timeouts: u32) -> Result<Self> {
+        let watchdog = SamplingWatchdog::start(timeout, max_consecutive_timeouts)?;
+        Ok(Sampler::with_watchdog(Some(watchdog)))
+    }
+
+    /// A sampler for hosts where NVML can't be loaded, e.g. without the NVIDIA
+    /// driver, or a fully static (musl) build, which can't load shared libraries.
+    /// Samples carry a `_gpu.count` of 0 and no GPU metrics, so collectors that
+    /// don't need NVML keep working; GPU queries fail with
+    /// `SymonError::NvmlUnavailable`.
+    pub fn without_nvml() -> Self {
+        Sampler::with_watchdog(None)
+    }
+
+    fn with_watchdog(watchdog: Option<SamplingWatchdog>) -> Self {
+        Sampler {
+            watchdog,
+            started: Instant::now(),
+            options: SampleOptions::default(),
+            process_list_every: None,
+            samples_taken: 0,
+            subscribers: Arc::new(Subscribers::new()),
+            alerts: AlertDetector::default(),
+            placement: PlacementChecker::default(),
+            power_policy: None,
+            fans: None,
+            dcgm: None,
+            events: Vec::new(),
+        }
+    }
+
+    fn watchdog(&mut self) -> Result<&mut SamplingWatchdog> {
+        self.watchdog.as_mut().ok_or(SymonError::NvmlUnavailable)
+    }
+
+    /// Add DCGM's profiling metrics to every sample, see `Dcgm`. DCGM's GPUs
+    /// are matched with NVML's by UUID, and DCGM is sampled on the NVML thread
+    /// so a hung DCGM call is bounded by the sampling timeout too.
+    pub fn set_dcgm(&mut self, dcgm: Option<Dcgm>) {
+        self.dcgm = match dcgm {
+            Some(mut dcgm) => {
+                match self.devices() {
+                    Ok(devices) => {
+                        let uuids: Vec<Option<String>> =
+                            devices.gpus.into_iter().map(|gpu| gpu.uuid).collect();
+                        if let Err(e) = dcgm.match_nvml(&uuids) {
+                            log::warning!("Error matching DCGM's GPUs with NVML's: {}", e);
+                        }
+                    }
+                    Err(e) => log::warning!("Error matching DCGM's GPUs with NVML's: {}", e),
+                }
+                Some(Arc::new(Mutex::new(dcgm)))
+            }
+            None => None,
+        };
+    }
+
+    /// Report SM, tensor core and DRAM activity, and activity per pipe, from
+    /// NVML's GPM on GPUs that support it (Hopper and newer). Redundant with
+    /// `set_dcgm`, except for the per-pipe activity.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.options.profiling = enabled;
+    }
+
+    /// Report GPU usage of `pid` and its children separately; 0 for none.
+    pub fn set_pid(&mut self, pid: i32) {
+        self.options.pid = pid;
+    }
+
+    /// The process whose GPU usage is reported separately; 0 for none.
+    pub fn pid(&self) -> i32 {
+        self.options.pid
+    }
+
+    /// Report GPU usage per Kubernetes pod and container in each sample.
+    pub fn set_pod_attribution(&mut self, enabled: bool) {
+        self.options.pods = enabled;
+    }
+
+    /// Report GPU usage per user in each sample.
+    pub fn set_user_attribution(&mut self, enabled: bool) {
+        self.options.users = enabled;
+    }
+
+    /// List the processes on each GPU in every `every`-th sample, starting
+    /// with the next one, or stop listing them if `None`.
+    pub fn set_process_list(&mut self, every: Option<u32>) {
+        self.process_list_every = every.map(|every| every.max(1));
+        self.samples_taken = 0;
+    }
+
+    /// Adjust power limits according to `policy` after every successful
+    /// sample, or stop adjusting them. Each change is reported as an event.
+    pub fn set_power_policy(&mut self, policy: Option<PowerPolicy>) {
+        self.power_policy = match (self.power_policy.take(), policy) {
+            (Some(mut engine), Some(policy)) => {
+                engine.set_policy(policy);
+                Some(engine)
+            }
+            (None, Some(policy)) => Some(PowerPolicyEngine::new(policy)),
+            (_, None) => None,
+        };
+    }
+
+    /// Drive fan speeds from `curve` after every sample, or hand the fans back
+    /// to the driver. Fans are also handed back whenever sampling fails.
+    pub fn set_fan_curve(&mut self, curve: Option<FanCurve>) {
+        let released = match (self.fans.as_mut(), curve) {
+            (Some(fans), Some(curve)) => fans.set_curve(curve),
+            (None, Some(curve)) => {
+                self.fans = Some(FanController::new(curve));
+                Vec::new()
+            }
+            (Some(fans), None) => {
+                let released = fans.release_all();
+                self.fans = None;
+                released
+            }
+            (None, None) => Vec::new(),
+        };
+        self.apply_fan_changes(released);
+    }
+
+    /// Callbacks notified of each sample, event and alert. The returned handle
+    /// can be kept to add or remove subscribers while the sampler runs, e.g.
+    /// after it was moved into `stream`.
+    pub fn subscribers(&self) -> Arc<Subscribers> {
+        self.subscribers.clone()
+    }
+
+    /// Take a sample into `metrics`, replacing its contents.
+    ///
+    /// The sample is always timestamped. If NVML fails or times out, the error
+    /// is returned and `metrics` holds whatever was collected, marked with
+    /// `_sampling_timeout` on timeout.
+    pub fn sample_into(&mut self, metrics: &mut Metrics) -> Result<()> {
+        metrics.clear();
+        let sampling_start = Instant::now();
+        let time = SampleTime {
+            wall: SystemTime::now(),
+            uptime: sampling_start.duration_since(self.started),
+        };
+        let mut span = otel::Span::sample("sample", time);
+        self.options.processes = self
+            .process_list_every
+            .is_some_and(|every| self.samples_taken.is_multiple_of(u64::from(every)));
+        self.samples_taken += 1;
+        let result = match self.watchdog.as_mut() {
+            Some(watchdog) => watchdog.sample(metrics, &self.options).map_err(Into::into),
+            None => {
+                metrics.add_metric("_gpu.count", 0u32);
+                Ok(())
+            }
+        };
+        let timed_out = result.as_ref().is_err_and(SymonError::is_timeout);
+        if timed_out {
+            metrics.add_metric("_sampling_timeout", true);
+        }
+        // DCGM calls run on the sampling thread too; behind a stuck sample they
+        // would only wait out another full timeout.
+        let nvml_stuck = timed_out
+            || self
+                .watchdog
+                .as_ref()
+                .is_some_and(SamplingWatchdog::sample_in_flight);
+        if let Some(dcgm) = self.dcgm.as_ref().filter(|_| !nvml_stuck) {
+            let mut span = otel::Span::start("dcgm.sample");
+            if let Err(e) = sample_dcgm(self.watchdog.as_mut(), dcgm, metrics) {
+                log::warning!("Error sampling DCGM: {}", e);
+                span.set_error(e);
+            }
+        }
+        let sampling_duration_ms = sampling_start.elapsed().as_secs_f64() * 1000.0;
+        metrics.add_metric("_sampling_duration_ms", sampling_duration_ms);
+        span.set_attribute("symon.sampling_duration_ms", sampling_duration_ms);
+        if let Err(e) = &result {
+            span.set_error(e);
+        }
+        metrics.set_time(time);
+
+        match &result {
+            Err(e) if e.is_timeout() => self.subscribers.notify_event(&Event::SamplingTimedOut),
+            Err(e) => self
+                .subscribers
+                .notify_event(&Event::SamplingFailed(e.to_string())),
+            Ok(()) => {}
+        }
+        let subscribers = &self.subscribers;
+        if subscribers.wants_alerts() {
+            self.alerts
+                .check(metrics, |alert| subscribers.notify_alert(&alert));
+        }
+        let watchdog = &mut self.watchdog;
+        let mismatches = self.placement.check(metrics, self.options.pid, || {
+            watchdog
+                .as_mut()?
+                .call(|nvidia_gpu| nvidia_gpu.topology().ok())
+                .ok()?
+        });
+        for mismatch in mismatches {
+            let alert = mismatch.alert();
+            log::warning!("{}", alert.message);
+            subscribers.notify_alert(&alert);
+            self.events.push(mismatch.record(time));
+        }
+        if result.is_ok() {
+            self.apply_power_policy(metrics, time);
+        }
+        if let Some(fans) = self.fans.as_mut() {
+            // Without complete readings the fans are safer with the driver
+            let changes = match &result {
+                Ok(()) => fans.evaluate(metrics),
+                Err(_) => fans.release_all(),
+            };
+            self.apply_fan_changes(changes);
+        }
+        self.subscribers.notify_sample(metrics);
+        result
+    }
+
+    fn apply_power_policy(&mut self, metrics: &Metrics, time: SampleTime) {
+        let Some(engine) = self.power_policy.as_mut() else {
+            return;
+        };
+        if engine.needs_limit_ranges() {
+            if let Some(Ok(ranges)) = self
+                .watchdog
+                .as_mut()
+                .map(|watchdog| watchdog.call(|nvidia_gpu| nvidia_gpu.power_limit_ranges()))
+            {
+                engine.set_limit_ranges(ranges);
+            }
+        }
+        for change in engine.evaluate(metrics, time.wall) {
+            let settings = DeviceSettings {
+                power_limit_watts: Some(change.watts),
+                ..DeviceSettings::default()
+            };
+            // Failed changes are tried again after the next sample
+            match self.apply_settings(settings, Some(vec![change.gpu])) {
+                Ok(failures) if failures.is_empty() => {
+                    log::info!(
+                        "Set GPU {} power limit to {:.0} W ({})",
+                        change.gpu,
+                        change.watts,
+                        change.reason
+                    );
+                    if let Some(engine) = self.power_policy.as_mut() {
+                        engine.applied(&change);
+                    }
+                    let mut record = Metrics::new();
+                    record.add_metric("_record", "event");
+                    record.add_metric("_event", "power_limit");
+                    record.add_metric("gpu", change.gpu);
+                    record.add_metric("limitWatts", change.watts);
+                    record.add_metric("reason", change.reason.clone());
+                    record.set_time(time);
+                    self.events.push(record);
+                    self.subscribers.notify_event(&Event::PowerLimitChanged {
+                        gpu: change.gpu,
+                        watts: change.watts,
+                        reason: change.reason,
+                    });
+                }
+                Ok(failures) => {
+                    for failure in failures {
+                        log::error!("Error applying power policy: {}", failure);
+                    }
+                }
+                Err(e) => log::error!("Error applying power policy: {}", e),
+            }
+        }
+    }
+
+    /// Event records raised while sampling since the previous call, e.g. a
+    /// `placement` event, to be written along with the samples.
+    pub fn take_events(&mut self) -> Vec<Metrics> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Take a sample. Timeouts yield a sample marked with `_sampling_timeout`
+    /// rather than an error.
+    pub fn sample(&mut self) -> Result<Sample> {
+        let mut sample = Sample::new();
+        match self.sample_into(&mut sample) {
+            Err(e) if !e.is_timeout() => Err(e),
+            _ => Ok(sample),
+        }
+    }
+
+    /// Describe how the GPUs are connected to each other and to the host.
+    pub fn topology(&mut self) -> Result<Topology> {
+        Ok(self
+            .watchdog()?
+            .call(|nvidia_gpu| nvidia_gpu.topology())??)
+    }
+
+    /// Driver and NVML versions and the features gated on them.
+    pub fn features(&mut self) -> Result<Features> {
+        Ok(self
+            .watchdog()?
+            .call(|nvidia_gpu| nvidia_gpu.features().clone())?)
+    }
+
+    /// How often the driver samples each GPU's utilization, if known. See
+    /// `NvidiaGpu::utilization_periods`.
+    pub fn utilization_periods(&mut self) -> Result<Vec<Option<Duration>>> {
+        Ok(self
+            .watchdog()?
+            .call(|nvidia_gpu| nvidia_gpu.utilization_periods())?)
+    }
+
+    /// Describe the devices and the driver, see `Devices`.
+    pub fn devices(&mut self) -> Result<Devices> {
+        Ok(self.watchdog()?.call(|nvidia_gpu| nvidia_gpu.devices())?)
+    }
+
+    /// A timestamped one-off record of the devices, see `Devices::to_metrics`.
+    pub fn devices_record(&mut self) -> Result<Metrics> {
+        let mut record = self.devices()?.to_metrics()?;
+        record.set_time(self.now());
+        Ok(record)
+    }
+
+    /// A timestamped one-off record of the topology, see `Topology::to_metrics`.
+    pub fn topology_record(&mut self) -> Result<Metrics> {
+        let mut record = self.topology()?.to_metrics()?;
+        record.set_time(self.now());
+        Ok(record)
+    }
+
+    fn apply_fan_changes(&mut self, changes: Vec<FanChange>) {
+        for FanChange { gpu, percent } in changes {
+            if let Err(e) = self.set_fan_speed(gpu, percent) {
+                log::error!("Error setting GPU {} fan speed: {}", gpu, e);
+            }
+        }
+    }
+
+    fn set_fan_speed(&mut self, gpu: u32, percent: Option<u32>) -> Result<()> {
+        Ok(self
+            .watchdog()?
+            .call(move |nvidia_gpu| nvidia_gpu.set_fan_speed(gpu, percent))??)
+    }
+
+    /// Run `f` with the NVML handle on the guarded sampling thread, for
+    /// operations the sampler has no dedicated method for.
+    pub fn with_gpu<T, F>(&mut self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&NvidiaGpu) -> T + Send + 'static,
+    {
+        Ok(self.watchdog()?.call(f)?)
+    }
+
+    /// Describe what `settings` would change on `gpus` (all if `None`).
+    pub fn plan_settings(
+        &mut self,
+        settings: DeviceSettings,
+        gpus: Option<Vec<u32>>,
+    ) -> Result<Vec<String>> {
+        Ok(self
+            .watchdog()?
+            .call(move |nvidia_gpu| nvidia_gpu.plan_settings(&settings, gpus.as_deref()))??)
+    }
+
+    /// Apply `settings` to `gpus` (all if `None`), returning per-device failures.
+    pub fn apply_settings(
+        &mut self,
+        settings: DeviceSettings,
+        gpus: Option<Vec<u32>>,
+    ) -> Result<Vec<SettingError>> {
+        Ok(self
+            .watchdog()?
+            .call(move |nvidia_gpu| nvidia_gpu.apply_settings(&settings, gpus.as_deref()))??)
+    }
+
+    /// The current time, for records written alongside samples.
+    pub fn now(&self) -> SampleTime {
+        SampleTime {
+            wall: SystemTime::now(),
+            uptime: self.started.elapsed(),
+        }
+    }
+
+    /// Shut down NVML, handing any fans under manual control back to the driver.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.set_fan_curve(None);
+        match self.watchdog.take() {
+            Some(watchdog) => Ok(watchdog.shutdown()?),
+            None => Ok(()),
+        }
+    }
+
+    /// Sample every `interval` as an async stream. Requires the `async` feature
+    /// and a Tokio runtime.
+    ///
+    /// NVML calls run on Tokio's blocking pool. Sampling stops and NVML is shut
+    /// down once the stream is dropped. Errors are logged and the partial
+    /// sample is still yielded, as the agent does.
+    #[cfg(feature = "async")]
+    pub fn stream(self, interval: Duration) -> SampleStream {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut sampler = self;
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            loop {
+                ticker.tick().await;
+                let sampled = tokio::task::spawn_blocking(move || {
+                    let mut sample = Sample::new();
+                    let result = sampler.sample_into(&mut sample);
+                    (sampler, sample, result)
+                })
+                .await;
+                // A panic while sampling ends the stream
+                let Ok((returned, sample, result)) = sampled else {
+                    return;
+                };
+                sampler = returned;
+                if let Err(e) = result {
+                    log::warning!("Error sampling GPU metrics: {}", e);
+                }
+                if sender.send(sample).await.is_err() {
+                    break;
+                }
+            }
+            let _ = tokio::task::spawn_blocking(move || sampler.shutdown()).await;
+        });
+        SampleStream { receiver }
+    }
+}
+
+/// Add DCGM's latest values to `metrics`, calling DCGM on the NVML thread.
+///
+/// A DCGM call that hangs holds on to `dcgm`, so later samples fail fast
+/// instead of queueing behind it.
+fn sample_dcgm(
+    watchdog: Option<&mut SamplingWatchdog>,
+    dcgm: &Arc<Mutex<Dcgm>>,
+    metrics: &mut Metrics,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dcgm = dcgm.clone();
+    let sample = move || -> Result<Metrics, DcgmError> {
+        let dcgm = dcgm
+            .try_lock()
+            .map_err(|_| DcgmError::new("a previous DCGM call hasn't returned"))?;
+        let mut sampled = Metrics::new();
+        dcgm.sample(&mut sampled)?;
+        Ok(sampled)
+    };
+    let sampled = match watchdog {
+        Some(watchdog) => watchdog.call(move |_| sample())??,
+        None => sample()?,
+    };
+    sampled.for_each(|key, value| metrics.add_metric(key.clone(), value.clone()));
+    Ok(())
+}
+
+/// Stream of samples returned by `Sampler::stream`.
+#[cfg(feature = "async")]
+pub struct SampleStream {
+    receiver: tokio::sync::mpsc::Receiver<Sample>,
+}
+
+#[cfg(feature = "async")]
+impl futures_core::Stream for SampleStream {
+    type Item = Sample;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Sample>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_processes_every_nth_sample() {
+        let mut sampler = Sampler::without_nvml();
+        sampler.set_process_list(Some(2));
+        let mut listed = Vec::new();
+        for _ in 0..4 {
+            let _ = sampler.sample();
+            listed.push(sampler.options.processes);
+        }
+        assert_eq!(listed, [true, false, true, false]);
+        // Turned off and back on by the overhead budget, listing resumes with
+        // the next sample
+        sampler.set_process_list(None);
+        let _ = sampler.sample();
+        assert!(!sampler.options.processes);
+        sampler.set_process_list(Some(2));
+        let _ = sampler.sample();
+        assert!(sampler.options.processes);
+    }
+}
@@ -0,0 +1,517 @@
+use crate::docker;
+use crate::log;
+use crate::privileges;
+use nix::libc;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+/// What the sandboxed agent may still do besides reading driver libraries,
+/// `/proc` and `/sys`, using `/dev/nvidia*` and opening sockets.
+#[derive(Default)]
+pub struct SandboxOptions {
+    /// Files and directories read after startup: the config file, certificates,
+    /// tokens, power policies, fan curves and baselines.
+    pub readable: Vec<PathBuf>,
+    /// Directories of output files, the state file and the pidfile, where files
+    /// may be created, replaced and removed, but not directories.
+    pub writable: Vec<PathBuf>,
+    /// The spool directory, where the agent also creates and removes directories.
+    pub spool: Option<PathBuf>,
+    /// Allow running programs, e.g. `ipmitool` for `--bmc ipmi`, `chronyc` or
+    /// the command of `symon run`. They need to read their own files, so
+    /// everything stays readable.
+    pub exec: bool,
+}
+
+/// Restrict the agent with Landlock, so it can only read what it samples and
+/// was configured with and only write to GPU devices and `options.writable`,
+/// and with a seccomp filter allowing only the system calls it makes. Denied
+/// calls fail with `EPERM` rather than killing the agent.
+///
+/// Landlock only applies to the calling thread and threads it starts later,
+/// so this must run before any threads are started. Kernels without Landlock
+/// (before 5.13) only get the seccomp filter.
+pub fn apply(options: &SandboxOptions) -> io::Result<()> {
+    // SAFETY: prctl with PR_SET_NO_NEW_PRIVS takes no pointers
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    match landlock(options) {
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EOPNOTSUPP)) => {
+            log::warning!(
+                "Landlock isn't available, only filtering system calls: {}",
+                e
+            )
+        }
+        result => result?,
+    }
+    seccomp(options)
+}
+
+// Landlock isn't covered by libc; see linux/landlock.h.
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+/// Everything Landlock's first ABI controls, including making device nodes,
+/// sockets, FIFOs and symlinks, which is never allowed. Connecting to Unix
+/// sockets, such as the kubelet's, isn't controlled by it.
+const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+/// The rights that apply to files rather than directories.
+const ACCESS_FS_FILE: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE;
+const ACCESS_FS_READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+/// Writing, replacing, rotating and removing files.
+const ACCESS_FS_OUTPUT: u64 =
+    ACCESS_FS_READ | ACCESS_FS_WRITE_FILE | ACCESS_FS_REMOVE_FILE | ACCESS_FS_MAKE_REG;
+/// Output, plus the directories spools are kept in.
+const ACCESS_FS_SPOOL: u64 = ACCESS_FS_OUTPUT | ACCESS_FS_REMOVE_DIR | ACCESS_FS_MAKE_DIR;
+
+/// Where the dynamic loader finds libnvidia-ml and the libraries it loads,
+/// including where container runtimes mount the driver.
+const LIBRARY_PATHS: &[&str] = &[
+    "/etc/ld.so.cache",
+    "/lib",
+    "/lib64",
+    "/usr/lib",
+    "/usr/lib64",
+    "/usr/local/nvidia",
+];
+
+/// Read to resolve host names of sinks, look up `--user`, and for
+/// container names of GPU processes.
+const SYSTEM_FILES: &[&str] = &[
+    "/etc/hosts",
+    "/etc/resolv.conf",
+    "/etc/nsswitch.conf",
+    "/etc/gai.conf",
+    "/etc/passwd",
+    "/etc/group",
+    "/etc/localtime",
+    docker::CONTAINERS,
+];
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+fn landlock(options: &SandboxOptions) -> io::Result<()> {
+    let attr = RulesetAttr {
+        handled_access_fs: ACCESS_FS_ALL,
+    };
+    // SAFETY: the kernel reads `size_of::<RulesetAttr>()` bytes from `attr`
+    let ruleset = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the ruleset descriptor was just created and is owned here
+    let ruleset = unsafe { <File as std::os::fd::FromRawFd>::from_raw_fd(ruleset as i32) };
+
+    if options.exec {
+        add_rule(&ruleset, "/".into(), ACCESS_FS_READ | ACCESS_FS_EXECUTE)?;
+    } else {
+        let system = ["/proc", "/sys"]
+            .iter()
+            .chain(LIBRARY_PATHS)
+            .chain(SYSTEM_FILES);
+        let library_path = std::env::var_os("LD_LIBRARY_PATH").unwrap_or_default();
+        let paths = system
+            .map(PathBuf::from)
+            .chain(std::env::split_paths(&library_path));
+        for path in paths {
+            add_optional_rule(&ruleset, path, ACCESS_FS_READ)?;
+        }
+    }
+    // Device nodes are opened for writing to issue ioctls, e.g. /dev/nvidiactl
+    let nvidia = fs::read_dir("/dev")?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().as_bytes().starts_with(b"nvidia"))
+        .map(|entry| entry.path());
+    let devices = nvidia.chain(["/dev/null".into()]).chain(
+        privileges::IPMI_DEVICES
+            .iter()
+            .filter(|_| options.exec)
+            .map(PathBuf::from),
+    );
+    for device in devices {
+        add_optional_rule(&ruleset, device, ACCESS_FS_READ | ACCESS_FS_WRITE_FILE)?;
+    }
+    for path in &options.readable {
+        match add_rule(&ruleset, path.clone(), ACCESS_FS_READ) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::warning!("{} doesn't exist and won't be readable", path.display())
+            }
+            result => result?,
+        }
+    }
+    let writable = options.writable.iter().map(|dir| (dir, ACCESS_FS_OUTPUT));
+    for (dir, access) in writable.chain(options.spool.iter().map(|dir| (dir, ACCESS_FS_SPOOL))) {
+        match add_rule(&ruleset, dir.clone(), access) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::warning!("{} doesn't exist and won't be writable", dir.display())
+            }
+            result => result?,
+        }
+    }
+
+    // SAFETY: restricting takes no pointers
+    if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset.as_raw_fd(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Allow `access` beneath `path`, or to the file itself, where only the file
+/// rights apply.
+fn add_rule(ruleset: &File, path: PathBuf, access: u64) -> io::Result<()> {
+    // Opened only as a reference, so device nodes aren't actually opened
+    let dir = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH)
+        .open(&path)?;
+    let access = if dir.metadata()?.is_dir() {
+        access
+    } else {
+        access & ACCESS_FS_FILE
+    };
+    let rule = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: dir.as_raw_fd(),
+    };
+    // SAFETY: the kernel reads the rule, and `dir` outlives the call
+    let ret = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_ADD_RULE,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &rule as *const PathBeneathAttr,
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Like `add_rule`, for paths that only exist on some hosts.
+fn add_optional_rule(ruleset: &File, path: PathBuf, access: u64) -> io::Result<()> {
+    match add_rule(ruleset, path, access) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// `AUDIT_ARCH_*` of the architectures with a filter, from linux/audit.h.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// System calls the agent makes: files, memory, threads, signals, polling,
+/// sockets, waiting for children, dropping privileges, and the ioctls and
+/// mappings of the NVIDIA driver. Everything else, e.g. `ptrace`, `mount`,
+/// `bpf` or loading kernel modules, is denied.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const ALLOWED: &[libc::c_long] = &[
+    // Files
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_lseek,
+    libc::SYS_close,
+    libc::SYS_close_range,
+    libc::SYS_openat,
+    libc::SYS_openat2,
+    libc::SYS_newfstatat,
+    libc::SYS_fstat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_fcntl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_ioctl,
+    libc::SYS_flock,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_fallocate,
+    libc::SYS_copy_file_range,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+    libc::SYS_mkdirat,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_getcwd,
+    libc::SYS_umask,
+    libc::SYS_inotify_init1,
+    libc::SYS_inotify_add_watch,
+    libc::SYS_inotify_rm_watch,
+    // Memory
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_membarrier,
+    libc::SYS_get_mempolicy,
+    // Threads and scheduling
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_get_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_getparam,
+    libc::SYS_sched_getscheduler,
+    libc::SYS_getpriority,
+    libc::SYS_prctl,
+    // Time
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_gettimeofday,
+    libc::SYS_adjtimex,
+    // Signals
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigtimedwait,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_kill,
+    libc::SYS_tgkill,
+    // Polling
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_eventfd2,
+    libc::SYS_pipe2,
+    libc::SYS_timerfd_create,
+    libc::SYS_timerfd_settime,
+    libc::SYS_timerfd_gettime,
+    // Sockets
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_shutdown,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    // Processes and identity
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_gettid,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+    libc::SYS_pidfd_open,
+    libc::SYS_pidfd_send_signal,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_getgroups,
+    libc::SYS_setuid,
+    libc::SYS_setgid,
+    libc::SYS_setresuid,
+    libc::SYS_setresgid,
+    libc::SYS_setgroups,
+    libc::SYS_capget,
+    libc::SYS_getrusage,
+    libc::SYS_getrlimit,
+    libc::SYS_prlimit64,
+    libc::SYS_sysinfo,
+    libc::SYS_uname,
+    libc::SYS_getrandom,
+    // Their older forms, which only x86_64 has
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_getdents,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_dup2,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rmdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_sendfile,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_select,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_create,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_eventfd,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_time,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+];
+
+/// Allowed with `SandboxOptions::exec`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const EXEC: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_vfork,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_fork,
+];
+// Missing from libc's BPF constants.
+#[cfg(target_arch = "x86_64")]
+const BPF_JGE: u16 = 0x30;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn statement(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp(options: &SandboxOptions) -> io::Result<()> {
+    let load = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+    let jeq = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+    let ret = (libc::BPF_RET | libc::BPF_K) as u16;
+    let deny = libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA);
+
+    // Offsets into `struct seccomp_data`
+    let nr_offset = 0;
+    let arch_offset = 4;
+    let mut program = vec![
+        statement(load, arch_offset),
+        jump(jeq, AUDIT_ARCH, 1, 0),
+        statement(ret, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(load, nr_offset),
+    ];
+    // x32 system calls on x86_64 are numbered from bit 30, sidestepping the list
+    #[cfg(target_arch = "x86_64")]
+    program.extend([
+        jump(
+            (libc::BPF_JMP | libc::BPF_K) as u16 | BPF_JGE,
+            0x4000_0000,
+            0,
+            1,
+        ),
+        statement(ret, deny),
+    ]);
+    let exec: &[libc::c_long] = if options.exec { EXEC } else { &[] };
+    for &nr in ALLOWED.iter().chain(exec) {
+        program.push(jump(jeq, nr as u32, 0, 1));
+        program.push(statement(ret, libc::SECCOMP_RET_ALLOW));
+    }
+    program.push(statement(ret, deny));
+
+    let filter = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_mut_ptr(),
+    };
+    // SAFETY: the kernel copies the program, which outlives the call
+    let status = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            &filter as *const libc::sock_fprog,
+        )
+    };
+    if status != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The allowed system calls are only listed for x86_64 and aarch64.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn seccomp(_options: &SandboxOptions) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "system call filtering is only supported on x86_64 and aarch64",
+    ))
+}
@@ -0,0 +1,133 @@
+use crate::metrics::{Metrics, SampleTime};
+use std::time::SystemTime;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Time of day during which samples are taken, e.g. `08:00-20:00`, in local
+/// time. A window that ends before it starts, such as `22:00-06:00`, spans
+/// midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ActiveWindow {
+    /// Minutes since midnight.
+    start: u32,
+    end: u32,
+}
+
+impl ActiveWindow {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected START-END, e.g. 08:00-20:00: {:?}", s))?;
+        let window = ActiveWindow {
+            start: parse_time_of_day(start)? % MINUTES_PER_DAY,
+            end: parse_time_of_day(end)?,
+        };
+        if window.start == window.end {
+            return Err(format!("empty window: {:?}", s));
+        }
+        Ok(window)
+    }
+
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let minute = local_minute_of_day(time);
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Parse `HH:MM`, where `24:00` is the end of the day.
+fn parse_time_of_day(s: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid time of day: {:?}", s);
+    let (hours, minutes) = s.trim().split_once(':').ok_or_else(invalid)?;
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+    let minute = hours * 60 + minutes;
+    if minutes >= 60 || minute > MINUTES_PER_DAY {
+        return Err(invalid());
+    }
+    Ok(minute)
+}
+
+#[cfg(unix)]
+fn local_minute_of_day(time: SystemTime) -> u32 {
+    use nix::libc;
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as libc::time_t;
+    // SAFETY: localtime_r only writes to the struct passed in
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return utc_minute_of_day(time);
+    }
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+/// Windows are in UTC where the local time zone isn't available.
+#[cfg(not(unix))]
+fn local_minute_of_day(time: SystemTime) -> u32 {
+    utc_minute_of_day(time)
+}
+
+fn utc_minute_of_day(time: SystemTime) -> u32 {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (secs / 60 % u64::from(MINUTES_PER_DAY)) as u32
+}
+
+/// A `run_start` or `run_end` event record, marking where a recording starts
+/// or where a bounded one (`--duration`, `--active-window`) stops. `reason` is
+/// e.g. `start`, `window`, `duration` or `shutdown`.
+pub fn run_record(event: &str, reason: &str, time: SampleTime) -> Metrics {
+    let mut record = Metrics::new();
+    record.add_metric("_record", "event");
+    record.add_metric("_event", event.to_string());
+    record.add_metric("_reason", reason.to_string());
+    record.set_time(time);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_windows_including_ones_across_midnight() {
+        assert_eq!(
+            ActiveWindow::parse("08:00-20:00"),
+            Ok(ActiveWindow {
+                start: 8 * 60,
+                end: 20 * 60
+            })
+        );
+        assert_eq!(
+            ActiveWindow::parse("22:30-06:00"),
+            Ok(ActiveWindow {
+                start: 22 * 60 + 30,
+                end: 6 * 60
+            })
+        );
+        // 24:00 ends the day, and starts it
+        assert_eq!(
+            ActiveWindow::parse("24:00-12:00"),
+            Ok(ActiveWindow {
+                start: 0,
+                end: 12 * 60
+            })
+        );
+        for invalid in ["08:00", "08:00-08:00", "8-20", "08:60-20:00", "00:00-24:01"] {
+            assert!(ActiveWindow::parse(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn minute_of_day_is_taken_modulo_days() {
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(3 * 86400 + 3723);
+        assert_eq!(utc_minute_of_day(time), 62);
+    }
+}
@@ -0,0 +1,91 @@
+use serde_json::{json, Value};
+
+/// Version of the record format, written to every record as `_schema_version`.
+///
+/// Within a version, records only ever gain metrics: keys keep their meaning,
+/// units and value types, and consumers must ignore keys they don't know.
+/// Renaming or removing a key, or changing its type or unit, requires a new
+/// version, and the schemas of older versions stay available from
+/// `symon schema --version`.
+pub const VERSION: u32 = 1;
+
+/// Versions `json_schema` can describe.
+pub const SUPPORTED_VERSIONS: &[u32] = &[1];
+
+/// The JSON Schema of records of `version`, or `None` if it isn't supported.
+pub fn json_schema(version: u32) -> Option<Value> {
+    match version {
+        1 => Some(v1()),
+        _ => None,
+    }
+}
+
+fn v1() -> Value {
+    let scalar = json!({
+        "type": ["number", "string", "boolean", "null", "array", "object"]
+    });
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": "https://github.com/dmitryduev/symon/schema/v1.json",
+        "title": "symon record, schema version 1",
+        "description": "A flat object of metrics. Samples carry per-GPU metrics as \
+            `gpu.<index>.<name>`; records of other kinds name themselves in `_record`. \
+            Keys not listed here may be added within version 1 and must be ignored \
+            by consumers that don't know them.",
+        "type": "object",
+        "required": ["_schema_version", "_timestamp"],
+        "properties": {
+            "_schema_version": {
+                "description": "Version of the record format",
+                "const": 1
+            },
+            "_timestamp": {
+                "description": "When the sample was taken, in seconds since the Unix epoch",
+                "type": "number"
+            },
+            "_time": {
+                "description": "The sample time in RFC 3339, with `?time=rfc3339`",
+                "type": "string"
+            },
+            "_uptime_ms": {
+                "description": "Milliseconds since the agent started, with `?time=uptime`",
+                "type": "integer",
+                "minimum": 0
+            },
+            "_units": {
+                "description": "Unit system of memory and clock metrics with `--units`: \
+                    memory in GB (si) or GiB (binary) and clocks in GHz, under the same \
+                    keys as always; without `_units`, memory is in bytes and clocks are in MHz",
+                "enum": ["si", "binary"]
+            },
+            "_record": {
+                "description": "Kind of a record that isn't a sample, e.g. `billing`, `capabilities`, `devices`, `event`, `gap` or `topology`",
+                "type": "string"
+            },
+            "_sampling_timeout": {
+                "description": "Set when NVML didn't return within the sampling timeout",
+                "type": "boolean"
+            },
+            "_tenant": {
+                "description": "Tenant whose share of a sample this is, on the tenant's sinks",
+                "type": "string"
+            },
+            "_window_samples": {
+                "description": "Number of samples aggregated into this one, with `?every=`",
+                "type": "integer",
+                "minimum": 0
+            },
+            "_gpu.count": {
+                "description": "Number of GPUs on the node",
+                "type": "integer",
+                "minimum": 0
+            }
+        },
+        "patternProperties": {
+            "^gpu\\.[0-9]+\\.": scalar,
+            "^_gpu\\.[0-9]+\\.": scalar,
+            "^_agent\\.": scalar
+        },
+        "additionalProperties": scalar
+    })
+}
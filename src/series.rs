@@ -0,0 +1,106 @@
+/// A metric key as a Prometheus or InfluxDB series.
+///
+/// The first part of the key names the measurement and the indices become
+/// labels named after the part before them, so `gpu.0.powerWatts` is
+/// `symon_gpu_power_watts{gpu="0"}` in Prometheus and field `power_watts`
+/// of measurement `symon_gpu` with tag `gpu=0` in InfluxDB. The leading
+/// underscore of extended metrics is dropped: `_agent.sink.1.errors` is
+/// `symon_agent_sink_errors{sink="1"}`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Series {
+    pub measurement: String,
+    pub field: String,
+    pub labels: Labels,
+}
+
+/// Label names and values, e.g. `gpu` and `0`.
+pub type Labels = Vec<(String, String)>;
+
+impl Series {
+    /// The series of a metric key, or None for keys that describe the sample
+    /// rather than measure something, e.g. `_timestamp`.
+    pub fn of(key: &str) -> Option<Self> {
+        if key == "_timestamp" || key.starts_with("_type.") {
+            return None;
+        }
+        let mut parts = key.trim_start_matches('_').split('.');
+        let first = parts.next().filter(|part| !part.is_empty())?;
+        let mut series = Series {
+            measurement: "symon".to_string(),
+            field: String::new(),
+            labels: Vec::new(),
+        };
+        let mut fields = Vec::new();
+        let mut previous = first;
+        for part in parts {
+            if !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()) {
+                series.labels.push((snake_case(previous), part.to_string()));
+            } else {
+                fields.push(snake_case(part));
+            }
+            previous = part;
+        }
+        if fields.is_empty() {
+            // A top-level metric, e.g. `cuda_version`
+            series.field = snake_case(first);
+        } else {
+            series.measurement = format!("symon_{}", snake_case(first));
+            series.field = fields.join("_");
+        }
+        Some(series)
+    }
+
+    /// The Prometheus metric name.
+    pub fn name(&self) -> String {
+        if self.measurement == "symon" {
+            format!("symon_{}", self.field)
+        } else {
+            format!("{}_{}", self.measurement, self.field)
+        }
+    }
+}
+
+/// `powerWatts` to `power_watts`, with characters Prometheus doesn't allow in
+/// names replaced by underscores.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len() + 4);
+    let mut previous_lower = false;
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            if previous_lower {
+                snake.push('_');
+            }
+            snake.push(c.to_ascii_lowercase());
+            previous_lower = false;
+        } else if c.is_ascii_alphanumeric() {
+            snake.push(c);
+            previous_lower = true;
+        } else {
+            snake.push('_');
+            previous_lower = false;
+        }
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_keys_to_series() {
+        let series = Series::of("_agent.sink.1.latencyMs").unwrap();
+        assert_eq!(series.name(), "symon_agent_sink_latency_ms");
+        assert_eq!(series.labels, [("sink".to_string(), "1".to_string())]);
+        assert_eq!(
+            Series::of("_gpu.0.powerWatts").unwrap().field,
+            "power_watts"
+        );
+        assert_eq!(
+            Series::of("cuda_version").unwrap().name(),
+            "symon_cuda_version"
+        );
+        assert_eq!(Series::of("_timestamp"), None);
+        assert_eq!(Series::of("_type.gpu.0.energyJoules"), None);
+    }
+}
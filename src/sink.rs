@@ -0,0 +1,867 @@
+use crate::encoding::Encoding;
+use crate::error::{Result, SymonError};
+use crate::gap::SampleSpan;
+use crate::histogram;
+use crate::log;
+use crate::metrics::{MetricKey, Metrics};
+use crate::pipe::{Backpressure, PipeStats, PipeWriter};
+use crate::query::Aggregation;
+use crate::sink_file::{Compression, FileSink, RotationOptions, ZstdFrames};
+#[cfg(feature = "net")]
+use crate::sink_http::{BatchOptions, HttpSink};
+#[cfg(feature = "net")]
+use crate::sink_nats::NatsSink;
+#[cfg(feature = "net")]
+use crate::sink_redis::{RedisMode, RedisOptions, RedisSink};
+use crate::sink_smi::SmiSink;
+use crate::sink_status::{StatusSink, StatusThresholds};
+#[cfg(feature = "net")]
+use crate::sink_tcp::TcpSink;
+#[cfg(feature = "net")]
+use crate::sink_udp::{self, UdpSink};
+use crate::sink_window::WindowSink;
+#[cfg(feature = "net")]
+use crate::sink_zmq::ZmqSink;
+use crate::spool::SpoolingSink;
+use crate::timefmt::UtcDateTime;
+#[cfg(feature = "net")]
+use crate::tls::{self, Connector};
+use crate::units::{self, UnitSystem};
+#[cfg(feature = "net")]
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Once};
+use std::time::Duration;
+
+/// Destination for completed samples.
+///
+/// Sinks run on the writer thread, so a slow sink delays other sinks but never
+/// the sampling loop.
+pub trait Sink: Send {
+    /// Human-readable sink name used in logs and self-metrics.
+    fn name(&self) -> &str;
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Counters the sink keeps about itself, if any.
+    fn metrics(&self) -> Option<Arc<dyn SinkMetrics>> {
+        None
+    }
+
+    /// Samples that were accepted by `write` but couldn't be delivered after
+    /// all, for a spool to take back.
+    fn take_undelivered(&mut self) -> Vec<Metrics> {
+        Vec::new()
+    }
+
+    /// Samples that were accepted by `write` but lost since the last call,
+    /// e.g. dropped as the oldest output of a stalled reader, for the writer
+    /// to mark with a gap record in this sink's output.
+    fn take_lost(&mut self) -> SampleSpan {
+        SampleSpan::default()
+    }
+}
+
+/// Self-metrics a sink reports besides the writer's own per-sink counters.
+pub trait SinkMetrics: Send + Sync {
+    /// Add the counters of the sink at `index` as `_agent.sink.{index}.*`.
+    fn add_metrics(&self, index: usize, metrics: &mut Metrics);
+}
+
+/// Writes one JSON object per line to stdout.
+///
+/// Output goes through a `PipeWriter`, so a reader that stops reading is
+/// noticed and handled as `Backpressure` says.
+pub struct StdoutSink {
+    buf: Vec<u8>,
+    frames: Option<ZstdFrames>,
+    /// Samples in the zstd frame being collected.
+    frame_samples: SampleSpan,
+    /// A finished zstd frame and its samples that stdout didn't take yet
+    /// with `Backpressure::Spill`. Its samples were already accepted, so it
+    /// goes out before any new sample is.
+    pending_frame: Option<(Vec<u8>, SampleSpan)>,
+    backpressure: Backpressure,
+    pipe: Option<PipeWriter>,
+    stats: Arc<PipeStats>,
+}
+
+impl StdoutSink {
+    pub fn new() -> Self {
+        StdoutSink {
+            buf: Vec::with_capacity(4096),
+            frames: None,
+            frame_samples: SampleSpan::default(),
+            pending_frame: None,
+            backpressure: Backpressure::default(),
+            pipe: None,
+            stats: Arc::new(PipeStats::default()),
+        }
+    }
+
+    /// Write a zstd stream for piping to a file or another host, see `ZstdFrames`.
+    pub fn zstd() -> Self {
+        StdoutSink {
+            frames: Some(ZstdFrames::new()),
+            ..StdoutSink::new()
+        }
+    }
+
+    /// Set what happens to new samples while the reader of stdout has stalled.
+    /// With `Backpressure::Spill`, writes fail with `WouldBlock` meanwhile, so
+    /// the sink has to be wrapped in a `SpoolingSink`.
+    pub fn set_backpressure(&mut self, backpressure: Backpressure) {
+        self.backpressure = backpressure;
+    }
+
+    fn pipe(&mut self) -> io::Result<&mut PipeWriter> {
+        if self.pipe.is_none() {
+            self.pipe = Some(PipeWriter::spawn(self.backpressure, self.stats.clone())?);
+        }
+        Ok(self.pipe.as_mut().expect("pipe was just started"))
+    }
+
+    /// Write out the pending zstd frame, keeping it if stdout refuses it.
+    fn write_pending_frame(&mut self) -> io::Result<()> {
+        let Some((mut frame, samples)) = self.pending_frame.take() else {
+            return Ok(());
+        };
+        let result = self.pipe().and_then(|pipe| pipe.write(&mut frame, samples));
+        if result.is_err() {
+            self.pending_frame = Some((frame, samples));
+        }
+        result
+    }
+}
+
+impl Default for StdoutSink {
+    fn default() -> Self {
+        StdoutSink::new()
+    }
+}
+
+impl Sink for StdoutSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        // A frame stdout refused goes first; until it does, new samples are
+        // refused too rather than collected behind it
+        self.write_pending_frame()?;
+        metrics.to_json_line(&mut self.buf)?;
+        match self.frames.as_mut() {
+            Some(frames) => {
+                self.frame_samples.push(metrics);
+                if let Some(frame) = frames.push(&self.buf)? {
+                    let samples = std::mem::take(&mut self.frame_samples);
+                    self.pending_frame = Some((frame, samples));
+                    // This sample is in the frame and so accepted either way
+                    match self.write_pending_frame() {
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        result => return result,
+                    }
+                }
+                Ok(())
+            }
+            None => {
+                let mut line = self.buf.clone();
+                let mut samples = SampleSpan::default();
+                samples.push(metrics);
+                self.pipe()?.write(&mut line, samples)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending_frame()?;
+        if let Some(frame) = self
+            .frames
+            .as_mut()
+            .map(ZstdFrames::finish)
+            .transpose()?
+            .flatten()
+        {
+            let samples = std::mem::take(&mut self.frame_samples);
+            self.pending_frame = Some((frame, samples));
+            self.write_pending_frame()?;
+        }
+        match self.pipe.as_mut() {
+            Some(pipe) => pipe.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn metrics(&self) -> Option<Arc<dyn SinkMetrics>> {
+        Some(self.stats.clone())
+    }
+
+    fn take_lost(&mut self) -> SampleSpan {
+        self.pipe
+            .as_mut()
+            .map(PipeWriter::take_lost)
+            .unwrap_or_default()
+    }
+}
+
+/// Additional representations of the sample time a sink can emit besides
+/// the float epoch `_timestamp`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimeFields {
+    /// `_time` as an RFC 3339 string with nanosecond precision.
+    pub rfc3339: bool,
+    /// `_uptime_ms`, milliseconds on the agent's monotonic clock.
+    pub uptime: bool,
+}
+
+impl TimeFields {
+    /// Parse a list such as `rfc3339,uptime`.
+    fn parse(s: &str) -> Result<Self, String> {
+        let mut fields = TimeFields::default();
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            match field {
+                "epoch" => {}
+                "rfc3339" => fields.rfc3339 = true,
+                "uptime" => fields.uptime = true,
+                _ => return Err(format!("unknown time field: {:?}", field)),
+            }
+        }
+        Ok(fields)
+    }
+}
+
+/// Adds the selected time fields to each sample before passing it on.
+pub struct TimeFieldsSink {
+    inner: Box<dyn Sink>,
+    fields: TimeFields,
+    scratch: Metrics,
+}
+
+impl TimeFieldsSink {
+    pub fn new(inner: Box<dyn Sink>, fields: TimeFields) -> Self {
+        TimeFieldsSink {
+            inner,
+            fields,
+            scratch: Metrics::new(),
+        }
+    }
+}
+
+impl Sink for TimeFieldsSink {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        let Some(time) = metrics.time() else {
+            return self.inner.write(metrics);
+        };
+        self.scratch.copy_from(metrics);
+        if self.fields.rfc3339 {
+            let formatted = UtcDateTime::from_system_time(time.wall).rfc3339();
+            self.scratch.add_metric("_time", formatted);
+        }
+        if self.fields.uptime {
+            self.scratch
+                .add_metric("_uptime_ms", time.uptime.as_secs_f64() * 1000.0);
+        }
+        self.inner.write(&self.scratch)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn metrics(&self) -> Option<Arc<dyn SinkMetrics>> {
+        self.inner.metrics()
+    }
+
+    fn take_lost(&mut self) -> SampleSpan {
+        self.inner.take_lost()
+    }
+}
+
+/// How samples written to stdout are formatted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// One JSON object per line
+    Json,
+    /// A compact, colorized line per sample for humans
+    Status,
+    /// A table laid out like `nvidia-smi`
+    Smi,
+}
+
+/// Converts memory and clock metrics to a unit system before passing samples
+/// on, recording it as `_units`. Keys stay the same.
+pub struct UnitsSink {
+    inner: Box<dyn Sink>,
+    units: UnitSystem,
+    /// Scale of each metric seen.
+    scales: HashMap<MetricKey, Option<f64>>,
+    converted: Vec<(MetricKey, serde_json::Value)>,
+    scratch: Metrics,
+}
+
+impl UnitsSink {
+    pub fn new(inner: Box<dyn Sink>, units: UnitSystem) -> Self {
+        UnitsSink {
+            inner,
+            units,
+            scales: HashMap::new(),
+            converted: Vec::new(),
+            scratch: Metrics::new(),
+        }
+    }
+}
+
+impl Sink for UnitsSink {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        self.converted.clear();
+        metrics.for_each(|key, value| {
+            let Some(number) = value.as_f64() else {
+                return;
+            };
+            let units = self.units;
+            let scale = *self
+                .scales
+                .entry(key.clone())
+                .or_insert_with(|| units.scale(key));
+            if let Some(scale) = scale {
+                self.converted.push((key.clone(), (number * scale).into()));
+            }
+        });
+        if self.converted.is_empty() {
+            return self.inner.write(metrics);
+        }
+        self.scratch.copy_from(metrics);
+        for (key, value) in self.converted.drain(..) {
+            self.scratch.add_metric(key, value);
+        }
+        self.scratch.add_metric("_units", self.units.as_str());
+        self.inner.write(&self.scratch)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn metrics(&self) -> Option<Arc<dyn SinkMetrics>> {
+        self.inner.metrics()
+    }
+
+    fn take_lost(&mut self) -> SampleSpan {
+        self.inner.take_lost()
+    }
+}
+
+/// Options shared by all sinks created from command-line specs.
+#[derive(Clone)]
+pub struct SinkOptions {
+    /// Directory to spool samples to while a network sink is unreachable.
+    pub spool_dir: Option<PathBuf>,
+    /// Maximum total size of each sink's spool in bytes.
+    pub spool_max_bytes: u64,
+    /// Rotation policy for file sinks.
+    pub rotation: RotationOptions,
+    /// Format of the stdout sink.
+    pub format: OutputFormat,
+    /// Color thresholds for `OutputFormat::Status`.
+    pub status_thresholds: StatusThresholds,
+    /// Redraw `OutputFormat::Smi` tables in place.
+    pub watch: bool,
+    /// Certificates for `tcps://` and `https://` sinks.
+    #[cfg(feature = "net")]
+    pub tls: tls::ClientOptions,
+    /// Bearer token sent by HTTP and NATS sinks, and the password of Redis sinks.
+    pub token: Option<String>,
+    /// Encoding of samples written to files and network sinks.
+    pub encoding: Encoding,
+    /// Units of memory and clock metrics in JSON and binary samples.
+    pub units: UnitSystem,
+    /// What JSON written to stdout does while its reader has stalled.
+    pub stdout_backpressure: Backpressure,
+}
+
+/// Per-sink options given as a query string after the spec.
+#[derive(Default)]
+struct SinkParams {
+    time_fields: TimeFields,
+    /// Pass samples on at most this often.
+    every: Option<Duration>,
+    /// Aggregate samples over `every` instead of passing on the latest.
+    aggregation: Option<Aggregation>,
+    /// Percentiles of power, utilization and temperature over `every`.
+    percentiles: Vec<f64>,
+    /// Output format, overriding `SinkOptions::format`. Only stdout supports
+    /// formats other than JSON.
+    format: Option<OutputFormat>,
+    /// Batching of HTTP sinks.
+    batch: Option<usize>,
+    linger: Option<Duration>,
+    /// Compression of HTTP requests, files and stdout, overriding
+    /// `RotationOptions::compression` for the latter two.
+    compress: Option<Compression>,
+    /// Redis delivery mode, key prefix and stream length.
+    #[cfg(feature = "net")]
+    mode: Option<RedisMode>,
+    prefix: Option<String>,
+    max_len: Option<u64>,
+    /// Wait for NATS JetStream acknowledgments.
+    jetstream: bool,
+    /// Encoding, overriding `SinkOptions::encoding`. Stdout is always text.
+    encoding: Option<Encoding>,
+    /// Datagram size limit of UDP sinks.
+    max_size: Option<u64>,
+    /// Unit system, overriding `SinkOptions::units`.
+    units: Option<UnitSystem>,
+}
+
+/// Create a sink from a spec such as `stdout`, `file:///var/log/symon.jsonl`,
+/// `tcp://collector:9000` or `http://collector:8080/ingest`, or their TLS
+/// counterparts `tcps://` and `https://`. `zmq://127.0.0.1:5556` publishes to
+/// ZeroMQ subscribers connecting to that loopback address. `redis://host:6379` (or
+/// `rediss://`) adds samples to the stream `symon:<host name>`, and
+/// `nats://host:4222` (or `natss://`) publishes to `symon.<host name>.<gpu>`.
+/// `udp://host:port` sends one datagram per sample.
+///
+/// A query suffix sets per-sink options: `time=rfc3339,uptime` selects extra
+/// time fields, `every=10s` writes at most one sample per interval,
+/// `agg=mean` (or any `symon query` aggregation) aggregates the samples of
+/// each interval instead of keeping the latest, and `format=status` picks the
+/// stdout format, e.g. `stdout?format=status` next to
+/// `file:///var/log/symon.jsonl?every=1m&agg=max`. `percentiles=50,90,99`
+/// adds those percentiles of each GPU's power, utilization and temperature
+/// over each interval, e.g. `gpu.0.powerWattsP99`. HTTP sinks also take
+/// `batch=500` (samples per request), `linger=10s` (how long a batch may wait
+/// to fill up) and `compress=gzip` or `compress=zstd`, as do file sinks
+/// (overriding `--compress`, and also taking `compress=zstd-stream`) and
+/// stdout (`compress=zstd-stream` only). Redis sinks take
+/// `mode=stream`, `mode=pubsub` or `mode=both`, `prefix=fleet` (for the key
+/// `fleet:<host name>`) and `maxlen=100000` to trim streams. NATS sinks take
+/// `prefix` too, and `jetstream=true` to wait for each message to be stored.
+/// UDP sinks take `maxsize=1400` (bytes per datagram). All sinks but stdout
+/// take `encoding=msgpack`, `encoding=cbor` or `encoding=protobuf` to write
+/// binary samples. `units=si` or `units=binary` converts memory and clock
+/// metrics (overriding `--units`); text formats on stdout have their own.
+pub fn from_spec(spec: &str, options: &SinkOptions) -> Result<Box<dyn Sink>> {
+    let (spec, params) = match spec.split_once('?') {
+        Some((spec, query)) => (spec, parse_query(query)?),
+        None => (spec, SinkParams::default()),
+    };
+    if params.aggregation.is_some() && params.every.is_none() {
+        return Err(SymonError::Sink(format!("{}: agg requires every", spec)));
+    }
+    if !params.percentiles.is_empty() && params.every.is_none() {
+        return Err(SymonError::Sink(format!(
+            "{}: percentiles requires every",
+            spec
+        )));
+    }
+    let mut sink = from_base_spec(spec, &params, options)?;
+    let units = params.units.unwrap_or(options.units);
+    let text = params.format.unwrap_or(if spec == "stdout" {
+        options.format
+    } else {
+        OutputFormat::Json
+    }) != OutputFormat::Json;
+    if units != UnitSystem::Raw && !text {
+        sink = Box::new(UnitsSink::new(sink, units));
+    }
+    if params.time_fields != TimeFields::default() {
+        sink = Box::new(TimeFieldsSink::new(sink, params.time_fields));
+    }
+    if let Some(every) = params.every {
+        sink = Box::new(
+            WindowSink::new(sink, every, params.aggregation).with_percentiles(params.percentiles),
+        );
+    }
+    Ok(sink)
+}
+
+/// The file a `file://` spec writes to, e.g. to allow writing there in a
+/// sandbox.
+pub fn file_path(spec: &str) -> Option<&Path> {
+    let spec = spec.split_once('?').map_or(spec, |(spec, _)| spec);
+    spec.strip_prefix("file://")
+        .filter(|path| !path.is_empty())
+        .map(Path::new)
+}
+
+fn parse_query(query: &str) -> Result<SinkParams> {
+    let mut params = SinkParams::default();
+    for param in query.split('&') {
+        let invalid = |e: String| SymonError::Sink(format!("invalid {:?}: {}", param, e));
+        match param.split_once('=') {
+            Some(("time", value)) => {
+                params.time_fields = TimeFields::parse(value).map_err(invalid)?
+            }
+            Some(("every", value)) => {
+                params.every = Some(units::parse_duration(value).map_err(invalid)?)
+            }
+            Some(("agg", value)) => {
+                params.aggregation = Some(Aggregation::parse(value).map_err(invalid)?)
+            }
+            Some(("percentiles", value)) => {
+                params.percentiles = value
+                    .split(',')
+                    .map(histogram::parse_percentile)
+                    .collect::<std::result::Result<_, _>>()
+                    .map_err(invalid)?
+            }
+            Some(("format", value)) => {
+                params.format = Some(clap::ValueEnum::from_str(value, true).map_err(invalid)?)
+            }
+            Some(("batch", value)) => {
+                params.batch = Some(value.parse().map_err(|e| invalid(format!("{}", e)))?)
+            }
+            Some(("linger", value)) => {
+                params.linger = Some(units::parse_duration(value).map_err(invalid)?)
+            }
+            Some(("compress", value)) => {
+                params.compress = Some(clap::ValueEnum::from_str(value, true).map_err(invalid)?)
+            }
+            #[cfg(feature = "net")]
+            Some(("mode", value)) => {
+                params.mode = Some(clap::ValueEnum::from_str(value, true).map_err(invalid)?)
+            }
+            Some(("prefix", value)) if !value.is_empty() => params.prefix = Some(value.to_string()),
+            Some(("jetstream", value)) => {
+                params.jetstream = value.parse().map_err(|e| invalid(format!("{}", e)))?
+            }
+            Some(("encoding", value)) => {
+                params.encoding = Some(clap::ValueEnum::from_str(value, true).map_err(invalid)?)
+            }
+            Some(("units", value)) => {
+                params.units = Some(clap::ValueEnum::from_str(value, true).map_err(invalid)?)
+            }
+            Some(("maxsize", value)) => {
+                params.max_size = Some(units::parse_size(value).map_err(invalid)?)
+            }
+            Some(("maxlen", value)) => {
+                params.max_len = Some(value.parse().map_err(|e| invalid(format!("{}", e)))?)
+            }
+            _ => {
+                return Err(SymonError::Sink(format!(
+                    "unsupported sink option: {:?}",
+                    param
+                )))
+            }
+        }
+    }
+    Ok(params)
+}
+
+fn from_base_spec(spec: &str, params: &SinkParams, options: &SinkOptions) -> Result<Box<dyn Sink>> {
+    let (scheme, target) = spec.split_once("://").unwrap_or((spec, ""));
+    let format = params.format;
+    if scheme != "stdout" && format.is_some_and(|format| format != OutputFormat::Json) {
+        return Err(SymonError::Sink(format!(
+            "{}: only stdout supports formats other than json",
+            spec
+        )));
+    }
+    let batched = params.batch.is_some() || params.linger.is_some();
+    if !matches!(scheme, "http" | "https") && batched {
+        return Err(SymonError::Sink(format!(
+            "{}: batch and linger only apply to http sinks",
+            spec
+        )));
+    }
+    if !matches!(scheme, "http" | "https" | "file" | "stdout") && params.compress.is_some() {
+        return Err(SymonError::Sink(format!(
+            "{}: compress only applies to http, file and stdout sinks",
+            spec
+        )));
+    }
+    if scheme == "stdout"
+        && params
+            .compress
+            .is_some_and(|c| c != Compression::ZstdStream)
+    {
+        return Err(SymonError::Sink(format!(
+            "{}: stdout only supports zstd-stream compression",
+            spec
+        )));
+    }
+    if matches!(scheme, "http" | "https") && params.compress == Some(Compression::ZstdStream) {
+        return Err(SymonError::Sink(format!(
+            "{}: http sinks compress each batch, use compress=zstd",
+            spec
+        )));
+    }
+    #[cfg(feature = "net")]
+    let redis = params.mode.is_some() || params.max_len.is_some();
+    #[cfg(not(feature = "net"))]
+    let redis = params.max_len.is_some();
+    if !matches!(scheme, "redis" | "rediss") && redis {
+        return Err(SymonError::Sink(format!(
+            "{}: mode and maxlen only apply to redis sinks",
+            spec
+        )));
+    }
+    if scheme == "stdout" && params.encoding.is_some_and(|e| e != Encoding::Json) {
+        return Err(SymonError::Sink(format!(
+            "{}: stdout only supports json encoding",
+            spec
+        )));
+    }
+    if scheme != "udp" && params.max_size.is_some() {
+        return Err(SymonError::Sink(format!(
+            "{}: maxsize only applies to udp sinks",
+            spec
+        )));
+    }
+    let encoding = params.encoding.unwrap_or(options.encoding);
+    #[cfg(not(feature = "compression"))]
+    if params.compress.unwrap_or(match scheme {
+        "file" | "stdout" => options.rotation.compression,
+        _ => Compression::None,
+    }) != Compression::None
+    {
+        return Err(SymonError::Sink(format!(
+            "{}: compression requires the `compression` feature",
+            spec
+        )));
+    }
+    #[cfg(not(feature = "protobuf"))]
+    if encoding == Encoding::Protobuf {
+        return Err(SymonError::Sink(format!(
+            "{}: protobuf encoding requires the `protobuf` feature",
+            spec
+        )));
+    }
+    if !matches!(scheme, "nats" | "natss") && params.jetstream {
+        return Err(SymonError::Sink(format!(
+            "{}: jetstream only applies to nats sinks",
+            spec
+        )));
+    }
+    if !matches!(scheme, "redis" | "rediss" | "nats" | "natss") && params.prefix.is_some() {
+        return Err(SymonError::Sink(format!(
+            "{}: prefix only applies to redis and nats sinks",
+            spec
+        )));
+    }
+    let (sink, is_network): (Box<dyn Sink>, bool) = match scheme {
+        "stdout" => match format.unwrap_or(options.format) {
+            // `--compress zstd-stream` only applies when stdout is piped
+            OutputFormat::Json
+                if params.compress == Some(Compression::ZstdStream)
+                    || (params.compress.is_none()
+                        && options.rotation.compression == Compression::ZstdStream
+                        && !io::stdout().is_terminal()) =>
+            {
+                stdout_sink(StdoutSink::zstd(), options)?
+            }
+            OutputFormat::Json => stdout_sink(StdoutSink::new(), options)?,
+            OutputFormat::Status => (Box::new(StatusSink::new(options.status_thresholds)), false),
+            OutputFormat::Smi => (Box::new(SmiSink::new(options.watch)), false),
+        },
+        "file" if !target.is_empty() => {
+            let rotation = RotationOptions {
+                compression: params.compress.unwrap_or(options.rotation.compression),
+                ..options.rotation.clone()
+            };
+            let sink = FileSink::new(Path::new(target), rotation, encoding)
+                .map_err(|e| SymonError::Sink(format!("failed to open {}: {}", target, e)))?;
+            (Box::new(sink), false)
+        }
+        #[cfg(feature = "net")]
+        "tcp" | "tcps" if !target.is_empty() => {
+            let tls = (scheme == "tcps")
+                .then(|| connector(spec, options))
+                .transpose()?;
+            (Box::new(TcpSink::new(target, tls, encoding)), true)
+        }
+        #[cfg(feature = "net")]
+        "redis" | "rediss" if !target.is_empty() => {
+            let tls = (scheme == "rediss")
+                .then(|| connector(spec, options))
+                .transpose()?;
+            let redis = RedisOptions {
+                mode: params.mode.unwrap_or_default(),
+                prefix: params.prefix.clone().unwrap_or_else(|| "symon".to_string()),
+                max_len: params.max_len,
+                password: options.token.clone(),
+                encoding,
+            };
+            (Box::new(RedisSink::new(target, tls, redis)), true)
+        }
+        #[cfg(feature = "net")]
+        "nats" | "natss" if !target.is_empty() => {
+            let tls = (scheme == "natss")
+                .then(|| connector(spec, options))
+                .transpose()?;
+            let prefix = params.prefix.as_deref().unwrap_or("symon");
+            let token = options.token.clone();
+            let sink = NatsSink::new(target, prefix, params.jetstream, encoding, tls, token);
+            (Box::new(sink), true)
+        }
+        #[cfg(feature = "net")]
+        "udp" if !target.is_empty() => {
+            let max_size = params
+                .max_size
+                .map_or(sink_udp::MAX_DATAGRAM, |size| size as usize);
+            let sink = UdpSink::new(target, encoding, max_size)
+                .map_err(|e| SymonError::Sink(format!("failed to open {}: {}", spec, e)))?;
+            (Box::new(sink), false)
+        }
+        #[cfg(feature = "net")]
+        "zmq" if !target.is_empty() => {
+            let sink = ZmqSink::bind(target, encoding)
+                .map_err(|e| SymonError::Sink(format!("failed to bind {}: {}", spec, e)))?;
+            (Box::new(sink), false)
+        }
+        #[cfg(feature = "net")]
+        "http" | "https" if !target.is_empty() => {
+            let tls = (scheme == "https")
+                .then(|| connector(spec, options))
+                .transpose()?;
+            let defaults = BatchOptions::default();
+            let batch = BatchOptions {
+                max_samples: params.batch.unwrap_or(defaults.max_samples),
+                max_delay: params.linger.unwrap_or(defaults.max_delay),
+                compression: params.compress.unwrap_or(defaults.compression),
+                encoding,
+            };
+            let mut sink = HttpSink::new(target, batch, tls, options.token.clone())
+                .map_err(|e| SymonError::Sink(format!("failed to start {}: {}", spec, e)))?;
+            if options.spool_dir.is_some() {
+                sink.return_undelivered();
+            }
+            (Box::new(sink), true)
+        }
+        #[cfg(not(feature = "net"))]
+        "tcp" | "tcps" | "redis" | "rediss" | "nats" | "natss" | "udp" | "zmq" | "http"
+        | "https" => {
+            return Err(SymonError::Sink(format!(
+                "{}: network sinks require the `net` feature",
+                spec
+            )))
+        }
+        _ => return Err(SymonError::Sink(format!("unsupported sink: {:?}", spec))),
+    };
+
+    match &options.spool_dir {
+        Some(dir) if is_network => {
+            let dir = dir.join(spool_dir_name(spec));
+            let spooling = SpoolingSink::new(sink, &dir, options.spool_max_bytes).map_err(|e| {
+                SymonError::Sink(format!("failed to open spool {}: {}", dir.display(), e))
+            })?;
+            Ok(Box::new(spooling))
+        }
+        _ => Ok(sink),
+    }
+}
+
+/// `sink` with the backpressure of `options`, spooling while the reader of
+/// stdout has stalled for `Backpressure::Spill`.
+fn stdout_sink(mut sink: StdoutSink, options: &SinkOptions) -> Result<(Box<dyn Sink>, bool)> {
+    sink.set_backpressure(options.stdout_backpressure);
+    if options.stdout_backpressure != Backpressure::Spill {
+        return Ok((Box::new(sink), false));
+    }
+    let Some(dir) = &options.spool_dir else {
+        return Err(SymonError::Sink(
+            "stdout: spilling requires --spool-dir".to_string(),
+        ));
+    };
+    let dir = dir.join("stdout");
+    // What spilled from stdout belongs to the reader of this run, not to the
+    // next one; sinks rebuilt on reload keep it
+    static DISCARD_LEFTOVERS: Once = Once::new();
+    let mut discarded = Ok(());
+    DISCARD_LEFTOVERS.call_once(|| discarded = discard_spool(&dir));
+    discarded
+        .map_err(|e| SymonError::Sink(format!("failed to clear spool {}: {}", dir.display(), e)))?;
+    let spooling = SpoolingSink::new(Box::new(sink), &dir, options.spool_max_bytes)
+        .map_err(|e| SymonError::Sink(format!("failed to open spool {}: {}", dir.display(), e)))?;
+    Ok((Box::new(spooling), false))
+}
+
+/// Remove the spool at `dir`, left over from an earlier run.
+fn discard_spool(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Ok(()) => {
+            log::info!("Discarded stdout spool {} of an earlier run", dir.display());
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(feature = "net")]
+fn connector(spec: &str, options: &SinkOptions) -> Result<Connector> {
+    Connector::new(&options.tls)
+        .map_err(|e| SymonError::Sink(format!("{}: invalid TLS settings: {}", spec, e)))
+}
+
+/// Split a sample into the metrics of each device (`gpu.<i>.…`, `_gpu.<i>.…`
+/// and `gpu.process.<i>.…`) and the remaining node metrics (under `None`),
+/// for sinks that publish per-device messages. Every part carries the sample
+/// time; parts left over from earlier samples are emptied, not removed.
+#[cfg(feature = "net")]
+pub(crate) fn split_by_device(metrics: &Metrics, parts: &mut BTreeMap<Option<usize>, Metrics>) {
+    for part in parts.values_mut() {
+        part.clear();
+    }
+    metrics.for_each(|key, value| {
+        parts
+            .entry(device_of(key))
+            .or_default()
+            .add_metric(key.clone(), value.clone());
+    });
+    if let Some(time) = metrics.time() {
+        for part in parts.values_mut().filter(|part| !part.is_empty()) {
+            part.set_time(time);
+        }
+    }
+}
+
+#[cfg(feature = "net")]
+fn device_of(key: &str) -> Option<usize> {
+    let rest = key.strip_prefix('_').unwrap_or(key).strip_prefix("gpu.")?;
+    let rest = rest.strip_prefix("process.").unwrap_or(rest);
+    let (index, _) = rest.split_once('.')?;
+    index.parse().ok()
+}
+
+/// Each network sink gets its own spool subdirectory derived from its spec.
+fn spool_dir_name(spec: &str) -> String {
+    spec.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_path_of_file_specs() {
+        assert_eq!(
+            file_path("file:///var/log/symon.jsonl"),
+            Some(Path::new("/var/log/symon.jsonl"))
+        );
+        assert_eq!(
+            file_path("file://out.jsonl?time=rfc3339"),
+            Some(Path::new("out.jsonl"))
+        );
+        assert_eq!(file_path("file://"), None);
+        assert_eq!(file_path("stdout"), None);
+        assert_eq!(file_path("tcp://localhost:9000"), None);
+    }
+}
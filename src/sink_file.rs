@@ -0,0 +1,475 @@
+use crate::encoding::Encoding;
+use crate::log;
+use crate::metrics::Metrics;
+use crate::sink::Sink;
+use crate::timefmt::UtcDateTime;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A zstd stream ends its current frame at least this often, so readers
+/// tailing it can decompress everything written up to then.
+const FRAME_INTERVAL: Duration = Duration::from_secs(10);
+/// Uncompressed size at which a frame is ended early.
+const MAX_FRAME_BYTES: usize = 1024 * 1024;
+
+/// Compression of output files. gzip and zstd compress files once they are
+/// rotated; zstd-stream compresses files (and stdout, when piped) as they are
+/// written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    /// zstd frames written as samples come in, see `ZstdFrames`
+    ZstdStream,
+}
+
+impl Compression {
+    fn extension(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gz"),
+            Compression::Zstd | Compression::ZstdStream => Some("zst"),
+        }
+    }
+
+    /// Compress an in-memory buffer.
+    #[cfg(feature = "net")]
+    pub(crate) fn encode(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(not(feature = "compression"))]
+            _ => Err(unsupported()),
+            #[cfg(feature = "compression")]
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "compression")]
+            Compression::Zstd | Compression::ZstdStream => zstd_frame(data),
+        }
+    }
+}
+
+/// Compress `data` into a single zstd frame.
+#[cfg(feature = "compression")]
+fn zstd_frame(data: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::encode_all(data, 0)
+}
+
+#[cfg(not(feature = "compression"))]
+fn zstd_frame(_data: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+/// What compressing fails with without the `compression` feature; sinks
+/// refuse to start with compression then, see `sink::from_spec`.
+#[cfg(not(feature = "compression"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "compression requires the `compression` feature",
+    )
+}
+
+/// Collects records into zstd frames for live compressed streams.
+///
+/// Each frame is complete on its own, and zstd decoders read concatenated
+/// frames as one stream, so the output can be followed with e.g.
+/// `tail -c +1 -f samples.jsonl.zst | zstdcat` while it is being written.
+pub(crate) struct ZstdFrames {
+    pending: Vec<u8>,
+    started: Instant,
+}
+
+impl ZstdFrames {
+    pub(crate) fn new() -> Self {
+        ZstdFrames {
+            pending: Vec::with_capacity(64 * 1024),
+            started: Instant::now(),
+        }
+    }
+
+    /// Add a record, returning the compressed frame if it's due.
+    pub(crate) fn push(&mut self, record: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        if self.pending.is_empty() {
+            self.started = Instant::now();
+        }
+        self.pending.extend_from_slice(record);
+        if self.pending.len() >= MAX_FRAME_BYTES || self.started.elapsed() >= FRAME_INTERVAL {
+            self.finish()
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// End the current frame, if it holds any records.
+    pub(crate) fn finish(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+        let frame = zstd_frame(&self.pending)?;
+        self.pending.clear();
+        Ok(Some(frame))
+    }
+}
+
+/// Rotation and retention policy for a file sink.
+#[derive(Clone, Debug)]
+pub struct RotationOptions {
+    /// Rotate once the file would exceed this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Rotate once the file has been open this long.
+    pub max_age: Option<Duration>,
+    pub compression: Compression,
+    /// Number of rotated files to keep; older ones are deleted.
+    pub retain: Option<usize>,
+}
+
+/// Appends samples to a file, as JSON lines or concatenated binary records,
+/// with optional rotation.
+///
+/// Rotated files are renamed to `<name>.<UTC timestamp>` and, if configured,
+/// compressed and pruned on a background thread so rotation never stalls the
+/// writer thread. With zstd-stream, the file is instead written as a series of zstd
+/// frames, see `ZstdFrames`, and rotated files only need renaming.
+pub struct FileSink {
+    name: String,
+    path: PathBuf,
+    options: RotationOptions,
+    encoding: Encoding,
+    file: Option<BufWriter<File>>,
+    frames: Option<ZstdFrames>,
+    size: u64,
+    opened_at: Instant,
+    buf: Vec<u8>,
+}
+
+impl FileSink {
+    pub fn new(path: &Path, options: RotationOptions, encoding: Encoding) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut sink = FileSink {
+            name: format!("file://{}", path.display()),
+            path: path.to_path_buf(),
+            frames: (options.compression == Compression::ZstdStream).then(ZstdFrames::new),
+            options,
+            encoding,
+            file: None,
+            size: 0,
+            opened_at: Instant::now(),
+            buf: Vec::with_capacity(4096),
+        };
+        sink.open()?;
+        Ok(sink)
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.file = Some(BufWriter::new(file));
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+
+    fn needs_rotation(&self, incoming: u64) -> bool {
+        let too_big = self
+            .options
+            .max_bytes
+            .is_some_and(|max| self.size > 0 && self.size + incoming > max);
+        let too_old = self
+            .options
+            .max_age
+            .is_some_and(|max| self.opened_at.elapsed() >= max);
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+
+        let stamp = UtcDateTime::from_system_time(SystemTime::now()).compact();
+        // Streams compressed as they are written only get the extension
+        let suffix = |stamp: String| match &self.frames {
+            Some(_) => format!("{}.zst", stamp),
+            None => stamp,
+        };
+        let mut rotated = append_suffix(&self.path, &suffix(stamp.clone()));
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = append_suffix(&self.path, &suffix(format!("{}-{}", stamp, n)));
+            n += 1;
+        }
+        fs::rename(&self.path, &rotated)?;
+        self.open()?;
+
+        let path = self.path.clone();
+        let options = self.options.clone();
+        let compression = match &self.frames {
+            Some(_) => Compression::None,
+            None => options.compression,
+        };
+        thread::Builder::new()
+            .name("rotate".to_string())
+            .spawn(move || {
+                if let Err(e) = compress(&rotated, compression) {
+                    log::error!("Error compressing {}: {}", rotated.display(), e);
+                }
+                if let Some(retain) = options.retain {
+                    if let Err(e) = prune(&path, retain) {
+                        log::error!("Error pruning rotated files of {}: {}", path.display(), e);
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Write a record or frame, rotating first if it's time.
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        let len = data.len() as u64;
+        if self.file.is_none() || self.needs_rotation(len) {
+            if self.file.is_some() {
+                self.rotate()?;
+            } else {
+                self.open()?;
+            }
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(data)?;
+            // Flush every record or frame so the file can be tailed
+            file.flush()?;
+            self.size += len;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for FileSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        self.encoding.encode(metrics, &mut self.buf)?;
+        if let Some(frames) = self.frames.as_mut() {
+            if let Some(frame) = frames.push(&self.buf)? {
+                self.append(&frame)?;
+            }
+            return Ok(());
+        }
+        let buf = std::mem::take(&mut self.buf);
+        let result = self.append(&buf);
+        self.buf = buf;
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(frame) = self
+            .frames
+            .as_mut()
+            .map(ZstdFrames::finish)
+            .transpose()?
+            .flatten()
+        {
+            self.append(&frame)?;
+        }
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn append_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Compress `path` into `path.<ext>` and remove the original.
+#[cfg(feature = "compression")]
+fn compress(path: &Path, compression: Compression) -> io::Result<()> {
+    let Some(extension) = compression.extension() else {
+        return Ok(());
+    };
+    let target = append_suffix(path, extension);
+    let mut input = File::open(path)?;
+    let output = BufWriter::new(File::create(&target)?);
+    match compression {
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+            io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+        Compression::Zstd | Compression::ZstdStream => {
+            let mut encoder = zstd::Encoder::new(output, 0)?;
+            io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+        Compression::None => {}
+    }
+    fs::remove_file(path)
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(_path: &Path, compression: Compression) -> io::Result<()> {
+    match compression.extension() {
+        Some(_) => Err(unsupported()),
+        None => Ok(()),
+    }
+}
+
+/// Delete all but the `retain` newest rotated files of `path`. Only files
+/// named as `rotate` names them are considered, so other files sharing the
+/// prefix, e.g. `metrics.jsonl.bak`, are left alone.
+fn prune(path: &Path, retain: usize) -> io::Result<()> {
+    let (Some(dir), Some(base)) = (path.parent(), path.file_name()) else {
+        return Ok(());
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let prefix = format!("{}.", base.to_string_lossy());
+
+    // Rotated names embed a UTC timestamp, so lexical order is chronological
+    let mut rotated: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.strip_prefix(&prefix).is_some_and(is_rotated_suffix)
+        })
+        .map(|entry| entry.path())
+        .collect();
+    rotated.sort();
+    let excess = rotated.len().saturating_sub(retain);
+    for old in &rotated[..excess] {
+        fs::remove_file(old)?;
+    }
+    Ok(())
+}
+
+/// Whether `suffix` is what `FileSink::rotate` appends to rotated files: a
+/// compact UTC timestamp, a counter if the name was taken, and the extension
+/// of the compression, e.g. `20261016T094500Z-1.gz`.
+fn is_rotated_suffix(suffix: &str) -> bool {
+    let stem = [".gz", ".zst"]
+        .iter()
+        .find_map(|extension| suffix.strip_suffix(extension))
+        .unwrap_or(suffix);
+    let (stamp, counter) = match stem.split_once('-') {
+        Some((stamp, counter)) => (stamp, Some(counter)),
+        None => (stem, None),
+    };
+    let bytes = stamp.as_bytes();
+    let stamp_ok = bytes.len() == 16
+        && bytes[..8].iter().all(u8::is_ascii_digit)
+        && bytes[8] == b'T'
+        && bytes[9..15].iter().all(u8::is_ascii_digit)
+        && bytes[15] == b'Z';
+    let counter_ok = counter.is_none_or(|c| !c.is_empty() && c.bytes().all(|b| b.is_ascii_digit()));
+    stamp_ok && counter_ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_rotated_names() {
+        for suffix in [
+            "20261016T094500Z",
+            "20261016T094500Z-2",
+            "20261016T094500Z.gz",
+            "20261016T094500Z-12.zst",
+        ] {
+            assert!(is_rotated_suffix(suffix), "{}", suffix);
+        }
+        for suffix in [
+            "bak",
+            "lock",
+            "20261016T094500Z.bak",
+            "20261016T0945Z",
+            "20261016T094500Z-",
+            "2026101xT094500Z",
+        ] {
+            assert!(!is_rotated_suffix(suffix), "{}", suffix);
+        }
+    }
+
+    #[test]
+    fn prunes_only_rotated_files() {
+        let dir = std::env::temp_dir().join(format!("symon-prune-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.jsonl");
+        for name in [
+            "metrics.jsonl",
+            "metrics.jsonl.bak",
+            "metrics.jsonl.20261016T094500Z.gz",
+            "metrics.jsonl.20261016T094600Z.gz",
+            "metrics.jsonl.20261016T094700Z",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        prune(&path, 1).unwrap();
+        let mut left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                "metrics.jsonl",
+                "metrics.jsonl.20261016T094700Z",
+                "metrics.jsonl.bak"
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn only_zstd_stream_compresses_the_live_file() {
+        let dir = std::env::temp_dir().join(format!("symon-zstd-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut sample = Metrics::new();
+        sample.add_metric("gpu.0.temp", 60);
+        for (compression, name) in [
+            (Compression::Zstd, "rotated.jsonl"),
+            (Compression::ZstdStream, "streamed.jsonl"),
+        ] {
+            let options = RotationOptions {
+                max_bytes: None,
+                max_age: None,
+                compression,
+                retain: None,
+            };
+            let path = dir.join(name);
+            let mut sink = FileSink::new(&path, options, Encoding::Json).unwrap();
+            sink.write(&sample).unwrap();
+            sink.flush().unwrap();
+            let data = fs::read(&path).unwrap();
+            let text = match compression {
+                Compression::ZstdStream => zstd::decode_all(&data[..]).unwrap(),
+                _ => data,
+            };
+            assert_eq!(text, b"{\"gpu.0.temp\":60}\n", "{:?}", compression);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,493 @@
+use crate::encoding::Encoding;
+use crate::gap::SampleSpan;
+use crate::log;
+use crate::metrics::Metrics;
+use crate::sink::{Sink, SinkMetrics};
+use crate::sink_file::Compression;
+use crate::tls::{self, Connector};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// Close a batch once its uncompressed body reaches this size.
+const MAX_BATCH_BYTES: usize = 1 << 20;
+/// Batches waiting to be sent before new samples are refused.
+const MAX_PENDING_BATCHES: usize = 16;
+/// Backoff before the first retry; doubled for every further attempt.
+const RETRY_BASE: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+/// Give up on a batch after this many attempts.
+const MAX_ATTEMPTS: u32 = 10;
+/// How long flushing waits for pending batches to be delivered.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// When a batch is closed and sent.
+#[derive(Clone, Copy, Debug)]
+pub struct BatchOptions {
+    /// Close a batch once it holds this many samples.
+    pub max_samples: usize,
+    /// Close a batch once its first sample is this old.
+    pub max_delay: Duration,
+    /// Compression of request bodies, sent as `Content-Encoding`.
+    pub compression: Compression,
+    /// Encoding of the samples in a batch, sent as `Content-Type`.
+    pub encoding: Encoding,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            max_samples: 100,
+            max_delay: Duration::from_secs(5),
+            compression: Compression::None,
+            encoding: Encoding::Json,
+        }
+    }
+}
+
+/// Self-metrics of an HTTP sink, reported as `_agent.sink.{i}.*`.
+#[derive(Default)]
+struct HttpStats {
+    keys: OnceLock<[&'static str; 5]>,
+    /// Time from the first sample of the last delivered batch to its acknowledgment.
+    batch_latency_us: AtomicU64,
+    pending_batches: AtomicUsize,
+    retries: AtomicU64,
+    dropped_batches: AtomicU64,
+    dropped_samples: AtomicU64,
+}
+
+impl SinkMetrics for HttpStats {
+    fn add_metrics(&self, index: usize, metrics: &mut Metrics) {
+        let [latency, pending, retries, dropped_batches, dropped_samples] =
+            *self.keys.get_or_init(|| {
+                [
+                    "batchLatencyMs",
+                    "pendingBatches",
+                    "retries",
+                    "droppedBatches",
+                    "droppedSamples",
+                ]
+                .map(|name| &*Box::leak(format!("_agent.sink.{}.{}", index, name).into_boxed_str()))
+            });
+        let latency_ms = self.batch_latency_us.load(Ordering::Relaxed) as f64 / 1000.0;
+        metrics.add_metric(latency, latency_ms);
+        metrics.add_metric(pending, self.pending_batches.load(Ordering::Relaxed) as u64);
+        metrics.add_metric(retries, self.retries.load(Ordering::Relaxed));
+        metrics.add_metric(
+            dropped_batches,
+            self.dropped_batches.load(Ordering::Relaxed),
+        );
+        metrics.add_metric(
+            dropped_samples,
+            self.dropped_samples.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// A closed batch waiting to be sent.
+struct Batch {
+    /// Sent as `Idempotency-Key`, the same on every attempt, so a collector
+    /// can discard a batch it already stored when an acknowledgment got lost.
+    id: String,
+    body: Vec<u8>,
+    samples: SampleSpan,
+    /// The samples themselves, to return if the batch can't be delivered.
+    kept: Vec<Metrics>,
+    opened_at: Instant,
+}
+
+/// Posts batches of samples (JSON lines, or concatenated binary records) to an
+/// HTTP collector, optionally over TLS (`https://`) and with a bearer token.
+///
+/// Samples are collected into a batch until it holds `max_samples` samples,
+/// reaches 1 MiB or its first sample is `max_delay` old, then handed to a
+/// sender thread so retries never block the writer thread. Failed requests
+/// (connection errors, 408, 429 and 5xx responses) are retried with
+/// exponential backoff and jitter; other responses drop the batch. Once
+/// `MAX_PENDING_BATCHES` batches are waiting, new samples are refused, so a
+/// spool (`--spool-dir`) backfills them once the collector catches up. With
+/// a spool, batches that still fail after `MAX_ATTEMPTS` are handed back to
+/// it rather than dropped. Dropped batches are marked by a `lost` gap record
+/// in a later batch.
+pub struct HttpSink {
+    name: String,
+    options: BatchOptions,
+    batch: Vec<u8>,
+    line: Vec<u8>,
+    samples: SampleSpan,
+    opened_at: Instant,
+    sequence: u64,
+    /// Distinguishes batch IDs across agent restarts.
+    run_id: u64,
+    queue: Option<SyncSender<Batch>>,
+    sender: Option<JoinHandle<()>>,
+    stats: Arc<HttpStats>,
+    /// Copies of the samples in the open batch, if undelivered samples are
+    /// returned.
+    kept: Option<Vec<Metrics>>,
+    undelivered: Arc<Mutex<Vec<Metrics>>>,
+    /// Samples in batches dropped since `take_lost`.
+    lost: Arc<Mutex<SampleSpan>>,
+}
+
+impl HttpSink {
+    /// `target` is `host:port/path` as in `http://host:port/path`. With `tls`,
+    /// requests go over HTTPS; with `token`, they carry
+    /// `Authorization: Bearer <token>`.
+    pub fn new(
+        target: &str,
+        options: BatchOptions,
+        tls: Option<Connector>,
+        token: Option<String>,
+    ) -> io::Result<Self> {
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        let content_encoding = match options.compression {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd | Compression::ZstdStream => Some("zstd"),
+        };
+        let mut endpoint = Endpoint::new(target, tls, options.encoding.content_type());
+        endpoint.token = token;
+        endpoint.content_encoding = content_encoding;
+        let stats = Arc::new(HttpStats::default());
+        let (queue, batches) = mpsc::sync_channel(MAX_PENDING_BATCHES);
+        let thread_stats = stats.clone();
+        let undelivered = Arc::new(Mutex::new(Vec::new()));
+        let returned = undelivered.clone();
+        let lost = Arc::new(Mutex::new(SampleSpan::default()));
+        let thread_lost = lost.clone();
+        let sender = thread::Builder::new()
+            .name("http-sink".to_string())
+            .spawn(move || {
+                send_batches(&endpoint, batches, &thread_stats, &returned, &thread_lost)
+            })?;
+        Ok(HttpSink {
+            name: format!("{}://{}", scheme, target),
+            options,
+            batch: Vec::with_capacity(64 * 1024),
+            line: Vec::with_capacity(4096),
+            samples: SampleSpan::default(),
+            opened_at: Instant::now(),
+            sequence: 0,
+            run_id: random(),
+            queue: Some(queue),
+            sender: Some(sender),
+            stats,
+            kept: None,
+            undelivered,
+            lost,
+        })
+    }
+
+    /// Keep batches that can't be delivered for `take_undelivered` instead
+    /// of dropping them, for a sink that spools.
+    pub fn return_undelivered(&mut self) {
+        self.kept = Some(Vec::new());
+    }
+
+    fn is_full(&self) -> bool {
+        self.samples.count() >= self.options.max_samples.max(1) as u64
+            || self.batch.len() >= MAX_BATCH_BYTES
+    }
+
+    fn is_due(&self) -> bool {
+        !self.samples.is_empty()
+            && (self.is_full() || self.opened_at.elapsed() >= self.options.max_delay)
+    }
+
+    /// Hand the open batch to the sender thread. Returns `false`, keeping the
+    /// batch open, if too many batches are pending.
+    fn close_batch(&mut self) -> io::Result<bool> {
+        let Some(queue) = &self.queue else {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        };
+        let batch = Batch {
+            id: format!("{:016x}-{}", self.run_id, self.sequence),
+            body: self.options.compression.encode(&self.batch)?,
+            samples: self.samples,
+            opened_at: self.opened_at,
+            kept: self.kept.as_mut().map(std::mem::take).unwrap_or_default(),
+        };
+        self.stats.pending_batches.fetch_add(1, Ordering::Relaxed);
+        match queue.try_send(batch) {
+            Ok(()) => {
+                self.sequence += 1;
+                self.batch.clear();
+                self.samples = SampleSpan::default();
+                Ok(true)
+            }
+            Err(TrySendError::Full(batch)) => {
+                self.stats.pending_batches.fetch_sub(1, Ordering::Relaxed);
+                if let Some(kept) = &mut self.kept {
+                    *kept = batch.kept;
+                }
+                Ok(false)
+            }
+            Err(TrySendError::Disconnected(batch)) => {
+                self.stats.pending_batches.fetch_sub(1, Ordering::Relaxed);
+                if let Some(kept) = &mut self.kept {
+                    *kept = batch.kept;
+                }
+                Err(io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+        }
+    }
+}
+
+impl Sink for HttpSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        if self.is_due() && !self.close_batch()? && self.is_full() {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} batches waiting to be sent", MAX_PENDING_BATCHES),
+            ));
+        }
+        if self.samples.is_empty() {
+            self.opened_at = Instant::now();
+        }
+        self.options.encoding.encode(metrics, &mut self.line)?;
+        self.batch.extend_from_slice(&self.line);
+        self.samples.push(metrics);
+        if let Some(kept) = &mut self.kept {
+            let mut copy = Metrics::new();
+            copy.copy_from(metrics);
+            kept.push(copy);
+        }
+        if self.is_full() {
+            self.close_batch()?;
+        }
+        Ok(())
+    }
+
+    /// Send the open batch and wait a while for pending batches to be
+    /// delivered. No more samples are accepted afterwards.
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.samples.is_empty() {
+            let deadline = Instant::now() + FLUSH_TIMEOUT;
+            while !self.close_batch()? && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+        self.queue = None;
+        let deadline = Instant::now() + FLUSH_TIMEOUT;
+        while self.stats.pending_batches.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let pending = self.stats.pending_batches.load(Ordering::Relaxed);
+        if pending > 0 {
+            log::warning!("{}: {} batches not delivered", self.name, pending);
+        } else if let Some(sender) = self.sender.take() {
+            let _ = sender.join();
+        }
+        Ok(())
+    }
+
+    fn metrics(&self) -> Option<Arc<dyn SinkMetrics>> {
+        Some(self.stats.clone())
+    }
+
+    fn take_undelivered(&mut self) -> Vec<Metrics> {
+        match self.undelivered.lock() {
+            Ok(mut undelivered) => std::mem::take(&mut *undelivered),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn take_lost(&mut self) -> SampleSpan {
+        match self.lost.lock() {
+            Ok(mut lost) => std::mem::take(&mut *lost),
+            Err(_) => SampleSpan::default(),
+        }
+    }
+}
+
+/// Where and how requests are posted; also used to export trace spans.
+pub(crate) struct Endpoint {
+    pub(crate) authority: String,
+    path: String,
+    tls: Option<Connector>,
+    token: Option<String>,
+    content_type: &'static str,
+    content_encoding: Option<&'static str>,
+}
+
+impl Endpoint {
+    /// `target` is `host:port/path` as in `http://host:port/path`.
+    pub(crate) fn new(target: &str, tls: Option<Connector>, content_type: &'static str) -> Self {
+        let (authority, path) = match target.find('/') {
+            Some(i) => (&target[..i], &target[i..]),
+            None => (target, "/"),
+        };
+        Endpoint {
+            authority: authority.to_string(),
+            path: path.to_string(),
+            tls,
+            token: None,
+            content_type,
+            content_encoding: None,
+        }
+    }
+
+    /// Post `body` once, without retries, returning the error if it wasn't
+    /// accepted.
+    pub(crate) fn post(&self, id: &str, body: &[u8]) -> Result<(), String> {
+        post(self, id, body).map_err(|(Failure::Retry(e) | Failure::Reject(e))| e)
+    }
+}
+
+/// Whether a failed request may succeed if repeated.
+enum Failure {
+    Retry(String),
+    Reject(String),
+}
+
+fn send_batches(
+    endpoint: &Endpoint,
+    batches: Receiver<Batch>,
+    stats: &HttpStats,
+    undelivered: &Mutex<Vec<Metrics>>,
+    lost: &Mutex<SampleSpan>,
+) {
+    let mut rng = random();
+    for batch in batches {
+        let mut attempt = 1;
+        loop {
+            match post(endpoint, &batch.id, &batch.body) {
+                Ok(()) => {
+                    let latency_us = batch.opened_at.elapsed().as_micros() as u64;
+                    stats.batch_latency_us.store(latency_us, Ordering::Relaxed);
+                    break;
+                }
+                Err(Failure::Retry(e)) if attempt < MAX_ATTEMPTS => {
+                    let backoff = backoff(attempt, &mut rng);
+                    log::warning!(
+                        "Posting batch {} to {} failed ({}), retrying in {:.1}s",
+                        batch.id,
+                        endpoint.authority,
+                        e,
+                        backoff.as_secs_f64()
+                    );
+                    stats.retries.fetch_add(1, Ordering::Relaxed);
+                    thread::sleep(backoff);
+                    attempt += 1;
+                }
+                Err(Failure::Retry(e)) if !batch.kept.is_empty() => {
+                    log::warning!(
+                        "Posting batch {} to {} failed ({}), returning its {} samples to the spool",
+                        batch.id,
+                        endpoint.authority,
+                        e,
+                        batch.samples.count()
+                    );
+                    if let Ok(mut undelivered) = undelivered.lock() {
+                        undelivered.extend(batch.kept);
+                    }
+                    break;
+                }
+                Err(Failure::Retry(e)) | Err(Failure::Reject(e)) => {
+                    log::error!(
+                        "Dropping batch {} of {} samples for {}: {}",
+                        batch.id,
+                        batch.samples.count(),
+                        endpoint.authority,
+                        e
+                    );
+                    stats.dropped_batches.fetch_add(1, Ordering::Relaxed);
+                    stats
+                        .dropped_samples
+                        .fetch_add(batch.samples.count(), Ordering::Relaxed);
+                    if let Ok(mut lost) = lost.lock() {
+                        lost.extend(&batch.samples);
+                    }
+                    break;
+                }
+            }
+        }
+        stats.pending_batches.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Exponential backoff with "equal jitter": half of the delay is fixed, the
+/// other half random, so agents that lost the collector at the same moment
+/// don't retry in lockstep.
+fn backoff(attempt: u32, rng: &mut u64) -> Duration {
+    let delay = RETRY_BASE
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(RETRY_MAX);
+    let jitter = (xorshift(rng) % 1000) as f64 / 1000.0;
+    delay / 2 + delay.mul_f64(jitter / 2.0)
+}
+
+fn post(endpoint: &Endpoint, id: &str, body: &[u8]) -> Result<(), Failure> {
+    let retry = |e: io::Error| Failure::Retry(e.to_string());
+    let mut stream = tls::connect(
+        &endpoint.authority,
+        endpoint.tls.as_ref(),
+        CONNECT_TIMEOUT,
+        IO_TIMEOUT,
+    )
+    .map_err(retry)?;
+    let mut head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\n\
+         Content-Length: {}\r\nIdempotency-Key: {}\r\nConnection: close\r\n",
+        endpoint.path,
+        endpoint.authority,
+        endpoint.content_type,
+        body.len(),
+        id
+    );
+    if let Some(token) = &endpoint.token {
+        head.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    if let Some(encoding) = endpoint.content_encoding {
+        head.push_str(&format!("Content-Encoding: {}\r\n", encoding));
+    }
+    head.push_str("\r\n");
+    stream
+        .write_all(head.as_bytes())
+        .and_then(|()| stream.write_all(body))
+        .and_then(|()| stream.flush())
+        .map_err(retry)?;
+
+    let mut status_line = String::new();
+    BufReader::new(&mut stream)
+        .read_line(&mut status_line)
+        .map_err(retry)?;
+    let status: u16 = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| Failure::Retry(format!("invalid response {:?}", status_line.trim())))?;
+    match status {
+        200..=299 => Ok(()),
+        408 | 429 | 500..=599 => Err(Failure::Retry(format!("HTTP {}", status))),
+        _ => Err(Failure::Reject(format!("HTTP {}", status))),
+    }
+}
+
+/// A seed that differs between processes and calls; not cryptographic.
+pub(crate) fn random() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let mut state = nanos ^ (u64::from(std::process::id()) << 32) | 1;
+    xorshift(&mut state)
+}
+
+pub(crate) fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
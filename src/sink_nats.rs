@@ -0,0 +1,288 @@
+use crate::encoding::Encoding;
+use crate::metrics::Metrics;
+use crate::otel;
+use crate::sink::{self, Sink};
+use crate::tls::{Connector, Stream};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+/// Longest protocol line accepted from the server (INFO can be a few KiB).
+const MAX_LINE: usize = 64 * 1024;
+
+/// Publishes samples to NATS, one message per device on
+/// `<prefix>.<host>.<gpu index>` plus `<prefix>.<host>.node` for the rest.
+///
+/// Every write ends with a PING and waits for the PONG, which confirms the
+/// server processed the messages, surfaces `-ERR` replies and answers the
+/// server's own keepalive PINGs. With JetStream, each message also waits for
+/// its publish acknowledgment, so only samples stored by a stream count as
+/// written. Connections are made and re-made like `TcpSink`'s.
+pub struct NatsSink {
+    name: String,
+    addr: String,
+    tls: Option<Connector>,
+    token: Option<String>,
+    jetstream: bool,
+    encoding: Encoding,
+    /// `<prefix>.<host>`, with dots in the host name replaced.
+    subject: String,
+    /// Reply subjects of JetStream acknowledgments are `<inbox>.<n>`.
+    inbox: String,
+    connection: Option<BufReader<Stream>>,
+    retry_after: Option<Instant>,
+    messages: BTreeMap<Option<usize>, Metrics>,
+    buf: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl NatsSink {
+    pub fn new(
+        addr: &str,
+        prefix: &str,
+        jetstream: bool,
+        encoding: Encoding,
+        tls: Option<Connector>,
+        token: Option<String>,
+    ) -> Self {
+        let scheme = if tls.is_some() { "natss" } else { "nats" };
+        let host = otel::hostname().unwrap_or_else(|| "localhost".to_string());
+        let token_safe = |s: &str| {
+            s.chars()
+                .map(|c| match c {
+                    '.' | '*' | '>' | ' ' => '_',
+                    c => c,
+                })
+                .collect::<String>()
+        };
+        NatsSink {
+            name: format!("{}://{}", scheme, addr),
+            addr: addr.to_string(),
+            tls,
+            token,
+            jetstream,
+            encoding,
+            subject: format!("{}.{}", prefix, token_safe(&host)),
+            inbox: format!("_INBOX.symon.{}", std::process::id()),
+            connection: None,
+            retry_after: None,
+            messages: BTreeMap::new(),
+            buf: Vec::with_capacity(4096),
+            out: Vec::with_capacity(4096),
+        }
+    }
+
+    fn connect(&mut self) -> io::Result<&mut BufReader<Stream>> {
+        if self.connection.is_none() {
+            if let Some(retry_after) = self.retry_after {
+                if Instant::now() < retry_after {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "waiting to reconnect",
+                    ));
+                }
+            }
+            self.connection = Some(self.handshake()?);
+        }
+        self.connection
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    fn handshake(&self) -> io::Result<BufReader<Stream>> {
+        let mut tcp = open(&self.addr)?;
+        // The server greets in plain text, even when TLS follows
+        let info = read_raw_line(&mut tcp)?;
+        if !info.starts_with("INFO ") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected greeting {:?}", info),
+            ));
+        }
+        let stream = match &self.tls {
+            Some(tls) => tls.connect(&self.addr, tcp)?,
+            None => Stream::Plain(tcp),
+        };
+        let mut connection = BufReader::new(stream);
+
+        let mut connect = serde_json::json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "symon",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol": 1,
+        });
+        if let Some(token) = &self.token {
+            connect["auth_token"] = token.clone().into();
+        }
+        let mut out = format!("CONNECT {}\r\n", connect).into_bytes();
+        if self.jetstream {
+            out.extend_from_slice(format!("SUB {}.* 1\r\n", self.inbox).as_bytes());
+        }
+        out.extend_from_slice(b"PING\r\n");
+        connection.get_mut().write_all(&out)?;
+        connection.get_mut().flush()?;
+        await_replies(&mut connection, 0)?;
+        Ok(connection)
+    }
+
+    fn publish(&mut self, acks: usize) -> io::Result<()> {
+        let out = std::mem::take(&mut self.out);
+        let result = self.connect().and_then(|connection| {
+            connection.get_mut().write_all(&out)?;
+            connection.get_mut().flush()?;
+            await_replies(connection, acks)
+        });
+        self.out = out;
+        result
+    }
+}
+
+impl Sink for NatsSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        sink::split_by_device(metrics, &mut self.messages);
+        self.out.clear();
+        let mut published = 0;
+        for (device, message) in &self.messages {
+            if message.is_empty() {
+                continue;
+            }
+            self.encoding.encode_message(message, &mut self.buf)?;
+            let subject = match device {
+                Some(device) => format!("{}.{}", self.subject, device),
+                None => format!("{}.node", self.subject),
+            };
+            let head = if self.jetstream {
+                format!(
+                    "PUB {} {}.{} {}\r\n",
+                    subject,
+                    self.inbox,
+                    published,
+                    self.buf.len()
+                )
+            } else {
+                format!("PUB {} {}\r\n", subject, self.buf.len())
+            };
+            self.out.extend_from_slice(head.as_bytes());
+            self.out.extend_from_slice(&self.buf);
+            self.out.extend_from_slice(b"\r\n");
+            published += 1;
+        }
+        self.out.extend_from_slice(b"PING\r\n");
+
+        let acks = if self.jetstream { published } else { 0 };
+        let result = self.publish(acks);
+        if let Err(e) = &result {
+            if e.kind() != io::ErrorKind::NotConnected {
+                self.connection = None;
+                self.retry_after = Some(Instant::now() + RECONNECT_BACKOFF);
+            }
+        }
+        result
+    }
+}
+
+fn open(addr: &str) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses resolved");
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// Read the INFO line byte by byte so nothing past it is consumed before a
+/// TLS upgrade.
+fn read_raw_line(stream: &mut TcpStream) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    while line.last() != Some(&b'\n') {
+        if stream.read(&mut byte)? == 0 || line.len() > MAX_LINE {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+/// Read until the server answered our PING and acknowledged `acks` JetStream
+/// publishes, replying to the server's own PINGs meanwhile. Acknowledgments can
+/// arrive after the PONG, as the stream stores messages asynchronously.
+fn await_replies(connection: &mut BufReader<Stream>, acks: usize) -> io::Result<()> {
+    let mut pong = false;
+    let mut acked = 0;
+    while !pong || acked < acks {
+        match read_message(connection)? {
+            Reply::Pong => pong = true,
+            Reply::Msg(payload) => {
+                check_ack(&payload)?;
+                acked += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fail if a JetStream acknowledgment reports an error, e.g. no stream for
+/// the subject.
+fn check_ack(payload: &[u8]) -> io::Result<()> {
+    let ack: serde_json::Value = serde_json::from_slice(payload)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    match ack.get("error") {
+        Some(error) => Err(io::Error::other(format!("JetStream: {}", error))),
+        None => Ok(()),
+    }
+}
+
+enum Reply {
+    Pong,
+    Msg(Vec<u8>),
+}
+
+fn read_message(connection: &mut BufReader<Stream>) -> io::Result<Reply> {
+    loop {
+        let mut line = String::new();
+        if connection.read_line(&mut line)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        let line = line.trim_end();
+        if line == "PONG" {
+            return Ok(Reply::Pong);
+        } else if line == "PING" {
+            connection.get_mut().write_all(b"PONG\r\n")?;
+            connection.get_mut().flush()?;
+        } else if let Some(error) = line.strip_prefix("-ERR") {
+            return Err(io::Error::other(
+                error.trim().trim_matches('\'').to_string(),
+            ));
+        } else if line.starts_with("MSG ") {
+            // MSG <subject> <sid> [reply-to] <#bytes>
+            let size: usize = line
+                .rsplit(' ')
+                .next()
+                .and_then(|size| size.parse().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, line.to_string()))?;
+            let mut payload = vec![0; size + 2];
+            connection.read_exact(&mut payload)?;
+            payload.truncate(size);
+            return Ok(Reply::Msg(payload));
+        }
+        // +OK and INFO updates need no action
+    }
+}
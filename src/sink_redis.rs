@@ -0,0 +1,199 @@
+use crate::encoding::Encoding;
+use crate::metrics::Metrics;
+use crate::otel;
+use crate::sink::Sink;
+use crate::tls::{Connector, Stream};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// How samples are handed to Redis.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RedisMode {
+    /// `XADD` to a stream, for consumers that must not miss samples
+    #[default]
+    Stream,
+    /// `PUBLISH` to a channel, for live dashboards
+    Pubsub,
+    /// Both
+    Both,
+}
+
+/// Where and how a Redis sink writes.
+#[derive(Clone, Debug)]
+pub struct RedisOptions {
+    pub mode: RedisMode,
+    /// Streams and channels are named `<prefix>:<host name>`.
+    pub prefix: String,
+    /// Trim streams to about this many entries (`MAXLEN ~`).
+    pub max_len: Option<u64>,
+    /// Sent with `AUTH` after connecting.
+    pub password: Option<String>,
+    pub encoding: Encoding,
+}
+
+/// Writes samples to Redis as stream entries and/or pub/sub messages, each
+/// carrying the encoded sample in a `sample` field.
+///
+/// Like `TcpSink`, the connection is made lazily and re-established at most
+/// once per `RECONNECT_BACKOFF`. Every command waits for its reply, so errors
+/// such as a wrong password or an out-of-memory server surface as write errors.
+pub struct RedisSink {
+    name: String,
+    addr: String,
+    tls: Option<Connector>,
+    options: RedisOptions,
+    key: String,
+    stream: Option<BufReader<Stream>>,
+    retry_after: Option<Instant>,
+    line: Vec<u8>,
+    command: Vec<u8>,
+}
+
+impl RedisSink {
+    pub fn new(addr: &str, tls: Option<Connector>, options: RedisOptions) -> Self {
+        let scheme = if tls.is_some() { "rediss" } else { "redis" };
+        let host = otel::hostname().unwrap_or_else(|| "localhost".to_string());
+        RedisSink {
+            name: format!("{}://{}", scheme, addr),
+            addr: addr.to_string(),
+            tls,
+            key: format!("{}:{}", options.prefix, host),
+            options,
+            stream: None,
+            retry_after: None,
+            line: Vec::with_capacity(4096),
+            command: Vec::with_capacity(4096),
+        }
+    }
+
+    fn connect(&mut self) -> io::Result<&mut BufReader<Stream>> {
+        if self.stream.is_none() {
+            if let Some(retry_after) = self.retry_after {
+                if Instant::now() < retry_after {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "waiting to reconnect",
+                    ));
+                }
+            }
+            let tcp = open(&self.addr)?;
+            let stream = match &self.tls {
+                Some(tls) => tls.connect(&self.addr, tcp)?,
+                None => Stream::Plain(tcp),
+            };
+            let mut stream = BufReader::new(stream);
+            if let Some(password) = &self.options.password {
+                let mut command = Vec::new();
+                encode(&mut command, &[b"AUTH", password.as_bytes()]);
+                call(&mut stream, &command)?;
+            }
+            self.stream = Some(stream);
+        }
+        self.stream
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        let command = std::mem::take(&mut self.command);
+        let result = self
+            .connect()
+            .and_then(|stream| call(stream, &command).map(drop));
+        self.command = command;
+        result
+    }
+}
+
+fn open(addr: &str) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses resolved");
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// Encode a command as a RESP array of bulk strings.
+fn encode(buf: &mut Vec<u8>, args: &[&[u8]]) {
+    buf.clear();
+    buf.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Send a command and read its reply, which for the commands used here is a
+/// single line: a status, an integer or a short bulk string.
+fn call(stream: &mut BufReader<Stream>, command: &[u8]) -> io::Result<String> {
+    let writer = stream.get_mut();
+    writer.write_all(command)?;
+    writer.flush()?;
+    let mut reply = String::new();
+    if stream.read_line(&mut reply)? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    match reply.as_bytes().first() {
+        Some(b'-') => Err(io::Error::other(reply.trim_end()[1..].to_string())),
+        Some(b'$') if !reply.starts_with("$-1") => {
+            // The entry ID of XADD
+            let mut value = String::new();
+            stream.read_line(&mut value)?;
+            Ok(value.trim_end().to_string())
+        }
+        Some(_) => Ok(reply.trim_end().to_string()),
+        None => Err(io::Error::from(io::ErrorKind::InvalidData)),
+    }
+}
+
+impl Sink for RedisSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        self.options
+            .encoding
+            .encode_message(metrics, &mut self.line)?;
+
+        let mut result = Ok(());
+        if matches!(self.options.mode, RedisMode::Stream | RedisMode::Both) {
+            let max_len = self.options.max_len.map(|n| n.to_string());
+            let mut args: Vec<&[u8]> = vec![b"XADD", self.key.as_bytes()];
+            if let Some(max_len) = &max_len {
+                args.extend([b"MAXLEN".as_slice(), b"~", max_len.as_bytes()]);
+            }
+            args.extend([b"*".as_slice(), b"sample", &self.line]);
+            encode(&mut self.command, &args);
+            result = self.send();
+        }
+        if result.is_ok() && matches!(self.options.mode, RedisMode::Pubsub | RedisMode::Both) {
+            encode(
+                &mut self.command,
+                &[b"PUBLISH", self.key.as_bytes(), &self.line],
+            );
+            result = self.send();
+        }
+        if let Err(e) = &result {
+            // Failing while waiting to reconnect mustn't push the retry back
+            if e.kind() != io::ErrorKind::NotConnected {
+                self.stream = None;
+                self.retry_after = Some(Instant::now() + RECONNECT_BACKOFF);
+            }
+        }
+        result
+    }
+}
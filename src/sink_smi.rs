@@ -0,0 +1,175 @@
+use crate::metrics::Metrics;
+use crate::sink::Sink;
+use crate::timefmt::UtcDateTime;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Clear the screen and move the cursor to the top-left corner.
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+const MIB: f64 = (1u64 << 20) as f64;
+const WIDTHS: [usize; 3] = [31, 22, 22];
+
+/// Renders each sample as a table laid out like `nvidia-smi`, followed by the
+/// extra metrics symon collects.
+///
+/// With `watch`, the screen is cleared before each table so it refreshes in place.
+pub struct SmiSink {
+    watch: bool,
+    out: String,
+}
+
+impl SmiSink {
+    pub fn new(watch: bool) -> Self {
+        SmiSink {
+            watch,
+            out: String::with_capacity(4096),
+        }
+    }
+
+    fn separator(&mut self, fill: char, corner: char, edge: char) {
+        self.out.push(edge);
+        for (i, width) in WIDTHS.iter().enumerate() {
+            if i > 0 {
+                self.out.push(corner);
+            }
+            self.out.extend(std::iter::repeat_n(fill, *width));
+        }
+        self.out.push(edge);
+        self.out.push('\n');
+    }
+
+    fn row(&mut self, cells: [&str; 3]) {
+        self.out.push('|');
+        for (cell, width) in cells.iter().zip(WIDTHS) {
+            let _ = write!(self.out, "{:<width$.width$}|", cell, width = width);
+        }
+        self.out.push('\n');
+    }
+
+    /// A row spanning the whole table.
+    fn wide_row(&mut self, text: &str) {
+        let width = WIDTHS.iter().sum::<usize>() + WIDTHS.len() - 1;
+        let _ = writeln!(self.out, "|{:<width$.width$}|", text, width = width);
+    }
+
+    fn format(&mut self, metrics: &Metrics) {
+        self.out.clear();
+        if self.watch {
+            self.out.push_str(CLEAR_SCREEN);
+        }
+        let number = |key: &str| metrics.get(key).and_then(|v| v.as_f64());
+        let text = |key: &str| metrics.get(key).and_then(|v| v.as_str()).unwrap_or("");
+        let or_na = |value: Option<String>| value.unwrap_or_else(|| "N/A".to_string());
+
+        if let Some(timestamp) = metrics.timestamp() {
+            let time = UNIX_EPOCH + Duration::try_from_secs_f64(timestamp).unwrap_or_default();
+            let time = UtcDateTime::from_system_time(time);
+            let _ = writeln!(
+                self.out,
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+                time.year, time.month, time.day, time.hour, time.minute, time.second
+            );
+        }
+
+        self.separator('-', '-', '+');
+        let header = format!(
+            " symon {:<38}CUDA Version: {:<10}",
+            env!("CARGO_PKG_VERSION"),
+            text("cuda_version")
+        );
+        self.wide_row(&header);
+        self.separator('-', '+', '|');
+        self.row([" GPU  Name", " Memory-Usage", " GPU-Util  Enc-Util"]);
+        self.row([
+            " Fan  Temp  Pwr:Usage/Cap",
+            " PCIe Link",
+            " SM MHz    Mem MHz",
+        ]);
+        self.separator('=', '+', '|');
+
+        let count = number("_gpu.count").unwrap_or(0.0) as u32;
+        for i in 0..count {
+            let key = |field: &str| format!("gpu.{}.{}", i, field);
+            let meta = |field: &str| format!("_gpu.{}.{}", i, field);
+
+            let name = format!("  {:>2}  {}", i, text(&meta("name")));
+            let memory = format!(
+                " {} / {}",
+                or_na(number(&key("memoryAllocatedBytes")).map(|b| format!("{:.0}MiB", b / MIB))),
+                or_na(number(&meta("memoryTotal")).map(|b| format!("{:.0}MiB", b / MIB)))
+            );
+            let utilization = format!(
+                " {:>7}  {:>8}",
+                or_na(number(&key("gpu")).map(|u| format!("{:.0}%", u))),
+                or_na(number(&meta("encoderUtilization")).map(|u| format!("{:.0}%", u)))
+            );
+            self.row([&name, &memory, &utilization]);
+
+            let power = format!(
+                " {:>4}  {:>4}  {:>5} / {:>4}",
+                or_na(number(&meta("fanSpeed")).map(|f| format!("{:.0}%", f))),
+                or_na(number(&key("temp")).map(|t| format!("{:.0}C", t))),
+                or_na(number(&key("powerWatts")).map(|p| format!("{:.0}W", p))),
+                or_na(number(&key("enforcedPowerLimitWatts")).map(|p| format!("{:.0}W", p)))
+            );
+            let pcie = match (number(&meta("pcieLinkGen")), number(&meta("pcieLinkWidth"))) {
+                (Some(link_gen), Some(width)) => format!(" PCIe Gen{:.0} x{:.0}", link_gen, width),
+                _ => " PCIe N/A".to_string(),
+            };
+            let clocks = format!(
+                " {:>6}    {:>7}",
+                or_na(number(&meta("smClock")).map(|c| format!("{:.0}", c))),
+                or_na(number(&meta("memoryClock")).map(|c| format!("{:.0}", c)))
+            );
+            self.row([&power, &pcie, &clocks]);
+
+            // Metrics nvidia-smi doesn't show in its summary table
+            let extra = format!(
+                "      Arch {}  Cores {}  ECC {}/{}  Energy {}  Replays {}",
+                or_na(
+                    metrics
+                        .get(&meta("architecture"))
+                        .and_then(|v| v.as_str())
+                        .map(str::to_string)
+                ),
+                or_na(number(&meta("cudaCores")).map(|c| format!("{:.0}", c))),
+                or_na(number(&meta("correctedMemoryErrors")).map(|e| format!("{:.0}", e))),
+                or_na(number(&meta("uncorrectedMemoryErrors")).map(|e| format!("{:.0}", e))),
+                or_na(number(&meta("energyJoules")).map(|e| format!("{:.1}kJ", e / 1000.0))),
+                or_na(number(&meta("pcieReplays")).map(|r| format!("{:.0}", r)))
+            );
+            self.wide_row(&extra);
+            self.separator('-', '+', '+');
+        }
+        if count == 0 {
+            self.wide_row("  No GPUs found");
+            self.separator('-', '-', '+');
+        }
+        if metrics.get("_sampling_timeout").is_some() {
+            self.out
+                .push_str("Sampling timed out; readings may be missing\n");
+        }
+    }
+}
+
+impl Sink for SmiSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        // Metadata records have nothing to display
+        if metrics.get("_record").is_some() {
+            return Ok(());
+        }
+        self.format(metrics);
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(self.out.as_bytes())?;
+        stdout.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().lock().flush()
+    }
+}
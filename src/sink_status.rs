@@ -0,0 +1,181 @@
+use crate::metrics::Metrics;
+use crate::sink::Sink;
+use crate::timefmt::UtcDateTime;
+use std::fmt::Write as _;
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, UNIX_EPOCH};
+
+const RESET: &str = "\x1b[0m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const GIB: f64 = (1u64 << 30) as f64;
+
+/// Levels at which a reading is shown in yellow and in red.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Threshold {
+    pub warn: f64,
+    pub crit: f64,
+}
+
+impl Threshold {
+    fn color(self, value: f64) -> Option<&'static str> {
+        if value >= self.crit {
+            Some(RED)
+        } else if value >= self.warn {
+            Some(YELLOW)
+        } else {
+            None
+        }
+    }
+}
+
+/// Color thresholds for the status line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatusThresholds {
+    /// GPU temperature in Celsius.
+    pub temp: Threshold,
+    /// Memory allocated, in percent of total.
+    pub memory: Threshold,
+    /// Power draw, in percent of the enforced limit.
+    pub power: Threshold,
+}
+
+impl Default for StatusThresholds {
+    fn default() -> Self {
+        StatusThresholds {
+            temp: Threshold {
+                warn: 80.0,
+                crit: 90.0,
+            },
+            memory: Threshold {
+                warn: 90.0,
+                crit: 98.0,
+            },
+            power: Threshold {
+                warn: 90.0,
+                crit: 100.0,
+            },
+        }
+    }
+}
+
+impl StatusThresholds {
+    /// Parse overrides such as `temp=75:85,memory=80:95`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut thresholds = StatusThresholds::default();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let invalid = || format!("invalid threshold {:?}: expected NAME=WARN:CRIT", part);
+            let (name, levels) = part.split_once('=').ok_or_else(invalid)?;
+            let (warn, crit) = levels.split_once(':').ok_or_else(invalid)?;
+            let threshold = Threshold {
+                warn: warn.trim().parse().map_err(|_| invalid())?,
+                crit: crit.trim().parse().map_err(|_| invalid())?,
+            };
+            match name.trim() {
+                "temp" => thresholds.temp = threshold,
+                "memory" => thresholds.memory = threshold,
+                "power" => thresholds.power = threshold,
+                other => return Err(format!("unknown threshold: {:?}", other)),
+            }
+        }
+        Ok(thresholds)
+    }
+}
+
+/// Prints a compact one-line summary of each sample for humans, e.g.
+/// `12:00:01 GPU0 97% 38.2/40.0GiB 311W 74°C | GPU1 ...`.
+///
+/// Colors are only used when stdout is a terminal and `NO_COLOR` is unset.
+pub struct StatusSink {
+    thresholds: StatusThresholds,
+    color: bool,
+    line: String,
+}
+
+impl StatusSink {
+    pub fn new(thresholds: StatusThresholds) -> Self {
+        StatusSink {
+            thresholds,
+            color: io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+            line: String::with_capacity(256),
+        }
+    }
+
+    fn push_colored(&mut self, text: &str, color: Option<&str>) {
+        match color.filter(|_| self.color) {
+            Some(color) => {
+                let _ = write!(self.line, "{}{}{}", color, text, RESET);
+            }
+            None => self.line.push_str(text),
+        }
+    }
+
+    fn format(&mut self, metrics: &Metrics) {
+        self.line.clear();
+        let number = |key: String| metrics.get(&key).and_then(|v| v.as_f64());
+
+        if let Some(timestamp) = metrics.timestamp() {
+            let time = UNIX_EPOCH + Duration::try_from_secs_f64(timestamp).unwrap_or_default();
+            let time = UtcDateTime::from_system_time(time);
+            let _ = write!(
+                self.line,
+                "{:02}:{:02}:{:02} ",
+                time.hour, time.minute, time.second
+            );
+        }
+
+        let count = number("_gpu.count".to_string()).unwrap_or(0.0) as u32;
+        for i in 0..count {
+            if i > 0 {
+                self.line.push_str(" | ");
+            }
+            let _ = write!(self.line, "GPU{}", i);
+            if let Some(util) = number(format!("gpu.{}.gpu", i)) {
+                let _ = write!(self.line, " {:.0}%", util);
+            }
+            if let (Some(used), Some(total)) = (
+                number(format!("gpu.{}.memoryAllocatedBytes", i)),
+                number(format!("_gpu.{}.memoryTotal", i)),
+            ) {
+                let text = format!(" {:.1}/{:.0}GiB", used / GIB, total / GIB);
+                let color = self.thresholds.memory.color(used / total * 100.0);
+                self.push_colored(&text, color);
+            }
+            if let Some(power) = number(format!("gpu.{}.powerWatts", i)) {
+                let color = number(format!("gpu.{}.powerPercent", i))
+                    .and_then(|percent| self.thresholds.power.color(percent));
+                self.push_colored(&format!(" {:.0}W", power), color);
+            }
+            if let Some(temp) = number(format!("gpu.{}.temp", i)) {
+                let color = self.thresholds.temp.color(temp);
+                self.push_colored(&format!(" {:.0}°C", temp), color);
+            }
+        }
+        if count == 0 {
+            self.line.push_str("no GPUs");
+        }
+        if metrics.get("_sampling_timeout").is_some() {
+            self.push_colored(" [sampling timed out]", Some(RED));
+        }
+        self.line.push('\n');
+    }
+}
+
+impl Sink for StatusSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        // Metadata records have nothing to display
+        if metrics.get("_record").is_some() {
+            return Ok(());
+        }
+        self.format(metrics);
+        io::stdout().lock().write_all(self.line.as_bytes())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().lock().flush()
+    }
+}
@@ -0,0 +1,101 @@
+use crate::encoding::Encoding;
+use crate::metrics::Metrics;
+use crate::sink::Sink;
+use crate::tls::{Connector, Stream};
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Streams JSON lines or binary records to a TCP collector, optionally over TLS
+/// (`tcps://`). Collectors authenticate the agent by its client certificate.
+///
+/// The connection is established lazily and re-established after failures,
+/// at most once per `RECONNECT_BACKOFF`, so an unreachable collector costs a
+/// failed write rather than a blocked writer thread.
+pub struct TcpSink {
+    name: String,
+    addr: String,
+    tls: Option<Connector>,
+    encoding: Encoding,
+    stream: Option<Stream>,
+    retry_after: Option<Instant>,
+    buf: Vec<u8>,
+}
+
+impl TcpSink {
+    pub fn new(addr: &str, tls: Option<Connector>, encoding: Encoding) -> Self {
+        let scheme = if tls.is_some() { "tcps" } else { "tcp" };
+        TcpSink {
+            name: format!("{}://{}", scheme, addr),
+            addr: addr.to_string(),
+            tls,
+            encoding,
+            stream: None,
+            retry_after: None,
+            buf: Vec::with_capacity(4096),
+        }
+    }
+
+    fn connect(&mut self) -> io::Result<&mut Stream> {
+        if self.stream.is_none() {
+            if let Some(retry_after) = self.retry_after {
+                if Instant::now() < retry_after {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "waiting to reconnect",
+                    ));
+                }
+            }
+            let stream = open(&self.addr)?;
+            self.stream = Some(match &self.tls {
+                Some(tls) => tls.connect(&self.addr, stream)?,
+                None => Stream::Plain(stream),
+            });
+        }
+        self.stream
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+    }
+}
+
+fn open(addr: &str) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses resolved");
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                // Only TLS handshakes read from the collector
+                stream.set_read_timeout(Some(WRITE_TIMEOUT))?;
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+impl Sink for TcpSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        let mut buf = std::mem::take(&mut self.buf);
+        self.encoding.encode(metrics, &mut buf)?;
+        let result = self.connect().and_then(|stream| stream.write_all(&buf));
+        self.buf = buf;
+        if let Err(e) = &result {
+            // Failing while waiting to reconnect mustn't push the retry back
+            if e.kind() != io::ErrorKind::NotConnected {
+                self.stream = None;
+                self.retry_after = Some(Instant::now() + RECONNECT_BACKOFF);
+            }
+        }
+        result
+    }
+}
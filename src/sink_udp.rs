@@ -0,0 +1,124 @@
+use crate::encoding::Encoding;
+use crate::gap::SampleSpan;
+use crate::metrics::Metrics;
+use crate::sink::{self, Sink, SinkMetrics};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+/// Largest payload of an IPv4 UDP datagram.
+pub const MAX_DATAGRAM: usize = 65507;
+
+/// Datagrams a UDP sink couldn't send, reported as `_agent.sink.{i}.droppedSamples`.
+#[derive(Default)]
+struct UdpStats {
+    key: OnceLock<&'static str>,
+    dropped: AtomicU64,
+}
+
+impl SinkMetrics for UdpStats {
+    fn add_metrics(&self, index: usize, metrics: &mut Metrics) {
+        let key = self.key.get_or_init(|| {
+            Box::leak(format!("_agent.sink.{}.droppedSamples", index).into_boxed_str())
+        });
+        metrics.add_metric(*key, self.dropped.load(Ordering::Relaxed));
+    }
+}
+
+/// Sends each sample as one datagram and never waits for anything.
+///
+/// A sample larger than `max_size` is split into one datagram per device plus
+/// one for the node metrics, each carrying the sample time; parts that are
+/// still too large are dropped. Dropped datagrams and failed sends (a full
+/// socket buffer, an unreachable port) are counted rather than reported as
+/// write errors, since losing the occasional sample is the point of this sink.
+/// Samples with a datagram that didn't go out are marked by a `lost` gap
+/// record in a later datagram.
+pub struct UdpSink {
+    name: String,
+    socket: UdpSocket,
+    encoding: Encoding,
+    max_size: usize,
+    parts: BTreeMap<Option<usize>, Metrics>,
+    buf: Vec<u8>,
+    stats: Arc<UdpStats>,
+    /// Samples with a dropped datagram since `take_lost`.
+    lost: SampleSpan,
+}
+
+impl UdpSink {
+    pub fn new(addr: &str, encoding: Encoding, max_size: usize) -> io::Result<Self> {
+        let target = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses resolved"))?;
+        let local = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(target)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpSink {
+            name: format!("udp://{}", addr),
+            socket,
+            encoding,
+            max_size: max_size.clamp(1, MAX_DATAGRAM),
+            parts: BTreeMap::new(),
+            buf: Vec::with_capacity(4096),
+            stats: Arc::new(UdpStats::default()),
+            lost: SampleSpan::default(),
+        })
+    }
+
+    /// Send `datagram`, returning `false` if it was dropped.
+    fn send(&self, datagram: &[u8]) -> bool {
+        if datagram.len() > self.max_size || self.socket.send(datagram).is_err() {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+}
+
+impl Sink for UdpSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        let mut buf = std::mem::take(&mut self.buf);
+        let result = self
+            .encoding
+            .encode_message(metrics, &mut buf)
+            .and_then(|()| {
+                if buf.len() <= self.max_size {
+                    return Ok(self.send(&buf));
+                }
+                sink::split_by_device(metrics, &mut self.parts);
+                let mut sent = true;
+                for part in self.parts.values().filter(|part| !part.is_empty()) {
+                    self.encoding.encode_message(part, &mut buf)?;
+                    sent &= self.send(&buf);
+                }
+                Ok(sent)
+            });
+        self.buf = buf;
+        // A lost gap record isn't marked in turn
+        if !result? && metrics.get("_record").is_none() {
+            self.lost.push(metrics);
+        }
+        Ok(())
+    }
+
+    fn metrics(&self) -> Option<Arc<dyn SinkMetrics>> {
+        Some(self.stats.clone())
+    }
+
+    fn take_lost(&mut self) -> SampleSpan {
+        std::mem::take(&mut self.lost)
+    }
+}
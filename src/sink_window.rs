@@ -0,0 +1,174 @@
+use crate::gap::SampleSpan;
+use crate::histogram::{self, Histogram};
+use crate::metrics::Metrics;
+use crate::query::Aggregation;
+use crate::sink::{Sink, SinkMetrics};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Passes samples on at most once per interval, either as the latest sample
+/// or aggregated over the interval.
+///
+/// Intervals are measured on the sample clock, so every sink is driven by the
+/// same sampling loop. The first sample is passed on right away. One-off
+/// records such as topology and billing are never held back.
+pub struct WindowSink {
+    inner: Box<dyn Sink>,
+    every: Duration,
+    aggregation: Option<Aggregation>,
+    /// Sample time of the last sample passed on.
+    last_emit: Option<Duration>,
+    numbers: BTreeMap<String, Vec<f64>>,
+    latest: Metrics,
+    samples: u64,
+    scratch: Metrics,
+    percentiles: Vec<f64>,
+    histograms: BTreeMap<String, Histogram>,
+}
+
+impl WindowSink {
+    /// With `aggregation`, numeric metrics are reduced over the interval and
+    /// other metrics take their latest value; without it, samples in between
+    /// are dropped.
+    pub fn new(inner: Box<dyn Sink>, every: Duration, aggregation: Option<Aggregation>) -> Self {
+        WindowSink {
+            inner,
+            every,
+            aggregation,
+            last_emit: None,
+            numbers: BTreeMap::new(),
+            latest: Metrics::new(),
+            samples: 0,
+            scratch: Metrics::new(),
+            percentiles: Vec::new(),
+            histograms: BTreeMap::new(),
+        }
+    }
+
+    /// Also add `percentiles` of each GPU's power, utilization and temperature
+    /// over the interval, e.g. `gpu.0.powerWattsP99`.
+    pub fn with_percentiles(mut self, percentiles: Vec<f64>) -> Self {
+        self.percentiles = percentiles;
+        self
+    }
+
+    fn is_due(&self, uptime: Duration) -> bool {
+        // Allow for jitter in the sampling loop's cadence
+        let tolerance = self.every / 100;
+        self.last_emit
+            .is_none_or(|last| uptime.saturating_sub(last) + tolerance >= self.every)
+    }
+
+    fn accumulate(&mut self, metrics: &Metrics) {
+        self.samples += 1;
+        self.latest.copy_from(metrics);
+        metrics.for_each(|key, value| {
+            let aggregable = !key.starts_with("_timestamp") && key != "_schema_version";
+            if let Some(number) = value.as_f64().filter(|_| aggregable) {
+                match self.numbers.get_mut(key.as_ref()) {
+                    Some(values) => values.push(number),
+                    None => {
+                        self.numbers.insert(key.to_string(), vec![number]);
+                    }
+                }
+            }
+        });
+    }
+
+    fn record_percentiles(&mut self, metrics: &Metrics) {
+        metrics.for_each(|key, value| {
+            let Some(number) = value.as_f64().filter(|_| histogram::is_tracked(key)) else {
+                return;
+            };
+            match self.histograms.get_mut(key.as_ref()) {
+                Some(histogram) => histogram.add(number),
+                None => {
+                    let mut histogram = Histogram::new();
+                    histogram.add(number);
+                    self.histograms.insert(key.to_string(), histogram);
+                }
+            }
+        });
+    }
+
+    /// Add the percentiles over the interval to `scratch` and start over.
+    fn add_percentiles(&mut self) {
+        for (key, histogram) in self.histograms.iter_mut() {
+            for &p in &self.percentiles {
+                if let Some(value) = histogram.percentile(p) {
+                    self.scratch
+                        .add_metric(histogram::percentile_key(key, p), value);
+                }
+            }
+            histogram.clear();
+        }
+    }
+
+    fn write_aggregate(&mut self, aggregation: Aggregation) -> io::Result<()> {
+        self.scratch.copy_from(&self.latest);
+        for (key, values) in self.numbers.iter_mut() {
+            if let Some(value) = aggregation.apply(values) {
+                self.scratch.add_metric(key.clone(), value);
+            }
+            values.clear();
+        }
+        self.add_percentiles();
+        self.scratch.add_metric("_window_samples", self.samples);
+        self.samples = 0;
+        self.inner.write(&self.scratch)
+    }
+}
+
+impl Sink for WindowSink {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        let Some(uptime) = metrics.time().map(|time| time.uptime) else {
+            return self.inner.write(metrics);
+        };
+        if metrics.get("_record").is_some() {
+            return self.inner.write(metrics);
+        }
+        let due = self.is_due(uptime);
+        if !self.percentiles.is_empty() {
+            self.record_percentiles(metrics);
+        }
+        match self.aggregation {
+            Some(aggregation) => {
+                self.accumulate(metrics);
+                if !due {
+                    return Ok(());
+                }
+                self.last_emit = Some(uptime);
+                self.write_aggregate(aggregation)
+            }
+            None if due && self.percentiles.is_empty() => {
+                self.last_emit = Some(uptime);
+                self.inner.write(metrics)
+            }
+            None if due => {
+                self.last_emit = Some(uptime);
+                self.scratch.copy_from(metrics);
+                self.add_percentiles();
+                self.inner.write(&self.scratch)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn metrics(&self) -> Option<Arc<dyn SinkMetrics>> {
+        self.inner.metrics()
+    }
+
+    fn take_lost(&mut self) -> SampleSpan {
+        self.inner.take_lost()
+    }
+}
@@ -0,0 +1,340 @@
+use crate::encoding::Encoding;
+use crate::log;
+use crate::metrics::Metrics;
+use crate::sink::{self, Sink};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// A subscriber that can't take a message within this long is disconnected,
+/// like a ZeroMQ subscriber over its high-water mark, so it can't stall other sinks.
+const SEND_TIMEOUT: Duration = Duration::from_millis(500);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest subscription or command frame accepted from a subscriber.
+const MAX_FRAME: u64 = 64 * 1024;
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+/// A connected subscriber and the topic prefixes it subscribed to.
+struct Subscriber {
+    stream: TcpStream,
+    topics: Arc<Mutex<Vec<Vec<u8>>>>,
+    connected: Arc<AtomicBool>,
+}
+
+impl Subscriber {
+    fn wants(&self, topic: &[u8]) -> bool {
+        self.topics
+            .lock()
+            .is_ok_and(|topics| topics.iter().any(|prefix| topic.starts_with(prefix)))
+    }
+}
+
+/// Publishes samples to ZeroMQ SUB sockets, speaking ZMTP 3.0 (NULL security)
+/// directly so no libzmq is needed.
+///
+/// Each sample is split into one two-frame message per device, with topic
+/// `symon.gpu.<i>` and a body holding that device's metrics and the
+/// sample time, plus one `symon.node` message with the remaining metrics.
+/// Subscribers filter by topic prefix as usual, e.g. `symon.gpu.0` for one GPU
+/// or `symon` for everything. Messages are only serialized for topics someone
+/// subscribed to.
+///
+/// ZMTP's NULL security carries samples in plaintext to anyone who connects,
+/// so only loopback addresses may be bound; remote subscribers reach the
+/// socket through a tunnel such as SSH or stunnel.
+pub struct ZmqSink {
+    name: String,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    encoding: Encoding,
+    /// Per-device message bodies, reused between samples.
+    messages: BTreeMap<Option<usize>, Metrics>,
+    buf: Vec<u8>,
+}
+
+impl ZmqSink {
+    /// Listen for subscribers on `addr`, a loopback address such as
+    /// `127.0.0.1:5556` or `localhost:5556`.
+    pub fn bind(addr: &str, encoding: Encoding) -> io::Result<Self> {
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        if addrs.is_empty() || addrs.iter().any(|a| !a.ip().is_loopback()) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "ZeroMQ subscribers are served without encryption or authentication, \
+                 so only loopback addresses may be bound; tunnel remote subscribers \
+                 over SSH or TLS",
+            ));
+        }
+        let listener = TcpListener::bind(&addrs[..])?;
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+        let accepted = subscribers.clone();
+        thread::Builder::new()
+            .name("zmq-accept".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            log::warning!("Error accepting ZeroMQ subscriber: {}", e);
+                            continue;
+                        }
+                    };
+                    // A slow peer mustn't hold up others while it greets us
+                    let accepted = accepted.clone();
+                    let spawned = thread::Builder::new()
+                        .name("zmq-handshake".to_string())
+                        .spawn(move || match handshake(stream) {
+                            Ok(subscriber) => {
+                                if let Ok(mut subscribers) = accepted.lock() {
+                                    subscribers.push(subscriber);
+                                }
+                            }
+                            Err(e) => log::warning!("Error accepting ZeroMQ subscriber: {}", e),
+                        });
+                    if let Err(e) = spawned {
+                        log::warning!("Error accepting ZeroMQ subscriber: {}", e);
+                    }
+                }
+            })?;
+        Ok(ZmqSink {
+            name: format!("zmq://{}", addr),
+            subscribers,
+            encoding,
+            messages: BTreeMap::new(),
+            buf: Vec::with_capacity(4096),
+        })
+    }
+}
+
+impl Sink for ZmqSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        let Ok(mut subscribers) = self.subscribers.lock() else {
+            return Err(io::Error::other("subscriber list poisoned"));
+        };
+        subscribers.retain(|s| {
+            let connected = s.connected.load(Ordering::Relaxed);
+            if !connected {
+                // Wakes the reader thread, which closes its end of the socket
+                let _ = s.stream.shutdown(Shutdown::Both);
+            }
+            connected
+        });
+        if subscribers.is_empty() {
+            return Ok(());
+        }
+
+        sink::split_by_device(metrics, &mut self.messages);
+        for (device, message) in &self.messages {
+            let topic = match device {
+                Some(device) => format!("symon.gpu.{}", device),
+                None => "symon.node".to_string(),
+            };
+            if message.is_empty() || !subscribers.iter().any(|s| s.wants(topic.as_bytes())) {
+                continue;
+            }
+            self.encoding.encode_message(message, &mut self.buf)?;
+            for subscriber in subscribers.iter_mut() {
+                if !subscriber.wants(topic.as_bytes()) {
+                    continue;
+                }
+                let sent = send_frame(&mut subscriber.stream, FLAG_MORE, topic.as_bytes())
+                    .and_then(|()| send_frame(&mut subscriber.stream, 0, &self.buf));
+                if sent.is_err() {
+                    subscriber.connected.store(false, Ordering::Relaxed);
+                    let _ = subscriber.stream.shutdown(Shutdown::Both);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Exchange greetings and READY commands with a new peer, then read its
+/// subscriptions on a separate thread.
+fn handshake(mut stream: TcpStream) -> io::Result<Subscriber> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    // Version 3.0, so peers send subscriptions as messages
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    stream.write_all(&greeting)?;
+    let mut peer = [0u8; 64];
+    stream.read_exact(&mut peer)?;
+    if peer[0] != 0xff || peer[9] != 0x7f || peer[10] < 3 || &peer[12..17] != b"NULL\0" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a ZMTP 3 peer with NULL security",
+        ));
+    }
+
+    let mut ready = vec![5];
+    ready.extend_from_slice(b"READY");
+    ready.push(11);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&3u32.to_be_bytes());
+    ready.extend_from_slice(b"PUB");
+    send_frame(&mut stream, FLAG_COMMAND, &ready)?;
+    let (flags, command) = read_frame(&mut stream)?;
+    let socket_type = property(&command, b"Socket-Type");
+    if flags & FLAG_COMMAND == 0
+        || !command.starts_with(b"\x05READY")
+        || !matches!(socket_type, Some(b"SUB" | b"XSUB"))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "peer is not a SUB socket",
+        ));
+    }
+
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(Some(SEND_TIMEOUT))?;
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let connected = Arc::new(AtomicBool::new(true));
+    let mut reader = stream.try_clone()?;
+    let (reader_topics, reader_connected) = (topics.clone(), connected.clone());
+    thread::Builder::new()
+        .name("zmq-subscriber".to_string())
+        .spawn(move || {
+            while reader_connected.load(Ordering::Relaxed) {
+                let Ok((flags, frame)) = read_frame(&mut reader) else {
+                    break;
+                };
+                let Ok(mut topics) = reader_topics.lock() else {
+                    break;
+                };
+                apply_subscription(&mut topics, flags, &frame);
+            }
+            reader_connected.store(false, Ordering::Relaxed);
+        })?;
+    Ok(Subscriber {
+        stream,
+        topics,
+        connected,
+    })
+}
+
+/// Handle a subscription message (`\x01topic` / `\x00topic`) or a ZMTP 3.1
+/// SUBSCRIBE / CANCEL command.
+fn apply_subscription(topics: &mut Vec<Vec<u8>>, flags: u8, frame: &[u8]) {
+    let (subscribe, topic) = if flags & FLAG_COMMAND != 0 {
+        if let Some(topic) = frame.strip_prefix(b"\x09SUBSCRIBE") {
+            (true, topic)
+        } else if let Some(topic) = frame.strip_prefix(b"\x06CANCEL") {
+            (false, topic)
+        } else {
+            return;
+        }
+    } else {
+        match frame.split_first() {
+            Some((1, topic)) => (true, topic),
+            Some((0, topic)) => (false, topic),
+            _ => return,
+        }
+    };
+    if subscribe {
+        topics.push(topic.to_vec());
+    } else if let Some(i) = topics.iter().position(|t| t == topic) {
+        topics.remove(i);
+    }
+}
+
+/// Find a property in the metadata of a READY command.
+fn property<'a>(command: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    let mut rest = command.get(6..)?;
+    while let Some((&len, tail)) = rest.split_first() {
+        let (key, tail) = tail.split_at_checked(len as usize)?;
+        let (size, tail) = tail.split_at_checked(4)?;
+        let size = u32::from_be_bytes(size.try_into().ok()?) as usize;
+        let (value, tail) = tail.split_at_checked(size)?;
+        if key.eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+        rest = tail;
+    }
+    None
+}
+
+fn send_frame(stream: &mut TcpStream, flags: u8, body: &[u8]) -> io::Result<()> {
+    let mut head = Vec::with_capacity(9);
+    if body.len() > 255 {
+        head.push(flags | FLAG_LONG);
+        head.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    } else {
+        head.push(flags);
+        head.push(body.len() as u8);
+    }
+    stream.write_all(&head)?;
+    stream.write_all(body)
+}
+
+fn read_frame(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let mut flags = [0u8];
+    stream.read_exact(&mut flags)?;
+    let size = if flags[0] & FLAG_LONG != 0 {
+        let mut size = [0u8; 8];
+        stream.read_exact(&mut size)?;
+        u64::from_be_bytes(size)
+    } else {
+        let mut size = [0u8];
+        stream.read_exact(&mut size)?;
+        u64::from(size[0])
+    };
+    if size > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let mut body = vec![0; size as usize];
+    stream.read_exact(&mut body)?;
+    Ok((flags[0], body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_binds_loopback() {
+        let error = ZmqSink::bind("0.0.0.0:0", Encoding::Json).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(ZmqSink::bind("127.0.0.1:0", Encoding::Json).is_ok());
+    }
+
+    #[test]
+    fn applies_subscriptions_and_cancellations() {
+        let mut topics = Vec::new();
+        apply_subscription(&mut topics, 0, b"\x01symon.gpu");
+        apply_subscription(&mut topics, FLAG_COMMAND, b"\x09SUBSCRIBEsymon.node");
+        apply_subscription(&mut topics, 0, b"\x00symon.gpu");
+        assert_eq!(topics, [b"symon.node".to_vec()]);
+        apply_subscription(&mut topics, FLAG_COMMAND, b"\x06CANCELsymon.node");
+        assert!(topics.is_empty());
+    }
+
+    #[test]
+    fn reads_ready_properties() {
+        let mut ready = b"\x05READY".to_vec();
+        ready.push(11);
+        ready.extend_from_slice(b"Socket-Type");
+        ready.extend_from_slice(&3u32.to_be_bytes());
+        ready.extend_from_slice(b"SUB");
+        assert_eq!(property(&ready, b"socket-type"), Some(&b"SUB"[..]));
+        assert_eq!(property(&ready, b"Identity"), None);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::metrics::{MetricKey, Metrics};
+use crate::report::gpu_field;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Metrics that get a smoothed companion: utilization, memory utilization and
+/// power of each GPU.
+const SMOOTHED_FIELDS: &[&str] = &["gpu", "memory", "powerWatts"];
+
+/// Per-metric state: the companion metric name and the previous average.
+struct Average {
+    key: &'static str,
+    last: Option<(f64, Duration)>,
+}
+
+/// Adds exponentially weighted moving averages of noisy metrics, e.g.
+/// `gpu.0.gpuSmoothed` next to `gpu.0.gpu`.
+///
+/// A reading's weight halves every `half_life`. Weights follow the time
+/// between samples, so the smoothing doesn't change with the sampling
+/// interval or when samples are missing. The first reading starts the average.
+pub struct Smoother {
+    half_life: Duration,
+    averages: HashMap<MetricKey, Average>,
+    scratch: Vec<(&'static str, f64)>,
+}
+
+impl Smoother {
+    pub fn new(half_life: Duration) -> Self {
+        Smoother {
+            half_life,
+            averages: HashMap::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    pub fn apply(&mut self, metrics: &mut Metrics) {
+        let Some(time) = metrics.time() else {
+            return;
+        };
+        let half_life = self.half_life.as_secs_f64();
+
+        self.scratch.clear();
+        metrics.for_each(|key, value| {
+            if !is_smoothed(key) {
+                return;
+            }
+            let Some(value) = value.as_f64() else {
+                return;
+            };
+            let average = self.averages.entry(key.clone()).or_insert_with(|| Average {
+                // Smoothed metrics are a small fixed set, so their names are
+                // leaked like the per-device metric names
+                key: Box::leak(format!("{}Smoothed", key).into_boxed_str()),
+                last: None,
+            });
+            let smoothed = match average.last {
+                Some((last, last_uptime)) if half_life > 0.0 => {
+                    let elapsed = time.uptime.saturating_sub(last_uptime).as_secs_f64();
+                    let weight = 1.0 - (-elapsed / half_life).exp2();
+                    last + weight * (value - last)
+                }
+                _ => value,
+            };
+            average.last = Some((smoothed, time.uptime));
+            self.scratch.push((average.key, smoothed));
+        });
+
+        for &(key, smoothed) in &self.scratch {
+            metrics.add_metric(key, smoothed);
+        }
+    }
+}
+
+fn is_smoothed(key: &str) -> bool {
+    key.starts_with("gpu.")
+        && gpu_field(key).is_some_and(|(_, field)| SMOOTHED_FIELDS.contains(&field))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::SampleTime;
+    use std::time::UNIX_EPOCH;
+
+    fn sample(secs: u64, gpu: f64) -> Metrics {
+        let mut metrics = Metrics::new();
+        metrics.add_metric("gpu.0.gpu", gpu);
+        metrics.add_metric("gpu.0.temp", 60.0);
+        metrics.set_time(SampleTime {
+            wall: UNIX_EPOCH + Duration::from_secs(1000 + secs),
+            uptime: Duration::from_secs(secs),
+        });
+        metrics
+    }
+
+    fn smoothed(metrics: &Metrics) -> Option<f64> {
+        metrics.get("gpu.0.gpuSmoothed").and_then(|v| v.as_f64())
+    }
+
+    #[test]
+    fn halves_the_weight_of_a_reading_every_half_life() {
+        let mut smoother = Smoother::new(Duration::from_secs(10));
+        let mut first = sample(0, 100.0);
+        smoother.apply(&mut first);
+        assert_eq!(smoothed(&first), Some(100.0));
+        assert!(first.get("gpu.0.tempSmoothed").is_none());
+
+        let mut second = sample(10, 0.0);
+        smoother.apply(&mut second);
+        assert_eq!(smoothed(&second), Some(50.0));
+        // Two half-lives at once weigh the same as two samples one apart
+        let mut third = sample(30, 0.0);
+        smoother.apply(&mut third);
+        assert_eq!(smoothed(&third), Some(12.5));
+    }
+}
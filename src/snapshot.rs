@@ -0,0 +1,251 @@
+use crate::devices::Devices;
+use crate::metrics::Metrics;
+use crate::timefmt::UtcDateTime;
+use serde_json::Value;
+use std::fmt::Write as _;
+use std::time::{Duration, UNIX_EPOCH};
+
+const MIB: f64 = (1u64 << 20) as f64;
+const LABEL_WIDTH: usize = 16;
+
+/// Render a sample and the devices as a human-readable overview of the node,
+/// one section per GPU, e.g. for bug reports.
+///
+/// Readings the GPU didn't report are left out rather than shown as N/A.
+pub fn render(metrics: &Metrics, devices: &Devices, nvml_version: &str) -> String {
+    let mut out = String::with_capacity(4096);
+    let number = |key: &str| metrics.get(key).and_then(|v| v.as_f64());
+    let text = |key: &str| metrics.get(key).and_then(|v| v.as_str());
+
+    let _ = write!(out, "symon {}", env!("CARGO_PKG_VERSION"));
+    if let Some(timestamp) = metrics.timestamp() {
+        let time = UNIX_EPOCH + Duration::try_from_secs_f64(timestamp).unwrap_or_default();
+        let time = UtcDateTime::from_system_time(time);
+        let _ = write!(
+            out,
+            "  {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            time.year, time.month, time.day, time.hour, time.minute, time.second
+        );
+    }
+    out.push('\n');
+    let _ = write!(out, "Driver {}", devices.driver_version);
+    if let Some(module) = devices.kernel_module {
+        let _ = write!(out, " ({} kernel module)", module);
+    }
+    if !nvml_version.is_empty() {
+        let _ = write!(out, "  NVML {}", nvml_version);
+    }
+    if let Some(cuda) = text("cuda_version") {
+        let _ = write!(out, "  CUDA {}", cuda);
+    }
+    out.push('\n');
+
+    let count = number("_gpu.count").unwrap_or(0.0) as u32;
+    if count == 0 {
+        out.push_str("\nNo GPUs found\n");
+    }
+    for i in 0..count {
+        let key = |field: &str| format!("gpu.{}.{}", i, field);
+        let meta = |field: &str| format!("_gpu.{}.{}", i, field);
+        let info = devices.gpus.iter().find(|gpu| gpu.index == i);
+        let mut section = Section::default();
+
+        let name = text(&meta("name")).unwrap_or("unknown");
+        let _ = writeln!(out, "\nGPU {}: {}", i, name);
+        if let Some(uuid) = info.and_then(|info| info.uuid.as_deref()) {
+            section.line("UUID", uuid.to_string());
+        }
+        if let Some(info) = info {
+            let architecture = info.architecture.as_deref().unwrap_or("unknown");
+            section.line("Architecture", format!("{} ({})", architecture, info.class));
+            if let Some(mode) = info.gsp_firmware_mode {
+                let version = info
+                    .gsp_firmware_version
+                    .as_deref()
+                    .map(|version| format!(" ({})", version))
+                    .unwrap_or_default();
+                section.line("GSP firmware", format!("{}{}", mode, version));
+            }
+        }
+        if let Some(mode) = text(&meta("virtualizationMode")).filter(|&mode| mode != "none") {
+            section.line("Virtualization", mode.to_string());
+        }
+
+        section.parts(
+            "Utilization",
+            [
+                number(&key("gpu")).map(|u| format!("GPU {:.0}%", u)),
+                number(&key("memory")).map(|u| format!("memory {:.0}%", u)),
+                number(&meta("encoderUtilization")).map(|u| format!("encoder {:.0}%", u)),
+            ],
+        );
+        if let (Some(used), Some(total)) = (
+            number(&key("memoryAllocatedBytes")),
+            number(&meta("memoryTotal")),
+        ) {
+            section.line(
+                "Memory",
+                format!(
+                    "{:.0} / {:.0} MiB ({:.1}%)",
+                    used / MIB,
+                    total / MIB,
+                    number(&key("memoryAllocated")).unwrap_or(0.0)
+                ),
+            );
+        }
+        section.parts(
+            "Temperature",
+            [
+                number(&key("temp")).map(|t| format!("GPU {:.0} C", t)),
+                number(&key("memoryTemp")).map(|t| format!("memory {:.0} C", t)),
+            ],
+        );
+        if let Some(watts) = number(&key("powerWatts")) {
+            let limit = match (
+                number(&key("enforcedPowerLimitWatts")),
+                number(&key("powerPercent")),
+            ) {
+                (Some(limit), Some(percent)) => format!(" / {:.0} W ({:.0}%)", limit, percent),
+                _ => " W".to_string(),
+            };
+            section.line("Power", format!("{:.0}{}", watts, limit));
+        }
+        section.parts(
+            "Fans",
+            [
+                number(&meta("fanSpeed")).map(|f| format!("{:.0}%", f)),
+                number(&meta("fanTargetSpeed")).map(|f| format!("target {:.0}%", f)),
+            ],
+        );
+        section.parts(
+            "Clocks",
+            [
+                number(&meta("smClock")).map(|c| format!("SM {:.0} MHz", c)),
+                number(&meta("memoryClock")).map(|c| format!("memory {:.0} MHz", c)),
+                number(&meta("pstate")).map(|p| format!("P{:.0}", p)),
+            ],
+        );
+        if let Some(reasons) = metrics
+            .get(&meta("throttleReasons"))
+            .and_then(|v| v.as_array())
+        {
+            let reasons: Vec<&str> = reasons.iter().filter_map(Value::as_str).collect();
+            let reasons = if reasons.is_empty() {
+                "none".to_string()
+            } else {
+                reasons.join(", ")
+            };
+            section.line("Throttling", reasons);
+        }
+        if let (Some(link_gen), Some(width)) =
+            (number(&meta("pcieLinkGen")), number(&meta("pcieLinkWidth")))
+        {
+            let max = match (
+                number(&meta("maxPcieLinkGen")),
+                number(&meta("maxPcieLinkWidth")),
+            ) {
+                (Some(max_gen), Some(max_width)) => {
+                    format!(" (max Gen{:.0} x{:.0})", max_gen, max_width)
+                }
+                _ => String::new(),
+            };
+            section.line("PCIe", format!("Gen{:.0} x{:.0}{}", link_gen, width, max));
+        }
+        section.parts(
+            "ECC errors",
+            [
+                number(&meta("correctedMemoryErrors")).map(|e| format!("{:.0} corrected", e)),
+                number(&meta("uncorrectedMemoryErrors")).map(|e| format!("{:.0} uncorrected", e)),
+            ],
+        );
+        section.parts(
+            "Remapped rows",
+            [
+                number(&meta("remappedRowsCorrectable")).map(|r| format!("{:.0} correctable", r)),
+                number(&meta("remappedRowsUncorrectable"))
+                    .map(|r| format!("{:.0} uncorrectable", r)),
+                (metrics.get(&meta("remappedRowsPending")) == Some(&Value::Bool(true)))
+                    .then(|| "pending reset".to_string()),
+                (metrics.get(&meta("remappedRowsFailed")) == Some(&Value::Bool(true)))
+                    .then(|| "failed".to_string()),
+            ],
+        );
+        if let Some(xids) = number(&meta("xidErrors")).filter(|&xids| xids > 0.0) {
+            let last = number(&meta("lastXid"))
+                .map(|xid| format!(", last {:.0}", xid))
+                .unwrap_or_default();
+            section.line("XID errors", format!("{:.0}{}", xids, last));
+        }
+        section.parts(
+            "Modes",
+            [
+                metrics
+                    .get(&meta("persistenceMode"))
+                    .and_then(|v| v.as_bool())
+                    .map(|on| format!("persistence {}", if on { "on" } else { "off" })),
+                text(&meta("computeMode")).map(|mode| format!("compute {}", mode)),
+                metrics
+                    .get(&meta("displayActive"))
+                    .and_then(|v| v.as_bool())
+                    .map(|on| format!("display {}", if on { "active" } else { "inactive" })),
+            ],
+        );
+
+        let processes = metrics
+            .get(&meta("processes"))
+            .and_then(|v| v.as_array())
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        section.line(
+            "Processes",
+            match processes.len() {
+                0 => "none".to_string(),
+                n => n.to_string(),
+            },
+        );
+        out.push_str(&section.out);
+        for process in processes {
+            let _ = write!(out, "    {:>8}", process["pid"].as_u64().unwrap_or(0));
+            if let Some(user) = process["user"].as_str() {
+                let _ = write!(out, "  {:<12}", user);
+            }
+            if let Some(bytes) = process["memoryBytes"].as_f64() {
+                let _ = write!(out, "  {:>8.0} MiB", bytes / MIB);
+            }
+            if let Some(command) = process["command"].as_str() {
+                let _ = write!(out, "  {}", command);
+            }
+            out.push('\n');
+        }
+    }
+    if metrics.get("_sampling_timeout").is_some() {
+        out.push_str("\nSampling timed out; readings may be missing\n");
+    }
+    out
+}
+
+/// The `label  value` lines of one GPU.
+#[derive(Default)]
+struct Section {
+    out: String,
+}
+
+impl Section {
+    fn line(&mut self, label: &str, value: String) {
+        let _ = writeln!(
+            self.out,
+            "  {:<width$}{}",
+            label,
+            value,
+            width = LABEL_WIDTH
+        );
+    }
+
+    /// A line of the reported parts joined by commas, if any were reported.
+    fn parts<const N: usize>(&mut self, label: &str, parts: [Option<String>; N]) {
+        let parts: Vec<String> = parts.into_iter().flatten().collect();
+        if !parts.is_empty() {
+            self.line(label, parts.join(", "));
+        }
+    }
+}
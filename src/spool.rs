@@ -0,0 +1,507 @@
+use crate::gap::SampleSpan;
+use crate::log;
+use crate::metrics::Metrics;
+use crate::sink::{Sink, SinkMetrics};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const CURSOR_FILE: &str = "cursor";
+const SEGMENT_EXTENSION: &str = "jsonl";
+const DRAIN_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Size-capped on-disk buffer of JSON lines, split into numbered segment files.
+///
+/// Records are appended to the newest segment and consumed from the oldest.
+/// When the cap is exceeded, whole segments are discarded oldest-first.
+/// Each line is prefixed with a sequence number, and the number of the last
+/// delivered record is persisted in a cursor file so that records delivered
+/// before a crash or restart are not replayed twice. Timestamps can't serve
+/// for this, as the clock may step back.
+struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    /// Segment ids and sizes, oldest first.
+    segments: VecDeque<(u64, u64)>,
+    writer: Option<File>,
+    last_delivered: Option<u64>,
+    next_seq: u64,
+    buf: Vec<u8>,
+}
+
+impl Spool {
+    fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                segments.push((id, fs::metadata(&path)?.len()));
+            }
+        }
+        segments.sort_unstable();
+
+        // A corrupted cursor only means some records may be delivered twice
+        let last_delivered: Option<u64> = fs::read_to_string(dir.join(CURSOR_FILE))
+            .ok()
+            .and_then(|s| s.trim().parse().ok());
+        // Continue numbering after the newest spooled record
+        let mut next_seq = last_delivered.map_or(0, |seq| seq + 1);
+        for &(id, _) in segments.iter().rev() {
+            let data = fs::read(dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION)))?;
+            let last = data
+                .split(|&b| b == b'\n')
+                .filter_map(|line| parse_line(line).map(|(seq, _)| seq))
+                .max();
+            if let Some(seq) = last {
+                next_seq = next_seq.max(seq + 1);
+                break;
+            }
+        }
+
+        Ok(Spool {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            segment_bytes: (max_bytes / 16).clamp(64 * 1024, 16 * 1024 * 1024),
+            segments: segments.into(),
+            writer: None,
+            last_delivered,
+            next_seq,
+            buf: Vec::with_capacity(4096),
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.segments.iter().all(|&(_, size)| size == 0)
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.segments.iter().map(|&(_, size)| size).sum()
+    }
+
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+    }
+
+    fn append(&mut self, metrics: &Metrics) -> io::Result<()> {
+        metrics.to_json_line(&mut self.buf)?;
+        let seq = self.next_seq;
+        self.buf.splice(0..0, format!("{} ", seq).into_bytes());
+        let len = self.buf.len() as u64;
+
+        // Enforce the size cap by discarding the oldest segments
+        while self.total_bytes() + len > self.max_bytes && self.segments.len() > 1 {
+            if let Some((id, size)) = self.segments.pop_front() {
+                log::warning!(
+                    "Spool {} is full, discarding {} bytes of oldest samples",
+                    self.dir.display(),
+                    size
+                );
+                fs::remove_file(self.segment_path(id))?;
+            }
+        }
+
+        let needs_new_segment = match self.segments.back() {
+            Some(&(_, size)) => self.writer.is_none() || size + len > self.segment_bytes,
+            None => true,
+        };
+        if needs_new_segment {
+            let id = self.segments.back().map_or(0, |&(id, _)| id + 1);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.segment_path(id))?;
+            self.writer = Some(file);
+            self.segments.push_back((id, 0));
+        }
+
+        if let (Some(writer), Some(segment)) = (self.writer.as_mut(), self.segments.back_mut()) {
+            writer.write_all(&self.buf)?;
+            segment.1 += len;
+        }
+        self.next_seq = seq + 1;
+        Ok(())
+    }
+
+    /// Deliver spooled records to `sink` in order, stopping at the first failure.
+    fn drain(&mut self, sink: &mut dyn Sink) -> io::Result<()> {
+        while let Some(&(id, _)) = self.segments.front() {
+            let path = self.segment_path(id);
+            let data = fs::read(&path)?;
+
+            let mut delivered = 0;
+            let mut result = Ok(());
+            for line in data.split_inclusive(|&b| b == b'\n') {
+                let (seq, metrics) = match parse_line(line) {
+                    Some(record) => record,
+                    // Skip records truncated by a crash mid-write
+                    None => {
+                        delivered += line.len();
+                        continue;
+                    }
+                };
+                if self.last_delivered.is_some_and(|last| seq <= last) {
+                    // Already delivered before a restart
+                    delivered += line.len();
+                    continue;
+                }
+                if let Err(e) = sink.write(&metrics) {
+                    result = Err(e);
+                    break;
+                }
+                delivered += line.len();
+                self.last_delivered = Some(seq);
+            }
+            self.save_cursor()?;
+
+            if result.is_err() {
+                if delivered == 0 {
+                    return result;
+                }
+                // Keep the undelivered tail of the segment for the next attempt
+                let remaining = &data[delivered..];
+                fs::write(&path, remaining)?;
+                if let Some(segment) = self.segments.front_mut() {
+                    segment.1 = remaining.len() as u64;
+                }
+                if self.segments.len() == 1 {
+                    // The tail was rewritten; reopen before appending to it again
+                    self.writer = None;
+                }
+                return result;
+            }
+
+            fs::remove_file(&path)?;
+            self.segments.pop_front();
+            if self.segments.is_empty() {
+                self.writer = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn save_cursor(&self) -> io::Result<()> {
+        if let Some(seq) = self.last_delivered {
+            fs::write(self.dir.join(CURSOR_FILE), format!("{}\n", seq))?;
+        }
+        Ok(())
+    }
+}
+
+/// Split a spooled line into its sequence number and record.
+fn parse_line(line: &[u8]) -> Option<(u64, Metrics)> {
+    let space = line.iter().position(|&b| b == b' ')?;
+    let seq = std::str::from_utf8(&line[..space]).ok()?.parse().ok()?;
+    let metrics = Metrics::from_json(&line[space + 1..]).ok()?;
+    Some((seq, metrics))
+}
+
+/// Wraps a network sink and spools samples to disk while it is unreachable.
+///
+/// Once the sink accepts writes again, spooled samples are replayed in order
+/// before any new sample is delivered.
+///
+/// The spool is only read on the first write, on the writer thread. When
+/// sinks are rebuilt on reload, the new writer is started before the old one
+/// is closed, and the old sink must have stopped appending by then.
+pub struct SpoolingSink {
+    inner: Box<dyn Sink>,
+    dir: PathBuf,
+    max_bytes: u64,
+    spool: Option<Spool>,
+    spooling: bool,
+    retry_after: Instant,
+}
+
+impl SpoolingSink {
+    pub fn new(inner: Box<dyn Sink>, dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(SpoolingSink {
+            inner,
+            dir: dir.to_path_buf(),
+            max_bytes,
+            spool: None,
+            spooling: false,
+            retry_after: Instant::now(),
+        })
+    }
+
+    /// Open the spool, replaying what an earlier run left behind first.
+    fn spool(&mut self) -> io::Result<&mut Spool> {
+        if self.spool.is_none() {
+            let spool = Spool::open(&self.dir, self.max_bytes)?;
+            self.spooling = !spool.is_empty();
+            self.spool = Some(spool);
+        }
+        Ok(self.spool.as_mut().expect("spool was just opened"))
+    }
+}
+
+impl Sink for SpoolingSink {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+        self.spool()?;
+        let undelivered = self.inner.take_undelivered();
+        if !undelivered.is_empty() {
+            log::warning!(
+                "Sink {} couldn't deliver {} samples, spooling them to {}",
+                self.inner.name(),
+                undelivered.len(),
+                self.dir.display()
+            );
+            let spool = self.spool()?;
+            for sample in &undelivered {
+                spool.append(sample)?;
+            }
+            if !self.spooling {
+                self.spooling = true;
+                self.retry_after = Instant::now() + DRAIN_RETRY_INTERVAL;
+            }
+        }
+        if !self.spooling {
+            match self.inner.write(metrics) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    log::warning!(
+                        "Sink {} unreachable ({}), spooling samples to {}",
+                        self.inner.name(),
+                        e,
+                        self.dir.display()
+                    );
+                    self.spooling = true;
+                    self.retry_after = Instant::now() + DRAIN_RETRY_INTERVAL;
+                }
+            }
+        }
+
+        self.spool()?.append(metrics)?;
+        if Instant::now() < self.retry_after {
+            return Ok(());
+        }
+        let Some(spool) = self.spool.as_mut() else {
+            return Ok(());
+        };
+        match spool.drain(self.inner.as_mut()) {
+            Ok(()) => {
+                log::info!("Sink {} reachable again, spool drained", self.inner.name());
+                self.spooling = false;
+            }
+            Err(_) => self.retry_after = Instant::now() + DRAIN_RETRY_INTERVAL,
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn metrics(&self) -> Option<Arc<dyn SinkMetrics>> {
+        self.inner.metrics()
+    }
+
+    fn take_lost(&mut self) -> SampleSpan {
+        self.inner.take_lost()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::SampleTime;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// Records the timestamps it receives, failing while `down`.
+    #[derive(Default)]
+    struct Collector {
+        down: bool,
+        received: Vec<f64>,
+    }
+
+    /// A collector that can be inspected after handing it to a `SpoolingSink`.
+    struct Shared(bool, std::sync::Arc<std::sync::Mutex<Vec<f64>>>);
+
+    impl Sink for Shared {
+        fn name(&self) -> &str {
+            "shared"
+        }
+
+        fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+            if self.0 {
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            self.1.lock().unwrap().extend(metrics.timestamp());
+            Ok(())
+        }
+    }
+
+    impl Sink for Collector {
+        fn name(&self) -> &str {
+            "collector"
+        }
+
+        fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+            if self.down {
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            self.received.extend(metrics.timestamp());
+            Ok(())
+        }
+    }
+
+    fn sample(timestamp: u64) -> Metrics {
+        let mut metrics = Metrics::new();
+        metrics.set_time(SampleTime {
+            wall: UNIX_EPOCH + Duration::from_secs(timestamp),
+            uptime: Duration::ZERO,
+        });
+        metrics
+    }
+
+    fn spool_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!(
+            "symon-spool-{}-{}-{}",
+            name,
+            std::process::id(),
+            nanos
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn replays_in_order_even_when_the_clock_steps_back() {
+        let dir = spool_dir("clock");
+        let mut spool = Spool::open(&dir, 1 << 20).unwrap();
+        for timestamp in [100, 101, 50, 51] {
+            spool.append(&sample(timestamp)).unwrap();
+        }
+        let mut sink = Collector::default();
+        spool.drain(&mut sink).unwrap();
+        assert_eq!(sink.received, [100.0, 101.0, 50.0, 51.0]);
+        assert!(spool.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn doesnt_replay_delivered_records_after_a_restart() {
+        let dir = spool_dir("restart");
+        let mut spool = Spool::open(&dir, 1 << 20).unwrap();
+        for timestamp in [10, 11, 12] {
+            spool.append(&sample(timestamp)).unwrap();
+        }
+        spool.last_delivered = Some(0);
+        spool.save_cursor().unwrap();
+        drop(spool);
+
+        // Reopening continues the numbering, so new records aren't mistaken
+        // for delivered ones even with older timestamps
+        let mut spool = Spool::open(&dir, 1 << 20).unwrap();
+        spool.append(&sample(5)).unwrap();
+        let mut sink = Collector::default();
+        spool.drain(&mut sink).unwrap();
+        assert_eq!(sink.received, [11.0, 12.0, 5.0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_undelivered_records_for_the_next_attempt() {
+        let dir = spool_dir("retry");
+        let mut spool = Spool::open(&dir, 1 << 20).unwrap();
+        let mut collector = Collector {
+            down: true,
+            ..Collector::default()
+        };
+        spool.append(&sample(1)).unwrap();
+        spool.append(&sample(2)).unwrap();
+        assert!(spool.drain(&mut collector).is_err());
+        collector.down = false;
+        spool.drain(&mut collector).unwrap();
+        assert_eq!(collector.received, [1.0, 2.0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_rebuilt_sink_takes_over_the_spool() {
+        let dir = spool_dir("rebuilt");
+        let received = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut old =
+            SpoolingSink::new(Box::new(Shared(true, received.clone())), &dir, 1 << 20).unwrap();
+        // Opening the replacement before the old sink is done doesn't read the spool yet
+        let mut new =
+            SpoolingSink::new(Box::new(Shared(false, received.clone())), &dir, 1 << 20).unwrap();
+        old.write(&sample(1)).unwrap();
+        old.write(&sample(2)).unwrap();
+        drop(old);
+        new.write(&sample(3)).unwrap();
+        assert_eq!(*received.lock().unwrap(), [1.0, 2.0, 3.0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Accepts every sample but gives the first one back as undelivered.
+    struct GivesBack {
+        given: bool,
+        undelivered: Option<Metrics>,
+        received: std::sync::Arc<std::sync::Mutex<Vec<f64>>>,
+    }
+
+    impl Sink for GivesBack {
+        fn name(&self) -> &str {
+            "gives-back"
+        }
+
+        fn write(&mut self, metrics: &Metrics) -> io::Result<()> {
+            if self.given {
+                self.received.lock().unwrap().extend(metrics.timestamp());
+            } else {
+                let mut copy = Metrics::new();
+                copy.copy_from(metrics);
+                self.undelivered = Some(copy);
+                self.given = true;
+            }
+            Ok(())
+        }
+
+        fn take_undelivered(&mut self) -> Vec<Metrics> {
+            self.undelivered.take().into_iter().collect()
+        }
+    }
+
+    #[test]
+    fn spools_samples_the_sink_gives_back() {
+        let dir = spool_dir("undelivered");
+        let received = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let inner = GivesBack {
+            given: false,
+            undelivered: None,
+            received: received.clone(),
+        };
+        let mut sink = SpoolingSink::new(Box::new(inner), &dir, 1 << 20).unwrap();
+        sink.write(&sample(1)).unwrap();
+        // The sample given back is spooled and the new one queued behind it
+        sink.write(&sample(2)).unwrap();
+        sink.retry_after = Instant::now();
+        sink.write(&sample(3)).unwrap();
+        assert_eq!(*received.lock().unwrap(), [1.0, 2.0, 3.0]);
+        assert!(!sink.spooling);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use crate::billing::BillingState;
+use crate::counters::CounterTotal;
+use crate::log;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How often the state is saved besides at exit and on billing records.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Everything that has to survive agent restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct State {
+    pub billing: Option<BillingState>,
+    /// Reset-adjusted GPU counters such as energy, ECC and XID errors.
+    pub counters: BTreeMap<String, CounterTotal>,
+}
+
+/// A JSON file holding `State`.
+///
+/// Saves write a temporary file, sync it and rename it over the state file,
+/// keeping the previous state as `<path>.bak`. If the state file is unreadable
+/// or corrupt, e.g. after a crash on a filesystem without ordered renames, the
+/// backup is used instead and the bad file is set aside as `<path>.corrupt`.
+pub struct StateFile {
+    path: PathBuf,
+    saved_at: Option<Instant>,
+}
+
+impl StateFile {
+    pub fn new(path: &Path) -> Self {
+        StateFile {
+            path: path.to_path_buf(),
+            saved_at: None,
+        }
+    }
+
+    /// Read the saved state, falling back to the backup and then to an empty
+    /// state, so a bad file never keeps the agent from starting.
+    pub fn load(&self) -> State {
+        let error = match read(&self.path) {
+            Ok(Some(state)) => return state,
+            Ok(None) => return State::default(),
+            Err(e) => e,
+        };
+        log::warning!(
+            "Error reading state from {}: {}",
+            self.path.display(),
+            error
+        );
+        let corrupt = with_suffix(&self.path, "corrupt");
+        if let Err(e) = fs::rename(&self.path, &corrupt) {
+            log::warning!("Error moving {} aside: {}", self.path.display(), e);
+        }
+
+        let backup = with_suffix(&self.path, "bak");
+        match read(&backup) {
+            Ok(Some(state)) => {
+                log::warning!("Recovered state from {}", backup.display());
+                state
+            }
+            Ok(None) => State::default(),
+            Err(e) => {
+                log::warning!("Error reading state from {}: {}", backup.display(), e);
+                State::default()
+            }
+        }
+    }
+
+    /// Whether the last save was long enough ago to save again.
+    pub fn save_due(&self) -> bool {
+        self.saved_at
+            .is_none_or(|saved_at| saved_at.elapsed() >= SAVE_INTERVAL)
+    }
+
+    pub fn save(&mut self, state: &State) -> io::Result<()> {
+        self.saved_at = Some(Instant::now());
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = with_suffix(&self.path, "tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(state)?)?;
+        file.sync_all()?;
+        drop(file);
+
+        // The state file stays intact while it's copied, so a torn backup
+        // only matters if the state file gets corrupted as well
+        match fs::copy(&self.path, with_suffix(&self.path, "bak")) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+/// Parse a state file; `None` if it doesn't exist.
+fn read(path: &Path) -> io::Result<Option<State>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
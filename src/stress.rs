@@ -0,0 +1,460 @@
+//! Burn-in for `symon stress`: loads every GPU with a CUDA kernel while
+//! symon samples them, and judges the thermal and power behavior, as an
+//! acceptance test for new nodes.
+//!
+//! The kernel is PTX the driver compiles when it's loaded, so no CUDA
+//! compiler is needed at build time.
+
+use crate::cuda::{
+    self, check, sym, CuContext, CuDevice, CuDevicePtr, CuFunction, CuModule, CuResult, CudaError,
+    CUDA_SUCCESS,
+};
+use crate::metrics::Metrics;
+use crate::report::gpu_field;
+use libloading::{Library, Symbol};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{c_char, c_int, c_uint, c_void};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+const CU_DEVICE_ATTRIBUTE_MULTIPROCESSOR_COUNT: c_int = 16;
+const THREADS_PER_BLOCK: c_uint = 256;
+/// Blocks per SM, enough to keep every SM busy.
+const BLOCKS_PER_SM: c_uint = 8;
+/// FMA iterations per launch, a few tens of milliseconds on current GPUs so
+/// stopping is prompt.
+const ITERATIONS: c_uint = 1 << 16;
+/// Device memory rewritten between launches to load the memory as well.
+const SCRATCH_BYTES: usize = 256 << 20;
+
+/// Two dependent FMAs per iteration on every thread, and one store at the end
+/// so the loop isn't optimized away.
+const BURN_PTX: &[u8] = b"
+.version 6.0
+.target sm_50
+.address_size 64
+
+.visible .entry burn(
+    .param .u64 burn_param_0,
+    .param .u32 burn_param_1
+)
+{
+    .reg .pred %p<2>;
+    .reg .f32 %f<4>;
+    .reg .b32 %r<8>;
+    .reg .b64 %rd<4>;
+
+    ld.param.u64 %rd1, [burn_param_0];
+    ld.param.u32 %r1, [burn_param_1];
+    mov.u32 %r2, %ctaid.x;
+    mov.u32 %r3, %ntid.x;
+    mov.u32 %r4, %tid.x;
+    mad.lo.s32 %r5, %r2, %r3, %r4;
+    cvt.rn.f32.u32 %f1, %r5;
+    mov.f32 %f2, 0f3F7FFFFE;
+    mov.f32 %f3, 0f3F800000;
+    mov.u32 %r6, 0;
+$L_loop:
+    fma.rn.f32 %f1, %f1, %f2, %f3;
+    fma.rn.f32 %f3, %f3, %f2, %f1;
+    add.u32 %r6, %r6, 1;
+    setp.lt.u32 %p1, %r6, %r1;
+    @%p1 bra $L_loop;
+    cvta.to.global.u64 %rd2, %rd1;
+    mul.wide.u32 %rd3, %r5, 4;
+    add.s64 %rd2, %rd2, %rd3;
+    st.global.f32 [%rd2], %f3;
+    ret;
+}
+\0";
+
+/// Throttle reasons that mean the GPU couldn't hold its clocks under load for
+/// reasons other than its configured power limit.
+const FAILING_THROTTLE_REASONS: &[&str] = &[
+    "hwSlowdown",
+    "swThermalSlowdown",
+    "hwThermalSlowdown",
+    "hwPowerBrakeSlowdown",
+];
+
+/// Runs the burn kernel on each of a set of GPUs, one thread per GPU, until
+/// stopped.
+pub struct Burner {
+    running: Arc<AtomicBool>,
+    threads: Vec<(u32, JoinHandle<Result<(), CudaError>>)>,
+}
+
+impl Burner {
+    /// Start loading `gpus`, given as NVML indices and PCI bus IDs.
+    pub fn start(gpus: &[(u32, String)]) -> Result<Self, CudaError> {
+        let lib = Arc::new(cuda::load()?);
+
+        let mut burner = Burner {
+            running: Arc::new(AtomicBool::new(true)),
+            threads: Vec::with_capacity(gpus.len()),
+        };
+        for (gpu, bus_id) in gpus {
+            let (lib, running, bus_id) = (lib.clone(), burner.running.clone(), bus_id.clone());
+            let spawned = thread::Builder::new()
+                .name(format!("stress-{}", gpu))
+                .spawn(move || burn(&lib, &bus_id, &running));
+            match spawned {
+                Ok(thread) => burner.threads.push((*gpu, thread)),
+                Err(e) => {
+                    // Stop the GPUs already loaded rather than leave them burning
+                    burner.stop();
+                    return Err(CudaError(e.to_string()));
+                }
+            }
+        }
+        Ok(burner)
+    }
+
+    /// Stop the load and wait for it to end, returning the GPUs whose load
+    /// failed and why.
+    pub fn stop(self) -> BTreeMap<u32, String> {
+        self.running.store(false, Ordering::Relaxed);
+        self.threads
+            .into_iter()
+            .filter_map(|(gpu, thread)| match thread.join() {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some((gpu, e.to_string())),
+                Err(_) => Some((gpu, "load thread panicked".to_string())),
+            })
+            .collect()
+    }
+}
+
+/// Launch the burn kernel on the GPU at `bus_id` back to back until
+/// `running` clears.
+fn burn(lib: &Library, bus_id: &str, running: &AtomicBool) -> Result<(), CudaError> {
+    // SAFETY: the signatures match cuda.h, every out pointer outlives its call,
+    // and the context is current on this thread for all calls using it
+    unsafe {
+        let attribute: Symbol<unsafe extern "C" fn(*mut c_int, c_int, CuDevice) -> CuResult> =
+            sym(lib, b"cuDeviceGetAttribute\0")?;
+        let ctx_create: Symbol<unsafe extern "C" fn(*mut CuContext, c_uint, CuDevice) -> CuResult> =
+            sym(lib, b"cuCtxCreate_v2\0")?;
+        let ctx_destroy: Symbol<unsafe extern "C" fn(CuContext) -> CuResult> =
+            sym(lib, b"cuCtxDestroy_v2\0")?;
+        let module_load: Symbol<unsafe extern "C" fn(*mut CuModule, *const c_void) -> CuResult> =
+            sym(lib, b"cuModuleLoadData\0")?;
+        let get_function: Symbol<
+            unsafe extern "C" fn(*mut CuFunction, CuModule, *const c_char) -> CuResult,
+        > = sym(lib, b"cuModuleGetFunction\0")?;
+        let mem_alloc: Symbol<unsafe extern "C" fn(*mut CuDevicePtr, usize) -> CuResult> =
+            sym(lib, b"cuMemAlloc_v2\0")?;
+        let mem_free: Symbol<unsafe extern "C" fn(CuDevicePtr) -> CuResult> =
+            sym(lib, b"cuMemFree_v2\0")?;
+        let memset: Symbol<unsafe extern "C" fn(CuDevicePtr, c_uint, usize) -> CuResult> =
+            sym(lib, b"cuMemsetD32_v2\0")?;
+        #[allow(clippy::type_complexity)]
+        let launch: Symbol<
+            unsafe extern "C" fn(
+                CuFunction,
+                c_uint,
+                c_uint,
+                c_uint,
+                c_uint,
+                c_uint,
+                c_uint,
+                c_uint,
+                *mut c_void,
+                *mut *mut c_void,
+                *mut *mut c_void,
+            ) -> CuResult,
+        > = sym(lib, b"cuLaunchKernel\0")?;
+        let synchronize: Symbol<unsafe extern "C" fn() -> CuResult> =
+            sym(lib, b"cuCtxSynchronize\0")?;
+
+        let device = cuda::device(lib, bus_id)?;
+        let mut sms: c_int = 0;
+        check(
+            "cuDeviceGetAttribute",
+            attribute(&mut sms, CU_DEVICE_ATTRIBUTE_MULTIPROCESSOR_COUNT, device),
+        )?;
+        let mut context: CuContext = std::ptr::null_mut();
+        check("cuCtxCreate", ctx_create(&mut context, 0, device))?;
+
+        // Destroying the context frees the module and memory, whatever failed
+        let result = (|| {
+            let mut module: CuModule = std::ptr::null_mut();
+            check(
+                "cuModuleLoadData",
+                module_load(&mut module, BURN_PTX.as_ptr().cast()),
+            )?;
+            let mut function: CuFunction = std::ptr::null_mut();
+            check(
+                "cuModuleGetFunction",
+                get_function(&mut function, module, c"burn".as_ptr()),
+            )?;
+            let blocks = (sms.max(1) as c_uint) * BLOCKS_PER_SM;
+            let threads = blocks * THREADS_PER_BLOCK;
+            let mut out: CuDevicePtr = 0;
+            check("cuMemAlloc", mem_alloc(&mut out, threads as usize * 4))?;
+            // Memory load is a bonus, so a GPU short on memory still burns
+            let mut scratch: CuDevicePtr = 0;
+            let scratch =
+                (mem_alloc(&mut scratch, SCRATCH_BYTES) == CUDA_SUCCESS).then_some(scratch);
+
+            let mut iterations = ITERATIONS;
+            let mut params = [
+                (&mut out as *mut CuDevicePtr).cast::<c_void>(),
+                (&mut iterations as *mut c_uint).cast::<c_void>(),
+            ];
+            let mut pass = 0u32;
+            while running.load(Ordering::Relaxed) {
+                check(
+                    "cuLaunchKernel",
+                    launch(
+                        function,
+                        blocks,
+                        1,
+                        1,
+                        THREADS_PER_BLOCK,
+                        1,
+                        1,
+                        0,
+                        std::ptr::null_mut(),
+                        params.as_mut_ptr(),
+                        std::ptr::null_mut(),
+                    ),
+                )?;
+                if let Some(scratch) = scratch {
+                    check("cuMemsetD32", memset(scratch, pass, SCRATCH_BYTES / 4))?;
+                }
+                check("cuCtxSynchronize", synchronize())?;
+                pass = pass.wrapping_add(1);
+            }
+            if let Some(scratch) = scratch {
+                mem_free(scratch);
+            }
+            mem_free(out);
+            Ok(())
+        })();
+        ctx_destroy(context);
+        result
+    }
+}
+
+/// Limits a GPU must stay within under load to pass.
+#[derive(Clone, Copy, Debug)]
+pub struct StressLimits {
+    /// Highest acceptable GPU temperature (in Celsius).
+    pub max_temp: f64,
+    /// Mean power draw, as a share of the power limit, the load must reach;
+    /// lower means the GPU isn't performing, e.g. stuck at low clocks.
+    pub min_power_percent: f64,
+}
+
+/// What the samples of one GPU showed under load.
+#[derive(Clone, Debug, Default)]
+struct GpuStats {
+    samples: u64,
+    max_temp: Option<f64>,
+    max_memory_temp: Option<f64>,
+    max_power_watts: Option<f64>,
+    power_percent_sum: f64,
+    power_percent_samples: u64,
+    min_sm_clock: Option<f64>,
+    throttle_reasons: BTreeSet<String>,
+    /// XID errors and uncorrected ECC errors at the first and last sample.
+    xids: Option<(u64, u64)>,
+    uncorrected_errors: Option<(u64, u64)>,
+}
+
+fn update_max(max: &mut Option<f64>, value: f64) {
+    *max = Some(max.map_or(value, |max| max.max(value)));
+}
+
+fn update_range(range: &mut Option<(u64, u64)>, value: u64) {
+    *range = Some(range.map_or((value, value), |(first, _)| (first, value)));
+}
+
+/// The per-GPU result of a stress run, built from the samples taken under
+/// load, see `StressReport::observe`.
+pub struct StressReport {
+    gpus: BTreeMap<u32, GpuStats>,
+    /// Load failures per GPU, see `Burner::stop`.
+    load_failures: BTreeMap<u32, String>,
+    limits: StressLimits,
+}
+
+impl StressReport {
+    pub fn new(gpus: &[u32], limits: StressLimits) -> Self {
+        StressReport {
+            gpus: gpus.iter().map(|&gpu| (gpu, GpuStats::default())).collect(),
+            load_failures: BTreeMap::new(),
+            limits,
+        }
+    }
+
+    /// Account for a sample taken under load.
+    pub fn observe(&mut self, metrics: &Metrics) {
+        metrics.for_each(|key, value| {
+            let Some((gpu, field)) = gpu_field(key) else {
+                return;
+            };
+            let Some(stats) = self.gpus.get_mut(&gpu) else {
+                return;
+            };
+            match field {
+                "temp" => {
+                    stats.samples += 1;
+                    if let Some(celsius) = value.as_f64() {
+                        update_max(&mut stats.max_temp, celsius);
+                    }
+                }
+                "memoryTemp" => {
+                    if let Some(celsius) = value.as_f64() {
+                        update_max(&mut stats.max_memory_temp, celsius);
+                    }
+                }
+                "powerWatts" => {
+                    if let Some(watts) = value.as_f64() {
+                        update_max(&mut stats.max_power_watts, watts);
+                    }
+                }
+                "powerPercent" => {
+                    if let Some(percent) = value.as_f64() {
+                        stats.power_percent_sum += percent;
+                        stats.power_percent_samples += 1;
+                    }
+                }
+                "smClock" => {
+                    if let Some(mhz) = value.as_f64() {
+                        stats.min_sm_clock =
+                            Some(stats.min_sm_clock.map_or(mhz, |min| min.min(mhz)));
+                    }
+                }
+                "throttleReasons" => {
+                    for reason in value.as_array().into_iter().flatten() {
+                        if let Some(reason) = reason.as_str() {
+                            stats.throttle_reasons.insert(reason.to_string());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        });
+        self.observe_errors(metrics);
+    }
+
+    /// Account for the error counters of a sample taken before or after the
+    /// load, so errors anywhere in the run are caught.
+    pub fn observe_errors(&mut self, metrics: &Metrics) {
+        metrics.for_each(|key, value| {
+            let Some((gpu, field)) = gpu_field(key) else {
+                return;
+            };
+            let (Some(stats), Some(count)) = (self.gpus.get_mut(&gpu), value.as_u64()) else {
+                return;
+            };
+            match field {
+                "xidErrors" => update_range(&mut stats.xids, count),
+                "uncorrectedMemoryErrors" => update_range(&mut stats.uncorrected_errors, count),
+                _ => {}
+            }
+        });
+    }
+
+    /// Record GPUs whose load failed, which fail the run.
+    pub fn set_load_failures(&mut self, failures: BTreeMap<u32, String>) {
+        self.load_failures = failures;
+    }
+
+    /// Why each GPU failed; empty for GPUs that passed.
+    pub fn failures(&self) -> BTreeMap<u32, Vec<String>> {
+        let limits = self.limits;
+        self.gpus
+            .iter()
+            .map(|(&gpu, stats)| {
+                let mut failures = Vec::new();
+                if let Some(failure) = self.load_failures.get(&gpu) {
+                    failures.push(format!("load failed: {}", failure));
+                }
+                if stats.samples == 0 {
+                    failures.push("no samples".to_string());
+                }
+                if let Some(celsius) = stats.max_temp.filter(|&t| t > limits.max_temp) {
+                    failures.push(format!(
+                        "reached {:.0} C, above {:.0} C",
+                        celsius, limits.max_temp
+                    ));
+                }
+                let slowdowns: Vec<&str> = FAILING_THROTTLE_REASONS
+                    .iter()
+                    .copied()
+                    .filter(|reason| stats.throttle_reasons.contains(*reason))
+                    .collect();
+                if !slowdowns.is_empty() {
+                    failures.push(format!("slowed down by {}", slowdowns.join(", ")));
+                }
+                if stats.power_percent_samples > 0 {
+                    let mean = stats.power_percent_sum / stats.power_percent_samples as f64;
+                    if mean < limits.min_power_percent {
+                        failures.push(format!(
+                            "drew {:.0}% of its power limit on average, below {:.0}%",
+                            mean, limits.min_power_percent
+                        ));
+                    }
+                }
+                if let Some((first, last)) = stats.xids.filter(|(first, last)| last > first) {
+                    failures.push(format!("{} XID errors", last - first));
+                }
+                if let Some((first, last)) = stats
+                    .uncorrected_errors
+                    .filter(|(first, last)| last > first)
+                {
+                    failures.push(format!("{} uncorrected ECC errors", last - first));
+                }
+                (gpu, failures)
+            })
+            .collect()
+    }
+
+    /// Whether every GPU passed.
+    pub fn passed(&self) -> bool {
+        self.failures().values().all(Vec::is_empty)
+    }
+}
+
+impl fmt::Display for StressReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failures = self.failures();
+        for (gpu, stats) in &self.gpus {
+            let failures = failures.get(gpu).map(Vec::as_slice).unwrap_or_default();
+            let verdict = if failures.is_empty() { "PASS" } else { "FAIL" };
+            writeln!(f, "GPU {}: {}", gpu, verdict)?;
+            let or_na = |value: Option<f64>, unit: &str| {
+                value.map_or_else(|| "N/A".to_string(), |v| format!("{:.0}{}", v, unit))
+            };
+            writeln!(
+                f,
+                "  max temp {}, max memory temp {}",
+                or_na(stats.max_temp, " C"),
+                or_na(stats.max_memory_temp, " C")
+            )?;
+            let mean_power_percent = (stats.power_percent_samples > 0)
+                .then(|| stats.power_percent_sum / stats.power_percent_samples as f64);
+            writeln!(
+                f,
+                "  max power {}, mean {} of limit, min SM clock {}",
+                or_na(stats.max_power_watts, " W"),
+                or_na(mean_power_percent, "%"),
+                or_na(stats.min_sm_clock, " MHz")
+            )?;
+            if !stats.throttle_reasons.is_empty() {
+                let reasons: Vec<&str> =
+                    stats.throttle_reasons.iter().map(String::as_str).collect();
+                writeln!(f, "  throttled by {}", reasons.join(", "))?;
+            }
+            for failure in failures {
+                writeln!(f, "  failed: {}", failure)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use crate::metrics::{MetricKey, Metrics};
+use crate::sampler::Sample;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Something that happened to the sampler itself or to settings it controls.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// NVML did not return within the sampling timeout.
+    SamplingTimedOut,
+    /// NVML returned an error; the sample may be incomplete.
+    SamplingFailed(String),
+    /// The power policy changed a GPU's power limit.
+    PowerLimitChanged {
+        gpu: u32,
+        watts: f64,
+        reason: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// A condition on a GPU that likely needs attention.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    pub severity: Severity,
+    /// The metric that triggered the alert.
+    pub metric: String,
+    pub message: String,
+}
+
+/// Identifies a registered subscriber so it can be removed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+type Callback<T> = Arc<dyn Fn(&T) + Send + Sync>;
+
+struct List<T: ?Sized> {
+    entries: RwLock<Vec<(SubscriptionId, Callback<T>)>>,
+}
+
+impl<T> List<T> {
+    fn new() -> Self {
+        List {
+            entries: RwLock::new(Vec::new()),
+        }
+    }
+
+    fn add(&self, id: SubscriptionId, callback: Callback<T>) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.push((id, callback));
+        }
+    }
+
+    fn remove(&self, id: SubscriptionId) -> bool {
+        let Ok(mut entries) = self.entries.write() else {
+            return false;
+        };
+        let before = entries.len();
+        entries.retain(|(entry, _)| *entry != id);
+        entries.len() != before
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries
+            .read()
+            .map_or(true, |entries| entries.is_empty())
+    }
+
+    fn notify(&self, value: &T) {
+        // Clone the callbacks so they can (un)subscribe without deadlocking
+        let callbacks: Vec<Callback<T>> = match self.entries.read() {
+            Ok(entries) => entries.iter().map(|(_, c)| c.clone()).collect(),
+            Err(_) => return,
+        };
+        for callback in callbacks {
+            callback(value);
+        }
+    }
+}
+
+/// Callbacks notified by a running `Sampler`, for fanning samples out inside
+/// an application without serializing them.
+///
+/// Callbacks run synchronously on the sampling thread, so they should return
+/// quickly. Subscribers can be added and removed at any time.
+pub struct Subscribers {
+    next_id: AtomicU64,
+    samples: List<Sample>,
+    events: List<Event>,
+    alerts: List<Alert>,
+}
+
+impl Default for Subscribers {
+    fn default() -> Self {
+        Subscribers::new()
+    }
+}
+
+impl Subscribers {
+    pub fn new() -> Self {
+        Subscribers {
+            next_id: AtomicU64::new(1),
+            samples: List::new(),
+            events: List::new(),
+            alerts: List::new(),
+        }
+    }
+
+    fn next_id(&self) -> SubscriptionId {
+        SubscriptionId(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Call `f` with every sample.
+    pub fn on_sample(&self, f: impl Fn(&Sample) + Send + Sync + 'static) -> SubscriptionId {
+        let id = self.next_id();
+        self.samples.add(id, Arc::new(f));
+        id
+    }
+
+    /// Call `f` when sampling times out or fails, or a setting is changed.
+    pub fn on_event(&self, f: impl Fn(&Event) + Send + Sync + 'static) -> SubscriptionId {
+        let id = self.next_id();
+        self.events.add(id, Arc::new(f));
+        id
+    }
+
+    /// Call `f` when a sample shows a condition that likely needs attention.
+    pub fn on_alert(&self, f: impl Fn(&Alert) + Send + Sync + 'static) -> SubscriptionId {
+        let id = self.next_id();
+        self.alerts.add(id, Arc::new(f));
+        id
+    }
+
+    /// Remove a subscriber. Returns whether it was registered.
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        self.samples.remove(id) || self.events.remove(id) || self.alerts.remove(id)
+    }
+
+    pub(crate) fn notify_sample(&self, sample: &Sample) {
+        self.samples.notify(sample);
+    }
+
+    pub(crate) fn notify_event(&self, event: &Event) {
+        self.events.notify(event);
+    }
+
+    pub(crate) fn wants_alerts(&self) -> bool {
+        !self.alerts.is_empty()
+    }
+
+    pub(crate) fn notify_alert(&self, alert: &Alert) {
+        self.alerts.notify(alert);
+    }
+}
+
+/// Raises alerts for counters that should never increase on a healthy GPU.
+#[derive(Default)]
+pub(crate) struct AlertDetector {
+    last: HashMap<MetricKey, f64>,
+}
+
+impl AlertDetector {
+    pub(crate) fn check(&mut self, metrics: &Metrics, mut raise: impl FnMut(Alert)) {
+        metrics.for_each(|key, value| {
+            let (severity, what) = if key.ends_with(".uncorrectedMemoryErrors") {
+                (Severity::Critical, "uncorrected ECC errors")
+            } else if key.ends_with(".xidErrors") {
+                (Severity::Critical, "critical XID errors")
+            } else if key.ends_with(".pcieReplays") {
+                (Severity::Warning, "PCIe replays")
+            } else {
+                return;
+            };
+            let Some(value) = value.as_f64() else {
+                return;
+            };
+            if let Some(last) = self.last.insert(key.clone(), value) {
+                if value > last {
+                    raise(Alert {
+                        severity,
+                        metric: key.to_string(),
+                        message: format!("{} {} since the previous sample", value - last, what),
+                    });
+                }
+            }
+        });
+    }
+}
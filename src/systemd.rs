@@ -0,0 +1,103 @@
+use std::env;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+type NotifySocket = (UnixDatagram, SocketAddr);
+/// sd_notify only exists on Linux; elsewhere the notifier is always disabled.
+#[cfg(not(target_os = "linux"))]
+type NotifySocket = std::convert::Infallible;
+
+/// Readiness and watchdog notifications for `Type=notify` systemd units.
+///
+/// All methods are no-ops when the agent is not started by systemd with
+/// `NOTIFY_SOCKET` set, so they can be called unconditionally.
+pub struct Notifier {
+    socket: Option<NotifySocket>,
+    watchdog_interval: Option<Duration>,
+    last_ping: Option<Instant>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        let socket = env::var("NOTIFY_SOCKET").ok().and_then(notify_socket);
+
+        // WATCHDOG_PID, if set, says which process the watchdog applies to
+        let watchdog_for_us = env::var("WATCHDOG_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_none_or(|pid| pid == std::process::id());
+        let watchdog_interval = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|_| watchdog_for_us)
+            // Ping at twice the required rate, as recommended by sd_watchdog_enabled(3)
+            .map(|usec| Duration::from_micros(usec / 2));
+
+        Notifier {
+            socket,
+            watchdog_interval,
+            last_ping: None,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn notify(&self, state: &str) -> io::Result<()> {
+        if let Some((socket, addr)) = &self.socket {
+            socket.send_to_addr(state.as_bytes(), addr)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn notify(&self, _state: &str) -> io::Result<()> {
+        match self.socket {
+            Some(never) => match never {},
+            None => Ok(()),
+        }
+    }
+
+    /// Tell systemd that startup finished.
+    pub fn ready(&self) -> io::Result<()> {
+        self.notify("READY=1")
+    }
+
+    /// Tell systemd that the agent is shutting down.
+    pub fn stopping(&self) -> io::Result<()> {
+        self.notify("STOPPING=1")
+    }
+
+    /// Send a watchdog keep-alive if one is due.
+    ///
+    /// Called from the sampling loop, so a hung loop stops the pings and lets
+    /// systemd restart the agent.
+    pub fn watchdog(&mut self) -> io::Result<()> {
+        let Some(interval) = self.watchdog_interval else {
+            return Ok(());
+        };
+        if self.last_ping.is_some_and(|last| last.elapsed() < interval) {
+            return Ok(());
+        }
+        self.last_ping = Some(Instant::now());
+        self.notify("WATCHDOG=1")
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn notify_socket(path: String) -> Option<NotifySocket> {
+    // Names starting with '@' live in the abstract namespace
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()).ok()?,
+        None => SocketAddr::from_pathname(&path).ok()?,
+    };
+    Some((UnixDatagram::unbound().ok()?, addr))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify_socket(_path: String) -> Option<NotifySocket> {
+    None
+}
@@ -0,0 +1,217 @@
+use crate::metrics::Metrics;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// A team that receives its own process-attributed metrics on separate sinks,
+/// configured in `tenants` of `--config`, e.g.
+/// `{"name": "ml", "namespaces": ["training"], "sinks": ["file:///var/log/symon/ml.jsonl"]}`.
+///
+/// The regular sinks keep receiving the full node-level stream.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tenant {
+    /// Added to the tenant's records as `_tenant`.
+    pub name: String,
+    /// Kubernetes namespaces whose pods belong to the tenant. Needs `--k8s`.
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+    /// Users whose processes belong to the tenant. Needs `--users` or `--processes`.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Processes of the tenant. Needs `--processes`; if this includes the
+    /// monitored process (`--pid`), the tenant also gets `gpu.process.*`.
+    #[serde(default)]
+    pub pids: Vec<u32>,
+    /// Sink specs the tenant's records are written to.
+    pub sinks: Vec<String>,
+}
+
+impl Tenant {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("tenant without a name".to_string());
+        }
+        // It names the tenant's spool directory
+        if self.name.contains(['/', '\\']) {
+            return Err(format!("tenant {}: names can't contain slashes", self.name));
+        }
+        if self.sinks.is_empty() {
+            return Err(format!("tenant {}: no sinks", self.name));
+        }
+        if self.namespaces.is_empty() && self.users.is_empty() && self.pids.is_empty() {
+            return Err(format!(
+                "tenant {}: expected namespaces, users or pids",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+
+    /// The tenant's share of a sample: its entries of `_gpu.N.pods`,
+    /// `_gpu.N.users` and `_gpu.N.processes`, and `gpu.process.*` if `pid`
+    /// (the monitored process) is one of its pids. Lists are kept, possibly
+    /// empty, wherever the sample has them, but samples with nothing of the
+    /// tenant's aren't shared, nor are other records.
+    pub fn record(&self, metrics: &Metrics, pid: i32) -> Option<Metrics> {
+        if metrics.get("_record").is_some() {
+            return None;
+        }
+        let time = metrics.time()?;
+        let mut record = Metrics::new();
+        record.add_metric("_tenant", self.name.as_str());
+        let gpu_count = metrics
+            .get("_gpu.count")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0);
+        record.add_metric("_gpu.count", gpu_count);
+
+        let mut found = false;
+        for i in 0..gpu_count {
+            // Processes of the tenant's pods belong to it, too
+            let mut pod_pids = HashSet::new();
+            let pods_key = format!("_gpu.{}.pods", i);
+            if let Some(pods) = metrics.get(&pods_key).and_then(|v| v.as_array()) {
+                let pods: Vec<Value> = pods
+                    .iter()
+                    .filter(|pod| matches(&self.namespaces, pod.get("namespace")))
+                    .cloned()
+                    .collect();
+                for pod in &pods {
+                    let pids = pod.get("pids").and_then(|v| v.as_array());
+                    pod_pids.extend(pids.into_iter().flatten().filter_map(|v| v.as_u64()));
+                }
+                found |= !pods.is_empty();
+                record.add_metric(pods_key, pods);
+            }
+
+            let users_key = format!("_gpu.{}.users", i);
+            if let Some(users) = metrics.get(&users_key).and_then(|v| v.as_array()) {
+                let users: Vec<Value> = users
+                    .iter()
+                    .filter(|user| matches(&self.users, user.get("user")))
+                    .cloned()
+                    .collect();
+                found |= !users.is_empty();
+                record.add_metric(users_key, users);
+            }
+
+            let processes_key = format!("_gpu.{}.processes", i);
+            if let Some(processes) = metrics.get(&processes_key).and_then(|v| v.as_array()) {
+                let processes: Vec<Value> = processes
+                    .iter()
+                    .filter(|process| {
+                        let pid = process.get("pid").and_then(|v| v.as_u64());
+                        pid.is_some_and(|pid| {
+                            pod_pids.contains(&pid) || self.pids.iter().any(|&p| p as u64 == pid)
+                        }) || matches(&self.users, process.get("user"))
+                    })
+                    .cloned()
+                    .collect();
+                found |= !processes.is_empty();
+                record.add_metric(processes_key, processes);
+            }
+        }
+
+        if pid > 0 && self.pids.contains(&(pid as u32)) {
+            metrics.for_each(|key, value| {
+                if key.starts_with("gpu.process.") {
+                    record.add_metric(key.clone(), value.clone());
+                    found = true;
+                }
+            });
+        }
+        if !found {
+            return None;
+        }
+        record.set_time(time);
+        Some(record)
+    }
+}
+
+/// Whether a name field is one of `names`.
+fn matches(names: &[String], field: Option<&Value>) -> bool {
+    field
+        .and_then(|v| v.as_str())
+        .is_some_and(|name| names.iter().any(|n| n == name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::SampleTime;
+    use serde_json::json;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn tenant() -> Tenant {
+        Tenant {
+            name: "ml".to_string(),
+            namespaces: vec!["training".to_string()],
+            users: vec!["alice".to_string()],
+            pids: vec![42],
+            sinks: vec!["stdout".to_string()],
+        }
+    }
+
+    fn sample() -> Metrics {
+        let mut metrics = Metrics::new();
+        metrics.add_metric("_gpu.count", 1u32);
+        metrics.add_metric(
+            "_gpu.0.pods",
+            json!([
+                {"namespace": "training", "pod": "a", "pids": [7]},
+                {"namespace": "serving", "pod": "b", "pids": [8]},
+            ]),
+        );
+        metrics.add_metric("_gpu.0.users", json!([{"user": "bob"}]));
+        metrics.add_metric(
+            "_gpu.0.processes",
+            json!([{"pid": 7, "user": "carol"}, {"pid": 8, "user": "dave"}]),
+        );
+        metrics.set_time(SampleTime {
+            wall: UNIX_EPOCH + Duration::from_secs(1000),
+            uptime: Duration::from_secs(1),
+        });
+        metrics
+    }
+
+    #[test]
+    fn shares_the_tenants_pods_users_and_processes() {
+        let record = tenant().record(&sample(), 0).expect("the tenant has a pod");
+        assert_eq!(record.get("_tenant"), Some(&json!("ml")));
+        let pods = record
+            .get("_gpu.0.pods")
+            .and_then(|v| v.as_array())
+            .unwrap();
+        assert_eq!(pods.len(), 1);
+        assert_eq!(record.get("_gpu.0.users"), Some(&json!([])));
+        // The process of the tenant's pod belongs to it
+        assert_eq!(
+            record.get("_gpu.0.processes"),
+            Some(&json!([{"pid": 7, "user": "carol"}]))
+        );
+    }
+
+    #[test]
+    fn skips_samples_without_a_share() {
+        let other = Tenant {
+            namespaces: vec!["inference".to_string()],
+            ..tenant()
+        };
+        assert!(other.record(&sample(), 0).is_none());
+        // Unless the monitored process is the tenant's
+        let mut metrics = sample();
+        metrics.add_metric("gpu.process.gpu", 50.0);
+        let record = other.record(&metrics, 42).expect("pid 42 is the tenant's");
+        assert_eq!(record.get("gpu.process.gpu"), Some(&json!(50.0)));
+    }
+
+    #[test]
+    fn rejects_names_with_slashes() {
+        let tenant = Tenant {
+            name: "../ml".to_string(),
+            ..tenant()
+        };
+        assert!(tenant.validate().is_err());
+    }
+}
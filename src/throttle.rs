@@ -0,0 +1,294 @@
+use crate::metrics::{Metrics, SampleTime};
+use crate::report::gpu_field;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Why a GPU's clocks are held down, derived from its throttle reasons.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThrottleCause {
+    /// The system asserted the power brake, e.g. a power supply in trouble.
+    PowerBrake,
+    /// The GPU or its memory is too hot.
+    Thermal,
+    /// The hardware slowed down for another reason, e.g. an external signal.
+    HardwareSlowdown,
+    /// Power draw reached the power limit.
+    PowerCap,
+    /// Clocks follow the slowest GPU of a sync boost group.
+    SyncBoost,
+}
+
+impl ThrottleCause {
+    /// Throttle reasons and the cause they indicate, most specific first: a
+    /// thermal slowdown also shows as `hwSlowdown`.
+    const REASONS: [(&'static str, ThrottleCause); 6] = [
+        ("hwPowerBrakeSlowdown", ThrottleCause::PowerBrake),
+        ("hwThermalSlowdown", ThrottleCause::Thermal),
+        ("swThermalSlowdown", ThrottleCause::Thermal),
+        ("hwSlowdown", ThrottleCause::HardwareSlowdown),
+        ("swPowerCap", ThrottleCause::PowerCap),
+        ("syncBoost", ThrottleCause::SyncBoost),
+    ];
+
+    /// The cause behind a set of throttle reasons, or `None` if clocks aren't
+    /// held down, e.g. only because the GPU is idle.
+    fn classify(reasons: &[Value]) -> Option<Self> {
+        Self::REASONS
+            .iter()
+            .find(|(name, _)| reasons.iter().any(|reason| reason.as_str() == Some(name)))
+            .map(|&(_, cause)| cause)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ThrottleCause::PowerBrake => "power brake",
+            ThrottleCause::Thermal => "thermally limited",
+            ThrottleCause::HardwareSlowdown => "hardware slowdown",
+            ThrottleCause::PowerCap => "power-capped",
+            ThrottleCause::SyncBoost => "sync boost",
+        }
+    }
+}
+
+/// What one sample says about a GPU.
+#[derive(Default)]
+struct Reading {
+    /// Whether the sample has throttle reasons at all.
+    classified: bool,
+    cause: Option<ThrottleCause>,
+    temp: Option<f64>,
+    power_watts: Option<f64>,
+    power_limit_watts: Option<f64>,
+    sm_clock: Option<f64>,
+}
+
+fn readings(metrics: &Metrics) -> BTreeMap<u32, Reading> {
+    let mut readings: BTreeMap<u32, Reading> = BTreeMap::new();
+    metrics.for_each(|key, value| {
+        let Some((index, field)) = gpu_field(key) else {
+            return;
+        };
+        match field {
+            "throttleReasons" => {
+                if let Some(reasons) = value.as_array() {
+                    let reading = readings.entry(index).or_default();
+                    reading.classified = true;
+                    reading.cause = ThrottleCause::classify(reasons);
+                }
+            }
+            "temp" => readings.entry(index).or_default().temp = value.as_f64(),
+            "powerWatts" => readings.entry(index).or_default().power_watts = value.as_f64(),
+            "enforcedPowerLimitWatts" => {
+                readings.entry(index).or_default().power_limit_watts = value.as_f64()
+            }
+            "smClock" => readings.entry(index).or_default().sm_clock = value.as_f64(),
+            _ => {}
+        }
+    });
+    readings
+}
+
+fn max(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// A stretch of samples with the same throttle cause.
+struct Episode {
+    cause: ThrottleCause,
+    start: f64,
+    end: f64,
+    max_temp: Option<f64>,
+    max_power_watts: Option<f64>,
+    power_limit_watts: Option<f64>,
+    min_sm_clock: Option<f64>,
+}
+
+impl Episode {
+    fn new(cause: ThrottleCause, timestamp: f64) -> Self {
+        Episode {
+            cause,
+            start: timestamp,
+            end: timestamp,
+            max_temp: None,
+            max_power_watts: None,
+            power_limit_watts: None,
+            min_sm_clock: None,
+        }
+    }
+
+    fn add(&mut self, timestamp: f64, reading: &Reading) {
+        self.end = timestamp;
+        self.max_temp = max(self.max_temp, reading.temp);
+        self.max_power_watts = max(self.max_power_watts, reading.power_watts);
+        self.power_limit_watts = reading.power_limit_watts.or(self.power_limit_watts);
+        self.min_sm_clock = match (self.min_sm_clock, reading.sm_clock) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+/// Classifies throttling from throttle reasons into causes, e.g.
+/// "power-capped" or "thermally limited", and writes a `throttle` event with
+/// the cause, duration, and the temperature, power and clocks during it, once
+/// each stretch of throttling for one cause ends.
+///
+/// Stretches shorter than `min_duration` aren't reported, so a GPU briefly
+/// touching its power limit doesn't flood the output.
+pub struct ThrottleClassifier {
+    min_duration: Duration,
+    episodes: BTreeMap<u32, Episode>,
+}
+
+impl ThrottleClassifier {
+    pub fn new(min_duration: Duration) -> Self {
+        ThrottleClassifier {
+            min_duration,
+            episodes: BTreeMap::new(),
+        }
+    }
+
+    /// Check a sample, returning an event record for each GPU whose throttling
+    /// for one cause just ended.
+    pub fn check(&mut self, metrics: &Metrics) -> Vec<Metrics> {
+        let (Some(timestamp), Some(time)) = (metrics.timestamp(), metrics.time()) else {
+            return Vec::new();
+        };
+        if metrics.get("_record").is_some() {
+            return Vec::new();
+        }
+
+        let mut events = Vec::new();
+        for (index, reading) in readings(metrics) {
+            // A sample missing throttle reasons neither starts nor ends throttling
+            if !reading.classified {
+                continue;
+            }
+            if let Some(episode) = self.episodes.get_mut(&index) {
+                if reading.cause == Some(episode.cause) {
+                    episode.add(timestamp, &reading);
+                    continue;
+                }
+                // The cause ended with this sample
+                episode.end = timestamp;
+            }
+            if let Some(episode) = self.episodes.remove(&index) {
+                events.extend(self.event(index, &episode, time));
+            }
+            if let Some(cause) = reading.cause {
+                let mut episode = Episode::new(cause, timestamp);
+                episode.add(timestamp, &reading);
+                self.episodes.insert(index, episode);
+            }
+        }
+        events
+    }
+
+    /// Event records for throttling still going on, e.g. when the agent stops.
+    pub fn finish(&mut self, time: SampleTime) -> Vec<Metrics> {
+        std::mem::take(&mut self.episodes)
+            .into_iter()
+            .filter_map(|(index, episode)| self.event(index, &episode, time))
+            .collect()
+    }
+
+    fn event(&self, gpu: u32, episode: &Episode, time: SampleTime) -> Option<Metrics> {
+        let seconds = episode.end - episode.start;
+        if seconds < self.min_duration.as_secs_f64() {
+            return None;
+        }
+        let mut record = Metrics::new();
+        record.add_metric("_record", "event");
+        record.add_metric("_event", "throttle");
+        record.add_metric("gpu", gpu);
+        record.add_metric("cause", episode.cause.as_str());
+        record.add_metric("startTimestamp", episode.start);
+        record.add_metric("durationSeconds", seconds);
+        if let Some(temp) = episode.max_temp {
+            record.add_metric("maxTemp", temp);
+        }
+        if let Some(watts) = episode.max_power_watts {
+            record.add_metric("maxPowerWatts", watts);
+        }
+        if let Some(watts) = episode.power_limit_watts {
+            record.add_metric("powerLimitWatts", watts);
+        }
+        if let Some(mhz) = episode.min_sm_clock {
+            record.add_metric("minSmClock", mhz);
+        }
+        record.set_time(time);
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::UNIX_EPOCH;
+
+    fn at(secs: u64) -> SampleTime {
+        SampleTime {
+            wall: UNIX_EPOCH + Duration::from_secs(1000 + secs),
+            uptime: Duration::from_secs(secs),
+        }
+    }
+
+    fn sample(secs: u64, reasons: Value, power_watts: f64) -> Metrics {
+        let mut metrics = Metrics::new();
+        metrics.add_metric("_gpu.0.throttleReasons", reasons);
+        metrics.add_metric("gpu.0.powerWatts", power_watts);
+        metrics.set_time(at(secs));
+        metrics
+    }
+
+    #[test]
+    fn reports_throttling_once_it_ends() {
+        let mut classifier = ThrottleClassifier::new(Duration::from_secs(2));
+        for (secs, watts) in [(0, 390.0), (1, 400.0), (2, 395.0)] {
+            let capped = sample(secs, json!(["swPowerCap"]), watts);
+            assert!(classifier.check(&capped).is_empty());
+        }
+        // Idle isn't throttling
+        let events = classifier.check(&sample(3, json!(["gpuIdle"]), 80.0));
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.get("cause"), Some(&json!("power-capped")));
+        assert_eq!(event.get("durationSeconds"), Some(&json!(3.0)));
+        assert_eq!(event.get("maxPowerWatts"), Some(&json!(400.0)));
+    }
+
+    #[test]
+    fn skips_short_stretches_and_samples_without_reasons() {
+        let mut classifier = ThrottleClassifier::new(Duration::from_secs(5));
+        classifier.check(&sample(
+            0,
+            json!(["hwSlowdown", "hwThermalSlowdown"]),
+            300.0,
+        ));
+        let mut unknown = Metrics::new();
+        unknown.add_metric("gpu.0.powerWatts", 300.0);
+        unknown.set_time(at(1));
+        assert!(classifier.check(&unknown).is_empty());
+        assert!(classifier.check(&sample(2, json!([]), 300.0)).is_empty());
+        assert!(classifier.finish(at(3)).is_empty());
+    }
+
+    #[test]
+    fn classifies_by_the_most_specific_reason() {
+        let cause = |reasons: Value| ThrottleCause::classify(reasons.as_array().unwrap());
+        assert_eq!(
+            cause(json!(["hwSlowdown", "hwThermalSlowdown"])),
+            Some(ThrottleCause::Thermal)
+        );
+        assert_eq!(
+            cause(json!(["swPowerCap", "hwPowerBrakeSlowdown"])),
+            Some(ThrottleCause::PowerBrake)
+        );
+        assert_eq!(cause(json!(["gpuIdle"])), None);
+    }
+}
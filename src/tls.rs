@@ -0,0 +1,267 @@
+use rustls::client::ClientConnection;
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::{ServerConnection, WebPkiClientVerifier};
+use rustls::{ClientConfig, RootCertStore, ServerConfig, StreamOwned};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Certificates used by network sinks for `tcps://` and `https://`.
+#[derive(Clone, Debug, Default)]
+pub struct ClientOptions {
+    /// PEM bundle of CAs trusted to sign collector certificates, instead of the
+    /// public web roots.
+    pub ca: Option<PathBuf>,
+    /// PEM certificate chain presented to collectors that require client
+    /// certificates; requires `key`.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+/// Certificates of a TLS listener.
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Require clients to present a certificate signed by one of these CAs.
+    pub client_ca: Option<PathBuf>,
+}
+
+/// TLS settings of a client, loaded once and shared by its connections.
+#[derive(Clone)]
+pub struct Connector {
+    config: Arc<ClientConfig>,
+}
+
+impl Connector {
+    pub fn new(options: &ClientOptions) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        match &options.ca {
+            Some(ca) => {
+                for cert in load_certs(ca)? {
+                    roots.add(cert).map_err(|e| invalid(ca, e))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_root_certificates(roots);
+        let config = match (&options.cert, &options.key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+                .map_err(|e| invalid(cert, e))?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a client certificate requires both a certificate and a key",
+                ))
+            }
+        };
+        Ok(Connector {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Start a TLS session over `stream` to `addr` (`host:port`), verifying
+    /// the server's certificate against the host name. The handshake completes
+    /// on the first read or write.
+    pub fn connect(&self, addr: &str, stream: TcpStream) -> io::Result<Stream> {
+        let host = match addr.rsplit_once(':') {
+            Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => addr,
+        };
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let connection =
+            ClientConnection::new(self.config.clone(), name).map_err(io::Error::other)?;
+        Ok(Stream::Client(Box::new(StreamOwned::new(
+            connection, stream,
+        ))))
+    }
+}
+
+/// TLS settings of a listener.
+#[derive(Clone)]
+pub struct Acceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl Acceptor {
+    pub fn new(options: &ServerOptions) -> io::Result<Self> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?;
+        let builder = match &options.client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca)? {
+                    roots.add(cert).map_err(|e| invalid(ca, e))?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider())
+                        .build()
+                        .map_err(|e| invalid(ca, e))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(load_certs(&options.cert)?, load_key(&options.key)?)
+            .map_err(|e| invalid(&options.cert, e))?;
+        Ok(Acceptor {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Start a TLS session with a client that connected to the listener.
+    pub fn accept(&self, stream: TcpStream) -> io::Result<Stream> {
+        let connection = ServerConnection::new(self.config.clone()).map_err(io::Error::other)?;
+        Ok(Stream::Server(Box::new(StreamOwned::new(
+            connection, stream,
+        ))))
+    }
+}
+
+/// A TCP connection, with or without TLS.
+pub enum Stream {
+    Plain(TcpStream),
+    Client(Box<StreamOwned<ClientConnection, TcpStream>>),
+    Server(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// Tell the peer no more data follows, so it can tell a complete response
+    /// from a truncated one.
+    pub fn close(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(_) => return Ok(()),
+            Stream::Client(stream) => stream.conn.send_close_notify(),
+            Stream::Server(stream) => stream.conn.send_close_notify(),
+        }
+        self.flush()
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Client(stream) => stream.read(buf),
+            Stream::Server(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Client(stream) => stream.write(buf),
+            Stream::Server(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Client(stream) => stream.flush(),
+            Stream::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Connect to `authority` (`host[:port]`, port 443 with `tls` and 80 without),
+/// trying each resolved address in turn.
+pub fn connect(
+    authority: &str,
+    tls: Option<&Connector>,
+    connect_timeout: Duration,
+    io_timeout: Duration,
+) -> io::Result<Stream> {
+    // An IPv6 address without a port ends in `]`
+    let addr = if authority
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.ends_with(']'))
+    {
+        authority.to_string()
+    } else {
+        let port = if tls.is_some() { 443 } else { 80 };
+        format!("{}:{}", authority, port)
+    };
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses resolved");
+    for socket_addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_addr, connect_timeout) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(io_timeout))?;
+                stream.set_read_timeout(Some(io_timeout))?;
+                return match tls {
+                    Some(tls) => tls.connect(&addr, stream),
+                    None => Ok(Stream::Plain(stream)),
+                };
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// Read a bearer token from a file, so it doesn't show up in the process list.
+pub fn read_token(path: &Path) -> io::Result<String> {
+    let token = fs::read_to_string(path)?.trim().to_string();
+    if token.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: empty token", path.display()),
+        ));
+    }
+    Ok(token)
+}
+
+/// Check an `Authorization` header value against the expected bearer token,
+/// in time independent of where they differ.
+pub fn is_authorized(header: Option<&str>, token: &str) -> bool {
+    let Some(given) = header.and_then(|h| h.trim().strip_prefix("Bearer ")) else {
+        return false;
+    };
+    let given = given.trim().as_bytes();
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// ring is the only provider compiled in; passing it explicitly keeps
+/// configurations independent of the process-wide default.
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| invalid(path, e))?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| invalid(path, e))?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| invalid(path, "no private key found"))
+}
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e),
+    )
+}
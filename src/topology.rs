@@ -0,0 +1,163 @@
+use crate::metrics::Metrics;
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::{Device, Nvml};
+use serde::Serialize;
+
+/// Upper bound on NVLinks per device (`NVML_NVLINK_MAX_LINKS`).
+const MAX_NVLINKS: u32 = 18;
+
+/// How GPUs are connected to each other and to the host, like
+/// `nvidia-smi topo -m`.
+#[derive(Debug, Serialize)]
+pub struct Topology {
+    pub gpus: Vec<GpuTopology>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GpuTopology {
+    pub index: u32,
+    /// PCI bus ID, e.g. `00000000:3B:00.0`.
+    pub bus_id: Option<String>,
+    /// NUMA node the GPU is attached to.
+    pub numa_node: Option<u32>,
+    /// CPUs with ideal affinity to the GPU, as a CPU list such as `0-23,48-71`.
+    pub cpu_affinity: Option<String>,
+    /// Connection to each GPU by index, in `nvidia-smi topo -m` terms:
+    /// `X` (itself), `NV<n>` (n NVLinks), `PIX` (a single PCIe switch),
+    /// `PXB` (multiple PCIe switches), `PHB` (a PCIe host bridge),
+    /// `NODE` (host bridges within a NUMA node) or `SYS` (across NUMA nodes).
+    pub links: Vec<String>,
+}
+
+impl Topology {
+    /// Query the topology of all devices.
+    pub fn discover(nvml: &Nvml) -> Result<Self, NvmlError> {
+        let device_count = nvml.device_count()?;
+        let bus_ids: Vec<Option<String>> = (0..device_count)
+            .map(|di| {
+                let device = nvml.device_by_index(di).ok()?;
+                device.pci_info().ok().map(|pci| pci.bus_id)
+            })
+            .collect();
+
+        let mut gpus = Vec::with_capacity(device_count as usize);
+        for di in 0..device_count {
+            let device = nvml.device_by_index(di)?;
+            let nvlinks = nvlink_counts(&device, &bus_ids);
+            let links = (0..device_count)
+                .map(|other| {
+                    if other == di {
+                        "X".to_string()
+                    } else if nvlinks[other as usize] > 0 {
+                        format!("NV{}", nvlinks[other as usize])
+                    } else {
+                        pcie_link(nvml, &device, other)
+                    }
+                })
+                .collect();
+            let bus_id = bus_ids[di as usize].clone();
+            gpus.push(GpuTopology {
+                index: di,
+                numa_node: bus_id.as_deref().and_then(numa_node),
+                cpu_affinity: cpu_affinity(&device),
+                bus_id,
+                links,
+            });
+        }
+        Ok(Topology { gpus })
+    }
+
+    /// A one-off record carrying the topology under `_topology`.
+    pub fn to_metrics(&self) -> Result<Metrics, serde_json::Error> {
+        let mut metrics = Metrics::new();
+        metrics.add_metric("_record", "topology");
+        metrics.add_metric("_topology", serde_json::to_value(self)?);
+        Ok(metrics)
+    }
+}
+
+/// Number of active NVLinks from `device` to each GPU, by index.
+fn nvlink_counts(device: &Device, bus_ids: &[Option<String>]) -> Vec<u32> {
+    let mut counts = vec![0; bus_ids.len()];
+    for link in 0..MAX_NVLINKS {
+        let link = device.link_wrapper_for(link);
+        if !link.is_active().unwrap_or(false) {
+            continue;
+        }
+        let Ok(remote) = link.remote_pci_info() else {
+            continue;
+        };
+        if let Some(other) = bus_ids
+            .iter()
+            .position(|bus_id| bus_id.as_deref() == Some(remote.bus_id.as_str()))
+        {
+            counts[other] += 1;
+        }
+    }
+    counts
+}
+
+#[cfg(target_os = "linux")]
+fn pcie_link(nvml: &Nvml, device: &Device, other: u32) -> String {
+    use nvml_wrapper::enum_wrappers::device::TopologyLevel;
+
+    let level = nvml
+        .device_by_index(other)
+        .and_then(|other| device.topology_common_ancestor(other));
+    match level {
+        Ok(TopologyLevel::Internal | TopologyLevel::Single) => "PIX",
+        Ok(TopologyLevel::Multiple) => "PXB",
+        Ok(TopologyLevel::HostBridge) => "PHB",
+        Ok(TopologyLevel::Node) => "NODE",
+        Ok(TopologyLevel::System) | Err(_) => "SYS",
+    }
+    .to_string()
+}
+
+/// NVML only reports PCIe topology on Linux.
+#[cfg(not(target_os = "linux"))]
+fn pcie_link(_nvml: &Nvml, _device: &Device, _other: u32) -> String {
+    "SYS".to_string()
+}
+
+#[cfg(target_os = "linux")]
+fn numa_node(bus_id: &str) -> Option<u32> {
+    // NVML pads the PCI domain to 8 digits; sysfs uses 4 and lower case
+    let sysfs_id = bus_id.get(bus_id.len().checked_sub(12)?..)?.to_lowercase();
+    let node =
+        std::fs::read_to_string(format!("/sys/bus/pci/devices/{}/numa_node", sysfs_id)).ok()?;
+    // -1 means the platform doesn't report a node
+    node.trim().parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn numa_node(_bus_id: &str) -> Option<u32> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn cpu_affinity(device: &Device) -> Option<String> {
+    // Enough words for 1024 CPUs
+    const WORDS: usize = 1024 / std::os::raw::c_ulong::BITS as usize;
+
+    let masks = device.cpu_affinity(WORDS).ok()?;
+    let bits = std::os::raw::c_ulong::BITS as usize;
+    let cpus: Vec<usize> = masks
+        .iter()
+        .enumerate()
+        .flat_map(|(word, &mask)| {
+            (0..bits)
+                .filter(move |bit| mask & (1 << bit) != 0)
+                .map(move |bit| word * bits + bit)
+        })
+        .collect();
+    if cpus.is_empty() {
+        return None;
+    }
+    Some(crate::limits::format_cpu_list(&cpus))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_affinity(_device: &Device) -> Option<String> {
+    None
+}
@@ -0,0 +1,177 @@
+use crate::encoding::{self, Encoding};
+use crate::metrics::Metrics;
+use crate::units::UnitSystem;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// `file`, decompressed if it's gzip or zstd compressed.
+#[cfg(feature = "compression")]
+fn decompressed(mut file: BufReader<File>) -> io::Result<Box<dyn BufRead>> {
+    let magic = file.fill_buf()?;
+    Ok(if magic.starts_with(GZIP_MAGIC) {
+        Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(file)))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Box::new(BufReader::new(zstd::Decoder::with_buffer(file)?))
+    } else {
+        Box::new(file)
+    })
+}
+
+/// `file`, which must not be compressed without the `compression` feature.
+#[cfg(not(feature = "compression"))]
+fn decompressed(mut file: BufReader<File>) -> io::Result<Box<dyn BufRead>> {
+    let magic = file.fill_buf()?;
+    if magic.starts_with(GZIP_MAGIC) || magic.starts_with(ZSTD_MAGIC) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "reading compressed traces requires the `compression` feature",
+        ));
+    }
+    Ok(Box::new(file))
+}
+
+/// Reads samples from a recorded trace, i.e. a file written by a file sink.
+///
+/// Rotated files compressed with gzip or zstd are decompressed transparently,
+/// and files written with `--encoding msgpack`, `cbor` or `protobuf` are told
+/// apart from JSON lines by their first bytes. Lines that are not valid
+/// samples are skipped and counted; binary samples can't be resynchronized,
+/// so a malformed one, e.g. truncated by a crash, ends the trace. Samples
+/// written with `--units` are converted back to the driver's units.
+pub struct TraceReader {
+    reader: Box<dyn BufRead>,
+    encoding: Encoding,
+    line: Vec<u8>,
+    skipped: usize,
+}
+
+impl TraceReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut reader = decompressed(BufReader::new(File::open(path)?))?;
+        let head = reader.fill_buf()?;
+        let encoding = match head.first() {
+            _ if is_protobuf(head) => Encoding::Protobuf,
+            // Map headers: fixmap, map 16 and map 32
+            Some(0x80..=0x8f | 0xde | 0xdf) => Encoding::Msgpack,
+            // Maps with a length in the head or in the next 1 to 8 bytes
+            Some(0xa0..=0xbb) => Encoding::Cbor,
+            _ => Encoding::Json,
+        };
+        Ok(TraceReader {
+            reader,
+            encoding,
+            line: Vec::new(),
+            skipped: 0,
+        })
+    }
+
+    /// Number of lines or samples that could not be parsed so far.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    fn next_line(&mut self) -> Option<io::Result<Metrics>> {
+        loop {
+            self.line.clear();
+            match self.reader.read_until(b'\n', &mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            if self.line.trim_ascii().is_empty() {
+                continue;
+            }
+            match Metrics::from_json(&self.line) {
+                Ok(mut metrics) => {
+                    UnitSystem::to_raw(&mut metrics);
+                    return Some(Ok(metrics));
+                }
+                // A truncated last line is expected if the agent was killed mid-write
+                Err(_) => self.skipped += 1,
+            }
+        }
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = io::Result<Metrics>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let binary = match self.encoding {
+            Encoding::Msgpack => encoding::read_msgpack(&mut self.reader),
+            Encoding::Cbor => encoding::read_cbor(&mut self.reader),
+            Encoding::Protobuf => encoding::read_protobuf(&mut self.reader),
+            _ => return self.next_line(),
+        };
+        match binary {
+            Ok(metrics) => metrics.map(|mut metrics| {
+                UnitSystem::to_raw(&mut metrics);
+                Ok(metrics)
+            }),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                // Nothing after a malformed sample can be trusted
+                self.skipped += 1;
+                self.reader = Box::new(io::empty());
+                None
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Whether a trace starts with a length-delimited `Sample`: a varint length,
+/// then the tag of `schema_version`, which is never 0 and so always written
+/// first. No JSON, MessagePack or CBOR sample starts like that.
+fn is_protobuf(head: &[u8]) -> bool {
+    let Some(end) = head.iter().take(10).position(|b| b & 0x80 == 0) else {
+        return false;
+    };
+    head.get(end + 1) == Some(&0x08)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn reads_every_encoding() {
+        let mut sample = Metrics::new();
+        sample.add_metric("_timestamp", 1700000000.5);
+        sample.add_metric("gpu.0.temp", 60);
+        let path = std::env::temp_dir().join(format!("symon-trace-{}", std::process::id()));
+        for encoding in [
+            Encoding::Json,
+            Encoding::Msgpack,
+            Encoding::Cbor,
+            Encoding::Protobuf,
+        ] {
+            let mut file = File::create(&path).unwrap();
+            let mut buf = Vec::new();
+            for _ in 0..3 {
+                encoding.encode(&sample, &mut buf).unwrap();
+                file.write_all(&buf).unwrap();
+            }
+            // Cut off the middle of the last sample, as a crash would
+            file.set_len(2 * buf.len() as u64 + 3).unwrap();
+            drop(file);
+            let mut reader = TraceReader::open(&path).unwrap();
+            let temps: Vec<_> = reader
+                .by_ref()
+                .map(|metrics| metrics.unwrap().get("gpu.0.temp").cloned())
+                .collect();
+            assert_eq!(temps, [Some(60.into()), Some(60.into())], "{:?}", encoding);
+            assert_eq!(reader.skipped(), 1, "{:?}", encoding);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
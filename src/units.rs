@@ -0,0 +1,253 @@
+use crate::metrics::Metrics;
+use crate::query;
+use std::time::Duration;
+
+/// Parse a human-readable byte size such as `512`, `64KiB`, `100MB` or `2GiB`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size: {:?}", s))?;
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "ki" | "kib" => 1 << 10,
+        "mi" | "mib" => 1 << 20,
+        "gi" | "gib" => 1 << 30,
+        "ti" | "tib" => 1 << 40,
+        _ => return Err(format!("invalid size unit: {:?}", unit)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parse a human-readable duration such as `500ms`, `30s`, `10m`, `2h` or `1d`.
+///
+/// A bare number is interpreted as seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {:?}", s))?;
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        "d" => number * 86400.0,
+        _ => return Err(format!("invalid duration unit: {:?}", unit)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("invalid duration: {:?}", s))
+}
+
+/// Parse a positive number of seconds that fits in a `Duration`, e.g. `0.5`.
+pub fn parse_seconds(s: &str) -> Result<f64, String> {
+    let seconds: f64 = s
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid number of seconds: {:?}", s))?;
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(duration) if !duration.is_zero() => Ok(seconds),
+        _ => Err(format!("expected a positive number of seconds: {:?}", s)),
+    }
+}
+
+/// Parse a positive percentage such as `0.5%` or `0.5`.
+pub fn parse_percent(s: &str) -> Result<f64, String> {
+    let number = s.trim().strip_suffix('%').unwrap_or(s.trim());
+    match number.trim().parse::<f64>() {
+        Ok(percent) if percent > 0.0 && percent.is_finite() => Ok(percent),
+        _ => Err(format!("expected a positive percentage: {:?}", s)),
+    }
+}
+
+/// Parse a positive power such as `250`, `250W` or `0.3kW`, in Watts.
+pub fn parse_watts(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid power: {:?}", s))?;
+    let watts = match unit.trim().to_lowercase().as_str() {
+        "" | "w" => number,
+        "kw" => number * 1000.0,
+        _ => return Err(format!("invalid power unit: {:?}", unit)),
+    };
+    if watts > 0.0 && watts.is_finite() {
+        Ok(watts)
+    } else {
+        Err(format!("expected a positive power: {:?}", s))
+    }
+}
+
+/// Units of memory and clock metrics in written samples.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum UnitSystem {
+    /// As read from the driver: memory in bytes, clocks in MHz, power in Watts.
+    #[default]
+    Raw,
+    /// Memory in GB (10^9 bytes), clocks in GHz, power in Watts.
+    Si,
+    /// Memory in GiB (2^30 bytes), clocks in GHz, power in Watts.
+    Binary,
+}
+
+impl UnitSystem {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UnitSystem::Raw => "raw",
+            UnitSystem::Si => "si",
+            UnitSystem::Binary => "binary",
+        }
+    }
+
+    /// The unit system named by a sample's `_units`; `Raw` without it.
+    pub fn of(metrics: &Metrics) -> Self {
+        match metrics.get("_units").and_then(|units| units.as_str()) {
+            Some("si") => UnitSystem::Si,
+            Some("binary") => UnitSystem::Binary,
+            _ => UnitSystem::Raw,
+        }
+    }
+
+    /// The factor converting a metric from the driver's unit to this unit
+    /// system, or `None` if it stays as it is. Keys never change, so readers
+    /// go by `_units` to tell which unit a value is in.
+    pub fn scale(self, key: &str) -> Option<f64> {
+        let memory = match self {
+            UnitSystem::Raw => return None,
+            UnitSystem::Si => 1e-9,
+            UnitSystem::Binary => 1.0 / (1u64 << 30) as f64,
+        };
+        let (_, quantity) = CONVERTED
+            .iter()
+            .find(|(pattern, _)| query::glob_match(pattern, key))?;
+        Some(match quantity {
+            Quantity::Memory => memory,
+            Quantity::Clock => 1e-3,
+        })
+    }
+
+    /// Convert a sample written with `--units` back to the driver's units and
+    /// drop its `_units`, so traces compare whatever they were written with.
+    pub fn to_raw(metrics: &mut Metrics) {
+        let units = UnitSystem::of(metrics);
+        if units == UnitSystem::Raw {
+            return;
+        }
+        let mut converted = Vec::new();
+        metrics.for_each(|key, value| {
+            if let (Some(number), Some(scale)) = (value.as_f64(), units.scale(key)) {
+                // Raw bytes and MHz are whole numbers
+                converted.push((key.clone(), (number / scale).round() as u64));
+            }
+        });
+        for (key, value) in converted {
+            metrics.add_metric(key, value);
+        }
+        metrics.retain(|key, _| key.as_ref() != "_units");
+    }
+}
+
+/// What a converted metric measures.
+enum Quantity {
+    /// Bytes, to GB or GiB.
+    Memory,
+    /// MHz, to GHz.
+    Clock,
+}
+
+/// Metrics converted by `--units`, with `*` in place of an index.
+const CONVERTED: &[(&str, Quantity)] = &[
+    ("_gpu.*.memoryTotal", Quantity::Memory),
+    ("gpu.*.memoryAllocatedBytes", Quantity::Memory),
+    ("node.gpu.totalMemoryUsedBytes", Quantity::Memory),
+    ("gpu.process.*.memoryAllocatedBytes", Quantity::Memory),
+    ("gpu.process.*.accountingMaxMemoryBytes", Quantity::Memory),
+    ("_agent.rssBytes", Quantity::Memory),
+    ("_gpu.*.smClock", Quantity::Clock),
+    ("_gpu.*.memoryClock", Quantity::Clock),
+    ("_gpu.*.graphicsClock", Quantity::Clock),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_only_listed_metrics() {
+        assert_eq!(
+            UnitSystem::Si.scale("gpu.3.memoryAllocatedBytes"),
+            Some(1e-9)
+        );
+        assert_eq!(UnitSystem::Binary.scale("_gpu.0.smClock"), Some(1e-3));
+        assert_eq!(UnitSystem::Raw.scale("_gpu.0.memoryTotal"), None);
+        assert_eq!(UnitSystem::Si.scale("gpu.0.memory"), None);
+        assert_eq!(UnitSystem::Si.scale("_agent.sink.0.pendingBytes"), None);
+        assert_eq!(
+            UnitSystem::Si.scale("_gpu.0.memoryBandwidthEstimateBytesPerSecond"),
+            None
+        );
+    }
+
+    #[test]
+    fn converts_back_to_raw() {
+        let mut metrics = Metrics::new();
+        metrics.add_metric("_units", "binary");
+        metrics.add_metric("gpu.0.memoryAllocatedBytes", 1.5);
+        metrics.add_metric("_gpu.0.smClock", 1.41);
+        metrics.add_metric("gpu.0.temp", 60);
+        UnitSystem::to_raw(&mut metrics);
+        assert_eq!(metrics.get("_units"), None);
+        assert_eq!(
+            metrics.get("gpu.0.memoryAllocatedBytes"),
+            Some(&(3u64 << 29).into())
+        );
+        assert_eq!(metrics.get("_gpu.0.smClock"), Some(&1410.into()));
+        assert_eq!(metrics.get("gpu.0.temp"), Some(&60.into()));
+    }
+
+    #[test]
+    fn parses_sizes_in_decimal_and_binary_units() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64KiB"), Ok(64 << 10));
+        assert_eq!(parse_size("100 MB"), Ok(100_000_000));
+        assert_eq!(parse_size("1.5g"), Ok(1_500_000_000));
+        assert!(parse_size("10 parsecs").is_err());
+        assert!(parse_size("MB").is_err());
+    }
+
+    #[test]
+    fn parses_durations_with_units() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration(" 1d "), Ok(Duration::from_secs(86400)));
+        for invalid in ["", "1w", "h", "-1s"] {
+            assert!(parse_duration(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_percentages_with_or_without_a_sign() {
+        assert_eq!(parse_percent("0.5%"), Ok(0.5));
+        assert_eq!(parse_percent(" 2 % "), Ok(2.0));
+        assert_eq!(parse_percent("10"), Ok(10.0));
+        for invalid in ["0%", "-1%", "%", "inf", "NaN", "half"] {
+            assert!(parse_percent(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use crate::error;
+use crate::gpu_nvidia::{NvidiaGpu, SampleOptions};
+use crate::log;
+use crate::metrics::Metrics;
+use crate::otel::{self, SpanContext};
+use nvml_wrapper::error::NvmlError;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Arbitrary work to run on the NVML thread; it sends its own result back.
+type Call = Box<dyn FnOnce(&NvidiaGpu) + Send>;
+
+enum Request {
+    Sample {
+        seq: u64,
+        options: SampleOptions,
+        metrics: Metrics,
+        /// The span the sample was requested in, to nest NVML spans under.
+        context: Option<SpanContext>,
+    },
+    Call(Call),
+    Shutdown,
+}
+
+enum Response {
+    Ready(Result<(), NvmlError>),
+    Sampled {
+        seq: u64,
+        metrics: Metrics,
+        result: Result<(), NvmlError>,
+    },
+    ShutDown(Result<(), NvmlError>),
+}
+
+/// Errors produced by guarded NVML calls.
+#[derive(Debug, thiserror::Error)]
+pub enum WatchdogError {
+    /// NVML returned an error.
+    #[error("NVML error: {0}")]
+    Nvml(#[source] NvmlError),
+    /// NVML did not respond within the configured timeout.
+    #[error("NVML did not respond within {0:?}")]
+    TimedOut(Duration),
+    /// The sampling thread exited unexpectedly (e.g. it panicked).
+    #[error("NVML sampling thread exited unexpectedly")]
+    WorkerGone,
+}
+
+impl WatchdogError {
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, WatchdogError::TimedOut(_))
+    }
+}
+
+struct Worker {
+    requests: Sender<Request>,
+    responses: Receiver<Response>,
+}
+
+impl Worker {
+    /// Spawn a thread that owns the NVML handle and wait for it to initialize.
+    fn spawn(timeout: Duration) -> Result<Self, WatchdogError> {
+        let (request_tx, request_rx) = mpsc::channel::<Request>();
+        let (response_tx, response_rx) = mpsc::channel::<Response>();
+
+        thread::Builder::new()
+            .name("nvml".to_string())
+            .spawn(move || {
+                let nvidia_gpu = match NvidiaGpu::new() {
+                    Ok(nvidia_gpu) => {
+                        let _ = response_tx.send(Response::Ready(Ok(())));
+                        nvidia_gpu
+                    }
+                    Err(e) => {
+                        let _ = response_tx.send(Response::Ready(Err(e)));
+                        return;
+                    }
+                };
+
+                for request in request_rx {
+                    match request {
+                        Request::Sample {
+                            seq,
+                            options,
+                            mut metrics,
+                            context,
+                        } => {
+                            let _scope = otel::enter(context);
+                            let mut span = otel::Span::start("nvml.sample");
+                            let result = nvidia_gpu.sample_with(&mut metrics, &options);
+                            if let Err(e) = &result {
+                                span.set_error(e);
+                            }
+                            drop(span);
+                            let response = Response::Sampled {
+                                seq,
+                                metrics,
+                                result,
+                            };
+                            if response_tx.send(response).is_err() {
+                                // The watchdog gave up on this worker
+                                return;
+                            }
+                        }
+                        Request::Call(call) => call(&nvidia_gpu),
+                        Request::Shutdown => break,
+                    }
+                }
+
+                let _ = response_tx.send(Response::ShutDown(nvidia_gpu.shutdown()));
+            })
+            .map_err(|_| WatchdogError::WorkerGone)?;
+
+        match response_rx.recv_timeout(timeout) {
+            Ok(Response::Ready(Ok(()))) => Ok(Worker {
+                requests: request_tx,
+                responses: response_rx,
+            }),
+            Ok(Response::Ready(Err(e))) => Err(WatchdogError::Nvml(e)),
+            Ok(_) => Err(WatchdogError::WorkerGone),
+            Err(RecvTimeoutError::Timeout) => Err(WatchdogError::TimedOut(timeout)),
+            Err(RecvTimeoutError::Disconnected) => Err(WatchdogError::WorkerGone),
+        }
+    }
+}
+
+/// Runs NVML calls on a dedicated thread and bounds how long the caller waits.
+///
+/// A hung driver (e.g. during an XID storm) can block NVML calls indefinitely.
+/// The watchdog makes sure the main loop keeps its cadence: a sample that does
+/// not complete within `timeout` is reported as timed out, and after
+/// `max_consecutive_timeouts` timeouts in a row the stuck thread is abandoned
+/// and NVML is re-initialized on a fresh one.
+pub struct SamplingWatchdog {
+    worker: Worker,
+    timeout: Duration,
+    max_consecutive_timeouts: u32,
+    consecutive_timeouts: u32,
+    seq: u64,
+    in_flight: Option<u64>,
+}
+
+impl SamplingWatchdog {
+    pub fn start(timeout: Duration, max_consecutive_timeouts: u32) -> Result<Self, WatchdogError> {
+        Ok(SamplingWatchdog {
+            worker: Worker::spawn(timeout)?,
+            timeout,
+            max_consecutive_timeouts,
+            consecutive_timeouts: 0,
+            seq: 0,
+            in_flight: None,
+        })
+    }
+
+    /// Sample GPU metrics into `metrics`, giving up after the configured timeout.
+    ///
+    /// If a previous sample is still stuck in the driver, no new request is queued
+    /// behind it; the call waits for the stale sample instead and times out if it
+    /// does not return either.
+    pub fn sample(
+        &mut self,
+        metrics: &mut Metrics,
+        options: &SampleOptions,
+    ) -> Result<(), WatchdogError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut dispatched = None;
+
+        loop {
+            if self.in_flight.is_none() {
+                self.seq += 1;
+                let request = Request::Sample {
+                    seq: self.seq,
+                    options: options.clone(),
+                    metrics: std::mem::take(metrics),
+                    context: otel::current(),
+                };
+                if self.worker.requests.send(request).is_err() {
+                    return self.on_worker_gone();
+                }
+                self.in_flight = Some(self.seq);
+                dispatched = Some(self.seq);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.worker.responses.recv_timeout(remaining) {
+                Ok(Response::Sampled {
+                    seq,
+                    metrics: sampled,
+                    result,
+                }) => {
+                    self.in_flight = None;
+                    self.consecutive_timeouts = 0;
+                    if Some(seq) == dispatched {
+                        *metrics = sampled;
+                        return result.map_err(WatchdogError::Nvml);
+                    }
+                    // A late response to a sample that already timed out; drop it
+                    // and dispatch a fresh request.
+                }
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.consecutive_timeouts += 1;
+                    if self.max_consecutive_timeouts > 0
+                        && self.consecutive_timeouts >= self.max_consecutive_timeouts
+                    {
+                        let reason = format!(
+                            "NVML unresponsive after {} consecutive timeouts",
+                            self.consecutive_timeouts
+                        );
+                        self.recover(&reason);
+                    }
+                    return Err(WatchdogError::TimedOut(self.timeout));
+                }
+                Err(RecvTimeoutError::Disconnected) => return self.on_worker_gone(),
+            }
+        }
+    }
+
+    /// Whether a sample that timed out is still stuck in the driver, so that
+    /// calls would queue behind it.
+    pub fn sample_in_flight(&self) -> bool {
+        self.in_flight.is_some()
+    }
+
+    /// Run `f` with the NVML handle on the sampling thread, giving up after
+    /// the configured timeout.
+    ///
+    /// Calls queue behind any sample still stuck in the driver. Unlike samples,
+    /// timed out calls don't count towards re-initializing NVML.
+    pub fn call<T, F>(&mut self, f: F) -> Result<T, WatchdogError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&NvidiaGpu) -> T + Send + 'static,
+    {
+        let (result_tx, result_rx) = mpsc::channel();
+        let call: Call = Box::new(move |nvidia_gpu| {
+            let _ = result_tx.send(f(nvidia_gpu));
+        });
+        if self.worker.requests.send(Request::Call(call)).is_err() {
+            self.recover("sampling thread exited");
+            return Err(WatchdogError::WorkerGone);
+        }
+        match result_rx.recv_timeout(self.timeout) {
+            Ok(result) => Ok(result),
+            Err(RecvTimeoutError::Timeout) => Err(WatchdogError::TimedOut(self.timeout)),
+            Err(RecvTimeoutError::Disconnected) => Err(WatchdogError::WorkerGone),
+        }
+    }
+
+    fn on_worker_gone(&mut self) -> Result<(), WatchdogError> {
+        self.recover("sampling thread exited");
+        Err(WatchdogError::WorkerGone)
+    }
+
+    /// Abandon the current worker thread and re-initialize NVML on a new one.
+    /// `reason` says why, e.g. that the sampling thread exited.
+    fn recover(&mut self, reason: &str) {
+        log::warning!("{}, re-initializing", reason);
+        match Worker::spawn(self.timeout) {
+            Ok(worker) => {
+                // Dropping the old worker's channels lets its thread exit
+                // if the hung call ever returns.
+                self.worker = worker;
+                self.in_flight = None;
+                self.consecutive_timeouts = 0;
+            }
+            Err(e) => {
+                log::error!("Error re-initializing NVML: {}", e);
+                error::report(&e);
+            }
+        }
+    }
+
+    /// Shut down NVML, waiting at most the configured timeout.
+    pub fn shutdown(self) -> Result<(), WatchdogError> {
+        if self.worker.requests.send(Request::Shutdown).is_err() {
+            return Err(WatchdogError::WorkerGone);
+        }
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.worker.responses.recv_timeout(remaining) {
+                Ok(Response::ShutDown(result)) => return result.map_err(WatchdogError::Nvml),
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(WatchdogError::TimedOut(self.timeout))
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(WatchdogError::WorkerGone),
+            }
+        }
+    }
+}
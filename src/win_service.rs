@@ -0,0 +1,120 @@
+use clap::Parser;
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use symon::log;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+const SERVICE_NAME: &str = "symon";
+const SERVICE_DISPLAY_NAME: &str = "symon GPU monitor";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/// Register symon with the service control manager.
+///
+/// `args` are the monitoring options the service is started with; they are
+/// stored as launch arguments ahead of `service run`.
+pub fn install(args: &[OsString]) -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+
+    let mut launch_arguments = args.to_vec();
+    launch_arguments.extend(["service".into(), "run".into()]);
+
+    let service_info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: SERVICE_DISPLAY_NAME.into(),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe().map_err(windows_service::Error::Winapi)?,
+        launch_arguments,
+        dependencies: vec![],
+        account_name: None, // run as LocalSystem
+        account_password: None,
+    };
+    let service = manager.create_service(&service_info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description("Samples NVIDIA GPU metrics")?;
+    println!("Installed service {}", SERVICE_NAME);
+    Ok(())
+}
+
+/// Stop the service if it is running and remove it.
+pub fn uninstall() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+    // Deletion takes effect once the service has stopped
+    service.delete()?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    println!("Uninstalled service {}", SERVICE_NAME);
+    Ok(())
+}
+
+/// Hand control to the service dispatcher. Blocks until the service stops.
+pub fn run() -> windows_service::Result<()> {
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        log::error!("Error running service: {}", e);
+    }
+}
+
+fn run_service() -> windows_service::Result<()> {
+    let running = Arc::new(AtomicBool::new(true));
+
+    let r = running.clone();
+    let event_handler = move |control_event| -> ServiceControlHandlerResult {
+        match control_event {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                r.store(false, Ordering::Relaxed);
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    };
+    let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)?;
+
+    let status = |current_state, exit_code| ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state,
+        controls_accepted: match current_state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    };
+    status_handle.set_service_status(status(ServiceState::Running, 0))?;
+
+    // The launch arguments registered at install time carry the monitoring options
+    let args = crate::Args::parse();
+    let exit_code = match crate::monitor(&args, running) {
+        Ok(_) => 0,
+        Err(e) => {
+            log::error!("symon service exited with an error: {}", e);
+            1
+        }
+    };
+
+    status_handle.set_service_status(status(ServiceState::Stopped, exit_code))
+}
@@ -0,0 +1,196 @@
+use crate::error;
+use crate::log::{self, Level};
+use crate::metrics::Metrics;
+use crate::otel;
+use crate::sink::{Sink, SinkMetrics};
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+/// Per-sink counters, reported as `_agent.sink.{i}.*`.
+struct SinkStats {
+    latency_key: &'static str,
+    errors_key: &'static str,
+    latency_us: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Counters shared between the sampling loop and the writer thread.
+pub struct WriterStats {
+    queue_depth: AtomicUsize,
+    dropped: AtomicU64,
+    sinks: Vec<SinkStats>,
+    /// Counters kept by the sinks themselves.
+    sink_metrics: Vec<Option<Arc<dyn SinkMetrics>>>,
+}
+
+/// Get the latency and error metric names of sink `i`.
+///
+/// Names are cached process-wide, so writers spawned again on reload, for
+/// tenants or for webhooks don't format them again.
+fn sink_keys(i: usize) -> (&'static str, &'static str) {
+    static KEYS: Mutex<Vec<(&'static str, &'static str)>> = Mutex::new(Vec::new());
+
+    let mut keys = KEYS.lock().unwrap_or_else(|e| e.into_inner());
+    while keys.len() <= i {
+        let si = keys.len();
+        keys.push((
+            Box::leak(format!("_agent.sink.{}.latencyMs", si).into_boxed_str()),
+            Box::leak(format!("_agent.sink.{}.errors", si).into_boxed_str()),
+        ));
+    }
+    keys[i]
+}
+
+impl WriterStats {
+    fn new(sinks: &[Box<dyn Sink>]) -> Self {
+        let sink_count = sinks.len();
+        WriterStats {
+            queue_depth: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+            sinks: (0..sink_count)
+                .map(|i| {
+                    let (latency_key, errors_key) = sink_keys(i);
+                    SinkStats {
+                        latency_key,
+                        errors_key,
+                        latency_us: AtomicU64::new(0),
+                        errors: AtomicU64::new(0),
+                    }
+                })
+                .collect(),
+            sink_metrics: sinks.iter().map(|sink| sink.metrics()).collect(),
+        }
+    }
+
+    /// Number of samples waiting to be written.
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+
+    /// Number of samples dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Add queue depth, dropped samples, and the latest write latency and
+    /// cumulative error count of each sink, plus any counters of its own.
+    pub fn add_metrics(&self, metrics: &mut Metrics) {
+        metrics.add_metric("_agent.queueDepth", self.queue_depth() as u64);
+        metrics.add_metric("_agent.droppedSamples", self.dropped());
+        for sink in &self.sinks {
+            let latency_ms = sink.latency_us.load(Ordering::Relaxed) as f64 / 1000.0;
+            metrics.add_metric(sink.latency_key, latency_ms);
+            metrics.add_metric(sink.errors_key, sink.errors.load(Ordering::Relaxed));
+        }
+        for (i, sink_metrics) in self.sink_metrics.iter().enumerate() {
+            if let Some(sink_metrics) = sink_metrics {
+                sink_metrics.add_metrics(i, metrics);
+            }
+        }
+    }
+}
+
+/// Writes samples to the configured sinks on a dedicated thread.
+///
+/// Samples are handed over through a bounded queue. If the sinks can't keep
+/// up, new samples are dropped (and counted) instead of delaying the sampling
+/// cadence. Written samples are sent back so their storage can be reused.
+pub struct SampleWriter {
+    queue: SyncSender<Metrics>,
+    recycled: Receiver<Metrics>,
+    stats: Arc<WriterStats>,
+    handle: JoinHandle<()>,
+}
+
+impl SampleWriter {
+    pub fn spawn(mut sinks: Vec<Box<dyn Sink>>, capacity: usize) -> io::Result<Self> {
+        let (queue_tx, queue_rx) = mpsc::sync_channel::<Metrics>(capacity);
+        let (recycle_tx, recycle_rx) = mpsc::channel::<Metrics>();
+        let stats = Arc::new(WriterStats::new(&sinks));
+
+        let thread_stats = stats.clone();
+        let handle = thread::Builder::new()
+            .name("writer".to_string())
+            .spawn(move || {
+                for metrics in queue_rx {
+                    thread_stats.queue_depth.fetch_sub(1, Ordering::Relaxed);
+                    let _scope = otel::enter(metrics.time().and_then(otel::sample_context));
+                    for (sink, sink_stats) in sinks.iter_mut().zip(&thread_stats.sinks) {
+                        let write_start = Instant::now();
+                        let mut span = otel::Span::start("sink.write");
+                        span.set_attribute("symon.sink", sink.name());
+                        // Mark what the sink lost since the last sample in its own output
+                        let lost = sink.take_lost();
+                        let mut result = Ok(());
+                        if !lost.is_empty() {
+                            result = sink.write(&lost.lost_record(&metrics));
+                        }
+                        if let Err(e) = result.and(sink.write(&metrics)) {
+                            span.set_error(&e);
+                            sink_stats.errors.fetch_add(1, Ordering::Relaxed);
+                            log::emit(
+                                Level::Error,
+                                &format!("Error writing metrics to {}: {}", sink.name(), e),
+                                &[("SYMON_SINK", sink.name())],
+                                (file!(), line!()),
+                            );
+                            error::report(&e);
+                        }
+                        let latency_us = write_start.elapsed().as_micros() as u64;
+                        sink_stats.latency_us.store(latency_us, Ordering::Relaxed);
+                    }
+                    let _ = recycle_tx.send(metrics);
+                }
+                for sink in sinks.iter_mut() {
+                    if let Err(e) = sink.flush() {
+                        log::error!("Error flushing {}: {}", sink.name(), e);
+                    }
+                }
+            })?;
+
+        Ok(SampleWriter {
+            queue: queue_tx,
+            recycled: recycle_rx,
+            stats,
+            handle,
+        })
+    }
+
+    pub fn stats(&self) -> &WriterStats {
+        &self.stats
+    }
+
+    /// Get an empty sample, reusing the storage of an already written one if possible.
+    pub fn recycled(&self) -> Metrics {
+        match self.recycled.try_recv() {
+            Ok(mut metrics) => {
+                metrics.clear();
+                metrics
+            }
+            Err(_) => Metrics::new(),
+        }
+    }
+
+    /// Queue a sample for writing. Returns `false` if it had to be dropped.
+    pub fn submit(&self, metrics: Metrics) -> bool {
+        self.stats.queue_depth.fetch_add(1, Ordering::Relaxed);
+        match self.queue.try_send(metrics) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.stats.queue_depth.fetch_sub(1, Ordering::Relaxed);
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Write out all queued samples and stop the writer thread.
+    pub fn close(self) {
+        drop(self.queue);
+        let _ = self.handle.join();
+    }
+}